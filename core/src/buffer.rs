@@ -10,18 +10,29 @@ use druid::{
 };
 use druid::{Env, FontFamily, PaintCtx, Point};
 use language::{new_highlight_config, new_parser, LapceLanguage};
-use lapce_proxy::dispatch::NewBufferResponse;
+use lapce_proxy::brackets::matching_pair;
+use lapce_proxy::buffer::{ExternalChange, LARGE_FILE_CHUNK_LINES};
+use lapce_proxy::dispatch::{BufferLinesResponse, NewBufferResponse};
+use lapce_proxy::indent::IndentStyle;
+use lapce_proxy::line_ending::LineEnding;
+use lapce_proxy::lsp::{
+    DocumentHighlightResponse, DocumentLinkItem, DocumentLinksResponse,
+    FoldingRangesResponse, InlayHintsResponse, LineStyles,
+};
+use lapce_proxy::position::{byte_to_utf16, utf16_to_byte};
+use lapce_proxy::symbols::EnclosingScopesResponse;
 use lsp_types::SemanticTokensServerCapabilities;
 use lsp_types::{CallHierarchyOptions, SemanticTokensLegend};
 use lsp_types::{
-    CodeActionResponse, Position, Range, TextDocumentContentChangeEvent,
+    CodeActionResponse, FoldingRangeKind, Position, PrepareRenameResponse, Range,
+    TextDocumentContentChangeEvent,
 };
 use lsp_types::{Location, SemanticTokens};
 use parking_lot::Mutex;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::cell::RefCell;
-use std::cmp::Ordering;
+use std::fs;
 use std::rc::Rc;
 use std::{
     borrow::Cow,
@@ -33,7 +44,6 @@ use std::{
     thread,
 };
 use std::{collections::HashMap, fs::File};
-use std::{fs, str::FromStr};
 use tree_sitter::{Node, Parser, Tree};
 use tree_sitter_highlight::{
     Highlight, HighlightConfiguration, HighlightEvent, Highlighter,
@@ -46,8 +56,8 @@ use xi_rope::{
     multiset::Subset,
     rope::Rope,
     spans::{Spans, SpansBuilder, SpansInfo},
-    Cursor, Delta, DeltaBuilder, Interval, LinesMetric, RopeDelta, RopeInfo,
-    Transformer,
+    Cursor, Delta, DeltaBuilder, DeltaElement, Interval, LinesMetric, RopeDelta,
+    RopeInfo, Transformer,
 };
 use xi_unicode::EmojiExt;
 
@@ -55,7 +65,10 @@ use crate::config::{Config, LapceTheme};
 use crate::data::EditorKind;
 use crate::editor::EditorLocationNew;
 use crate::find::FindProgress;
+use crate::folding::{self, SavedFolds};
+use crate::hex::num_hex_lines;
 use crate::theme::OldLapceTheme;
+use crate::undo_history::{content_hash, UndoHistory, UNDO_HISTORY_VERSION};
 use crate::{
     command::LapceUICommand,
     command::LAPCE_UI_COMMAND,
@@ -126,7 +139,7 @@ pub struct Style {
 
 pub enum UpdateEvent {
     Buffer(BufferUpdate),
-    SemanticTokens(BufferUpdate, Vec<(usize, usize, String)>),
+    SemanticTokens(BufferUpdate, Vec<LineStyles>),
 }
 
 pub struct BufferUpdate {
@@ -136,7 +149,10 @@ pub struct BufferUpdate {
     pub rev: u64,
     pub language: LapceLanguage,
     pub highlights: Arc<Spans<Style>>,
-    pub semantic_tokens: bool,
+    pub semantic_styles: Option<Arc<Spans<Style>>>,
+    /// The edits from the rev before, each with the rev it made, for the
+    /// syntax tree to be edited instead of parsed again. Empty on a load.
+    pub deltas: Vec<(u64, RopeDelta)>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -156,8 +172,8 @@ impl EditType {
     }
 }
 
-#[derive(Clone)]
-enum Contents {
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Contents {
     Edit {
         /// Groups related edits together so that they are undone and re-done
         /// together. For example, an auto-indent insertion would be un-done
@@ -180,10 +196,10 @@ enum Contents {
     },
 }
 
-#[derive(Clone)]
-struct Revision {
-    max_undo_so_far: usize,
-    edit: Contents,
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Revision {
+    pub(crate) max_undo_so_far: usize,
+    pub(crate) edit: Contents,
 }
 
 #[derive(Clone)]
@@ -192,9 +208,30 @@ pub struct BufferNew {
     pub rope: Rope,
     pub path: PathBuf,
     pub line_styles: Rc<RefCell<Vec<Option<Arc<Vec<(usize, usize, Style)>>>>>>,
+    /// The syntax highlighting with the semantic tokens merged over it.
     pub styles: Arc<Spans<Style>>,
-    pub semantic_tokens: bool,
+    pub semantic_styles: Option<Arc<Spans<Style>>>,
     pub language: Option<LapceLanguage>,
+    /// The language id the proxy detected when loading the file, empty for
+    /// plain text.
+    pub language_id: String,
+    /// For a large file, which is read-only and loaded by chunks: the
+    /// lines loaded so far and the lines of the file.
+    pub large_file: Option<(usize, usize)>,
+    /// A binary file is loaded like a large file, with lines of hex.
+    pub binary: bool,
+    /// Whether more lines of a large file are being asked for.
+    pub loading_lines: Rc<RefCell<bool>>,
+    /// What the lines of the file end with. The rope only has `\n`, the
+    /// proxy puts the endings back on save.
+    pub line_ending: LineEnding,
+    /// What a level of indentation is, worked out by the proxy from the
+    /// file.
+    pub indent: IndentStyle,
+    /// Off for a read-only file, which takes no edits.
+    pub editable: bool,
+    /// How another program changed the file since it was loaded or saved.
+    pub external_change: Option<ExternalChange>,
     pub max_len: usize,
     pub max_len_line: usize,
     pub num_lines: usize,
@@ -205,6 +242,9 @@ pub struct BufferNew {
     pub local: bool,
     update_sender: Arc<Sender<UpdateEvent>>,
     pub line_changes: HashMap<usize, char>,
+    /// What was inserted or deleted since the file was loaded, kept through
+    /// later edits, for trimming the whitespace of the lines edited only.
+    edited_ranges: Vec<(usize, usize)>,
 
     pub find: Rc<RefCell<Find>>,
     pub find_progress: Rc<RefCell<FindProgress>>,
@@ -225,9 +265,34 @@ pub struct BufferNew {
     pub scroll_offset: Vec2,
 
     pub code_actions: im::HashMap<usize, CodeActionResponse>,
+    /// The hints of the lines last shown, painted only while their `rev`
+    /// is the buffer's.
+    pub inlay_hints: Option<Arc<InlayHintsResponse>>,
+    /// The scopes of the first line in view, shown in the header. Kept
+    /// over edits until the ones of the new `rev` come, so it doesn't
+    /// flicker while typing.
+    pub enclosing_scopes: Option<Arc<EnclosingScopesResponse>>,
+    /// The occurrences of the symbol under the cursor, painted only while
+    /// their `rev` is the buffer's.
+    pub document_highlights: Option<Arc<DocumentHighlightResponse>>,
+    /// The links of the lines last shown, for ctrl-click.
+    pub document_links: Option<Arc<DocumentLinksResponse>>,
+    /// The foldable ranges, kept in line with the edits made since they
+    /// were computed.
+    pub folds: Arc<Vec<Fold>>,
+    /// The folds collapsed in the last session, until the file is loaded.
+    pub saved_folds: Option<SavedFolds>,
     pub syntax_tree: Option<Arc<Tree>>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fold {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub kind: Option<FoldingRangeKind>,
+    pub collapsed: bool,
+}
+
 impl BufferNew {
     pub fn new(path: PathBuf, update_sender: Arc<Sender<UpdateEvent>>) -> Self {
         let rope = Rope::from("");
@@ -236,12 +301,20 @@ impl BufferNew {
             id: BufferId::next(),
             rope,
             language,
+            language_id: String::new(),
+            large_file: None,
+            binary: false,
+            loading_lines: Rc::new(RefCell::new(false)),
+            line_ending: LineEnding::Lf,
+            indent: IndentStyle::default(),
+            editable: true,
+            external_change: None,
             path,
             styles: Arc::new(SpansBuilder::new(0).build()),
             line_styles: Rc::new(RefCell::new(Vec::new())),
             find: Rc::new(RefCell::new(Find::new(0))),
             find_progress: Rc::new(RefCell::new(FindProgress::Ready)),
-            semantic_tokens: false,
+            semantic_styles: None,
             max_len: 0,
             max_len_line: 0,
             num_lines: 0,
@@ -252,6 +325,7 @@ impl BufferNew {
             update_sender,
             local: false,
             line_changes: HashMap::new(),
+            edited_ranges: Vec::new(),
 
             revs: vec![Revision {
                 max_undo_so_far: 0,
@@ -275,6 +349,12 @@ impl BufferNew {
             scroll_offset: Vec2::ZERO,
 
             code_actions: im::HashMap::new(),
+            inlay_hints: None,
+            enclosing_scopes: None,
+            document_highlights: None,
+            document_links: None,
+            folds: Arc::new(Vec::new()),
+            saved_folds: None,
             syntax_tree: None,
         };
         *buffer.line_styles.borrow_mut() = vec![None; buffer.num_lines()];
@@ -306,6 +386,7 @@ impl BufferNew {
 
     pub fn load_content(&mut self, content: &str) {
         self.reset_revs();
+        self.edited_ranges.clear();
 
         if content != "" {
             let delta =
@@ -319,16 +400,96 @@ impl BufferNew {
         }
 
         self.code_actions.clear();
+        self.folds = Arc::new(Vec::new());
         let (max_len, max_len_line) = self.get_max_line_len();
         self.max_len = max_len;
         self.max_len_line = max_len_line;
         self.num_lines = self.num_lines();
         *self.line_styles.borrow_mut() = vec![None; self.num_lines()];
         self.loaded = true;
-        self.notify_update();
+        self.notify_update(None);
+    }
+
+    pub(crate) fn undo_history(&self) -> UndoHistory {
+        UndoHistory {
+            version: UNDO_HISTORY_VERSION,
+            content_hash: content_hash(&self.rope),
+            revs: self.revs.clone(),
+            cur_undo: self.cur_undo,
+            undos: self.undos.clone(),
+            undo_group_id: self.undo_group_id,
+            live_undos: self.live_undos.clone(),
+            deletes_from_union: self.deletes_from_union.clone(),
+            undone_groups: self.undone_groups.clone(),
+            tombstones: self.tombstones.clone(),
+            cursor_offset: self.cursor_offset,
+        }
+    }
+
+    /// Takes back the history of an earlier session, whose text is the one
+    /// just loaded.
+    pub(crate) fn restore_undo_history(&mut self, history: UndoHistory) {
+        self.revs = history.revs;
+        self.cur_undo = history.cur_undo;
+        self.undos = history.undos;
+        self.undo_group_id = history.undo_group_id;
+        self.live_undos = history.live_undos;
+        self.deletes_from_union = history.deletes_from_union;
+        self.undone_groups = history.undone_groups;
+        self.tombstones = history.tombstones;
+        self.last_edit_type = EditType::Other;
+        self.cursor_offset = history.cursor_offset.min(self.len());
+    }
+
+    /// Marks the buffer as a large file whose first chunk is about to be
+    /// loaded, which gets no highlighting.
+    pub fn set_large_file(&mut self, num_lines: usize) {
+        self.language = None;
+        self.large_file = Some((LARGE_FILE_CHUNK_LINES.min(num_lines), num_lines));
+    }
+
+    /// Marks the buffer as a binary file of `file_size` bytes, whose lines
+    /// of hex are all loaded by chunks.
+    pub fn set_binary(&mut self, file_size: u64) {
+        self.language = None;
+        self.binary = true;
+        self.large_file = Some((0, num_hex_lines(file_size)));
+    }
+
+    /// Appends the next lines of a large file, unless they aren't the ones
+    /// following the lines loaded.
+    pub fn append_lines(&mut self, start_line: usize, lines: &BufferLinesResponse) {
+        *self.loading_lines.borrow_mut() = false;
+        let (loaded, num_lines) = match self.large_file {
+            Some(large_file) => large_file,
+            None => return,
+        };
+        if start_line != loaded || lines.end_line <= loaded {
+            return;
+        }
+
+        let len = self.len();
+        let delta = Delta::simple_edit(
+            Interval::new(len, len),
+            Rope::from(&lines.text),
+            len,
+        );
+        let (new_rev, new_text, new_tombstones, new_deletes_from_union) =
+            self.mk_new_rev(0, delta);
+        self.revs.push(new_rev);
+        self.rope = new_text;
+        self.tombstones = new_tombstones;
+        self.deletes_from_union = new_deletes_from_union;
+        self.large_file = Some((lines.end_line, num_lines));
+
+        let (max_len, max_len_line) = self.get_max_line_len();
+        self.max_len = max_len;
+        self.max_len_line = max_len_line;
+        self.num_lines = self.num_lines();
+        self.line_styles.borrow_mut().resize(self.num_lines, None);
     }
 
-    pub fn notify_update(&self) {
+    pub fn notify_update(&self, delta: Option<&RopeDelta>) {
         if let Some(language) = self.language {
             self.update_sender.send(UpdateEvent::Buffer(BufferUpdate {
                 id: self.id,
@@ -337,7 +498,10 @@ impl BufferNew {
                 rev: self.rev,
                 language,
                 highlights: self.styles.clone(),
-                semantic_tokens: self.semantic_tokens,
+                semantic_styles: self.semantic_styles.clone(),
+                deltas: delta
+                    .map(|delta| vec![(self.rev, delta.clone())])
+                    .unwrap_or_default(),
             }));
         }
     }
@@ -360,21 +524,32 @@ impl BufferNew {
                 id,
                 path.clone(),
                 Box::new(move |result| {
-                    if let Ok(res) = result {
-                        if let Ok(resp) =
-                            serde_json::from_value::<NewBufferResponse>(res)
-                        {
-                            event_sink.submit_command(
-                                LAPCE_UI_COMMAND,
-                                LapceUICommand::LoadBuffer {
-                                    path,
-                                    content: resp.content,
-                                    locations,
-                                },
-                                Target::Widget(tab_id),
-                            );
+                    let res = match result {
+                        Ok(res) => res,
+                        Err(e) => {
+                            eprintln!("can't open {:?}: {:?}", path, e);
+                            return;
                         }
                     };
+                    if let Ok(resp) =
+                        serde_json::from_value::<NewBufferResponse>(res)
+                    {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::LoadBuffer {
+                                path,
+                                content: resp.content,
+                                language_id: resp.language_id,
+                                large_file: resp.large_file.then(|| resp.num_lines),
+                                binary: resp.is_binary.then(|| resp.file_size),
+                                line_ending: resp.line_ending,
+                                indent: resp.indent,
+                                editable: resp.editable,
+                                locations,
+                            },
+                            Target::Widget(tab_id),
+                        );
+                    }
                 }),
             )
         });
@@ -542,6 +717,32 @@ impl BufferNew {
         WordCursor::new(&self.rope, offset).select_word()
     }
 
+    /// The start of the symbol to rename and the name to start editing
+    /// from, as suggested by the server's `prepareRename` answer.
+    pub fn rename_placeholder(
+        &self,
+        offset: usize,
+        response: &PrepareRenameResponse,
+    ) -> (Position, String) {
+        let (start, end) = match response {
+            PrepareRenameResponse::Range(range)
+            | PrepareRenameResponse::RangeWithPlaceholder { range, .. } => (
+                self.offset_of_position(&range.start),
+                self.offset_of_position(&range.end),
+            ),
+            PrepareRenameResponse::DefaultBehavior { .. } => {
+                self.select_word(offset)
+            }
+        };
+        let placeholder = match response {
+            PrepareRenameResponse::RangeWithPlaceholder { placeholder, .. } => {
+                placeholder.clone()
+            }
+            _ => self.slice_to_cow(start..end).to_string(),
+        };
+        (self.offset_to_position(start), placeholder)
+    }
+
     pub fn char_at_offset(&self, offset: usize) -> Option<char> {
         if self.len() == 0 {
             return None;
@@ -626,16 +827,17 @@ impl BufferNew {
         config: &Config,
     ) -> PietTextLayout {
         let (line_content, cursor_index) = if line_content.contains('\t') {
+            let tab_width = config.tab_width(&self.language_id);
             let cursor_index = cursor_index.map(|index| {
                 line_content
                     .chars()
                     .enumerate()
                     .filter(|(i, c)| *i < index && *c == '\t')
                     .count()
-                    * 3
+                    * (tab_width - 1)
                     + index
             });
-            let line_content = line_content.replace('\t', "    ");
+            let line_content = line_content.replace('\t', &" ".repeat(tab_width));
             (line_content, cursor_index)
         } else {
             (line_content.to_string(), cursor_index)
@@ -664,9 +866,7 @@ impl BufferNew {
 
         for (start, end, style) in styles.iter() {
             if let Some(fg_color) = style.fg_color.as_ref() {
-                if let Some(fg_color) =
-                    config.get_color(&("style.".to_string() + fg_color))
-                {
+                if let Some(fg_color) = config.get_style_color(fg_color) {
                     layout_builder = layout_builder.range_attribute(
                         start..end,
                         TextAttribute::TextColor(fg_color.clone()),
@@ -689,16 +889,42 @@ impl BufferNew {
         self.rope.slice_to_cow(range)
     }
 
+    /// The lsp position of `offset`, whose character is in utf-16 code
+    /// units.
     pub fn offset_to_position(&self, offset: usize) -> Position {
-        let (line, col) = self.offset_to_line_col(offset);
+        let offset = offset.min(self.len());
+        let line = self.line_of_offset(offset);
+        let line_start = self.offset_of_line(line);
+        let line_content = self.slice_to_cow(line_start..offset);
         Position {
             line: line as u32,
-            character: col as u32,
+            character: byte_to_utf16(&line_content, offset - line_start) as u32,
         }
     }
 
+    /// The link at the offset, if the links are of the current revision.
+    pub fn document_link_at(&self, offset: usize) -> Option<&DocumentLinkItem> {
+        let links = self.document_links.as_ref()?;
+        if links.rev != self.rev {
+            return None;
+        }
+        links.links.iter().find(|link| {
+            self.offset_of_position(&link.range.start) <= offset
+                && offset < self.offset_of_position(&link.range.end)
+        })
+    }
+
+    /// The offset of an lsp position. Servers send positions past the end
+    /// of a line, or of the text, which are the end of it.
     pub fn offset_of_position(&self, pos: &Position) -> usize {
-        self.offset_of_line_col(pos.line as usize, pos.character as usize)
+        let line = pos.line as usize;
+        if line > self.last_line() {
+            return self.len();
+        }
+        let line_start = self.offset_of_line(line);
+        let line_end = self.line_end_offset(line, true);
+        let line_content = self.slice_to_cow(line_start..line_end);
+        line_start + utf16_to_byte(&line_content, pos.character as usize)
     }
 
     pub fn offset_of_mouse(
@@ -790,6 +1016,62 @@ impl BufferNew {
         self.line_end_offset(line, caret)
     }
 
+    /// Where the trailing whitespace is on the lines edited since the file
+    /// was loaded, or on every line with `whole_file`.
+    pub fn trailing_whitespace(&self, whole_file: bool) -> Vec<(usize, usize)> {
+        let lines: BTreeSet<usize> = if whole_file {
+            (0..self.num_lines()).collect()
+        } else {
+            self.edited_ranges
+                .iter()
+                .flat_map(|(start, end)| {
+                    self.line_of_offset(*start)..=self.line_of_offset(*end)
+                })
+                .collect()
+        };
+        lines
+            .into_iter()
+            .filter_map(|line| {
+                let end = self.line_end_offset(line, true);
+                let content = self.slice_to_cow(self.offset_of_line(line)..end);
+                let trimmed =
+                    content.trim_end_matches(|c: char| c == ' ' || c == '\t');
+                let start = end - (content.len() - trimmed.len());
+                (start < end).then(|| (start, end))
+            })
+            .collect()
+    }
+
+    /// Whether the buffer has text that doesn't end with a newline.
+    pub fn missing_final_newline(&self) -> bool {
+        let len = self.len();
+        len > 0 && self.slice_to_cow(len - 1..len) != "\n"
+    }
+
+    fn update_edited_ranges(&mut self, delta: &RopeDelta) {
+        let mut transformer = Transformer::new(delta);
+        let mut ranges: Vec<(usize, usize)> = self
+            .edited_ranges
+            .iter()
+            .map(|(start, end)| {
+                (
+                    transformer.transform(*start, false),
+                    transformer.transform(*end, true),
+                )
+            })
+            .collect();
+        ranges.extend(changed_ranges(delta));
+        ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.edited_ranges = merged;
+    }
+
     pub fn line_len(&self, line: usize) -> usize {
         self.offset_of_line(line + 1) - self.offset_of_line(line)
     }
@@ -1076,18 +1358,11 @@ impl BufferNew {
                 }
             }
             Movement::MatchPairs => {
-                if self.syntax_tree.is_some() {
-                    let new_offset =
-                        self.find_matching_pair(offset).unwrap_or(offset);
-                    let (_, col) = self.offset_to_line_col(new_offset);
-                    (new_offset, ColPosition::Col(col))
-                } else {
-                    let new_offset = WordCursor::new(&self.rope, offset)
-                        .match_pairs()
+                let new_offset =
+                    matching_pair(&self.rope, self.syntax_tree.as_deref(), offset)
                         .unwrap_or(offset);
-                    let (_, col) = self.offset_to_line_col(new_offset);
-                    (new_offset, ColPosition::Col(col))
-                }
+                let (_, col) = self.offset_to_line_col(new_offset);
+                (new_offset, ColPosition::Col(col))
             }
         }
     }
@@ -1100,25 +1375,6 @@ impl BufferNew {
         }
     }
 
-    fn find_matching_pair(&self, offset: usize) -> Option<usize> {
-        let tree = self.syntax_tree.as_ref()?;
-        let node = tree
-            .root_node()
-            .descendant_for_byte_range(offset, offset + 1)?;
-        let mut chars = node.kind().chars();
-        let char = chars.next()?;
-        let char = matching_char(char)?;
-        let tag = &char.to_string();
-
-        if let Some(offset) = self.find_tag_in_siblings(node, true, tag) {
-            return Some(offset);
-        }
-        if let Some(offset) = self.find_tag_in_siblings(node, false, tag) {
-            return Some(offset);
-        }
-        None
-    }
-
     fn find_tag(&self, offset: usize, previous: bool, tag: &str) -> Option<usize> {
         let tree = self.syntax_tree.as_ref()?;
         let node = tree
@@ -1191,20 +1447,77 @@ impl BufferNew {
         self.syntax_tree = Some(Arc::new(tree));
     }
 
+    /// Takes the new ranges, the ones already known keeping whether they
+    /// are collapsed.
+    pub fn update_folding_ranges(&mut self, resp: &FoldingRangesResponse) {
+        if resp.rev != self.rev {
+            return;
+        }
+        let folds = resp
+            .ranges
+            .iter()
+            .map(|range| {
+                let (start_line, end_line) =
+                    (range.start_line as usize, range.end_line as usize);
+                let collapsed = self.folds.iter().any(|fold| {
+                    fold.collapsed
+                        && fold.start_line == start_line
+                        && fold.end_line == end_line
+                });
+                Fold {
+                    start_line,
+                    end_line,
+                    kind: range.kind.clone(),
+                    collapsed,
+                }
+            })
+            .collect();
+        self.folds = Arc::new(folds);
+    }
+
+    fn update_folds(&mut self, inval_lines: &InvalLines) {
+        if self.folds.is_empty() {
+            return;
+        }
+        self.folds = Arc::new(folding::apply_inval_lines(&self.folds, inval_lines));
+    }
+
+    /// Collapses the folds of the last session, if the text is still what
+    /// they were collapsed in.
+    pub fn restore_folds(&mut self) {
+        if let Some(saved) = self.saved_folds.take() {
+            if saved.content_hash == content_hash(&self.rope) {
+                self.folds = Arc::new(folding::restored(&saved));
+            }
+        }
+    }
+
     pub fn update_styles(
         &mut self,
         rev: u64,
         highlights: Spans<Style>,
-        semantic_tokens: bool,
+        semantic_styles: Option<Spans<Style>>,
+        changed_lines: Option<&[(usize, usize)]>,
     ) {
         if rev != self.rev {
             return;
         }
-        if semantic_tokens {
-            self.semantic_tokens = true;
+        if let Some(semantic_styles) = semantic_styles {
+            self.semantic_styles = Some(Arc::new(semantic_styles));
         }
         self.styles = Arc::new(highlights);
-        *self.line_styles.borrow_mut() = vec![None; self.num_lines];
+        let mut line_styles = self.line_styles.borrow_mut();
+        match changed_lines {
+            Some(changed_lines) => {
+                for (start, end) in changed_lines {
+                    let end = (*end).min(line_styles.len());
+                    for line in (*start).min(end)..end {
+                        line_styles[line] = None;
+                    }
+                }
+            }
+            None => *line_styles = vec![None; self.num_lines],
+        }
     }
 
     fn update_size(&mut self, inval_lines: &InvalLines) {
@@ -1241,6 +1554,9 @@ impl BufferNew {
 
     fn update_line_styles(&mut self, delta: &RopeDelta, inval_lines: &InvalLines) {
         Arc::make_mut(&mut self.styles).apply_shape(delta);
+        if let Some(semantic_styles) = self.semantic_styles.as_mut() {
+            Arc::make_mut(semantic_styles).apply_shape(delta);
+        }
         let mut line_styles = self.line_styles.borrow_mut();
         let mut right = line_styles.split_off(inval_lines.start_line);
         let right = &right[inval_lines.inval_count..];
@@ -1333,14 +1649,55 @@ impl BufferNew {
         }
         self.rev += 1;
         self.dirty = true;
-
-        let (iv, newlen) = delta.summary();
-        let old_logical_end_line = self.rope.line_of_offset(iv.end) + 1;
+        self.update_edited_ranges(delta);
 
         if !self.local {
-            proxy.update(self.id, &delta, self.rev);
+            proxy.update(self.id, delta, self.rev);
         }
 
+        self.set_new_rev(
+            delta,
+            new_rev,
+            new_text,
+            new_tombstones,
+            new_deletes_from_union,
+        );
+    }
+
+    /// Takes the content the proxy read again from disk as an edit that
+    /// keeps the lines which didn't change, so that cursors can follow it.
+    pub fn reload(&mut self, rev: u64, content: &str) -> RopeDelta {
+        let delta = reload_delta(&self.rope, content);
+        self.this_edit_type = EditType::Other;
+        let undo_group = self.calculate_undo_group();
+        self.last_edit_type = self.this_edit_type;
+
+        let (new_rev, new_text, new_tombstones, new_deletes_from_union) =
+            self.mk_new_rev(undo_group, delta.clone());
+        self.rev = rev;
+        self.dirty = false;
+        self.edited_ranges.clear();
+        self.set_new_rev(
+            &delta,
+            new_rev,
+            new_text,
+            new_tombstones,
+            new_deletes_from_union,
+        );
+        delta
+    }
+
+    fn set_new_rev(
+        &mut self,
+        delta: &RopeDelta,
+        new_rev: Revision,
+        new_text: Rope,
+        new_tombstones: Rope,
+        new_deletes_from_union: Subset,
+    ) {
+        let (iv, newlen) = delta.summary();
+        let old_logical_end_line = self.rope.line_of_offset(iv.end) + 1;
+
         self.revs.push(new_rev);
         self.rope = new_text.clone();
         self.tombstones = new_tombstones;
@@ -1359,10 +1716,11 @@ impl BufferNew {
             new_count: new_hard_count,
         };
         self.update_size(&inval_lines);
-        self.update_line_styles(&delta, &inval_lines);
+        self.update_line_styles(delta, &inval_lines);
+        self.update_folds(&inval_lines);
         self.find.borrow_mut().unset();
         *self.find_progress.borrow_mut() = FindProgress::Started;
-        self.notify_update();
+        self.notify_update(Some(delta));
     }
 
     pub fn update_edit_type(&mut self) {
@@ -1375,8 +1733,22 @@ impl BufferNew {
         edits: Vec<(&Selection, &str)>,
         proxy: Arc<LapceProxy>,
         edit_type: EditType,
+    ) -> RopeDelta {
+        self.edit_regions(edits, proxy, edit_type)
+    }
+
+    /// Replaces every region of each selection with its content, as one
+    /// undo group with the edits of the same type before it.
+    pub(crate) fn edit_regions(
+        &mut self,
+        edits: Vec<(&Selection, &str)>,
+        proxy: Arc<LapceProxy>,
+        edit_type: EditType,
     ) -> RopeDelta {
         let mut builder = DeltaBuilder::new(self.len());
+        if self.large_file.is_some() || !self.editable {
+            return builder.build();
+        }
         let mut interval_rope = Vec::new();
         for (selection, content) in edits {
             let rope = Rope::from(content);
@@ -1384,16 +1756,7 @@ impl BufferNew {
                 interval_rope.push((region.min(), region.max(), rope.clone()));
             }
         }
-        interval_rope.sort_by(|a, b| {
-            if a.0 == b.0 && a.1 == b.1 {
-                Ordering::Equal
-            } else if a.1 == b.0 {
-                Ordering::Less
-            } else {
-                a.1.cmp(&b.0)
-            }
-        });
-        for (start, end, rope) in interval_rope.into_iter() {
+        for (start, end, rope) in non_overlapping(interval_rope) {
             builder.replace(start..end, rope);
         }
         let delta = builder.build();
@@ -1428,7 +1791,7 @@ impl BufferNew {
     }
 
     pub fn do_undo(&mut self, proxy: Arc<LapceProxy>) -> Option<RopeDelta> {
-        if self.cur_undo > 1 {
+        if self.cur_undo > 1 && self.editable {
             self.cur_undo -= 1;
             self.undos.insert(self.live_undos[self.cur_undo]);
             self.last_edit_type = EditType::Undo;
@@ -1439,7 +1802,7 @@ impl BufferNew {
     }
 
     pub fn do_redo(&mut self, proxy: Arc<LapceProxy>) -> Option<RopeDelta> {
-        if self.cur_undo < self.live_undos.len() {
+        if self.cur_undo < self.live_undos.len() && self.editable {
             self.undos.remove(&self.live_undos[self.cur_undo]);
             self.cur_undo += 1;
             self.last_edit_type = EditType::Redo;
@@ -1594,6 +1957,80 @@ impl BufferNew {
     }
 }
 
+/// The replacements in order, those that overlap one before them dropped,
+/// as two cursors whose backspaces would delete the same character.
+fn non_overlapping(
+    mut intervals: Vec<(usize, usize, Rope)>,
+) -> Vec<(usize, usize, Rope)> {
+    intervals.sort_by_key(|(start, end, _)| (*start, *end));
+    let mut end = 0;
+    intervals
+        .into_iter()
+        .filter(|(start, interval_end, _)| {
+            if *start < end {
+                return false;
+            }
+            end = *interval_end;
+            true
+        })
+        .collect()
+}
+
+/// Where in the text after `delta` it inserted, or deleted at.
+fn changed_ranges(delta: &RopeDelta) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let (mut old, mut new) = (0, 0);
+    for element in delta.els.iter() {
+        match element {
+            DeltaElement::Copy(start, end) => {
+                // a replacement is already there from its insert
+                if *start > old && ranges.last().map(|r| r.1) != Some(new) {
+                    ranges.push((new, new));
+                }
+                new += end - start;
+                old = *end;
+            }
+            DeltaElement::Insert(node) => {
+                ranges.push((new, new + node.len()));
+                new += node.len();
+            }
+        }
+    }
+    if old < delta.base_len {
+        ranges.push((new, new));
+    }
+    ranges
+}
+
+/// The edit from `old` to `new` replacing only what's between the lines
+/// they start and end with.
+fn reload_delta(old: &Rope, new: &str) -> RopeDelta {
+    let old_text = old.to_string();
+    let old_lines: Vec<&str> = old_text.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let len = |lines: &[&str]| lines.iter().map(|line| line.len()).sum::<usize>();
+    let start = len(&old_lines[..prefix]);
+    let old_end = old_text.len() - len(&old_lines[old_lines.len() - suffix..]);
+    let new_end = new.len() - len(&new_lines[new_lines.len() - suffix..]);
+
+    let mut builder = DeltaBuilder::new(old.len());
+    if start != old_end || start != new_end {
+        builder.replace(start..old_end, Rope::from(&new[start..new_end]));
+    }
+    builder.build()
+}
+
 fn shuffle_tombstones(
     text: &Rope,
     tombstones: &Rope,
@@ -1741,19 +2178,6 @@ impl<'a> WordCursor<'a> {
         return candidate;
     }
 
-    pub fn match_pairs(&mut self) -> Option<usize> {
-        let c = self.inner.peek_next_codepoint()?;
-        let other = matching_char(c)?;
-        let left = matching_pair_direction(other)?;
-        if left {
-            self.previous_unmatched(other)
-        } else {
-            self.inner.next_codepoint();
-            let offset = self.next_unmatched(other)?;
-            Some(offset - 1)
-        }
-    }
-
     pub fn next_unmatched(&mut self, c: char) -> Option<usize> {
         let other = matching_char(c)?;
         let mut n = 0;
@@ -2205,13 +2629,40 @@ pub struct DiffHunk {
 //    }
 //}
 
-fn language_id_from_path(path: &str) -> Option<&str> {
-    let path_buf = PathBuf::from_str(path).ok()?;
-    Some(match path_buf.extension()?.to_str()? {
-        "rs" => "rust",
-        "go" => "go",
-        _ => return None,
-    })
+/// Lays `semantic` over `syntax`, the semantic tokens winning where they
+/// overlap.
+pub fn merge_styles(syntax: &Spans<Style>, semantic: &Spans<Style>) -> Spans<Style> {
+    let semantic: Vec<(usize, usize, Style)> = semantic
+        .iter()
+        .map(|(iv, style)| (iv.start(), iv.end(), style.clone()))
+        .collect();
+    let mut spans = semantic.clone();
+    let mut first = 0;
+    for (iv, style) in syntax.iter() {
+        let (mut start, end) = (iv.start(), iv.end());
+        while first < semantic.len() && semantic[first].1 <= start {
+            first += 1;
+        }
+        for (semantic_start, semantic_end, _) in &semantic[first..] {
+            if *semantic_start >= end {
+                break;
+            }
+            if start < *semantic_start {
+                spans.push((start, *semantic_start, style.clone()));
+            }
+            start = start.max(*semantic_end);
+        }
+        if start < end {
+            spans.push((start, end, style.clone()));
+        }
+    }
+    spans.sort_by_key(|(start, _, _)| *start);
+
+    let mut builder = SpansBuilder::new(syntax.len());
+    for (start, end, style) in spans {
+        builder.add_span(Interval::new(start, end), style);
+    }
+    builder.build()
 }
 
 fn semantic_tokens_lengend(
@@ -2262,3 +2713,45 @@ pub fn grapheme_column_width(s: &str) -> usize {
     }
     UnicodeWidthStr::width(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_ranges() {
+        let mut builder = DeltaBuilder::new(10);
+        builder.replace(Interval::new(2, 4), Rope::from("abc"));
+        builder.delete(Interval::new(6, 8));
+        let delta = builder.build();
+        assert_eq!(vec![(2, 5), (7, 7)], changed_ranges(&delta));
+
+        let mut builder = DeltaBuilder::new(10);
+        builder.delete(Interval::new(8, 10));
+        assert_eq!(vec![(8, 8)], changed_ranges(&builder.build()));
+    }
+
+    #[test]
+    fn test_non_overlapping() {
+        let intervals = vec![
+            (4, 6, Rope::from("c")),
+            (0, 0, Rope::from("a")),
+            (5, 7, Rope::from("d")),
+            (0, 0, Rope::from("b")),
+            (6, 6, Rope::from("e")),
+        ];
+        let intervals: Vec<(usize, usize, String)> = non_overlapping(intervals)
+            .into_iter()
+            .map(|(start, end, rope)| (start, end, String::from(&rope)))
+            .collect();
+        assert_eq!(
+            vec![
+                (0, 0, "a".to_string()),
+                (0, 0, "b".to_string()),
+                (4, 6, "c".to_string()),
+                (6, 6, "e".to_string()),
+            ],
+            intervals
+        );
+    }
+}
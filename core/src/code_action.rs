@@ -1,5 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
+use anyhow::{anyhow, Result};
 use druid::{
     BoxConstraints, Color, Command, Data, Env, Event, EventCtx, FontDescriptor,
     FontFamily, LayoutCtx, LifeCycle, LifeCycleCtx, PaintCtx, Point, Rect,
@@ -11,12 +12,11 @@ use lsp_types::{
 };
 
 use crate::{
-    buffer::EditType,
     command::{LapceCommand, LapceUICommand, LAPCE_UI_COMMAND},
     config::LapceTheme,
     data::{EditorContent, LapceMainSplitData, LapceTabData},
     keypress::{KeyPressData, KeyPressFocus},
-    movement::{Movement, Selection},
+    movement::Movement,
     proxy::LapceProxy,
     state::Mode,
     theme::OldLapceTheme,
@@ -113,48 +113,12 @@ impl CodeActionData {
                 let code_actions =
                     buffer.code_actions.get(&prev_offset).unwrap_or(&empty_vec);
 
-                let action = &code_actions[self.main_split.current_code_actions];
-                match action {
-                    CodeActionOrCommand::Command(cmd) => {}
-                    CodeActionOrCommand::CodeAction(action) => {
-                        if let Some(edit) = action.edit.as_ref() {
-                            if let Some(edits) = workspce_edits(edit) {
-                                if let Some(edits) =
-                                    edits.get(&Url::from_file_path(&path).unwrap())
-                                {
-                                    let path = path.clone();
-                                    let buffer = self
-                                        .main_split
-                                        .open_files
-                                        .get_mut(&path)
-                                        .unwrap();
-                                    let edits: Vec<(Selection, String)> = edits
-                                        .iter()
-                                        .map(|edit| {
-                                            let selection = Selection::region(
-                                                buffer.offset_of_position(
-                                                    &edit.range.start,
-                                                ),
-                                                buffer.offset_of_position(
-                                                    &edit.range.end,
-                                                ),
-                                            );
-                                            (selection, edit.new_text.clone())
-                                        })
-                                        .collect();
-                                    self.main_split.edit(
-                                        ctx,
-                                        &path,
-                                        edits
-                                            .iter()
-                                            .map(|(s, c)| (s, c.as_ref()))
-                                            .collect(),
-                                        EditType::Other,
-                                    );
-                                }
-                            }
-                        }
-                    }
+                let buffer_id = buffer.id;
+                if let Some(action) = code_actions
+                    .get(self.main_split.current_code_actions)
+                    .cloned()
+                {
+                    self.main_split.run_code_action(ctx, buffer_id, &action);
                 }
             }
             EditorContent::None => {}
@@ -358,44 +322,95 @@ impl Widget<LapceTabData> for CodeAction {
     }
 }
 
-fn workspce_edits(edit: &WorkspaceEdit) -> Option<HashMap<Url, Vec<TextEdit>>> {
-    if let Some(changes) = edit.changes.as_ref() {
-        return Some(changes.clone());
-    }
-
-    let changes = edit.document_changes.as_ref()?;
-    let edits = match changes {
-        DocumentChanges::Edits(edits) => edits
-            .iter()
-            .map(|e| {
-                (
-                    e.text_document.uri.clone(),
-                    e.edits
-                        .iter()
-                        .map(|e| match e {
-                            OneOf::Left(e) => e.clone(),
-                            OneOf::Right(e) => e.text_edit.clone(),
-                        })
-                        .collect(),
-                )
-            })
-            .collect::<HashMap<Url, Vec<TextEdit>>>(),
-        DocumentChanges::Operations(ops) => ops
+/// The text edits of a workspace edit by file, each with the version of the
+/// document they were made for when the server gave one. Files aren't
+/// created, renamed or deleted, so an edit that does any of that is refused
+/// as a whole rather than applied in part.
+pub fn workspace_edits(
+    edit: &WorkspaceEdit,
+) -> Result<HashMap<Url, (Option<i32>, Vec<TextEdit>)>> {
+    let mut edits: HashMap<Url, (Option<i32>, Vec<TextEdit>)> = HashMap::new();
+    let document_edits = match edit.document_changes.as_ref() {
+        Some(DocumentChanges::Edits(edits)) => edits.iter().collect(),
+        Some(DocumentChanges::Operations(ops)) => ops
             .iter()
-            .filter_map(|o| match o {
-                DocumentChangeOperation::Op(op) => None,
-                DocumentChangeOperation::Edit(e) => Some((
-                    e.text_document.uri.clone(),
-                    e.edits
-                        .iter()
-                        .map(|e| match e {
-                            OneOf::Left(e) => e.clone(),
-                            OneOf::Right(e) => e.text_edit.clone(),
-                        })
-                        .collect(),
-                )),
+            .map(|op| match op {
+                DocumentChangeOperation::Op(op) => {
+                    Err(anyhow!("unsupported resource operation {:?}", op))
+                }
+                DocumentChangeOperation::Edit(edit) => Ok(edit),
             })
-            .collect::<HashMap<Url, Vec<TextEdit>>>(),
+            .collect::<Result<Vec<_>>>()?,
+        None => {
+            for (url, changes) in edit.changes.iter().flatten() {
+                edits
+                    .entry(url.clone())
+                    .or_insert((None, Vec::new()))
+                    .1
+                    .extend(changes.iter().cloned());
+            }
+            return Ok(edits);
+        }
+    };
+    for edit in document_edits {
+        let (_, changes) = edits
+            .entry(edit.text_document.uri.clone())
+            .or_insert((edit.text_document.version, Vec::new()));
+        changes.extend(edit.edits.iter().map(|e| match e {
+            OneOf::Left(e) => e.clone(),
+            OneOf::Right(e) => e.text_edit.clone(),
+        }));
+    }
+    Ok(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{
+        CreateFile, OptionalVersionedTextDocumentIdentifier, Position, Range,
+        ResourceOp, TextDocumentEdit,
     };
-    Some(edits)
+
+    use super::*;
+
+    fn document_edit(uri: &Url, version: Option<i32>) -> TextDocumentEdit {
+        TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version,
+            },
+            edits: vec![OneOf::Left(TextEdit {
+                range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                new_text: "a".to_string(),
+            })],
+        }
+    }
+
+    #[test]
+    fn test_workspace_edits() {
+        let uri = Url::parse("file:///tmp/a.rs").unwrap();
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Edit(document_edit(&uri, Some(3))),
+                DocumentChangeOperation::Edit(document_edit(&uri, Some(3))),
+            ])),
+            ..Default::default()
+        };
+        let edits = workspace_edits(&edit).unwrap();
+        assert_eq!(edits[&uri].0, Some(3));
+        assert_eq!(edits[&uri].1.len(), 2);
+
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: uri.clone(),
+                    options: None,
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Edit(document_edit(&uri, None)),
+            ])),
+            ..Default::default()
+        };
+        assert!(workspace_edits(&edit).is_err());
+    }
 }
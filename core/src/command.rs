@@ -4,10 +4,28 @@ use alacritty_terminal::ansi::CursorShape;
 use anyhow::Result;
 use druid::{Point, Rect, Selector, Size, WidgetId};
 use indexmap::IndexMap;
-use lapce_proxy::terminal::TermId;
+use lapce_proxy::backup::BackupManifest;
+use lapce_proxy::buffer::{ApplyFileEditsResponse, ExternalChange};
+use lapce_proxy::comment::ToggleCommentResponse;
+use lapce_proxy::conflicts::ConflictResolution;
+use lapce_proxy::dispatch::{BufferLinesResponse, ReferenceLocation};
+use lapce_proxy::git::{BranchInfo, GitStatus};
+use lapce_proxy::indent::IndentStyle;
+use lapce_proxy::line_ending::LineEnding;
+use lapce_proxy::lsp::{
+    DiagnosticCounts, DocumentHighlightResponse, DocumentLinksResponse,
+    FoldingRangesResponse, InlayHintsResponse, LineStyles, LspCapabilities,
+    SignatureHelpResponse, WorkProgress,
+};
+use lapce_proxy::replace::{ApplyReplaceResponse, ReplacePreview};
+use lapce_proxy::search::SearchMatch;
+use lapce_proxy::settings::ConfigChange;
+use lapce_proxy::symbols::EnclosingScopesResponse;
+use lapce_proxy::terminal::{TermId, TerminalInfo};
 use lsp_types::{
-    CodeActionResponse, CompletionItem, CompletionResponse, Location, Position,
-    PublishDiagnosticsParams, Range, TextEdit,
+    CodeAction, CodeActionResponse, CompletionItem, CompletionResponse, Location,
+    Position, PrepareRenameResponse, PublishDiagnosticsParams, Range, TextEdit,
+    WorkspaceEdit,
 };
 use serde_json::Value;
 use strum::{self, EnumMessage, IntoEnumIterator};
@@ -19,7 +37,7 @@ use xi_rope::spans::Spans;
 use crate::{
     buffer::BufferId,
     buffer::{InvalLines, Style},
-    data::EditorKind,
+    data::{EditReply, EditorKind},
     editor::{EditorLocation, EditorLocationNew, HighlightTextLayout},
     movement::{LinePosition, Movement},
     palette::{NewPaletteItem, PaletteType},
@@ -42,6 +60,42 @@ pub struct LapceCommandNew {
 
 impl LapceCommandNew {
     pub const PALETTE: &'static str = "palette";
+
+    /// Its name in the palette, made of its id when it has none.
+    pub fn title(&self) -> String {
+        self.palette_desc
+            .clone()
+            .unwrap_or_else(|| title_case(&self.cmd))
+    }
+
+    /// What it's about, shown before its title: the plugin of a plugin's
+    /// command, what comes before the `.` of an id like `palette.line`, or
+    /// else what it's run on.
+    pub fn category(&self) -> String {
+        match &self.target {
+            CommandTarget::Plugin(plugin) => plugin.clone(),
+            _ if self.cmd.contains('.') => {
+                title_case(self.cmd.split('.').next().unwrap_or(""))
+            }
+            CommandTarget::Workbench => "Workbench".to_string(),
+            CommandTarget::Focus => "Editor".to_string(),
+        }
+    }
+}
+
+/// `split_move_right` as `Split Move Right`.
+fn title_case(id: &str) -> String {
+    id.split(|c| c == '_' || c == '.')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -89,6 +143,14 @@ pub enum LapceWorkbenchCommand {
     #[strum(message = "Open Folder")]
     OpenFolder,
 
+    #[strum(serialize = "connect_ssh_host")]
+    #[strum(message = "Connect to SSH Host")]
+    ConnectSshHost,
+
+    #[strum(serialize = "show_proxy_stats")]
+    #[strum(message = "Show Proxy Connection Stats")]
+    ShowProxyStats,
+
     #[strum(serialize = "change_theme")]
     #[strum(message = "Change Theme")]
     ChangeTheme,
@@ -121,6 +183,78 @@ pub enum LapceWorkbenchCommand {
     #[strum(message = "Reload Window")]
     ReloadWindow,
 
+    #[strum(serialize = "restart_lsp_server")]
+    #[strum(message = "LSP: Restart Server")]
+    RestartLspServer,
+
+    #[strum(serialize = "rebuild_file_index")]
+    #[strum(message = "Rebuild File Index")]
+    RebuildFileIndex,
+
+    #[strum(serialize = "change_line_ending_lf")]
+    #[strum(message = "Change Line Ending to LF")]
+    ChangeLineEndingToLf,
+
+    #[strum(serialize = "change_line_ending_crlf")]
+    #[strum(message = "Change Line Ending to CRLF")]
+    ChangeLineEndingToCrlf,
+
+    #[strum(serialize = "save_copy_as")]
+    #[strum(message = "Save a Copy As...")]
+    SaveCopyAs,
+
+    #[strum(serialize = "trim_trailing_whitespace")]
+    #[strum(message = "Trim Whitespace in File")]
+    TrimTrailingWhitespace,
+
+    #[strum(serialize = "toggle_read_only")]
+    #[strum(message = "Toggle Read-only")]
+    ToggleReadOnly,
+
+    #[strum(serialize = "restore_unsaved_changes")]
+    #[strum(message = "Restore Unsaved Changes")]
+    RestoreUnsavedChanges,
+
+    #[strum(serialize = "discard_unsaved_changes")]
+    #[strum(message = "Discard Unsaved Changes")]
+    DiscardUnsavedChanges,
+
+    #[strum(serialize = "reload_from_disk")]
+    #[strum(message = "Reload File from Disk")]
+    ReloadFromDisk,
+
+    #[strum(serialize = "overwrite_on_disk")]
+    #[strum(message = "Save and Overwrite the File on Disk")]
+    OverwriteOnDisk,
+
+    #[strum(serialize = "git_checkout")]
+    #[strum(message = "Git: Checkout...")]
+    GitCheckout,
+
+    #[strum(serialize = "git_create_branch")]
+    #[strum(message = "Git: Create Branch...")]
+    GitCreateBranch,
+
+    #[strum(serialize = "git_stash")]
+    #[strum(message = "Git: Stash Changes")]
+    GitStash,
+
+    #[strum(serialize = "git_stash_include_untracked")]
+    #[strum(message = "Git: Stash Changes Including Untracked")]
+    GitStashIncludeUntracked,
+
+    #[strum(serialize = "git_stash_pop")]
+    #[strum(message = "Git: Pop Latest Stash")]
+    GitStashPop,
+
+    #[strum(serialize = "run_task")]
+    #[strum(message = "Tasks: Run Task...")]
+    RunTask,
+
+    #[strum(serialize = "git_mark_resolved")]
+    #[strum(message = "Git: Mark File as Resolved")]
+    GitMarkResolved,
+
     #[strum(serialize = "palette.line")]
     PaletteLine,
 
@@ -130,12 +264,25 @@ pub enum LapceWorkbenchCommand {
     #[strum(serialize = "palette.symbol")]
     PaletteSymbol,
 
+    #[strum(serialize = "palette.workspace_symbol")]
+    #[strum(message = "Go to Symbol in Workspace")]
+    PaletteWorkspaceSymbol,
+
     #[strum(serialize = "palette.command")]
     PaletteCommand,
 
     #[strum(serialize = "palette.workspace")]
+    #[strum(message = "Open Recent Workspace")]
     PaletteWorkspace,
 
+    #[strum(serialize = "palette.closed_editor")]
+    #[strum(message = "Reopen Closed Editor")]
+    PaletteClosedEditor,
+
+    #[strum(serialize = "reopen_last_closed")]
+    #[strum(message = "Reopen Last Closed Editor")]
+    ReopenLastClosed,
+
     #[strum(serialize = "toggle_terminal")]
     ToggleTerminal,
 
@@ -163,6 +310,16 @@ pub enum LapceCommand {
     CodeActionsCancel,
     #[strum(serialize = "palette.cancel")]
     PaletteCancel,
+    #[strum(serialize = "palette.toggle_search_case_sensitive")]
+    ToggleSearchCaseSensitive,
+    #[strum(serialize = "palette.toggle_search_whole_word")]
+    ToggleSearchWholeWord,
+    #[strum(serialize = "palette.toggle_search_regex")]
+    ToggleSearchRegex,
+    #[strum(serialize = "palette.replace")]
+    PaletteReplace,
+    #[strum(serialize = "palette.drop_replace_match")]
+    PaletteDropReplaceMatch,
     #[strum(serialize = "delete_backward")]
     DeleteBackward,
     #[strum(serialize = "delete_foreward")]
@@ -173,6 +330,36 @@ pub enum LapceCommand {
     DeleteVisual,
     #[strum(serialize = "delete_operator")]
     DeleteOperator,
+    #[strum(serialize = "change_operator")]
+    ChangeOperator,
+    #[strum(serialize = "yank_operator")]
+    YankOperator,
+    #[strum(serialize = "indent_operator")]
+    IndentOperator,
+    #[strum(serialize = "outdent_operator")]
+    OutdentOperator,
+    #[strum(serialize = "repeat_last_change")]
+    RepeatLastChange,
+    #[strum(serialize = "select_next_occurrence")]
+    SelectNextOccurrence,
+    #[strum(serialize = "select_all_occurrences")]
+    SelectAllOccurrences,
+    #[strum(serialize = "insert_cursor_above")]
+    InsertCursorAbove,
+    #[strum(serialize = "insert_cursor_below")]
+    InsertCursorBelow,
+    #[strum(serialize = "toggle_column_selection")]
+    ToggleColumnSelection,
+    #[strum(serialize = "fold")]
+    Fold,
+    #[strum(serialize = "unfold")]
+    Unfold,
+    #[strum(serialize = "fold_recursively")]
+    FoldRecursively,
+    #[strum(serialize = "fold_all")]
+    FoldAll,
+    #[strum(serialize = "unfold_all")]
+    UnfoldAll,
     #[strum(serialize = "delete_word_backward")]
     DeleteWordBackward,
     #[strum(serialize = "delete_to_beginning_of_line")]
@@ -223,12 +410,32 @@ pub enum LapceCommand {
     SplitUp,
     #[strum(serialize = "split_down")]
     SplitDown,
+    #[strum(serialize = "split_move_right")]
+    SplitMoveRight,
+    #[strum(serialize = "split_move_left")]
+    SplitMoveLeft,
+    #[strum(serialize = "split_move_up")]
+    SplitMoveUp,
+    #[strum(serialize = "split_move_down")]
+    SplitMoveDown,
+    #[strum(serialize = "split_rotate")]
+    SplitRotate,
+    #[strum(serialize = "split_increase")]
+    SplitIncrease,
+    #[strum(serialize = "split_decrease")]
+    SplitDecrease,
     #[strum(serialize = "insert_mode")]
     InsertMode,
     #[strum(serialize = "insert_first_non_blank")]
     InsertFirstNonBlank,
     #[strum(serialize = "toggle_comment")]
     ToggleComment,
+    #[strum(serialize = "resolve_conflict_ours")]
+    ResolveConflictOurs,
+    #[strum(serialize = "resolve_conflict_theirs")]
+    ResolveConflictTheirs,
+    #[strum(serialize = "resolve_conflict_both")]
+    ResolveConflictBoth,
     #[strum(serialize = "normal_mode")]
     NormalMode,
     #[strum(serialize = "toggle_visual_mode")]
@@ -247,6 +454,8 @@ pub enum LapceCommand {
     GetReferences,
     #[strum(serialize = "insert_new_line")]
     InsertNewLine,
+    #[strum(serialize = "insert_tab")]
+    InsertTab,
     #[strum(serialize = "word_backward")]
     WordBackward,
     #[strum(serialize = "word_foward")]
@@ -281,6 +490,18 @@ pub enum LapceCommand {
     CenterOfWindow,
     #[strum(serialize = "goto_definition")]
     GotoDefinition,
+    #[strum(serialize = "rename_symbol")]
+    RenameSymbol,
+    #[strum(serialize = "goto_implementation")]
+    GotoImplementation,
+    #[strum(serialize = "goto_type_definition")]
+    GotoTypeDefinition,
+    #[strum(serialize = "goto_declaration")]
+    GotoDeclaration,
+    #[strum(serialize = "expand_selection")]
+    ExpandSelection,
+    #[strum(serialize = "shrink_selection")]
+    ShrinkSelection,
     #[strum(serialize = "jump_location_backward")]
     JumpLocationBackward,
     #[strum(serialize = "jump_location_forward")]
@@ -291,6 +512,8 @@ pub enum LapceCommand {
     PreviousError,
     #[strum(serialize = "document_formatting")]
     DocumentFormatting,
+    #[strum(serialize = "format_selection")]
+    FormatSelection,
     #[strum(serialize = "save")]
     Save,
     #[strum(serialize = "show_code_actions")]
@@ -368,12 +591,33 @@ pub enum EnsureVisiblePosition {
 #[derive(Debug)]
 pub enum LapceUICommand {
     InitTerminalPanel(bool),
+    /// Tabs for the shells the proxy kept from an earlier core.
+    RestoreTerminals(Vec<TerminalInfo>),
     ReloadConfig,
     LoadBuffer {
         path: PathBuf,
         content: String,
+        language_id: String,
+        /// The lines of the file, if it's a large file.
+        large_file: Option<usize>,
+        /// The size of the file, if it's a binary file.
+        binary: Option<u64>,
+        line_ending: LineEnding,
+        indent: IndentStyle,
+        editable: bool,
         locations: Vec<(WidgetId, EditorLocationNew)>,
     },
+    AppendBufferLines(PathBuf, usize, BufferLinesResponse),
+    SetLineEnding(PathBuf, LineEnding),
+    SetBufferEditable(PathBuf, bool),
+    /// The proxy saved a buffer by itself, at a rev.
+    BufferSaved(BufferId, u64),
+    /// The backups of unsaved changes left by the last session.
+    UnsavedBackups(Vec<BackupManifest>),
+    /// The settings the proxy merged, replacing the tab's.
+    ConfigChanged(ConfigChange),
+    /// The path, the backup id and the text to put in the buffer.
+    RestoreBackup(PathBuf, String, String),
     LoadBufferAndGoToPosition {
         path: PathBuf,
         content: String,
@@ -385,8 +629,31 @@ pub enum LapceUICommand {
     OpenFile(PathBuf),
     CancelCompletion(usize),
     ResolveCompletion(BufferId, u64, usize, CompletionItem),
+    /// The selection range chains for the offsets expand selection asked
+    /// for, innermost first.
+    ExpandSelection(BufferId, u64, Vec<usize>, Vec<Vec<Range>>),
     UpdateCompletion(usize, String, CompletionResponse),
+    CompletionItemResolved(usize, (usize, usize), CompletionItem),
     UpdateCodeActions(PathBuf, u64, usize, CodeActionResponse),
+    RunCodeAction(BufferId, CodeAction),
+    ApplyWorkspaceEdit(WorkspaceEdit, EditReply),
+    /// The edits of the open buffers of a workspace edit whose files on disk
+    /// were edited, with what puts those back if the buffers can't be.
+    ApplyBufferEdits(
+        Vec<(PathBuf, u64, Vec<TextEdit>)>,
+        ApplyFileEditsResponse,
+        EditReply,
+    ),
+    PrepareRename(PathBuf, u64, usize, PrepareRenameResponse),
+    RenameSymbol(BufferId, PathBuf, u64, Position, String),
+    /// The edit of a rename, with the revs of the open buffers when it was
+    /// asked for.
+    ApplyRename(HashMap<PathBuf, u64>, WorkspaceEdit),
+    /// A file or directory was renamed, with the edits the servers want
+    /// made for it.
+    PathRenamed(PathBuf, PathBuf, Vec<WorkspaceEdit>),
+    ApplyToggleComment(PathBuf, WidgetId, ToggleCommentResponse),
+    ApplyConflictResolution(PathBuf, ConflictResolution),
     CancelPalette,
     ShowCodeActions,
     CancelCodeActions,
@@ -394,8 +661,10 @@ pub enum LapceUICommand {
     FocusSourceControl,
     FocusEditor,
     RunPalette(Option<PaletteType>),
-    RunPaletteReferences(Vec<EditorLocationNew>),
+    RunPaletteReferences(Vec<ReferenceLocation>),
+    RunPaletteRename(BufferId, PathBuf, u64, Position, String),
     UpdatePaletteItems(String, Vec<NewPaletteItem>),
+    ExecutePluginCommand(String),
     FilterPaletteItems(String, String, Vec<NewPaletteItem>),
     UpdateWindowOrigin,
     RequestLayout,
@@ -415,9 +684,11 @@ pub enum LapceUICommand {
     RequestPaintRect(Rect),
     ApplyEdits(usize, u64, Vec<TextEdit>),
     ApplyEditsAndSave(usize, u64, Result<Value>),
+    DocumentFormat(PathBuf, u64, Result<Value>),
     DocumentFormatAndSave(PathBuf, u64, Result<Value>),
+    WillSaveEdits(PathBuf, u64, Result<Value>),
     BufferSave(PathBuf, u64),
-    UpdateSemanticTokens(BufferId, PathBuf, u64, Vec<(usize, usize, String)>),
+    UpdateSemanticTokens(BufferId, PathBuf, u64, Vec<LineStyles>),
     UpdateHighlights(BufferId, u64, Vec<(usize, usize, Highlight)>),
     UpdateTerminalTitle(TermId, String),
     UpdateStyle {
@@ -425,7 +696,11 @@ pub enum LapceUICommand {
         path: PathBuf,
         rev: u64,
         highlights: Spans<Style>,
-        semantic_tokens: bool,
+        /// Set when `highlights` has new semantic tokens merged in.
+        semantic_styles: Option<Spans<Style>>,
+        /// The lines, from and to, whose highlights a reparse changed, the
+        /// others being as the buffer has them. None for all of them.
+        changed_lines: Option<Vec<(usize, usize)>>,
     },
     UpdateSyntaxTree {
         id: BufferId,
@@ -437,8 +712,24 @@ pub enum LapceUICommand {
     UpdateBufferLineChanges(BufferId, u64, HashMap<usize, char>),
     UpdateLineChanges(BufferId),
     PublishDiagnostics(PublishDiagnosticsParams),
+    UpdateDiagnosticsCounts(PathBuf, DiagnosticCounts),
+    UpdateWorkProgress(WorkProgress),
+    UpdateServerCapabilities(String, LspCapabilities),
+    UpdateSignature(SignatureHelpResponse),
+    UpdateInlayHints(PathBuf, InlayHintsResponse),
+    UpdateEnclosingScopes(PathBuf, EnclosingScopesResponse),
+    GlobalSearchResults(u64, Vec<SearchMatch>, bool),
+    UpdateReplacePreview(String, ReplacePreview),
+    ApplyReplace(u64, Vec<usize>),
+    ApplyReplaceResponse(ApplyReplaceResponse),
+    RefreshInlayHints,
+    UpdateDocumentHighlights(DocumentHighlightResponse),
+    UpdateDocumentLinks(PathBuf, DocumentLinksResponse),
+    UpdateFoldingRanges(PathBuf, FoldingRangesResponse),
     UpdateDiffFiles(Vec<PathBuf>),
+    UpdateGitStatus(GitStatus, BranchInfo),
     ReloadBuffer(BufferId, u64, String),
+    BufferExternalChange(BufferId, Option<ExternalChange>),
     EnsureVisible((Rect, (f64, f64), Option<EnsureVisiblePosition>)),
     EnsureRectVisible(Rect),
     EnsureCursorVisible(Option<EnsureVisiblePosition>),
@@ -452,7 +743,10 @@ pub enum LapceUICommand {
     SplitTerminalClose(TermId, WidgetId, Option<WidgetId>),
     SplitEditor(bool, WidgetId),
     SplitEditorMove(SplitMoveDirection, WidgetId),
+    SplitEditorSwap(SplitMoveDirection, WidgetId),
     SplitEditorExchange(WidgetId),
+    SplitEditorRotate(WidgetId),
+    SplitEditorResize(WidgetId, f64),
     SplitEditorClose(WidgetId),
     Split(bool),
     SplitExchange,
@@ -461,10 +755,12 @@ pub enum LapceUICommand {
     JumpToPosition(EditorKind, Position),
     JumpToLine(EditorKind, usize),
     JumpToLocation(EditorKind, EditorLocationNew),
+    /// Drops the closed editors of files that are gone.
+    PruneClosedEditors(Vec<PathBuf>),
     TerminalJumpToLine(i32),
     GoToLocationNew(WidgetId, EditorLocationNew),
     GotoReference(WidgetId, usize, EditorLocationNew),
     GotoDefinition(WidgetId, usize, EditorLocationNew),
-    PaletteReferences(usize, Vec<Location>),
+    PaletteReferences(usize, Vec<ReferenceLocation>),
     GotoLocation(Location),
 }
@@ -0,0 +1,57 @@
+//! How often and how lately the commands were run from the palette, for
+//! those used most to rank above others matching as well.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+const WEEK: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandUsage {
+    pub count: u64,
+    /// In seconds since the epoch.
+    pub last_used: u64,
+}
+
+pub fn record(usage: &mut HashMap<String, CommandUsage>, command: &str, now: u64) {
+    let usage = usage.entry(command.to_string()).or_default();
+    usage.count += 1;
+    usage.last_used = now;
+}
+
+/// What's added to how well a command matches: a point for each use up to
+/// ten, and up to ten more for how lately, fading over a week. A match of
+/// one more character still counts for more.
+pub fn boost(usage: Option<&CommandUsage>, now: u64) -> i64 {
+    let usage = match usage {
+        Some(usage) => usage,
+        None => return 0,
+    };
+    let frequency = usage.count.min(10) as i64;
+    let age = now.saturating_sub(usage.last_used);
+    let recency = 10 - (age * 10 / WEEK).min(10) as i64;
+    frequency + recency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boost() {
+        let mut usage = HashMap::new();
+        assert_eq!(0, boost(usage.get("save"), 0));
+        record(&mut usage, "save", 100);
+        assert_eq!(11, boost(usage.get("save"), 100));
+        // fading over the week
+        assert_eq!(6, boost(usage.get("save"), 100 + WEEK / 2));
+        assert_eq!(1, boost(usage.get("save"), 100 + 2 * WEEK));
+
+        for _ in 0..20 {
+            record(&mut usage, "save", 200);
+        }
+        assert_eq!(21, usage["save"].count);
+        assert_eq!(20, boost(usage.get("save"), 200));
+    }
+}
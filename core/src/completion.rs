@@ -1,6 +1,5 @@
-use std::{cmp::Ordering, collections::HashMap, fmt::Display, sync::Arc};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
-use anyhow::Error;
 use bit_vec::BitVec;
 use druid::{
     piet::{Svg, Text, TextAttribute, TextLayoutBuilder},
@@ -13,9 +12,11 @@ use druid::{
     UpdateCtx, Vec2, Widget, WidgetExt, WidgetId, WidgetPod, WindowId,
 };
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
-use itertools::Itertools;
-use lsp_types::{CompletionItem, CompletionItemKind, CompletionResponse, Position};
-use regex::Regex;
+use lapce_proxy::snippet::{Snippet, SnippetVariables};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionResponse, CompletionTextEdit,
+    InsertTextFormat, Position,
+};
 use std::str::FromStr;
 
 use crate::{
@@ -30,211 +31,6 @@ use crate::{
     theme::OldLapceTheme,
 };
 
-#[derive(Debug)]
-pub struct Snippet {
-    elements: Vec<SnippetElement>,
-}
-
-impl Snippet {
-    fn extract_elements(
-        s: &str,
-        pos: usize,
-        escs: Vec<&str>,
-        loose_escs: Vec<&str>,
-    ) -> (Vec<SnippetElement>, usize) {
-        let mut elements = Vec::new();
-        let mut pos = pos;
-        loop {
-            if s.len() == pos {
-                break;
-            } else if let Some((ele, end)) = Self::extract_tabstop(s, pos) {
-                elements.push(ele);
-                pos = end;
-            } else if let Some((ele, end)) = Self::extract_placeholder(s, pos) {
-                elements.push(ele);
-                pos = end;
-            } else if let Some((ele, end)) =
-                Self::extract_text(s, pos, escs.clone(), loose_escs.clone())
-            {
-                elements.push(ele);
-                pos = end;
-            } else {
-                break;
-            }
-        }
-        (elements, pos)
-    }
-
-    fn extract_tabstop(s: &str, pos: usize) -> Option<(SnippetElement, usize)> {
-        for re in &[
-            Regex::new(r#"^\$(\d+)"#).unwrap(),
-            Regex::new(r#"^\$\{(\d+)\}"#).unwrap(),
-        ] {
-            if let Some(caps) = re.captures(&s[pos..]) {
-                let end = pos + re.find(&s[pos..])?.end();
-                let m = caps.get(1)?;
-                let n = m.as_str().parse::<usize>().ok()?;
-                return Some((SnippetElement::Tabstop(n), end));
-            }
-        }
-
-        None
-    }
-
-    fn extract_placeholder(s: &str, pos: usize) -> Option<(SnippetElement, usize)> {
-        let re = Regex::new(r#"^\$\{(\d+):(.*?)\}"#).unwrap();
-        let end = pos + re.find(&s[pos..])?.end();
-
-        let caps = re.captures(&s[pos..])?;
-
-        let tab = caps.get(1)?.as_str().parse::<usize>().ok()?;
-
-        let m = caps.get(2)?;
-        let content = m.as_str();
-        if content == "" {
-            return Some((
-                SnippetElement::PlaceHolder(
-                    tab,
-                    vec![SnippetElement::Text("".to_string())],
-                ),
-                end,
-            ));
-        }
-        let (els, pos) =
-            Self::extract_elements(s, pos + m.start(), vec!["$", "}", "\\"], vec![]);
-        Some((SnippetElement::PlaceHolder(tab, els), pos + 1))
-    }
-
-    fn extract_text(
-        s: &str,
-        pos: usize,
-        escs: Vec<&str>,
-        loose_escs: Vec<&str>,
-    ) -> Option<(SnippetElement, usize)> {
-        let mut s = &s[pos..];
-        let mut ele = "".to_string();
-        let mut end = pos;
-
-        while s.len() > 0 {
-            if s.len() >= 2 {
-                let esc = &s[..2];
-                let mut new_escs = escs.clone();
-                new_escs.extend_from_slice(&loose_escs);
-                let new_escs: Vec<String> =
-                    new_escs.iter().map(|e| format!("\\{}", e)).collect();
-                if new_escs.contains(&esc.to_string()) {
-                    ele = ele + &s[1..2].to_string();
-                    end += 2;
-                    s = &s[2..];
-                    continue;
-                }
-            }
-            if escs.contains(&&s[0..1]) {
-                break;
-            }
-            ele = ele + &s[0..1].to_string();
-            end += 1;
-            s = &s[1..];
-        }
-        if ele.len() == 0 {
-            return None;
-        }
-        Some((SnippetElement::Text(ele), end))
-    }
-
-    pub fn text(&self) -> String {
-        self.elements.iter().map(|e| e.text()).join("")
-    }
-
-    pub fn tabs(&self, pos: usize) -> Vec<(usize, (usize, usize))> {
-        Self::elements_tabs(&self.elements, pos)
-    }
-
-    pub fn elements_tabs(
-        elements: &[SnippetElement],
-        start: usize,
-    ) -> Vec<(usize, (usize, usize))> {
-        let mut tabs = Vec::new();
-        let mut pos = start;
-        for el in elements {
-            match el {
-                SnippetElement::Text(t) => {
-                    pos += t.len();
-                }
-                SnippetElement::PlaceHolder(tab, els) => {
-                    let placeholder_tabs = Self::elements_tabs(els, pos);
-                    let end = pos + els.iter().map(|e| e.len()).sum::<usize>();
-                    tabs.push((*tab, (pos, end)));
-                    tabs.extend_from_slice(&placeholder_tabs);
-                    pos = end;
-                }
-                SnippetElement::Tabstop(tab) => {
-                    tabs.push((*tab, (pos, pos)));
-                }
-            }
-        }
-        tabs
-    }
-}
-
-impl FromStr for Snippet {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (elements, _) = Self::extract_elements(s, 0, vec!["$", "\\"], vec!["}"]);
-        Ok(Snippet { elements })
-    }
-}
-
-impl Display for Snippet {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let text = self.elements.iter().map(|e| e.to_string()).join("");
-        f.write_str(&text)
-    }
-}
-
-#[derive(Debug)]
-pub enum SnippetElement {
-    Text(String),
-    PlaceHolder(usize, Vec<SnippetElement>),
-    Tabstop(usize),
-}
-
-impl SnippetElement {
-    pub fn len(&self) -> usize {
-        match &self {
-            SnippetElement::Text(text) => text.len(),
-            SnippetElement::PlaceHolder(_, elements) => {
-                elements.iter().map(|e| e.len()).sum()
-            }
-            SnippetElement::Tabstop(_) => 0,
-        }
-    }
-
-    pub fn text(&self) -> String {
-        match &self {
-            SnippetElement::Text(t) => t.to_string(),
-            SnippetElement::PlaceHolder(_, elements) => {
-                elements.iter().map(|e| e.text()).join("")
-            }
-            SnippetElement::Tabstop(_) => "".to_string(),
-        }
-    }
-}
-
-impl Display for SnippetElement {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            SnippetElement::Text(text) => f.write_str(text),
-            SnippetElement::PlaceHolder(tab, elements) => {
-                let elements = elements.iter().map(|e| e.to_string()).join("");
-                write!(f, "${{{}:{}}}", tab, elements)
-            }
-            SnippetElement::Tabstop(tab) => write!(f, "${}", tab),
-        }
-    }
-}
-
 #[derive(Clone, PartialEq)]
 pub enum CompletionStatus {
     Inactive,
@@ -252,6 +48,11 @@ pub struct CompletionData {
     pub input: String,
     pub index: usize,
     pub input_items: im::HashMap<String, Arc<Vec<ScoredCompletionItem>>>,
+    /// Items resolved with `completionItem/resolve`, keyed by
+    /// `ScoredCompletionItem::key`. `None` means the request is in flight.
+    pub resolved: im::HashMap<(usize, usize), Option<Arc<CompletionItem>>>,
+    /// Counts received item lists, to give every item a unique key.
+    received: usize,
     empty: Arc<Vec<ScoredCompletionItem>>,
     pub filtered_items: Arc<Vec<ScoredCompletionItem>>,
     pub matcher: Arc<SkimMatcherV2>,
@@ -270,6 +71,8 @@ impl CompletionData {
             buffer_id: BufferId(0),
             input: "".to_string(),
             input_items: im::HashMap::new(),
+            resolved: im::HashMap::new(),
+            received: 0,
             filtered_items: Arc::new(Vec::new()),
             matcher: Arc::new(SkimMatcherV2::default().ignore_case()),
             size: Size::new(400.0, 300.0),
@@ -311,6 +114,68 @@ impl CompletionData {
         self.current_items()[self.index].item.label.as_str()
     }
 
+    /// The highlighted item as the server resolved it, if that already
+    /// happened.
+    pub fn current_resolved_item(&self) -> Option<Arc<CompletionItem>> {
+        let key = self.current_items().get(self.index)?.key;
+        self.resolved.get(&key).cloned().flatten()
+    }
+
+    /// Lazily resolves the highlighted item, so that its documentation and
+    /// `additionalTextEdits` are at hand when it's accepted. Each item is
+    /// only asked for once per completion session.
+    pub fn resolve_current_item(
+        &mut self,
+        proxy: Arc<LapceProxy>,
+        event_sink: ExtEventSink,
+    ) {
+        if self.status == CompletionStatus::Inactive {
+            return;
+        }
+        let scored = match self.current_items().get(self.index) {
+            Some(scored) => scored.clone(),
+            None => return,
+        };
+        if self.resolved.contains_key(&scored.key) {
+            return;
+        }
+        self.resolved.insert(scored.key, None);
+
+        let key = scored.key;
+        let request_id = self.request_id;
+        let completion_widget_id = self.id;
+        proxy.completion_resolve(
+            self.buffer_id,
+            scored.item,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(item) = serde_json::from_value::<CompletionItem>(res) {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::CompletionItemResolved(
+                                request_id, key, item,
+                            ),
+                            Target::Widget(completion_widget_id),
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    pub fn receive_resolved(
+        &mut self,
+        request_id: usize,
+        key: (usize, usize),
+        item: CompletionItem,
+    ) {
+        if self.status == CompletionStatus::Inactive || self.request_id != request_id
+        {
+            return;
+        }
+        self.resolved.insert(key, Some(Arc::new(item)));
+    }
+
     pub fn request(
         &self,
         proxy: Arc<LapceProxy>,
@@ -353,6 +218,7 @@ impl CompletionData {
         self.status = CompletionStatus::Inactive;
         self.input = "".to_string();
         self.input_items.clear();
+        self.resolved.clear();
         self.index = 0;
     }
 
@@ -380,10 +246,14 @@ impl CompletionData {
             CompletionResponse::Array(items) => items,
             CompletionResponse::List(list) => list.items,
         };
+        self.received += 1;
+        let received = self.received;
         let items = items
             .iter()
-            .map(|i| ScoredCompletionItem {
+            .enumerate()
+            .map(|(index, i)| ScoredCompletionItem {
                 item: i.to_owned(),
+                key: (received, index),
                 score: 0,
                 index: 0,
                 indices: Vec::new(),
@@ -427,6 +297,82 @@ impl CompletionData {
     }
 }
 
+/// The edits an accepted completion item makes to the buffer.
+#[derive(Debug)]
+pub struct CompletionEdits {
+    /// `(start, end, text)` replacements in offsets of the text before the
+    /// edit, ordered from the end of the document to the start, so
+    /// applying them one after another keeps the remaining offsets valid.
+    pub edits: Vec<(usize, usize, String)>,
+    /// Position of the primary edit in `edits`.
+    pub primary: usize,
+    /// Set when the item is a snippet; its tab stops are relative to the
+    /// start of the primary edit.
+    pub snippet: Option<Snippet>,
+}
+
+/// Works out the edits of a completion item: the primary `textEdit` (or
+/// `insertText`, or the label) replacing at least `word`, the range of the
+/// word being completed, followed by the `additionalTextEdits` such as
+/// auto-imports. Additional edits that overlap the primary one are
+/// dropped, as the protocol doesn't allow them. The variables of a snippet
+/// are resolved with `variables`.
+pub fn completion_edits(
+    item: &CompletionItem,
+    word: (usize, usize),
+    offset_of_position: impl Fn(&Position) -> usize,
+    variables: &SnippetVariables,
+) -> anyhow::Result<CompletionEdits> {
+    let (start, end, new_text) = match &item.text_edit {
+        Some(CompletionTextEdit::Edit(edit)) => (
+            offset_of_position(&edit.range.start),
+            offset_of_position(&edit.range.end),
+            edit.new_text.as_str(),
+        ),
+        Some(CompletionTextEdit::InsertAndReplace(edit)) => (
+            offset_of_position(&edit.replace.start),
+            offset_of_position(&edit.replace.end),
+            edit.new_text.as_str(),
+        ),
+        None => (
+            word.0,
+            word.1,
+            item.insert_text.as_deref().unwrap_or(&item.label),
+        ),
+    };
+    let start = start.min(word.0);
+    let end = end.max(word.1);
+
+    let (text, snippet) = match item.insert_text_format {
+        Some(InsertTextFormat::Snippet) => {
+            let mut snippet = Snippet::from_str(new_text)?;
+            snippet.resolve_variables(variables);
+            (snippet.text(), Some(snippet))
+        }
+        _ => (new_text.to_string(), None),
+    };
+
+    let mut edits = vec![(0, (start, end, text))];
+    for (i, edit) in item.additional_text_edits.iter().flatten().enumerate() {
+        let edit_start = offset_of_position(&edit.range.start);
+        let edit_end = offset_of_position(&edit.range.end);
+        if edit_start < end && start < edit_end
+            || edit_start == edit_end && edit_start > start && edit_start < end
+        {
+            continue;
+        }
+        edits.push((i + 1, (edit_start, edit_end, edit.new_text.clone())));
+    }
+    edits.sort_by(|(_, a), (_, b)| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+    let primary = edits.iter().position(|(i, _)| *i == 0).unwrap();
+
+    Ok(CompletionEdits {
+        edits: edits.into_iter().map(|(_, edit)| edit).collect(),
+        primary,
+        snippet,
+    })
+}
+
 pub struct CompletionContainer {
     id: WidgetId,
     scroll_id: WidgetId,
@@ -503,6 +449,22 @@ impl Widget<LapceTabData> for CompletionContainer {
                             input.to_owned(),
                             resp.to_owned(),
                         );
+                        completion.resolve_current_item(
+                            data.proxy.clone(),
+                            ctx.get_external_handle(),
+                        );
+                    }
+                    LapceUICommand::CompletionItemResolved(
+                        request_id,
+                        key,
+                        item,
+                    ) => {
+                        let completion = Arc::make_mut(&mut data.completion);
+                        completion.receive_resolved(
+                            *request_id,
+                            *key,
+                            item.to_owned(),
+                        );
                     }
                     LapceUICommand::CancelCompletion(request_id) => {
                         if data.completion.request_id == *request_id {
@@ -757,6 +719,7 @@ impl Widget<LapceTabData> for CompletionNew {
 #[derive(Clone)]
 pub struct ScoredCompletionItem {
     pub item: CompletionItem,
+    key: (usize, usize),
     index: usize,
     score: i64,
     indices: Vec<usize>,
@@ -821,6 +784,7 @@ impl CompletionState {
                 let mut item = ScoredCompletionItem {
                     item: item.to_owned(),
                     score: -1 - index as i64,
+                    key: (0, index),
                     index: index,
                     indices: Vec::new(),
                 };
@@ -882,4 +846,66 @@ mod tests {
             parsed.tabs(0)
         );
     }
+
+    #[test]
+    fn test_completion_edits_with_auto_import() {
+        let text = "fn main() {\n    let map = HashM\n}\n";
+        let mut rope = xi_rope::Rope::from(text);
+        let offset_of_position = |position: &Position| {
+            rope.offset_of_line(position.line as usize) + position.character as usize
+        };
+        // rust-analyzer's resolved item for `HashMap` with an import edit
+        // above the cursor.
+        let item: CompletionItem = serde_json::from_value(serde_json::json!({
+            "label": "HashMap",
+            "kind": 22,
+            "insertTextFormat": 2,
+            "textEdit": {
+                "range": {
+                    "start": { "line": 1, "character": 14 },
+                    "end": { "line": 1, "character": 19 }
+                },
+                "newText": "HashMap::new()$0"
+            },
+            "additionalTextEdits": [{
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 }
+                },
+                "newText": "use std::collections::HashMap;\n\n"
+            }]
+        }))
+        .unwrap();
+
+        let edits = completion_edits(
+            &item,
+            (26, 31),
+            offset_of_position,
+            &SnippetVariables::default(),
+        )
+        .unwrap();
+        assert_eq!(0, edits.primary);
+        assert_eq!(
+            vec![
+                (26, 31, "HashMap::new()".to_string()),
+                (0, 0, "use std::collections::HashMap;\n\n".to_string()),
+            ],
+            edits.edits
+        );
+
+        for (start, end, text) in edits.edits.iter() {
+            rope.edit(*start..*end, text.as_str());
+        }
+        assert_eq!(
+            "use std::collections::HashMap;\n\nfn main() {\n    let map = HashMap::new()\n}\n",
+            String::from(&rope)
+        );
+
+        // The tab stop follows the primary edit down by the import's length.
+        let shift = "use std::collections::HashMap;\n\n".len();
+        assert_eq!(
+            vec![(0, (26 + shift + 14, 26 + shift + 14))],
+            edits.snippet.unwrap().tabs(26 + shift)
+        );
+    }
 }
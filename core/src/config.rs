@@ -7,7 +7,9 @@ use druid::{
     theme, Color, Env, FontDescriptor, FontFamily, Key, Size,
 };
 use hashbrown::HashMap;
+use lapce_proxy::settings::SettingSource;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 
 use crate::{
     data::hex_to_color,
@@ -77,6 +79,11 @@ pub struct LapceConfig {
     pub modal: bool,
     pub color_theme: String,
     pub icon_theme: String,
+    pub workspace_symbol_limit: usize,
+    pub file_search_limit: usize,
+    /// "hide" or "disable" the commands of the palette whose keymaps don't
+    /// apply where it was opened from.
+    pub unavailable_commands: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -85,22 +92,63 @@ pub struct EditorConfig {
     pub font_family: String,
     pub font_size: usize,
     pub line_height: usize,
+    /// How many columns a tab takes.
+    pub tab_width: usize,
+    /// The columns a vertical line is drawn at.
+    pub rulers: Vec<usize>,
+    pub format_on_save: bool,
+    /// How long saving waits for the formatting edits, in milliseconds.
+    pub format_on_save_timeout: u64,
+    /// Languages whose indentation is left to the language server's on type
+    /// formatting instead of the built-in auto-indent.
+    pub server_indent_languages: Vec<String>,
+    /// How big the undo history kept for a file across sessions can get,
+    /// in kilobytes. 0 turns it off.
+    pub undo_history_size: usize,
+    /// Trims the trailing whitespace of the lines edited on save.
+    pub trim_trailing_whitespace: bool,
+    /// Ends the file with a newline on save.
+    pub insert_final_newline: bool,
+}
+
+/// The options of the editor a `[language.<id>]` table sets differently
+/// for the language.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct LanguageConfig {
+    pub tab_width: Option<usize>,
+    pub rulers: Option<Vec<usize>>,
+    pub format_on_save: Option<bool>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
 }
 
 impl EditorConfig {
     pub fn font_family(&self) -> FontFamily {
         FontFamily::new_unchecked(self.font_family.clone())
     }
+
+    pub fn server_indents(&self, language_id: &str) -> bool {
+        self.server_indent_languages
+            .iter()
+            .any(|l| l == language_id)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
     pub lapce: LapceConfig,
     pub editor: EditorConfig,
+    /// What the languages set differently, by language id.
+    #[serde(default)]
+    pub language: std::collections::HashMap<String, LanguageConfig>,
     #[serde(skip)]
     pub theme: HashMap<String, Color>,
     #[serde(skip)]
     pub themes: HashMap<String, HashMap<String, Color>>,
+    /// Where the keys set come from, the others having their default.
+    #[serde(skip)]
+    pub sources: BTreeMap<String, SettingSource>,
 }
 
 impl Config {
@@ -125,6 +173,27 @@ impl Config {
             }
         }
 
+        Self::from_settings(settings)
+    }
+
+    /// The defaults with the settings the proxy merged from the user's and
+    /// the workspace's files over them.
+    pub fn with_overlay(
+        overlay: &serde_json::Value,
+        sources: BTreeMap<String, SettingSource>,
+    ) -> Result<Self> {
+        let mut settings = config::Config::default().with_merged(
+            config::File::from_str(default_settings, config::FileFormat::Toml),
+        )?;
+        let overlay = toml::to_string(&toml::Value::try_from(overlay)?)?;
+        settings
+            .merge(config::File::from_str(&overlay, config::FileFormat::Toml))?;
+        let mut config = Self::from_settings(settings)?;
+        config.sources = sources;
+        Ok(config)
+    }
+
+    fn from_settings(settings: config::Config) -> Result<Self> {
         let mut config: Config = settings.try_into()?;
 
         config.theme = get_theme(default_light_theme)?;
@@ -137,35 +206,63 @@ impl Config {
         Ok(config)
     }
 
+    pub fn tab_width(&self, language_id: &str) -> usize {
+        self.language
+            .get(language_id)
+            .and_then(|language| language.tab_width)
+            .unwrap_or(self.editor.tab_width)
+            .max(1)
+    }
+
+    pub fn rulers(&self, language_id: &str) -> &[usize] {
+        self.language
+            .get(language_id)
+            .and_then(|language| language.rulers.as_deref())
+            .unwrap_or(&self.editor.rulers)
+    }
+
+    pub fn format_on_save(&self, language_id: &str) -> bool {
+        self.language
+            .get(language_id)
+            .and_then(|language| language.format_on_save)
+            .unwrap_or(self.editor.format_on_save)
+    }
+
+    pub fn trims_trailing_whitespace(&self, language_id: &str) -> bool {
+        self.language
+            .get(language_id)
+            .and_then(|language| language.trim_trailing_whitespace)
+            .unwrap_or(self.editor.trim_trailing_whitespace)
+    }
+
+    pub fn inserts_final_newline(&self, language_id: &str) -> bool {
+        self.language
+            .get(language_id)
+            .and_then(|language| language.insert_final_newline)
+            .unwrap_or(self.editor.insert_final_newline)
+    }
+
+    /// Where the value of a key, as in `editor.font-size`, comes from.
+    pub fn source(&self, key: &str) -> SettingSource {
+        self.sources
+            .get(key)
+            .copied()
+            .unwrap_or(SettingSource::Default)
+    }
+
     pub fn settings_file() -> Option<PathBuf> {
         ProjectDirs::from("", "", "Lapce")
             .map(|d| d.config_dir().join("settings.toml"))
     }
 
+    /// Sets a key of the user's settings file, keeping its comments and
+    /// formatting.
     pub fn update_file(key: &str, value: toml::Value) -> Option<()> {
         let path = Config::settings_file()?;
-        let content = std::fs::read(&path).ok()?;
-        let mut toml_value: toml::Value = toml::from_slice(&content)
-            .unwrap_or(toml::Value::Table(toml::value::Table::new()));
-
-        let mut table = toml_value.as_table_mut()?;
-        let parts: Vec<&str> = key.split(".").collect();
-        let n = parts.len();
-        for (i, key) in parts.into_iter().enumerate() {
-            if i == n - 1 {
-                table.insert(key.to_string(), value.clone());
-            } else {
-                if !table.contains_key(key) {
-                    table.insert(
-                        key.to_string(),
-                        toml::Value::Table(toml::value::Table::new()),
-                    );
-                }
-                table = table.get_mut(key)?.as_table_mut()?;
-            }
+        let value = serde_json::to_value(value).ok()?;
+        if let Err(e) = lapce_proxy::registry::update_file(&path, key, &value) {
+            eprintln!("can't set {}: {}", key, e);
         }
-
-        std::fs::write(&path, toml::to_string(&toml_value).ok()?.as_bytes()).ok()?;
         None
     }
 
@@ -196,6 +293,18 @@ impl Config {
         theme.get(name)
     }
 
+    /// The color of a `style.` key, falling back to less specific keys, as
+    /// in `variable.mutable` to `variable`.
+    pub fn get_style_color(&self, style: &str) -> Option<&Color> {
+        let mut style = style;
+        loop {
+            if let Some(color) = self.get_color(&format!("style.{}", style)) {
+                return Some(color);
+            }
+            style = &style[..style.rfind('.')?];
+        }
+    }
+
     pub fn editor_text_width(&self, text: &mut PietText, c: &str) -> f64 {
         let text_layout = text
             .new_text_layout(c.to_string())
@@ -317,10 +426,11 @@ impl Config {
                     let kind = value.get("kind")?.as_str()?;
                     let kind = match kind {
                         s if kind.starts_with("ssh://") => {
-                            let mut parts = s[6..].split("@");
-                            let user = parts.next()?.to_string();
-                            let host = parts.next()?.to_string();
-                            LapceWorkspaceType::RemoteSSH(user, host)
+                            let (user, host) = s[6..].rsplit_once('@')?;
+                            LapceWorkspaceType::RemoteSSH(
+                                user.to_string(),
+                                host.to_string(),
+                            )
                         }
                         _ => LapceWorkspaceType::Local,
                     };
@@ -3,7 +3,7 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{BufReader, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{self, Stdio},
     rc::Rc,
     str::FromStr,
@@ -25,11 +25,28 @@ use druid::{
     Rect, Size, Target, TextLayout, Vec2, WidgetId, WindowId,
 };
 use im::{self, hashmap};
+use lapce_proxy::backup::BackupManifest;
+use lapce_proxy::buffer::{
+    ApplyFileEditsResponse, BufferReadOnly, READ_ONLY_ERROR_CODE,
+};
+use lapce_proxy::comment::ToggleCommentResponse;
+use lapce_proxy::conflicts::ConflictResolution;
+use lapce_proxy::git::{CheckoutConflict, CHECKOUT_ERROR_CODE};
+use lapce_proxy::line_ending::LineEnding;
+use lapce_proxy::lsp::{
+    DiagnosticCounts, LineStyles, LspCapabilities, LspServerId,
+    SignatureHelpResponse, WorkProgress,
+};
+use lapce_proxy::replace::ApplyReplaceResponse;
+use lapce_proxy::snippet::Snippet;
+use lapce_proxy::task::TaskConfig;
 use lapce_proxy::terminal::TermId;
+use lapce_rpc::transport::TransportStats;
 use lsp_types::{
-    CodeActionOrCommand, CodeActionResponse, CompletionItem, CompletionResponse,
-    CompletionTextEdit, Diagnostic, DiagnosticSeverity, GotoDefinitionResponse,
-    Location, Position, TextEdit, WorkspaceClientCapabilities,
+    CodeAction, CodeActionOrCommand, CodeActionResponse, CompletionItem,
+    CompletionResponse, CompletionTextEdit, Diagnostic, DiagnosticSeverity,
+    GotoDefinitionResponse, Location, Position, Range, TextEdit,
+    WorkspaceClientCapabilities, WorkspaceEdit,
 };
 use parking_lot::Mutex;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -43,35 +60,43 @@ use xi_core_lib::{
     watcher::{FileWatcher, Notify, WatchToken},
 };
 use xi_rope::{
-    spans::SpansBuilder, DeltaBuilder, Interval, Rope, RopeDelta, Transformer,
+    spans::{Spans, SpansBuilder},
+    DeltaBuilder, Interval, Rope, RopeDelta, Transformer,
 };
 use xi_rpc::{RpcLoop, RpcPeer};
 
 use crate::{
     buffer::{
         get_word_property, has_unmatched_pair, matching_char,
-        matching_pair_direction, previous_has_unmatched_pair, BufferId, BufferNew,
-        BufferState, BufferUpdate, EditType, Style, UpdateEvent, WordProperty,
+        matching_pair_direction, merge_styles, previous_has_unmatched_pair,
+        BufferId, BufferNew, BufferState, BufferUpdate, EditType, Style,
+        UpdateEvent, WordProperty,
     },
+    code_action::workspace_edits,
     command::{
         CommandTarget, EnsureVisiblePosition, LapceCommand, LapceCommandNew,
         LapceUICommand, LapceWorkbenchCommand, LAPCE_COMMAND, LAPCE_NEW_COMMAND,
         LAPCE_UI_COMMAND,
     },
-    completion::{CompletionData, CompletionStatus, Snippet},
+    completion::{CompletionData, CompletionStatus},
     config::{Config, LapceTheme},
     db::{LapceDb, WorkspaceInfo},
     editor::{EditorLocationNew, LapceEditorBufferData, LapceEditorViewContent},
     find::Find,
+    folding,
     keypress::{KeyPressData, KeyPressFocus},
     language::{new_highlight_config, new_parser, LapceLanguage},
     movement::{Cursor, CursorMode, LinePosition, Movement, SelRegion, Selection},
     palette::{PaletteData, PaletteType, PaletteViewData},
     panel::PanelPosition,
     proxy::{LapceProxy, ProxyHandlerNew, TermEvent},
+    recent::{self, ClosedEditor},
     source_control::{SourceControlData, SOURCE_CONTROL_BUFFER},
+    split_tree::SplitTree,
     state::{LapceWorkspace, LapceWorkspaceType, Mode, VisualMode},
+    syntax::{highlight_interval, rainbow_brackets, SyntaxState},
     terminal::TerminalSplitData,
+    vim::VimState,
 };
 
 #[derive(Clone, Data)]
@@ -297,7 +322,7 @@ impl LapceTabData {
         let update_sender = Arc::new(update_sender);
         let (term_sender, term_receiver) = unbounded();
         let proxy = Arc::new(LapceProxy::new(tab_id, term_sender.clone()));
-        let palette = Arc::new(PaletteData::new(proxy.clone()));
+        let palette = Arc::new(PaletteData::new(proxy.clone(), db.clone()));
         let completion = Arc::new(CompletionData::new());
         let source_control = Arc::new(SourceControlData::new());
         let mut main_split = LapceMainSplitData::new(
@@ -375,10 +400,12 @@ impl LapceTabData {
         if let Some(receiver) = self.update_receiver.take() {
             let tab_id = self.id;
             let local_event_sink = event_sink.clone();
+            let sender = self.update_sender.clone();
             thread::spawn(move || {
                 LapceTabData::buffer_update_process(
                     tab_id,
                     receiver,
+                    sender,
                     local_event_sink,
                 );
                 println!("buffer update process stopped");
@@ -588,6 +615,34 @@ impl LapceTabData {
         }
     }
 
+    /// Converts the line endings of the active buffer, written on its next
+    /// save.
+    fn set_line_ending(&self, ctx: &mut EventCtx, ending: LineEnding) {
+        let editor = self.main_split.active_editor();
+        let path = match &editor.content {
+            EditorContent::Buffer(path) => path.clone(),
+            _ => return,
+        };
+        let buffer = match self.main_split.open_files.get(&path) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let event_sink = ctx.get_external_handle();
+        self.proxy.set_line_ending(
+            buffer.id,
+            ending,
+            Box::new(move |result| {
+                if result.is_ok() {
+                    event_sink.submit_command(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::SetLineEnding(path, ending),
+                        Target::Auto,
+                    );
+                }
+            }),
+        );
+    }
+
     pub fn run_workbench_command(
         &mut self,
         ctx: &mut EventCtx,
@@ -623,6 +678,61 @@ impl LapceTabData {
                     }
                 });
             }
+            LapceWorkbenchCommand::ConnectSshHost => {
+                let event_sink = ctx.get_external_handle();
+                thread::spawn(move || {
+                    let input = tinyfiledialogs::input_box(
+                        "Connect to SSH Host",
+                        "user@host:/path",
+                        "",
+                    );
+                    let workspace =
+                        input.and_then(|s| LapceWorkspace::parse_remote(&s));
+                    if let Some(workspace) = workspace {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::SetWorkspace(workspace),
+                            Target::Auto,
+                        );
+                    }
+                });
+            }
+            LapceWorkbenchCommand::ShowProxyStats => {
+                self.proxy.proxy_stats(Box::new(|result| {
+                    let stats = match result {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            eprintln!("getting the proxy stats failed: {:?}", e);
+                            return;
+                        }
+                    };
+                    let stats = serde_json::from_value::<TransportStats>(stats);
+                    let message = match stats {
+                        Ok(stats) => format!(
+                            "Compression: {}, batching: {}\n\
+                             Sent: {} messages, {} bytes\n\
+                             Received: {} messages, {} bytes\n\
+                             {:.1} messages a second, compressed {:.1}x",
+                            stats.options.compression,
+                            stats.options.batching,
+                            stats.messages_out,
+                            stats.bytes_out,
+                            stats.messages_in,
+                            stats.bytes_in,
+                            stats.messages_per_sec,
+                            stats.compression_ratio,
+                        ),
+                        Err(e) => e.to_string(),
+                    };
+                    thread::spawn(move || {
+                        tinyfiledialogs::message_box_ok(
+                            "Proxy Connection",
+                            &message,
+                            tinyfiledialogs::MessageBoxIcon::Info,
+                        );
+                    });
+                }));
+            }
             LapceWorkbenchCommand::EnableModal => {
                 let config = Arc::make_mut(&mut self.config);
                 config.lapce.modal = true;
@@ -709,6 +819,13 @@ impl LapceTabData {
                     Target::Widget(self.palette.widget_id),
                 ));
             }
+            LapceWorkbenchCommand::PaletteWorkspaceSymbol => {
+                ctx.submit_command(Command::new(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RunPalette(Some(PaletteType::WorkspaceSymbol)),
+                    Target::Widget(self.palette.widget_id),
+                ));
+            }
             LapceWorkbenchCommand::PaletteCommand => {
                 ctx.submit_command(Command::new(
                     LAPCE_UI_COMMAND,
@@ -723,6 +840,16 @@ impl LapceTabData {
                     Target::Widget(self.palette.widget_id),
                 ));
             }
+            LapceWorkbenchCommand::PaletteClosedEditor => {
+                ctx.submit_command(Command::new(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RunPalette(Some(PaletteType::ClosedEditor)),
+                    Target::Widget(self.palette.widget_id),
+                ));
+            }
+            LapceWorkbenchCommand::ReopenLastClosed => {
+                self.main_split.reopen_last_closed(ctx, &self.config);
+            }
             LapceWorkbenchCommand::NewTab => {
                 ctx.submit_command(Command::new(
                     LAPCE_UI_COMMAND,
@@ -758,6 +885,277 @@ impl LapceTabData {
                     Target::Auto,
                 ));
             }
+            LapceWorkbenchCommand::RestartLspServer => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    if let Some(buffer) = self.main_split.open_files.get(path) {
+                        self.proxy.restart_lsp_server(buffer.id);
+                    }
+                }
+            }
+            LapceWorkbenchCommand::GitCheckout => {
+                let proxy = self.proxy.clone();
+                thread::spawn(move || {
+                    if let Some(target) = tinyfiledialogs::input_box(
+                        "Checkout",
+                        "The branch or commit to check out",
+                        "",
+                    ) {
+                        git_checkout(proxy, target.trim().to_string(), false, false);
+                    }
+                });
+            }
+            LapceWorkbenchCommand::GitCreateBranch => {
+                let proxy = self.proxy.clone();
+                thread::spawn(move || {
+                    if let Some(name) = tinyfiledialogs::input_box(
+                        "Create Branch",
+                        "The name of the branch, made at HEAD and checked out",
+                        "",
+                    ) {
+                        git_checkout(proxy, name.trim().to_string(), true, false);
+                    }
+                });
+            }
+            LapceWorkbenchCommand::GitStash
+            | LapceWorkbenchCommand::GitStashIncludeUntracked => {
+                let include_untracked =
+                    command == &LapceWorkbenchCommand::GitStashIncludeUntracked;
+                let proxy = self.proxy.clone();
+                thread::spawn(move || {
+                    if let Some(message) = tinyfiledialogs::input_box(
+                        "Stash",
+                        "A message for the stash, if any",
+                        "",
+                    ) {
+                        let message = Some(message.trim())
+                            .filter(|message| !message.is_empty());
+                        proxy.git_stash_save(
+                            message,
+                            include_untracked,
+                            Box::new(|result| {
+                                if let Err(e) = result {
+                                    eprintln!("stash failed: {:?}", e);
+                                }
+                            }),
+                        );
+                    }
+                });
+            }
+            LapceWorkbenchCommand::GitStashPop => {
+                self.proxy.git_stash_pop(
+                    0,
+                    Box::new(|result| {
+                        if let Err(e) = result {
+                            eprintln!("stash pop failed: {:?}", e);
+                        }
+                    }),
+                );
+            }
+            LapceWorkbenchCommand::RunTask => {
+                let proxy = self.proxy.clone();
+                self.proxy.list_tasks(Box::new(move |result| {
+                    let tasks = match result
+                        .map_err(|e| anyhow!("{:?}", e))
+                        .and_then(|res| {
+                            Ok(serde_json::from_value::<Vec<TaskConfig>>(res)?)
+                        }) {
+                        Ok(tasks) if !tasks.is_empty() => tasks,
+                        Ok(_) => {
+                            eprintln!("the workspace has no tasks");
+                            return;
+                        }
+                        Err(e) => {
+                            eprintln!("listing the tasks failed: {}", e);
+                            return;
+                        }
+                    };
+                    let names: Vec<String> =
+                        tasks.into_iter().map(|task| task.name).collect();
+                    thread::spawn(move || {
+                        let prompt = format!("One of {}", names.join(", "));
+                        if let Some(name) = tinyfiledialogs::input_box(
+                            "Run Task", &prompt, &names[0],
+                        ) {
+                            proxy.run_task(
+                                name.trim(),
+                                Box::new(|result| {
+                                    if let Err(e) = result {
+                                        eprintln!(
+                                            "running the task failed: {:?}",
+                                            e
+                                        );
+                                    }
+                                }),
+                            );
+                        }
+                    });
+                }));
+            }
+            LapceWorkbenchCommand::GitMarkResolved => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    self.proxy.git_mark_resolved(
+                        path,
+                        Box::new(|result| {
+                            if let Err(e) = result {
+                                eprintln!("mark resolved failed: {:?}", e);
+                            }
+                        }),
+                    );
+                }
+            }
+            LapceWorkbenchCommand::RebuildFileIndex => {
+                self.proxy.rebuild_file_index(Box::new(|result| {
+                    if let Err(e) = result {
+                        eprintln!("rebuild file index err {:?}", e);
+                    }
+                }));
+            }
+            LapceWorkbenchCommand::ChangeLineEndingToLf => {
+                self.set_line_ending(ctx, LineEnding::Lf);
+            }
+            LapceWorkbenchCommand::ChangeLineEndingToCrlf => {
+                self.set_line_ending(ctx, LineEnding::Crlf);
+            }
+            LapceWorkbenchCommand::SaveCopyAs => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    if let Some(buffer) = self.main_split.open_files.get(path) {
+                        let buffer_id = buffer.id;
+                        let path = path.to_string_lossy().to_string();
+                        let proxy = self.proxy.clone();
+                        thread::spawn(move || {
+                            if let Some(copy) = tinyfiledialogs::save_file_dialog(
+                                "Save a copy as",
+                                &path,
+                            ) {
+                                proxy.save_copy_as(
+                                    buffer_id,
+                                    PathBuf::from(copy),
+                                    Box::new(|result| {
+                                        if let Err(e) = result {
+                                            eprintln!("save a copy failed: {:?}", e);
+                                        }
+                                    }),
+                                );
+                            }
+                        });
+                    }
+                }
+            }
+            LapceWorkbenchCommand::TrimTrailingWhitespace => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    let path = path.clone();
+                    self.main_split.trim_trailing_whitespace(ctx, &path);
+                }
+            }
+            LapceWorkbenchCommand::ToggleReadOnly => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    if let Some(buffer) = self.main_split.open_files.get(path) {
+                        let path = path.clone();
+                        let event_sink = ctx.get_external_handle();
+                        self.proxy.set_buffer_readonly(
+                            buffer.id,
+                            buffer.editable,
+                            Box::new(move |result| {
+                                let editable = result
+                                    .ok()
+                                    .and_then(|res| res.get("editable")?.as_bool());
+                                if let Some(editable) = editable {
+                                    event_sink.submit_command(
+                                        LAPCE_UI_COMMAND,
+                                        LapceUICommand::SetBufferEditable(
+                                            path, editable,
+                                        ),
+                                        Target::Auto,
+                                    );
+                                }
+                            }),
+                        );
+                    }
+                }
+            }
+            LapceWorkbenchCommand::RestoreUnsavedChanges => {
+                let editor = self.main_split.active_editor();
+                let path = match &editor.content {
+                    EditorContent::Buffer(path)
+                        if self.main_split.unsaved_backups.contains_key(path) =>
+                    {
+                        path.clone()
+                    }
+                    _ => {
+                        // to the next file that has some
+                        if let Some(path) =
+                            self.main_split.unsaved_backups.keys().next()
+                        {
+                            ctx.submit_command(Command::new(
+                                LAPCE_UI_COMMAND,
+                                LapceUICommand::OpenFile(path.clone()),
+                                Target::Widget(self.id),
+                            ));
+                        }
+                        return;
+                    }
+                };
+                let loaded = self
+                    .main_split
+                    .open_files
+                    .get(&path)
+                    .map(|buffer| buffer.loaded)
+                    .unwrap_or(false);
+                if !loaded {
+                    return;
+                }
+                let id = self.main_split.unsaved_backups[&path].id.clone();
+                let event_sink = ctx.get_external_handle();
+                let tab_id = self.id;
+                self.proxy.restore_backup(
+                    id.clone(),
+                    Box::new(move |result| {
+                        let content = result.ok().and_then(|res| {
+                            Some(res.get("content")?.as_str()?.to_string())
+                        });
+                        match content {
+                            Some(content) => {
+                                event_sink.submit_command(
+                                    LAPCE_UI_COMMAND,
+                                    LapceUICommand::RestoreBackup(path, id, content),
+                                    Target::Widget(tab_id),
+                                );
+                            }
+                            None => eprintln!("can't restore {:?}", path),
+                        }
+                    }),
+                );
+            }
+            LapceWorkbenchCommand::DiscardUnsavedChanges => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    if let Some(backup) =
+                        self.main_split.unsaved_backups.remove(path)
+                    {
+                        self.proxy.discard_backup(backup.id, Box::new(|_| {}));
+                    }
+                }
+            }
+            LapceWorkbenchCommand::ReloadFromDisk => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    if let Some(buffer) = self.main_split.open_files.get(path) {
+                        self.proxy.reload_buffer(buffer.id, Box::new(|_| {}));
+                    }
+                }
+            }
+            LapceWorkbenchCommand::OverwriteOnDisk => {
+                let editor = self.main_split.active_editor();
+                if let EditorContent::Buffer(path) = &editor.content {
+                    let path = path.clone();
+                    self.main_split.write_document(ctx, &path, true);
+                }
+            }
             LapceWorkbenchCommand::ToggleTerminal => {
                 if self.focus_area == FocusArea::Terminal {
                     for (_, panel) in self.panels.iter_mut() {
@@ -898,6 +1296,7 @@ impl LapceTabData {
     pub fn buffer_update_process(
         tab_id: WidgetId,
         receiver: Receiver<UpdateEvent>,
+        sender: Arc<Sender<UpdateEvent>>,
         event_sink: ExtEventSink,
     ) {
         use std::collections::{HashMap, HashSet};
@@ -905,21 +1304,25 @@ impl LapceTabData {
             updates: &mut HashMap<BufferId, UpdateEvent>,
             event: UpdateEvent,
         ) {
-            let update = match &event {
+            let mut event = event;
+            let update = match &mut event {
                 UpdateEvent::Buffer(update) => update,
                 UpdateEvent::SemanticTokens(update, tokens) => update,
             };
             if let Some(current) = updates.get(&update.id) {
-                let current = match &event {
-                    UpdateEvent::Buffer(update) => update,
-                    UpdateEvent::SemanticTokens(update, tokens) => update,
+                let current = match current {
+                    UpdateEvent::Buffer(current) => current,
+                    UpdateEvent::SemanticTokens(current, tokens) => current,
                 };
-                if update.rev > current.rev {
-                    updates.insert(update.id, event);
+                if update.rev <= current.rev {
+                    return;
                 }
-            } else {
-                updates.insert(update.id, event);
+                // the edits of the update replaced are still to be parsed
+                let mut deltas = current.deltas.clone();
+                deltas.append(&mut update.deltas);
+                update.deltas = deltas;
             }
+            updates.insert(update.id, event);
         }
 
         fn receive_batch(
@@ -944,6 +1347,8 @@ impl LapceTabData {
         let mut parsers = HashMap::new();
         let mut highlighter = Highlighter::new();
         let mut highlight_configs = HashMap::new();
+        let mut syntax_styles = HashMap::new();
+        let mut syntax_trees = HashMap::new();
         loop {
             let events = receive_batch(&receiver);
             if events.len() == 0 {
@@ -954,34 +1359,26 @@ impl LapceTabData {
                     UpdateEvent::Buffer(update) => {
                         buffer_receive_update(
                             update,
+                            &receiver,
+                            &sender,
                             &mut parsers,
                             &mut highlighter,
                             &mut highlight_configs,
+                            &mut syntax_styles,
+                            &mut syntax_trees,
                             &event_sink,
                             tab_id,
                         );
                     }
-                    UpdateEvent::SemanticTokens(update, tokens) => {
-                        let mut highlights = SpansBuilder::new(update.rope.len());
-                        for (start, end, hl) in tokens {
-                            highlights.add_span(
-                                Interval::new(start, end),
-                                Style {
-                                    fg_color: Some(hl.to_string()),
-                                },
-                            );
-                        }
-                        let highlights = highlights.build();
-                        event_sink.submit_command(
-                            LAPCE_UI_COMMAND,
-                            LapceUICommand::UpdateStyle {
-                                id: update.id,
-                                path: update.path,
-                                rev: update.rev,
-                                highlights,
-                                semantic_tokens: true,
-                            },
-                            Target::Widget(tab_id),
+                    UpdateEvent::SemanticTokens(update, lines) => {
+                        buffer_receive_semantic_tokens(
+                            update,
+                            lines,
+                            &mut highlighter,
+                            &mut highlight_configs,
+                            &mut syntax_styles,
+                            &event_sink,
+                            tab_id,
                         );
                     }
                 };
@@ -1077,6 +1474,24 @@ pub enum EditorKind {
     SplitActive,
 }
 
+/// Where to say whether a workspace edit was applied, or why not, for the
+/// server that asked for it with `workspace/applyEdit` to be answered. The
+/// default is for an edit nobody waits on.
+#[derive(Clone, Debug, Default)]
+pub struct EditReply(Option<Sender<Result<(), String>>>);
+
+impl EditReply {
+    pub fn new(sender: Sender<Result<(), String>>) -> Self {
+        Self(Some(sender))
+    }
+
+    pub fn send(&self, result: Result<(), String>) {
+        if let Some(sender) = self.0.as_ref() {
+            let _ = sender.send(result);
+        }
+    }
+}
+
 #[derive(Clone, Data, Lens)]
 pub struct LapceMainSplitData {
     pub tab_id: Arc<WidgetId>,
@@ -1084,6 +1499,8 @@ pub struct LapceMainSplitData {
     pub active: Arc<WidgetId>,
     pub editors: im::HashMap<WidgetId, Arc<LapceEditorData>>,
     pub editors_order: Arc<Vec<WidgetId>>,
+    /// How the editors of `editors_order` are laid out.
+    pub split: Arc<SplitTree<WidgetId>>,
     pub open_files: im::HashMap<PathBuf, Arc<BufferNew>>,
     pub update_sender: Arc<Sender<UpdateEvent>>,
     pub register: Arc<Register>,
@@ -1092,11 +1509,36 @@ pub struct LapceMainSplitData {
     pub show_code_actions: bool,
     pub current_code_actions: usize,
     pub diagnostics: im::HashMap<PathBuf, Arc<Vec<EditorDiagnostic>>>,
+    /// Diagnostic counts of every file in the workspace, open or not, for
+    /// the file explorer and the status bar totals.
+    pub diagnostics_counts: im::HashMap<PathBuf, Arc<DiagnosticCounts>>,
     pub error_count: usize,
     pub warning_count: usize,
+    pub work_progress: im::HashMap<(LspServerId, String), Arc<WorkProgress>>,
+    /// What the servers of each language support, once they told.
+    pub server_capabilities: im::HashMap<String, LspCapabilities>,
+    pub signature: Option<Arc<SignatureHelpResponse>>,
+    /// The backups of unsaved changes the last session left, by file.
+    pub unsaved_backups: im::HashMap<PathBuf, BackupManifest>,
+    /// The editors closed in the workspace, the last on top.
+    pub closed_editors: Arc<Vec<ClosedEditor>>,
 }
 
 impl LapceMainSplitData {
+    /// Whether the servers of the file's language support a feature, taken
+    /// as so until they told what they support.
+    pub fn supports(
+        &self,
+        path: &PathBuf,
+        supported: impl Fn(&LspCapabilities) -> bool,
+    ) -> bool {
+        self.open_files
+            .get(path)
+            .and_then(|buffer| self.server_capabilities.get(&buffer.language_id))
+            .map(supported)
+            .unwrap_or(true)
+    }
+
     pub fn editor_kind(&self, kind: &EditorKind) -> &LapceEditorData {
         match kind {
             EditorKind::PalettePreview => {
@@ -1125,6 +1567,102 @@ impl LapceMainSplitData {
         Arc::make_mut(self.editors.get_mut(&self.active).unwrap())
     }
 
+    /// Applies the edits of a formatting request made at revision `rev`,
+    /// unless the buffer has changed since.
+    pub fn document_format(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        rev: u64,
+        result: &Result<Value>,
+    ) {
+        let buffer = match self.open_files.get(path) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        if buffer.rev != rev {
+            eprintln!("{:?} changed while formatting, dropped the edits", path);
+            return;
+        }
+        match result {
+            Ok(res) => self.apply_formatting(ctx, path, res),
+            Err(e) => eprintln!("formatting {:?} failed: {}", path, e),
+        }
+    }
+
+    /// Applies the edits of a comment toggle made at `resp.rev`, putting
+    /// the selections of the editor it was made in where the proxy says.
+    pub fn toggle_comment(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        view_id: WidgetId,
+        resp: &ToggleCommentResponse,
+    ) {
+        match self.open_files.get(path) {
+            Some(buffer) if buffer.rev == resp.rev => {}
+            _ => {
+                eprintln!("{:?} changed while toggling comments", path);
+                return;
+            }
+        }
+        if resp.edits.is_empty() {
+            return;
+        }
+        let edits: Vec<(Selection, &str)> = resp
+            .edits
+            .iter()
+            .map(|(start, end, text)| {
+                (Selection::region(*start, *end), text.as_str())
+            })
+            .collect();
+        self.edit(
+            ctx,
+            path,
+            edits.iter().map(|(s, c)| (s, *c)).collect(),
+            EditType::Other,
+        );
+        if let Some(editor) = self.editors.get_mut(&view_id) {
+            let editor = Arc::make_mut(editor);
+            if let CursorMode::Insert(_) = editor.cursor.mode {
+                let mut selection = Selection::new();
+                for (start, end) in &resp.selections {
+                    selection.add_region(SelRegion::new(*start, *end, None));
+                }
+                editor.cursor = Cursor::new(CursorMode::Insert(selection), None);
+            }
+        }
+    }
+
+    /// Makes the edit resolving a conflict, worked out at `resp.rev`.
+    pub fn resolve_conflict(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        resp: &ConflictResolution,
+    ) {
+        match self.open_files.get(path) {
+            Some(buffer) if buffer.rev == resp.rev => {}
+            _ => {
+                eprintln!("{:?} changed while resolving a conflict", path);
+                return;
+            }
+        }
+        let edits: Vec<(Selection, &str)> = resp
+            .edits
+            .iter()
+            .map(|(start, end, text)| {
+                (Selection::region(*start, *end), text.as_str())
+            })
+            .collect();
+        self.edit(
+            ctx,
+            path,
+            edits.iter().map(|(s, c)| (s, *c)).collect(),
+            EditType::Other,
+        );
+    }
+
     pub fn document_format_and_save(
         &mut self,
         ctx: &mut EventCtx,
@@ -1138,125 +1676,730 @@ impl LapceMainSplitData {
         }
 
         if let Ok(res) = result {
-            let edits: Result<Vec<TextEdit>, serde_json::Error> =
-                serde_json::from_value(res.clone());
-            if let Ok(edits) = edits {
-                if edits.len() > 0 {
-                    let buffer = self.open_files.get_mut(path).unwrap();
+            self.apply_formatting(ctx, path, res);
+        }
+        self.document_save(ctx, path);
+    }
+
+    fn apply_formatting(&mut self, ctx: &mut EventCtx, path: &PathBuf, res: &Value) {
+        let edits: Result<Vec<TextEdit>, serde_json::Error> =
+            serde_json::from_value(res.clone());
+        if let Ok(edits) = edits {
+            if edits.len() > 0 {
+                let buffer = self.open_files.get_mut(path).unwrap();
+
+                let edits: Vec<(Selection, String)> = edits
+                    .iter()
+                    .map(|edit| {
+                        let selection = Selection::region(
+                            buffer.offset_of_position(&edit.range.start),
+                            buffer.offset_of_position(&edit.range.end),
+                        );
+                        (selection, edit.new_text.clone())
+                    })
+                    .collect();
+
+                self.edit(
+                    ctx,
+                    &path,
+                    edits.iter().map(|(s, c)| (s, c.as_ref())).collect(),
+                    EditType::Other,
+                );
+            }
+        }
+    }
+
+    /// Trims the trailing whitespace of the lines edited since the file was
+    /// loaded and ends it with a newline, as the settings of its language
+    /// say. Runs before format on save, so that the server formats the
+    /// result.
+    pub fn apply_save_transforms(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        config: &Config,
+    ) {
+        let buffer = match self.open_files.get(path) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let trim = config.trims_trailing_whitespace(&buffer.language_id);
+        let final_newline = config.inserts_final_newline(&buffer.language_id)
+            && buffer.missing_final_newline();
+        let whitespace = if trim {
+            buffer.trailing_whitespace(false)
+        } else {
+            Vec::new()
+        };
+        self.edit_whitespace(ctx, path, whitespace, final_newline);
+    }
+
+    /// Trims the trailing whitespace of every line of the file.
+    pub fn trim_trailing_whitespace(&mut self, ctx: &mut EventCtx, path: &PathBuf) {
+        let whitespace = match self.open_files.get(path) {
+            Some(buffer) => buffer.trailing_whitespace(true),
+            None => return,
+        };
+        self.edit_whitespace(ctx, path, whitespace, false);
+    }
+
+    /// Puts the text of a backup in the buffer, as an edit that can be
+    /// undone, and deletes the backup.
+    pub fn restore_backup(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        id: &str,
+        content: &str,
+    ) {
+        let buffer = match self.open_files.get(path) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let selection = Selection::region(0, buffer.len());
+        self.edit(ctx, path, vec![(&selection, content)], EditType::Other);
+        self.unsaved_backups.remove(path);
+        self.proxy.discard_backup(id.to_string(), Box::new(|_| {}));
+    }
+
+    /// Deletes `whitespace` and adds a final newline in one edit, which the
+    /// cursors in the whitespace follow to the end of their line.
+    fn edit_whitespace(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        whitespace: Vec<(usize, usize)>,
+        final_newline: bool,
+    ) {
+        if whitespace.is_empty() && !final_newline {
+            return;
+        }
+        let buffer = self.open_files.get(path).unwrap();
+        let mut selection = Selection::new();
+        for (start, end) in whitespace {
+            selection.add_region(SelRegion::new(start, end, None));
+        }
+        let end = Selection::caret(buffer.len());
+        let mut edits = vec![(&selection, "")];
+        if final_newline {
+            edits.push((&end, "\n"));
+        }
+        self.edit(ctx, path, edits, EditType::Other);
+    }
+
+    /// Saves once the servers had their `willSaveWaitUntil` say, which the
+    /// proxy answers within its timeout.
+    pub fn document_save(&mut self, ctx: &mut EventCtx, path: &PathBuf) {
+        let buffer = self.open_files.get(path).unwrap();
+        let rev = buffer.rev;
+        let buffer_id = buffer.id;
+        let event_sink = ctx.get_external_handle();
+        let path = path.clone();
+        self.proxy.will_save(
+            buffer_id,
+            Box::new(move |result| {
+                event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::WillSaveEdits(
+                        path,
+                        rev,
+                        result.map_err(|e| anyhow!("{:?}", e)),
+                    ),
+                    Target::Auto,
+                );
+            }),
+        );
+    }
+
+    /// Takes the content of a buffer read again from disk. The cursors
+    /// follow the change, and the line at the top of each editor stays.
+    pub fn reload_buffer(
+        &mut self,
+        ctx: &mut EventCtx,
+        buffer_id: BufferId,
+        rev: u64,
+        new_content: &str,
+        config: &Config,
+    ) {
+        let path = match self
+            .open_files
+            .values()
+            .find(|buffer| buffer.id == buffer_id)
+        {
+            Some(buffer) if buffer.rev + 1 == rev => buffer.path.clone(),
+            _ => return,
+        };
+        let buffer = Arc::make_mut(self.open_files.get_mut(&path).unwrap());
+        let old_rope = buffer.rope.clone();
+        let delta = buffer.reload(rev, new_content);
+        let buffer = self.open_files.get(&path).unwrap();
+        self.proxy.sync_buffer(
+            buffer.id,
+            path.clone(),
+            buffer.rope.clone(),
+            buffer.rev,
+        );
+
+        let line_height = config.editor.line_height as f64;
+        for (_, editor) in self.editors.iter_mut() {
+            match &editor.content {
+                EditorContent::Buffer(editor_path) if editor_path == &path => {}
+                _ => continue,
+            }
+            let editor = Arc::make_mut(editor);
+            editor.cursor.apply_delta(&delta);
+            if editor.cursor.offset() >= buffer.len() {
+                let offset = buffer.len().saturating_sub(1);
+                editor.cursor = if config.lapce.modal {
+                    Cursor::new(CursorMode::Normal(offset), None)
+                } else {
+                    Cursor::new(CursorMode::Insert(Selection::caret(offset)), None)
+                };
+            }
+
+            let last_line = old_rope.line_of_offset(old_rope.len());
+            let top_line = ((editor.scroll_offset.y / line_height).floor() as usize)
+                .min(last_line);
+            let top_offset = Transformer::new(&delta)
+                .transform(old_rope.offset_of_line(top_line), false);
+            let new_top_line = buffer.line_of_offset(top_offset);
+            if new_top_line != top_line {
+                let y = editor.scroll_offset.y
+                    + (new_top_line as f64 - top_line as f64) * line_height;
+                ctx.submit_command(Command::new(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::ScrollTo((editor.scroll_offset.x, y)),
+                    Target::Widget(editor.view_id),
+                ));
+            }
+        }
+        self.update_diagnositcs_offset(&path, &delta);
+    }
+
+    /// Applies the edits of `willSaveWaitUntil` and writes the buffer. The
+    /// edits are dropped if the buffer changed while waiting for them, but
+    /// the save goes ahead.
+    pub fn document_will_save_edits(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        rev: u64,
+        result: &Result<Value>,
+    ) {
+        let buffer = match self.open_files.get(path) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        match result {
+            Ok(res) if buffer.rev == rev => self.apply_formatting(ctx, path, res),
+            Ok(_) => {
+                eprintln!("{:?} changed before saving, dropped the edits", path)
+            }
+            Err(e) => eprintln!("willSaveWaitUntil for {:?} failed: {}", path, e),
+        }
+        self.write_document(ctx, path, false);
+    }
+
+    /// Writes the buffer to disk. Without `overwrite`, the proxy refuses if
+    /// another program changed the file since it was loaded.
+    fn write_document(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        overwrite: bool,
+    ) {
+        let buffer = self.open_files.get(path).unwrap();
+        let rev = buffer.rev;
+        let buffer_id = buffer.id;
+        let event_sink = ctx.get_external_handle();
+        let path = path.clone();
+        self.proxy.save(
+            rev,
+            buffer_id,
+            overwrite,
+            Box::new(move |result| match result {
+                Ok(_) => {
+                    event_sink.submit_command(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::BufferSave(path, rev),
+                        Target::Auto,
+                    );
+                }
+                Err(xi_rpc::Error::RemoteError(xi_rpc::RemoteError::Custom {
+                    code,
+                    message,
+                    data,
+                })) if code == READ_ONLY_ERROR_CODE => {
+                    let elevated_save = data
+                        .and_then(|data| {
+                            serde_json::from_value::<BufferReadOnly>(data).ok()
+                        })
+                        .map(|read_only| read_only.elevated_save)
+                        .unwrap_or(false);
+                    if elevated_save {
+                        eprintln!("{}, it can be saved with sudo", message);
+                    } else {
+                        eprintln!("{}", message);
+                    }
+                }
+                Err(e) => eprintln!("failed to save {:?}: {:?}", path, e),
+            }),
+        );
+    }
+
+    fn initiate_diagnositcs_offset(&mut self, path: &PathBuf) {
+        if let Some(diagnostics) = self.diagnostics.get_mut(path) {
+            if let Some(buffer) = self.open_files.get(path) {
+                for diagnostic in Arc::make_mut(diagnostics).iter_mut() {
+                    if diagnostic.range.is_none() {
+                        diagnostic.range = Some((
+                            buffer.offset_of_position(
+                                &diagnostic.diagnositc.range.start,
+                            ),
+                            buffer.offset_of_position(
+                                &diagnostic.diagnositc.range.end,
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    fn update_diagnositcs_offset(&mut self, path: &PathBuf, delta: &RopeDelta) {
+        if let Some(diagnostics) = self.diagnostics.get_mut(path) {
+            if let Some(buffer) = self.open_files.get(path) {
+                let mut transformer = Transformer::new(delta);
+                for diagnostic in Arc::make_mut(diagnostics).iter_mut() {
+                    let (start, end) = diagnostic.range.clone().unwrap();
+                    let (new_start, new_end) = (
+                        transformer.transform(start, false),
+                        transformer.transform(end, true),
+                    );
+                    diagnostic.range = Some((new_start, new_end));
+                    if start != new_start {
+                        diagnostic.diagnositc.range.start =
+                            buffer.offset_to_position(new_start);
+                    }
+                    if end != new_end {
+                        diagnostic.diagnositc.range.end =
+                            buffer.offset_to_position(new_end);
+                        buffer.offset_to_position(new_end);
+                    }
+                }
+            }
+        }
+    }
+
+    fn cursor_apply_delta(&mut self, path: &PathBuf, delta: &RopeDelta) {
+        for (view_id, editor) in self.editors.iter_mut() {
+            match &editor.content {
+                EditorContent::Buffer(current_path) => {
+                    if current_path == path {
+                        Arc::make_mut(editor).cursor.apply_delta(delta);
+                    }
+                }
+                EditorContent::None => {}
+            }
+        }
+    }
+
+    pub fn edit(
+        &mut self,
+        ctx: &mut EventCtx,
+        path: &PathBuf,
+        edits: Vec<(&Selection, &str)>,
+        edit_type: EditType,
+    ) -> Option<RopeDelta> {
+        self.initiate_diagnositcs_offset(path);
+        let proxy = self.proxy.clone();
+        let buffer = self.open_files.get_mut(path)?;
+        let delta =
+            Arc::make_mut(buffer).edit_multiple(ctx, edits, proxy, edit_type);
+        self.cursor_apply_delta(path, &delta);
+        self.update_diagnositcs_offset(path, &delta);
+        Some(delta)
+    }
+
+    pub fn rename(
+        &mut self,
+        ctx: &mut EventCtx,
+        buffer_id: BufferId,
+        path: &PathBuf,
+        rev: u64,
+        position: Position,
+        new_name: &str,
+    ) {
+        // any of the open buffers the edit turns out to touch must be as
+        // they are now, and the one renamed in as it was at `position`
+        let mut revs: HashMap<PathBuf, u64> = self
+            .open_files
+            .iter()
+            .filter(|(_, buffer)| buffer.loaded)
+            .map(|(path, buffer)| (path.clone(), buffer.rev))
+            .collect();
+        revs.insert(path.clone(), rev);
+        let event_sink = ctx.get_external_handle();
+        self.proxy.rename(
+            buffer_id,
+            position,
+            new_name,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(edit) = serde_json::from_value::<WorkspaceEdit>(res) {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::ApplyRename(revs, edit),
+                            Target::Auto,
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Renames a file or directory through the proxy, which moves its
+    /// buffers along and gives the edits the servers want for the rename.
+    pub fn rename_path(&mut self, ctx: &mut EventCtx, old: &Path, new: &Path) {
+        let (old_path, new_path) = (old.to_path_buf(), new.to_path_buf());
+        let event_sink = ctx.get_external_handle();
+        self.proxy.rename_path(
+            old,
+            new,
+            Box::new(move |result| {
+                let edits = result.map_err(|e| anyhow!("{:?}", e)).and_then(|res| {
+                    Ok(serde_json::from_value::<Vec<WorkspaceEdit>>(
+                        res["edits"].clone(),
+                    )?)
+                });
+                match edits {
+                    Ok(edits) => {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::PathRenamed(old_path, new_path, edits),
+                            Target::Auto,
+                        );
+                    }
+                    Err(e) => eprintln!("failed to rename {:?}: {}", old_path, e),
+                }
+            }),
+        );
+    }
+
+    /// Points the buffers and editors of `old`, or of the files in it when
+    /// it's a directory, at where they were moved.
+    pub fn retarget_path(&mut self, old: &Path, new: &Path) {
+        let moved = |path: &Path| match path.strip_prefix(old) {
+            Ok(rest) if rest.as_os_str().is_empty() => Some(new.to_path_buf()),
+            Ok(rest) => Some(new.join(rest)),
+            Err(_) => None,
+        };
+        let paths: Vec<(PathBuf, PathBuf)> = self
+            .open_files
+            .keys()
+            .filter_map(|path| Some((path.clone(), moved(path)?)))
+            .collect();
+        for (path, new_path) in paths {
+            if let Some(mut buffer) = self.open_files.remove(&path) {
+                Arc::make_mut(&mut buffer).path = new_path.clone();
+                if buffer.loaded && !buffer.local {
+                    self.proxy.sync_buffer(
+                        buffer.id,
+                        new_path.clone(),
+                        buffer.rope.clone(),
+                        buffer.rev,
+                    );
+                }
+                self.open_files.insert(new_path, buffer);
+            }
+            // the servers publish them again for the new path
+            self.diagnostics.remove(&path);
+        }
+        for editor in self.editors.values_mut() {
+            let content = match &editor.content {
+                EditorContent::Buffer(path) => moved(path),
+                EditorContent::None => None,
+            };
+            let locations_moved =
+                editor.locations.iter().any(|l| moved(&l.path).is_some());
+            if content.is_none() && !locations_moved {
+                continue;
+            }
+            let editor = Arc::make_mut(editor);
+            if let Some(new_path) = content {
+                editor.content = EditorContent::Buffer(new_path);
+            }
+            for location in editor.locations.iter_mut() {
+                if let Some(new_path) = moved(&location.path) {
+                    location.path = new_path;
+                }
+            }
+        }
+    }
+
+    pub fn run_code_action(
+        &mut self,
+        ctx: &mut EventCtx,
+        buffer_id: BufferId,
+        action: &CodeActionOrCommand,
+    ) {
+        match action {
+            CodeActionOrCommand::Command(command) => {
+                self.proxy
+                    .execute_command(buffer_id, command, Box::new(|_| {}));
+            }
+            CodeActionOrCommand::CodeAction(action) => {
+                if action.edit.is_some() {
+                    self.apply_code_action(ctx, buffer_id, action);
+                    return;
+                }
+                let event_sink = ctx.get_external_handle();
+                self.proxy.resolve_code_action(
+                    buffer_id,
+                    action,
+                    Box::new(move |result| {
+                        if let Ok(res) = result {
+                            if let Ok(action) =
+                                serde_json::from_value::<CodeAction>(res)
+                            {
+                                event_sink.submit_command(
+                                    LAPCE_UI_COMMAND,
+                                    LapceUICommand::RunCodeAction(buffer_id, action),
+                                    Target::Auto,
+                                );
+                            }
+                        }
+                    }),
+                );
+            }
+        }
+    }
+
+    pub fn apply_code_action(
+        &mut self,
+        ctx: &mut EventCtx,
+        buffer_id: BufferId,
+        action: &CodeAction,
+    ) {
+        if let Some(edit) = action.edit.as_ref() {
+            self.apply_workspace_edit(ctx, edit, EditReply::default());
+        }
+        if let Some(command) = action.command.as_ref() {
+            self.proxy
+                .execute_command(buffer_id, command, Box::new(|_| {}));
+        }
+    }
+
+    /// Files that aren't open are edited on disk by the proxy, and the open
+    /// buffers are only edited once that has succeeded. When one of them has
+    /// changed by then, the files are put back, so the edit is applied
+    /// everywhere or nowhere, and `reply` is told which.
+    pub fn apply_workspace_edit(
+        &mut self,
+        ctx: &mut EventCtx,
+        edit: &WorkspaceEdit,
+        reply: EditReply,
+    ) {
+        self.apply_workspace_edit_at(ctx, edit, None, reply);
+    }
+
+    /// Applies the edit of a rename asked for when the open buffers were at
+    /// `revs`, nothing of it if any of those it touches has changed since.
+    pub fn apply_rename(
+        &mut self,
+        ctx: &mut EventCtx,
+        revs: &HashMap<PathBuf, u64>,
+        edit: &WorkspaceEdit,
+    ) {
+        self.apply_workspace_edit_at(ctx, edit, Some(revs), EditReply::default());
+    }
 
-                    let edits: Vec<(Selection, String)> = edits
-                        .iter()
-                        .map(|edit| {
-                            let selection = Selection::region(
-                                buffer.offset_of_position(&edit.range.start),
-                                buffer.offset_of_position(&edit.range.end),
-                            );
-                            (selection, edit.new_text.clone())
-                        })
-                        .collect();
+    fn apply_workspace_edit_at(
+        &mut self,
+        ctx: &mut EventCtx,
+        edit: &WorkspaceEdit,
+        revs: Option<&HashMap<PathBuf, u64>>,
+        reply: EditReply,
+    ) {
+        let (buffer_edits, file_edits) = match self.workspace_edit_files(edit, revs)
+        {
+            Ok(edits) => edits,
+            Err(e) => {
+                eprintln!("can't apply workspace edit: {}", e);
+                reply.send(Err(e.to_string()));
+                return;
+            }
+        };
 
-                    self.edit(
-                        ctx,
-                        &path,
-                        edits.iter().map(|(s, c)| (s, c.as_ref())).collect(),
-                        EditType::Other,
-                    );
-                }
+        if file_edits.is_empty() {
+            let result = self.apply_buffer_edits(ctx, &buffer_edits);
+            if let Err(e) = result.as_ref() {
+                eprintln!("can't apply workspace edit: {}", e);
             }
+            reply.send(result.map_err(|e| e.to_string()));
+            return;
         }
-
-        let buffer = self.open_files.get(path).unwrap();
-        let rev = buffer.rev;
-        let buffer_id = buffer.id;
         let event_sink = ctx.get_external_handle();
-        let path = path.clone();
-        self.proxy.save(
-            rev,
-            buffer_id,
+        self.proxy.apply_file_edits(
+            file_edits,
             Box::new(move |result| {
-                if let Ok(r) = result {
-                    event_sink.submit_command(
-                        LAPCE_UI_COMMAND,
-                        LapceUICommand::BufferSave(path, rev),
-                        Target::Auto,
-                    );
+                let resp = result.map_err(|e| anyhow!("{:?}", e)).and_then(|res| {
+                    Ok(serde_json::from_value::<ApplyFileEditsResponse>(res)?)
+                });
+                match resp {
+                    Ok(resp) => {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::ApplyBufferEdits(
+                                buffer_edits,
+                                resp,
+                                reply,
+                            ),
+                            Target::Auto,
+                        );
+                    }
+                    Err(e) => {
+                        eprintln!("failed to apply workspace edit: {}", e);
+                        reply.send(Err(e.to_string()));
+                    }
                 }
             }),
         );
     }
 
-    fn initiate_diagnositcs_offset(&mut self, path: &PathBuf) {
-        if let Some(diagnostics) = self.diagnostics.get_mut(path) {
-            if let Some(buffer) = self.open_files.get(path) {
-                for diagnostic in Arc::make_mut(diagnostics).iter_mut() {
-                    if diagnostic.range.is_none() {
-                        diagnostic.range = Some((
-                            buffer.offset_of_position(
-                                &diagnostic.diagnositc.range.start,
-                            ),
-                            buffer.offset_of_position(
-                                &diagnostic.diagnositc.range.end,
-                            ),
-                        ));
+    /// Splits the edits of a workspace edit into those of the open buffers,
+    /// with the rev their positions refer to, and those of the files on
+    /// disk. The buffers must still be at the version the server gave, and
+    /// at their rev in `revs` when it's given.
+    fn workspace_edit_files(
+        &self,
+        edit: &WorkspaceEdit,
+        revs: Option<&HashMap<PathBuf, u64>>,
+    ) -> Result<(
+        Vec<(PathBuf, u64, Vec<TextEdit>)>,
+        HashMap<PathBuf, Vec<TextEdit>>,
+    )> {
+        let mut buffer_edits = Vec::new();
+        let mut file_edits = HashMap::new();
+        for (url, (version, edits)) in workspace_edits(edit)? {
+            if edits_overlap(&edits) {
+                return Err(anyhow!("overlapping edits in {}", url));
+            }
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow!("{} isn't a file", url))?;
+            match self.open_files.get(&path) {
+                Some(buffer) if !buffer.loaded => {
+                    return Err(anyhow!("{:?} isn't loaded yet", path));
+                }
+                Some(buffer) => {
+                    // the server's version of the document is the rev it was
+                    // last sent
+                    if let Some(version) = version {
+                        if version as u64 != buffer.rev {
+                            return Err(anyhow!(
+                                "{:?} changed since version {}",
+                                path,
+                                version
+                            ));
+                        }
+                    }
+                    if let Some(revs) = revs {
+                        if revs.get(&path) != Some(&buffer.rev) {
+                            return Err(anyhow!(
+                                "{:?} changed since the edit was asked for",
+                                path
+                            ));
+                        }
                     }
+                    buffer_edits.push((path, buffer.rev, edits));
+                }
+                None => {
+                    file_edits.insert(path, edits);
                 }
             }
         }
+        Ok((buffer_edits, file_edits))
     }
 
-    fn update_diagnositcs_offset(&mut self, path: &PathBuf, delta: &RopeDelta) {
-        if let Some(diagnostics) = self.diagnostics.get_mut(path) {
-            if let Some(buffer) = self.open_files.get(path) {
-                let mut transformer = Transformer::new(delta);
-                for diagnostic in Arc::make_mut(diagnostics).iter_mut() {
-                    let (start, end) = diagnostic.range.clone().unwrap();
-                    let (new_start, new_end) = (
-                        transformer.transform(start, false),
-                        transformer.transform(end, true),
-                    );
-                    diagnostic.range = Some((new_start, new_end));
-                    if start != new_start {
-                        diagnostic.diagnositc.range.start =
-                            buffer.offset_to_position(new_start);
-                    }
-                    if end != new_end {
-                        diagnostic.diagnositc.range.end =
-                            buffer.offset_to_position(new_end);
-                        buffer.offset_to_position(new_end);
+    /// Makes the replacements `ids` of the preview `session_id`. The proxy
+    /// writes the files that aren't open, and the open buffers are edited
+    /// here so that the replacements can be undone.
+    pub fn apply_replace(
+        &mut self,
+        ctx: &mut EventCtx,
+        session_id: u64,
+        ids: &[usize],
+    ) {
+        let event_sink = ctx.get_external_handle();
+        self.proxy.apply_replace(
+            session_id,
+            ids.to_vec(),
+            Box::new(move |result| {
+                let resp = result.map_err(|e| anyhow!("{:?}", e)).and_then(|res| {
+                    Ok(serde_json::from_value::<ApplyReplaceResponse>(res)?)
+                });
+                match resp {
+                    Ok(resp) => {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::ApplyReplaceResponse(resp),
+                            Target::Auto,
+                        );
                     }
+                    Err(e) => eprintln!("failed to replace: {}", e),
                 }
-            }
-        }
+            }),
+        );
     }
 
-    fn cursor_apply_delta(&mut self, path: &PathBuf, delta: &RopeDelta) {
-        for (view_id, editor) in self.editors.iter_mut() {
-            match &editor.content {
-                EditorContent::Buffer(current_path) => {
-                    if current_path == path {
-                        Arc::make_mut(editor).cursor.apply_delta(delta);
+    /// The positions of the edits refer to revision `rev` of each buffer, so
+    /// nothing is applied if any of the buffers has changed since, or can't
+    /// be edited.
+    pub fn apply_buffer_edits(
+        &mut self,
+        ctx: &mut EventCtx,
+        edits: &[(PathBuf, u64, Vec<TextEdit>)],
+    ) -> Result<()> {
+        for (path, rev, _) in edits.iter() {
+            match self.open_files.get(path) {
+                Some(buffer) if buffer.loaded && buffer.rev == *rev => {
+                    if !buffer.editable
+                        || buffer.binary
+                        || buffer.large_file.is_some()
+                    {
+                        return Err(anyhow!("{:?} can't be edited", path));
                     }
                 }
-                EditorContent::None => {}
+                _ => {
+                    return Err(anyhow!(
+                        "{:?} changed before the edit could be applied",
+                        path
+                    ));
+                }
             }
         }
-    }
 
-    pub fn edit(
-        &mut self,
-        ctx: &mut EventCtx,
-        path: &PathBuf,
-        edits: Vec<(&Selection, &str)>,
-        edit_type: EditType,
-    ) -> Option<RopeDelta> {
-        self.initiate_diagnositcs_offset(path);
-        let proxy = self.proxy.clone();
-        let buffer = self.open_files.get_mut(path)?;
-        let delta =
-            Arc::make_mut(buffer).edit_multiple(ctx, edits, proxy, edit_type);
-        self.cursor_apply_delta(path, &delta);
-        self.update_diagnositcs_offset(path, &delta);
-        Some(delta)
+        for (path, _, edits) in edits.iter() {
+            let buffer = self.open_files.get(path).unwrap();
+            let edits: Vec<(Selection, String)> = edits
+                .iter()
+                .map(|edit| {
+                    let selection = Selection::region(
+                        buffer.offset_of_position(&edit.range.start),
+                        buffer.offset_of_position(&edit.range.end),
+                    );
+                    (selection, edit.new_text.clone())
+                })
+                .collect();
+            self.edit(
+                ctx,
+                path,
+                edits.iter().map(|(s, c)| (s, c.as_ref())).collect(),
+                EditType::Other,
+            );
+        }
+        Ok(())
     }
 
     pub fn jump_to_position(
@@ -1363,6 +2506,15 @@ impl LapceMainSplitData {
                     ));
                 }
             }
+
+            let buffer = self.open_files.get_mut(&path).unwrap();
+            let line = buffer.line_of_offset(offset);
+            if folding::is_hidden(&buffer.folds, line) {
+                folding::reveal(
+                    Arc::make_mut(&mut Arc::make_mut(buffer).folds),
+                    line,
+                );
+            }
         }
     }
 
@@ -1439,11 +2591,10 @@ impl LapceMainSplitData {
                         ));
 
                         if !open_files.contains_key(path) {
-                            let buffer = Arc::new(BufferNew::new(
-                                path.clone(),
-                                update_sender.clone(),
-                            ));
-                            open_files.insert(path.clone(), buffer.clone());
+                            let mut buffer =
+                                BufferNew::new(path.clone(), update_sender.clone());
+                            buffer.saved_folds = e.folds.clone();
+                            open_files.insert(path.clone(), Arc::new(buffer));
                         }
                     }
                     EditorContent::None => {}
@@ -1474,6 +2625,17 @@ impl LapceMainSplitData {
             editors.insert(editor.view_id, Arc::new(editor));
         }
 
+        // the editors are in the order they were saved in, which the
+        // saved layout has them by
+        let split = workspace_info
+            .and_then(|info| info.split.as_ref())
+            .and_then(|split| split.map(&|i: &usize| editors_order.get(*i).copied()))
+            .filter(|split| {
+                split.leaves().len() == editors_order.len()
+                    && editors_order.iter().all(|view_id| split.contains(view_id))
+            })
+            .unwrap_or_else(|| SplitTree::row(editors_order.clone()));
+
         let path = PathBuf::from("[Palette Preview Editor]");
         let editor = LapceEditorData::new(
             Some(palette_preview_editor),
@@ -1491,7 +2653,8 @@ impl LapceMainSplitData {
             tab_id: Arc::new(tab_id),
             split_id,
             editors,
-            editors_order: Arc::new(editors_order),
+            editors_order: Arc::new(split.leaves()),
+            split: Arc::new(split),
             open_files,
             active: Arc::new(active),
             update_sender,
@@ -1501,8 +2664,69 @@ impl LapceMainSplitData {
             show_code_actions: false,
             current_code_actions: 0,
             diagnostics: im::HashMap::new(),
+            diagnostics_counts: im::HashMap::new(),
             error_count: 0,
             warning_count: 0,
+            work_progress: im::HashMap::new(),
+            server_capabilities: im::HashMap::new(),
+            signature: None,
+            unsaved_backups: im::HashMap::new(),
+            closed_editors: Arc::new(
+                workspace_info
+                    .and_then(|info| info.closed.clone())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Keeps the file of an editor being closed, to be reopened.
+    pub fn push_closed_editor(&mut self, view_id: &WidgetId) {
+        let editor = match self.editors.get(view_id) {
+            Some(editor) => editor,
+            None => return,
+        };
+        if let EditorContent::Buffer(path) = &editor.content {
+            let position = self
+                .open_files
+                .get(path)
+                .map(|buffer| buffer.offset_to_position(editor.cursor.offset()));
+            recent::push_closed(
+                Arc::make_mut(&mut self.closed_editors),
+                ClosedEditor {
+                    path: path.clone(),
+                    position,
+                },
+            );
+        }
+    }
+
+    /// Whether an editor of the split has the file.
+    pub fn is_open(&self, path: &Path) -> bool {
+        self.editors_order.iter().any(|view_id| {
+            match self.editors.get(view_id).map(|editor| &editor.content) {
+                Some(EditorContent::Buffer(p)) => p == path,
+                _ => false,
+            }
+        })
+    }
+
+    /// Opens the last closed editor not open again in the active one, each
+    /// time one further back.
+    pub fn reopen_last_closed(&mut self, ctx: &mut EventCtx, config: &Config) {
+        let mut closed = (*self.closed_editors).clone();
+        let editor = recent::pop_closed(&mut closed, |path| self.is_open(path));
+        self.closed_editors = Arc::new(closed);
+        if let Some(editor) = editor {
+            self.jump_to_location(
+                ctx,
+                *self.active,
+                EditorLocationNew {
+                    path: editor.path,
+                    position: editor.position,
+                    scroll_offset: None,
+                },
+                config,
+            );
         }
     }
 
@@ -1569,6 +2793,28 @@ pub struct LapceEditorData {
     pub last_movement: Movement,
     pub last_inline_find: Option<(InlineFindDirection, String)>,
     pub inline_find: Option<InlineFindDirection>,
+    pub selection_expansion: Option<SelectionExpansion>,
+    pub vim: VimState,
+    /// The selection select next occurrence last made, and whether it was
+    /// whole words, to go on with when it's still the selection.
+    pub occurrences: Option<(Selection, bool)>,
+    /// A drag selects a rectangle rather than from one offset to another.
+    pub column_selection: bool,
+    /// Where the last click was, the corner a column selection is from.
+    pub column_anchor: Option<usize>,
+}
+
+/// Where expand selection started and the selections it went through, so
+/// shrink selection can step back through them.
+#[derive(Clone, Debug)]
+pub struct SelectionExpansion {
+    pub rev: u64,
+    /// The offsets the selection ranges are asked for, kept the same for
+    /// every step so the proxy can answer from its cache.
+    pub offsets: Vec<usize>,
+    /// The cursor before each step, the last one most recent.
+    pub previous: Vec<Cursor>,
+    pub current: Selection,
 }
 
 impl LapceEditorData {
@@ -1598,6 +2844,11 @@ impl LapceEditorData {
             last_movement: Movement::Left,
             inline_find: None,
             last_inline_find: None,
+            selection_expansion: None,
+            vim: VimState::default(),
+            occurrences: None,
+            column_selection: false,
+            column_anchor: None,
         }
     }
 
@@ -1733,13 +2984,24 @@ impl LapceEditorViewData {
         let prev_offset = self.buffer.prev_code_boundary(offset);
         if self.buffer.code_actions.get(&prev_offset).is_none() {
             let buffer_id = self.buffer.id;
-            let position = self.buffer.offset_to_position(prev_offset);
+            let range = Range {
+                start: self.buffer.offset_to_position(prev_offset),
+                end: self.buffer.offset_to_position(offset),
+            };
+            let diagnostics = self
+                .diagnostics
+                .iter()
+                .map(|d| &d.diagnositc)
+                .filter(|d| d.range.start <= range.end && range.start <= d.range.end)
+                .cloned()
+                .collect();
             let path = self.buffer.path.clone();
             let rev = self.buffer.rev;
             let event_sink = ctx.get_external_handle();
             self.proxy.get_code_actions(
                 buffer_id,
-                position,
+                range,
+                diagnostics,
                 Box::new(move |result| {
                     if let Ok(res) = result {
                         if let Ok(resp) =
@@ -3637,14 +4899,23 @@ pub fn hex_to_color(hex: &str) -> Result<Color> {
     ))
 }
 
+/// Parses and highlights the text of an update, incrementally when the
+/// tree of its buffer can be edited to it. A parse of an edit that takes
+/// over `PARSE_BUDGET` with other updates waiting is sent to the back of
+/// the queue, to be done in one go then, the core showing the highlights
+/// it has, shifted by the edits, meanwhile.
 fn buffer_receive_update(
-    update: BufferUpdate,
+    mut update: BufferUpdate,
+    receiver: &Receiver<UpdateEvent>,
+    sender: &Sender<UpdateEvent>,
     parsers: &mut HashMap<LapceLanguage, Parser>,
     highlighter: &mut Highlighter,
     highlight_configs: &mut HashMap<
         LapceLanguage,
         (HighlightConfiguration, Vec<String>),
     >,
+    syntax_styles: &mut HashMap<BufferId, (u64, Spans<Style>)>,
+    syntax_trees: &mut HashMap<BufferId, SyntaxState>,
     event_sink: &ExtEventSink,
     tab_id: WidgetId,
 ) {
@@ -3653,76 +4924,223 @@ fn buffer_receive_update(
         parsers.insert(update.language, parser);
     }
     let parser = parsers.get_mut(&update.language).unwrap();
-    if let Some(tree) = parser.parse(
-        update.rope.slice_to_cow(0..update.rope.len()).as_bytes(),
-        None,
-    ) {
-        event_sink.submit_command(
-            LAPCE_UI_COMMAND,
-            LapceUICommand::UpdateSyntaxTree {
-                id: update.id,
-                path: update.path.clone(),
-                rev: update.rev,
-                tree,
-            },
-            Target::Widget(tab_id),
-        );
+    let text = update.rope.to_string();
+    let text = text.as_bytes();
+
+    let mut incremental = None;
+    if let Some(mut state) = syntax_trees.remove(&update.id) {
+        let old_rev = state.rev;
+        let old_styles = match syntax_styles.remove(&update.id) {
+            Some((rev, styles)) if rev == old_rev => Some(styles),
+            _ => None,
+        };
+        if let Some(mut styles) = old_styles {
+            let hurry = !state.is_stale() && !receiver.is_empty();
+            if state.edit(update.rev, &update.deltas) {
+                for (delta_rev, delta) in &update.deltas {
+                    if *delta_rev > old_rev {
+                        styles.apply_shape(delta);
+                    }
+                }
+                match state.reparse(parser, text, hurry) {
+                    Some(changed) => incremental = Some((state, styles, changed)),
+                    None => {
+                        syntax_styles.insert(update.id, (update.rev, styles));
+                        syntax_trees.insert(update.id, state);
+                        // the tree has the edits already
+                        update.deltas.clear();
+                        sender.send(UpdateEvent::Buffer(update));
+                        return;
+                    }
+                }
+            }
+        }
     }
 
-    if !update.semantic_tokens {
-        if !highlight_configs.contains_key(&update.language) {
-            let (highlight_config, highlight_names) =
-                new_highlight_config(update.language);
-            highlight_configs
-                .insert(update.language, (highlight_config, highlight_names));
+    let (state, highlights, changed) = match incremental {
+        Some((state, mut styles, changed)) => {
+            if !highlight_configs.contains_key(&update.language) {
+                highlight_configs
+                    .insert(update.language, new_highlight_config(update.language));
+            }
+            let (config, names) = highlight_configs.get(&update.language).unwrap();
+            for iv in &changed {
+                let spans =
+                    highlight_interval(&config.query, names, &state.tree, text, *iv);
+                let brackets = rainbow_brackets(&update.rope, &state.tree, *iv);
+                styles.edit(*iv, merge_styles(&spans, &brackets));
+            }
+            (state, styles, Some(changed))
         }
-        let (highlight_config, highlight_names) =
-            highlight_configs.get(&update.language).unwrap();
-        let mut current_hl: Option<Highlight> = None;
-        let mut highlights = SpansBuilder::new(update.rope.len());
-        for hightlight in highlighter
-            .highlight(
-                highlight_config,
-                update.rope.slice_to_cow(0..update.rope.len()).as_bytes(),
+        None => {
+            let tree = match parser.parse(text, None) {
+                Some(tree) => tree,
+                None => return,
+            };
+            let highlights = merge_styles(
+                &syntax_highlights(&update, highlighter, highlight_configs),
+                &rainbow_brackets(
+                    &update.rope,
+                    &tree,
+                    Interval::new(0, update.rope.len()),
+                ),
+            );
+            (
+                SyntaxState::new(update.rev, update.rope.clone(), tree),
+                highlights,
                 None,
-                |_| None,
             )
-            .unwrap()
-        {
-            if let Ok(highlight) = hightlight {
-                match highlight {
-                    HighlightEvent::Source { start, end } => {
-                        if let Some(hl) = current_hl {
-                            if let Some(hl) = highlight_names.get(hl.0) {
-                                highlights.add_span(
-                                    Interval::new(start, end),
-                                    Style {
-                                        fg_color: Some(hl.to_string()),
-                                    },
-                                );
-                            }
+        }
+    };
+    event_sink.submit_command(
+        LAPCE_UI_COMMAND,
+        LapceUICommand::UpdateSyntaxTree {
+            id: update.id,
+            path: update.path.clone(),
+            rev: update.rev,
+            tree: state.tree.clone(),
+        },
+        Target::Widget(tab_id),
+    );
+    syntax_trees.insert(update.id, state);
+
+    let changed_lines = changed.map(|changed| {
+        changed
+            .iter()
+            .filter(|iv| !iv.is_empty())
+            .map(|iv| {
+                let start = update.rope.line_of_offset(iv.start);
+                (start, update.rope.line_of_offset(iv.end - 1) + 1)
+            })
+            .collect()
+    });
+    let styles = match update.semantic_styles.as_ref() {
+        // semantic styles shifted by the edits since they came in
+        Some(semantic) if semantic.len() == highlights.len() => {
+            merge_styles(&highlights, semantic)
+        }
+        _ => highlights.clone(),
+    };
+    syntax_styles.insert(update.id, (update.rev, highlights));
+    event_sink.submit_command(
+        LAPCE_UI_COMMAND,
+        LapceUICommand::UpdateStyle {
+            id: update.id,
+            path: update.path,
+            rev: update.rev,
+            highlights: styles,
+            semantic_styles: None,
+            changed_lines,
+        },
+        Target::Widget(tab_id),
+    );
+}
+
+fn buffer_receive_semantic_tokens(
+    update: BufferUpdate,
+    lines: Vec<LineStyles>,
+    highlighter: &mut Highlighter,
+    highlight_configs: &mut HashMap<
+        LapceLanguage,
+        (HighlightConfiguration, Vec<String>),
+    >,
+    syntax_styles: &mut HashMap<BufferId, (u64, Spans<Style>)>,
+    event_sink: &ExtEventSink,
+    tab_id: WidgetId,
+) {
+    let len = update.rope.len();
+    let num_lines = update.rope.line_of_offset(len) + 1;
+    let mut semantic = SpansBuilder::new(len);
+    for line_styles in lines {
+        if line_styles.line >= num_lines {
+            break;
+        }
+        let line_start = update.rope.offset_of_line(line_styles.line);
+        for (start, end, style) in line_styles.styles {
+            semantic.add_span(
+                Interval::new(
+                    (line_start + start).min(len),
+                    (line_start + end).min(len),
+                ),
+                Style {
+                    fg_color: Some(style),
+                },
+            );
+        }
+    }
+    let semantic = semantic.build();
+
+    let syntax = match syntax_styles.get(&update.id) {
+        Some((rev, syntax)) if *rev == update.rev => syntax.clone(),
+        _ => {
+            let syntax = syntax_highlights(&update, highlighter, highlight_configs);
+            syntax_styles.insert(update.id, (update.rev, syntax.clone()));
+            syntax
+        }
+    };
+    event_sink.submit_command(
+        LAPCE_UI_COMMAND,
+        LapceUICommand::UpdateStyle {
+            id: update.id,
+            path: update.path,
+            rev: update.rev,
+            highlights: merge_styles(&syntax, &semantic),
+            semantic_styles: Some(semantic),
+            changed_lines: None,
+        },
+        Target::Widget(tab_id),
+    );
+}
+
+fn syntax_highlights(
+    update: &BufferUpdate,
+    highlighter: &mut Highlighter,
+    highlight_configs: &mut HashMap<
+        LapceLanguage,
+        (HighlightConfiguration, Vec<String>),
+    >,
+) -> Spans<Style> {
+    if !highlight_configs.contains_key(&update.language) {
+        let (highlight_config, highlight_names) =
+            new_highlight_config(update.language);
+        highlight_configs
+            .insert(update.language, (highlight_config, highlight_names));
+    }
+    let (highlight_config, highlight_names) =
+        highlight_configs.get(&update.language).unwrap();
+    let mut current_hl: Option<Highlight> = None;
+    let mut highlights = SpansBuilder::new(update.rope.len());
+    for hightlight in highlighter
+        .highlight(
+            highlight_config,
+            update.rope.slice_to_cow(0..update.rope.len()).as_bytes(),
+            None,
+            |_| None,
+        )
+        .unwrap()
+    {
+        if let Ok(highlight) = hightlight {
+            match highlight {
+                HighlightEvent::Source { start, end } => {
+                    if let Some(hl) = current_hl {
+                        if let Some(hl) = highlight_names.get(hl.0) {
+                            highlights.add_span(
+                                Interval::new(start, end),
+                                Style {
+                                    fg_color: Some(hl.to_string()),
+                                },
+                            );
                         }
                     }
-                    HighlightEvent::HighlightStart(hl) => {
-                        current_hl = Some(hl);
-                    }
-                    HighlightEvent::HighlightEnd => current_hl = None,
                 }
+                HighlightEvent::HighlightStart(hl) => {
+                    current_hl = Some(hl);
+                }
+                HighlightEvent::HighlightEnd => current_hl = None,
             }
         }
-        let highlights = highlights.build();
-        event_sink.submit_command(
-            LAPCE_UI_COMMAND,
-            LapceUICommand::UpdateStyle {
-                id: update.id,
-                path: update.path,
-                rev: update.rev,
-                highlights,
-                semantic_tokens: false,
-            },
-            Target::Widget(tab_id),
-        );
     }
+    highlights.build()
 }
 
 fn str_is_pair_left(c: &str) -> bool {
@@ -3752,3 +5170,59 @@ fn str_matching_pair(c: &str) -> Option<char> {
 }
 
 fn progress_term_event() {}
+
+fn edits_overlap(edits: &[TextEdit]) -> bool {
+    let mut ranges: Vec<&Range> = edits.iter().map(|edit| &edit.range).collect();
+    ranges.sort_by_key(|range| (range.start, range.end));
+    ranges
+        .windows(2)
+        .any(|ranges| ranges[1].start < ranges[0].end)
+}
+
+/// Checks out `target`, asking whether to overwrite the changes that would
+/// stop it.
+fn git_checkout(proxy: Arc<LapceProxy>, target: String, create: bool, force: bool) {
+    let local_proxy = proxy.clone();
+    proxy.git_checkout(
+        &target.clone(),
+        create,
+        force,
+        Box::new(move |result| match result {
+            Ok(_) => (),
+            Err(xi_rpc::Error::RemoteError(xi_rpc::RemoteError::Custom {
+                code,
+                message,
+                data,
+            })) if code == CHECKOUT_ERROR_CODE => {
+                let files = data
+                    .and_then(|data| {
+                        serde_json::from_value::<CheckoutConflict>(data).ok()
+                    })
+                    .map(|conflict| conflict.files)
+                    .unwrap_or_default();
+                // not on the thread the responses come in on
+                thread::spawn(move || {
+                    let files: Vec<String> = files
+                        .iter()
+                        .map(|file| file.to_string_lossy().to_string())
+                        .collect();
+                    let answer = tinyfiledialogs::message_box_yes_no(
+                        "Checkout",
+                        &format!(
+                            "{}:\n{}\n\nDiscard the changes and check out anyway?",
+                            message,
+                            files.join("\n")
+                        ),
+                        tinyfiledialogs::MessageBoxIcon::Warning,
+                        tinyfiledialogs::YesNo::No,
+                    );
+                    if matches!(answer, tinyfiledialogs::YesNo::Yes) {
+                        // the branch was made by the first try
+                        git_checkout(local_proxy, target, false, true);
+                    }
+                });
+            }
+            Err(e) => eprintln!("checkout of {} failed: {:?}", target, e),
+        }),
+    );
+}
@@ -1,21 +1,27 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use anyhow::{anyhow, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use directories::ProjectDirs;
-use druid::Vec2;
+use druid::{Vec2, WidgetId};
 use lsp_types::Position;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    command_usage::CommandUsage,
     data::{EditorContent, EditorType, LapceData, LapceTabData, LapceWindowData},
+    folding::{self, SavedFolds},
     movement::Cursor,
+    recent::ClosedEditor,
+    split_tree::SplitTree,
     state::LapceWorkspace,
+    undo_history::content_hash,
 };
 
 pub enum SaveEvent {
     Workspace(LapceWorkspace, WorkspaceInfo),
     Tabs(TabsInfo),
+    CommandUsage(HashMap<String, CommandUsage>),
 }
 
 #[derive(Clone)]
@@ -28,6 +34,10 @@ pub struct LapceDb {
 pub struct WorkspaceInfo {
     pub active_editor: usize,
     pub editors: Vec<EditorInfo>,
+    /// The layout of the editors, by their index in `editors`.
+    pub split: Option<SplitTree<usize>>,
+    /// The editors closed, the last on top.
+    pub closed: Option<Vec<ClosedEditor>>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -41,6 +51,7 @@ pub struct EditorInfo {
     pub content: EditorContent,
     pub scroll_offset: (f64, f64),
     pub position: Option<Position>,
+    pub folds: Option<SavedFolds>,
 }
 
 impl LapceDb {
@@ -62,6 +73,9 @@ impl LapceDb {
                     SaveEvent::Tabs(info) => {
                         local_db.insert_tabs(&info);
                     }
+                    SaveEvent::CommandUsage(usage) => {
+                        local_db.insert_command_usage(&usage);
+                    }
                 }
             }
         });
@@ -98,6 +112,35 @@ impl LapceDb {
         Ok(workspaces)
     }
 
+    pub fn get_command_usage(&self) -> Result<HashMap<String, CommandUsage>> {
+        let db = self.get_db()?;
+        let usage = db
+            .get(b"command_usage")?
+            .ok_or(anyhow!("can't find command usage"))?;
+        let usage = std::str::from_utf8(&usage)?;
+        let usage = serde_json::from_str(usage)?;
+        Ok(usage)
+    }
+
+    pub fn save_command_usage(
+        &self,
+        usage: HashMap<String, CommandUsage>,
+    ) -> Result<()> {
+        self.save_tx.send(SaveEvent::CommandUsage(usage))?;
+        Ok(())
+    }
+
+    fn insert_command_usage(
+        &self,
+        usage: &HashMap<String, CommandUsage>,
+    ) -> Result<()> {
+        let usage = serde_json::to_string(usage)?;
+        let db = self.get_db()?;
+        db.insert(b"command_usage", usage.as_str())?;
+        db.flush()?;
+        Ok(())
+    }
+
     pub fn get_workspace_info(
         &self,
         workspace: &LapceWorkspace,
@@ -147,22 +190,37 @@ impl LapceDb {
                     active_editor = i;
                 }
                 let editor = data.main_split.editors.get(view_id).unwrap();
+                let (position, folds) = match &editor.content {
+                    EditorContent::Buffer(path) => {
+                        let buffer = data.main_split.open_files.get(path).unwrap();
+                        (
+                            Some(buffer.offset_to_position(editor.cursor.offset())),
+                            folding::saved(
+                                &buffer.folds,
+                                content_hash(&buffer.rope),
+                            ),
+                        )
+                    }
+                    EditorContent::None => (None, None),
+                };
                 EditorInfo {
                     content: editor.content.clone(),
                     scroll_offset: (editor.scroll_offset.x, editor.scroll_offset.y),
-                    position: if let EditorContent::Buffer(path) = &editor.content {
-                        let buffer =
-                            data.main_split.open_files.get(path).unwrap().clone();
-                        Some(buffer.offset_to_position(editor.cursor.offset()))
-                    } else {
-                        None
-                    },
+                    position,
+                    folds,
                 }
             })
             .collect();
+        let order = &data.main_split.editors_order;
+        let split = data
+            .main_split
+            .split
+            .map(&|view_id: &WidgetId| order.iter().position(|id| id == view_id));
         let workspace_info = WorkspaceInfo {
             editors,
             active_editor,
+            split,
+            closed: Some((*data.main_split.closed_editors).clone()),
         };
 
         self.insert_workspace(workspace, &workspace_info)?;
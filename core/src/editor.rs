@@ -2,15 +2,18 @@ use crate::buffer::{has_unmatched_pair, EditType};
 use crate::command::{
     CommandTarget, LapceCommandNew, LapceWorkbenchCommand, LAPCE_NEW_COMMAND,
 };
-use crate::completion::{CompletionData, CompletionStatus, Snippet};
+use crate::completion::{completion_edits, CompletionData, CompletionStatus};
 use crate::config::{Config, LapceTheme, LOGO};
 use crate::data::{
     EditorContent, EditorDiagnostic, EditorKind, EditorType, FocusArea,
     InlineFindDirection, LapceEditorData, LapceMainSplitData, LapceTabData,
-    RegisterData,
+    RegisterData, SelectionExpansion,
 };
 use crate::find::Find;
+use crate::folding;
+use crate::hex::{hex_lines, num_hex_lines, BYTES_PER_LINE};
 use crate::keypress::{KeyMap, KeyPress, KeyPressFocus};
+use crate::multi_cursor;
 use crate::proxy::LapceProxy;
 use crate::scroll::LapceIdentityWrapper;
 use crate::signature::SignatureState;
@@ -18,6 +21,7 @@ use crate::split::LapceSplitNew;
 use crate::state::LapceWorkspace;
 use crate::svg::{file_svg_new, get_svg, logo_svg};
 use crate::theme::OldLapceTheme;
+use crate::vim::{self, Operator, VimEdit, VimResult};
 use crate::{buffer::get_word_property, state::LapceFocus};
 use crate::{buffer::matching_char, data::LapceEditorViewData};
 use crate::{buffer::previous_has_unmatched_pair, movement::Cursor};
@@ -31,7 +35,7 @@ use crate::{
     scroll::LapcePadding,
 };
 use crate::{
-    buffer::{BufferId, BufferUIState, InvalLines},
+    buffer::{BufferId, BufferUIState, Fold, InvalLines},
     command::{
         EnsureVisiblePosition, LapceCommand, LapceUICommand, LAPCE_UI_COMMAND,
     },
@@ -62,15 +66,27 @@ use druid::{
         PietTextLayout, Text, TextAttribute, TextLayout as TextLayoutTrait,
         TextLayoutBuilder,
     },
-    FontWeight,
+    FontStyle, FontWeight,
 };
 use fzyr::has_match;
 use itertools::Itertools;
-use lsp_types::CompletionTextEdit;
+use lapce_proxy::buffer::LARGE_FILE_CHUNK_LINES;
+use lapce_proxy::comment::ToggleCommentResponse;
+use lapce_proxy::conflicts::{Conflict, ConflictChoice, ConflictResolution};
+use lapce_proxy::dispatch::{
+    BinaryChunkResponse, BufferLinesResponse, ReferenceLocation,
+};
+use lapce_proxy::lsp::{
+    DocumentLinkItem, DocumentLinksResponse, FoldingRangesResponse, InlayHintItem,
+    InlayHintKind, InlayHintsResponse, TIMEOUT_ERROR_CODE, UNSUPPORTED_ERROR_CODE,
+};
+use lapce_proxy::snippet::SnippetVariables;
+use lapce_proxy::symbols::EnclosingScopesResponse;
 use lsp_types::{
     CodeActionOrCommand, CodeActionResponse, CompletionItem, CompletionResponse,
-    Diagnostic, DiagnosticSeverity, DocumentChanges, GotoDefinitionResponse,
-    Location, Position, SignatureHelp, TextEdit, Url, WorkspaceEdit,
+    Diagnostic, DiagnosticSeverity, DocumentChanges, DocumentHighlightKind,
+    GotoDefinitionResponse, Location, Position, PrepareRenameResponse, Range,
+    SignatureHelp, TextEdit, Url, WorkspaceEdit,
 };
 use serde_json::Value;
 use std::thread;
@@ -259,6 +275,228 @@ impl LapceEditorBufferData {
         }
     }
 
+    /// The first and last lines in view.
+    fn visible_lines(&self) -> (usize, usize) {
+        let line_height = self.config.editor.line_height as f64;
+        let start_line =
+            (self.editor.scroll_offset.y / line_height).floor() as usize;
+        let end_line = ((self.editor.size.borrow().height
+            + self.editor.scroll_offset.y)
+            / line_height)
+            .ceil() as usize;
+        let last_line = self.buffer.last_line();
+        (start_line.min(last_line), end_line.min(last_line))
+    }
+
+    /// Asks for the inlay hints of the lines in view, which the proxy
+    /// mostly answers from its cache.
+    fn get_inlay_hints(&self, ctx: &mut UpdateCtx) {
+        if !self.buffer.loaded || self.buffer.local {
+            return;
+        }
+        let (start_line, end_line) = self.visible_lines();
+        let range = Range {
+            start: Position::new(start_line as u32, 0),
+            end: self
+                .buffer
+                .offset_to_position(self.buffer.line_end_offset(end_line, true)),
+        };
+        let path = self.buffer.path.clone();
+        let event_sink = ctx.get_external_handle();
+        self.proxy.get_inlay_hints(
+            self.buffer.id,
+            range,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(resp) =
+                        serde_json::from_value::<InlayHintsResponse>(res)
+                    {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateInlayHints(path, resp),
+                            Target::Auto,
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Asks for the scopes of the first line in view, for the header. The
+    /// proxy answers from the symbols it has for the rev, so scrolling
+    /// doesn't ask the server again.
+    fn get_enclosing_scopes(&self, ctx: &mut UpdateCtx) {
+        if !self.buffer.loaded || self.buffer.local {
+            return;
+        }
+        let (start_line, _) = self.visible_lines();
+        let path = self.buffer.path.clone();
+        let event_sink = ctx.get_external_handle();
+        self.proxy.enclosing_scopes(
+            self.buffer.id,
+            start_line as u32,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(resp) =
+                        serde_json::from_value::<EnclosingScopesResponse>(res)
+                    {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateEnclosingScopes(path, resp),
+                            Target::Auto,
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Asks for the next chunk of a large file when the lines in view get
+    /// close to the end of what's loaded.
+    fn load_more_lines(&self, ctx: &mut UpdateCtx) {
+        let (loaded, num_lines) = match self.buffer.large_file {
+            Some(large_file) => large_file,
+            None => return,
+        };
+        let (_, end_line) = self.visible_lines();
+        if loaded >= num_lines
+            || end_line + LARGE_FILE_CHUNK_LINES / 2 < loaded
+            || *self.buffer.loading_lines.borrow()
+        {
+            return;
+        }
+        *self.buffer.loading_lines.borrow_mut() = true;
+
+        let path = self.buffer.path.clone();
+        let event_sink = ctx.get_external_handle();
+        if self.buffer.binary {
+            let offset = (loaded * BYTES_PER_LINE) as u64;
+            self.proxy.open_binary(
+                path.clone(),
+                offset,
+                LARGE_FILE_CHUNK_LINES * BYTES_PER_LINE,
+                Box::new(move |result| {
+                    let bytes = result
+                        .ok()
+                        .and_then(|res| {
+                            serde_json::from_value::<BinaryChunkResponse>(res).ok()
+                        })
+                        .and_then(|chunk| base64::decode(chunk.data).ok())
+                        .unwrap_or_default();
+                    let lines = BufferLinesResponse {
+                        text: hex_lines(offset, &bytes),
+                        end_line: loaded + num_hex_lines(bytes.len() as u64),
+                    };
+                    event_sink.submit_command(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::AppendBufferLines(path, loaded, lines),
+                        Target::Auto,
+                    );
+                }),
+            );
+            return;
+        }
+        self.proxy.get_buffer_lines(
+            self.buffer.id,
+            loaded,
+            loaded + LARGE_FILE_CHUNK_LINES,
+            Box::new(move |result| {
+                let lines = result
+                    .ok()
+                    .and_then(|res| {
+                        serde_json::from_value::<BufferLinesResponse>(res).ok()
+                    })
+                    .unwrap_or(BufferLinesResponse {
+                        text: String::new(),
+                        end_line: loaded,
+                    });
+                event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::AppendBufferLines(path, loaded, lines),
+                    Target::Auto,
+                );
+            }),
+        );
+    }
+
+    /// Asks for the folding ranges, which the proxy holds back until the
+    /// buffer stops changing.
+    fn get_folding_ranges(&self, ctx: &mut UpdateCtx) {
+        if !self.buffer.loaded || self.buffer.local {
+            return;
+        }
+        let path = self.buffer.path.clone();
+        let event_sink = ctx.get_external_handle();
+        self.proxy.get_folding_ranges(
+            self.buffer.id,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(resp) =
+                        serde_json::from_value::<FoldingRangesResponse>(res)
+                    {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateFoldingRanges(path, resp),
+                            Target::Auto,
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Asks for the links of the lines in view, to be opened with
+    /// ctrl-click.
+    fn get_document_links(&self, ctx: &mut UpdateCtx) {
+        if !self.buffer.loaded || self.buffer.local {
+            return;
+        }
+        let (start_line, end_line) = self.visible_lines();
+        let range = Range {
+            start: Position::new(start_line as u32, 0),
+            end: self
+                .buffer
+                .offset_to_position(self.buffer.line_end_offset(end_line, true)),
+        };
+        let path = self.buffer.path.clone();
+        let event_sink = ctx.get_external_handle();
+        self.proxy.get_document_links(
+            self.buffer.id,
+            range,
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(resp) =
+                        serde_json::from_value::<DocumentLinksResponse>(res)
+                    {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateDocumentLinks(path, resp),
+                            Target::Auto,
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Hides the signature help once the cursor leaves the line of the call
+    /// or goes back to normal mode.
+    fn check_signature(&mut self) {
+        let signature = match self.main_split.signature.as_ref() {
+            Some(signature) => signature.clone(),
+            None => return,
+        };
+        let line = self.buffer.line_of_offset(self.editor.cursor.offset());
+        if signature.buffer_id == self.buffer.id
+            && line == signature.position.line as usize
+            && self.editor.cursor.is_insert()
+        {
+            return;
+        }
+        self.main_split.signature = None;
+        self.proxy.cancel_signature(signature.buffer_id);
+    }
+
     fn get_code_actions(&self, ctx: &mut EventCtx) {
         if !self.buffer.loaded {
             return;
@@ -270,13 +508,30 @@ impl LapceEditorBufferData {
         let prev_offset = self.buffer.prev_code_boundary(offset);
         if self.buffer.code_actions.get(&prev_offset).is_none() {
             let buffer_id = self.buffer.id;
-            let position = self.buffer.offset_to_position(prev_offset);
+            let range = Range {
+                start: self.buffer.offset_to_position(prev_offset),
+                end: self.buffer.offset_to_position(offset),
+            };
+            let diagnostics = self
+                .diagnostics()
+                .map(|diagnostics| {
+                    diagnostics
+                        .iter()
+                        .map(|d| &d.diagnositc)
+                        .filter(|d| {
+                            d.range.start <= range.end && range.start <= d.range.end
+                        })
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
             let path = self.buffer.path.clone();
             let rev = self.buffer.rev;
             let event_sink = ctx.get_external_handle();
             self.proxy.get_code_actions(
                 buffer_id,
-                position,
+                range,
+                diagnostics,
                 Box::new(move |result| {
                     if let Ok(res) = result {
                         if let Ok(resp) =
@@ -365,113 +620,83 @@ impl LapceEditorBufferData {
         }
     }
 
+    /// Applies the primary edit of the item together with its
+    /// `additionalTextEdits`, in one delta so a single undo reverts the
+    /// completion, then puts the cursor on the first snippet placeholder.
     fn apply_completion_item(
         &mut self,
         ctx: &mut EventCtx,
         item: &CompletionItem,
     ) -> Result<()> {
-        let additioal_edit = item.additional_text_edits.as_ref().map(|edits| {
-            edits
-                .iter()
-                .map(|edit| {
-                    let selection = Selection::region(
-                        self.buffer.offset_of_position(&edit.range.start),
-                        self.buffer.offset_of_position(&edit.range.end),
-                    );
-                    (selection, edit.new_text.clone())
-                })
-                .collect::<Vec<(Selection, String)>>()
-        });
-        let additioal_edit = additioal_edit.as_ref().map(|edits| {
-            edits
-                .into_iter()
-                .map(|(selection, c)| (selection, c.as_str()))
-                .collect()
-        });
-
-        let text_format = item
-            .insert_text_format
-            .unwrap_or(lsp_types::InsertTextFormat::PlainText);
-        if let Some(edit) = &item.text_edit {
-            match edit {
-                CompletionTextEdit::Edit(edit) => {
-                    let offset = self.editor.cursor.offset();
-                    let start_offset = self.buffer.prev_code_boundary(offset);
-                    let end_offset = self.buffer.next_code_boundary(offset);
-                    let edit_start =
-                        self.buffer.offset_of_position(&edit.range.start);
-                    let edit_end = self.buffer.offset_of_position(&edit.range.end);
-                    let selection = Selection::region(
-                        start_offset.min(edit_start),
-                        end_offset.max(edit_end),
-                    );
-                    match text_format {
-                        lsp_types::InsertTextFormat::PlainText => {
-                            let (selection, _) = self.edit(
-                                ctx,
-                                &selection,
-                                &edit.new_text,
-                                additioal_edit,
-                                true,
-                                EditType::InsertChars,
-                            );
-                            self.set_cursor_after_change(selection);
-                            return Ok(());
-                        }
-                        lsp_types::InsertTextFormat::Snippet => {
-                            let snippet = Snippet::from_str(&edit.new_text)?;
-                            let text = snippet.text();
-                            let (selection, delta) = self.edit(
-                                ctx,
-                                &selection,
-                                &text,
-                                additioal_edit,
-                                true,
-                                EditType::InsertChars,
-                            );
-
-                            let mut transformer = Transformer::new(&delta);
-                            let offset = transformer
-                                .transform(start_offset.min(edit_start), false);
-                            let snippet_tabs = snippet.tabs(offset);
-
-                            if snippet_tabs.len() == 0 {
-                                self.set_cursor_after_change(selection);
-                                return Ok(());
-                            }
-
-                            let mut selection = Selection::new();
-                            let (tab, (start, end)) = &snippet_tabs[0];
-                            let region = SelRegion::new(*start, *end, None);
-                            selection.add_region(region);
-                            self.set_cursor(Cursor::new(
-                                CursorMode::Insert(selection),
-                                None,
-                            ));
-                            Arc::make_mut(&mut self.editor)
-                                .add_snippet_placeholders(snippet_tabs);
-                            return Ok(());
-                        }
-                    }
-                }
-                CompletionTextEdit::InsertAndReplace(_) => (),
-            }
-        }
-
         let offset = self.editor.cursor.offset();
-        let start_offset = self.buffer.prev_code_boundary(offset);
-        let end_offset = self.buffer.next_code_boundary(offset);
-        let selection = Selection::region(start_offset, end_offset);
-
-        let (selection, _) = self.edit(
+        let word = (
+            self.buffer.prev_code_boundary(offset),
+            self.buffer.next_code_boundary(offset),
+        );
+        let line = self.buffer.line_of_offset(offset);
+        let variables = SnippetVariables {
+            selected_text: String::new(),
+            current_line: self
+                .buffer
+                .line_content(line)
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_string(),
+            current_word: self.buffer.slice_to_cow(word.0..word.1).to_string(),
+            line_index: line,
+            path: Some(self.buffer.path.clone()),
+            workspace: self.workspace.as_ref().map(|w| w.path.clone()),
+            clipboard: Application::global()
+                .clipboard()
+                .get_string()
+                .unwrap_or_default(),
+        };
+        let completion_edits = completion_edits(
+            item,
+            word,
+            |position| self.buffer.offset_of_position(position),
+            &variables,
+        )?;
+
+        let selections = completion_edits
+            .edits
+            .iter()
+            .map(|(start, end, text)| (Selection::region(*start, *end), text))
+            .collect::<Vec<(Selection, &String)>>();
+        let (primary, text) = &selections[completion_edits.primary];
+        let (primary_start, _, _) = completion_edits.edits[completion_edits.primary];
+        let additional_edit = selections
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != completion_edits.primary)
+            .map(|(_, (selection, text))| (selection, text.as_str()))
+            .collect::<Vec<(&Selection, &str)>>();
+        let (selection, delta) = self.edit(
             ctx,
-            &selection,
-            item.insert_text.as_ref().unwrap_or(&item.label),
-            additioal_edit,
+            primary,
+            text,
+            Some(additional_edit),
             true,
             EditType::InsertChars,
         );
-        self.set_cursor_after_change(selection);
+
+        let snippet_tabs = match completion_edits.snippet.as_ref() {
+            Some(snippet) => {
+                let mut transformer = Transformer::new(&delta);
+                snippet.tabs(transformer.transform(primary_start, false))
+            }
+            None => Vec::new(),
+        };
+        if snippet_tabs.len() == 0 {
+            self.set_cursor_after_change(selection);
+            return Ok(());
+        }
+
+        let mut selection = Selection::new();
+        let (_, (start, end)) = &snippet_tabs[0];
+        let region = SelRegion::new(*start, *end, None);
+        selection.add_region(region);
+        self.set_cursor(Cursor::new(CursorMode::Insert(selection), None));
+        Arc::make_mut(&mut self.editor).add_snippet_placeholders(snippet_tabs);
         Ok(())
     }
 
@@ -515,6 +740,8 @@ impl LapceEditorBufferData {
             && completion.buffer_id == self.buffer.id
         {
             completion.update_input(input.clone());
+            completion
+                .resolve_current_item(self.proxy.clone(), ctx.get_external_handle());
 
             if !completion.input_items.contains_key("") {
                 let event_sink = ctx.get_external_handle();
@@ -550,6 +777,7 @@ impl LapceEditorBufferData {
         completion.input = input.clone();
         completion.status = CompletionStatus::Started;
         completion.input_items.clear();
+        completion.resolved.clear();
         completion.request_id += 1;
         let event_sink = ctx.get_external_handle();
         completion.request(
@@ -595,8 +823,10 @@ impl LapceEditorBufferData {
         let first_half = self.buffer.slice_to_cow(line_start..offset).to_string();
         let second_half = self.buffer.slice_to_cow(offset..line_end).to_string();
 
-        let indent = if has_unmatched_pair(&first_half) {
-            format!("{}    ", line_indent)
+        let indent = if self.server_indents() {
+            line_indent.clone()
+        } else if has_unmatched_pair(&first_half) {
+            format!("{}{}", line_indent, self.buffer.indent.unit())
         } else {
             let next_line_indent = self.buffer.indent_on_line(line + 1);
             if next_line_indent.len() > line_indent.len() {
@@ -645,6 +875,12 @@ impl LapceEditorBufferData {
         }
     }
 
+    /// Whether the language server's on type formatting indents this
+    /// buffer, in which case the built-in auto-indent stays out of its way.
+    fn server_indents(&self) -> bool {
+        self.config.editor.server_indents(&self.buffer.language_id)
+    }
+
     fn set_cursor_after_change(&mut self, selection: Selection) {
         match self.editor.cursor.mode {
             CursorMode::Normal(_) | CursorMode::Visual { .. } => {
@@ -662,6 +898,35 @@ impl LapceEditorBufferData {
         match data.mode {
             VisualMode::Normal => {
                 Arc::make_mut(&mut self.editor).snippet = None;
+                // as many lines as cursors, as after copying from them all
+                let split = match &self.editor.cursor.mode {
+                    CursorMode::Insert(selection) => {
+                        multi_cursor::split_paste(selection, &data.content)
+                            .map(|edits| (selection.clone(), edits))
+                    }
+                    _ => None,
+                };
+                if let Some((selection, edits)) = split {
+                    let additional = edits[1..]
+                        .iter()
+                        .map(|(selection, line)| (selection, line.as_str()))
+                        .collect();
+                    let (_, delta) = self.edit(
+                        ctx,
+                        &edits[0].0,
+                        &edits[0].1,
+                        Some(additional),
+                        true,
+                        EditType::InsertChars,
+                    );
+                    let selection =
+                        selection.apply_delta(&delta, true, InsertDrift::Default);
+                    self.set_cursor(Cursor::new(
+                        CursorMode::Insert(selection),
+                        None,
+                    ));
+                    return;
+                }
                 let selection = match self.editor.cursor.mode {
                     CursorMode::Normal(offset) => {
                         let line_end = self.buffer.offset_line_end(offset, true);
@@ -766,6 +1031,320 @@ impl LapceEditorBufferData {
         editor.cursor = cursor;
     }
 
+    /// Has the keys typed next taken as what a vim operator applies to.
+    fn start_operator(&mut self, operator: Operator, count: Option<usize>) {
+        if !self.config.lapce.modal {
+            return;
+        }
+        Arc::make_mut(&mut self.editor)
+            .vim
+            .start_operator(operator, count);
+    }
+
+    fn apply_vim_edit(&mut self, ctx: &mut EventCtx, edit: &VimEdit) {
+        let text = self.buffer.rope.clone();
+        let offset = self.editor.cursor.offset();
+        let register = RegisterData {
+            content: edit.register_text(&text),
+            mode: if edit.linewise {
+                VisualMode::Linewise
+            } else {
+                VisualMode::Normal
+            },
+        };
+        match edit.operator {
+            Operator::Yank => {
+                Arc::make_mut(&mut self.main_split.register).add_yank(register);
+                let offset = if edit.linewise
+                    && text.line_of_offset(offset) == text.line_of_offset(edit.start)
+                {
+                    offset
+                } else {
+                    edit.start
+                };
+                self.set_cursor(Cursor::new(CursorMode::Normal(offset), None));
+            }
+            Operator::Delete | Operator::Change => {
+                let (start, end) = edit.delete_range(&text);
+                // an edit in insert mode leaves the register to us
+                self.set_cursor(Cursor::new(
+                    CursorMode::Insert(Selection::caret(offset)),
+                    None,
+                ));
+                let (selection, _) = self.edit(
+                    ctx,
+                    &Selection::region(start, end),
+                    "",
+                    None,
+                    true,
+                    EditType::Delete,
+                );
+                Arc::make_mut(&mut self.main_split.register).add_delete(register);
+                let offset = selection.min_offset();
+                let mode = match edit.mode {
+                    Mode::Insert => CursorMode::Insert(Selection::caret(offset)),
+                    _ if edit.linewise => {
+                        let line = self.buffer.line_of_offset(offset);
+                        CursorMode::Normal(
+                            self.buffer.first_non_blank_character_on_line(line),
+                        )
+                    }
+                    _ => CursorMode::Normal(
+                        self.buffer.offset_line_end(offset, false).min(offset),
+                    ),
+                };
+                self.set_cursor(Cursor::new(mode, None));
+            }
+            Operator::Indent | Operator::Outdent => {
+                let (first, last) = edit.lines(&text);
+                let (regions, content) = if edit.operator == Operator::Indent {
+                    (
+                        vim::indent_regions(&text, first, last),
+                        self.buffer.indent.unit(),
+                    )
+                } else {
+                    let width = self.buffer.indent.width() as usize;
+                    (
+                        vim::outdent_regions(&text, first, last, width),
+                        String::new(),
+                    )
+                };
+                if !regions.is_empty() {
+                    let mut selection = Selection::new();
+                    for (start, end) in regions {
+                        selection.add_region(SelRegion::new(start, end, None));
+                    }
+                    self.set_cursor(Cursor::new(
+                        CursorMode::Insert(Selection::caret(offset)),
+                        None,
+                    ));
+                    self.edit(
+                        ctx,
+                        &selection,
+                        &content,
+                        None,
+                        true,
+                        EditType::Other,
+                    );
+                }
+                let offset = self.buffer.first_non_blank_character_on_line(first);
+                self.set_cursor(Cursor::new(CursorMode::Normal(offset), None));
+            }
+        }
+    }
+
+    /// The last change of a vim operator made again at the cursor, with
+    /// what was typed after a `c`.
+    fn repeat_last_change(&mut self, ctx: &mut EventCtx, count: Option<usize>) {
+        if !self.config.lapce.modal {
+            return;
+        }
+        let text = self.buffer.rope.clone();
+        let offset = self.editor.cursor.offset();
+        let (edit, inserted) = match Arc::make_mut(&mut self.editor)
+            .vim
+            .repeat(count, &text, offset)
+        {
+            Some(change) => change,
+            None => return,
+        };
+        self.apply_vim_edit(ctx, &edit);
+        if edit.mode == Mode::Insert {
+            let selection = self.editor.cursor.edit_selection(&self.buffer);
+            let (selection, _) = self.edit(
+                ctx,
+                &selection,
+                &inserted,
+                None,
+                true,
+                EditType::InsertChars,
+            );
+            let offset = self
+                .buffer
+                .move_offset(
+                    selection.get_cursor_offset(),
+                    None,
+                    1,
+                    &Movement::Left,
+                    Mode::Normal,
+                )
+                .0;
+            self.set_cursor(Cursor::new(CursorMode::Normal(offset), None));
+        }
+    }
+
+    /// The selection the cursors are added to, a bare cursor in normal mode.
+    fn multi_cursor_selection(&self) -> Selection {
+        match &self.editor.cursor.mode {
+            CursorMode::Normal(offset) => Selection::caret(*offset),
+            _ => self.editor.cursor.edit_selection(&self.buffer),
+        }
+    }
+
+    /// Has the cursors in insert mode, typing after it being an undo of its
+    /// own.
+    fn set_multi_cursor(&mut self, selection: Selection) {
+        self.buffer_mut().update_edit_type();
+        self.set_cursor(Cursor::new(CursorMode::Insert(selection), None));
+    }
+
+    /// Adds the next occurrence of the selection, or all of them. Started
+    /// from a bare cursor, it's whole words for as long as it goes on.
+    fn select_occurrences(&mut self, all: bool) {
+        let selection = self.multi_cursor_selection();
+        let whole_word = match &self.editor.occurrences {
+            Some((last, whole_word)) if *last == selection => *whole_word,
+            _ => selection.is_caret(),
+        };
+        let selection = if all {
+            multi_cursor::select_all_occurrences(
+                &self.buffer.rope,
+                &selection,
+                whole_word,
+            )
+        } else {
+            multi_cursor::select_next_occurrence(
+                &self.buffer.rope,
+                &selection,
+                whole_word,
+            )
+        };
+        Arc::make_mut(&mut self.editor).occurrences =
+            Some((selection.clone(), whole_word));
+        self.set_multi_cursor(selection);
+    }
+
+    fn insert_cursor(&mut self, above: bool) {
+        let selection = multi_cursor::add_cursor(
+            &self.buffer.rope,
+            &self.multi_cursor_selection(),
+            above,
+        );
+        self.set_multi_cursor(selection);
+    }
+
+    /// Sends `command` to the split the editor is in, if it's one.
+    fn submit_split_command(&self, ctx: &mut EventCtx, command: LapceUICommand) {
+        if let Some(split_id) = self.editor.split_id {
+            if self.editor.editor_type == EditorType::Normal {
+                ctx.submit_command(Command::new(
+                    LAPCE_UI_COMMAND,
+                    command,
+                    Target::Widget(split_id),
+                ));
+            }
+        }
+    }
+
+    /// Changes which folds are collapsed, `f` telling whether it changed
+    /// any, and has the cursor on a line still shown.
+    fn update_folds(&mut self, f: impl FnOnce(&mut [Fold]) -> bool) {
+        let mut folds = (*self.buffer.folds).clone();
+        if !f(&mut folds) {
+            return;
+        }
+        self.buffer_mut().folds = Arc::new(folds);
+        let line = self.buffer.line_of_offset(self.editor.cursor.offset());
+        let visible = folding::visible_line(&self.buffer.folds, line);
+        if visible != line {
+            let offset = self.buffer.first_non_blank_character_on_line(visible);
+            self.do_move(&Movement::Offset(offset), 1);
+        }
+    }
+
+    fn cursor_line(&self) -> usize {
+        self.buffer.line_of_offset(self.editor.cursor.offset())
+    }
+
+    /// Opens the folds hiding where a search moved the cursor to.
+    fn reveal_cursor(&mut self) {
+        let line = self.cursor_line();
+        self.update_folds(|folds| folding::reveal(folds, line));
+    }
+
+    /// Selects `selection`, as a visual selection in modal editing when
+    /// it's a single region.
+    fn set_selection(&mut self, selection: Selection) {
+        let mode = match (&self.editor.cursor.mode, selection.regions()) {
+            (CursorMode::Normal(_) | CursorMode::Visual { .. }, [region])
+                if !region.is_caret() =>
+            {
+                CursorMode::Visual {
+                    start: region.min(),
+                    end: self.buffer.prev_grapheme_offset(
+                        region.max(),
+                        1,
+                        region.min(),
+                    ),
+                    mode: VisualMode::Normal,
+                }
+            }
+            _ => CursorMode::Insert(selection),
+        };
+        self.set_cursor(Cursor::new(mode, None));
+    }
+
+    /// The selection expand selection is at, if nothing else moved the
+    /// cursor or edited the buffer since.
+    fn current_expansion(&self) -> Option<&SelectionExpansion> {
+        let expansion = self.editor.selection_expansion.as_ref()?;
+        if expansion.rev != self.buffer.rev
+            || expansion.current != self.editor.cursor.edit_selection(&self.buffer)
+        {
+            return None;
+        }
+        Some(expansion)
+    }
+
+    /// Grows each region of the selection to the smallest range of the
+    /// chains around it that's bigger than the region itself.
+    fn expand_selection(&mut self, offsets: Vec<usize>, chains: &[Vec<Range>]) {
+        let selection = self.editor.cursor.edit_selection(&self.buffer);
+        let mut expanded = Selection::new();
+        for region in selection.regions() {
+            let (start, end) = (region.min(), region.max());
+            let range = offsets
+                .iter()
+                .zip(chains.iter())
+                .filter(|(offset, _)| start <= **offset && **offset <= end)
+                .flat_map(|(_, chain)| chain.iter())
+                .map(|range| {
+                    (
+                        self.buffer.offset_of_position(&range.start),
+                        self.buffer.offset_of_position(&range.end),
+                    )
+                })
+                .filter(|(range_start, range_end)| {
+                    *range_start <= start
+                        && end <= *range_end
+                        && (*range_start, *range_end) != (start, end)
+                })
+                .min_by_key(|(range_start, range_end)| range_end - range_start);
+            let (start, end) = range.unwrap_or((start, end));
+            expanded.add_region(SelRegion::new(start, end, None));
+        }
+        if expanded == selection {
+            return;
+        }
+
+        let cursor = self.editor.cursor.clone();
+        let expansion = match self.current_expansion().cloned() {
+            Some(mut expansion) => {
+                expansion.previous.push(cursor);
+                expansion.current = expanded.clone();
+                expansion
+            }
+            None => SelectionExpansion {
+                rev: self.buffer.rev,
+                offsets,
+                previous: vec![cursor],
+                current: expanded.clone(),
+            },
+        };
+        self.set_selection(expanded);
+        Arc::make_mut(&mut self.editor).selection_expansion = Some(expansion);
+    }
+
     fn jump_to_nearest_delta(&mut self, delta: &RopeDelta) {
         let mut transformer = Transformer::new(delta);
 
@@ -1191,6 +1770,7 @@ impl LapceEditorBufferData {
             .build()
             .unwrap();
         ctx.draw_text(&text_layout, Point::new(30.0, 7.0));
+        let mut x = 30.0 + text_layout.size().width;
 
         if let Some(workspace) = self.workspace.as_ref() {
             path = path
@@ -1204,8 +1784,6 @@ impl LapceEditorBufferData {
             .unwrap_or("")
             .to_string();
         if folder != "" {
-            let x = text_layout.size().width;
-
             let text_layout = ctx
                 .text()
                 .new_text_layout(folder)
@@ -1217,7 +1795,34 @@ impl LapceEditorBufferData {
                 )
                 .build()
                 .unwrap();
-            ctx.draw_text(&text_layout, Point::new(30.0 + x + 5.0, 7.0));
+            ctx.draw_text(&text_layout, Point::new(x + 5.0, 7.0));
+            x += 5.0 + text_layout.size().width;
+        }
+
+        let scopes = self
+            .buffer
+            .enclosing_scopes
+            .as_ref()
+            .map(|resp| resp.scopes.as_slice())
+            .unwrap_or(&[]);
+        if !scopes.is_empty() {
+            let scopes = scopes
+                .iter()
+                .map(|scope| scope.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(" \u{203a} ");
+            let text_layout = ctx
+                .text()
+                .new_text_layout(format!("\u{203a} {}", scopes))
+                .font(FontFamily::SYSTEM_UI, 13.0)
+                .text_color(
+                    self.config
+                        .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                        .clone(),
+                )
+                .build()
+                .unwrap();
+            ctx.draw_text(&text_layout, Point::new(x + 10.0, 7.0));
         }
 
         if is_hot {
@@ -1332,6 +1937,7 @@ impl LapceEditorBufferData {
 
         if *self.main_split.active == self.view_id {
             self.paint_code_actions_hint(ctx, gutter_width);
+            self.paint_signature(ctx, gutter_width);
         }
     }
 
@@ -1360,6 +1966,72 @@ impl LapceEditorBufferData {
         }
     }
 
+    /// Draws the active signature on the line above the call, with the
+    /// active parameter in bold.
+    fn paint_signature(&self, ctx: &mut PaintCtx, gutter_width: f64) {
+        let signature = match self.main_split.signature.as_ref() {
+            Some(signature) if signature.buffer_id == self.buffer.id => signature,
+            _ => return,
+        };
+        let item = match signature.signatures.get(signature.active_signature) {
+            Some(item) => item,
+            None => return,
+        };
+        let line_height = self.config.editor.line_height as f64;
+        let char_width = self.config.editor_text_width(ctx.text(), "W");
+
+        let mut text_layout = ctx
+            .text()
+            .new_text_layout(item.label.clone())
+            .font(
+                self.config.editor.font_family(),
+                self.config.editor.font_size as f64,
+            )
+            .text_color(
+                self.config
+                    .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                    .clone(),
+            );
+        if let Some((start, end)) = signature
+            .active_parameter
+            .and_then(|index| item.parameters.get(index))
+        {
+            text_layout = text_layout.range_attribute(
+                *start..*end,
+                TextAttribute::Weight(FontWeight::BOLD),
+            );
+        }
+        let text_layout = text_layout.build().unwrap();
+
+        let line = signature.position.line as usize;
+        let (_, col) = self
+            .buffer
+            .offset_to_line_col(self.buffer.offset_of_position(&signature.position));
+        // above the call, unless it's the first line
+        let y = if line == 0 { line + 1 } else { line - 1 } as f64 * line_height
+            - self.editor.scroll_offset.y;
+        let x = (gutter_width + col as f64 * char_width
+            - self.editor.scroll_offset.x)
+            .max(gutter_width);
+        let rect = Size::new(text_layout.size().width + 10.0, line_height)
+            .to_rect()
+            .with_origin(Point::new(x, y));
+        ctx.fill(
+            rect,
+            self.config
+                .get_color_unchecked(LapceTheme::EDITOR_CURRENT_LINE),
+        );
+        ctx.stroke(
+            rect,
+            self.config.get_color_unchecked(LapceTheme::LAPCE_BORDER),
+            1.0,
+        );
+        ctx.draw_text(
+            &text_layout,
+            Point::new(x + 5.0, y + (line_height - text_layout.size().height) / 2.0),
+        );
+    }
+
     fn paint_content(
         &self,
         ctx: &mut PaintCtx,
@@ -1369,6 +2041,7 @@ impl LapceEditorBufferData {
     ) {
         let line_height = self.config.editor.line_height as f64;
         self.paint_cursor(ctx, is_focused, placeholder, config);
+        self.paint_rulers(ctx);
         self.paint_find(ctx);
         let rect = ctx.region().bounding_box();
         let start_line = (rect.y0 / line_height).floor() as usize;
@@ -1425,6 +2098,8 @@ impl LapceEditorBufferData {
 
         self.paint_snippet(ctx);
         self.paint_diagnostics(ctx);
+        self.paint_inlay_hints(ctx, y_shift);
+        self.paint_document_highlights(ctx);
         if self.buffer.len() == 0 {
             if let Some(placeholder) = placeholder {
                 let text_layout = ctx
@@ -1688,6 +2363,23 @@ impl LapceEditorBufferData {
         );
     }
 
+    fn paint_rulers(&self, ctx: &mut PaintCtx) {
+        let rulers = self.config.rulers(&self.buffer.language_id);
+        if rulers.is_empty() {
+            return;
+        }
+        let width = self.config.editor_text_width(ctx.text(), "W");
+        let rect = ctx.region().bounding_box();
+        for col in rulers {
+            let x = (*col as f64 * width).round() + 0.5;
+            ctx.stroke(
+                Line::new(Point::new(x, rect.y0), Point::new(x, rect.y1)),
+                self.config.get_color_unchecked(LapceTheme::LAPCE_BORDER),
+                1.0,
+            );
+        }
+    }
+
     fn paint_find(&self, ctx: &mut PaintCtx) {
         let line_height = self.config.editor.line_height as f64;
         let start_line =
@@ -1786,6 +2478,101 @@ impl LapceEditorBufferData {
         }
     }
 
+    /// Draws the inlay hints of each line after its text, dimmed, with
+    /// parameter hints in italics.
+    fn paint_inlay_hints(&self, ctx: &mut PaintCtx, y_shift: f64) {
+        let inlay_hints = match self.buffer.inlay_hints.as_ref() {
+            Some(inlay_hints) if inlay_hints.rev == self.buffer.rev => inlay_hints,
+            _ => return,
+        };
+        let line_height = self.config.editor.line_height as f64;
+        let (start_line, end_line) = self.visible_lines();
+        let width = self.config.editor_text_width(ctx.text(), "W");
+
+        let mut hints: Vec<&InlayHintItem> = inlay_hints
+            .hints
+            .iter()
+            .filter(|hint| {
+                let line = hint.position.line as usize;
+                start_line <= line && line <= end_line
+            })
+            .collect();
+        hints.sort_by_key(|hint| (hint.position.line, hint.position.character));
+
+        let mut current_line = None;
+        let mut x = 0.0;
+        for hint in hints {
+            let line = hint.position.line as usize;
+            if current_line != Some(line) {
+                current_line = Some(line);
+                x = (self.buffer.line_end_col(line, true) + 1) as f64 * width;
+            }
+            let mut label = hint.label.concat();
+            if hint.padding_left {
+                label.insert(0, ' ');
+            }
+            if hint.padding_right {
+                label.push(' ');
+            }
+            let mut text_layout = ctx
+                .text()
+                .new_text_layout(label)
+                .font(
+                    self.config.editor.font_family(),
+                    self.config.editor.font_size as f64,
+                )
+                .text_color(
+                    self.config
+                        .get_color_unchecked(LapceTheme::EDITOR_DIM)
+                        .clone(),
+                );
+            if hint.kind == InlayHintKind::Parameter {
+                text_layout = text_layout
+                    .default_attribute(TextAttribute::Style(FontStyle::Italic));
+            }
+            let text_layout = text_layout.build().unwrap();
+            ctx.draw_text(
+                &text_layout,
+                Point::new(x, line_height * line as f64 + y_shift),
+            );
+            x += text_layout.size().width + width;
+        }
+    }
+
+    /// Underlines the occurrences of the symbol under the cursor, writes in
+    /// the foreground color and the others dimmed.
+    fn paint_document_highlights(&self, ctx: &mut PaintCtx) {
+        let highlights = match self.buffer.document_highlights.as_ref() {
+            Some(highlights) if highlights.rev == self.buffer.rev => highlights,
+            _ => return,
+        };
+        let line_height = self.config.editor.line_height as f64;
+        let (start_line, end_line) = self.visible_lines();
+        let width = self.config.editor_text_width(ctx.text(), "W");
+        for highlight in highlights.highlights.iter() {
+            let start = self.buffer.offset_of_position(&highlight.range.start);
+            let end = self.buffer.offset_of_position(&highlight.range.end);
+            let (line, start_col) = self.buffer.offset_to_line_col(start);
+            let (end_line_of_range, end_col) = self.buffer.offset_to_line_col(end);
+            if line < start_line || line > end_line || end_line_of_range != line {
+                continue;
+            }
+            let color = match highlight.kind {
+                Some(DocumentHighlightKind::Write) => LapceTheme::EDITOR_FOREGROUND,
+                _ => LapceTheme::EDITOR_DIM,
+            };
+            let y = (line + 1) as f64 * line_height - 1.0;
+            ctx.stroke(
+                Line::new(
+                    Point::new(start_col as f64 * width, y),
+                    Point::new(end_col as f64 * width, y),
+                ),
+                self.config.get_color_unchecked(color),
+                1.0,
+            );
+        }
+    }
+
     fn paint_diagnostics(&self, ctx: &mut PaintCtx) {
         let line_height = self.config.editor.line_height as f64;
         let start_line =
@@ -1822,7 +2609,10 @@ impl LapceEditorBufferData {
                         }
 
                         let x0 = if line == start.line as usize {
-                            start.character as f64 * width
+                            let (_, col) = self.buffer.offset_to_line_col(
+                                self.buffer.offset_of_position(&start),
+                            );
+                            col as f64 * width
                         } else {
                             let (_, col) = self.buffer.offset_to_line_col(
                                 self.buffer.first_non_blank_character_on_line(line),
@@ -1830,7 +2620,10 @@ impl LapceEditorBufferData {
                             col as f64 * width
                         };
                         let x1 = if line == end.line as usize {
-                            end.character as f64 * width
+                            let (_, col) = self.buffer.offset_to_line_col(
+                                self.buffer.offset_of_position(&end),
+                            );
+                            col as f64 * width
                         } else {
                             (self.buffer.line_end_col(line, false) + 1) as f64
                                 * width
@@ -1999,7 +2792,7 @@ impl KeyPressFocus for LapceEditorBufferData {
     }
 
     fn expect_char(&self) -> bool {
-        self.editor.inline_find.is_some()
+        self.editor.inline_find.is_some() || self.editor.vim.is_pending()
     }
 
     fn check_condition(&self, condition: &str) -> bool {
@@ -2024,6 +2817,10 @@ impl KeyPressFocus for LapceEditorBufferData {
         count: Option<usize>,
         env: &Env,
     ) {
+        // a key that isn't a char ends what an operator was waiting for
+        if self.editor.vim.is_pending() {
+            Arc::make_mut(&mut self.editor).vim.cancel();
+        }
         if let Some(movement) = cmd.move_command(count) {
             self.do_move(&movement, count.unwrap_or(1));
             if let Some(snippet) = self.editor.snippet.as_ref() {
@@ -2092,28 +2889,79 @@ impl KeyPressFocus for LapceEditorBufferData {
                 }
             }
             LapceCommand::SplitExchange => {
-                if let Some(split_id) = self.editor.split_id.clone() {
-                    if self.editor.editor_type == EditorType::Normal {
-                        ctx.submit_command(Command::new(
-                            LAPCE_UI_COMMAND,
-                            LapceUICommand::SplitEditorExchange(self.editor.view_id),
-                            Target::Widget(split_id),
-                        ));
-                    }
-                }
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorExchange(self.editor.view_id),
+                );
+            }
+            LapceCommand::SplitVertical => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditor(true, self.editor.view_id),
+                );
+            }
+            LapceCommand::SplitHorizontal => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditor(false, self.editor.view_id),
+                );
+            }
+            LapceCommand::SplitMoveRight => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorSwap(
+                        SplitMoveDirection::Right,
+                        self.editor.view_id,
+                    ),
+                );
+            }
+            LapceCommand::SplitMoveLeft => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorSwap(
+                        SplitMoveDirection::Left,
+                        self.editor.view_id,
+                    ),
+                );
+            }
+            LapceCommand::SplitMoveUp => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorSwap(
+                        SplitMoveDirection::Up,
+                        self.editor.view_id,
+                    ),
+                );
+            }
+            LapceCommand::SplitMoveDown => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorSwap(
+                        SplitMoveDirection::Down,
+                        self.editor.view_id,
+                    ),
+                );
+            }
+            LapceCommand::SplitRotate => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorRotate(self.editor.view_id),
+                );
             }
-            LapceCommand::SplitVertical => {
-                if let Some(split_id) = self.editor.split_id.clone() {
-                    if self.editor.editor_type == EditorType::Normal {
-                        ctx.submit_command(Command::new(
-                            LAPCE_UI_COMMAND,
-                            LapceUICommand::SplitEditor(true, self.editor.view_id),
-                            Target::Widget(split_id),
-                        ));
-                    }
-                }
+            LapceCommand::SplitIncrease => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorResize(self.editor.view_id, 0.1),
+                );
+            }
+            LapceCommand::SplitDecrease => {
+                self.submit_split_command(
+                    ctx,
+                    LapceUICommand::SplitEditorResize(self.editor.view_id, -0.1),
+                );
             }
             LapceCommand::SplitClose => {
+                crate::undo_history::persist(&self.buffer, &self.config);
                 if let Some(split_id) = self.editor.split_id.clone() {
                     if self.editor.editor_type == EditorType::Normal {
                         ctx.submit_command(Command::new(
@@ -2263,6 +3111,24 @@ impl KeyPressFocus for LapceEditorBufferData {
                     }
                 }
             }
+            LapceCommand::DeleteOperator => {
+                self.start_operator(Operator::Delete, count);
+            }
+            LapceCommand::ChangeOperator => {
+                self.start_operator(Operator::Change, count);
+            }
+            LapceCommand::YankOperator => {
+                self.start_operator(Operator::Yank, count);
+            }
+            LapceCommand::IndentOperator => {
+                self.start_operator(Operator::Indent, count);
+            }
+            LapceCommand::OutdentOperator => {
+                self.start_operator(Operator::Outdent, count);
+            }
+            LapceCommand::RepeatLastChange => {
+                self.repeat_last_change(ctx, count);
+            }
             LapceCommand::Yank => {
                 let data = self.editor.cursor.yank(&self.buffer);
                 let register = Arc::make_mut(&mut self.main_split.register);
@@ -2422,6 +3288,19 @@ impl KeyPressFocus for LapceEditorBufferData {
                 self.insert_new_line(ctx, self.editor.cursor.offset());
                 self.update_completion(ctx);
             }
+            LapceCommand::InsertTab => {
+                let selection = self.editor.cursor.edit_selection(&self.buffer);
+                let indent = self.buffer.indent.unit();
+                let (selection, _) = self.edit(
+                    ctx,
+                    &selection,
+                    &indent,
+                    None,
+                    true,
+                    EditType::InsertChars,
+                );
+                self.set_cursor(Cursor::new(CursorMode::Insert(selection), None));
+            }
             LapceCommand::ToggleVisualMode => {
                 self.toggle_visual(VisualMode::Normal);
             }
@@ -2431,6 +3310,41 @@ impl KeyPressFocus for LapceEditorBufferData {
             LapceCommand::ToggleBlockwiseVisualMode => {
                 self.toggle_visual(VisualMode::Blockwise);
             }
+            LapceCommand::SelectNextOccurrence => {
+                self.select_occurrences(false);
+            }
+            LapceCommand::SelectAllOccurrences => {
+                self.select_occurrences(true);
+            }
+            LapceCommand::InsertCursorAbove => {
+                self.insert_cursor(true);
+            }
+            LapceCommand::InsertCursorBelow => {
+                self.insert_cursor(false);
+            }
+            LapceCommand::ToggleColumnSelection => {
+                let editor = Arc::make_mut(&mut self.editor);
+                editor.column_selection = !editor.column_selection;
+            }
+            LapceCommand::Fold => {
+                let line = self.cursor_line();
+                self.update_folds(|folds| folding::fold(folds, line));
+            }
+            LapceCommand::Unfold => {
+                let line = self.cursor_line();
+                self.update_folds(|folds| folding::unfold(folds, line));
+            }
+            LapceCommand::FoldRecursively => {
+                let line = self.cursor_line();
+                self.update_folds(|folds| folding::fold_recursively(folds, line));
+            }
+            LapceCommand::FoldAll => {
+                let line = self.cursor_line();
+                self.update_folds(|folds| folding::fold_level(folds, count, line));
+            }
+            LapceCommand::UnfoldAll => {
+                self.update_folds(folding::unfold_all);
+            }
             LapceCommand::CenterOfWindow => {
                 ctx.submit_command(Command::new(
                     LAPCE_UI_COMMAND,
@@ -2463,10 +3377,18 @@ impl KeyPressFocus for LapceEditorBufferData {
             LapceCommand::ListNext => {
                 let completion = Arc::make_mut(&mut self.completion);
                 completion.next();
+                completion.resolve_current_item(
+                    self.proxy.clone(),
+                    ctx.get_external_handle(),
+                );
             }
             LapceCommand::ListPrevious => {
                 let completion = Arc::make_mut(&mut self.completion);
                 completion.previous();
+                completion.resolve_current_item(
+                    self.proxy.clone(),
+                    ctx.get_external_handle(),
+                );
             }
             LapceCommand::JumpToNextSnippetPlaceholder => {
                 if let Some(snippet) = self.editor.snippet.as_ref() {
@@ -2538,9 +3460,12 @@ impl KeyPressFocus for LapceEditorBufferData {
                     selection
                 };
 
+                let resolved = self.completion.current_resolved_item();
                 let item = self.completion.current_item().to_owned();
                 self.cancel_completion();
-                if item.data.is_some() {
+                if let Some(item) = resolved {
+                    self.apply_completion_item(ctx, &item);
+                } else {
                     let view_id = self.editor.view_id;
                     let buffer_id = self.buffer.id;
                     let rev = self.buffer.rev;
@@ -2550,7 +3475,6 @@ impl KeyPressFocus for LapceEditorBufferData {
                         buffer_id,
                         item.clone(),
                         Box::new(move |result| {
-                            println!("completion resolve result {:?}", result);
                             let mut item = item.clone();
                             if let Ok(res) = result {
                                 if let Ok(i) =
@@ -2568,8 +3492,6 @@ impl KeyPressFocus for LapceEditorBufferData {
                             );
                         }),
                     );
-                } else {
-                    self.apply_completion_item(ctx, &item);
                 }
             }
             LapceCommand::NormalMode => {
@@ -2601,6 +3523,7 @@ impl KeyPressFocus for LapceEditorBufferData {
                 editor.cursor.horiz = None;
                 editor.snippet = None;
                 editor.inline_find = None;
+                editor.vim.finish_insert();
                 self.cancel_completion();
             }
             LapceCommand::GotoDefinition => {
@@ -2640,6 +3563,7 @@ impl KeyPressFocus for LapceEditorBufferData {
                                         proxy.get_references(
                                             buffer_id,
                                             position,
+                                            false,
                                             Box::new(move |result| {
                                                 process_get_references(
                                                     editor_view_id,
@@ -2674,6 +3598,126 @@ impl KeyPressFocus for LapceEditorBufferData {
                     }),
                 );
             }
+            LapceCommand::GetReferences
+            | LapceCommand::GotoImplementation
+            | LapceCommand::GotoTypeDefinition
+            | LapceCommand::GotoDeclaration => {
+                let offset = self.editor.cursor.offset();
+                let position = self.buffer.offset_to_position(offset);
+                let editor_view_id = self.editor.view_id;
+                let event_sink = ctx.get_external_handle();
+                let f = Box::new(move |result: Result<Value, xi_rpc::Error>| {
+                    let _ = process_get_references(
+                        editor_view_id,
+                        offset,
+                        result,
+                        event_sink,
+                    );
+                });
+                match cmd {
+                    LapceCommand::GetReferences => {
+                        self.proxy.get_references(self.buffer.id, position, true, f)
+                    }
+                    LapceCommand::GotoTypeDefinition => {
+                        self.proxy.goto_type_definition(self.buffer.id, position, f)
+                    }
+                    LapceCommand::GotoDeclaration => {
+                        self.proxy.goto_declaration(self.buffer.id, position, f)
+                    }
+                    _ => self.proxy.get_implementation(self.buffer.id, position, f),
+                }
+            }
+            LapceCommand::ExpandSelection => {
+                let offsets = match self.current_expansion() {
+                    Some(expansion) => expansion.offsets.clone(),
+                    None => self
+                        .editor
+                        .cursor
+                        .edit_selection(&self.buffer)
+                        .regions()
+                        .iter()
+                        .map(|region| region.min())
+                        .collect(),
+                };
+                let positions = offsets
+                    .iter()
+                    .map(|offset| self.buffer.offset_to_position(*offset))
+                    .collect();
+                let view_id = self.editor.view_id;
+                let buffer_id = self.buffer.id;
+                let rev = self.buffer.rev;
+                let event_sink = ctx.get_external_handle();
+                self.proxy.get_selection_ranges(
+                    buffer_id,
+                    positions,
+                    Box::new(move |result| {
+                        if let Ok(res) = result {
+                            if let Ok(chains) =
+                                serde_json::from_value::<Vec<Vec<Range>>>(res)
+                            {
+                                event_sink.submit_command(
+                                    LAPCE_UI_COMMAND,
+                                    LapceUICommand::ExpandSelection(
+                                        buffer_id, rev, offsets, chains,
+                                    ),
+                                    Target::Widget(view_id),
+                                );
+                            }
+                        }
+                    }),
+                );
+            }
+            LapceCommand::ShrinkSelection => {
+                let mut expansion = match self.current_expansion().cloned() {
+                    Some(expansion) => expansion,
+                    None => return,
+                };
+                if let Some(cursor) = expansion.previous.pop() {
+                    expansion.current = cursor.edit_selection(&self.buffer);
+                    self.set_cursor(cursor);
+                    Arc::make_mut(&mut self.editor).selection_expansion =
+                        Some(expansion)
+                            .filter(|expansion| !expansion.previous.is_empty());
+                }
+            }
+            LapceCommand::RenameSymbol => {
+                if !self
+                    .main_split
+                    .supports(&self.buffer.path, |caps| caps.rename)
+                {
+                    return;
+                }
+                let offset = self.editor.cursor.offset();
+                let position = self.buffer.offset_to_position(offset);
+                let path = self.buffer.path.clone();
+                let rev = self.buffer.rev;
+                let event_sink = ctx.get_external_handle();
+                self.proxy.prepare_rename(
+                    self.buffer.id,
+                    position,
+                    Box::new(move |result| {
+                        if let Ok(res) = result {
+                            match serde_json::from_value::<
+                                Option<PrepareRenameResponse>,
+                            >(res)
+                            {
+                                Ok(Some(resp)) => {
+                                    event_sink.submit_command(
+                                        LAPCE_UI_COMMAND,
+                                        LapceUICommand::PrepareRename(
+                                            path, rev, offset, resp,
+                                        ),
+                                        Target::Auto,
+                                    );
+                                }
+                                _ => {
+                                    eprintln!("nothing to rename at {:?}", position);
+                                }
+                            }
+                        }
+                    }),
+                );
+            }
             LapceCommand::SourceControl => {
                 ctx.submit_command(Command::new(
                     LAPCE_UI_COMMAND,
@@ -2710,6 +3754,7 @@ impl KeyPressFocus for LapceEditorBufferData {
                 let next = self.find.next(&self.buffer.rope, offset, false, true);
                 if let Some((start, end)) = next {
                     self.do_move(&Movement::Offset(start), 1);
+                    self.reveal_cursor();
                 }
             }
             LapceCommand::SearchForward => {
@@ -2717,6 +3762,7 @@ impl KeyPressFocus for LapceEditorBufferData {
                 let next = self.find.next(&self.buffer.rope, offset, false, true);
                 if let Some((start, end)) = next {
                     self.do_move(&Movement::Offset(start), 1);
+                    self.reveal_cursor();
                 }
             }
             LapceCommand::SearchBackward => {
@@ -2724,6 +3770,7 @@ impl KeyPressFocus for LapceEditorBufferData {
                 let next = self.find.next(&self.buffer.rope, offset, true, true);
                 if let Some((start, end)) = next {
                     self.do_move(&Movement::Offset(start), 1);
+                    self.reveal_cursor();
                 }
             }
             LapceCommand::ClearSearch => {
@@ -2759,30 +3806,187 @@ impl KeyPressFocus for LapceEditorBufferData {
                     );
                 }
             }
+            LapceCommand::DocumentFormatting => {
+                let path = self.buffer.path.clone();
+                let rev = self.buffer.rev;
+                let event_sink = ctx.get_external_handle();
+                self.proxy.format_document(
+                    self.buffer.id,
+                    Box::new(move |result: Result<Value, xi_rpc::Error>| {
+                        if result.as_ref().err().map(is_timeout).unwrap_or(false) {
+                            return;
+                        }
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::DocumentFormat(
+                                path,
+                                rev,
+                                result.map_err(|e| anyhow!("{:?}", e)),
+                            ),
+                            Target::Auto,
+                        );
+                    }),
+                );
+            }
+            LapceCommand::ToggleComment => {
+                let selections = match &self.editor.cursor.mode {
+                    CursorMode::Normal(offset) => vec![(*offset, *offset)],
+                    CursorMode::Insert(selection) => selection
+                        .regions()
+                        .iter()
+                        .map(|region| (region.start(), region.end()))
+                        .collect(),
+                    CursorMode::Visual { .. } => self
+                        .editor
+                        .cursor
+                        .edit_selection(&self.buffer)
+                        .regions()
+                        .iter()
+                        .map(|region| (region.start(), region.end()))
+                        .collect(),
+                };
+                let path = self.buffer.path.clone();
+                let view_id = self.editor.view_id;
+                let event_sink = ctx.get_external_handle();
+                self.proxy.toggle_comment(
+                    self.buffer.id,
+                    selections,
+                    Box::new(move |result| {
+                        if let Ok(res) = result {
+                            if let Ok(resp) =
+                                serde_json::from_value::<ToggleCommentResponse>(res)
+                            {
+                                event_sink.submit_command(
+                                    LAPCE_UI_COMMAND,
+                                    LapceUICommand::ApplyToggleComment(
+                                        path, view_id, resp,
+                                    ),
+                                    Target::Auto,
+                                );
+                            }
+                        }
+                    }),
+                );
+            }
+            LapceCommand::ResolveConflictOurs
+            | LapceCommand::ResolveConflictTheirs
+            | LapceCommand::ResolveConflictBoth => {
+                let choice = match cmd {
+                    LapceCommand::ResolveConflictOurs => ConflictChoice::Ours,
+                    LapceCommand::ResolveConflictTheirs => ConflictChoice::Theirs,
+                    _ => ConflictChoice::Both,
+                };
+                // the conflict the cursor is in
+                let line = self.buffer.line_of_offset(self.editor.cursor.offset());
+                let buffer_id = self.buffer.id;
+                let path = self.buffer.path.clone();
+                let proxy = self.proxy.clone();
+                let event_sink = ctx.get_external_handle();
+                self.proxy.get_conflicts(
+                    buffer_id,
+                    Box::new(move |result| {
+                        let conflicts = result
+                            .ok()
+                            .and_then(|res| {
+                                serde_json::from_value::<Vec<Conflict>>(
+                                    res["conflicts"].clone(),
+                                )
+                                .ok()
+                            })
+                            .unwrap_or_default();
+                        let index = match conflicts
+                            .iter()
+                            .position(|c| c.start <= line && line <= c.end)
+                        {
+                            Some(index) => index,
+                            None => return,
+                        };
+                        proxy.resolve_conflict(
+                            buffer_id,
+                            index,
+                            choice,
+                            Box::new(move |result| {
+                                if let Ok(res) = result {
+                                    if let Ok(resp) =
+                                        serde_json::from_value::<ConflictResolution>(
+                                            res,
+                                        )
+                                    {
+                                        event_sink.submit_command(
+                                            LAPCE_UI_COMMAND,
+                                            LapceUICommand::ApplyConflictResolution(
+                                                path, resp,
+                                            ),
+                                            Target::Auto,
+                                        );
+                                    }
+                                }
+                            }),
+                        );
+                    }),
+                );
+            }
+            LapceCommand::FormatSelection => {
+                let selection = self.editor.cursor.edit_selection(&self.buffer);
+                let range = Range {
+                    start: self.buffer.offset_to_position(selection.min_offset()),
+                    end: self.buffer.offset_to_position(selection.max_offset()),
+                };
+                let path = self.buffer.path.clone();
+                let rev = self.buffer.rev;
+                let event_sink = ctx.get_external_handle();
+                self.proxy.format_range(
+                    self.buffer.id,
+                    range,
+                    Box::new(move |result: Result<Value, xi_rpc::Error>| {
+                        if result.as_ref().err().map(is_timeout).unwrap_or(false) {
+                            return;
+                        }
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::DocumentFormat(
+                                path,
+                                rev,
+                                result.map_err(|e| anyhow!("{:?}", e)),
+                            ),
+                            Target::Auto,
+                        );
+                    }),
+                );
+            }
             LapceCommand::Save => {
                 if !self.buffer.dirty {
                     return;
                 }
 
-                let proxy = self.proxy.clone();
-                let buffer_id = self.buffer.id;
-                let rev = self.buffer.rev;
                 let path = self.buffer.path.clone();
+                self.main_split
+                    .apply_save_transforms(ctx, &path, &self.config);
+                if !self.config.format_on_save(&self.buffer.language_id) {
+                    self.main_split.document_save(ctx, &path);
+                    return;
+                }
+
+                let proxy = self.proxy.clone();
+                let buffer = self.main_split.open_files.get(&path).unwrap();
+                let buffer_id = buffer.id;
+                let rev = buffer.rev;
+                let timeout =
+                    Duration::from_millis(self.config.editor.format_on_save_timeout);
                 let event_sink = ctx.get_external_handle();
                 let (sender, receiver) = bounded(1);
                 thread::spawn(move || {
-                    proxy.get_document_formatting(
+                    proxy.format_document(
                         buffer_id,
                         Box::new(move |result| {
                             sender.send(result);
                         }),
                     );
 
-                    let result =
-                        receiver.recv_timeout(Duration::from_secs(1)).map_or_else(
-                            |e| Err(anyhow!("{}", e)),
-                            |v| v.map_err(|e| anyhow!("{:?}", e)),
-                        );
+                    let result = receiver.recv_timeout(timeout).map_or_else(
+                        |e| Err(anyhow!("{}", e)),
+                        |v| v.map_err(|e| anyhow!("{:?}", e)),
+                    );
                     event_sink.submit_command(
                         LAPCE_UI_COMMAND,
                         LapceUICommand::DocumentFormatAndSave(path, rev, result),
@@ -2811,7 +4015,9 @@ impl KeyPressFocus for LapceEditorBufferData {
                         let offset = selection.get_cursor_offset();
                         let line = self.buffer.line_of_offset(offset);
                         let line_start = self.buffer.offset_of_line(line);
-                        if self.buffer.slice_to_cow(line_start..offset).trim() == ""
+                        if !self.server_indents()
+                            && self.buffer.slice_to_cow(line_start..offset).trim()
+                                == ""
                         {
                             if let Some(c) = matching_char(c) {
                                 if let Some(previous_offset) =
@@ -2842,6 +4048,7 @@ impl KeyPressFocus for LapceEditorBufferData {
             let editor = Arc::make_mut(&mut self.editor);
             editor.cursor.mode = CursorMode::Insert(selection.clone());
             editor.cursor.horiz = None;
+            editor.vim.record_insert(c);
             if c.chars().count() == 1 {
                 let c = c.chars().next().unwrap();
                 if matching_pair_direction(c).unwrap_or(false) {
@@ -2868,6 +4075,16 @@ impl KeyPressFocus for LapceEditorBufferData {
                 }
             }
             self.update_completion(ctx);
+        } else if self.editor.vim.is_pending() {
+            let offset = self.editor.cursor.offset();
+            let result = Arc::make_mut(&mut self.editor).vim.feed(
+                c,
+                &self.buffer.rope,
+                offset,
+            );
+            if let VimResult::Apply(edit) = result {
+                self.apply_vim_edit(ctx, &edit);
+            }
         } else {
             if let Some(direction) = self.editor.inline_find.clone() {
                 self.inline_find(direction.clone(), c);
@@ -2934,6 +4151,11 @@ impl LapceEditorView {
             LapceUICommand::EnsureRectVisible(rect) => {
                 self.ensure_rect_visible(ctx, data, *rect, env);
             }
+            LapceUICommand::ExpandSelection(buffer_id, rev, offsets, chains) => {
+                if data.buffer.id == *buffer_id && data.buffer.rev == *rev {
+                    data.expand_selection(offsets.clone(), chains);
+                }
+            }
             LapceUICommand::ResolveCompletion(buffer_id, rev, offset, item) => {
                 if data.buffer.id != *buffer_id {
                     return;
@@ -3186,6 +4408,7 @@ impl Widget<LapceTabData> for LapceEditorView {
                             self.editor.widget().editor.widget().inner().offset(),
                         );
                         editor_data.get_code_actions(ctx);
+                        editor_data.check_signature();
 
                         data.keypress = keypress.clone();
                     }
@@ -3321,6 +4544,69 @@ impl Widget<LapceTabData> for LapceEditorView {
                 {
                     ctx.request_paint();
                 }
+
+                if !old_data
+                    .main_split
+                    .signature
+                    .same(&data.main_split.signature)
+                {
+                    ctx.request_paint();
+                }
+
+                if buffer.id != old_buffer.id
+                    || buffer.rev != old_buffer.rev
+                    || buffer.loaded != old_buffer.loaded
+                    || data.visible_lines() != old_data.visible_lines()
+                    || (buffer.inlay_hints.is_none()
+                        && old_buffer.inlay_hints.is_some())
+                {
+                    data.get_inlay_hints(ctx);
+                    data.get_document_links(ctx);
+                }
+                if buffer.large_file != old_buffer.large_file
+                    || data.visible_lines() != old_data.visible_lines()
+                {
+                    data.load_more_lines(ctx);
+                }
+                if buffer.id != old_buffer.id
+                    || buffer.rev != old_buffer.rev
+                    || buffer.loaded != old_buffer.loaded
+                    || data.visible_lines().0 != old_data.visible_lines().0
+                {
+                    data.get_enclosing_scopes(ctx);
+                }
+                if !buffer.enclosing_scopes.same(&old_buffer.enclosing_scopes) {
+                    ctx.request_paint();
+                }
+                if !buffer.inlay_hints.same(&old_buffer.inlay_hints) {
+                    ctx.request_paint();
+                }
+
+                if buffer.id != old_buffer.id
+                    || buffer.rev != old_buffer.rev
+                    || buffer.loaded != old_buffer.loaded
+                {
+                    data.get_folding_ranges(ctx);
+                }
+
+                if *data.main_split.active == self.view_id
+                    && (buffer.id != old_buffer.id
+                        || buffer.rev != old_buffer.rev
+                        || data.editor.cursor.offset()
+                            != old_data.editor.cursor.offset())
+                    && buffer.loaded
+                    && !buffer.local
+                {
+                    let offset = data.editor.cursor.offset();
+                    data.proxy
+                        .cursor_moved(buffer.id, buffer.offset_to_position(offset));
+                }
+                if !buffer
+                    .document_highlights
+                    .same(&old_buffer.document_highlights)
+                {
+                    ctx.request_paint();
+                }
             }
             (LapceEditorViewContent::Buffer(_), LapceEditorViewContent::None) => {
                 ctx.request_layout();
@@ -4001,6 +5287,27 @@ impl Widget<LapceTabData> for LapceEditor {
                                 };
                                 editor.cursor.horiz = None;
                             }
+                            CursorMode::Insert(selection)
+                                if editor.column_selection =>
+                            {
+                                let anchor =
+                                    editor.column_anchor.unwrap_or_else(|| {
+                                        selection
+                                            .first()
+                                            .map(|r| r.start())
+                                            .unwrap_or(0)
+                                    });
+                                editor.cursor = Cursor::new(
+                                    CursorMode::Insert(
+                                        multi_cursor::column_selection(
+                                            &buffer.rope,
+                                            anchor,
+                                            new_offset,
+                                        ),
+                                    ),
+                                    None,
+                                );
+                            }
                             CursorMode::Insert(selection) => {
                                 let mut new_selection = Selection::new();
                                 if let Some(region) = selection.first() {
@@ -4070,6 +5377,19 @@ impl Widget<LapceTabData> for LapceEditor {
                         (line, col)
                     };
                     let new_offset = buffer.offset_of_line_col(line, col);
+                    if mouse_event.mods.ctrl() {
+                        if let Some(link) = buffer.document_link_at(new_offset) {
+                            open_document_link(
+                                data.proxy.clone(),
+                                buffer.id,
+                                editor.view_id,
+                                editor.cursor.offset(),
+                                link.clone(),
+                                ctx.get_external_handle(),
+                            );
+                            return;
+                        }
+                    }
                     let editor = Arc::make_mut(editor);
                     match editor.cursor.mode.clone() {
                         CursorMode::Normal(offset) => {
@@ -4104,6 +5424,22 @@ impl Widget<LapceTabData> for LapceEditor {
                                 );
                             }
                         }
+                        CursorMode::Insert(_)
+                            if editor.column_selection
+                                && mouse_event.mods.shift() =>
+                        {
+                            let anchor = editor
+                                .column_anchor
+                                .unwrap_or(editor.cursor.offset());
+                            editor.cursor = Cursor::new(
+                                CursorMode::Insert(multi_cursor::column_selection(
+                                    &buffer.rope,
+                                    anchor,
+                                    new_offset,
+                                )),
+                                None,
+                            );
+                        }
                         CursorMode::Insert(selection) => {
                             if mouse_event.mods.shift() {
                                 let mut new_selection = Selection::new();
@@ -4124,6 +5460,7 @@ impl Widget<LapceTabData> for LapceEditor {
                                     None,
                                 );
                             } else {
+                                editor.column_anchor = Some(new_offset);
                                 editor.cursor = Cursor::new(
                                     CursorMode::Insert(Selection::caret(new_offset)),
                                     None,
@@ -4463,14 +5800,36 @@ fn str_matching_pair(c: &str) -> Option<char> {
     None
 }
 
+/// Whether the proxy gave up on the request as the server didn't answer in
+/// time, which isn't worth reporting.
+fn is_timeout(error: &xi_rpc::Error) -> bool {
+    matches!(
+        error,
+        xi_rpc::Error::RemoteError(xi_rpc::RemoteError::Custom { code, .. })
+            if *code == TIMEOUT_ERROR_CODE
+    )
+}
+
 fn process_get_references(
     editor_view_id: WidgetId,
     offset: usize,
     result: Result<Value, xi_rpc::Error>,
     event_sink: ExtEventSink,
 ) -> Result<()> {
-    let res = result.map_err(|e| anyhow!("{:?}", e))?;
-    let locations: Vec<Location> = serde_json::from_value(res)?;
+    let res = match result {
+        Ok(res) => res,
+        Err(xi_rpc::Error::RemoteError(xi_rpc::RemoteError::Custom {
+            code,
+            message,
+            ..
+        })) if code == UNSUPPORTED_ERROR_CODE => {
+            eprintln!("{}", message);
+            return Ok(());
+        }
+        Err(e) if is_timeout(&e) => return Ok(()),
+        Err(e) => return Err(anyhow!("{:?}", e)),
+    };
+    let locations: Vec<ReferenceLocation> = serde_json::from_value(res)?;
     if locations.len() == 0 {
         return Ok(());
     }
@@ -4482,13 +5841,14 @@ fn process_get_references(
                 editor_view_id,
                 offset,
                 EditorLocationNew {
-                    path: PathBuf::from(location.uri.path()),
-                    position: Some(location.range.start.clone()),
+                    path: location.path.clone(),
+                    position: Some(Position::new(location.line, location.column)),
                     scroll_offset: None,
                 },
             ),
             Target::Auto,
         );
+        return Ok(());
     }
     event_sink.submit_command(
         LAPCE_UI_COMMAND,
@@ -4498,6 +5858,96 @@ fn process_get_references(
     Ok(())
 }
 
+/// Opens the link's target, resolving it first if it has none yet.
+fn open_document_link(
+    proxy: Arc<LapceProxy>,
+    buffer_id: BufferId,
+    editor_view_id: WidgetId,
+    offset: usize,
+    link: DocumentLinkItem,
+    event_sink: ExtEventSink,
+) {
+    if let Some(target) = link.target.as_ref() {
+        open_link_target(editor_view_id, offset, target, &event_sink);
+        return;
+    }
+    proxy.resolve_document_link(
+        buffer_id,
+        link,
+        Box::new(move |result| {
+            if let Ok(res) = result {
+                if let Ok(DocumentLinkItem {
+                    target: Some(target),
+                    ..
+                }) = serde_json::from_value::<DocumentLinkItem>(res)
+                {
+                    open_link_target(editor_view_id, offset, &target, &event_sink);
+                }
+            }
+        }),
+    );
+}
+
+/// Jumps to file targets, at the line and column of an `#L<line>,<col>`
+/// fragment if there's one, and hands other URLs to the system.
+fn open_link_target(
+    editor_view_id: WidgetId,
+    offset: usize,
+    target: &str,
+    event_sink: &ExtEventSink,
+) {
+    let url = match Url::parse(target) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("invalid link target {}: {}", target, e);
+            return;
+        }
+    };
+    if url.scheme() != "file" {
+        if let Err(e) = open_url(target) {
+            eprintln!("can't open {}: {}", target, e);
+        }
+        return;
+    }
+    let path = match url.to_file_path() {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let position = url.fragment().and_then(|fragment| {
+        let mut parts = fragment.strip_prefix('L')?.split(',');
+        let line: u32 = parts.next()?.parse().ok()?;
+        let col: u32 = parts.next().and_then(|col| col.parse().ok()).unwrap_or(1);
+        Some(Position::new(line.saturating_sub(1), col.saturating_sub(1)))
+    });
+    let _ = event_sink.submit_command(
+        LAPCE_UI_COMMAND,
+        LapceUICommand::GotoDefinition(
+            editor_view_id,
+            offset,
+            EditorLocationNew {
+                path,
+                position: Some(position.unwrap_or_else(|| Position::new(0, 0))),
+                scroll_offset: None,
+            },
+        ),
+        Target::Auto,
+    );
+}
+
+fn open_url(url: &str) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    let mut command = std::process::Command::new("open");
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = std::process::Command::new("cmd");
+        command.args(&["/C", "start", ""]);
+        command
+    };
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let mut command = std::process::Command::new("xdg-open");
+    command.arg(url).spawn().map(|_| ())
+}
+
 fn paint_wave_line(
     ctx: &mut PaintCtx,
     origin: Point,
@@ -0,0 +1,318 @@
+//! Which of the folding ranges of a buffer are collapsed. A collapsed fold
+//! hides the lines after its start line up to its end line; the folds are
+//! nested, so one is inside another when its lines are.
+
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::{Fold, InvalLines};
+
+/// The collapsed folds of a buffer, kept in the session to be collapsed
+/// again when the file is opened with the same content.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedFolds {
+    /// The hash of the text they were collapsed in.
+    pub content_hash: u64,
+    pub folded: Vec<(usize, usize)>,
+}
+
+fn contains(fold: &Fold, line: usize) -> bool {
+    fold.start_line <= line && line <= fold.end_line
+}
+
+fn hides(fold: &Fold, line: usize) -> bool {
+    fold.collapsed && fold.start_line < line && line <= fold.end_line
+}
+
+fn inside(fold: &Fold, outer: &Fold) -> bool {
+    outer.start_line <= fold.start_line && fold.end_line <= outer.end_line
+}
+
+pub fn is_hidden(folds: &[Fold], line: usize) -> bool {
+    folds.iter().any(|fold| hides(fold, line))
+}
+
+/// The line shown for `line`, the start line of the outermost fold hiding
+/// it.
+pub fn visible_line(folds: &[Fold], line: usize) -> usize {
+    folds
+        .iter()
+        .filter(|fold| hides(fold, line))
+        .map(|fold| fold.start_line)
+        .min()
+        .unwrap_or(line)
+}
+
+/// Collapses the innermost open fold around the line.
+pub fn fold(folds: &mut [Fold], line: usize) -> bool {
+    match folds
+        .iter_mut()
+        .filter(|fold| !fold.collapsed && contains(fold, line))
+        .max_by_key(|fold| (fold.start_line, std::cmp::Reverse(fold.end_line)))
+    {
+        Some(fold) => {
+            fold.collapsed = true;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Opens the innermost collapsed fold around the line.
+pub fn unfold(folds: &mut [Fold], line: usize) -> bool {
+    match folds
+        .iter_mut()
+        .filter(|fold| fold.collapsed && contains(fold, line))
+        .max_by_key(|fold| (fold.start_line, std::cmp::Reverse(fold.end_line)))
+    {
+        Some(fold) => {
+            fold.collapsed = false;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Collapses the outermost fold around the line and every fold inside it.
+pub fn fold_recursively(folds: &mut [Fold], line: usize) -> bool {
+    let outer = match folds
+        .iter()
+        .filter(|fold| contains(fold, line))
+        .min_by_key(|fold| (fold.start_line, std::cmp::Reverse(fold.end_line)))
+    {
+        Some(fold) => fold.clone(),
+        None => return false,
+    };
+    let mut changed = false;
+    for fold in folds.iter_mut().filter(|fold| inside(fold, &outer)) {
+        changed |= !fold.collapsed;
+        fold.collapsed = true;
+    }
+    changed
+}
+
+/// Collapses the folds `level` deep, the outermost being 1, or all of them
+/// without a level. Those the line is in stay open, so it isn't hidden.
+pub fn fold_level(folds: &mut [Fold], level: Option<usize>, line: usize) -> bool {
+    let depths: Vec<usize> = folds
+        .iter()
+        .map(|fold| {
+            folds
+                .iter()
+                .filter(|outer| *outer != fold && inside(fold, outer))
+                .count()
+                + 1
+        })
+        .collect();
+    let mut changed = false;
+    for (fold, depth) in folds.iter_mut().zip(depths) {
+        if level.map(|level| level == depth).unwrap_or(true)
+            && !(fold.start_line < line && line <= fold.end_line)
+            && !fold.collapsed
+        {
+            fold.collapsed = true;
+            changed = true;
+        }
+    }
+    changed
+}
+
+pub fn unfold_all(folds: &mut [Fold]) -> bool {
+    let mut changed = false;
+    for fold in folds.iter_mut().filter(|fold| fold.collapsed) {
+        fold.collapsed = false;
+        changed = true;
+    }
+    changed
+}
+
+/// Opens the folds hiding the line, and only those, for a jump to it.
+pub fn reveal(folds: &mut [Fold], line: usize) -> bool {
+    let mut changed = false;
+    for fold in folds.iter_mut().filter(|fold| hides(fold, line)) {
+        fold.collapsed = false;
+        changed = true;
+    }
+    changed
+}
+
+/// Moves the folds along with the lines edited, dropping the ones that no
+/// longer fold anything. An edit of the lines a fold hides, or one that
+/// splits or joins its start line, opens it.
+pub fn apply_inval_lines(folds: &[Fold], inval_lines: &InvalLines) -> Vec<Fold> {
+    let start = inval_lines.start_line;
+    let old_end = start + inval_lines.inval_count;
+    let new_end = start + inval_lines.new_count;
+    let shift = |line: usize| {
+        if line < start {
+            line
+        } else if line >= old_end {
+            line + new_end - old_end
+        } else {
+            line.min(new_end.saturating_sub(1))
+        }
+    };
+    folds
+        .iter()
+        .filter_map(|fold| {
+            let start_line = shift(fold.start_line);
+            let end_line = shift(fold.end_line);
+            if end_line <= start_line {
+                return None;
+            }
+            let hidden_edited =
+                start <= fold.end_line && old_end > fold.start_line + 1;
+            let start_edited = start <= fold.start_line
+                && fold.start_line < old_end
+                && inval_lines.inval_count != inval_lines.new_count;
+            Some(Fold {
+                start_line,
+                end_line,
+                collapsed: fold.collapsed && !hidden_edited && !start_edited,
+                ..fold.clone()
+            })
+        })
+        .collect()
+}
+
+/// What's collapsed, None when nothing is.
+pub fn saved(folds: &[Fold], content_hash: u64) -> Option<SavedFolds> {
+    let folded: Vec<(usize, usize)> = folds
+        .iter()
+        .filter(|fold| fold.collapsed)
+        .map(|fold| (fold.start_line, fold.end_line))
+        .collect();
+    if folded.is_empty() {
+        return None;
+    }
+    Some(SavedFolds {
+        content_hash,
+        folded,
+    })
+}
+
+/// The folds collapsed in the session, which the folding ranges keep
+/// collapsed when they come.
+pub fn restored(saved: &SavedFolds) -> Vec<Fold> {
+    saved
+        .folded
+        .iter()
+        .map(|(start_line, end_line)| Fold {
+            start_line: *start_line,
+            end_line: *end_line,
+            kind: None,
+            collapsed: true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folds(ranges: &[(usize, usize)]) -> Vec<Fold> {
+        ranges
+            .iter()
+            .map(|(start_line, end_line)| Fold {
+                start_line: *start_line,
+                end_line: *end_line,
+                kind: None,
+                collapsed: false,
+            })
+            .collect()
+    }
+
+    fn collapsed(folds: &[Fold]) -> Vec<(usize, usize)> {
+        folds
+            .iter()
+            .filter(|fold| fold.collapsed)
+            .map(|fold| (fold.start_line, fold.end_line))
+            .collect()
+    }
+
+    #[test]
+    fn test_fold_unfold() {
+        // 0 { 1 { 2 } 3 } 4 { 5 }
+        let mut folds = folds(&[(0, 3), (1, 2), (4, 5)]);
+        assert!(fold(&mut folds, 2));
+        assert_eq!(vec![(1, 2)], collapsed(&folds));
+        assert!(is_hidden(&folds, 2));
+        assert!(!is_hidden(&folds, 1));
+        assert_eq!(1, visible_line(&folds, 2));
+        assert!(fold(&mut folds, 1));
+        assert_eq!(vec![(0, 3), (1, 2)], collapsed(&folds));
+        assert_eq!(0, visible_line(&folds, 2));
+        assert!(!fold(&mut folds, 0));
+
+        assert!(unfold(&mut folds, 0));
+        assert_eq!(vec![(1, 2)], collapsed(&folds));
+        assert!(!unfold(&mut folds, 4));
+
+        assert!(unfold_all(&mut folds));
+        assert!(collapsed(&folds).is_empty());
+        assert!(fold_recursively(&mut folds, 2));
+        assert_eq!(vec![(0, 3), (1, 2)], collapsed(&folds));
+    }
+
+    #[test]
+    fn test_fold_level() {
+        let mut folds = folds(&[(0, 3), (1, 2), (4, 8), (5, 6)]);
+        assert!(fold_level(&mut folds, Some(2), 0));
+        assert_eq!(vec![(1, 2), (5, 6)], collapsed(&folds));
+        unfold_all(&mut folds);
+        // the folds around the cursor stay open
+        assert!(fold_level(&mut folds, None, 6));
+        assert_eq!(vec![(0, 3), (1, 2)], collapsed(&folds));
+    }
+
+    #[test]
+    fn test_reveal() {
+        let mut folds = folds(&[(0, 5), (1, 3), (4, 5)]);
+        fold_recursively(&mut folds, 0);
+        assert!(reveal(&mut folds, 2));
+        assert_eq!(vec![(4, 5)], collapsed(&folds));
+        assert!(!reveal(&mut folds, 0));
+    }
+
+    #[test]
+    fn test_apply_inval_lines() {
+        let mut folds = folds(&[(2, 4), (6, 8)]);
+        fold_level(&mut folds, None, 0);
+        let edit = |folds: &[Fold], start_line, inval_count, new_count| {
+            apply_inval_lines(
+                folds,
+                &InvalLines {
+                    start_line,
+                    inval_count,
+                    new_count,
+                },
+            )
+        };
+
+        // a line added above moves them
+        let moved = edit(&folds, 0, 1, 2);
+        assert_eq!(vec![(3, 5), (7, 9)], collapsed(&moved));
+        // typing on the start line keeps it collapsed
+        let typed = edit(&folds, 2, 1, 1);
+        assert_eq!(vec![(2, 4), (6, 8)], collapsed(&typed));
+        // but splitting it doesn't
+        let split = edit(&folds, 2, 1, 2);
+        assert_eq!(vec![(7, 9)], collapsed(&split));
+        // nor an edit of what it hides
+        let inside = edit(&folds, 7, 1, 1);
+        assert_eq!(vec![(2, 4)], collapsed(&inside));
+        // deleting the lines it folds drops it
+        let deleted = edit(&folds, 5, 4, 1);
+        assert_eq!(vec![(2, 4)], collapsed(&deleted));
+        assert_eq!(1, deleted.len());
+    }
+
+    #[test]
+    fn test_saved() {
+        let mut folds = folds(&[(0, 3), (1, 2)]);
+        assert_eq!(None, saved(&folds, 7));
+        fold(&mut folds, 2);
+        let saved = saved(&folds, 7).unwrap();
+        assert_eq!(vec![(1, 2)], saved.folded);
+        assert_eq!(vec![(1, 2)], collapsed(&restored(&saved)));
+    }
+}
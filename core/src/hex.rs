@@ -0,0 +1,58 @@
+//! The text binary files are shown as: a line per 16 bytes, with their
+//! offset, their hex and the ones that are printable ASCII.
+
+use std::fmt::Write;
+
+pub const BYTES_PER_LINE: usize = 16;
+
+/// The lines of `bytes`, which start at `offset` in the file, each ending
+/// with a newline.
+pub fn hex_lines(offset: u64, bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let line_offset = offset + (i * BYTES_PER_LINE) as u64;
+        let _ = write!(text, "{:08x}  ", line_offset);
+        for col in 0..BYTES_PER_LINE {
+            match line.get(col) {
+                Some(byte) => {
+                    let _ = write!(text, "{:02x} ", byte);
+                }
+                None => text.push_str("   "),
+            }
+            if col == BYTES_PER_LINE / 2 - 1 {
+                text.push(' ');
+            }
+        }
+        text.push(' ');
+        text.extend(line.iter().map(|byte| match byte {
+            0x20..=0x7e => *byte as char,
+            _ => '.',
+        }));
+        text.push('\n');
+    }
+    text
+}
+
+/// How many lines `file_size` bytes take.
+pub fn num_hex_lines(file_size: u64) -> usize {
+    ((file_size + BYTES_PER_LINE as u64 - 1) / BYTES_PER_LINE as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_lines() {
+        let bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00\x01";
+        let expected = concat!(
+            "00000010  89 50 4e 47 0d 0a 1a 0a  ",
+            "00 00 00 0d 49 48 44 52  .PNG........IHDR\n",
+            "00000020  00 01                    ",
+            "                         ..\n",
+        );
+        assert_eq!(expected, hex_lines(16, bytes));
+        assert_eq!(2, num_hex_lines(18));
+        assert_eq!(0, num_hex_lines(0));
+    }
+}
@@ -16,6 +16,7 @@ use crate::command::{
     lapce_internal_commands, CommandTarget, LapceCommandNew, LAPCE_NEW_COMMAND,
 };
 use crate::data::LapceTabData;
+use crate::when::{ContextValue, KeymapContext, When};
 use crate::{
     command::LapceCommand,
     state::{LapceFocus, Mode},
@@ -28,10 +29,9 @@ const default_keymaps_macos: &'static str =
 const default_keymaps_linux: &'static str =
     include_str!("../../defaults/keymaps-linux.toml");
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 enum KeymapMatch {
     Full(String),
-    Multiple(Vec<String>),
     Prefix,
     None,
 }
@@ -56,16 +56,91 @@ impl KeyPress {
         }
         false
     }
+
+    /// As it's shown, as `Ctrl+Shift+T`; a character with modifiers is
+    /// shown in uppercase, as on the key.
+    pub fn label(&self) -> String {
+        let mut label = String::new();
+        for (set, name) in [
+            (self.mods.ctrl(), "Ctrl"),
+            (self.mods.alt(), "Alt"),
+            (self.mods.shift(), "Shift"),
+            (self.mods.meta(), "Meta"),
+        ] {
+            if set {
+                label.push_str(name);
+                label.push('+');
+            }
+        }
+        match &self.key {
+            druid::keyboard_types::Key::Character(c) if c == " " => {
+                label.push_str("Space")
+            }
+            druid::keyboard_types::Key::Character(c) if !self.mods.is_empty() => {
+                label.push_str(&c.to_uppercase())
+            }
+            druid::keyboard_types::Key::Character(c) => label.push_str(c),
+            key => label.push_str(&key.to_string()),
+        }
+        label
+    }
+}
+
+/// The keys of a keymap as they're shown, as `Ctrl+W L`.
+pub fn keys_label(keys: &[KeyPress]) -> String {
+    keys.iter()
+        .map(|key| key.label())
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct KeyMap {
     pub key: Vec<KeyPress>,
     pub modes: Vec<Mode>,
-    pub when: Option<String>,
+    pub when: Option<When>,
     pub command: String,
 }
 
+impl KeyMap {
+    fn holds(&self, context: &KeymapContext) -> bool {
+        if !self.modes.is_empty()
+            && !self.modes.iter().any(|mode| {
+                context.get("mode")
+                    == Some(&ContextValue::String(mode_name(mode).to_string()))
+            })
+        {
+            return false;
+        }
+        self.when
+            .as_ref()
+            .map(|when| when.eval(context))
+            .unwrap_or(true)
+    }
+
+    /// The conditions of its `when`, then whether it's for some modes
+    /// only, so a keymap of a focus wins over one of a mode.
+    fn specificity(&self) -> (usize, bool) {
+        (
+            self.when
+                .as_ref()
+                .map(|when| when.specificity())
+                .unwrap_or(0),
+            !self.modes.is_empty(),
+        )
+    }
+}
+
+/// The `mode` of the context of a keymap.
+pub fn mode_name(mode: &Mode) -> &'static str {
+    match mode {
+        Mode::Normal => "normal",
+        Mode::Insert => "insert",
+        Mode::Visual => "visual",
+        Mode::Terminal => "terminal",
+    }
+}
+
 pub trait KeyPressFocus {
     fn get_mode(&self) -> Mode;
     fn check_condition(&self, condition: &str) -> bool;
@@ -196,19 +271,14 @@ impl KeyPressData {
         let mut keypresses: Vec<KeyPress> = self.pending_keypress.clone();
         keypresses.push(keypress.clone());
 
-        let matches = self.match_keymap(&keypresses, focus);
-        let keymatch = if matches.len() == 0 {
-            KeymapMatch::None
-        } else if matches.len() == 1 && matches[0].key == keypresses {
-            KeymapMatch::Full(matches[0].command.clone())
-        } else if matches.len() > 1
-            && matches.iter().filter(|m| m.key != keypresses).count() == 0
+        // a character typed for the focus isn't a key
+        let keymatch = if focus.expect_char()
+            && keypresses.len() == 1
+            && keypresses[0].is_char()
         {
-            KeymapMatch::Multiple(
-                matches.iter().map(|m| m.command.clone()).collect(),
-            )
+            KeymapMatch::None
         } else {
-            KeymapMatch::Prefix
+            self.resolve_key(&keypresses, &self.context(&keypresses, focus))
         };
         match keymatch {
             KeymapMatch::Full(command) => {
@@ -217,14 +287,6 @@ impl KeyPressData {
                 self.pending_keypress = Vec::new();
                 return true;
             }
-            KeymapMatch::Multiple(commands) => {
-                let count = self.count.take();
-                for command in commands {
-                    self.run_command(ctx, &command, count, focus, env);
-                }
-                self.pending_keypress = Vec::new();
-                return true;
-            }
             KeymapMatch::Prefix => {
                 self.pending_keypress.push(keypress);
                 return false;
@@ -256,114 +318,142 @@ impl KeyPressData {
         false
     }
 
-    fn match_keymap<T: KeyPressFocus>(
+    /// The names of the context the keymaps of the keys test, as the
+    /// focus answers them.
+    fn context<T: KeyPressFocus>(
         &self,
-        keypresses: &Vec<KeyPress>,
-        check: &T,
-    ) -> Vec<&KeyMap> {
-        self.keymaps
-            .get(keypresses)
-            .map(|keymaps| {
-                keymaps
-                    .iter()
-                    .filter(|keymap| {
-                        if check.expect_char()
-                            && keypresses.len() == 1
-                            && keypresses[0].is_char()
-                        {
-                            return false;
-                        }
-                        if keymap.modes.len() > 0
-                            && !keymap.modes.contains(&check.get_mode())
-                        {
-                            return false;
-                        }
-                        if let Some(condition) = &keymap.when {
-                            if !self.check_condition(condition, check) {
-                                return false;
-                            }
-                        }
-                        true
-                    })
-                    .collect()
-            })
-            .unwrap_or(Vec::new())
+        keypresses: &[KeyPress],
+        focus: &T,
+    ) -> KeymapContext {
+        let mut context = KeymapContext::default();
+        context.set_string("mode", mode_name(&focus.get_mode()));
+        if let Some(keymaps) = self.keymaps.get(keypresses) {
+            for when in keymaps.iter().filter_map(|keymap| keymap.when.as_ref()) {
+                for name in when.names() {
+                    if name != "mode" && context.get(name).is_none() {
+                        context.set_bool(name, focus.check_condition(name));
+                    }
+                }
+            }
+        }
+        context
     }
 
-    fn check_one_condition<T: KeyPressFocus>(
+    /// What the keys pressed so far do in the context. Of the keymaps whose
+    /// conditions hold, the most specific wins, and of those as specific the
+    /// one defined last, so a user's keymap wins over a default. When it's
+    /// one of more keys than pressed, the keys are waited on.
+    fn resolve_key(
         &self,
-        condition: &str,
-        check: &T,
-    ) -> bool {
-        let condition = condition.trim();
-        let (reverse, condition) = if condition.starts_with("!") {
-            (true, &condition[1..])
-        } else {
-            (false, condition)
+        keypresses: &[KeyPress],
+        context: &KeymapContext,
+    ) -> KeymapMatch {
+        let keymaps = match self.keymaps.get(keypresses) {
+            Some(keymaps) => keymaps,
+            None => return KeymapMatch::None,
         };
-        let matched = check.check_condition(condition);
-        if reverse {
-            !matched
-        } else {
-            matched
+        let mut winner: Option<&KeyMap> = None;
+        for keymap in keymaps.iter().filter(|keymap| keymap.holds(context)) {
+            if winner
+                .map(|winner| keymap.specificity() >= winner.specificity())
+                .unwrap_or(true)
+            {
+                winner = Some(keymap);
+            }
+        }
+        match winner {
+            Some(keymap) if keymap.key.len() > keypresses.len() => {
+                KeymapMatch::Prefix
+            }
+            Some(keymap) => KeymapMatch::Full(keymap.command.clone()),
+            None => KeymapMatch::None,
         }
     }
 
-    fn check_condition<T: KeyPressFocus>(&self, condition: &str, check: &T) -> bool {
-        let or_indics: Vec<_> = condition.match_indices("||").collect();
-        let and_indics: Vec<_> = condition.match_indices("&&").collect();
-        if and_indics.is_empty() {
-            if or_indics.is_empty() {
-                return self.check_one_condition(&condition, check);
-            } else {
-                return self
-                    .check_one_condition(&condition[..or_indics[0].0], check)
-                    || self
-                        .check_condition(&condition[or_indics[0].0 + 2..], check);
-            }
-        } else {
-            if or_indics.is_empty() {
-                return self
-                    .check_one_condition(&condition[..and_indics[0].0], check)
-                    && self
-                        .check_condition(&condition[and_indics[0].0 + 2..], check);
-            } else {
-                if or_indics[0].0 < and_indics[0].0 {
-                    return self
-                        .check_one_condition(&condition[..or_indics[0].0], check)
-                        || self.check_condition(
-                            &condition[or_indics[0].0 + 2..],
-                            check,
-                        );
-                } else {
-                    return self
-                        .check_one_condition(&condition[..and_indics[0].0], check)
-                        && self.check_condition(
-                            &condition[and_indics[0].0 + 2..],
-                            check,
-                        );
+    /// The keymaps of a command, each once.
+    fn command_keymaps<'a>(
+        &'a self,
+        command: &'a str,
+    ) -> impl Iterator<Item = &'a KeyMap> + 'a {
+        self.keymaps
+            .iter()
+            .flat_map(|(key, keymaps)| {
+                keymaps.iter().filter(move |keymap| &keymap.key == key)
+            })
+            .filter(move |keymap| keymap.command == command)
+    }
+
+    /// The keys that run the command in the context, those of a keymap it
+    /// has whose conditions hold and that no other keymap wins over.
+    pub fn command_keys(
+        &self,
+        command: &str,
+        context: &KeymapContext,
+    ) -> Option<Vec<KeyPress>> {
+        self.command_keymaps(command)
+            .find(|keymap| {
+                keymap.holds(context)
+                    && self.resolve_key(&keymap.key, context)
+                        == KeymapMatch::Full(command.to_string())
+            })
+            .map(|keymap| keymap.key.clone())
+    }
+
+    /// Whether the command applies in the context, as its keymaps tell: not
+    /// when every one of them has a `when` that's false there.
+    pub fn is_available(&self, command: &str, context: &KeymapContext) -> bool {
+        let mut keymaps = self.command_keymaps(command).peekable();
+        keymaps.peek().is_none()
+            || keymaps.any(|keymap| {
+                keymap
+                    .when
+                    .as_ref()
+                    .map(|when| when.eval(context))
+                    .unwrap_or(true)
+            })
+    }
+
+    /// The keymaps of the files, in order, an entry whose command starts
+    /// with `-` taking the keymaps of that command on the key, or on any key
+    /// without one, out of those before it.
+    fn keymaps_from_str(
+        sources: &[&str],
+    ) -> Result<IndexMap<Vec<KeyPress>, Vec<KeyMap>>> {
+        let mut all: Vec<KeyMap> = Vec::new();
+        for s in sources {
+            let toml_keymaps: toml::Value = toml::from_str(s)?;
+            let toml_keymaps = toml_keymaps
+                .get("keymaps")
+                .and_then(|v| v.as_array())
+                .ok_or(anyhow!("no keymaps"))?;
+            for toml_keymap in toml_keymaps {
+                match Self::get_keymap(toml_keymap) {
+                    Ok(keymap) => match keymap.command.strip_prefix('-') {
+                        Some(command) => all.retain(|existing| {
+                            existing.command != command
+                                || (!keymap.key.is_empty()
+                                    && existing.key != keymap.key)
+                                || (!keymap.modes.is_empty()
+                                    && existing.modes != keymap.modes)
+                                || (keymap.when.is_some()
+                                    && existing.when != keymap.when)
+                        }),
+                        None if !keymap.key.is_empty() => all.push(keymap),
+                        None => (),
+                    },
+                    Err(e) => eprintln!("bad keymap: {}", e),
                 }
             }
         }
-    }
-
-    fn keymaps_from_str(s: &str) -> Result<IndexMap<Vec<KeyPress>, Vec<KeyMap>>> {
-        let toml_keymaps: toml::Value = toml::from_str(s)?;
-        let toml_keymaps = toml_keymaps
-            .get("keymaps")
-            .and_then(|v| v.as_array())
-            .ok_or(anyhow!("no keymaps"))?;
 
         let mut keymaps: IndexMap<Vec<KeyPress>, Vec<KeyMap>> = IndexMap::new();
-        for toml_keymap in toml_keymaps {
-            if let Ok(keymap) = Self::get_keymap(toml_keymap) {
-                for i in 1..keymap.key.len() + 1 {
-                    let key = keymap.key[..i].to_vec();
-                    match keymaps.get_mut(&key) {
-                        Some(keymaps) => keymaps.push(keymap.clone()),
-                        None => {
-                            keymaps.insert(key, vec![keymap.clone()]);
-                        }
+        for keymap in all {
+            for i in 1..keymap.key.len() + 1 {
+                let key = keymap.key[..i].to_vec();
+                match keymaps.get_mut(&key) {
+                    Some(keymaps) => keymaps.push(keymap.clone()),
+                    None => {
+                        keymaps.insert(key, vec![keymap.clone()]);
                     }
                 }
             }
@@ -373,34 +463,32 @@ impl KeyPressData {
     }
 
     fn get_keymaps() -> Result<IndexMap<Vec<KeyPress>, Vec<KeyMap>>> {
-        let mut keymaps_str = if std::env::consts::OS == "macos" {
+        let default_keymaps = if std::env::consts::OS == "macos" {
             default_keymaps_macos
         } else if std::env::consts::OS == "linux" {
             default_keymaps_linux
         } else {
             default_keymaps_windows
-        }
-        .to_string();
-
-        if let Some(proj_dirs) = ProjectDirs::from("", "", "Lapce") {
-            let path = proj_dirs.config_dir().join("keymaps.toml");
-            if let Ok(content) = std::fs::read_to_string(path) {
-                if content != "" {
-                    let result: Result<toml::Value, toml::de::Error> =
-                        toml::from_str(&content);
-                    if result.is_ok() {
-                        keymaps_str += &content;
-                    }
-                }
+        };
+
+        let user_keymaps = ProjectDirs::from("", "", "Lapce")
+            .and_then(|proj_dirs| {
+                std::fs::read_to_string(proj_dirs.config_dir().join("keymaps.toml"))
+                    .ok()
+            })
+            .filter(|content| !content.trim().is_empty());
+        if let Some(user_keymaps) = user_keymaps {
+            match Self::keymaps_from_str(&[default_keymaps, &user_keymaps]) {
+                Ok(keymaps) => return Ok(keymaps),
+                Err(e) => eprintln!("can't load keymaps.toml: {}", e),
             }
         }
-
-        Self::keymaps_from_str(&keymaps_str)
+        Self::keymaps_from_str(&[default_keymaps])
     }
 
     fn get_keypress(key: &str) -> Vec<KeyPress> {
         let mut keypresses = Vec::new();
-        for k in key.split(" ") {
+        for k in key.split_whitespace() {
             let mut mods = Modifiers::default();
 
             let parts = k.split("+").collect::<Vec<&str>>();
@@ -444,19 +532,22 @@ impl KeyPressData {
         keypresses
     }
 
+    /// A keymap of the file, without a key for one taking a command off
+    /// every key.
     fn get_keymap(toml_keymap: &toml::Value) -> Result<KeyMap> {
         let key = toml_keymap
             .get("key")
             .and_then(|v| v.as_str())
-            .ok_or(anyhow!("no key in keymap"))?;
+            .unwrap_or("");
+        let when = match toml_keymap.get("when").and_then(|w| w.as_str()) {
+            Some(when) => Some(When::parse(when)?),
+            None => None,
+        };
 
         Ok(KeyMap {
             key: Self::get_keypress(key),
             modes: Self::get_modes(toml_keymap),
-            when: toml_keymap
-                .get("when")
-                .and_then(|w| w.as_str())
-                .map(|w| w.to_string()),
+            when,
             command: toml_keymap
                 .get("command")
                 .and_then(|c| c.as_str())
@@ -498,7 +589,7 @@ keymaps = [
     { key = "ctrl+w",   command = "left", when = "n" },
 ]
         "###;
-        let keymaps = KeyPressData::keymaps_from_str(keymaps).unwrap();
+        let keymaps = KeyPressData::keymaps_from_str(&[keymaps]).unwrap();
         let keypress = KeyPressData::get_keypress("ctrl+w");
         assert_eq!(keymaps.get(&keypress).unwrap().len(), 4);
 
@@ -511,4 +602,372 @@ keymaps = [
         let keypress = KeyPressData::get_keypress("ctrl+w l l");
         assert_eq!(keymaps.get(&keypress).unwrap().len(), 1);
     }
+
+    fn keypress_data(sources: &[&str]) -> KeyPressData {
+        KeyPressData {
+            pending_keypress: Vec::new(),
+            keymaps: Arc::new(KeyPressData::keymaps_from_str(sources).unwrap()),
+            commands: Arc::new(IndexMap::new()),
+            count: None,
+        }
+    }
+
+    fn context(mode: &str, names: &[&str]) -> KeymapContext {
+        let mut context = KeymapContext::default();
+        context.set_string("mode", mode);
+        for name in names {
+            context.set_bool(name, true);
+        }
+        context
+    }
+
+    fn resolve(
+        data: &KeyPressData,
+        keys: &str,
+        context: &KeymapContext,
+    ) -> KeymapMatch {
+        data.resolve_key(&KeyPressData::get_keypress(keys), context)
+    }
+
+    fn full(command: &str) -> KeymapMatch {
+        KeymapMatch::Full(command.to_string())
+    }
+
+    #[test]
+    fn test_resolve_single_keys() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "ctrl+s", command = "save" },
+    { key = "ctrl+shift+s", command = "save_all" },
+    { key = "esc", command = "" },
+]
+"#]);
+        let normal = context("normal", &[]);
+        assert_eq!(full("save"), resolve(&data, "ctrl+s", &normal));
+        assert_eq!(full("save_all"), resolve(&data, "ctrl+shift+s", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "ctrl+x", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "s", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "ctrl+s ctrl+s", &normal));
+        // an empty command takes the key from the focus
+        assert_eq!(full(""), resolve(&data, "esc", &normal));
+    }
+
+    #[test]
+    fn test_resolve_chords() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "ctrl+k ctrl+c", command = "toggle_comment" },
+    { key = "ctrl+k ctrl+u", command = "uncomment" },
+    { key = "ctrl+k ctrl+k ctrl+k", command = "deep" },
+    { key = "g g", command = "go_to_start", mode = "n" },
+]
+"#]);
+        let normal = context("normal", &[]);
+        let insert = context("insert", &[]);
+        assert_eq!(KeymapMatch::Prefix, resolve(&data, "ctrl+k", &normal));
+        assert_eq!(
+            full("toggle_comment"),
+            resolve(&data, "ctrl+k ctrl+c", &normal)
+        );
+        assert_eq!(full("uncomment"), resolve(&data, "ctrl+k ctrl+u", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "ctrl+k x", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "ctrl+c", &normal));
+        assert_eq!(
+            KeymapMatch::Prefix,
+            resolve(&data, "ctrl+k ctrl+k", &normal)
+        );
+        assert_eq!(
+            full("deep"),
+            resolve(&data, "ctrl+k ctrl+k ctrl+k", &normal)
+        );
+
+        // a chord whose condition doesn't hold isn't waited on
+        assert_eq!(KeymapMatch::Prefix, resolve(&data, "g", &normal));
+        assert_eq!(full("go_to_start"), resolve(&data, "g g", &normal));
+        assert_eq!(KeymapMatch::None, resolve(&data, "g", &insert));
+        assert_eq!(KeymapMatch::None, resolve(&data, "g g", &insert));
+    }
+
+    #[test]
+    fn test_resolve_when() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "esc", command = "palette.cancel", when = "palette_focus" },
+    { key = "esc", command = "code_actions.cancel", when = "code_actions_focus" },
+    { key = "ctrl+j", command = "focus_terminal", when = "editor_focus && !terminal_focus" },
+    { key = "ctrl+j", command = "focus_editor", when = "terminal_focus" },
+    { key = "x", command = "delete", when = 'editor_focus && mode == "normal"' },
+    { key = "y", command = "yank", when = "mode != insert && (editor_focus || panel_focus)" },
+]
+"#]);
+        assert_eq!(
+            full("palette.cancel"),
+            resolve(&data, "esc", &context("normal", &["palette_focus"]))
+        );
+        assert_eq!(
+            full("code_actions.cancel"),
+            resolve(&data, "esc", &context("insert", &["code_actions_focus"]))
+        );
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "esc", &context("normal", &["editor_focus"]))
+        );
+
+        assert_eq!(
+            full("focus_terminal"),
+            resolve(&data, "ctrl+j", &context("normal", &["editor_focus"]))
+        );
+        assert_eq!(
+            full("focus_editor"),
+            resolve(
+                &data,
+                "ctrl+j",
+                &context("normal", &["editor_focus", "terminal_focus"])
+            )
+        );
+
+        assert_eq!(
+            full("delete"),
+            resolve(&data, "x", &context("normal", &["editor_focus"]))
+        );
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "x", &context("visual", &["editor_focus"]))
+        );
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "x", &context("normal", &["panel_focus"]))
+        );
+
+        assert_eq!(
+            full("yank"),
+            resolve(&data, "y", &context("visual", &["panel_focus"]))
+        );
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "y", &context("insert", &["editor_focus"]))
+        );
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "y", &context("normal", &[]))
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflicts() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "enter", command = "list.select", when = "list_focus" },
+    { key = "enter", command = "insert_new_line", mode = "i" },
+    { key = "enter", command = "accept", when = "list_focus && completion_focus" },
+    { key = "meta+l", command = "file_explorer.cancel", when = "file_explorer_focus" },
+    { key = "meta+l", command = "split_right", mode = "n" },
+    { key = "meta+l", command = "split_right_again", mode = "n" },
+    { key = "tab", command = "first" },
+    { key = "tab", command = "second" },
+]
+"#]);
+        // the more conditions the more specific, a condition of the when
+        // counting over a mode
+        assert_eq!(
+            full("list.select"),
+            resolve(&data, "enter", &context("normal", &["list_focus"]))
+        );
+        assert_eq!(
+            full("insert_new_line"),
+            resolve(&data, "enter", &context("insert", &[]))
+        );
+        assert_eq!(
+            full("accept"),
+            resolve(
+                &data,
+                "enter",
+                &context("insert", &["list_focus", "completion_focus"])
+            )
+        );
+        assert_eq!(
+            full("list.select"),
+            resolve(&data, "enter", &context("insert", &["list_focus"]))
+        );
+        assert_eq!(
+            full("file_explorer.cancel"),
+            resolve(
+                &data,
+                "meta+l",
+                &context("normal", &["file_explorer_focus"])
+            )
+        );
+        // as specific, the last defined wins
+        assert_eq!(
+            full("split_right_again"),
+            resolve(&data, "meta+l", &context("normal", &[]))
+        );
+        assert_eq!(
+            full("second"),
+            resolve(&data, "tab", &context("normal", &[]))
+        );
+    }
+
+    #[test]
+    fn test_resolve_chord_conflicts() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "ctrl+k ctrl+c", command = "toggle_comment" },
+    { key = "ctrl+k", command = "clear_terminal", when = "terminal_focus" },
+    { key = "ctrl+w", command = "close" },
+    { key = "ctrl+w l", command = "right" },
+    { key = "ctrl+e ctrl+e", command = "chord" },
+    { key = "ctrl+e", command = "single" },
+]
+"#]);
+        let editor = context("normal", &["editor_focus"]);
+        let terminal = context("terminal", &["terminal_focus"]);
+        // the more specific key wins over the chord, and the other way
+        assert_eq!(KeymapMatch::Prefix, resolve(&data, "ctrl+k", &editor));
+        assert_eq!(full("clear_terminal"), resolve(&data, "ctrl+k", &terminal));
+        assert_eq!(
+            full("toggle_comment"),
+            resolve(&data, "ctrl+k ctrl+c", &terminal)
+        );
+        // as specific, the one defined last
+        assert_eq!(KeymapMatch::Prefix, resolve(&data, "ctrl+w", &editor));
+        assert_eq!(full("right"), resolve(&data, "ctrl+w l", &editor));
+        assert_eq!(full("single"), resolve(&data, "ctrl+e", &editor));
+        assert_eq!(full("chord"), resolve(&data, "ctrl+e ctrl+e", &editor));
+    }
+
+    #[test]
+    fn test_resolve_user_keymaps() {
+        let defaults = r#"
+keymaps = [
+    { key = "meta+p", command = "palette" },
+    { key = "ctrl+p", command = "palette" },
+    { key = "ctrl+p", command = "list.previous", when = "list_focus" },
+    { key = "ctrl+n", command = "list.next", when = "list_focus" },
+    { key = "ctrl+n", command = "list.next", when = "palette_focus" },
+    { key = "o", command = "new_line_below", mode = "n" },
+    { key = "o", command = "new_line_below", mode = "v" },
+    { key = "meta+s", command = "save" },
+]
+"#;
+        let user = r#"
+[[keymaps]]
+key = "meta+p"
+command = "-palette"
+
+[[keymaps]]
+key = "ctrl+shift+p"
+command = "palette"
+
+[[keymaps]]
+key = "ctrl+n"
+command = "-list.next"
+when = "list_focus"
+
+[[keymaps]]
+key = "o"
+command = "-new_line_below"
+mode = "v"
+
+[[keymaps]]
+command = "-save"
+
+[[keymaps]]
+key = "meta+s"
+command = "save_all"
+"#;
+        let data = keypress_data(&[defaults, user]);
+        let normal = context("normal", &[]);
+        let list = context("normal", &["list_focus"]);
+        let palette = context("normal", &["palette_focus"]);
+
+        // taken off the one key only
+        assert_eq!(KeymapMatch::None, resolve(&data, "meta+p", &normal));
+        assert_eq!(full("palette"), resolve(&data, "ctrl+p", &normal));
+        assert_eq!(full("palette"), resolve(&data, "ctrl+shift+p", &normal));
+        assert_eq!(full("list.previous"), resolve(&data, "ctrl+p", &list));
+
+        // with a when, off that when only
+        assert_eq!(KeymapMatch::None, resolve(&data, "ctrl+n", &list));
+        assert_eq!(full("list.next"), resolve(&data, "ctrl+n", &palette));
+
+        // with a mode, off that mode only
+        assert_eq!(full("new_line_below"), resolve(&data, "o", &normal));
+        assert_eq!(
+            KeymapMatch::None,
+            resolve(&data, "o", &context("visual", &[]))
+        );
+
+        // without a key, off every key, and bound again after
+        assert_eq!(full("save_all"), resolve(&data, "meta+s", &normal));
+        assert_eq!(
+            1,
+            data.keymaps
+                .get(&KeyPressData::get_keypress("meta+s"))
+                .unwrap()
+                .len()
+        );
+
+        // a user keymap wins over a default one as specific
+        let data = keypress_data(&[
+            defaults,
+            r#"keymaps = [{ key = "ctrl+p", command = "open_file" }]"#,
+        ]);
+        assert_eq!(full("open_file"), resolve(&data, "ctrl+p", &normal));
+        assert_eq!(full("list.previous"), resolve(&data, "ctrl+p", &list));
+    }
+
+    #[test]
+    fn test_command_keys() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "ctrl+shift+t", command = "reopen" },
+    { key = "ctrl+w l", command = "split_right", mode = "n" },
+    { key = "ctrl+l", command = "split_right", mode = "i" },
+    { key = "esc", command = "palette.cancel", when = "palette_focus" },
+    { key = "ctrl+e", command = "focus_editor", when = "terminal_focus" },
+    { key = "ctrl+e", command = "end", when = "terminal_focus && in_vim" },
+]
+"#]);
+        let normal = context("normal", &["terminal_focus"]);
+        let keys = |command: &str, context: &KeymapContext| {
+            data.command_keys(command, context)
+                .map(|keys| keys_label(&keys))
+        };
+        assert_eq!(Some("Ctrl+Shift+T".to_string()), keys("reopen", &normal));
+        // the keys of the mode
+        assert_eq!(Some("Ctrl+W l".to_string()), keys("split_right", &normal));
+        assert_eq!(
+            Some("Ctrl+L".to_string()),
+            keys("split_right", &context("insert", &[]))
+        );
+        assert_eq!(Some("Ctrl+E".to_string()), keys("focus_editor", &normal));
+        // not those another keymap wins over
+        let vim = context("normal", &["terminal_focus", "in_vim"]);
+        assert_eq!(None, keys("focus_editor", &vim));
+        assert_eq!(None, keys("palette.cancel", &normal));
+
+        assert!(data.is_available("reopen", &normal));
+        assert!(data.is_available("unbound", &normal));
+        assert!(data.is_available("focus_editor", &normal));
+        assert!(!data.is_available("palette.cancel", &normal));
+        assert!(data
+            .is_available("palette.cancel", &context("insert", &["palette_focus"])));
+    }
+
+    #[test]
+    fn test_bad_keymaps() {
+        let data = keypress_data(&[r#"
+keymaps = [
+    { key = "a", command = "bad", when = "editor_focus &&" },
+    { key = "a", command = "good", when = "editor_focus" },
+    { command = "no_key" },
+]
+"#]);
+        let editor = context("normal", &["editor_focus"]);
+        assert_eq!(full("good"), resolve(&data, "a", &editor));
+        assert_eq!(1, data.keymaps.len());
+        assert!(KeyPressData::keymaps_from_str(&["keymaps = 1"]).is_err());
+        assert!(KeyPressData::keymaps_from_str(&["not toml"]).is_err());
+    }
 }
@@ -2,6 +2,7 @@ pub mod app;
 pub mod buffer;
 pub mod code_action;
 pub mod command;
+pub mod command_usage;
 pub mod completion;
 pub mod config;
 pub mod container;
@@ -10,22 +11,32 @@ pub mod db;
 pub mod editor;
 pub mod explorer;
 pub mod find;
+pub mod folding;
+pub mod hex;
 pub mod keypress;
 pub mod language;
 pub mod lsp;
 pub mod movement;
+pub mod multi_cursor;
 pub mod outline;
 pub mod palette;
 pub mod panel;
 pub mod proxy;
+pub mod recent;
+pub mod remote;
 pub mod scroll;
 pub mod signature;
 pub mod source_control;
 pub mod split;
+pub mod split_tree;
 pub mod state;
 pub mod status;
 pub mod svg;
+pub mod syntax;
 mod tab;
 pub mod terminal;
 pub mod theme;
+mod undo_history;
+pub mod vim;
+pub mod when;
 pub mod window;
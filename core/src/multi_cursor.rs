@@ -0,0 +1,463 @@
+//! What multiple cursors do that a single one doesn't: selecting the next
+//! or all occurrences of what's selected, adding a cursor above or below,
+//! and the rectangle of a column selection. These only make selections; an
+//! edit of a selection is made to all its regions in one delta, so it's one
+//! undo however many cursors there are.
+
+use xi_rope::Rope;
+
+use crate::buffer::{char_width, str_col};
+use crate::movement::{ColPosition, SelRegion, Selection};
+
+/// The word the offset is in or at the end of.
+pub fn word_at(text: &Rope, offset: usize) -> Option<(usize, usize)> {
+    let line = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line);
+    let line_text = text.slice_to_cow(line_start..line_end(text, line));
+    let at = offset - line_start;
+    let start = line_text[..at]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(at);
+    let end = line_text[at..]
+        .char_indices()
+        .find(|(_, c)| !is_word_char(*c))
+        .map(|(i, _)| at + i)
+        .unwrap_or(line_text.len());
+    if start == end {
+        None
+    } else {
+        Some((line_start + start, line_start + end))
+    }
+}
+
+/// From bare cursors, the words they're on. Otherwise what the last region
+/// selects is found again after it, wrapping around to the start, and added
+/// to the selection; `whole_word` skips what's part of a longer word.
+pub fn select_next_occurrence(
+    text: &Rope,
+    selection: &Selection,
+    whole_word: bool,
+) -> Selection {
+    if selection.is_caret() {
+        return select_words(text, selection);
+    }
+    let last = match selection.last() {
+        Some(region) => *region,
+        None => return selection.clone(),
+    };
+    let needle = text.slice_to_cow(last.min()..last.max());
+    let occurrences = occurrences(text, &needle, whole_word);
+    let next = occurrences
+        .iter()
+        .filter(|(start, _)| *start >= last.max())
+        .chain(occurrences.iter())
+        .find(|(start, end)| {
+            selection
+                .regions_in_range(*start, *end)
+                .iter()
+                .all(|region| region.max() <= *start || region.min() >= *end)
+        });
+    let mut selection = selection.clone();
+    if let Some((start, end)) = next {
+        selection.add_region(SelRegion::new(*start, *end, None));
+    }
+    selection
+}
+
+/// Everything that's the same as what the last region selects, the word of
+/// a bare cursor being whole words only.
+pub fn select_all_occurrences(
+    text: &Rope,
+    selection: &Selection,
+    whole_word: bool,
+) -> Selection {
+    let (selection, whole_word) = if selection.is_caret() {
+        (select_words(text, selection), true)
+    } else {
+        (selection.clone(), whole_word)
+    };
+    let last = match selection.last() {
+        Some(region) if !region.is_caret() => *region,
+        _ => return selection,
+    };
+    let needle = text.slice_to_cow(last.min()..last.max());
+    let mut all = Selection::new();
+    for (start, end) in occurrences(text, &needle, whole_word) {
+        all.add_region(SelRegion::new(start, end, None));
+    }
+    all
+}
+
+fn select_words(text: &Rope, selection: &Selection) -> Selection {
+    let mut words = Selection::new();
+    for region in selection.regions() {
+        let (start, end) =
+            word_at(text, region.end()).unwrap_or((region.end(), region.end()));
+        words.add_region(SelRegion::new(start, end, None));
+    }
+    words
+}
+
+/// Where `needle` is in the text, without overlaps.
+fn occurrences(text: &Rope, needle: &str, whole_word: bool) -> Vec<(usize, usize)> {
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    let haystack = text.slice_to_cow(0..text.len());
+    haystack
+        .match_indices(needle)
+        .map(|(start, _)| (start, start + needle.len()))
+        .filter(|(start, end)| {
+            !whole_word
+                || (!haystack[..*start]
+                    .chars()
+                    .next_back()
+                    .map(is_word_char)
+                    .unwrap_or(false)
+                    && !haystack[*end..]
+                        .chars()
+                        .next()
+                        .map(is_word_char)
+                        .unwrap_or(false))
+        })
+        .collect()
+}
+
+/// A cursor on the line above the first region or below the last. The
+/// column it's at is that the region keeps going back to, so a short line
+/// in between puts the cursor at its end without losing the column.
+pub fn add_cursor(text: &Rope, selection: &Selection, above: bool) -> Selection {
+    let region = match if above {
+        selection.first()
+    } else {
+        selection.last()
+    } {
+        Some(region) => *region,
+        None => return selection.clone(),
+    };
+    let line = text.line_of_offset(region.end());
+    let line = if above {
+        match line.checked_sub(1) {
+            Some(line) => line,
+            None => return selection.clone(),
+        }
+    } else if line < text.line_of_offset(text.len()) {
+        line + 1
+    } else {
+        return selection.clone();
+    };
+    let col = match region.horiz() {
+        Some(ColPosition::Col(col)) => *col,
+        _ => column(text, region.end()),
+    };
+    let offset = offset_of_column(text, line, col);
+    let mut selection = selection.clone();
+    selection.add_region(SelRegion::new(
+        offset,
+        offset,
+        Some(ColPosition::Col(col)),
+    ));
+    selection
+}
+
+/// A region on each line from `anchor` to `head`, from the column of the
+/// anchor to that of the head. The lines that end before the rectangle
+/// starts have none.
+pub fn column_selection(text: &Rope, anchor: usize, head: usize) -> Selection {
+    let anchor_line = text.line_of_offset(anchor);
+    let head_line = text.line_of_offset(head);
+    let anchor_col = column(text, anchor);
+    let head_col = column(text, head);
+    let mut selection = Selection::new();
+    for line in anchor_line.min(head_line)..=anchor_line.max(head_line) {
+        let line_start = text.offset_of_line(line);
+        let end_col = str_col(&text.slice_to_cow(line_start..line_end(text, line)));
+        if end_col < anchor_col.min(head_col) {
+            continue;
+        }
+        selection.add_region(SelRegion::new(
+            offset_of_column(text, line, anchor_col),
+            offset_of_column(text, line, head_col),
+            Some(ColPosition::Col(head_col)),
+        ));
+    }
+    selection
+}
+
+/// A paste of as many lines as there are cursors, one line to each.
+pub fn split_paste(
+    selection: &Selection,
+    content: &str,
+) -> Option<Vec<(Selection, String)>> {
+    let content = content.strip_suffix('\n').unwrap_or(content);
+    let lines: Vec<&str> = content
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    if selection.len() < 2 || lines.len() != selection.len() {
+        return None;
+    }
+    Some(
+        selection
+            .regions()
+            .iter()
+            .zip(lines)
+            .map(|(region, line)| {
+                (
+                    Selection::region(region.min(), region.max()),
+                    line.to_string(),
+                )
+            })
+            .collect(),
+    )
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn line_end(text: &Rope, line: usize) -> usize {
+    if line < text.line_of_offset(text.len()) {
+        let end = text.offset_of_line(line + 1) - 1;
+        let start = text.offset_of_line(line);
+        if end > start && text.slice_to_cow(end - 1..end) == "\r" {
+            end - 1
+        } else {
+            end
+        }
+    } else {
+        text.len()
+    }
+}
+
+fn column(text: &Rope, offset: usize) -> usize {
+    let line_start = text.offset_of_line(text.line_of_offset(offset));
+    str_col(&text.slice_to_cow(line_start..offset))
+}
+
+/// The offset at a column of a line, or the end of the line when it's
+/// shorter.
+fn offset_of_column(text: &Rope, line: usize, col: usize) -> usize {
+    let line_start = text.offset_of_line(line);
+    let mut pos = 0;
+    let mut offset = line_start;
+    for c in text.slice_to_cow(line_start..line_end(text, line)).chars() {
+        pos += char_width(c);
+        if pos > col {
+            break;
+        }
+        offset += c.len_utf8();
+    }
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crossbeam_channel::unbounded;
+    use druid::WidgetId;
+    use xi_core_lib::selection::InsertDrift;
+
+    use super::*;
+    use crate::buffer::{BufferNew, EditType};
+    use crate::movement::Movement;
+    use crate::proxy::LapceProxy;
+    use crate::state::Mode;
+
+    fn new_buffer(text: &str) -> (BufferNew, Arc<LapceProxy>) {
+        let (update_sender, _) = unbounded();
+        let mut buffer = BufferNew::new(
+            PathBuf::from("multi-cursor.txt"),
+            Arc::new(update_sender),
+        );
+        // with no proxy running to be sent the edits
+        buffer.local = true;
+        buffer.load_content(text);
+        let (term_sender, _) = unbounded();
+        let proxy = LapceProxy::new(WidgetId::next(), term_sender);
+        (buffer, Arc::new(proxy))
+    }
+
+    fn carets(offsets: &[usize]) -> Selection {
+        let mut selection = Selection::new();
+        for offset in offsets {
+            selection.add_region(SelRegion::caret(*offset));
+        }
+        selection
+    }
+
+    fn regions(selection: &Selection) -> Vec<(usize, usize)> {
+        selection
+            .regions()
+            .iter()
+            .map(|region| (region.start(), region.end()))
+            .collect()
+    }
+
+    #[test]
+    fn test_select_next_occurrence() {
+        let text = Rope::from("foo foobar foo\nfoo");
+        // a bare cursor selects its word, then whole words only
+        let selection = select_next_occurrence(&text, &Selection::caret(1), true);
+        assert_eq!(vec![(0, 3)], regions(&selection));
+        let selection = select_next_occurrence(&text, &selection, true);
+        assert_eq!(vec![(0, 3), (11, 14)], regions(&selection));
+        let selection = select_next_occurrence(&text, &selection, true);
+        assert_eq!(vec![(0, 3), (11, 14), (15, 18)], regions(&selection));
+        let done = select_next_occurrence(&text, &selection, true);
+        assert_eq!(selection, done);
+
+        // a selection is found inside words too, and wraps around
+        let selection =
+            select_next_occurrence(&text, &Selection::region(11, 14), false);
+        assert_eq!(vec![(11, 14), (15, 18)], regions(&selection));
+        let selection = select_next_occurrence(&text, &selection, false);
+        assert_eq!(vec![(0, 3), (11, 14), (15, 18)], regions(&selection));
+        let selection = select_next_occurrence(&text, &selection, false);
+        assert_eq!(
+            vec![(0, 3), (4, 7), (11, 14), (15, 18)],
+            regions(&selection)
+        );
+    }
+
+    #[test]
+    fn test_select_all_occurrences() {
+        let text = Rope::from("a_b ab a_b(a_b) ");
+        let selection = select_all_occurrences(&text, &Selection::caret(0), false);
+        assert_eq!(vec![(0, 3), (7, 10), (11, 14)], regions(&selection));
+        let selection =
+            select_all_occurrences(&text, &Selection::region(1, 2), false);
+        assert_eq!(vec![(1, 2), (8, 9), (12, 13)], regions(&selection));
+        // nothing under the cursor is nothing to find
+        let selection = select_all_occurrences(&text, &Selection::caret(16), false);
+        assert_eq!(vec![(16, 16)], regions(&selection));
+    }
+
+    #[test]
+    fn test_add_cursor() {
+        let text = Rope::from("abcdef\nab\nabcdef\n");
+        let selection = add_cursor(&text, &Selection::caret(5), false);
+        assert_eq!(vec![(5, 5), (9, 9)], regions(&selection));
+        // the column comes back after the short line
+        let selection = add_cursor(&text, &selection, false);
+        assert_eq!(vec![(5, 5), (9, 9), (15, 15)], regions(&selection));
+        let selection = add_cursor(&text, &selection, false);
+        assert_eq!(
+            vec![(5, 5), (9, 9), (15, 15), (17, 17)],
+            regions(&selection)
+        );
+
+        let selection = add_cursor(&text, &Selection::caret(15), true);
+        let selection = add_cursor(&text, &selection, true);
+        assert_eq!(vec![(5, 5), (9, 9), (15, 15)], regions(&selection));
+        let top = add_cursor(&text, &selection, true);
+        assert_eq!(selection, top);
+    }
+
+    #[test]
+    fn test_column_selection() {
+        let text = Rope::from("abcdef\nab\n\nabcdef");
+        let selection = column_selection(&text, 1, 15);
+        assert_eq!(vec![(1, 4), (8, 9), (12, 15)], regions(&selection));
+        // upwards, the regions keep the anchor at their start
+        let selection = column_selection(&text, 15, 1);
+        assert_eq!(vec![(4, 1), (9, 8), (15, 12)], regions(&selection));
+        let selection = column_selection(&text, 1, 12);
+        assert_eq!(vec![(1, 1), (8, 8), (12, 12)], regions(&selection));
+    }
+
+    #[test]
+    fn test_type_at_cursors() {
+        let (mut buffer, proxy) = new_buffer("ab\ncd\nef");
+        let selection = carets(&[1, 4, 7]);
+        let delta = buffer.edit_regions(
+            vec![(&selection, "x")],
+            proxy.clone(),
+            EditType::InsertChars,
+        );
+        assert_eq!("axb\ncxd\nexf", String::from(&buffer.rope));
+        let selection = selection.apply_delta(&delta, true, InsertDrift::Default);
+        assert_eq!(vec![(2, 2), (6, 6), (10, 10)], regions(&selection));
+        buffer.edit_regions(
+            vec![(&selection, "y")],
+            proxy.clone(),
+            EditType::InsertChars,
+        );
+        assert_eq!("axyb\ncxyd\nexyf", String::from(&buffer.rope));
+
+        // the typing at every cursor goes in one undo
+        buffer.do_undo(proxy.clone());
+        assert_eq!("ab\ncd\nef", String::from(&buffer.rope));
+        buffer.do_redo(proxy);
+        assert_eq!("axyb\ncxyd\nexyf", String::from(&buffer.rope));
+    }
+
+    #[test]
+    fn test_backspace_at_cursors() {
+        let backspace =
+            |buffer: &mut BufferNew, proxy: &Arc<LapceProxy>, at: &[usize]| {
+                let selection = buffer.update_selection(
+                    &carets(at),
+                    1,
+                    &Movement::Left,
+                    Mode::Insert,
+                    true,
+                );
+                buffer.edit_regions(
+                    vec![(&selection, "")],
+                    proxy.clone(),
+                    EditType::Delete,
+                );
+            };
+
+        // at line starts, joining each line to the one above
+        let (mut buffer, proxy) = new_buffer("a\nb\nc");
+        backspace(&mut buffer, &proxy, &[2, 4]);
+        assert_eq!("abc", String::from(&buffer.rope));
+        buffer.do_undo(proxy.clone());
+        assert_eq!("a\nb\nc", String::from(&buffer.rope));
+
+        // at the start of the buffer nothing's left to delete
+        backspace(&mut buffer, &proxy, &[0, 2]);
+        assert_eq!("ab\nc", String::from(&buffer.rope));
+
+        // cursors either side of a line break delete up to each other
+        let (mut buffer, proxy) = new_buffer("ab\ncd");
+        backspace(&mut buffer, &proxy, &[2, 3]);
+        assert_eq!("acd", String::from(&buffer.rope));
+
+        // deletes that overlap take each character once
+        let (mut buffer, proxy) = new_buffer("abcdef");
+        buffer.edit_regions(
+            vec![
+                (&Selection::region(1, 4), ""),
+                (&Selection::region(3, 5), ""),
+            ],
+            proxy,
+            EditType::Delete,
+        );
+        assert_eq!("aef", String::from(&buffer.rope));
+    }
+
+    #[test]
+    fn test_split_paste() {
+        let mut selection = Selection::caret(0);
+        selection.add_region(SelRegion::new(4, 6, None));
+        let edits = split_paste(&selection, "one\r\ntwo\n").unwrap();
+        assert_eq!(
+            vec![
+                (Selection::caret(0), "one".to_string()),
+                (Selection::region(4, 6), "two".to_string())
+            ],
+            edits
+        );
+        assert!(split_paste(&selection, "one\ntwo\nthree").is_none());
+        assert!(split_paste(&Selection::caret(0), "one").is_none());
+    }
+}
@@ -22,9 +22,16 @@ use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use fzyr::{has_match, locate, Score};
 use itertools::Itertools;
+use lapce_proxy::dispatch::ReferenceLocation;
+use lapce_proxy::file_index::FileMatch;
+use lapce_proxy::lsp::WorkspaceSymbolResponse;
+use lapce_proxy::plugin::PluginCommandItem;
+use lapce_proxy::replace::ReplacePreview;
+use lapce_proxy::search::{SearchMatch, SearchOptions};
 use lapce_proxy::terminal::TermId;
 use lsp_types::{DocumentSymbolResponse, Location, Position, Range, SymbolKind};
 use serde_json::{self, json, Value};
+use std::collections::HashMap;
 use std::fs::{self, DirEntry};
 use std::marker::PhantomData;
 use std::path::PathBuf;
@@ -37,20 +44,25 @@ use usvg;
 use uuid::Uuid;
 
 use crate::{
+    buffer::BufferId,
     command::LAPCE_COMMAND,
     command::LAPCE_UI_COMMAND,
     command::{CommandTarget, LapceCommand, LAPCE_NEW_COMMAND},
     command::{LapceCommandNew, LapceUICommand},
+    command_usage::{self, CommandUsage},
     config::{Config, LapceTheme},
     data::{
         EditorContent, EditorKind, FocusArea, LapceEditorData, LapceEditorViewData,
         LapceMainSplitData, LapceTabData,
     },
+    db::LapceDb,
     editor::{EditorLocationNew, LapceEditorContainer, LapceEditorView},
     find::Find,
-    keypress::{KeyPressData, KeyPressFocus},
+    keypress::{keys_label, mode_name, KeyPressData, KeyPressFocus},
     movement::Movement,
     proxy::LapceProxy,
+    recent::{self, ClosedEditor},
+    remote::destination,
     scroll::{LapceIdentityWrapper, LapceScroll, LapceScrollNew},
     state::LapceFocus,
     state::LapceWorkspace,
@@ -59,6 +71,7 @@ use crate::{
     svg::{file_svg_new, symbol_svg_new},
     terminal::TerminalSplitData,
     theme::OldLapceTheme,
+    when::KeymapContext,
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -67,10 +80,15 @@ pub enum PaletteType {
     Line,
     GlobalSearch,
     DocumentSymbol,
+    WorkspaceSymbol,
     Workspace,
     Command,
     Reference,
     Theme,
+    Rename,
+    /// The input is what the matches of a search are replaced with.
+    Replace,
+    ClosedEditor,
 }
 
 impl PaletteType {
@@ -79,11 +97,15 @@ impl PaletteType {
             PaletteType::File => "".to_string(),
             PaletteType::Line => "/".to_string(),
             PaletteType::DocumentSymbol => "@".to_string(),
+            PaletteType::WorkspaceSymbol => "#".to_string(),
             PaletteType::GlobalSearch => "?".to_string(),
             PaletteType::Workspace => ">".to_string(),
             PaletteType::Command => ":".to_string(),
             PaletteType::Reference => "".to_string(),
             PaletteType::Theme => "".to_string(),
+            PaletteType::Rename => "".to_string(),
+            PaletteType::Replace => "".to_string(),
+            PaletteType::ClosedEditor => "".to_string(),
         }
     }
 
@@ -91,8 +113,11 @@ impl PaletteType {
         match &self {
             PaletteType::Line
             | PaletteType::DocumentSymbol
+            | PaletteType::WorkspaceSymbol
             | PaletteType::GlobalSearch
-            | PaletteType::Reference => true,
+            | PaletteType::Reference
+            | PaletteType::Replace
+            | PaletteType::ClosedEditor => true,
             _ => false,
         }
     }
@@ -123,10 +148,40 @@ pub enum PaletteItemContent {
         range: Range,
         container_name: Option<String>,
     },
-    ReferenceLocation(PathBuf, EditorLocationNew),
+    WorkspaceSymbol {
+        kind: SymbolKind,
+        name: String,
+        location: EditorLocationNew,
+        container_name: Option<String>,
+        language_id: String,
+    },
+    ReferenceLocation(PathBuf, EditorLocationNew, String),
     Workspace(LapceWorkspace),
-    Command(LapceCommandNew),
+    Command {
+        command: LapceCommandNew,
+        /// The keys that run it, as they're shown.
+        keys: String,
+        /// False when its keymaps don't apply where the palette was opened
+        /// from, for it to be shown but not run.
+        enabled: bool,
+    },
+    PluginCommand(PluginCommandItem),
     Theme(String),
+    Rename {
+        buffer_id: BufferId,
+        path: PathBuf,
+        rev: u64,
+        position: Position,
+        new_name: String,
+    },
+    ReplaceMatch {
+        session_id: u64,
+        id: usize,
+        rel_path: PathBuf,
+        location: EditorLocationNew,
+        before: String,
+        after: String,
+    },
 }
 
 impl PaletteItemContent {
@@ -170,7 +225,22 @@ impl PaletteItemContent {
                     Target::Auto,
                 ));
             }
-            PaletteItemContent::ReferenceLocation(rel_path, location) => {
+            PaletteItemContent::WorkspaceSymbol { location, .. } => {
+                let kind = if preview {
+                    EditorKind::PalettePreview
+                } else {
+                    EditorKind::SplitActive
+                };
+                ctx.submit_command(Command::new(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::JumpToLocation(kind, location.clone()),
+                    Target::Auto,
+                ));
+            }
+            PaletteItemContent::ReferenceLocation(rel_path, location, _)
+            | PaletteItemContent::ReplaceMatch {
+                rel_path, location, ..
+            } => {
                 let kind = if preview {
                     EditorKind::PalettePreview
                 } else {
@@ -198,8 +268,10 @@ impl PaletteItemContent {
                     Target::Auto,
                 ));
             }
-            PaletteItemContent::Command(command) => {
-                if !preview {
+            PaletteItemContent::Command {
+                command, enabled, ..
+            } => {
+                if !preview && *enabled {
                     ctx.submit_command(Command::new(
                         LAPCE_NEW_COMMAND,
                         command.clone(),
@@ -207,6 +279,15 @@ impl PaletteItemContent {
                     ));
                 }
             }
+            PaletteItemContent::PluginCommand(command) => {
+                if !preview {
+                    ctx.submit_command(Command::new(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::ExecutePluginCommand(command.id.clone()),
+                        Target::Auto,
+                    ));
+                }
+            }
             PaletteItemContent::TerminalLine(line, content) => {
                 if !preview {
                     ctx.submit_command(Command::new(
@@ -216,6 +297,27 @@ impl PaletteItemContent {
                     ));
                 }
             }
+            PaletteItemContent::Rename {
+                buffer_id,
+                path,
+                rev,
+                position,
+                new_name,
+            } => {
+                if !preview {
+                    ctx.submit_command(Command::new(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::RenameSymbol(
+                            *buffer_id,
+                            path.clone(),
+                            *rev,
+                            *position,
+                            new_name.clone(),
+                        ),
+                        Target::Auto,
+                    ));
+                }
+            }
         }
         None
     }
@@ -262,31 +364,52 @@ impl PaletteItemContent {
                     .collect();
                 (symbol_svg_new(kind), text, text_indices, hint, hint_indices)
             }
+            PaletteItemContent::WorkspaceSymbol {
+                kind,
+                name,
+                container_name,
+                language_id,
+                ..
+            } => {
+                let hint = container_name.clone().unwrap_or(language_id.clone());
+                (
+                    symbol_svg_new(kind),
+                    name.to_string(),
+                    indices.to_vec(),
+                    hint,
+                    vec![],
+                )
+            }
             PaletteItemContent::Line(_, text) => {
                 (None, text.clone(), indices.to_vec(), "".to_string(), vec![])
             }
-            PaletteItemContent::ReferenceLocation(rel_path, location) => {
-                file_paint_items(rel_path, indices)
+            PaletteItemContent::ReferenceLocation(rel_path, location, preview) => {
+                let (svg, text, text_indices, _, _) =
+                    file_paint_items(rel_path, indices);
+                (svg, text, text_indices, preview.trim().to_string(), vec![])
             }
             PaletteItemContent::Workspace(w) => {
                 let text = w.path.to_str().unwrap();
                 let text = match &w.kind {
                     LapceWorkspaceType::Local => text.to_string(),
                     LapceWorkspaceType::RemoteSSH(user, host) => {
-                        format!("[{}@{}] {}", user, host, text)
+                        format!("[{}] {}", destination(user, host), text)
                     }
                 };
                 (None, text, indices.to_vec(), "".to_string(), vec![])
             }
-            PaletteItemContent::Command(command) => (
+            PaletteItemContent::Command { command, keys, .. } => (
                 None,
-                command
-                    .palette_desc
-                    .as_ref()
-                    .map(|m| m.to_string())
-                    .unwrap_or("".to_string()),
+                command_text(command),
                 indices.to_vec(),
-                "".to_string(),
+                keys.clone(),
+                vec![],
+            ),
+            PaletteItemContent::PluginCommand(command) => (
+                None,
+                command.title.clone(),
+                indices.to_vec(),
+                command.plugin.clone(),
                 vec![],
             ),
             PaletteItemContent::Theme(theme) => (
@@ -303,6 +426,23 @@ impl PaletteItemContent {
                 "".to_string(),
                 vec![],
             ),
+            PaletteItemContent::Rename { new_name, .. } => (
+                None,
+                format!("Rename to {}", new_name),
+                vec![],
+                "".to_string(),
+                vec![],
+            ),
+            PaletteItemContent::ReplaceMatch {
+                rel_path,
+                before,
+                after,
+                ..
+            } => {
+                let (svg, text, _, _, _) = file_paint_items(rel_path, &[]);
+                let hint = format!("{} \u{2192} {}", before.trim(), after.trim());
+                (svg, text, vec![], hint, vec![])
+            }
         };
 
         if let Some(svg) = svg.as_ref() {
@@ -316,23 +456,28 @@ impl PaletteItemContent {
         }
 
         let svg_x = match &self {
-            &PaletteItemContent::Line(_, _) | &PaletteItemContent::Workspace(_) => {
-                0.0
-            }
+            &PaletteItemContent::Line(_, _)
+            | &PaletteItemContent::Workspace(_)
+            | &PaletteItemContent::Rename { .. } => 0.0,
             _ => line_height,
         };
 
         let focus_color = Color::rgb8(0, 0, 0);
+        let text_color = match &self {
+            PaletteItemContent::Command { enabled: false, .. } => config
+                .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                .clone()
+                .with_alpha(0.5),
+            _ => config
+                .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                .clone(),
+        };
 
         let mut text_layout = ctx
             .text()
             .new_text_layout(text.clone())
             .font(FontFamily::SYSTEM_UI, 14.0)
-            .text_color(
-                config
-                    .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
-                    .clone(),
-            );
+            .text_color(text_color);
         for i in &text_indices {
             let i = *i;
             text_layout = text_layout.range_attribute(
@@ -444,6 +589,15 @@ pub struct PaletteData {
     items: Vec<NewPaletteItem>,
     filtered_items: Vec<NewPaletteItem>,
     pub preview_editor: WidgetId,
+    /// The workspace search the items are of, the batches of older ones
+    /// being dropped.
+    search_id: u64,
+    pub search_options: SearchOptions,
+    /// The search whose matches a `Replace` palette replaces.
+    replace_query: String,
+    db: Arc<LapceDb>,
+    /// How the commands were used, for those used most to rank higher.
+    command_usage: Arc<HashMap<String, CommandUsage>>,
 }
 
 impl KeyPressFocus for PaletteViewData {
@@ -485,6 +639,21 @@ impl KeyPressFocus for PaletteViewData {
             LapceCommand::ListSelect => {
                 self.select(ctx);
             }
+            LapceCommand::ToggleSearchCaseSensitive => {
+                self.toggle_search_option(ctx, |o| &mut o.case_sensitive);
+            }
+            LapceCommand::ToggleSearchWholeWord => {
+                self.toggle_search_option(ctx, |o| &mut o.whole_word);
+            }
+            LapceCommand::ToggleSearchRegex => {
+                self.toggle_search_option(ctx, |o| &mut o.regex);
+            }
+            LapceCommand::PaletteReplace => {
+                self.run_replace(ctx);
+            }
+            LapceCommand::PaletteDropReplaceMatch => {
+                self.drop_replace_match(ctx);
+            }
             _ => {}
         }
     }
@@ -498,7 +667,7 @@ impl KeyPressFocus for PaletteViewData {
 }
 
 impl PaletteData {
-    pub fn new(proxy: Arc<LapceProxy>) -> Self {
+    pub fn new(proxy: Arc<LapceProxy>, db: Arc<LapceDb>) -> Self {
         let (sender, receiver) = unbounded();
         let widget_id = WidgetId::next();
         let scroll_id = WidgetId::next();
@@ -518,15 +687,45 @@ impl PaletteData {
             items: Vec::new(),
             filtered_items: Vec::new(),
             preview_editor,
+            search_id: 0,
+            search_options: SearchOptions::default(),
+            replace_query: "".to_string(),
+            command_usage: Arc::new(db.get_command_usage().unwrap_or_default()),
+            db,
         }
     }
 
+    /// Counts a use of the command of the item, if it's one that runs when
+    /// selected, a disabled command doing nothing.
+    fn record_command_usage(&mut self, item: &NewPaletteItem) {
+        let id = match &item.content {
+            PaletteItemContent::Command {
+                command,
+                enabled: true,
+                ..
+            } => command.cmd.clone(),
+            PaletteItemContent::PluginCommand(command) => command.id.clone(),
+            _ => return,
+        };
+        let usage = Arc::make_mut(&mut self.command_usage);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        command_usage::record(usage, &id, now);
+        let _ = self.db.save_command_usage(usage.clone());
+    }
+
     fn len(&self) -> usize {
         self.current_items().len()
     }
 
     pub fn current_items(&self) -> &Vec<NewPaletteItem> {
-        if self.get_input() == "" {
+        // the matches of a search are filtered by the proxy
+        if self.get_input() == ""
+            || self.palette_type == PaletteType::GlobalSearch
+            || self.palette_type == PaletteType::Replace
+        {
             &self.items
         } else {
             &self.filtered_items
@@ -552,13 +751,98 @@ impl PaletteData {
             PaletteType::File => &self.input,
             PaletteType::Reference => &self.input,
             PaletteType::Theme => &self.input,
+            PaletteType::Rename => &self.input,
+            PaletteType::Replace => &self.input,
+            PaletteType::ClosedEditor => &self.input,
             PaletteType::Line => &self.input[1..],
             PaletteType::DocumentSymbol => &self.input[1..],
+            PaletteType::WorkspaceSymbol => &self.input[1..],
             PaletteType::Workspace => &self.input[1..],
             PaletteType::Command => &self.input[1..],
             PaletteType::GlobalSearch => &self.input[1..],
         }
     }
+
+    /// Adds a batch of the matches of search `search_id`, unless the palette
+    /// has moved on to another.
+    pub fn add_search_matches(
+        &mut self,
+        ctx: &mut EventCtx,
+        search_id: u64,
+        matches: &[SearchMatch],
+        workspace: Option<&LapceWorkspace>,
+    ) {
+        if search_id != self.search_id
+            || self.palette_type != PaletteType::GlobalSearch
+        {
+            return;
+        }
+        let first = self.items.is_empty();
+        self.items.extend(matches.iter().map(|m| {
+            let path = workspace
+                .and_then(|workspace| m.path.strip_prefix(&workspace.path).ok())
+                .unwrap_or(m.path.as_path())
+                .to_path_buf();
+            let location = EditorLocationNew {
+                path: m.path.clone(),
+                position: Some(Position::new(m.line as u32, m.column as u32)),
+                scroll_offset: None,
+            };
+            NewPaletteItem {
+                filter_text: path.to_str().unwrap_or("").to_string(),
+                content: PaletteItemContent::ReferenceLocation(
+                    path,
+                    location,
+                    m.line_text.clone(),
+                ),
+                score: 0,
+                indices: vec![],
+            }
+        }));
+        if first && !self.items.is_empty() {
+            self.preview(ctx);
+        }
+    }
+
+    /// Shows the replacements of `preview`, one item for each.
+    pub fn set_replace_preview(
+        &mut self,
+        ctx: &mut EventCtx,
+        preview: &ReplacePreview,
+        workspace: Option<&LapceWorkspace>,
+    ) {
+        self.index = 0;
+        self.items = preview
+            .files
+            .iter()
+            .flat_map(|file| {
+                let rel_path = workspace
+                    .and_then(|workspace| {
+                        file.path.strip_prefix(&workspace.path).ok()
+                    })
+                    .unwrap_or(file.path.as_path())
+                    .to_path_buf();
+                file.matches.iter().map(move |m| NewPaletteItem {
+                    filter_text: "".to_string(),
+                    content: PaletteItemContent::ReplaceMatch {
+                        session_id: preview.session_id,
+                        id: m.id,
+                        rel_path: rel_path.clone(),
+                        location: EditorLocationNew {
+                            path: file.path.clone(),
+                            position: Some(Position::new(m.line as u32, m.column)),
+                            scroll_offset: None,
+                        },
+                        before: m.before.clone(),
+                        after: m.after.clone(),
+                    },
+                    score: 0,
+                    indices: vec![],
+                })
+            })
+            .collect();
+        self.preview(ctx);
+    }
 }
 
 impl PaletteViewData {
@@ -568,6 +852,9 @@ impl PaletteViewData {
         palette.input = "".to_string();
         palette.cursor = 0;
         palette.index = 0;
+        if palette.palette_type == PaletteType::GlobalSearch {
+            palette.proxy.cancel_search(palette.search_id);
+        }
         palette.palette_type = PaletteType::File;
         palette.items.clear();
         palette.filtered_items.clear();
@@ -579,9 +866,12 @@ impl PaletteViewData {
     pub fn run_references(
         &mut self,
         ctx: &mut EventCtx,
-        locations: &Vec<EditorLocationNew>,
+        locations: &Vec<ReferenceLocation>,
     ) {
         self.run(ctx, Some(PaletteType::Reference));
+        // ignored files, like the ones in target/, go to the end
+        let mut locations: Vec<&ReferenceLocation> = locations.iter().collect();
+        locations.sort_by_key(|l| l.ignored);
         let items: Vec<NewPaletteItem> = locations
             .iter()
             .map(|l| {
@@ -594,10 +884,16 @@ impl PaletteViewData {
                         .to_path_buf();
                 }
                 let filter_text = path.to_str().unwrap_or("").to_string();
+                let location = EditorLocationNew {
+                    path: l.path.clone(),
+                    position: Some(Position::new(l.line, l.column)),
+                    scroll_offset: None,
+                };
                 NewPaletteItem {
                     content: PaletteItemContent::ReferenceLocation(
                         path.to_path_buf(),
-                        l.clone(),
+                        location,
+                        l.preview_line.clone(),
                     ),
                     filter_text,
                     score: 0,
@@ -610,6 +906,53 @@ impl PaletteViewData {
         palette.preview(ctx);
     }
 
+    /// Opens the palette as an input for the new name of the symbol at
+    /// `position`, starting from `placeholder`.
+    pub fn run_rename(
+        &mut self,
+        ctx: &mut EventCtx,
+        buffer_id: BufferId,
+        path: &PathBuf,
+        rev: u64,
+        position: Position,
+        placeholder: &str,
+    ) {
+        self.run(ctx, Some(PaletteType::Rename));
+        let palette = Arc::make_mut(&mut self.palette);
+        palette.input = placeholder.to_string();
+        palette.cursor = palette.input.len();
+        let item = NewPaletteItem {
+            content: PaletteItemContent::Rename {
+                buffer_id,
+                path: path.clone(),
+                rev,
+                position,
+                new_name: placeholder.to_string(),
+            },
+            filter_text: "".to_string(),
+            score: 0,
+            indices: vec![],
+        };
+        palette.items = vec![item.clone()];
+        palette.filtered_items = vec![item];
+    }
+
+    fn update_rename(&mut self) {
+        let palette = Arc::make_mut(&mut self.palette);
+        let new_name = palette.input.clone();
+        for item in palette
+            .items
+            .iter_mut()
+            .chain(palette.filtered_items.iter_mut())
+        {
+            if let PaletteItemContent::Rename { new_name: name, .. } =
+                &mut item.content
+            {
+                *name = new_name.clone();
+            }
+        }
+    }
+
     pub fn run(&mut self, ctx: &mut EventCtx, palette_type: Option<PaletteType>) {
         let palette = Arc::make_mut(&mut self.palette);
         palette.status = PaletteStatus::Started;
@@ -637,6 +980,9 @@ impl PaletteViewData {
             &PaletteType::DocumentSymbol => {
                 self.get_document_symbols(ctx);
             }
+            &PaletteType::WorkspaceSymbol => {
+                self.get_workspace_symbols(ctx);
+            }
             &PaletteType::Workspace => {
                 self.get_workspaces(ctx);
             }
@@ -651,7 +997,89 @@ impl PaletteViewData {
                 let config = self.config.clone();
                 self.get_themes(ctx, &config);
             }
+            &PaletteType::Rename => {}
+            &PaletteType::Replace => {}
+            &PaletteType::ClosedEditor => {
+                self.get_closed_editors(ctx);
+            }
+        }
+    }
+
+    /// Turns a search palette into one for what its matches are replaced
+    /// with, previewing the replacements as it's typed.
+    fn run_replace(&mut self, ctx: &mut EventCtx) {
+        if self.palette.palette_type != PaletteType::GlobalSearch
+            || self.palette.get_input() == ""
+        {
+            return;
+        }
+        let query = self.palette.get_input().to_string();
+        self.run(ctx, Some(PaletteType::Replace));
+        Arc::make_mut(&mut self.palette).replace_query = query;
+        self.get_replace_preview(ctx);
+    }
+
+    fn get_replace_preview(&mut self, ctx: &mut EventCtx) {
+        let widget_id = self.palette.widget_id;
+        let replacement = self.palette.get_input().to_string();
+        let event_sink = ctx.get_external_handle();
+        self.palette.proxy.global_replace(
+            &self.palette.replace_query,
+            self.palette.search_options,
+            self.palette.get_input(),
+            Box::new(move |result| {
+                if let Ok(res) = result {
+                    if let Ok(preview) = serde_json::from_value(res) {
+                        event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateReplacePreview(
+                                replacement,
+                                preview,
+                            ),
+                            Target::Widget(widget_id),
+                        );
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Leaves the replacement selected out of the ones made.
+    fn drop_replace_match(&mut self, ctx: &mut EventCtx) {
+        let palette = Arc::make_mut(&mut self.palette);
+        if palette.palette_type != PaletteType::Replace
+            || palette.index >= palette.items.len()
+        {
+            return;
+        }
+        palette.items.remove(palette.index);
+        palette.index = palette.index.min(palette.items.len().saturating_sub(1));
+        palette.preview(ctx);
+    }
+
+    /// Makes the replacements left in the palette.
+    fn apply_replace(&mut self, ctx: &mut EventCtx) {
+        let mut session = None;
+        let ids: Vec<usize> = self
+            .palette
+            .items
+            .iter()
+            .filter_map(|item| match &item.content {
+                PaletteItemContent::ReplaceMatch { session_id, id, .. } => {
+                    session = Some(*session_id);
+                    Some(*id)
+                }
+                _ => None,
+            })
+            .collect();
+        if let Some(session_id) = session {
+            ctx.submit_command(Command::new(
+                LAPCE_UI_COMMAND,
+                LapceUICommand::ApplyReplace(session_id, ids),
+                Target::Auto,
+            ));
         }
+        self.cancel(ctx);
     }
 
     fn delete_backward(&mut self, ctx: &mut EventCtx) {
@@ -675,8 +1103,12 @@ impl PaletteViewData {
             &PaletteType::File => 0,
             &PaletteType::Reference => 0,
             &PaletteType::Theme => 0,
+            &PaletteType::Rename => 0,
+            &PaletteType::Replace => 0,
+            &PaletteType::ClosedEditor => 0,
             &PaletteType::Line => 1,
             &PaletteType::DocumentSymbol => 1,
+            &PaletteType::WorkspaceSymbol => 1,
             &PaletteType::Workspace => 1,
             &PaletteType::Command => 1,
             &PaletteType::GlobalSearch => 1,
@@ -707,6 +1139,10 @@ impl PaletteViewData {
     }
 
     pub fn select(&mut self, ctx: &mut EventCtx) {
+        if self.palette.palette_type == PaletteType::Replace {
+            self.apply_replace(ctx);
+            return;
+        }
         if self.palette.palette_type == PaletteType::Line {
             Arc::make_mut(&mut self.find).set_find(
                 self.palette.get_input(),
@@ -716,7 +1152,8 @@ impl PaletteViewData {
             );
         }
         let palette = Arc::make_mut(&mut self.palette);
-        if let Some(item) = palette.get_item() {
+        if let Some(item) = palette.get_item().cloned() {
+            palette.record_command_usage(&item);
             if let Some(palette_type) = item.content.select(ctx, false) {
                 self.run(ctx, Some(palette_type));
             } else {
@@ -735,6 +1172,28 @@ impl PaletteViewData {
             self.run(ctx, Some(palette_type));
             return;
         }
+        if palette_type == PaletteType::Rename {
+            // the only item is the input itself
+            self.update_rename();
+            return;
+        }
+        if palette_type == PaletteType::WorkspaceSymbol {
+            // the servers do the matching, so every keystroke is a new query
+            self.get_workspace_symbols(ctx);
+        }
+        if palette_type == PaletteType::File {
+            // the proxy's index does the matching
+            self.get_files(ctx);
+            return;
+        }
+        if palette_type == PaletteType::GlobalSearch {
+            self.get_global_search(ctx);
+            return;
+        }
+        if palette_type == PaletteType::Replace {
+            self.get_replace_preview(ctx);
+            return;
+        }
         if self.palette.get_input() != "" {
             self.palette.sender.send((
                 self.palette.run_id.clone(),
@@ -747,8 +1206,12 @@ impl PaletteViewData {
     }
 
     fn get_palette_type(&self) -> PaletteType {
-        if self.palette.palette_type == PaletteType::Reference {
-            return PaletteType::Reference;
+        if self.palette.palette_type == PaletteType::Reference
+            || self.palette.palette_type == PaletteType::Rename
+            || self.palette.palette_type == PaletteType::Replace
+            || self.palette.palette_type == PaletteType::ClosedEditor
+        {
+            return self.palette.palette_type.clone();
         }
         if self.palette.input == "" {
             return PaletteType::File;
@@ -756,78 +1219,128 @@ impl PaletteViewData {
         match self.palette.input {
             _ if self.palette.input.starts_with("/") => PaletteType::Line,
             _ if self.palette.input.starts_with("@") => PaletteType::DocumentSymbol,
+            _ if self.palette.input.starts_with("#") => PaletteType::WorkspaceSymbol,
             _ if self.palette.input.starts_with(">") => PaletteType::Workspace,
             _ if self.palette.input.starts_with(":") => PaletteType::Command,
+            _ if self.palette.input.starts_with("?") => PaletteType::GlobalSearch,
             _ => PaletteType::File,
         }
     }
 
+    /// Asks the proxy's index for the files best matching the input, every
+    /// keystroke being a new query.
     fn get_files(&self, ctx: &mut EventCtx) {
         let run_id = self.palette.run_id.clone();
         let widget_id = self.palette.widget_id;
-        let workspace = self.workspace.clone();
+        let input = self.palette.get_input().to_string();
         let event_sink = ctx.get_external_handle();
-        self.palette.proxy.get_files(Box::new(move |result| {
-            if let Ok(res) = result {
-                let resp: Result<Vec<PathBuf>, serde_json::Error> =
-                    serde_json::from_value(res);
-                if let Ok(resp) = resp {
-                    let items: Vec<NewPaletteItem> = resp
-                        .iter()
-                        .enumerate()
-                        .map(|(index, path)| {
-                            let full_path = path.clone();
-                            let mut path = path.clone();
-                            if let Some(workspace) = workspace.as_ref() {
-                                path = path
-                                    .strip_prefix(&workspace.path)
-                                    .unwrap_or(&full_path)
-                                    .to_path_buf();
-                            }
-                            let filter_text =
-                                path.to_str().unwrap_or("").to_string();
-                            NewPaletteItem {
-                                content: PaletteItemContent::File(
-                                    path.to_owned(),
-                                    full_path,
-                                ),
-                                filter_text,
-                                score: 0,
-                                indices: Vec::new(),
-                            }
-                        })
-                        .collect();
-                    event_sink.submit_command(
-                        LAPCE_UI_COMMAND,
-                        LapceUICommand::UpdatePaletteItems(run_id, items),
-                        Target::Widget(widget_id),
-                    );
-                }
-            }
-        }));
+        self.palette.proxy.fuzzy_file_search(
+            self.palette.get_input(),
+            self.config.lapce.file_search_limit,
+            Box::new(move |result| {
+                let matches: Vec<FileMatch> = match result
+                    .ok()
+                    .and_then(|res| serde_json::from_value(res).ok())
+                {
+                    Some(matches) => matches,
+                    None => return,
+                };
+                let items: Vec<NewPaletteItem> = matches
+                    .into_iter()
+                    .map(|m| NewPaletteItem {
+                        filter_text: m.relative.to_str().unwrap_or("").to_string(),
+                        content: PaletteItemContent::File(m.relative, m.path),
+                        score: m.score,
+                        indices: m.indices,
+                    })
+                    .collect();
+                let command = match input.is_empty() {
+                    true => LapceUICommand::UpdatePaletteItems(run_id, items),
+                    false => {
+                        LapceUICommand::FilterPaletteItems(run_id, input, items)
+                    }
+                };
+                event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    command,
+                    Target::Widget(widget_id),
+                );
+            }),
+        );
     }
 
+    /// The recent workspaces, those of local paths that are gone dropped
+    /// once they've been checked for.
     fn get_workspaces(&mut self, ctx: &mut EventCtx) {
         let workspaces = Config::recent_workspaces().unwrap_or(Vec::new());
+        let paths = recent::local_paths(&workspaces);
         let palette = Arc::make_mut(&mut self.palette);
-        palette.items = workspaces
-            .into_iter()
-            .map(|w| {
-                let text = w.path.to_str().unwrap();
-                let filter_text = match &w.kind {
-                    LapceWorkspaceType::Local => text.to_string(),
-                    LapceWorkspaceType::RemoteSSH(user, host) => {
-                        format!("[{}@{}] {}", user, host, text)
-                    }
-                };
-                NewPaletteItem {
-                    content: PaletteItemContent::Workspace(w),
-                    filter_text,
-                    score: 0,
-                    indices: vec![],
-                }
-            })
+        palette.items = workspace_items(workspaces);
+
+        let run_id = palette.run_id.clone();
+        let widget_id = palette.widget_id;
+        let event_sink = ctx.get_external_handle();
+        recent::check_missing(paths, move |missing| {
+            let mut workspaces = Config::recent_workspaces().unwrap_or(Vec::new());
+            recent::prune_workspaces(&mut workspaces, &missing);
+            Config::update_recent_workspaces(workspaces.clone());
+            event_sink.submit_command(
+                LAPCE_UI_COMMAND,
+                LapceUICommand::UpdatePaletteItems(
+                    run_id,
+                    workspace_items(workspaces),
+                ),
+                Target::Widget(widget_id),
+            );
+        });
+    }
+
+    /// The editors closed that aren't open again, the last first. Only the
+    /// files of a local workspace are checked for.
+    fn get_closed_editors(&mut self, ctx: &mut EventCtx) {
+        let workspace = self.workspace.clone();
+        let closed: Vec<ClosedEditor> = self
+            .main_split
+            .closed_editors
+            .iter()
+            .rev()
+            .filter(|editor| !self.main_split.is_open(&editor.path))
+            .cloned()
             .collect();
+        let palette = Arc::make_mut(&mut self.palette);
+        palette.items = closed_editor_items(&closed, workspace.as_deref());
+
+        let is_local = workspace
+            .as_ref()
+            .map(|w| w.kind == LapceWorkspaceType::Local)
+            .unwrap_or(true);
+        if !is_local {
+            return;
+        }
+        let paths = closed.iter().map(|editor| editor.path.clone()).collect();
+        let run_id = palette.run_id.clone();
+        let widget_id = palette.widget_id;
+        let tab_id = *self.main_split.tab_id;
+        let event_sink = ctx.get_external_handle();
+        recent::check_missing(paths, move |missing| {
+            let closed: Vec<ClosedEditor> = closed
+                .into_iter()
+                .filter(|editor| !missing.contains(&editor.path))
+                .collect();
+            event_sink.submit_command(
+                LAPCE_UI_COMMAND,
+                LapceUICommand::UpdatePaletteItems(
+                    run_id,
+                    closed_editor_items(&closed, workspace.as_deref()),
+                ),
+                Target::Widget(widget_id),
+            );
+            event_sink.submit_command(
+                LAPCE_UI_COMMAND,
+                LapceUICommand::PruneClosedEditors(missing),
+                Target::Widget(tab_id),
+            );
+        });
     }
 
     fn get_themes(&mut self, ctx: &mut EventCtx, config: &Config) {
@@ -844,21 +1357,106 @@ impl PaletteViewData {
             .collect();
     }
 
+    /// The commands by title, with the keys that run them, those used most
+    /// first. The score of an item is the boost of its use, which that of a
+    /// match adds to. Those whose keymaps don't apply where the palette was
+    /// opened from are hidden or shown disabled, as configured.
     fn get_commands(&mut self, ctx: &mut EventCtx) {
+        let context = self.keymap_context();
+        let hide = self.config.lapce.unavailable_commands == "hide";
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         let palette = Arc::make_mut(&mut self.palette);
-        palette.items = self
+        let usage = palette.command_usage.clone();
+        let mut items: Vec<NewPaletteItem> = self
             .keypress
             .commands
             .iter()
-            .filter_map(|(_, c)| {
-                c.palette_desc.as_ref().map(|m| NewPaletteItem {
-                    content: PaletteItemContent::Command(c.clone()),
-                    filter_text: m.to_string(),
-                    score: 0,
+            .filter(|(_, c)| {
+                c.palette_desc.is_some() || c.target == CommandTarget::Workbench
+            })
+            .filter_map(|(id, c)| {
+                let enabled = self.keypress.is_available(id, &context);
+                if hide && !enabled {
+                    return None;
+                }
+                let keys = self
+                    .keypress
+                    .command_keys(id, &context)
+                    .map(|keys| keys_label(&keys))
+                    .unwrap_or_default();
+                Some(NewPaletteItem {
+                    filter_text: command_text(c),
+                    content: PaletteItemContent::Command {
+                        command: c.clone(),
+                        keys,
+                        enabled,
+                    },
+                    score: command_usage::boost(usage.get(id), now),
                     indices: vec![],
                 })
             })
             .collect();
+        items.sort_by_key(|item| std::cmp::Reverse(item.score));
+        palette.items = items;
+
+        let run_id = palette.run_id.clone();
+        let widget_id = palette.widget_id;
+        let builtin_items = palette.items.clone();
+        let event_sink = ctx.get_external_handle();
+        palette.proxy.get_plugin_commands(Box::new(move |result| {
+            if let Ok(res) = result {
+                let resp: Result<Vec<PluginCommandItem>, serde_json::Error> =
+                    serde_json::from_value(res);
+                if let Ok(resp) = resp {
+                    if resp.is_empty() {
+                        return;
+                    }
+                    let mut items = builtin_items;
+                    items.extend(resp.into_iter().map(|command| NewPaletteItem {
+                        filter_text: command.title.clone(),
+                        score: command_usage::boost(usage.get(&command.id), now),
+                        content: PaletteItemContent::PluginCommand(command),
+                        indices: vec![],
+                    }));
+                    items.sort_by_key(|item| std::cmp::Reverse(item.score));
+                    event_sink.submit_command(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::UpdatePaletteItems(run_id, items),
+                        Target::Widget(widget_id),
+                    );
+                }
+            }
+        }));
+    }
+
+    /// The context of the keymaps where the palette was opened from.
+    fn keymap_context(&self) -> KeymapContext {
+        let editor = self.main_split.active_editor();
+        let mode = match self.focus_area {
+            FocusArea::Terminal => self
+                .terminal
+                .terminals
+                .get(&self.terminal.active_term_id)
+                .map(|terminal| terminal.mode)
+                .unwrap_or(Mode::Terminal),
+            _ => editor.cursor.get_mode(),
+        };
+        let mut context = KeymapContext::default();
+        context.set_string("mode", mode_name(&mode));
+        context.set_bool("editor_focus", self.focus_area == FocusArea::Editor);
+        context.set_bool("terminal_focus", self.focus_area == FocusArea::Terminal);
+        context.set_bool(
+            "source_control_focus",
+            self.focus_area == FocusArea::SourceControl,
+        );
+        context.set_bool(
+            "in_snippet",
+            self.focus_area == FocusArea::Editor && editor.snippet.is_some(),
+        );
+        context
     }
 
     fn get_lines(&mut self, ctx: &mut EventCtx) {
@@ -946,7 +1544,38 @@ impl PaletteViewData {
         }
     }
 
-    fn get_global_search(&mut self, ctx: &mut EventCtx) {}
+    /// Searches the workspace for the input, with the matches coming in
+    /// batches to `add_search_matches`. The proxy stops the search before.
+    fn get_global_search(&mut self, ctx: &mut EventCtx) {
+        let palette = Arc::make_mut(&mut self.palette);
+        palette.search_id += 1;
+        palette.items.clear();
+        palette.index = 0;
+        let query = palette.get_input().to_string();
+        if query.is_empty() {
+            palette.proxy.cancel_search(palette.search_id - 1);
+            return;
+        }
+        palette.proxy.global_search(
+            palette.search_id,
+            &query,
+            palette.search_options,
+            Box::new(|_| {}),
+        );
+    }
+
+    fn toggle_search_option(
+        &mut self,
+        ctx: &mut EventCtx,
+        option: fn(&mut SearchOptions) -> &mut bool,
+    ) {
+        let palette = Arc::make_mut(&mut self.palette);
+        let enabled = option(&mut palette.search_options);
+        *enabled = !*enabled;
+        if palette.palette_type == PaletteType::GlobalSearch {
+            self.get_global_search(ctx);
+        }
+    }
 
     fn get_document_symbols(&mut self, ctx: &mut EventCtx) {
         let editor = self.main_split.active_editor();
@@ -1028,6 +1657,54 @@ impl PaletteViewData {
         }
     }
 
+    fn get_workspace_symbols(&mut self, ctx: &mut EventCtx) {
+        let widget_id = self.palette.widget_id;
+        let run_id = self.palette.run_id.clone();
+        let event_sink = ctx.get_external_handle();
+        self.palette.proxy.get_workspace_symbols(
+            self.palette.get_input(),
+            self.config.lapce.workspace_symbol_limit,
+            Box::new(move |result| {
+                let resp: WorkspaceSymbolResponse = match result
+                    .ok()
+                    .and_then(|res| serde_json::from_value(res).ok())
+                {
+                    Some(resp) => resp,
+                    None => return,
+                };
+                let items: Vec<NewPaletteItem> = resp
+                    .symbols
+                    .into_iter()
+                    .filter_map(|item| {
+                        let symbol = item.symbol;
+                        let path = symbol.location.uri.to_file_path().ok()?;
+                        Some(NewPaletteItem {
+                            filter_text: symbol.name.clone(),
+                            content: PaletteItemContent::WorkspaceSymbol {
+                                kind: symbol.kind,
+                                name: symbol.name,
+                                location: EditorLocationNew {
+                                    path,
+                                    position: Some(symbol.location.range.start),
+                                    scroll_offset: None,
+                                },
+                                container_name: symbol.container_name,
+                                language_id: item.language_id,
+                            },
+                            score: 0,
+                            indices: Vec::new(),
+                        })
+                    })
+                    .collect();
+                event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdatePaletteItems(run_id, items),
+                    Target::Widget(widget_id),
+                );
+            }),
+        );
+    }
+
     pub fn update_process(
         receiver: Receiver<(String, String, Vec<NewPaletteItem>)>,
         widget_id: WidgetId,
@@ -1084,7 +1761,8 @@ impl PaletteViewData {
                     matcher.fuzzy_indices(&i.filter_text, input)
                 {
                     let mut item = i.clone();
-                    item.score = score;
+                    // what the item scores on its own adds to the match
+                    item.score = score + i.score;
                     item.indices = indices;
                     Some(item)
                 } else {
@@ -1178,17 +1856,55 @@ impl Widget<LapceTabData> for NewPalette {
                         data.workspace = palette_data.workspace.clone();
                         data.main_split = palette_data.main_split.clone();
                     }
+                    LapceUICommand::RunPaletteRename(
+                        buffer_id,
+                        path,
+                        rev,
+                        position,
+                        placeholder,
+                    ) => {
+                        ctx.request_focus();
+                        let mut palette_data = data.palette_view_data();
+                        palette_data.run_rename(
+                            ctx,
+                            *buffer_id,
+                            path,
+                            *rev,
+                            *position,
+                            placeholder,
+                        );
+                        data.palette = palette_data.palette.clone();
+                        data.keypress = palette_data.keypress.clone();
+                        data.workspace = palette_data.workspace.clone();
+                        data.main_split = palette_data.main_split.clone();
+                    }
                     LapceUICommand::CancelPalette => {
                         let mut palette_data = data.palette_view_data();
                         palette_data.cancel(ctx);
                         data.palette = palette_data.palette.clone();
                     }
+                    LapceUICommand::UpdateReplacePreview(replacement, preview) => {
+                        let workspace = data.workspace.clone();
+                        let palette = Arc::make_mut(&mut data.palette);
+                        if palette.palette_type == PaletteType::Replace
+                            && &palette.get_input() == replacement
+                        {
+                            palette.set_replace_preview(
+                                ctx,
+                                preview,
+                                workspace.as_deref(),
+                            );
+                        }
+                    }
                     LapceUICommand::UpdatePaletteItems(run_id, items) => {
                         let palette = Arc::make_mut(&mut data.palette);
                         if &palette.run_id == run_id {
                             palette.items = items.to_owned();
                             palette.preview(ctx);
-                            if palette.get_input() != "" {
+                            // the files are matched by the proxy
+                            if palette.get_input() != ""
+                                && palette.palette_type != PaletteType::File
+                            {
                                 palette.sender.send((
                                     palette.run_id.clone(),
                                     palette.get_input().to_string(),
@@ -1731,6 +2447,62 @@ impl PaletteContent {
     }
 }
 
+/// A command as it's listed and matched, as `Palette: Open Recent Workspace`.
+fn command_text(command: &LapceCommandNew) -> String {
+    format!("{}: {}", command.category(), command.title())
+}
+
+fn workspace_items(workspaces: Vec<LapceWorkspace>) -> Vec<NewPaletteItem> {
+    workspaces
+        .into_iter()
+        .map(|w| {
+            let text = w.path.to_str().unwrap();
+            let filter_text = match &w.kind {
+                LapceWorkspaceType::Local => text.to_string(),
+                LapceWorkspaceType::RemoteSSH(user, host) => {
+                    format!("[{}] {}", destination(user, host), text)
+                }
+            };
+            NewPaletteItem {
+                content: PaletteItemContent::Workspace(w),
+                filter_text,
+                score: 0,
+                indices: vec![],
+            }
+        })
+        .collect()
+}
+
+/// The closed editors as locations, shown relative to the workspace.
+fn closed_editor_items(
+    closed: &[ClosedEditor],
+    workspace: Option<&LapceWorkspace>,
+) -> Vec<NewPaletteItem> {
+    closed
+        .iter()
+        .map(|editor| {
+            let rel_path = workspace
+                .and_then(|w| editor.path.strip_prefix(&w.path).ok())
+                .unwrap_or(&editor.path)
+                .to_path_buf();
+            NewPaletteItem {
+                filter_text: rel_path.to_str().unwrap_or("").to_string(),
+                content: PaletteItemContent::ReferenceLocation(
+                    rel_path,
+                    EditorLocationNew {
+                        path: editor.path.clone(),
+                        position: editor.position,
+                        scroll_offset: None,
+                    },
+                    "".to_string(),
+                ),
+                score: 0,
+                indices: vec![],
+            }
+        })
+        .collect()
+}
+
 fn file_paint_items(
     path: &PathBuf,
     indices: &[usize],
@@ -2,8 +2,14 @@ use std::collections::HashMap;
 use std::io::BufReader;
 use std::process::Command;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
-use std::{path::PathBuf, process::Child, sync::Arc};
+use std::time::{Duration, Instant};
+use std::{
+    path::{Path, PathBuf},
+    process::Child,
+    sync::Arc,
+};
 
 use alacritty_terminal::term::cell::Cell;
 use anyhow::{anyhow, Result};
@@ -11,40 +17,78 @@ use crossbeam_channel::Sender;
 use crossbeam_utils::sync::WaitGroup;
 use druid::{ExtEventSink, WidgetId};
 use druid::{Target, WindowId};
+use lapce_proxy::backup::BackupManifest;
+use lapce_proxy::buffer::ExternalChange;
+use lapce_proxy::conflicts::ConflictChoice;
 use lapce_proxy::dispatch::{FileNodeItem, NewBufferResponse};
-use lapce_proxy::terminal::TermId;
+use lapce_proxy::git::{BranchInfo, GitStatus, HunkRange};
+use lapce_proxy::line_ending::LineEnding;
+use lapce_proxy::lsp::{
+    DiagnosticCounts, DocumentHighlightResponse, DocumentLinkItem, LineStyles,
+    LspCapabilities, SignatureHelpResponse, WorkProgress,
+};
+use lapce_proxy::search::{SearchMatch, SearchOptions};
+use lapce_proxy::settings::ConfigChange;
+use lapce_proxy::task::TaskId;
+use lapce_proxy::terminal::{TermId, TerminalInfo};
+use lapce_rpc::transport::{DecodingReader, TransportOptions};
+use lsp_types::ApplyWorkspaceEditResponse;
+use lsp_types::CodeAction;
 use lsp_types::CompletionItem;
+use lsp_types::Diagnostic;
 use lsp_types::Position;
 use lsp_types::PublishDiagnosticsParams;
+use lsp_types::Range;
+use lsp_types::TextEdit;
+use lsp_types::WorkspaceEdit;
 use parking_lot::{Condvar, Mutex};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use serde_json::Value;
-use xi_rope::RopeDelta;
+use xi_rope::{Rope, RopeDelta};
 use xi_rpc::Callback;
 use xi_rpc::Handler;
 use xi_rpc::RpcLoop;
 use xi_rpc::RpcPeer;
 
 use crate::command::LapceUICommand;
+use crate::data::EditReply;
+use crate::remote;
 use crate::state::LapceWorkspace;
 use crate::state::LapceWorkspaceType;
 use crate::terminal::RawTerminal;
 use crate::{buffer::BufferId, command::LAPCE_UI_COMMAND};
 
+/// How long to wait before starting a proxy that crashed or lost its
+/// connection again, doubled on each try up to the most.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
 pub enum TermEvent {
     NewTerminal(Arc<Mutex<RawTerminal>>),
     UpdateContent(String),
     CloseTerminal,
 }
 
+/// A buffer as the proxy was last told it is, for one started again to be
+/// given it.
+struct SyncedBuffer {
+    path: PathBuf,
+    text: Rope,
+    rev: u64,
+}
+
 #[derive(Clone)]
 pub struct LapceProxy {
     peer: Arc<Mutex<Option<RpcPeer>>>,
     process: Arc<Mutex<Option<Child>>>,
     initiated: Arc<Mutex<bool>>,
     cond: Arc<Condvar>,
+    buffers: Arc<Mutex<HashMap<BufferId, SyncedBuffer>>>,
     term_tx: Sender<(TermId, TermEvent)>,
+    /// Which start the proxy is of, for one that's gone to be started again
+    /// only while it's the last.
+    generation: Arc<AtomicU64>,
     pub tab_id: WidgetId,
 }
 
@@ -55,7 +99,9 @@ impl LapceProxy {
             process: Arc::new(Mutex::new(None)),
             initiated: Arc::new(Mutex::new(false)),
             cond: Arc::new(Condvar::new()),
+            buffers: Arc::new(Mutex::new(HashMap::new())),
             term_tx,
+            generation: Arc::new(AtomicU64::new(0)),
             tab_id,
         };
         proxy
@@ -64,69 +110,173 @@ impl LapceProxy {
     pub fn start(&self, workspace: LapceWorkspace, event_sink: ExtEventSink) {
         let proxy = self.clone();
         *proxy.initiated.lock() = false;
+        proxy.buffers.lock().clear();
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        thread::spawn(move || {
+            let mut delay = RECONNECT_DELAY;
+            let mut restarted = false;
+            loop {
+                let started = Instant::now();
+                if let Err(e) = proxy.run(&workspace, event_sink.clone(), restarted)
+                {
+                    eprintln!("can't start proxy: {}", e);
+                }
+                // stopped, or started again for another workspace
+                if proxy.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                if started.elapsed() > MAX_RECONNECT_DELAY {
+                    delay = RECONNECT_DELAY;
+                }
+                eprintln!("proxy gone, starting it again in {:?}", delay);
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                restarted = true;
+            }
+        });
+    }
+
+    /// Starts the proxy and handles what it sends until it's gone. One
+    /// `restarted` after the last crashed or lost its connection is given
+    /// the buffers of the core again.
+    fn run(
+        &self,
+        workspace: &LapceWorkspace,
+        event_sink: ExtEventSink,
+        restarted: bool,
+    ) -> Result<()> {
         let tab_id = self.tab_id;
         let term_tx = self.term_tx.clone();
-        thread::spawn(move || {
-            let mut child = match workspace.kind {
-                LapceWorkspaceType::Local => Command::new(
-                    std::env::current_exe()
-                        .unwrap()
-                        .parent()
-                        .unwrap()
-                        .join("lapce-proxy"),
-                )
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn(),
-                LapceWorkspaceType::RemoteSSH(user, host) => Command::new("ssh")
-                    .arg(format!("{}@{}", user, host))
-                    .arg("-o")
-                    .arg("ControlMaster=auto")
-                    .arg("-o")
-                    .arg("ControlPath=~/.ssh/cm-%r@%h:%p")
-                    .arg("-o")
-                    .arg("ControlPersist=30m")
-                    .arg("/tmp/proxy/target/release/lapce-proxy")
+        let mut child = match &workspace.kind {
+            LapceWorkspaceType::Local => Command::new(
+                std::env::current_exe()
+                    .unwrap()
+                    .parent()
+                    .unwrap()
+                    .join("lapce-proxy"),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?,
+            LapceWorkspaceType::RemoteSSH(user, host) => {
+                let path = remote::deploy_proxy(user, host)?;
+                remote::ssh_command(user, host)?
+                    .arg(path)
+                    .arg("--remote")
                     .stdin(Stdio::piped())
                     .stdout(Stdio::piped())
-                    .spawn(),
-            };
-            if child.is_err() {
-                println!("can't start proxy {:?}", child);
-                return;
-            }
-            let mut child = child.unwrap();
-            let child_stdin = child.stdin.take().unwrap();
-            let child_stdout = child.stdout.take().unwrap();
-            let mut looper = RpcLoop::new(child_stdin);
-            let peer: RpcPeer = Box::new(looper.get_raw_peer());
-            {
-                *proxy.peer.lock() = Some(peer);
-                let mut process = proxy.process.lock();
-                let mut old_process = process.take();
-                *process = Some(child);
-                if let Some(mut old) = old_process {
-                    old.kill();
-                }
+                    .spawn()?
             }
-            proxy.initialize(workspace.path.clone());
-            {
-                *proxy.initiated.lock() = true;
-                proxy.cond.notify_all();
+        };
+        let child_stdin = child.stdin.take().unwrap();
+        let child_stdout = child.stdout.take().unwrap();
+        let mut looper = RpcLoop::new(child_stdin);
+        let peer: RpcPeer = Box::new(looper.get_raw_peer());
+        // no edit goes to the proxy until it has the buffers again
+        let buffers = self.buffers.lock();
+        {
+            *self.peer.lock() = Some(peer);
+            let mut process = self.process.lock();
+            let mut old_process = process.take();
+            *process = Some(child);
+            if let Some(mut old) = old_process {
+                old.kill();
             }
-
-            let mut handler = ProxyHandlerNew {
-                tab_id,
-                term_tx,
-                event_sink,
-            };
-            if let Err(e) =
-                looper.mainloop(|| BufReader::new(child_stdout), &mut handler)
-            {
-                println!("proxy main loop failed {:?}", e);
+        }
+        // a remote proxy's messages are compressed and batched, not a
+        // local one's
+        let remote = matches!(workspace.kind, LapceWorkspaceType::RemoteSSH(..));
+        self.initialize(
+            workspace.path.clone(),
+            TransportOptions {
+                compression: remote,
+                batching: remote,
+            },
+        );
+        if restarted {
+            for (buffer_id, buffer) in buffers.iter() {
+                self.resync_buffer(*buffer_id, buffer);
             }
-            println!("proxy main loop exit");
-        });
+        }
+        {
+            *self.initiated.lock() = true;
+            self.cond.notify_all();
+        }
+        drop(buffers);
+        self.check_version();
+        // the shells a proxy kept from an earlier core
+        let local_event_sink = event_sink.clone();
+        self.terminal_list(Box::new(move |result| {
+            let terminals = result.ok().and_then(|res| {
+                serde_json::from_value::<Vec<TerminalInfo>>(res).ok()
+            });
+            if let Some(terminals) = terminals.filter(|t| !t.is_empty()) {
+                local_event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RestoreTerminals(terminals),
+                    Target::Widget(tab_id),
+                );
+            }
+        }));
+        let local_event_sink = event_sink.clone();
+        self.list_unsaved_backups(Box::new(move |result| {
+            let backups = result.ok().and_then(|res| {
+                serde_json::from_value::<Vec<BackupManifest>>(res).ok()
+            });
+            if let Some(backups) = backups {
+                local_event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UnsavedBackups(backups),
+                    Target::Widget(tab_id),
+                );
+            }
+        }));
+
+        let mut handler = ProxyHandlerNew {
+            tab_id,
+            term_tx,
+            event_sink,
+        };
+        if let Err(e) = looper.mainloop(
+            || BufReader::new(DecodingReader::new(BufReader::new(child_stdout))),
+            &mut handler,
+        ) {
+            println!("proxy main loop failed {:?}", e);
+        }
+        println!("proxy main loop exit");
+        Ok(())
+    }
+
+    /// Tells the user when the proxy isn't of the version of the core, as
+    /// an old one uploaded to a remote host would be.
+    fn check_version(&self) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "proxy_version",
+                &json!({}),
+                Box::new(|result| {
+                    let version = result.ok().and_then(|res| {
+                        res["version"].as_str().map(|v| v.to_string())
+                    });
+                    if version.as_deref() == Some(lapce_proxy::VERSION) {
+                        return;
+                    }
+                    let message = format!(
+                        "The proxy is version {}, but Lapce is version {}.",
+                        version.as_deref().unwrap_or("unknown"),
+                        lapce_proxy::VERSION
+                    );
+                    // not on the thread the responses come in on
+                    thread::spawn(move || {
+                        tinyfiledialogs::message_box_ok(
+                            "Proxy Version Mismatch",
+                            &message,
+                            tinyfiledialogs::MessageBoxIcon::Error,
+                        );
+                    });
+                }),
+            );
+        }
     }
 
     fn wait(&self) {
@@ -136,23 +286,93 @@ impl LapceProxy {
         }
     }
 
-    pub fn initialize(&self, workspace: PathBuf) {
+    pub fn initialize(&self, workspace: PathBuf, transport: TransportOptions) {
         self.peer.lock().as_ref().unwrap().send_rpc_notification(
             "initialize",
             &json!({
                 "workspace": workspace,
+                "transport": transport,
             }),
         )
     }
 
-    pub fn terminal_resize(&self, term_id: TermId, width: usize, height: usize) {
+    pub fn trust_workspace(&self, trusted: bool) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "trust_workspace",
+                &json!({ "trusted": trusted }),
+                Box::new(|result| {
+                    if let Err(e) = result {
+                        eprintln!("trusting the workspace failed: {:?}", e);
+                    }
+                }),
+            );
+        }
+    }
+
+    pub fn get_setting(&self, key: &str, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("get_setting", &json!({ "key": key }), f);
+        }
+    }
+
+    /// Sets a key of the user's settings file, removing it for null.
+    pub fn update_setting(&self, key: &str, value: Value, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "update_setting",
+                &json!({ "key": key, "value": value }),
+                f,
+            );
+        }
+    }
+
+    pub fn proxy_stats(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("proxy_stats", &json!({}), f);
+        }
+    }
+
+    pub fn add_workspace_folder(&self, path: PathBuf) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification(
+                "add_workspace_folder",
+                &json!({
+                    "path": path,
+                }),
+            );
+        }
+    }
+
+    pub fn remove_workspace_folder(&self, path: PathBuf) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification(
+                "remove_workspace_folder",
+                &json!({
+                    "path": path,
+                }),
+            );
+        }
+    }
+
+    pub fn terminal_resize(&self, term_id: TermId, cols: usize, rows: usize) {
         self.wait();
         self.peer.lock().as_ref().unwrap().send_rpc_notification(
             "terminal_resize",
             &json!({
                 "term_id": term_id,
-                "width": width,
-                "height": height,
+                "cols": cols,
+                "rows": rows,
+            }),
+        )
+    }
+
+    pub fn terminal_close(&self, term_id: TermId) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "terminal_close",
+            &json!({
+                "term_id": term_id,
             }),
         )
     }
@@ -176,15 +396,249 @@ impl LapceProxy {
     ) {
         self.term_tx.send((term_id, TermEvent::NewTerminal(raw)));
         self.wait();
-        self.peer.lock().as_ref().unwrap().send_rpc_notification(
-            "new_terminal",
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "terminal_new",
             &json!({
                 "term_id": term_id,
                 "cwd": cwd,
+                "cols": 50,
+                "rows": 10,
+            }),
+            Box::new(|result| {
+                if let Err(e) = result {
+                    eprintln!("terminal failed to start: {:?}", e);
+                }
+            }),
+        )
+    }
+
+    /// Replays the output the proxy kept of a shell, the output that
+    /// follows being sent on as it comes.
+    pub fn terminal_attach(&self, term_id: TermId, raw: Arc<Mutex<RawTerminal>>) {
+        self.term_tx.send((term_id, TermEvent::NewTerminal(raw)));
+        let term_tx = self.term_tx.clone();
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "terminal_attach",
+            &json!({
+                "term_id": term_id,
+            }),
+            Box::new(move |result| match result {
+                Ok(res) => {
+                    if let Some(scrollback) = res["scrollback"].as_str() {
+                        term_tx.send((
+                            term_id,
+                            TermEvent::UpdateContent(scrollback.to_string()),
+                        ));
+                    }
+                }
+                Err(e) => eprintln!("terminal attach failed: {:?}", e),
+            }),
+        )
+    }
+
+    /// Has the output of a shell sent again after a reconnection, to the tab
+    /// that already shows what came before.
+    pub fn terminal_resume(&self, term_id: TermId) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "terminal_attach",
+                &json!({ "term_id": term_id }),
+                Box::new(move |result| {
+                    if let Err(e) = result {
+                        eprintln!("terminal resume failed: {:?}", e);
+                    }
+                }),
+            );
+        }
+    }
+
+    pub fn terminal_list(&self, f: Box<dyn Callback>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "terminal_list",
+            &json!({}),
+            f,
+        );
+    }
+
+    pub fn list_tasks(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("list_tasks", &json!({}), f);
+        }
+    }
+
+    pub fn run_task(&self, name: &str, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("run_task", &json!({ "name": name }), f);
+        }
+    }
+
+    pub fn cancel_task(&self, task_id: TaskId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "cancel_task",
+                &json!({ "task_id": task_id }),
+                f,
+            );
+        }
+    }
+
+    pub fn resolved_env(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("resolved_env", &json!({}), f);
+        }
+    }
+
+    pub fn get_plugin_list(&self, f: Box<dyn Callback>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "plugin_list",
+            &json!({}),
+            f,
+        );
+    }
+
+    pub fn install_plugin(&self, source: &str, f: Box<dyn Callback>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "install_plugin",
+            &json!({ "source": source }),
+            f,
+        );
+    }
+
+    pub fn uninstall_plugin(&self, name: &str, f: Box<dyn Callback>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "uninstall_plugin",
+            &json!({
+                "name": name,
+            }),
+            f,
+        );
+    }
+
+    pub fn disable_plugin(&self, name: &str) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "disable_plugin",
+            &json!({
+                "name": name,
+            }),
+        )
+    }
+
+    pub fn enable_plugin(&self, name: &str) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "enable_plugin",
+            &json!({
+                "name": name,
+            }),
+        )
+    }
+
+    pub fn reload_plugin(&self, name: &str) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "reload_plugin",
+            &json!({
+                "name": name,
+            }),
+        )
+    }
+
+    pub fn get_plugin_commands(&self, f: Box<dyn Callback>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "plugin_commands",
+            &json!({}),
+            f,
+        );
+    }
+
+    pub fn execute_plugin_command(&self, id: &str, args: Option<Value>) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "execute_plugin_command",
+            &json!({
+                "id": id,
+                "args": args,
+            }),
+        )
+    }
+
+    pub fn cancel_signature(&self, buffer_id: BufferId) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "cancel_signature",
+            &json!({
+                "buffer_id": buffer_id,
+            }),
+        )
+    }
+
+    /// Lets the proxy highlight the occurrences of the symbol at the
+    /// cursor once it rests there.
+    pub fn cursor_moved(&self, buffer_id: BufferId, position: Position) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification(
+                "cursor_moved",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "position": position,
+                }),
+            );
+        }
+    }
+
+    /// Tells the proxy its tab went to the background, for an auto-save
+    /// on focus change.
+    pub fn focus_lost(&self) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification("focus_lost", &json!({}));
+        }
+    }
+
+    pub fn restart_lsp_server(&self, buffer_id: BufferId) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_notification(
+            "restart_lsp_server",
+            &json!({
+                "buffer_id": buffer_id,
             }),
         )
     }
 
+    /// Opens a buffer in a restarted proxy with the text and rev the core
+    /// has, the edits not saved included.
+    fn resync_buffer(&self, buffer_id: BufferId, buffer: &SyncedBuffer) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "resync_buffer",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "path": buffer.path,
+                    "content": buffer.text.to_string(),
+                    "rev": buffer.rev,
+                }),
+                Box::new(move |result| {
+                    if let Err(e) = result {
+                        eprintln!("resync of {:?} failed: {:?}", buffer_id, e);
+                    }
+                }),
+            );
+        }
+    }
+
+    /// Makes the proxy panic, for the restart to be tested.
+    pub fn proxy_panic_for_test(&self) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification("proxy_panic_for_test", &json!({}));
+        }
+    }
+
     pub fn new_buffer(
         &self,
         buffer_id: BufferId,
@@ -199,7 +653,26 @@ impl LapceProxy {
         );
     }
 
+    /// Keeps what the proxy has of a buffer, as loaded, read again or moved,
+    /// for a restarted proxy to be given it.
+    pub fn sync_buffer(
+        &self,
+        buffer_id: BufferId,
+        path: PathBuf,
+        text: Rope,
+        rev: u64,
+    ) {
+        self.buffers
+            .lock()
+            .insert(buffer_id, SyncedBuffer { path, text, rev });
+    }
+
     pub fn update(&self, buffer_id: BufferId, delta: &RopeDelta, rev: u64) {
+        let mut buffers = self.buffers.lock();
+        if let Some(buffer) = buffers.get_mut(&buffer_id) {
+            buffer.text = delta.apply(&buffer.text);
+            buffer.rev = rev;
+        }
         self.peer.lock().as_ref().unwrap().send_rpc_notification(
             "update",
             &json!({
@@ -210,158 +683,1121 @@ impl LapceProxy {
         )
     }
 
-    pub fn save(&self, rev: u64, buffer_id: BufferId, f: Box<dyn Callback>) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "save",
-            &json!({
-                "rev": rev,
-                "buffer_id": buffer_id,
-            }),
-            f,
-        );
+    pub fn will_save(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "will_save",
+                &json!({
+                    "buffer_id": buffer_id,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn set_line_ending(
+        &self,
+        buffer_id: BufferId,
+        ending: LineEnding,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "set_line_ending",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "ending": ending,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// The backups left over by a session that ended with unsaved edits.
+    pub fn list_unsaved_backups(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("list_unsaved_backups", &json!({}), f);
+        }
+    }
+
+    /// The text of a backup, and the path it's a backup of.
+    pub fn restore_backup(&self, id: String, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "restore_backup",
+                &json!({
+                    "id": id,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn discard_backup(&self, id: String, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "discard_backup",
+                &json!({
+                    "id": id,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Makes the buffer read-only, or editable again. The response tells
+    /// whether it's editable, as a large file stays read-only.
+    pub fn set_buffer_readonly(
+        &self,
+        buffer_id: BufferId,
+        readonly: bool,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "set_buffer_readonly",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "readonly": readonly,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Saves the buffer, over a file another program changed only with
+    /// `overwrite`.
+    pub fn save(
+        &self,
+        rev: u64,
+        buffer_id: BufferId,
+        overwrite: bool,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "save",
+            &json!({
+                "rev": rev,
+                "buffer_id": buffer_id,
+                "overwrite": overwrite,
+            }),
+            f,
+        );
+    }
+
+    pub fn save_copy_as(
+        &self,
+        buffer_id: BufferId,
+        path: PathBuf,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "save_copy_as",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "path": path,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn reload_buffer(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "reload_buffer",
+                &json!({
+                    "buffer_id": buffer_id,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_completion(
+        &self,
+        request_id: usize,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_completion",
+            &json!({
+                "request_id": request_id,
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            f,
+        );
+    }
+
+    pub fn completion_resolve(
+        &self,
+        buffer_id: BufferId,
+        completion_item: CompletionItem,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "completion_resolve",
+            &json!({
+                "buffer_id": buffer_id,
+                "completion_item": completion_item,
+            }),
+            f,
+        );
+    }
+
+    pub fn get_signature(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_signature",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            f,
+        );
+    }
+
+    pub fn get_references(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        include_declaration: bool,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_references",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+                "include_declaration": include_declaration,
+            }),
+            f,
+        );
+    }
+
+    pub fn get_implementation(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_implementation",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            f,
+        );
+    }
+
+    pub fn goto_type_definition(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "goto_type_definition",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "position": position,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn goto_declaration(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "goto_declaration",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "position": position,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_selection_ranges(
+        &self,
+        buffer_id: BufferId,
+        positions: Vec<Position>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_selection_ranges",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "positions": positions,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn prepare_call_hierarchy(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "prepare_call_hierarchy",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "position": position,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Callers of the call hierarchy node `handle` stands for.
+    pub fn call_hierarchy_incoming(&self, handle: u64, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "call_hierarchy_incoming",
+                &json!({
+                    "handle": handle,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Functions called by the call hierarchy node `handle` stands for.
+    pub fn call_hierarchy_outgoing(&self, handle: u64, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "call_hierarchy_outgoing",
+                &json!({
+                    "handle": handle,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_status(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_status", &json!({}), f);
+        }
+    }
+
+    pub fn git_branch_info(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_branch_info", &json!({}), f);
+        }
+    }
+
+    /// The hunks of the buffer that differ from HEAD, as of its current rev.
+    pub fn git_diff_buffer(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_diff_buffer",
+                &json!({ "buffer_id": buffer_id }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_stage(&self, paths: &[PathBuf], f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_stage", &json!({ "paths": paths }), f);
+        }
+    }
+
+    pub fn git_unstage(&self, paths: &[PathBuf], f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_unstage",
+                &json!({ "paths": paths }),
+                f,
+            );
+        }
+    }
+
+    /// Without `confirmed` the response only tells what would be lost, for
+    /// the user to confirm.
+    pub fn git_discard(
+        &self,
+        paths: &[PathBuf],
+        confirmed: bool,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_discard",
+                &json!({
+                    "paths": paths,
+                    "confirmed": confirmed,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_stage_hunk(
+        &self,
+        path: &Path,
+        hunk_range: HunkRange,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_stage_hunk",
+                &json!({
+                    "path": path,
+                    "hunk_range": hunk_range,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_unstage_hunk(
+        &self,
+        path: &Path,
+        hunk_range: HunkRange,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_unstage_hunk",
+                &json!({
+                    "path": path,
+                    "hunk_range": hunk_range,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_commit(&self, message: &str, amend: bool, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_commit",
+                &json!({
+                    "message": message,
+                    "amend": amend,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_branches(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_branches", &json!({}), f);
+        }
+    }
+
+    /// Fails with a `CheckoutConflict` when it would overwrite changes,
+    /// unless `force`.
+    pub fn git_checkout(
+        &self,
+        target: &str,
+        create: bool,
+        force: bool,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_checkout",
+                &json!({
+                    "target": target,
+                    "create": create,
+                    "force": force,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_create_branch(
+        &self,
+        name: &str,
+        from: Option<&str>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_create_branch",
+                &json!({
+                    "name": name,
+                    "from": from,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_stash_save(
+        &self,
+        message: Option<&str>,
+        include_untracked: bool,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_stash_save",
+                &json!({
+                    "message": message,
+                    "include_untracked": include_untracked,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_stash_pop(&self, index: usize, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_stash_pop",
+                &json!({ "index": index }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_stash_list(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_stash_list", &json!({}), f);
+        }
+    }
+
+    pub fn git_blame(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("git_blame", &json!({ "path": path }), f);
+        }
+    }
+
+    pub fn git_blame_line(&self, path: &Path, line: usize, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_blame_line",
+                &json!({
+                    "path": path,
+                    "line": line,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_file_history(
+        &self,
+        path: &Path,
+        limit: usize,
+        skip: usize,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_file_history",
+                &json!({
+                    "path": path,
+                    "limit": limit,
+                    "skip": skip,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_show_file_at(
+        &self,
+        path: &Path,
+        commit_id: &str,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_show_file_at",
+                &json!({
+                    "path": path,
+                    "commit_id": commit_id,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Without `rev_b` the diff is to the file on disk.
+    pub fn git_diff_paths(
+        &self,
+        path: &Path,
+        rev_a: &str,
+        rev_b: Option<&str>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_diff_paths",
+                &json!({
+                    "path": path,
+                    "rev_a": rev_a,
+                    "rev_b": rev_b,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_files(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_files",
+                &json!({
+                    "path": "path",
+                }),
+                f,
+            );
+        }
+    }
+
+    /// The files of the workspace best matching `query`, from the proxy's
+    /// index of them.
+    pub fn fuzzy_file_search(
+        &self,
+        query: &str,
+        max_results: usize,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "fuzzy_file_search",
+                &json!({
+                    "query": query,
+                    "max_results": max_results,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn rebuild_file_index(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("rebuild_file_index", &json!({}), f);
+        }
+    }
+
+    pub fn read_dir(&self, path: &PathBuf, f: Box<dyn Callback>) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "read_dir",
+            &json!({
+                "path": path,
+            }),
+            f,
+        );
+    }
+
+    /// The listings of the directories down to `path`, for the explorer to
+    /// expand to it.
+    pub fn reveal_path(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("reveal_path", &json!({ "path": path }), f);
+        }
+    }
+
+    /// Sends the entries of `path` again whenever they change, until
+    /// `unwatch_dir`.
+    pub fn watch_dir(&self, path: &Path) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification("watch_dir", &json!({ "path": path }));
+        }
+    }
+
+    pub fn unwatch_dir(&self, path: &Path) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification("unwatch_dir", &json!({ "path": path }));
+        }
+    }
+
+    pub fn create_file(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("create_file", &json!({ "path": path }), f);
+        }
+    }
+
+    pub fn create_directory(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "create_directory",
+                &json!({ "path": path }),
+                f,
+            );
+        }
+    }
+
+    pub fn rename_path(&self, old: &Path, new: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "rename_path",
+                &json!({
+                    "old": old,
+                    "new": new,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn trash_path(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("trash_path", &json!({ "path": path }), f);
+        }
+    }
+
+    pub fn duplicate_path(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "duplicate_path",
+                &json!({ "path": path }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_definition(
+        &self,
+        request_id: usize,
+        buffer_id: BufferId,
+        position: Position,
+        f: Box<dyn Callback>,
+    ) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_definition",
+            &json!({
+                "request_id": request_id,
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            f,
+        );
+    }
+
+    pub fn get_document_symbols(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_document_symbols",
+            &json!({
+                "buffer_id": buffer_id,
+            }),
+            f,
+        );
+    }
+
+    pub fn enclosing_scopes(
+        &self,
+        buffer_id: BufferId,
+        line: u32,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "enclosing_scopes",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "line": line,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_workspace_symbols(
+        &self,
+        query: &str,
+        limit: usize,
+        f: Box<dyn Callback>,
+    ) {
+        self.wait();
+        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
+            "get_workspace_symbols",
+            &json!({
+                "query": query,
+                "limit": limit,
+            }),
+            f,
+        );
+    }
+
+    pub fn get_code_actions(
+        &self,
+        buffer_id: BufferId,
+        range: Range,
+        diagnostics: Vec<Diagnostic>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_code_actions",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "range": range,
+                    "diagnostics": diagnostics,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_inlay_hints(
+        &self,
+        buffer_id: BufferId,
+        range: Range,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_inlay_hints",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "range": range,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_buffer_lines(
+        &self,
+        buffer_id: BufferId,
+        start_line: usize,
+        end_line: usize,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_buffer_lines",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "start_line": start_line,
+                    "end_line": end_line,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Reads `len` bytes of a binary file from `offset`, for its hex view.
+    pub fn open_binary(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: usize,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "open_binary",
+                &json!({
+                    "path": path,
+                    "offset": offset,
+                    "len": len,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn toggle_comment(
+        &self,
+        buffer_id: BufferId,
+        selections: Vec<(usize, usize)>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "toggle_comment",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "selections": selections,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn get_conflicts(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_conflicts",
+                &json!({ "buffer_id": buffer_id }),
+                f,
+            );
+        }
+    }
+
+    pub fn resolve_conflict(
+        &self,
+        buffer_id: BufferId,
+        conflict_index: usize,
+        choice: ConflictChoice,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "resolve_conflict",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "conflict_index": conflict_index,
+                    "choice": choice,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn git_mark_resolved(&self, path: &Path, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "git_mark_resolved",
+                &json!({ "path": path }),
+                f,
+            );
+        }
+    }
+
+    /// Starts search `search_id` of the workspace, answered at once, with
+    /// the matches following in `global_search_results` notifications.
+    pub fn global_search(
+        &self,
+        search_id: u64,
+        query: &str,
+        options: SearchOptions,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "global_search",
+                &json!({
+                    "search_id": search_id,
+                    "query": query,
+                    "options": options,
+                }),
+                f,
+            );
+        }
+    }
+
+    /// Finds the replacements of the matches of a search, answered with a
+    /// preview of them, without making them.
+    pub fn global_replace(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        replacement: &str,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "global_replace",
+                &json!({
+                    "query": query,
+                    "options": options,
+                    "replacement": replacement,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn apply_replace(
+        &self,
+        session_id: u64,
+        ids: Vec<usize>,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "apply_replace",
+                &json!({
+                    "session_id": session_id,
+                    "ids": ids,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn cancel_search(&self, search_id: u64) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_notification(
+                "cancel_search",
+                &json!({
+                    "search_id": search_id,
+                }),
+            );
+        }
+    }
+
+    pub fn get_folding_ranges(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_folding_ranges",
+                &json!({
+                    "buffer_id": buffer_id,
+                }),
+                f,
+            );
+        }
     }
 
-    pub fn get_completion(
+    pub fn get_all_diagnostics(&self, f: Box<dyn Callback>) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async("get_all_diagnostics", &json!({}), f);
+        }
+    }
+
+    pub fn get_document_links(
         &self,
-        request_id: usize,
         buffer_id: BufferId,
-        position: Position,
+        range: Range,
         f: Box<dyn Callback>,
     ) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_completion",
-            &json!({
-                "request_id": request_id,
-                "buffer_id": buffer_id,
-                "position": position,
-            }),
-            f,
-        );
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "get_document_links",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "range": range,
+                }),
+                f,
+            );
+        }
     }
 
-    pub fn completion_resolve(
+    pub fn resolve_document_link(
         &self,
         buffer_id: BufferId,
-        completion_item: CompletionItem,
+        link: DocumentLinkItem,
         f: Box<dyn Callback>,
     ) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "completion_resolve",
-            &json!({
-                "buffer_id": buffer_id,
-                "completion_item": completion_item,
-            }),
-            f,
-        );
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "resolve_document_link",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "link": link,
+                }),
+                f,
+            );
+        }
     }
 
-    pub fn get_signature(
+    pub fn prepare_rename(
         &self,
         buffer_id: BufferId,
         position: Position,
         f: Box<dyn Callback>,
     ) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_signature",
-            &json!({
-                "buffer_id": buffer_id,
-                "position": position,
-            }),
-            f,
-        );
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "prepare_rename",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "position": position,
+                }),
+                f,
+            );
+        }
     }
 
-    pub fn get_references(
+    pub fn rename(
         &self,
         buffer_id: BufferId,
         position: Position,
+        new_name: &str,
         f: Box<dyn Callback>,
     ) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_references",
-            &json!({
-                "buffer_id": buffer_id,
-                "position": position,
-            }),
-            f,
-        );
-    }
-
-    pub fn get_files(&self, f: Box<dyn Callback>) {
         if let Some(peer) = self.peer.lock().as_ref() {
             peer.send_rpc_request_async(
-                "get_files",
+                "rename",
                 &json!({
-                    "path": "path",
+                    "buffer_id": buffer_id,
+                    "position": position,
+                    "new_name": new_name,
                 }),
                 f,
             );
         }
     }
 
-    pub fn read_dir(&self, path: &PathBuf, f: Box<dyn Callback>) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "read_dir",
-            &json!({
-                "path": path,
-            }),
-            f,
-        );
-    }
-
-    pub fn get_definition(
+    pub fn resolve_code_action(
         &self,
-        request_id: usize,
         buffer_id: BufferId,
-        position: Position,
+        action: &CodeAction,
         f: Box<dyn Callback>,
     ) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_definition",
-            &json!({
-                "request_id": request_id,
-                "buffer_id": buffer_id,
-                "position": position,
-            }),
-            f,
-        );
-    }
-
-    pub fn get_document_symbols(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
-        self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_document_symbols",
-            &json!({
-                "buffer_id": buffer_id,
-            }),
-            f,
-        );
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "resolve_code_action",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "action": action,
+                }),
+                f,
+            );
+        }
     }
 
-    pub fn get_code_actions(
+    pub fn execute_command(
         &self,
         buffer_id: BufferId,
-        position: Position,
+        command: &lsp_types::Command,
         f: Box<dyn Callback>,
     ) {
         if let Some(peer) = self.peer.lock().as_ref() {
             peer.send_rpc_request_async(
-                "get_code_actions",
+                "execute_command",
                 &json!({
                     "buffer_id": buffer_id,
-                    "position": position,
+                    "command": command,
                 }),
                 f,
             );
         }
     }
 
-    pub fn get_document_formatting(
+    pub fn apply_file_edits(
         &self,
-        buffer_id: BufferId,
+        edits: HashMap<PathBuf, Vec<TextEdit>>,
         f: Box<dyn Callback>,
     ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "apply_file_edits",
+                &json!({
+                    "edits": edits,
+                }),
+                f,
+            );
+        }
+    }
+
+    pub fn format_document(&self, buffer_id: BufferId, f: Box<dyn Callback>) {
         self.peer.lock().as_ref().unwrap().send_rpc_request_async(
-            "get_document_formatting",
+            "format_document",
             &json!({
                 "buffer_id": buffer_id,
             }),
@@ -369,7 +1805,27 @@ impl LapceProxy {
         );
     }
 
+    pub fn format_range(
+        &self,
+        buffer_id: BufferId,
+        range: Range,
+        f: Box<dyn Callback>,
+    ) {
+        if let Some(peer) = self.peer.lock().as_ref() {
+            peer.send_rpc_request_async(
+                "format_range",
+                &json!({
+                    "buffer_id": buffer_id,
+                    "range": range,
+                }),
+                f,
+            );
+        }
+    }
+
     pub fn stop(&self) {
+        // for the connection going not to be taken for a dropped one
+        self.generation.fetch_add(1, Ordering::SeqCst);
         let mut process = self.process.lock();
         if let Some(mut p) = process.as_mut() {
             p.kill();
@@ -403,7 +1859,7 @@ pub enum Notification {
         rev: u64,
         buffer_id: BufferId,
         path: PathBuf,
-        tokens: Vec<(usize, usize, String)>,
+        lines: Vec<LineStyles>,
     },
     UpdateGit {
         buffer_id: BufferId,
@@ -415,26 +1871,117 @@ pub enum Notification {
         new_content: String,
         rev: u64,
     },
+    BufferExternalChange {
+        buffer_id: BufferId,
+        state: Option<ExternalChange>,
+    },
+    /// A save the proxy made by itself, like an auto-save.
+    BufferSaved {
+        buffer_id: BufferId,
+        rev: u64,
+        auto: bool,
+    },
     PublishDiagnostics {
         diagnostics: PublishDiagnosticsParams,
     },
+    DiagnosticsChanged {
+        path: PathBuf,
+        count_by_severity: DiagnosticCounts,
+    },
     ListDir {
         items: Vec<FileNodeItem>,
     },
+    /// The entries of a directory the explorer changed.
+    RefreshDir {
+        path: PathBuf,
+        items: Vec<FileNodeItem>,
+    },
     DiffFiles {
         files: Vec<PathBuf>,
     },
-    UpdateTerminal {
+    /// The user and workspace settings, whenever either file changes.
+    ConfigChanged(ConfigChange),
+    /// Sent again whenever the repository changes.
+    GitStatus {
+        status: GitStatus,
+        branch: BranchInfo,
+    },
+    /// Output of the shell, in base64.
+    TerminalOutput {
         term_id: TermId,
-        content: String,
+        data: String,
     },
-    CloseTerminal {
+    /// None when how the shell exited couldn't be had.
+    TerminalExited {
         term_id: TermId,
+        success: Option<bool>,
+    },
+    PluginStarted {
+        name: String,
+    },
+    PluginError {
+        name: String,
+        error: String,
+    },
+    PluginLog {
+        name: String,
+        level: String,
+        message: String,
+    },
+    TaskOutput {
+        task_id: TaskId,
+        stream: String,
+        text: String,
+    },
+    TaskFinished {
+        task_id: TaskId,
+        name: String,
+        code: Option<i32>,
+    },
+    PluginManifestError {
+        path: PathBuf,
+        field: Option<String>,
+        error: String,
+    },
+    LspServerStatus {
+        language_id: String,
+        status: String,
+        message: String,
+    },
+    WorkProgress {
+        progress: WorkProgress,
+    },
+    ServerCapabilities {
+        language_id: String,
+        caps: LspCapabilities,
+    },
+    SignatureHelp {
+        signature: SignatureHelpResponse,
+    },
+    InlayHintsRefresh {},
+    DocumentHighlight {
+        highlight: DocumentHighlightResponse,
+    },
+    OnTypeFormatting {
+        path: PathBuf,
+        rev: u64,
+        edits: Vec<TextEdit>,
+    },
+    GlobalSearchResults {
+        search_id: u64,
+        matches: Vec<SearchMatch>,
+        done: bool,
     },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Request {}
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "method", content = "params")]
+pub enum Request {
+    /// A `workspace/applyEdit` of a server, answered once the edit has been
+    /// applied or refused.
+    ApplyWorkspaceEdit { edit: WorkspaceEdit },
+}
 
 pub struct ProxyHandlerNew {
     tab_id: WidgetId,
@@ -456,12 +2003,12 @@ impl Handler for ProxyHandlerNew {
                 rev,
                 buffer_id,
                 path,
-                tokens,
+                lines,
             } => {
                 self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
                     LapceUICommand::UpdateSemanticTokens(
-                        buffer_id, path, rev, tokens,
+                        buffer_id, path, rev, lines,
                     ),
                     Target::Widget(self.tab_id),
                 );
@@ -492,6 +2039,20 @@ impl Handler for ProxyHandlerNew {
                     Target::Widget(self.tab_id),
                 );
             }
+            Notification::BufferSaved { buffer_id, rev, .. } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::BufferSaved(buffer_id, rev),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::BufferExternalChange { buffer_id, state } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::BufferExternalChange(buffer_id, state),
+                    Target::Widget(self.tab_id),
+                );
+            }
             Notification::PublishDiagnostics { diagnostics } => {
                 self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -499,7 +2060,25 @@ impl Handler for ProxyHandlerNew {
                     Target::Widget(self.tab_id),
                 );
             }
+            Notification::DiagnosticsChanged {
+                path,
+                count_by_severity,
+            } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateDiagnosticsCounts(path, count_by_severity),
+                    Target::Widget(self.tab_id),
+                );
+            }
             Notification::ListDir { items } => {}
+            Notification::RefreshDir { path, items } => {}
+            Notification::ConfigChanged(change) => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::ConfigChanged(change),
+                    Target::Widget(self.tab_id),
+                );
+            }
             Notification::DiffFiles { files } => {
                 self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -507,11 +2086,17 @@ impl Handler for ProxyHandlerNew {
                     Target::Widget(self.tab_id),
                 );
             }
-            Notification::UpdateTerminal { term_id, content } => {
-                self.term_tx
-                    .send((term_id, TermEvent::UpdateContent(content)));
+            Notification::GitStatus { status, branch } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateGitStatus(status, branch),
+                    Target::Widget(self.tab_id),
+                );
             }
-            Notification::CloseTerminal { term_id } => {
+            Notification::TerminalOutput { term_id, data } => {
+                self.term_tx.send((term_id, TermEvent::UpdateContent(data)));
+            }
+            Notification::TerminalExited { term_id, .. } => {
                 self.term_tx.send((term_id, TermEvent::CloseTerminal));
                 self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -519,6 +2104,82 @@ impl Handler for ProxyHandlerNew {
                     Target::Widget(self.tab_id),
                 );
             }
+            Notification::PluginStarted { name } => {
+                eprintln!("plugin {} started", name);
+            }
+            Notification::PluginError { name, error } => {
+                eprintln!("plugin {} error: {}", name, error);
+            }
+            Notification::PluginLog { .. } => {}
+            Notification::TaskOutput { text, .. } => {
+                eprint!("{}", text);
+            }
+            Notification::TaskFinished { name, code, .. } => {
+                eprintln!("task {} finished with {:?}", name, code);
+            }
+            Notification::PluginManifestError { error, .. } => {
+                eprintln!("plugin manifest error: {}", error);
+            }
+            Notification::LspServerStatus { message, .. } => {
+                eprintln!("{}", message);
+            }
+            Notification::WorkProgress { progress } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateWorkProgress(progress),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::ServerCapabilities { language_id, caps } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateServerCapabilities(language_id, caps),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::OnTypeFormatting { path, rev, edits } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::DocumentFormat(
+                        path,
+                        rev,
+                        Ok(serde_json::to_value(edits).unwrap()),
+                    ),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::SignatureHelp { signature } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateSignature(signature),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::InlayHintsRefresh {} => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RefreshInlayHints,
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::DocumentHighlight { highlight } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateDocumentHighlights(highlight),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            Notification::GlobalSearchResults {
+                search_id,
+                matches,
+                done,
+            } => {
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::GlobalSearchResults(search_id, matches, done),
+                    Target::Widget(self.tab_id),
+                );
+            }
         }
     }
 
@@ -527,6 +2188,26 @@ impl Handler for ProxyHandlerNew {
         ctx: &xi_rpc::RpcCtx,
         rpc: Self::Request,
     ) -> Result<serde_json::Value, xi_rpc::RemoteError> {
-        Err(xi_rpc::RemoteError::InvalidRequest(None))
+        match rpc {
+            Request::ApplyWorkspaceEdit { edit } => {
+                // the responses to the requests the edit makes of the proxy
+                // come in on another thread, so this one can wait for it
+                let (sender, receiver) = crossbeam_channel::bounded(1);
+                self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::ApplyWorkspaceEdit(edit, EditReply::new(sender)),
+                    Target::Widget(self.tab_id),
+                );
+                let result = receiver.recv().unwrap_or_else(|_| {
+                    Err("the edit was dropped before it was applied".to_string())
+                });
+                let failure_reason = result.err();
+                Ok(json!(ApplyWorkspaceEditResponse {
+                    applied: failure_reason.is_none(),
+                    failure_reason,
+                    failed_change: None,
+                }))
+            }
+        }
     }
 }
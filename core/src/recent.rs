@@ -0,0 +1,187 @@
+//! What was open before: the workspaces, latest opened first, and the
+//! editors closed in a workspace, latest on top, to be opened again. Paths
+//! that are gone are pruned when they're listed, the check being made off
+//! the UI thread since a path on a disconnected drive can take long to
+//! answer.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use lsp_types::Position;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{LapceWorkspace, LapceWorkspaceType};
+
+pub const MAX_RECENT_WORKSPACES: usize = 50;
+pub const MAX_CLOSED_EDITORS: usize = 30;
+
+/// An editor closed, and where its cursor was.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClosedEditor {
+    pub path: PathBuf,
+    pub position: Option<Position>,
+}
+
+/// Puts the workspace first, opened at `now`.
+pub fn touch_workspace(
+    workspaces: &mut Vec<LapceWorkspace>,
+    workspace: &LapceWorkspace,
+    now: u64,
+) {
+    workspaces.retain(|w| w.path != workspace.path || w.kind != workspace.kind);
+    workspaces.insert(
+        0,
+        LapceWorkspace {
+            last_open: now,
+            ..workspace.clone()
+        },
+    );
+    workspaces.sort_by_key(|w| std::cmp::Reverse(w.last_open));
+    workspaces.truncate(MAX_RECENT_WORKSPACES);
+}
+
+/// The local paths of the workspaces, those that can be checked for.
+pub fn local_paths(workspaces: &[LapceWorkspace]) -> Vec<PathBuf> {
+    workspaces
+        .iter()
+        .filter(|w| w.kind == LapceWorkspaceType::Local)
+        .map(|w| w.path.clone())
+        .collect()
+}
+
+/// Drops the local workspaces at the paths.
+pub fn prune_workspaces(workspaces: &mut Vec<LapceWorkspace>, missing: &[PathBuf]) {
+    workspaces.retain(|w| {
+        w.kind != LapceWorkspaceType::Local || !missing.contains(&w.path)
+    });
+}
+
+/// Puts the editor on top of the stack, dropping an earlier close of the
+/// same file and the oldest past the limit.
+pub fn push_closed(closed: &mut Vec<ClosedEditor>, editor: ClosedEditor) {
+    closed.retain(|e| e.path != editor.path);
+    closed.push(editor);
+    if closed.len() > MAX_CLOSED_EDITORS {
+        closed.drain(..closed.len() - MAX_CLOSED_EDITORS);
+    }
+}
+
+/// Takes the last closed editor off the stack, skipping those open again,
+/// so each call goes back one further.
+pub fn pop_closed(
+    closed: &mut Vec<ClosedEditor>,
+    is_open: impl Fn(&Path) -> bool,
+) -> Option<ClosedEditor> {
+    while let Some(editor) = closed.pop() {
+        if !is_open(&editor.path) {
+            return Some(editor);
+        }
+    }
+    None
+}
+
+/// Checks on a thread which of the paths no longer exist, `f` being called
+/// there with them if there are any. A path that can't be looked at for
+/// another reason, like a permission or a drive that's away, is kept.
+pub fn check_missing(
+    paths: Vec<PathBuf>,
+    f: impl FnOnce(Vec<PathBuf>) + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        let missing: Vec<PathBuf> =
+            paths.into_iter().filter(|path| is_missing(path)).collect();
+        if !missing.is_empty() {
+            f(missing);
+        }
+    });
+}
+
+fn is_missing(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Err(e) => e.kind() == ErrorKind::NotFound,
+        Ok(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workspace(path: &str, last_open: u64) -> LapceWorkspace {
+        LapceWorkspace {
+            kind: LapceWorkspaceType::Local,
+            path: PathBuf::from(path),
+            last_open,
+        }
+    }
+
+    fn closed(path: &str) -> ClosedEditor {
+        ClosedEditor {
+            path: PathBuf::from(path),
+            position: None,
+        }
+    }
+
+    #[test]
+    fn test_touch_workspace() {
+        let mut workspaces = vec![workspace("/a", 2), workspace("/b", 1)];
+        touch_workspace(&mut workspaces, &workspace("/b", 0), 3);
+        assert_eq!(vec![workspace("/b", 3), workspace("/a", 2)], workspaces);
+
+        // the same path on a remote host is another workspace
+        let remote = LapceWorkspace {
+            kind: LapceWorkspaceType::RemoteSSH(
+                "me".to_string(),
+                "host".to_string(),
+            ),
+            ..workspace("/a", 0)
+        };
+        touch_workspace(&mut workspaces, &remote, 4);
+        assert_eq!(3, workspaces.len());
+        assert_eq!(remote.kind, workspaces[0].kind);
+
+        prune_workspaces(&mut workspaces, &[PathBuf::from("/a")]);
+        assert_eq!(2, workspaces.len());
+        assert_eq!(remote.kind, workspaces[0].kind);
+        assert_eq!(vec![PathBuf::from("/b")], local_paths(&workspaces));
+    }
+
+    #[test]
+    fn test_closed_stack() {
+        let mut stack = Vec::new();
+        push_closed(&mut stack, closed("/a"));
+        push_closed(&mut stack, closed("/b"));
+        push_closed(&mut stack, closed("/c"));
+        push_closed(&mut stack, closed("/a"));
+        assert_eq!(vec![closed("/b"), closed("/c"), closed("/a")], stack);
+
+        // "/c" was opened again in the meantime
+        let is_open = |path: &Path| path == Path::new("/c");
+        assert_eq!(Some(closed("/a")), pop_closed(&mut stack, is_open));
+        assert_eq!(Some(closed("/b")), pop_closed(&mut stack, is_open));
+        assert_eq!(None, pop_closed(&mut stack, is_open));
+
+        for i in 0..MAX_CLOSED_EDITORS + 5 {
+            push_closed(&mut stack, closed(&i.to_string()));
+        }
+        assert_eq!(MAX_CLOSED_EDITORS, stack.len());
+        assert_eq!(closed("5"), stack[0]);
+    }
+
+    #[test]
+    fn test_is_missing() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-recent-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(!is_missing(&dir));
+        assert!(is_missing(&dir.join("gone")));
+        #[cfg(unix)]
+        {
+            // under a file, which isn't a directory, rather than not found
+            let file = dir.join("file");
+            std::fs::write(&file, "").unwrap();
+            assert!(!is_missing(&file.join("child")));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
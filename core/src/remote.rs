@@ -0,0 +1,179 @@
+//! Running the proxy of a workspace on another machine, through the `ssh`
+//! of the system so the user's `~/.ssh/config`, keys, agent and jump hosts
+//! all apply. The proxy is kept in `~/.lapce` of the remote home under a
+//! name with its version, uploaded when it isn't there yet.
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+
+/// The directory of the remote home the proxies are uploaded to.
+const REMOTE_DIR: &str = ".lapce";
+
+/// Who to log in as and where, the user left to the ssh config when empty.
+pub fn destination(user: &str, host: &str) -> String {
+    if user.is_empty() {
+        host.to_string()
+    } else {
+        format!("{}@{}", user, host)
+    }
+}
+
+/// An `ssh` to the host, sharing one connection between the commands run.
+/// A user or host starting with `-` is refused, for it not to be taken as
+/// an option of `ssh`.
+pub fn ssh_command(user: &str, host: &str) -> Result<Command> {
+    check_destination(user, host)?;
+    let mut command = Command::new("ssh");
+    command
+        .arg("-o")
+        .arg("ControlMaster=auto")
+        .arg("-o")
+        .arg("ControlPath=~/.ssh/cm-%r@%h:%p")
+        .arg("-o")
+        .arg("ControlPersist=30m")
+        .arg("--")
+        .arg(destination(user, host));
+    Ok(command)
+}
+
+fn check_destination(user: &str, host: &str) -> Result<()> {
+    if user.starts_with('-') {
+        return Err(anyhow!("invalid ssh user {}", user));
+    }
+    if host.is_empty() || host.starts_with('-') {
+        return Err(anyhow!("invalid ssh host {}", host));
+    }
+    Ok(())
+}
+
+fn run(mut command: Command) -> Result<String> {
+    let output = command.stdin(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The name of the proxy built for a platform, in the terms of `uname`.
+fn proxy_name(os: &str, arch: &str) -> String {
+    format!(
+        "lapce-proxy-{}-{}-{}",
+        lapce_proxy::VERSION,
+        os.to_lowercase(),
+        arch.to_lowercase()
+    )
+}
+
+/// The `uname -s` and `uname -m` of the machine the core runs on.
+fn local_platform() -> (&'static str, &'static str) {
+    let os = match std::env::consts::OS {
+        "macos" => "Darwin",
+        "linux" => "Linux",
+        "freebsd" => "FreeBSD",
+        os => os,
+    };
+    let arch = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("macos", "aarch64") => "arm64",
+        (_, arch) => arch,
+    };
+    (os, arch)
+}
+
+/// The proxy to upload for the platform, the one next to the core when it
+/// runs on the same, or one built for it put there alongside.
+fn local_proxy(os: &str, arch: &str) -> Result<PathBuf> {
+    let dir = std::env::current_exe()?
+        .parent()
+        .ok_or_else(|| anyhow!("the core has no directory"))?
+        .to_path_buf();
+    let (local_os, local_arch) = local_platform();
+    let path = if os == local_os && arch == local_arch {
+        dir.join("lapce-proxy")
+    } else {
+        dir.join(proxy_name(os, arch))
+    };
+    if !path.exists() {
+        return Err(anyhow!(
+            "there's no proxy for {} {}, {} is missing",
+            os,
+            arch,
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// The path on the host of a proxy of this version, uploaded if it isn't
+/// there yet, relative to the remote home.
+pub fn deploy_proxy(user: &str, host: &str) -> Result<String> {
+    let mut uname = ssh_command(user, host)?;
+    uname.arg("uname -s -m");
+    let platform =
+        run(uname).map_err(|e| anyhow!("can't connect to {}: {}", host, e))?;
+    let (os, arch) = platform
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("unknown platform {} of {}", platform, host))?;
+    let remote_path = format!("{}/{}", REMOTE_DIR, proxy_name(os, arch));
+
+    let mut exists = ssh_command(user, host)?;
+    exists.arg(format!("test -x {}", remote_path));
+    if run(exists).is_ok() {
+        return Ok(remote_path);
+    }
+
+    let local_path = local_proxy(os, arch)?;
+    let mut mkdir = ssh_command(user, host)?;
+    mkdir.arg(format!("mkdir -p {}", REMOTE_DIR));
+    run(mkdir)?;
+    // uploaded aside first, for a dropped upload not to leave half a proxy
+    let upload_path = format!("{}.upload", remote_path);
+    let mut scp = Command::new("scp");
+    scp.arg("-o")
+        .arg("ControlPath=~/.ssh/cm-%r@%h:%p")
+        .arg("--")
+        .arg(&local_path)
+        .arg(format!("{}:{}", destination(user, host), upload_path));
+    run(scp).map_err(|e| anyhow!("can't upload the proxy: {}", e))?;
+    let mut install = ssh_command(user, host)?;
+    install.arg(format!(
+        "chmod +x {0} && mv {0} {1}",
+        upload_path, remote_path
+    ));
+    run(install)?;
+    Ok(remote_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_name() {
+        assert_eq!(
+            format!("lapce-proxy-{}-linux-x86_64", lapce_proxy::VERSION),
+            proxy_name("Linux", "x86_64")
+        );
+        assert_eq!("host", destination("", "host"));
+        assert_eq!("me@host", destination("me", "host"));
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let args = |command: &Command| {
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let command = ssh_command("me", "host").unwrap();
+        assert_eq!(["--", "me@host"], args(&command)[6..]);
+        assert!(ssh_command("", "-oProxyCommand=evil").is_err());
+        assert!(ssh_command("-oProxyCommand=evil", "host").is_err());
+        assert!(ssh_command("me", "").is_err());
+    }
+}
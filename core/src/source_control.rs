@@ -1,5 +1,7 @@
 use std::{path::PathBuf, sync::Arc};
 
+use lapce_proxy::git::{BranchInfo, GitStatus};
+
 use druid::{
     kurbo::BezPath,
     piet::{
@@ -41,6 +43,9 @@ pub struct SourceControlData {
     pub file_list_index: usize,
     pub editor_view_id: WidgetId,
     pub diff_files: Vec<(PathBuf, bool)>,
+    /// The files changed, staged and not, as the proxy last sent them.
+    pub status: GitStatus,
+    pub branch: BranchInfo,
 }
 
 impl SourceControlData {
@@ -54,6 +59,8 @@ impl SourceControlData {
             file_list_index: 0,
             split_id: WidgetId::next(),
             diff_files: Vec::new(),
+            status: GitStatus::default(),
+            branch: BranchInfo::default(),
         }
     }
 }
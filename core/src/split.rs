@@ -4,9 +4,10 @@ use crate::{
     data::{EditorContent, EditorType, LapceEditorData, LapceTabData, PanelData},
     editor::{EditorLocation, LapceEditorView},
     scroll::{LapcePadding, LapceScroll},
+    split_tree::{SplitBar, SplitTree},
     terminal::{LapceTerminal, LapceTerminalData, LapceTerminalView},
 };
-use std::{cmp::Ordering, sync::Arc};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
 use druid::{
     kurbo::{Line, Rect},
@@ -151,57 +152,9 @@ impl LapceSplitNew {
         }
     }
 
-    pub fn split_editor_close(
-        &mut self,
-        ctx: &mut EventCtx,
-        data: &mut LapceTabData,
-        widget_id: WidgetId,
-    ) {
-        if self.children.len() == 0 {
-            return;
-        }
-
-        if self.children.len() == 1 {
-            let view_id = self.children[0].widget.id();
-            let editor = data.main_split.editors.get_mut(&view_id).unwrap();
-            Arc::make_mut(editor).content = EditorContent::None;
-            return;
-        }
-
-        let mut index = 0;
-        for (i, child_id) in self.children_ids.iter().enumerate() {
-            if child_id == &widget_id {
-                index = i;
-                break;
-            }
-        }
-
-        let new_index = if index >= self.children.len() - 1 {
-            index - 1
-        } else {
-            index + 1
-        };
-        let view_id = self.children[index].widget.id();
-        let new_view_id = self.children[new_index].widget.id();
-        let new_editor = data.main_split.editors.get(&new_view_id).unwrap();
-        if *data.main_split.active == view_id {
-            data.main_split.active = Arc::new(new_editor.view_id);
-            data.focus = new_editor.view_id;
-            ctx.set_focus(new_editor.view_id);
-        }
-        data.main_split.editors.remove(&view_id);
-        self.children.remove(index);
-        self.children_ids.remove(index);
-        data.main_split.editors_order = Arc::new(self.children_ids.clone());
-
-        self.even_flex_children();
-        ctx.children_changed();
-    }
-
     pub fn split_editor_exchange(
         &mut self,
         ctx: &mut EventCtx,
-        data: &mut LapceTabData,
         widget_id: WidgetId,
     ) {
         if self.children.len() <= 1 {
@@ -228,7 +181,6 @@ impl LapceSplitNew {
 
         self.children.swap(index, index + 1);
         self.children_ids.swap(index, index + 1);
-        data.main_split.editors_order = Arc::new(self.children_ids.clone());
 
         ctx.request_layout();
     }
@@ -345,6 +297,8 @@ impl LapceSplitNew {
         if self.children.len() == 0 {
             return;
         }
+        // gone already when the shell exited
+        data.proxy.terminal_close(term_id);
 
         if self.children.len() == 1 {
             Arc::make_mut(&mut data.terminal).terminals.remove(&term_id);
@@ -396,56 +350,6 @@ impl LapceSplitNew {
         self.even_flex_children();
         ctx.children_changed();
     }
-
-    pub fn split_editor(
-        &mut self,
-        ctx: &mut EventCtx,
-        data: &mut LapceTabData,
-        vertical: bool,
-        widget_id: WidgetId,
-    ) {
-        let mut index = 0;
-        for (i, child_id) in self.children_ids.iter().enumerate() {
-            if child_id == &widget_id {
-                index = i;
-                break;
-            }
-        }
-
-        let view_id = self.children[index].widget.id();
-        let from_editor = data.main_split.editors.get(&view_id).unwrap();
-        let mut editor_data = LapceEditorData::new(
-            None,
-            Some(self.split_id),
-            from_editor.content.clone(),
-            EditorType::Normal,
-            &data.config,
-        );
-        editor_data.cursor = from_editor.cursor.clone();
-        editor_data.locations = from_editor.locations.clone();
-        ctx.submit_command(Command::new(
-            LAPCE_UI_COMMAND,
-            LapceUICommand::ForceScrollTo(
-                from_editor.scroll_offset.x,
-                from_editor.scroll_offset.y,
-            ),
-            Target::Widget(editor_data.view_id),
-        ));
-
-        let editor = LapceEditorView::new(&editor_data);
-        self.insert_flex_child(
-            index + 1,
-            editor.boxed(),
-            Some(editor_data.view_id),
-            1.0,
-        );
-        self.even_flex_children();
-        ctx.children_changed();
-        data.main_split
-            .editors
-            .insert(editor_data.view_id, Arc::new(editor_data));
-        data.main_split.editors_order = Arc::new(self.children_ids.clone());
-    }
 }
 
 impl Widget<LapceTabData> for LapceSplitNew {
@@ -467,17 +371,11 @@ impl Widget<LapceTabData> for LapceSplitNew {
             Event::Command(cmd) if cmd.is(LAPCE_UI_COMMAND) => {
                 let command = cmd.get_unchecked(LAPCE_UI_COMMAND);
                 match command {
-                    LapceUICommand::SplitEditor(vertical, widget_id) => {
-                        self.split_editor(ctx, data, *vertical, *widget_id);
-                    }
                     LapceUICommand::SplitEditorMove(direction, widget_id) => {
                         self.split_editor_move(ctx, data, direction, *widget_id);
                     }
                     LapceUICommand::SplitEditorExchange(widget_id) => {
-                        self.split_editor_exchange(ctx, data, *widget_id);
-                    }
-                    LapceUICommand::SplitEditorClose(widget_id) => {
-                        self.split_editor_close(ctx, data, *widget_id);
+                        self.split_editor_exchange(ctx, *widget_id);
                     }
                     LapceUICommand::SplitTerminal(
                         vertical,
@@ -537,6 +435,36 @@ impl Widget<LapceTabData> for LapceSplitNew {
                             ctx.children_changed();
                         }
                     }
+                    LapceUICommand::RestoreTerminals(terminals) => {
+                        for info in terminals {
+                            if data.terminal.terminals.contains_key(&info.term_id) {
+                                // a connection made again, its tab still open
+                                data.proxy.terminal_resume(info.term_id);
+                                continue;
+                            }
+                            let terminal_data = Arc::new(LapceTerminalData::attach(
+                                info,
+                                data.terminal.split_id,
+                                ctx.get_external_handle(),
+                                Some(data.terminal.widget_id),
+                                data.proxy.clone(),
+                            ));
+                            let terminal = LapceTerminalView::new(&terminal_data);
+                            self.insert_flex_child(
+                                self.children.len(),
+                                terminal.boxed(),
+                                Some(terminal_data.widget_id),
+                                1.0,
+                            );
+                            let terminal_panel = Arc::make_mut(&mut data.terminal);
+                            terminal_panel.active = terminal_data.widget_id;
+                            terminal_panel.active_term_id = terminal_data.term_id;
+                            terminal_panel
+                                .terminals
+                                .insert(terminal_data.term_id, terminal_data);
+                        }
+                        ctx.children_changed();
+                    }
                     _ => (),
                 }
             }
@@ -650,3 +578,374 @@ impl Widget<LapceTabData> for LapceSplitNew {
         }
     }
 }
+
+/// The editors of the main split, laid out by the split tree of
+/// `LapceMainSplitData`, with the bars between them dragged to resize.
+pub struct LapceEditorSplit {
+    split_id: WidgetId,
+    children:
+        HashMap<WidgetId, WidgetPod<LapceTabData, Box<dyn Widget<LapceTabData>>>>,
+    rect: Rect,
+    /// The bars of the last layout.
+    bars: Vec<SplitBar>,
+    dragging: Option<SplitBar>,
+}
+
+impl LapceEditorSplit {
+    pub fn new(data: &LapceTabData) -> Self {
+        let children = data
+            .main_split
+            .split
+            .leaves()
+            .into_iter()
+            .filter_map(|view_id| {
+                let editor = data.main_split.editors.get(&view_id)?;
+                Some((
+                    view_id,
+                    WidgetPod::new(LapceEditorView::new(editor).boxed()),
+                ))
+            })
+            .collect();
+        Self {
+            split_id: *data.main_split.split_id,
+            children,
+            rect: Rect::ZERO,
+            bars: Vec::new(),
+            dragging: None,
+        }
+    }
+
+    fn set_split(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        split: SplitTree<WidgetId>,
+    ) {
+        data.main_split.editors_order = Arc::new(split.leaves());
+        data.main_split.split = Arc::new(split);
+        ctx.request_layout();
+    }
+
+    fn bar_at(&self, pos: Point) -> Option<&SplitBar> {
+        self.bars.iter().find(|bar| bar.contains(pos.x, pos.y, 3.0))
+    }
+
+    fn focus(&self, ctx: &mut EventCtx, view_id: WidgetId) {
+        ctx.submit_command(Command::new(
+            LAPCE_UI_COMMAND,
+            LapceUICommand::Focus,
+            Target::Widget(view_id),
+        ));
+        ctx.submit_command(Command::new(
+            LAPCE_UI_COMMAND,
+            LapceUICommand::EnsureCursorVisible(None),
+            Target::Widget(view_id),
+        ));
+    }
+
+    /// Puts a copy of the editor right of it, or below it.
+    fn split_editor(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        vertical: bool,
+        view_id: WidgetId,
+    ) {
+        let from_editor = match data.main_split.editors.get(&view_id) {
+            Some(editor) => editor,
+            None => return,
+        };
+        let mut editor_data = LapceEditorData::new(
+            None,
+            Some(self.split_id),
+            from_editor.content.clone(),
+            EditorType::Normal,
+            &data.config,
+        );
+        editor_data.cursor = from_editor.cursor.clone();
+        editor_data.locations = from_editor.locations.clone();
+        ctx.submit_command(Command::new(
+            LAPCE_UI_COMMAND,
+            LapceUICommand::ForceScrollTo(
+                from_editor.scroll_offset.x,
+                from_editor.scroll_offset.y,
+            ),
+            Target::Widget(editor_data.view_id),
+        ));
+
+        let mut split = (*data.main_split.split).clone();
+        if !split.split(&view_id, editor_data.view_id, vertical) {
+            return;
+        }
+        self.children.insert(
+            editor_data.view_id,
+            WidgetPod::new(LapceEditorView::new(&editor_data).boxed()),
+        );
+        data.main_split
+            .editors
+            .insert(editor_data.view_id, Arc::new(editor_data));
+        self.set_split(ctx, data, split);
+        ctx.children_changed();
+    }
+
+    /// Closes the editor, the one taking its place focused. The last one
+    /// is only emptied.
+    fn split_editor_close(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        view_id: WidgetId,
+    ) {
+        let mut split = (*data.main_split.split).clone();
+        let center = split
+            .rects(self.rect)
+            .into_iter()
+            .find(|(id, _)| id == &view_id)
+            .map(|(_, rect)| rect.center());
+        data.main_split.push_closed_editor(&view_id);
+        if !split.remove(&view_id) {
+            if let Some(editor) = data.main_split.editors.get_mut(&view_id) {
+                Arc::make_mut(editor).content = EditorContent::None;
+            }
+            return;
+        }
+
+        let new_view_id = center
+            .and_then(|center| {
+                split
+                    .rects(self.rect)
+                    .into_iter()
+                    .find(|(_, rect)| rect.contains(center))
+            })
+            .map(|(id, _)| id)
+            .unwrap_or_else(|| split.leaves()[0]);
+        if *data.main_split.active == view_id {
+            data.main_split.active = Arc::new(new_view_id);
+            data.focus = new_view_id;
+            ctx.set_focus(new_view_id);
+        }
+        data.main_split.editors.remove(&view_id);
+        self.children.remove(&view_id);
+        self.set_split(ctx, data, split);
+        ctx.children_changed();
+    }
+
+    /// Swaps the editor with the one after it, the focus staying where it
+    /// is.
+    fn split_editor_exchange(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        view_id: WidgetId,
+    ) {
+        let mut split = (*data.main_split.split).clone();
+        let leaves = split.leaves();
+        let next = match leaves.iter().position(|id| id == &view_id) {
+            Some(i) if i + 1 < leaves.len() => leaves[i + 1],
+            _ => return,
+        };
+        split.swap(&view_id, &next);
+        self.set_split(ctx, data, split);
+        self.focus(ctx, next);
+    }
+
+    /// Focuses the editor next to it in the direction.
+    fn split_editor_move(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        direction: &SplitMoveDirection,
+        view_id: WidgetId,
+    ) {
+        if let Some(next) = data
+            .main_split
+            .split
+            .neighbor(&view_id, direction, self.rect)
+        {
+            self.focus(ctx, next);
+        }
+    }
+
+    /// Moves the editor to where the one next to it in the direction is,
+    /// that one taking its place.
+    fn split_editor_swap(
+        &mut self,
+        ctx: &mut EventCtx,
+        data: &mut LapceTabData,
+        direction: &SplitMoveDirection,
+        view_id: WidgetId,
+    ) {
+        let mut split = (*data.main_split.split).clone();
+        if let Some(next) = split.neighbor(&view_id, direction, self.rect) {
+            split.swap(&view_id, &next);
+            self.set_split(ctx, data, split);
+            self.focus(ctx, view_id);
+        }
+    }
+}
+
+impl Widget<LapceTabData> for LapceEditorSplit {
+    fn id(&self) -> Option<WidgetId> {
+        Some(self.split_id)
+    }
+
+    fn event(
+        &mut self,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut LapceTabData,
+        env: &Env,
+    ) {
+        match event {
+            Event::MouseDown(mouse_event) => {
+                if let Some(bar) = self.bar_at(mouse_event.pos).cloned() {
+                    self.dragging = Some(bar);
+                    ctx.set_active(true);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            Event::MouseMove(mouse_event) => {
+                if let Some(bar) = self.dragging.clone() {
+                    let position = if bar.vertical {
+                        mouse_event.pos.x
+                    } else {
+                        mouse_event.pos.y
+                    };
+                    let mut split = (*data.main_split.split).clone();
+                    split.drag(&bar, position);
+                    self.set_split(ctx, data, split);
+                    ctx.set_handled();
+                    return;
+                }
+                if let Some(bar) = self.bar_at(mouse_event.pos) {
+                    ctx.set_cursor(if bar.vertical {
+                        &Cursor::ResizeLeftRight
+                    } else {
+                        &Cursor::ResizeUpDown
+                    });
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            Event::MouseUp(_) => {
+                if self.dragging.take().is_some() {
+                    ctx.set_active(false);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            _ => (),
+        }
+
+        for child in self.children.values_mut() {
+            child.event(ctx, event, data, env);
+        }
+        match event {
+            Event::Command(cmd) if cmd.is(LAPCE_UI_COMMAND) => {
+                let command = cmd.get_unchecked(LAPCE_UI_COMMAND);
+                match command {
+                    LapceUICommand::SplitEditor(vertical, view_id) => {
+                        self.split_editor(ctx, data, *vertical, *view_id);
+                    }
+                    LapceUICommand::SplitEditorMove(direction, view_id) => {
+                        self.split_editor_move(ctx, data, direction, *view_id);
+                    }
+                    LapceUICommand::SplitEditorSwap(direction, view_id) => {
+                        self.split_editor_swap(ctx, data, direction, *view_id);
+                    }
+                    LapceUICommand::SplitEditorExchange(view_id) => {
+                        self.split_editor_exchange(ctx, data, *view_id);
+                    }
+                    LapceUICommand::SplitEditorRotate(view_id) => {
+                        let mut split = (*data.main_split.split).clone();
+                        if split.rotate(view_id) {
+                            self.set_split(ctx, data, split);
+                        }
+                    }
+                    LapceUICommand::SplitEditorResize(view_id, amount) => {
+                        let mut split = (*data.main_split.split).clone();
+                        if split.resize(view_id, *amount) {
+                            self.set_split(ctx, data, split);
+                        }
+                    }
+                    LapceUICommand::SplitEditorClose(view_id) => {
+                        self.split_editor_close(ctx, data, *view_id);
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
+        data: &LapceTabData,
+        env: &Env,
+    ) {
+        for child in self.children.values_mut() {
+            child.lifecycle(ctx, event, data, env);
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut UpdateCtx,
+        old_data: &LapceTabData,
+        data: &LapceTabData,
+        env: &Env,
+    ) {
+        if !old_data.main_split.split.same(&data.main_split.split) {
+            ctx.request_layout();
+        }
+        for child in self.children.values_mut() {
+            child.update(ctx, data, env);
+        }
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        data: &LapceTabData,
+        env: &Env,
+    ) -> Size {
+        let size = bc.max();
+        self.rect = size.to_rect();
+        for (view_id, rect) in data.main_split.split.rects(self.rect) {
+            if let Some(child) = self.children.get_mut(&view_id) {
+                child.layout(ctx, &BoxConstraints::tight(rect.size()), data, env);
+                child.set_origin(ctx, data, env, rect.origin());
+            }
+        }
+        self.bars = data.main_split.split.bars(self.rect);
+        size
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &LapceTabData, env: &Env) {
+        for child in self.children.values_mut() {
+            child.paint(ctx, data, env);
+        }
+        for bar in self.bars.iter() {
+            let line = if bar.vertical {
+                Line::new(
+                    Point::new(bar.position, bar.rect.y0),
+                    Point::new(bar.position, bar.rect.y1),
+                )
+            } else {
+                Line::new(
+                    Point::new(bar.rect.x0, bar.position),
+                    Point::new(bar.rect.x1, bar.position),
+                )
+            };
+            ctx.stroke(
+                line,
+                data.config.get_color_unchecked(LapceTheme::LAPCE_BORDER),
+                1.0,
+            );
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! The layout of the editors of the main split: a tree of splits whose
+//! children are side by side or one above another, each taking a share of
+//! the space by its weight. The leaves are the editors.
+
+use druid::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::split::SplitMoveDirection;
+
+/// The least an editor is dragged down to.
+const MIN_SPLIT_SIZE: f64 = 50.0;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SplitTree<T> {
+    Leaf(T),
+    /// A `vertical` split has its children side by side, split by vertical
+    /// bars.
+    Split {
+        vertical: bool,
+        children: Vec<SplitChild<T>>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SplitChild<T> {
+    pub weight: f64,
+    pub tree: SplitTree<T>,
+}
+
+/// A bar between two children of a split, dragged to resize them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitBar {
+    /// The indexes of the children down to the split.
+    pub path: Vec<usize>,
+    /// The child after the bar.
+    pub index: usize,
+    pub vertical: bool,
+    /// Where the split is laid out.
+    pub rect: Rect,
+    /// The x of a vertical bar, the y of a horizontal one.
+    pub position: f64,
+}
+
+impl SplitBar {
+    pub fn contains(&self, x: f64, y: f64, margin: f64) -> bool {
+        if self.vertical {
+            (x - self.position).abs() <= margin
+                && self.rect.y0 <= y
+                && y <= self.rect.y1
+        } else {
+            (y - self.position).abs() <= margin
+                && self.rect.x0 <= x
+                && x <= self.rect.x1
+        }
+    }
+}
+
+fn leaf<T>(weight: f64, leaf: T) -> SplitChild<T> {
+    SplitChild {
+        weight,
+        tree: SplitTree::Leaf(leaf),
+    }
+}
+
+/// The rects of the children of a split laid out in `rect`.
+fn child_rects<T>(
+    vertical: bool,
+    children: &[SplitChild<T>],
+    rect: Rect,
+) -> Vec<Rect> {
+    let total: f64 = children.iter().map(|child| child.weight).sum();
+    let (origin, length) = if vertical {
+        (rect.x0, rect.width())
+    } else {
+        (rect.y0, rect.height())
+    };
+    let mut start = origin;
+    let mut weight = 0.0;
+    children
+        .iter()
+        .map(|child| {
+            weight += child.weight;
+            let end = (origin + length * weight / total).round();
+            let child_rect = if vertical {
+                Rect::new(start, rect.y0, end, rect.y1)
+            } else {
+                Rect::new(rect.x0, start, rect.x1, end)
+            };
+            start = end;
+            child_rect
+        })
+        .collect()
+}
+
+/// How far `value` is from the range.
+fn distance(value: f64, start: f64, end: f64) -> f64 {
+    if value < start {
+        start - value
+    } else if value > end {
+        value - end
+    } else {
+        0.0
+    }
+}
+
+impl<T: Clone + PartialEq> SplitTree<T> {
+    /// The leaves side by side, as the editors were before there were
+    /// splits of splits.
+    pub fn row(leaves: Vec<T>) -> Self {
+        if leaves.len() == 1 {
+            return SplitTree::Leaf(leaves.into_iter().next().unwrap());
+        }
+        SplitTree::Split {
+            vertical: true,
+            children: leaves.into_iter().map(|l| leaf(1.0, l)).collect(),
+        }
+    }
+
+    /// The leaves from left to right and top to bottom.
+    pub fn leaves(&self) -> Vec<T> {
+        let mut leaves = Vec::new();
+        self.collect_leaves(&mut leaves);
+        leaves
+    }
+
+    fn collect_leaves(&self, leaves: &mut Vec<T>) {
+        match self {
+            SplitTree::Leaf(leaf) => leaves.push(leaf.clone()),
+            SplitTree::Split { children, .. } => {
+                for child in children {
+                    child.tree.collect_leaves(leaves);
+                }
+            }
+        }
+    }
+
+    fn leaves_mut(&mut self) -> Vec<&mut T> {
+        match self {
+            SplitTree::Leaf(leaf) => vec![leaf],
+            SplitTree::Split { children, .. } => children
+                .iter_mut()
+                .flat_map(|child| child.tree.leaves_mut())
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, leaf: &T) -> bool {
+        self.leaves().contains(leaf)
+    }
+
+    /// The same tree with other leaves, None if `f` has none for one.
+    pub fn map<U>(&self, f: &impl Fn(&T) -> Option<U>) -> Option<SplitTree<U>> {
+        Some(match self {
+            SplitTree::Leaf(leaf) => SplitTree::Leaf(f(leaf)?),
+            SplitTree::Split { vertical, children } => SplitTree::Split {
+                vertical: *vertical,
+                children: children
+                    .iter()
+                    .map(|child| {
+                        Some(SplitChild {
+                            weight: child.weight,
+                            tree: child.tree.map(f)?,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?,
+            },
+        })
+    }
+
+    /// Where each leaf is laid out in `rect`.
+    pub fn rects(&self, rect: Rect) -> Vec<(T, Rect)> {
+        let mut rects = Vec::new();
+        self.collect_rects(rect, &mut rects);
+        rects
+    }
+
+    fn collect_rects(&self, rect: Rect, rects: &mut Vec<(T, Rect)>) {
+        match self {
+            SplitTree::Leaf(leaf) => rects.push((leaf.clone(), rect)),
+            SplitTree::Split { vertical, children } => {
+                let child_rects = child_rects(*vertical, children, rect);
+                for (child, rect) in children.iter().zip(child_rects) {
+                    child.tree.collect_rects(rect, rects);
+                }
+            }
+        }
+    }
+
+    /// The bars between the children of every split laid out in `rect`.
+    pub fn bars(&self, rect: Rect) -> Vec<SplitBar> {
+        let mut bars = Vec::new();
+        self.collect_bars(rect, &mut Vec::new(), &mut bars);
+        bars
+    }
+
+    fn collect_bars(
+        &self,
+        rect: Rect,
+        path: &mut Vec<usize>,
+        bars: &mut Vec<SplitBar>,
+    ) {
+        if let SplitTree::Split { vertical, children } = self {
+            let child_rects = child_rects(*vertical, children, rect);
+            for i in 1..children.len() {
+                bars.push(SplitBar {
+                    path: path.clone(),
+                    index: i,
+                    vertical: *vertical,
+                    rect,
+                    position: if *vertical {
+                        child_rects[i].x0
+                    } else {
+                        child_rects[i].y0
+                    },
+                });
+            }
+            for (i, (child, rect)) in children.iter().zip(child_rects).enumerate() {
+                path.push(i);
+                child.tree.collect_bars(rect, path, bars);
+                path.pop();
+            }
+        }
+    }
+
+    /// The split the leaf is a child of, whether it's vertical, and the
+    /// leaf's index in it.
+    fn parent_mut(
+        &mut self,
+        leaf: &T,
+    ) -> Option<(bool, &mut Vec<SplitChild<T>>, usize)> {
+        match self {
+            SplitTree::Leaf(_) => None,
+            SplitTree::Split { vertical, children } => {
+                if let Some(index) = children.iter().position(
+                    |child| matches!(&child.tree, SplitTree::Leaf(l) if l == leaf),
+                ) {
+                    return Some((*vertical, children, index));
+                }
+                children
+                    .iter_mut()
+                    .find_map(|child| child.tree.parent_mut(leaf))
+            }
+        }
+    }
+
+    fn node_mut(&mut self, path: &[usize]) -> Option<&mut SplitTree<T>> {
+        match path.split_first() {
+            None => Some(self),
+            Some((i, rest)) => match self {
+                SplitTree::Leaf(_) => None,
+                SplitTree::Split { children, .. } => {
+                    children.get_mut(*i)?.tree.node_mut(rest)
+                }
+            },
+        }
+    }
+
+    /// Puts `new` after the leaf, right of it in a vertical split and below
+    /// it in a horizontal one, the two sharing the leaf's space.
+    pub fn split(&mut self, leaf: &T, new: T, vertical: bool) -> bool {
+        if let SplitTree::Leaf(l) = self {
+            if l != leaf {
+                return false;
+            }
+            let l = l.clone();
+            *self = SplitTree::Split {
+                vertical,
+                children: vec![self::leaf(1.0, l), self::leaf(1.0, new)],
+            };
+            return true;
+        }
+        let (parent_vertical, children, index) = match self.parent_mut(leaf) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        if parent_vertical == vertical {
+            let weight = children[index].weight / 2.0;
+            children[index].weight = weight;
+            children.insert(index + 1, self::leaf(weight, new));
+        } else {
+            children[index].tree = SplitTree::Split {
+                vertical,
+                children: vec![self::leaf(1.0, leaf.clone()), self::leaf(1.0, new)],
+            };
+        }
+        true
+    }
+
+    /// Takes the leaf out, its space going to the children left. The last
+    /// leaf stays.
+    pub fn remove(&mut self, leaf: &T) -> bool {
+        let (_, children, index) = match self.parent_mut(leaf) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        children.remove(index);
+        self.normalize();
+        true
+    }
+
+    /// Replaces a split of one child by the child, and puts the children
+    /// of a split into its parent when they go the same way.
+    fn normalize(&mut self) {
+        if let SplitTree::Split { vertical, children } = self {
+            for child in children.iter_mut() {
+                child.tree.normalize();
+            }
+            let mut flattened = Vec::new();
+            for child in children.drain(..) {
+                match child.tree {
+                    SplitTree::Split {
+                        vertical: child_vertical,
+                        children: grandchildren,
+                    } if child_vertical == *vertical => {
+                        let total: f64 =
+                            grandchildren.iter().map(|g| g.weight).sum();
+                        for grandchild in grandchildren {
+                            flattened.push(SplitChild {
+                                weight: child.weight * grandchild.weight / total,
+                                tree: grandchild.tree,
+                            });
+                        }
+                    }
+                    tree => flattened.push(SplitChild {
+                        weight: child.weight,
+                        tree,
+                    }),
+                }
+            }
+            *children = flattened;
+            if children.len() == 1 {
+                *self = children.remove(0).tree;
+            }
+        }
+    }
+
+    pub fn swap(&mut self, a: &T, b: &T) {
+        for leaf in self.leaves_mut() {
+            if leaf == a {
+                *leaf = b.clone();
+            } else if leaf == b {
+                *leaf = a.clone();
+            }
+        }
+    }
+
+    /// Moves the children of the leaf's split one further, the last one
+    /// becoming the first, their sizes staying where they are.
+    pub fn rotate(&mut self, leaf: &T) -> bool {
+        let (_, children, _) = match self.parent_mut(leaf) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let mut trees: Vec<SplitTree<T>> = children
+            .iter_mut()
+            .map(|child| {
+                std::mem::replace(
+                    &mut child.tree,
+                    SplitTree::Split {
+                        vertical: true,
+                        children: Vec::new(),
+                    },
+                )
+            })
+            .collect();
+        trees.rotate_right(1);
+        for (child, tree) in children.iter_mut().zip(trees) {
+            child.tree = tree;
+        }
+        true
+    }
+
+    /// Grows the leaf by `amount` of what it shares with the child after
+    /// it, or before it when it's the last, shrinking it when negative.
+    pub fn resize(&mut self, leaf: &T, amount: f64) -> bool {
+        let (_, children, index) = match self.parent_mut(leaf) {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let other = if index + 1 < children.len() {
+            index + 1
+        } else {
+            index - 1
+        };
+        let total = children[index].weight + children[other].weight;
+        let weight = (children[index].weight + total * amount)
+            .max(total * 0.1)
+            .min(total * 0.9);
+        children[index].weight = weight;
+        children[other].weight = total - weight;
+        true
+    }
+
+    /// Moves the bar to `position`, the children each side of it keeping at
+    /// least a little of their space.
+    pub fn drag(&mut self, bar: &SplitBar, position: f64) {
+        if let Some(SplitTree::Split { vertical, children }) =
+            self.node_mut(&bar.path)
+        {
+            if bar.index == 0 || bar.index >= children.len() {
+                return;
+            }
+            let rects = child_rects(*vertical, children, bar.rect);
+            let (before, after) = (rects[bar.index - 1], rects[bar.index]);
+            let (start, end) = if *vertical {
+                (before.x0, after.x1)
+            } else {
+                (before.y0, after.y1)
+            };
+            if end <= start {
+                return;
+            }
+            let min = MIN_SPLIT_SIZE.min((end - start) / 2.0);
+            let position = position.max(start + min).min(end - min);
+            let share = (position - start) / (end - start);
+            let total = children[bar.index - 1].weight + children[bar.index].weight;
+            children[bar.index - 1].weight = total * share;
+            children[bar.index].weight = total * (1.0 - share);
+        }
+    }
+
+    /// The leaf next to `leaf` in the direction, laid out in `rect`: the
+    /// nearest of those it faces, then the one level with its center.
+    pub fn neighbor(
+        &self,
+        leaf: &T,
+        direction: &SplitMoveDirection,
+        rect: Rect,
+    ) -> Option<T> {
+        let rects = self.rects(rect);
+        let from = rects.iter().find(|(l, _)| l == leaf)?.1;
+        let center = from.center();
+        rects
+            .iter()
+            .filter(|(l, _)| l != leaf)
+            .filter_map(|(l, r)| {
+                let (gap, faces, off) = match direction {
+                    SplitMoveDirection::Right => (
+                        r.x0 - from.x1,
+                        r.y0 < from.y1 && from.y0 < r.y1,
+                        distance(center.y, r.y0, r.y1),
+                    ),
+                    SplitMoveDirection::Left => (
+                        from.x0 - r.x1,
+                        r.y0 < from.y1 && from.y0 < r.y1,
+                        distance(center.y, r.y0, r.y1),
+                    ),
+                    SplitMoveDirection::Down => (
+                        r.y0 - from.y1,
+                        r.x0 < from.x1 && from.x0 < r.x1,
+                        distance(center.x, r.x0, r.x1),
+                    ),
+                    SplitMoveDirection::Up => (
+                        from.y0 - r.y1,
+                        r.x0 < from.x1 && from.x0 < r.x1,
+                        distance(center.x, r.x0, r.x1),
+                    ),
+                };
+                (gap > -0.5 && faces).then(|| ((gap.max(0.0), off), l))
+            })
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+            .map(|(_, l)| l.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect() -> Rect {
+        Rect::new(0.0, 0.0, 400.0, 200.0)
+    }
+
+    fn rects(tree: &SplitTree<char>) -> Vec<(char, (f64, f64, f64, f64))> {
+        tree.rects(rect())
+            .into_iter()
+            .map(|(l, r)| (l, (r.x0, r.y0, r.x1, r.y1)))
+            .collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let mut tree = SplitTree::Leaf('a');
+        assert!(tree.split(&'a', 'b', true));
+        assert!(tree.split(&'b', 'c', true));
+        assert_eq!(
+            vec![
+                ('a', (0.0, 0.0, 200.0, 200.0)),
+                ('b', (200.0, 0.0, 300.0, 200.0)),
+                ('c', (300.0, 0.0, 400.0, 200.0)),
+            ],
+            rects(&tree)
+        );
+        assert!(tree.split(&'a', 'd', false));
+        assert_eq!(
+            vec![
+                ('a', (0.0, 0.0, 200.0, 100.0)),
+                ('d', (0.0, 100.0, 200.0, 200.0)),
+                ('b', (200.0, 0.0, 300.0, 200.0)),
+                ('c', (300.0, 0.0, 400.0, 200.0)),
+            ],
+            rects(&tree)
+        );
+        assert!(!tree.split(&'x', 'e', false));
+        assert_eq!(vec!['a', 'd', 'b', 'c'], tree.leaves());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = SplitTree::row(vec!['a', 'b']);
+        tree.split(&'b', 'c', false);
+        // b's split goes, c taking its place beside a
+        assert!(tree.remove(&'b'));
+        assert_eq!(SplitTree::row(vec!['a', 'c']), tree);
+        assert!(tree.remove(&'a'));
+        assert_eq!(SplitTree::Leaf('c'), tree);
+        assert!(!tree.remove(&'c'));
+
+        // the children of a split going the same way are put in its parent
+        let mut tree = SplitTree::row(vec!['a', 'b']);
+        tree.split(&'b', 'c', false);
+        tree.split(&'c', 'd', true);
+        tree.remove(&'b');
+        assert_eq!(vec!['a', 'c', 'd'], tree.leaves());
+        assert_eq!(
+            vec![
+                ('a', (0.0, 0.0, 200.0, 200.0)),
+                ('c', (200.0, 0.0, 300.0, 200.0)),
+                ('d', (300.0, 0.0, 400.0, 200.0)),
+            ],
+            rects(&tree)
+        );
+    }
+
+    #[test]
+    fn test_swap_rotate() {
+        let mut tree = SplitTree::row(vec!['a', 'b', 'c']);
+        tree.resize(&'a', 0.25);
+        tree.swap(&'a', &'c');
+        assert_eq!(vec!['c', 'b', 'a'], tree.leaves());
+        assert!(tree.rotate(&'b'));
+        assert_eq!(vec!['a', 'c', 'b'], tree.leaves());
+        // the sizes stay
+        assert_eq!(('a', (0.0, 0.0, 200.0, 200.0)), rects(&tree)[0]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut tree = SplitTree::row(vec!['a', 'b']);
+        assert!(tree.resize(&'b', 0.25));
+        assert_eq!(('b', (100.0, 0.0, 400.0, 200.0)), rects(&tree)[1]);
+        tree.resize(&'b', 1.0);
+        assert_eq!(('b', (40.0, 0.0, 400.0, 200.0)), rects(&tree)[1]);
+        assert!(!SplitTree::Leaf('a').resize(&'a', 0.1));
+    }
+
+    #[test]
+    fn test_drag() {
+        let mut tree = SplitTree::row(vec!['a', 'b']);
+        tree.split(&'b', 'c', false);
+        let bars = tree.bars(rect());
+        assert_eq!(2, bars.len());
+        assert!(bars[0].vertical);
+        assert_eq!(200.0, bars[0].position);
+        assert!(bars[0].contains(202.0, 50.0, 4.0));
+        assert!(!bars[1].vertical);
+        assert_eq!(vec![1], bars[1].path);
+        assert_eq!(100.0, bars[1].position);
+
+        tree.drag(&bars[0], 100.0);
+        assert_eq!(('a', (0.0, 0.0, 100.0, 200.0)), rects(&tree)[0]);
+        tree.drag(&bars[1], 190.0);
+        assert_eq!(('b', (100.0, 0.0, 400.0, 150.0)), rects(&tree)[1]);
+        // dragged past the end, the next child keeps a little
+        tree.drag(&tree.bars(rect())[0], 500.0);
+        assert_eq!(('a', (0.0, 0.0, 350.0, 200.0)), rects(&tree)[0]);
+    }
+
+    #[test]
+    fn test_neighbor() {
+        // a | b
+        //   | c
+        let mut tree = SplitTree::row(vec!['a', 'b']);
+        tree.split(&'b', 'c', false);
+        let neighbor = |leaf, direction| tree.neighbor(&leaf, &direction, rect());
+        assert_eq!(Some('b'), neighbor('a', SplitMoveDirection::Right));
+        assert_eq!(Some('a'), neighbor('c', SplitMoveDirection::Left));
+        assert_eq!(Some('c'), neighbor('b', SplitMoveDirection::Down));
+        assert_eq!(Some('b'), neighbor('c', SplitMoveDirection::Up));
+        assert_eq!(None, neighbor('a', SplitMoveDirection::Left));
+        assert_eq!(None, neighbor('a', SplitMoveDirection::Up));
+    }
+
+    #[test]
+    fn test_map() {
+        let mut tree = SplitTree::row(vec![0, 1]);
+        tree.split(&1, 2, false);
+        let names = ['a', 'b', 'c'];
+        let mapped = tree.map(&|i| names.get(*i).copied()).unwrap();
+        assert_eq!(vec!['a', 'b', 'c'], mapped.leaves());
+        assert!(tree.map(&|i| (*i < 2).then(|| *i)).is_none());
+    }
+}
@@ -28,6 +28,8 @@ use xi_rpc::RpcLoop;
 use xi_rpc::RpcPeer;
 use xi_trace::enable_tracing;
 
+use crate::remote::destination;
+
 #[derive(PartialEq)]
 enum KeymapMatch {
     Full,
@@ -88,7 +90,7 @@ impl Display for LapceWorkspaceType {
         match self {
             LapceWorkspaceType::Local => f.write_str("Local"),
             LapceWorkspaceType::RemoteSSH(user, host) => {
-                write!(f, "ssh://{}@{}", user, host)
+                write!(f, "ssh://{}", destination(user, host))
             }
         }
     }
@@ -117,6 +119,32 @@ impl Default for LapceWorkspace {
     }
 }
 
+impl LapceWorkspace {
+    /// A remote workspace of `user@host:/path`, or `host:/path` for the
+    /// user of the ssh config.
+    pub fn parse_remote(s: &str) -> Option<Self> {
+        let (destination, path) = s.trim().split_once(':')?;
+        if path.is_empty() || destination.is_empty() {
+            return None;
+        }
+        let (user, host) = match destination.rsplit_once('@') {
+            Some((user, host)) => (user.to_string(), host.to_string()),
+            None => (String::new(), destination.to_string()),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            kind: LapceWorkspaceType::RemoteSSH(user, host),
+            path: PathBuf::from(path),
+            last_open: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        })
+    }
+}
+
 impl Display for LapceWorkspace {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}", self.kind, self.path.to_str().unwrap())
@@ -172,6 +200,23 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_parse_remote() {
+        let workspace = LapceWorkspace::parse_remote("me@host:/src/app").unwrap();
+        assert_eq!(
+            LapceWorkspaceType::RemoteSSH("me".to_string(), "host".to_string()),
+            workspace.kind
+        );
+        assert_eq!(PathBuf::from("/src/app"), workspace.path);
+        let workspace = LapceWorkspace::parse_remote("host:/src").unwrap();
+        assert_eq!(
+            LapceWorkspaceType::RemoteSSH("".to_string(), "host".to_string()),
+            workspace.kind
+        );
+        assert!(LapceWorkspace::parse_remote("host").is_none());
+        assert!(LapceWorkspace::parse_remote("me@:/src").is_none());
+    }
+
     #[test]
     fn test_check_condition() {
         // let rope = Rope::from_str("abc\nabc\n").unwrap();
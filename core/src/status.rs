@@ -5,13 +5,15 @@ use druid::theme;
 use druid::Color;
 use druid::Vec2;
 use druid::{
-    kurbo::Line, Event, FontDescriptor, FontFamily, Point, RenderContext, Size,
-    Widget, WidgetId, WindowId,
+    kurbo::Line, Data, Event, FontDescriptor, FontFamily, Point, RenderContext,
+    Size, Widget, WidgetId, WindowId,
 };
+use lapce_proxy::buffer::ExternalChange;
 use lsp_types::DiagnosticSeverity;
 
 use crate::command::{LapceUICommand, LAPCE_UI_COMMAND};
 use crate::config::LapceTheme;
+use crate::data::EditorContent;
 use crate::data::FocusArea;
 use crate::data::LapceTabData;
 use crate::state::Mode;
@@ -64,6 +66,15 @@ impl Widget<LapceTabData> for LapceStatusNew {
             ctx.request_paint();
             return;
         }
+
+        if !old_data
+            .main_split
+            .work_progress
+            .same(&data.main_split.work_progress)
+        {
+            ctx.request_paint();
+            return;
+        }
     }
 
     fn layout(
@@ -152,5 +163,86 @@ impl Widget<LapceTabData> for LapceStatusNew {
             .unwrap();
         ctx.draw_text(&text_layout, Point::new(left + 10.0, 4.0));
         left += 10.0 + text_layout.size().width;
+
+        if let EditorContent::Buffer(path) = &data.main_split.active_editor().content
+        {
+            if let Some(buffer) = data.main_split.open_files.get(path) {
+                let mut text = format!("{}  {}", buffer.indent, buffer.line_ending);
+                match buffer.external_change {
+                    Some(ExternalChange::Changed | ExternalChange::Conflict) => {
+                        text += "  Changed on Disk";
+                    }
+                    Some(ExternalChange::Deleted) => text += "  Deleted on Disk",
+                    None => (),
+                }
+                if !buffer.editable && buffer.large_file.is_none() {
+                    text += "  Read-only";
+                }
+                if data.main_split.unsaved_backups.contains_key(path) {
+                    text += "  Unsaved Changes Backed Up";
+                }
+                let text_layout = ctx
+                    .text()
+                    .new_text_layout(text)
+                    .font(FontFamily::SYSTEM_UI, 13.0)
+                    .text_color(
+                        data.config
+                            .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                            .clone(),
+                    )
+                    .build()
+                    .unwrap();
+                ctx.draw_text(&text_layout, Point::new(left + 10.0, 4.0));
+                left += 10.0 + text_layout.size().width;
+            }
+            let (large_file, binary) = data
+                .main_split
+                .open_files
+                .get(path)
+                .map(|buffer| (buffer.large_file.is_some(), buffer.binary))
+                .unwrap_or((false, false));
+            if large_file {
+                let text_layout = ctx
+                    .text()
+                    .new_text_layout(if binary {
+                        "Binary (read-only)"
+                    } else {
+                        "Large File (read-only)"
+                    })
+                    .font(FontFamily::SYSTEM_UI, 13.0)
+                    .text_color(
+                        data.config
+                            .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                            .clone(),
+                    )
+                    .build()
+                    .unwrap();
+                ctx.draw_text(&text_layout, Point::new(left + 10.0, 4.0));
+                left += 10.0 + text_layout.size().width;
+            }
+        }
+
+        if let Some(progress) = data.main_split.work_progress.values().next() {
+            let mut text = format!("{}: {}", progress.server, progress.title);
+            if let Some(message) = progress.message.as_ref() {
+                text += &format!(" {}", message);
+            }
+            if let Some(percentage) = progress.percentage {
+                text += &format!(" ({}%)", percentage);
+            }
+            let text_layout = ctx
+                .text()
+                .new_text_layout(text)
+                .font(FontFamily::SYSTEM_UI, 13.0)
+                .text_color(
+                    data.config
+                        .get_color_unchecked(LapceTheme::EDITOR_FOREGROUND)
+                        .clone(),
+                )
+                .build()
+                .unwrap();
+            ctx.draw_text(&text_layout, Point::new(left + 10.0, 4.0));
+            left += 10.0 + text_layout.size().width;
+        }
     }
 }
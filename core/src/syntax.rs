@@ -0,0 +1,411 @@
+//! The syntax tree of each buffer, edited along with the buffer so that
+//! tree-sitter parses incrementally, and the highlights of what a parse
+//! changed, so that a keystroke doesn't highlight the whole file again.
+
+use std::time::Duration;
+
+use lapce_proxy::brackets::bracket_depths;
+use tree_sitter::{InputEdit, Parser, Point, Query, QueryCursor, Tree};
+use xi_rope::{
+    spans::{Spans, SpansBuilder},
+    Interval, Rope, RopeDelta,
+};
+
+use crate::buffer::Style;
+
+/// How long a parse can hold up the updates of the other buffers, after
+/// which it's given up on when there are newer edits to parse instead.
+pub const PARSE_BUDGET: Duration = Duration::from_millis(5);
+/// How many colors brackets take turns at by depth, the theme's
+/// `style.punctuation.bracket.0` and on.
+pub const RAINBOW_BRACKET_COLORS: usize = 3;
+
+pub struct SyntaxState {
+    pub rev: u64,
+    pub tree: Tree,
+    /// The text `tree` is of, or will be once parsed again.
+    rope: Rope,
+    /// The intervals edited since the last parse, in the current text.
+    edited: Vec<Interval>,
+    /// Whether `tree` was edited and needs parsing again.
+    stale: bool,
+}
+
+impl SyntaxState {
+    pub fn new(rev: u64, rope: Rope, tree: Tree) -> Self {
+        Self {
+            rev,
+            tree,
+            rope,
+            edited: Vec::new(),
+            stale: false,
+        }
+    }
+
+    /// Whether the tree was edited since it was last parsed.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Edits the tree with the deltas that turned its text into the one of
+    /// `rev`, each with the rev it made. False if they don't start from the
+    /// tree's rev or don't end at `rev`, and the text has to be parsed from
+    /// scratch.
+    pub fn edit(&mut self, rev: u64, deltas: &[(u64, RopeDelta)]) -> bool {
+        let deltas: Vec<&(u64, RopeDelta)> = deltas
+            .iter()
+            .filter(|(delta_rev, _)| *delta_rev > self.rev)
+            .collect();
+        let follows_on = deltas
+            .iter()
+            .enumerate()
+            .all(|(i, (delta_rev, _))| *delta_rev == self.rev + 1 + i as u64);
+        if !follows_on || self.rev + deltas.len() as u64 != rev {
+            return false;
+        }
+        for (delta_rev, delta) in deltas {
+            let rope = delta.apply(&self.rope);
+            self.tree.edit(&input_edit(&self.rope, &rope, delta));
+            self.edited = self
+                .edited
+                .iter()
+                .map(|iv| shift_interval(*iv, delta))
+                .collect();
+            let (iv, new_len) = delta.summary();
+            self.edited
+                .push(Interval::new(iv.start, iv.start + new_len));
+            self.rope = rope;
+            self.rev = *delta_rev;
+            self.stale = true;
+        }
+        true
+    }
+
+    /// Parses the edited tree again, within `PARSE_BUDGET` when `hurry`,
+    /// giving the lines of the text whose highlights can have changed.
+    pub fn reparse(
+        &mut self,
+        parser: &mut Parser,
+        text: &[u8],
+        hurry: bool,
+    ) -> Option<Vec<Interval>> {
+        if !self.stale {
+            return Some(Vec::new());
+        }
+        if hurry {
+            parser.set_timeout_micros(PARSE_BUDGET.as_micros() as u64);
+        }
+        let tree = parser.parse(text, Some(&self.tree));
+        parser.set_timeout_micros(0);
+        let tree = match tree {
+            Some(tree) => tree,
+            None => {
+                parser.reset();
+                return None;
+            }
+        };
+        let mut changed = std::mem::take(&mut self.edited);
+        changed.extend(
+            self.tree
+                .changed_ranges(&tree)
+                .map(|range| Interval::new(range.start_byte, range.end_byte)),
+        );
+        self.tree = tree;
+        self.stale = false;
+        Some(line_intervals(&self.rope, changed))
+    }
+}
+
+/// The edit of a tree for `delta`, which turns `before` into `after`.
+fn input_edit(before: &Rope, after: &Rope, delta: &RopeDelta) -> InputEdit {
+    let (iv, new_len) = delta.summary();
+    InputEdit {
+        start_byte: iv.start,
+        old_end_byte: iv.end,
+        new_end_byte: iv.start + new_len,
+        start_position: point(before, iv.start),
+        old_end_position: point(before, iv.end),
+        new_end_position: point(after, iv.start + new_len),
+    }
+}
+
+fn point(rope: &Rope, offset: usize) -> Point {
+    let row = rope.line_of_offset(offset);
+    Point {
+        row,
+        column: offset - rope.offset_of_line(row),
+    }
+}
+
+/// Where an interval of the text before `delta` is after it, the part of
+/// it the delta replaced with the part that replaced it.
+fn shift_interval(iv: Interval, delta: &RopeDelta) -> Interval {
+    let (edit, new_len) = delta.summary();
+    let shift = |offset: usize, inside: usize| {
+        if offset <= edit.start {
+            offset
+        } else if offset >= edit.end {
+            offset + new_len - (edit.end - edit.start)
+        } else {
+            inside
+        }
+    };
+    Interval::new(
+        shift(iv.start, edit.start),
+        shift(iv.end, edit.start + new_len),
+    )
+}
+
+/// The intervals that start and end on line boundaries and cover
+/// `intervals`, merged where they overlap or touch.
+fn line_intervals(rope: &Rope, mut intervals: Vec<Interval>) -> Vec<Interval> {
+    let len = rope.len();
+    intervals.sort_by_key(|iv| iv.start);
+    let mut lines: Vec<Interval> = Vec::new();
+    for iv in intervals {
+        let start = rope.offset_of_line(rope.line_of_offset(iv.start.min(len)));
+        let end = rope.offset_of_line(rope.line_of_offset(iv.end.min(len)) + 1);
+        match lines.last_mut() {
+            Some(last) if start <= last.end => {
+                *last = Interval::new(last.start, last.end.max(end));
+            }
+            _ => lines.push(Interval::new(start, end)),
+        }
+    }
+    lines
+}
+
+/// For each capture of `query`, the name in `names` it's highlighted as:
+/// the one with the most parts, all of which the capture has, the way
+/// `HighlightConfiguration::configure` picks it.
+pub fn capture_highlights(query: &Query, names: &[String]) -> Vec<Option<usize>> {
+    query
+        .capture_names()
+        .iter()
+        .map(|capture| {
+            let parts: Vec<&str> = capture.split('.').collect();
+            names
+                .iter()
+                .enumerate()
+                .filter(|(_, name)| {
+                    name.split('.').all(|part| parts.contains(&part))
+                })
+                .max_by_key(|(_, name)| name.split('.').count())
+                .map(|(i, _)| i)
+        })
+        .collect()
+}
+
+/// The highlights of `iv` in `text`, from the captures of `query` in
+/// `tree`, the innermost node winning and the first pattern for a node.
+pub fn highlight_interval(
+    query: &Query,
+    names: &[String],
+    tree: &Tree,
+    text: &[u8],
+    iv: Interval,
+) -> Spans<Style> {
+    let highlights = capture_highlights(query, names);
+    let mut captures = Vec::new();
+    let mut cursor = QueryCursor::new();
+    cursor.set_byte_range(iv.start, iv.end);
+    for (m, i) in
+        cursor.captures(query, tree.root_node(), |node| &text[node.byte_range()])
+    {
+        let capture = m.captures[i];
+        if let Some(hl) = highlights[capture.index as usize] {
+            let range = capture.node.byte_range();
+            captures.push((range.start, range.end, m.pattern_index, hl));
+        }
+    }
+    captures.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
+
+    // which highlight each byte has
+    let mut painted: Vec<Option<usize>> = vec![None; iv.end - iv.start];
+    let mut last = None;
+    for (start, end, _, hl) in captures {
+        if last == Some((start, end)) {
+            continue;
+        }
+        last = Some((start, end));
+        let start = start.clamp(iv.start, iv.end) - iv.start;
+        let end = end.clamp(iv.start, iv.end) - iv.start;
+        for byte in &mut painted[start..end] {
+            *byte = Some(hl);
+        }
+    }
+
+    let mut spans = SpansBuilder::new(painted.len());
+    let mut start = 0;
+    for end in 1..=painted.len() {
+        if end == painted.len() || painted[end] != painted[start] {
+            if let Some(hl) = painted[start] {
+                spans.add_span(
+                    Interval::new(start, end),
+                    Style {
+                        fg_color: Some(names[hl].clone()),
+                    },
+                );
+            }
+            start = end;
+        }
+    }
+    spans.build()
+}
+
+/// The styles of the brackets in `iv` by how deep they're nested, as
+/// spans from the start of `iv`, to go over its highlights.
+pub fn rainbow_brackets(rope: &Rope, tree: &Tree, iv: Interval) -> Spans<Style> {
+    let mut spans = SpansBuilder::new(iv.end - iv.start);
+    for (offset, depth) in bracket_depths(rope, Some(tree), iv.start, iv.end) {
+        spans.add_span(
+            Interval::new(offset - iv.start, offset + 1 - iv.start),
+            Style {
+                fg_color: Some(format!(
+                    "punctuation.bracket.{}",
+                    depth % RAINBOW_BRACKET_COLORS
+                )),
+            },
+        );
+    }
+    spans.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use xi_rope::DeltaBuilder;
+
+    #[test]
+    fn test_intervals() {
+        let rope = Rope::from("fn main() {\n    let a = 1;\n}\n");
+        // "1" replaced with "42"
+        let mut builder = DeltaBuilder::new(rope.len());
+        builder.replace(Interval::new(24, 25), Rope::from("42"));
+        let delta = builder.build();
+        assert_eq!(
+            Interval::new(24, 26),
+            shift_interval(Interval::new(24, 25), &delta)
+        );
+        assert_eq!(
+            Interval::new(3, 7),
+            shift_interval(Interval::new(3, 7), &delta)
+        );
+        assert_eq!(
+            Interval::new(27, 29),
+            shift_interval(Interval::new(26, 28), &delta)
+        );
+
+        let rope = delta.apply(&rope);
+        assert_eq!(
+            vec![Interval::new(12, 28)],
+            line_intervals(
+                &rope,
+                vec![Interval::new(24, 26), Interval::new(16, 20)]
+            )
+        );
+        assert_eq!(
+            vec![Interval::new(0, 12), Interval::new(28, 30)],
+            line_intervals(&rope, vec![Interval::new(28, 29), Interval::new(2, 3)])
+        );
+    }
+
+    fn rust_source(len: usize) -> String {
+        let mut source = String::new();
+        let mut i = 0;
+        while source.len() < len {
+            source.push_str(&format!(
+                "fn f{0}(a: usize) -> usize {{\n    let b = a * {0};\n    \
+                 if b > 10 {{ b - 10 }} else {{ b + {0} }}\n}}\n\n",
+                i
+            ));
+            i += 1;
+        }
+        source
+    }
+
+    /// The state of `source` parsed, and the delta typing `typed` in the
+    /// middle of it makes, at the offset typed at.
+    fn typed_in_middle(
+        parser: &mut Parser,
+        source: &str,
+        typed: &str,
+    ) -> (SyntaxState, RopeDelta, usize) {
+        parser.set_language(tree_sitter_rust::language()).unwrap();
+        let rope = Rope::from(source);
+        let tree = parser.parse(source.as_bytes(), None).unwrap();
+        let state = SyntaxState::new(0, rope.clone(), tree);
+
+        let middle = source.len() / 2;
+        let offset = middle + source[middle..].find("if b").unwrap() + 3;
+        let mut builder = DeltaBuilder::new(rope.len());
+        builder.replace(Interval::new(offset, offset), Rope::from(typed));
+        (state, builder.build(), offset)
+    }
+
+    #[test]
+    fn test_reparse() {
+        let source = rust_source(4096);
+        let mut parser = Parser::new();
+        for typed in ["x", " + 1 > a && b"] {
+            let (mut state, delta, offset) =
+                typed_in_middle(&mut parser, &source, typed);
+            let text = String::from(&delta.apply(&state.rope));
+
+            assert!(state.edit(1, &[(1, delta)]));
+            let edited_tree = state.tree.clone();
+            let edited = state.edited.clone();
+            let changed =
+                state.reparse(&mut parser, text.as_bytes(), false).unwrap();
+
+            // the same tree and changes as when parsing from scratch
+            let full = parser.parse(text.as_bytes(), None).unwrap();
+            assert_eq!(full.root_node().to_sexp(), state.tree.root_node().to_sexp());
+            let mut full_changed = edited;
+            full_changed.extend(
+                edited_tree
+                    .changed_ranges(&full)
+                    .map(|range| Interval::new(range.start_byte, range.end_byte)),
+            );
+            assert_eq!(line_intervals(&state.rope, full_changed), changed);
+
+            // only the line typed on needs highlighting again
+            let line = state.rope.line_of_offset(offset);
+            assert_eq!(
+                vec![Interval::new(
+                    state.rope.offset_of_line(line),
+                    state.rope.offset_of_line(line + 1)
+                )],
+                changed
+            );
+        }
+    }
+
+    /// How long a keystroke in the middle of a ~1MB Rust file takes to parse
+    /// again from the edited tree and from scratch. Run with
+    /// `cargo test -p lapce-core --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_reparse_large_file() {
+        let source = rust_source(1 << 20);
+        let mut parser = Parser::new();
+        let (mut state, delta, _) = typed_in_middle(&mut parser, &source, "x");
+        let text = String::from(&delta.apply(&state.rope));
+
+        let started = Instant::now();
+        assert!(state.edit(1, &[(1, delta)]));
+        state.reparse(&mut parser, text.as_bytes(), false).unwrap();
+        let incremental = started.elapsed();
+        let started = Instant::now();
+        parser.parse(text.as_bytes(), None).unwrap();
+        let from_scratch = started.elapsed();
+        eprintln!(
+            "{} bytes: edit and reparse {:?}, full parse {:?}",
+            text.len(),
+            incremental,
+            from_scratch
+        );
+    }
+}
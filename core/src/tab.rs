@@ -9,7 +9,8 @@ use druid::{
     Point, Rect, RenderContext, Size, Target, Vec2, Widget, WidgetExt, WidgetId,
     WidgetPod, WindowConfig,
 };
-use lsp_types::{CallHierarchyOptions, DiagnosticSeverity};
+use lapce_proxy::lsp::DiagnosticCounts;
+use lsp_types::CallHierarchyOptions;
 
 use crate::{
     buffer::{BufferId, BufferNew, BufferState, BufferUpdate, UpdateEvent},
@@ -21,19 +22,20 @@ use crate::{
     completion::{CompletionContainer, CompletionNew, CompletionStatus},
     config::{Config, LapceTheme},
     data::{
-        EditorContent, EditorDiagnostic, EditorKind, EditorType, LapceMainSplitData,
+        EditReply, EditorDiagnostic, EditorKind, EditorType, LapceMainSplitData,
         LapceTabData,
     },
-    editor::{EditorLocationNew, LapceEditorView},
-    movement::{self, CursorMode, Selection},
+    editor::EditorLocationNew,
     palette::{NewPalette, PaletteViewLens},
     panel::{PanelPosition, PanelResizePosition},
+    remote::destination,
     scroll::LapceScrollNew,
     source_control::SourceControlNew,
-    split::LapceSplitNew,
+    split::LapceEditorSplit,
     state::{LapceWorkspace, LapceWorkspaceType},
     status::LapceStatusNew,
     terminal::TerminalPanel,
+    undo_history,
 };
 
 pub struct LapceTabNew {
@@ -53,15 +55,7 @@ pub struct LapceTabNew {
 
 impl LapceTabNew {
     pub fn new(data: &LapceTabData) -> Self {
-        let mut main_split = LapceSplitNew::new(*data.main_split.split_id);
-        for view_id in data.main_split.editors_order.iter() {
-            let editor = data.main_split.editors.get(view_id).unwrap();
-            main_split = main_split.with_flex_child(
-                LapceEditorView::new(editor).boxed(),
-                Some(editor.view_id),
-                1.0,
-            );
-        }
+        let main_split = LapceEditorSplit::new(data);
         let completion = CompletionContainer::new(&data.completion);
         let palette = NewPalette::new(
             &data.palette,
@@ -229,11 +223,36 @@ impl Widget<LapceTabData> for LapceTabNew {
                     LapceUICommand::LoadBuffer {
                         path,
                         content,
+                        language_id,
+                        large_file,
+                        binary,
+                        line_ending,
+                        indent,
+                        editable,
                         locations,
                     } => {
                         let buffer =
                             data.main_split.open_files.get_mut(path).unwrap();
-                        Arc::make_mut(buffer).load_content(content);
+                        let buffer = Arc::make_mut(buffer);
+                        buffer.language_id = language_id.clone();
+                        buffer.line_ending = *line_ending;
+                        buffer.indent = *indent;
+                        buffer.editable = *editable;
+                        if let Some(num_lines) = large_file {
+                            buffer.set_large_file(*num_lines);
+                        }
+                        if let Some(file_size) = binary {
+                            buffer.set_binary(*file_size);
+                        }
+                        buffer.load_content(content);
+                        buffer.restore_folds();
+                        undo_history::restore(buffer, &data.config);
+                        data.proxy.sync_buffer(
+                            buffer.id,
+                            path.clone(),
+                            buffer.rope.clone(),
+                            buffer.rev,
+                        );
                         for (view_id, location) in locations {
                             data.main_split.go_to_location(
                                 ctx,
@@ -252,6 +271,63 @@ impl Widget<LapceTabData> for LapceTabNew {
                             Arc::make_mut(&mut terminal).title = title.to_string();
                         }
                     }
+                    LapceUICommand::ConfigChanged(change) => {
+                        match Config::with_overlay(
+                            &change.settings,
+                            change.sources.clone(),
+                        ) {
+                            Ok(config) => data.config = Arc::new(config),
+                            Err(e) => eprintln!("bad settings: {}", e),
+                        }
+                        if !change.warnings.is_empty() {
+                            let warnings: Vec<String> = change
+                                .warnings
+                                .iter()
+                                .map(|warning| warning.to_string())
+                                .collect();
+                            for warning in warnings.iter() {
+                                eprintln!("{}", warning);
+                            }
+                            let message = warnings.join("\n");
+                            thread::spawn(move || {
+                                tinyfiledialogs::message_box_ok(
+                                    "Settings",
+                                    &message,
+                                    tinyfiledialogs::MessageBoxIcon::Warning,
+                                );
+                            });
+                        }
+                        if !change.untrusted.is_empty() && change.trusted.is_none() {
+                            let proxy = data.proxy.clone();
+                            let keys = change.untrusted.join("\n");
+                            thread::spawn(move || {
+                                let answer = tinyfiledialogs::message_box_yes_no(
+                                    "Workspace Settings",
+                                    &format!(
+                                        "The workspace settings set\n{}\n\n\
+                                         which could run programs. Trust the \
+                                         workspace?",
+                                        keys
+                                    ),
+                                    tinyfiledialogs::MessageBoxIcon::Warning,
+                                    tinyfiledialogs::YesNo::No,
+                                );
+                                proxy.trust_workspace(matches!(
+                                    answer,
+                                    tinyfiledialogs::YesNo::Yes
+                                ));
+                            });
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::RestoreTerminals(terminals) => {
+                        ctx.submit_command(Command::new(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::RestoreTerminals(terminals.clone()),
+                            Target::Widget(data.terminal.split_id),
+                        ));
+                        ctx.set_handled();
+                    }
                     LapceUICommand::CloseTerminal(id) => {
                         let terminal_panel = Arc::make_mut(&mut data.terminal);
                         if let Some(terminal) = terminal_panel.terminals.get_mut(id)
@@ -268,6 +344,10 @@ impl Widget<LapceTabData> for LapceTabNew {
                         }
                         ctx.set_handled();
                     }
+                    LapceUICommand::ExecutePluginCommand(id) => {
+                        data.proxy.execute_plugin_command(id, None);
+                        ctx.set_handled();
+                    }
                     LapceUICommand::UpdateDiffFiles(files) => {
                         let source_control = Arc::make_mut(&mut data.source_control);
                         source_control.diff_files = files
@@ -285,9 +365,15 @@ impl Widget<LapceTabData> for LapceTabNew {
                             .collect();
                         ctx.set_handled();
                     }
+                    LapceUICommand::UpdateGitStatus(status, branch) => {
+                        let source_control = Arc::make_mut(&mut data.source_control);
+                        source_control.status = status.clone();
+                        source_control.branch = branch.clone();
+                        ctx.set_handled();
+                    }
                     LapceUICommand::PublishDiagnostics(diagnostics) => {
                         let path = PathBuf::from(diagnostics.uri.path());
-                        let diagnostics = diagnostics
+                        let diagnostics: Vec<EditorDiagnostic> = diagnostics
                             .diagnostics
                             .iter()
                             .map(|d| EditorDiagnostic {
@@ -295,40 +381,84 @@ impl Widget<LapceTabData> for LapceTabNew {
                                 diagnositc: d.clone(),
                             })
                             .collect();
-                        data.main_split
-                            .diagnostics
-                            .insert(path, Arc::new(diagnostics));
-
-                        let mut errors = 0;
-                        let mut warnings = 0;
-                        for (_, diagnositics) in data.main_split.diagnostics.iter() {
-                            for diagnositic in diagnositics.iter() {
-                                if let Some(severity) =
-                                    diagnositic.diagnositc.severity
-                                {
-                                    match severity {
-                                        DiagnosticSeverity::Error => errors += 1,
-                                        DiagnosticSeverity::Warning => warnings += 1,
-                                        _ => (),
-                                    }
-                                }
-                            }
+                        if diagnostics.is_empty() {
+                            data.main_split.diagnostics.remove(&path);
+                        } else {
+                            data.main_split
+                                .diagnostics
+                                .insert(path, Arc::new(diagnostics));
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateDiagnosticsCounts(path, counts) => {
+                        if counts == &DiagnosticCounts::default() {
+                            data.main_split.diagnostics_counts.remove(path);
+                        } else {
+                            data.main_split
+                                .diagnostics_counts
+                                .insert(path.clone(), Arc::new(*counts));
                         }
+                        let counts = &data.main_split.diagnostics_counts;
+                        let errors: usize = counts.values().map(|c| c.errors).sum();
+                        let warnings: usize =
+                            counts.values().map(|c| c.warnings).sum();
                         data.main_split.error_count = errors;
                         data.main_split.warning_count = warnings;
 
                         ctx.set_handled();
                     }
+                    LapceUICommand::UpdateWorkProgress(progress) => {
+                        let key = (progress.server_id, progress.token.clone());
+                        if progress.done {
+                            data.main_split.work_progress.remove(&key);
+                        } else {
+                            data.main_split
+                                .work_progress
+                                .insert(key, Arc::new(progress.clone()));
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateServerCapabilities(language_id, caps) => {
+                        data.main_split
+                            .server_capabilities
+                            .insert(language_id.clone(), *caps);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateSignature(signature) => {
+                        let open = data
+                            .main_split
+                            .open_files
+                            .values()
+                            .any(|b| b.id == signature.buffer_id);
+                        data.main_split.signature =
+                            if open && !signature.signatures.is_empty() {
+                                Some(Arc::new(signature.clone()))
+                            } else {
+                                None
+                            };
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::DocumentFormat(path, rev, result) => {
+                        data.main_split.document_format(ctx, path, *rev, result);
+                        ctx.set_handled();
+                    }
                     LapceUICommand::DocumentFormatAndSave(path, rev, result) => {
                         data.main_split
                             .document_format_and_save(ctx, path, *rev, result);
                         ctx.set_handled();
                     }
+                    LapceUICommand::WillSaveEdits(path, rev, result) => {
+                        data.main_split
+                            .document_will_save_edits(ctx, path, *rev, result);
+                        ctx.set_handled();
+                    }
                     LapceUICommand::BufferSave(path, rev) => {
                         let buffer =
                             data.main_split.open_files.get_mut(path).unwrap();
                         if buffer.rev == *rev {
-                            Arc::make_mut(buffer).dirty = false;
+                            let buffer = Arc::make_mut(buffer);
+                            buffer.dirty = false;
+                            undo_history::persist(buffer, &data.config);
                         }
                         ctx.set_handled();
                     }
@@ -340,7 +470,16 @@ impl Widget<LapceTabData> for LapceTabNew {
                     } => {
                         let buffer =
                             data.main_split.open_files.get_mut(path).unwrap();
-                        Arc::make_mut(buffer).load_content(content);
+                        let buffer = Arc::make_mut(buffer);
+                        buffer.load_content(content);
+                        buffer.restore_folds();
+                        undo_history::restore(buffer, &data.config);
+                        data.proxy.sync_buffer(
+                            buffer.id,
+                            path.clone(),
+                            buffer.rope.clone(),
+                            buffer.rev,
+                        );
                         data.main_split.go_to_location(
                             ctx,
                             *editor_view_id,
@@ -383,6 +522,11 @@ impl Widget<LapceTabData> for LapceTabNew {
                         );
                         ctx.set_handled();
                     }
+                    LapceUICommand::PruneClosedEditors(missing) => {
+                        Arc::make_mut(&mut data.main_split.closed_editors)
+                            .retain(|editor| !missing.contains(&editor.path));
+                        ctx.set_handled();
+                    }
                     LapceUICommand::JumpToLocation(kind, location) => {
                         let editor_view_id =
                             data.main_split.editor_kind(kind).view_id;
@@ -468,58 +612,270 @@ impl Widget<LapceTabData> for LapceTabNew {
                             }
                         }
                     }
+                    LapceUICommand::UpdateInlayHints(path, resp) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            if buffer.rev == resp.rev {
+                                Arc::make_mut(buffer).inlay_hints =
+                                    Some(Arc::new(resp.clone()));
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::GlobalSearchResults(search_id, matches, _) => {
+                        let workspace = data.workspace.clone();
+                        Arc::make_mut(&mut data.palette).add_search_matches(
+                            ctx,
+                            *search_id,
+                            matches,
+                            workspace.as_deref(),
+                        );
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyReplace(session_id, ids) => {
+                        data.main_split.apply_replace(ctx, *session_id, ids);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyReplaceResponse(resp) => {
+                        for (path, reason) in resp.skipped.iter() {
+                            eprintln!("replace skipped {:?}: {}", path, reason);
+                        }
+                        if let Err(e) = data
+                            .main_split
+                            .apply_buffer_edits(ctx, &resp.buffer_edits)
+                        {
+                            eprintln!("replace not applied: {}", e);
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateEnclosingScopes(path, resp) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            let newer = buffer
+                                .enclosing_scopes
+                                .as_ref()
+                                .map(|scopes| scopes.rev > resp.rev)
+                                .unwrap_or(false);
+                            if !newer {
+                                Arc::make_mut(buffer).enclosing_scopes =
+                                    Some(Arc::new(resp.clone()));
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::AppendBufferLines(path, start_line, lines) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            Arc::make_mut(buffer).append_lines(*start_line, lines);
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::SetLineEnding(path, line_ending) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            Arc::make_mut(buffer).line_ending = *line_ending;
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::SetBufferEditable(path, editable) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            Arc::make_mut(buffer).editable = *editable;
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateFoldingRanges(path, resp) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            if buffer.rev == resp.rev {
+                                Arc::make_mut(buffer).update_folding_ranges(resp);
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::RefreshInlayHints => {
+                        for (_, buffer) in data.main_split.open_files.iter_mut() {
+                            if buffer.inlay_hints.is_some() {
+                                Arc::make_mut(buffer).inlay_hints = None;
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateDocumentLinks(path, resp) => {
+                        if let Some(buffer) =
+                            data.main_split.open_files.get_mut(path)
+                        {
+                            if buffer.rev == resp.rev {
+                                Arc::make_mut(buffer).document_links =
+                                    Some(Arc::new(resp.clone()));
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UpdateDocumentHighlights(resp) => {
+                        for (_, buffer) in data.main_split.open_files.iter_mut() {
+                            if buffer.id == resp.buffer_id {
+                                Arc::make_mut(buffer).document_highlights =
+                                    Some(Arc::new(resp.clone()));
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::RunCodeAction(buffer_id, action) => {
+                        data.main_split.apply_code_action(ctx, *buffer_id, action);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyWorkspaceEdit(edit, reply) => {
+                        data.main_split.apply_workspace_edit(
+                            ctx,
+                            edit,
+                            reply.clone(),
+                        );
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::PrepareRename(path, rev, offset, resp) => {
+                        if let Some(buffer) = data.main_split.open_files.get(path) {
+                            if buffer.rev == *rev {
+                                let (position, placeholder) =
+                                    buffer.rename_placeholder(*offset, resp);
+                                ctx.submit_command(Command::new(
+                                    LAPCE_UI_COMMAND,
+                                    LapceUICommand::RunPaletteRename(
+                                        buffer.id,
+                                        path.clone(),
+                                        *rev,
+                                        position,
+                                        placeholder,
+                                    ),
+                                    Target::Widget(data.palette.widget_id),
+                                ));
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::RenameSymbol(
+                        buffer_id,
+                        path,
+                        rev,
+                        position,
+                        new_name,
+                    ) => {
+                        data.main_split.rename(
+                            ctx, *buffer_id, path, *rev, *position, new_name,
+                        );
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::PathRenamed(old, new, edits) => {
+                        data.main_split.retarget_path(old, new);
+                        for edit in edits {
+                            data.main_split.apply_workspace_edit(
+                                ctx,
+                                edit,
+                                EditReply::default(),
+                            );
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyRename(revs, edit) => {
+                        data.main_split.apply_rename(ctx, revs, edit);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyToggleComment(path, view_id, resp) => {
+                        data.main_split.toggle_comment(ctx, path, *view_id, resp);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyConflictResolution(path, resp) => {
+                        data.main_split.resolve_conflict(ctx, path, resp);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::ApplyBufferEdits(edits, files, reply) => {
+                        let result = data.main_split.apply_buffer_edits(ctx, edits);
+                        if let Err(e) = result.as_ref() {
+                            eprintln!("can't apply workspace edit: {}", e);
+                            // the buffers changed or became read-only while
+                            // the files were written
+                            data.proxy.apply_file_edits(
+                                files.undo.clone(),
+                                Box::new(|result| {
+                                    if let Err(e) = result {
+                                        eprintln!(
+                                            "failed to undo workspace edit: {:?}",
+                                            e
+                                        );
+                                    }
+                                }),
+                            );
+                        }
+                        reply.send(result.map_err(|e| e.to_string()));
+                        ctx.set_handled();
+                    }
                     LapceUICommand::PaletteReferences(offset, locations) => {
                         if *offset == data.main_split.active_editor().cursor.offset()
                         {
-                            let locations = locations
-                                .iter()
-                                .map(|l| EditorLocationNew {
-                                    path: PathBuf::from(l.uri.path()),
-                                    position: Some(l.range.start.clone()),
-                                    scroll_offset: None,
-                                })
-                                .collect();
                             ctx.submit_command(Command::new(
                                 LAPCE_UI_COMMAND,
-                                LapceUICommand::RunPaletteReferences(locations),
+                                LapceUICommand::RunPaletteReferences(
+                                    locations.clone(),
+                                ),
                                 Target::Widget(data.palette.widget_id),
                             ));
                         }
                         ctx.set_handled();
                     }
                     LapceUICommand::ReloadBuffer(id, rev, new_content) => {
+                        data.main_split.reload_buffer(
+                            ctx,
+                            *id,
+                            *rev,
+                            new_content,
+                            &data.config,
+                        );
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::BufferSaved(id, rev) => {
                         for (_, buffer) in data.main_split.open_files.iter_mut() {
                             if &buffer.id == id {
-                                if buffer.rev + 1 == *rev {
+                                if buffer.rev == *rev {
                                     let buffer = Arc::make_mut(buffer);
-                                    buffer.load_content(new_content);
-                                    buffer.rev = *rev;
-
-                                    for (_, editor) in
-                                        data.main_split.editors.iter_mut()
-                                    {
-                                        match &editor.content {
-                                            EditorContent::Buffer(path) => {
-                                                if path == &buffer.path {
-                                                    if editor.cursor.offset()
-                                                        >= buffer.len()
-                                                    {
-                                                        let editor =
-                                                            Arc::make_mut(editor);
-                                                        if data.config.lapce.modal {
-                                                            editor.cursor =
-                                                                movement::Cursor::new(CursorMode::Normal(buffer.len() - 1), None);
-                                                        } else {
-                                                            editor.cursor =
-                                                                movement::Cursor::new(CursorMode::Insert(Selection::caret(buffer.len() - 1)), None);
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            EditorContent::None => {}
-                                        }
-                                    }
+                                    buffer.dirty = false;
+                                    undo_history::persist(buffer, &data.config);
+                                }
+                                break;
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::UnsavedBackups(backups) => {
+                        for backup in backups {
+                            match &backup.path {
+                                Some(path) => {
+                                    data.main_split
+                                        .unsaved_backups
+                                        .insert(path.clone(), backup.clone());
                                 }
+                                // the core has no buffers without a file
+                                None => eprintln!(
+                                    "unsaved untitled buffer in backup {}",
+                                    backup.id
+                                ),
+                            }
+                        }
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::RestoreBackup(path, id, content) => {
+                        data.main_split.restore_backup(ctx, path, id, content);
+                        ctx.set_handled();
+                    }
+                    LapceUICommand::BufferExternalChange(id, state) => {
+                        for (_, buffer) in data.main_split.open_files.iter_mut() {
+                            if &buffer.id == id {
+                                Arc::make_mut(buffer).external_change = *state;
                                 break;
                             }
                         }
@@ -555,7 +911,10 @@ impl Widget<LapceTabData> for LapceTabNew {
                                             rev: *rev,
                                             language: *language,
                                             highlights: buffer.styles.clone(),
-                                            semantic_tokens: true,
+                                            semantic_styles: buffer
+                                                .semantic_styles
+                                                .clone(),
+                                            deltas: Vec::new(),
                                         },
                                         tokens.to_owned(),
                                     ),
@@ -578,7 +937,11 @@ impl Widget<LapceTabData> for LapceTabNew {
                                 let dir = match &w.kind {
                                     LapceWorkspaceType::Local => dir.to_string(),
                                     LapceWorkspaceType::RemoteSSH(user, host) => {
-                                        format!("{} [{}@{}]", dir, user, host)
+                                        format!(
+                                            "{} [{}]",
+                                            dir,
+                                            destination(user, host)
+                                        )
                                     }
                                 };
                                 dir
@@ -597,14 +960,16 @@ impl Widget<LapceTabData> for LapceTabNew {
                         path,
                         rev,
                         highlights,
-                        semantic_tokens,
+                        semantic_styles,
+                        changed_lines,
                     } => {
                         let buffer =
                             data.main_split.open_files.get_mut(path).unwrap();
                         Arc::make_mut(buffer).update_styles(
                             *rev,
                             highlights.to_owned(),
-                            *semantic_tokens,
+                            semantic_styles.to_owned(),
+                            changed_lines.as_deref(),
                         );
                         ctx.set_handled();
                     }
@@ -1166,7 +1531,7 @@ impl Widget<LapceTabData> for LapceTabHeader {
                 let dir = match &w.kind {
                     LapceWorkspaceType::Local => dir.to_string(),
                     LapceWorkspaceType::RemoteSSH(user, host) => {
-                        format!("{} [{}@{}]", dir, user, host)
+                        format!("{} [{}]", dir, destination(user, host))
                     }
                 };
                 dir
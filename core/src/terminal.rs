@@ -32,7 +32,7 @@ use druid::{
 };
 use hashbrown::HashMap;
 use itertools::Itertools;
-use lapce_proxy::terminal::TermId;
+use lapce_proxy::terminal::{TermId, TerminalInfo};
 use parking_lot::Mutex;
 use serde::{Deserialize, Deserializer, Serialize};
 use unicode_width::UnicodeWidthChar;
@@ -530,20 +530,54 @@ impl LapceTerminalData {
         proxy: Arc<LapceProxy>,
     ) -> Self {
         let cwd = workspace.map(|w| w.path.clone());
-        let widget_id = WidgetId::next();
-        let view_id = WidgetId::next();
-        let term_id = TermId::next();
+        let data = Self::with_id(
+            TermId::next(),
+            split_id,
+            event_sink,
+            panel_widget_id,
+            proxy,
+        );
+        data.proxy.new_terminal(data.term_id, cwd, data.raw.clone());
+        data
+    }
+
+    /// For a shell the proxy kept from an earlier core, with the output it
+    /// kept replayed.
+    pub fn attach(
+        info: &TerminalInfo,
+        split_id: WidgetId,
+        event_sink: ExtEventSink,
+        panel_widget_id: Option<WidgetId>,
+        proxy: Arc<LapceProxy>,
+    ) -> Self {
+        let mut data = Self::with_id(
+            info.term_id,
+            split_id,
+            event_sink,
+            panel_widget_id,
+            proxy,
+        );
+        data.title = info.title.clone().unwrap_or_default();
+        data.proxy.terminal_attach(data.term_id, data.raw.clone());
+        data
+    }
+
+    fn with_id(
+        term_id: TermId,
+        split_id: WidgetId,
+        event_sink: ExtEventSink,
+        panel_widget_id: Option<WidgetId>,
+        proxy: Arc<LapceProxy>,
+    ) -> Self {
         let raw = Arc::new(Mutex::new(RawTerminal::new(
             term_id,
             proxy.clone(),
             event_sink,
         )));
-        proxy.new_terminal(term_id, cwd, raw.clone());
-
         Self {
             term_id,
-            widget_id,
-            view_id,
+            widget_id: WidgetId::next(),
+            view_id: WidgetId::next(),
             split_id,
             title: "".to_string(),
             panel_widget_id,
@@ -0,0 +1,411 @@
+//! The undo history of a buffer, kept across sessions in a file per path
+//! under the config directory. It's only taken back when the file still
+//! has the content the history ends with.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use xi_rope::multiset::{CountMatcher, Subset};
+use xi_rope::Rope;
+
+use crate::buffer::{BufferNew, Contents, Revision};
+use crate::config::Config;
+
+/// Bumped on any change to the format. Histories of other versions are
+/// dropped.
+pub(crate) const UNDO_HISTORY_VERSION: u32 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct UndoHistory {
+    pub(crate) version: u32,
+    /// The hash of the text after the last revision.
+    pub(crate) content_hash: u64,
+    pub(crate) revs: Vec<Revision>,
+    pub(crate) cur_undo: usize,
+    pub(crate) undos: BTreeSet<usize>,
+    pub(crate) undo_group_id: usize,
+    pub(crate) live_undos: Vec<usize>,
+    pub(crate) deletes_from_union: Subset,
+    pub(crate) undone_groups: BTreeSet<usize>,
+    pub(crate) tombstones: Rope,
+    pub(crate) cursor_offset: usize,
+}
+
+impl UndoHistory {
+    /// Drops the oldest quarter of the undo groups, merging their edits
+    /// into the text they're based on. `false` when there's nothing left
+    /// to drop.
+    fn prune(&mut self) -> bool {
+        // the group the text was loaded with has nothing to undo, and the
+        // group of the last revision keeps the head where it is
+        let last_group = match self.revs.last().map(|rev| &rev.edit) {
+            Some(Contents::Edit { undo_group, .. }) => Some(*undo_group),
+            _ => None,
+        };
+        let groups: BTreeSet<usize> = self
+            .revs
+            .iter()
+            .filter_map(|rev| match &rev.edit {
+                Contents::Edit { undo_group, .. } => Some(*undo_group),
+                Contents::Undo { .. } => None,
+            })
+            .filter(|group| *group != 0 && Some(*group) != last_group)
+            .collect();
+        if groups.is_empty() {
+            return false;
+        }
+        let n = (groups.len() / 4).max(1);
+        let gc_groups = groups.into_iter().take(n).collect();
+        self.gc(&gc_groups);
+        true
+    }
+
+    /// Removes the revisions of `gc_groups`, along with the text only
+    /// they had or deleted.
+    fn gc(&mut self, gc_groups: &BTreeSet<usize>) {
+        let mut gc_dels = Subset::new(self.union_len_before_first_rev());
+        for rev in self.revs.iter() {
+            if let Contents::Edit {
+                undo_group,
+                inserts,
+                deletes,
+            } = &rev.edit
+            {
+                if gc_groups.contains(undo_group) {
+                    if self.undone_groups.contains(undo_group) {
+                        if !inserts.is_empty() {
+                            gc_dels = gc_dels.transform_union(inserts);
+                        }
+                    } else {
+                        if !inserts.is_empty() {
+                            gc_dels = gc_dels.transform_expand(inserts);
+                        }
+                        if !deletes.is_empty() {
+                            gc_dels = gc_dels.union(deletes);
+                        }
+                    }
+                } else if !inserts.is_empty() {
+                    gc_dels = gc_dels.transform_expand(inserts);
+                }
+            }
+        }
+        if !gc_dels.is_empty() {
+            let not_in_tombstones = self.deletes_from_union.complement();
+            let dels_from_tombstones = gc_dels.transform_shrink(&not_in_tombstones);
+            self.tombstones = dels_from_tombstones.delete_from(&self.tombstones);
+            self.deletes_from_union =
+                self.deletes_from_union.transform_shrink(&gc_dels);
+        }
+
+        // walking back from the head, `gc_dels` is where the removed text
+        // is in the union string after each revision
+        let old_revs = std::mem::take(&mut self.revs);
+        for rev in old_revs.into_iter().rev() {
+            match rev.edit {
+                Contents::Edit {
+                    undo_group,
+                    inserts,
+                    deletes,
+                } => {
+                    let new_gc_dels = if inserts.is_empty() {
+                        None
+                    } else {
+                        Some(gc_dels.transform_shrink(&inserts))
+                    };
+                    if !gc_groups.contains(&undo_group) {
+                        let (inserts, deletes) = if gc_dels.is_empty() {
+                            (inserts, deletes)
+                        } else {
+                            (
+                                inserts.transform_shrink(&gc_dels),
+                                deletes.transform_shrink(&gc_dels),
+                            )
+                        };
+                        self.revs.push(Revision {
+                            max_undo_so_far: rev.max_undo_so_far,
+                            edit: Contents::Edit {
+                                undo_group,
+                                inserts,
+                                deletes,
+                            },
+                        });
+                    }
+                    if let Some(new_gc_dels) = new_gc_dels {
+                        gc_dels = new_gc_dels;
+                    }
+                }
+                Contents::Undo {
+                    toggled_groups,
+                    deletes_bitxor,
+                } => {
+                    let toggled_groups: BTreeSet<usize> =
+                        toggled_groups.difference(gc_groups).cloned().collect();
+                    if !toggled_groups.is_empty() {
+                        let deletes_bitxor = if gc_dels.is_empty() {
+                            deletes_bitxor
+                        } else {
+                            deletes_bitxor.transform_shrink(&gc_dels)
+                        };
+                        self.revs.push(Revision {
+                            max_undo_so_far: rev.max_undo_so_far,
+                            edit: Contents::Undo {
+                                toggled_groups,
+                                deletes_bitxor,
+                            },
+                        });
+                    }
+                }
+            }
+        }
+        self.revs.reverse();
+
+        let removed = self.live_undos[..self.cur_undo]
+            .iter()
+            .filter(|group| gc_groups.contains(group))
+            .count();
+        self.cur_undo -= removed;
+        self.live_undos.retain(|group| !gc_groups.contains(group));
+        self.undos = self.undos.difference(gc_groups).cloned().collect();
+        self.undone_groups =
+            self.undone_groups.difference(gc_groups).cloned().collect();
+    }
+
+    fn union_len_before_first_rev(&self) -> usize {
+        match self.revs.first().map(|rev| &rev.edit) {
+            Some(Contents::Edit { inserts, .. }) => {
+                inserts.count(CountMatcher::Zero)
+            }
+            Some(Contents::Undo { deletes_bitxor, .. }) => {
+                deletes_bitxor.count(CountMatcher::All)
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Writes the history of a buffer without edits in the background, so
+/// that it's there when the file is opened again.
+pub(crate) fn persist(buffer: &BufferNew, config: &Config) {
+    let max_size = config.editor.undo_history_size * 1024;
+    if buffer.dirty || buffer.large_file.is_some() || max_size == 0 {
+        return;
+    }
+    let path = buffer.path.clone();
+    let mut history = buffer.undo_history();
+    thread::spawn(move || {
+        if let Err(e) = save(&path, &mut history, max_size) {
+            eprintln!("failed to save the undo history of {:?}: {}", path, e);
+        }
+    });
+}
+
+fn save(path: &Path, history: &mut UndoHistory, max_size: usize) -> Result<()> {
+    match history_path(path) {
+        Some(history_path) => write_history(&history_path, history, max_size),
+        None => Ok(()),
+    }
+}
+
+/// Writes the history pruned down to `max_size` bytes, or removes the one
+/// written before when not even its last edit fits.
+fn write_history(
+    history_path: &Path,
+    history: &mut UndoHistory,
+    max_size: usize,
+) -> Result<()> {
+    loop {
+        let bytes = serde_json::to_vec(history)?;
+        if bytes.len() <= max_size {
+            fs::create_dir_all(history_path.parent().unwrap())?;
+            fs::write(history_path, bytes)?;
+            return Ok(());
+        }
+        if !history.prune() {
+            // even a single edit is too big to keep
+            let _ = fs::remove_file(history_path);
+            return Ok(());
+        }
+    }
+}
+
+/// Gives a buffer just loaded the history of its file from an earlier
+/// session.
+pub(crate) fn restore(buffer: &mut BufferNew, config: &Config) {
+    if buffer.large_file.is_some() || config.editor.undo_history_size == 0 {
+        return;
+    }
+    if let Some(history) = load(&buffer.path, &buffer.rope) {
+        buffer.restore_undo_history(history);
+    }
+}
+
+/// The history kept for `path`, if it ends with `content`.
+fn load(path: &Path, content: &Rope) -> Option<UndoHistory> {
+    read_history(&history_path(path)?, content)
+}
+
+fn read_history(history_path: &Path, content: &Rope) -> Option<UndoHistory> {
+    let bytes = fs::read(history_path).ok()?;
+    let history: UndoHistory = serde_json::from_slice(&bytes).ok()?;
+    (history.version == UNDO_HISTORY_VERSION
+        && history.content_hash == content_hash(content))
+    .then(|| history)
+}
+
+pub(crate) fn content_hash(content: &Rope) -> u64 {
+    let mut hash = Fnv::new();
+    for chunk in content.iter_chunks(..) {
+        hash.write(chunk.as_bytes());
+    }
+    hash.0
+}
+
+/// The histories are named by a hash of the path of their file.
+fn history_path(path: &Path) -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("", "", "Lapce")?;
+    let mut hash = Fnv::new();
+    hash.write(path.to_string_lossy().as_bytes());
+    Some(
+        proj_dirs
+            .config_dir()
+            .join("undo")
+            .join(format!("{:016x}.json", hash.0)),
+    )
+}
+
+/// FNV-1a, which unlike the hasher of std stays the same across releases.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crossbeam_channel::unbounded;
+    use druid::WidgetId;
+
+    use super::*;
+    use crate::buffer::EditType;
+    use crate::movement::Selection;
+    use crate::proxy::LapceProxy;
+
+    fn new_buffer(text: &str) -> (BufferNew, Arc<LapceProxy>) {
+        let (update_sender, _) = unbounded();
+        let mut buffer =
+            BufferNew::new(PathBuf::from("undo.txt"), Arc::new(update_sender));
+        // with no proxy running to be sent the edits
+        buffer.local = true;
+        buffer.load_content(text);
+        let (term_sender, _) = unbounded();
+        let proxy = LapceProxy::new(WidgetId::next(), term_sender);
+        (buffer, Arc::new(proxy))
+    }
+
+    /// A buffer of "x" with each of `appended` appended as an undo group
+    /// of its own.
+    fn edited_buffer(appended: &[&str]) -> (BufferNew, Arc<LapceProxy>) {
+        let (mut buffer, proxy) = new_buffer("x");
+        for text in appended {
+            let end = Selection::caret(buffer.len());
+            buffer.edit_regions(vec![(&end, *text)], proxy.clone(), EditType::Other);
+        }
+        (buffer, proxy)
+    }
+
+    fn history_file(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("lapce-test-{}-{}", name, std::process::id()))
+            .join("history.json")
+    }
+
+    #[test]
+    fn test_save_load() {
+        let (buffer, _) = edited_buffer(&["a", "b"]);
+        let path = history_file("undo-save-load");
+        write_history(&path, &mut buffer.undo_history(), usize::MAX).unwrap();
+
+        let (mut buffer, proxy) = new_buffer("xab");
+        let history = read_history(&path, &buffer.rope);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        buffer.restore_undo_history(history.unwrap());
+        buffer.do_undo(proxy.clone()).unwrap();
+        assert_eq!("xa", buffer.rope.to_string());
+        buffer.do_undo(proxy.clone()).unwrap();
+        assert_eq!("x", buffer.rope.to_string());
+        assert!(buffer.do_undo(proxy.clone()).is_none());
+        buffer.do_redo(proxy).unwrap();
+        assert_eq!("xa", buffer.rope.to_string());
+    }
+
+    #[test]
+    fn test_prune() {
+        let appended = ["1", "2", "3", "4", "5", "6", "7", "8"];
+        let (buffer, _) = edited_buffer(&appended);
+        let mut history = buffer.undo_history();
+        let max_size = serde_json::to_vec(&history).unwrap().len() - 1;
+        let path = history_file("undo-prune");
+        write_history(&path, &mut history, max_size).unwrap();
+        let size = fs::metadata(&path).unwrap().len() as usize;
+
+        let (mut buffer, proxy) = new_buffer("x12345678");
+        let history = read_history(&path, &buffer.rope);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(size <= max_size);
+        buffer.restore_undo_history(history.unwrap());
+
+        // the oldest edits are kept in the text, the newest still undo
+        let mut kept = appended.len();
+        while buffer.do_undo(proxy.clone()).is_some() {
+            kept -= 1;
+            assert_eq!(
+                format!("x{}", appended[..kept].concat()),
+                buffer.rope.to_string()
+            );
+        }
+        assert!(kept > 0);
+        assert!(kept < appended.len());
+        while buffer.do_redo(proxy.clone()).is_some() {}
+        assert_eq!("x12345678", buffer.rope.to_string());
+    }
+
+    #[test]
+    fn test_load_other_content() {
+        let (buffer, _) = edited_buffer(&["a"]);
+        let path = history_file("undo-other-content");
+        write_history(&path, &mut buffer.undo_history(), usize::MAX).unwrap();
+        let other = read_history(&path, &Rope::from("xb"));
+        let same = read_history(&path, &Rope::from("xa"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(other.is_none());
+        assert!(same.is_some());
+    }
+
+    #[test]
+    fn test_load_other_version() {
+        let (buffer, _) = edited_buffer(&["a"]);
+        let mut history = buffer.undo_history();
+        history.version = UNDO_HISTORY_VERSION + 1;
+        let path = history_file("undo-other-version");
+        write_history(&path, &mut history, usize::MAX).unwrap();
+        let loaded = read_history(&path, &Rope::from("xa"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(loaded.is_none());
+    }
+}
@@ -0,0 +1,1194 @@
+//! Vim's operators, `d`, `c`, `y`, `>` and `<`, and what they work on, a
+//! motion as in `d2w` or a text object as in `ci(`. The keys typed after an
+//! operator are fed to a `VimState`, which answers with the range the
+//! operator applies to once they make one, and keeps the last change for
+//! `.` to repeat. It knows nothing of the editor but its text.
+
+use xi_rope::{Cursor, Rope, RopeInfo};
+
+use crate::buffer::{get_word_property, WordProperty};
+use crate::state::Mode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+}
+
+impl Operator {
+    /// The key it's typed with, typed again to have it apply to lines.
+    fn key(&self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Change => 'c',
+            Operator::Yank => 'y',
+            Operator::Indent => '>',
+            Operator::Outdent => '<',
+        }
+    }
+}
+
+/// The range an operator applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VimEdit {
+    pub operator: Operator,
+    pub start: usize,
+    pub end: usize,
+    /// It's whole lines, `end` being at the start of the line after them.
+    pub linewise: bool,
+    /// The mode the editor is left in.
+    pub mode: Mode,
+}
+
+impl VimEdit {
+    /// The first and the last line of the range.
+    pub fn lines(&self, text: &Rope) -> (usize, usize) {
+        let last = self.end.saturating_sub(1).max(self.start);
+        (text.line_of_offset(self.start), text.line_of_offset(last))
+    }
+
+    /// What's deleted, the last line of a text without a final newline
+    /// taking the newline before it.
+    pub fn delete_range(&self, text: &Rope) -> (usize, usize) {
+        if self.linewise
+            && self.start > 0
+            && self.end == text.len()
+            && char_before(text, self.end) != Some('\n')
+        {
+            (self.start - 1, self.end)
+        } else {
+            (self.start, self.end)
+        }
+    }
+
+    /// What goes in the register, lines ending with a newline.
+    pub fn register_text(&self, text: &Rope) -> String {
+        let mut content = text.slice_to_cow(self.start..self.end).to_string();
+        if self.linewise && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VimResult {
+    /// More keys are needed.
+    Pending,
+    /// The keys make nothing the operator can apply to.
+    Cancelled,
+    Apply(VimEdit),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Change {
+    operator: Operator,
+    /// The count typed before the operator.
+    count: Option<usize>,
+    /// The keys typed after it.
+    keys: String,
+    /// What was typed in insert mode after a `c`.
+    inserted: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VimState {
+    pending: Option<Change>,
+    /// The `c` that entered insert mode, until insert mode is left.
+    inserting: Option<Change>,
+    last_change: Option<Change>,
+}
+
+impl VimState {
+    pub fn start_operator(&mut self, operator: Operator, count: Option<usize>) {
+        self.pending = Some(Change {
+            operator,
+            count,
+            keys: String::new(),
+            inserted: String::new(),
+        });
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// Takes the keys typed after the operator, with the text and the
+    /// cursor they apply to.
+    pub fn feed(&mut self, keys: &str, text: &Rope, offset: usize) -> VimResult {
+        let pending = match self.pending.as_mut() {
+            Some(pending) => pending,
+            None => return VimResult::Cancelled,
+        };
+        pending.keys.push_str(keys);
+        let (count, target) = match parse(pending.operator, &pending.keys) {
+            Parsed::Incomplete => return VimResult::Pending,
+            Parsed::Invalid => {
+                self.pending = None;
+                return VimResult::Cancelled;
+            }
+            Parsed::Complete(count, target) => (count, target),
+        };
+        let change = self.pending.take().unwrap();
+        let count = multiply(change.count, count);
+        let edit = match apply(change.operator, &target, count, text, offset) {
+            Some(edit) => edit,
+            None => return VimResult::Cancelled,
+        };
+        match change.operator {
+            Operator::Yank => {}
+            Operator::Change => self.inserting = Some(change),
+            _ => self.last_change = Some(change),
+        }
+        VimResult::Apply(edit)
+    }
+
+    /// Keeps what's typed in insert mode for the `c` that entered it.
+    pub fn record_insert(&mut self, s: &str) {
+        if let Some(change) = self.inserting.as_mut() {
+            change.inserted.push_str(s);
+        }
+    }
+
+    /// Insert mode was left, which ends the `c` that entered it.
+    pub fn finish_insert(&mut self) {
+        if let Some(change) = self.inserting.take() {
+            self.last_change = Some(change);
+        }
+    }
+
+    /// The last change again at `offset`, with what was inserted after it.
+    /// A count replaces that of the change, and is kept for the next time.
+    pub fn repeat(
+        &mut self,
+        count: Option<usize>,
+        text: &Rope,
+        offset: usize,
+    ) -> Option<(VimEdit, String)> {
+        let change = self.last_change.as_mut()?;
+        let (keys_count, target) = match parse(change.operator, &change.keys) {
+            Parsed::Complete(count, target) => (count, target),
+            _ => return None,
+        };
+        let count = match count {
+            Some(count) => {
+                change.count = Some(count);
+                change.keys = change
+                    .keys
+                    .trim_start_matches(|c: char| c.is_ascii_digit())
+                    .to_string();
+                Some(count)
+            }
+            None => multiply(change.count, keys_count),
+        };
+        let edit = apply(change.operator, &target, count, text, offset)?;
+        Some((edit, change.inserted.clone()))
+    }
+}
+
+/// The counts typed before and after the operator, as in `2d3w`.
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    /// `w`, `W` when big.
+    WordForward(bool),
+    WordBackward(bool),
+    WordEnd(bool),
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    /// `f`, `t`, `F` and `T`, `till` stopping before the char.
+    Find {
+        c: char,
+        forward: bool,
+        till: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    /// The operator typed again, as in `dd`.
+    Lines,
+    Motion(Motion),
+    Object {
+        around: bool,
+        object: char,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    Incomplete,
+    Invalid,
+    Complete(Option<usize>, Target),
+}
+
+fn parse(operator: Operator, keys: &str) -> Parsed {
+    // a 0 that isn't after a digit is the motion to the start of the line
+    let digits = keys
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .count()
+        .min(if keys.starts_with('0') { 0 } else { keys.len() });
+    let (count, rest) = keys.split_at(digits);
+    let count = match count {
+        "" => None,
+        count => match count.parse() {
+            Ok(count) => Some(count),
+            Err(_) => return Parsed::Invalid,
+        },
+    };
+
+    let mut chars = rest.chars();
+    let first = match chars.next() {
+        Some(c) => c,
+        None => return Parsed::Incomplete,
+    };
+    let second = chars.next();
+    if chars.next().is_some() {
+        return Parsed::Invalid;
+    }
+    let target = match (first, second) {
+        (c, None) if c == operator.key() => Target::Lines,
+        ('i' | 'a' | 'f' | 't' | 'F' | 'T' | 'g', None) => {
+            return Parsed::Incomplete
+        }
+        ('i' | 'a', Some(object)) if is_object(object) => Target::Object {
+            around: first == 'a',
+            object,
+        },
+        ('f' | 't' | 'F' | 'T', Some(c)) => Target::Motion(Motion::Find {
+            c,
+            forward: first == 'f' || first == 't',
+            till: first == 't' || first == 'T',
+        }),
+        ('g', Some('g')) => Target::Motion(Motion::FirstLine),
+        (c, None) => match motion(c) {
+            Some(motion) => Target::Motion(motion),
+            None => return Parsed::Invalid,
+        },
+        _ => return Parsed::Invalid,
+    };
+    Parsed::Complete(count, target)
+}
+
+fn motion(c: char) -> Option<Motion> {
+    Some(match c {
+        'h' => Motion::Left,
+        'l' => Motion::Right,
+        'k' => Motion::Up,
+        'j' => Motion::Down,
+        'w' => Motion::WordForward(false),
+        'W' => Motion::WordForward(true),
+        'b' => Motion::WordBackward(false),
+        'B' => Motion::WordBackward(true),
+        'e' => Motion::WordEnd(false),
+        'E' => Motion::WordEnd(true),
+        '0' => Motion::LineStart,
+        '^' => Motion::FirstNonBlank,
+        '$' => Motion::LineEnd,
+        'G' => Motion::LastLine,
+        _ => return None,
+    })
+}
+
+fn is_object(c: char) -> bool {
+    matches!(c, 'w' | 'W' | 'p' | '"' | '\'' | '`') || brackets(c).is_some()
+}
+
+fn brackets(c: char) -> Option<(char, char)> {
+    Some(match c {
+        '(' | ')' | 'b' => ('(', ')'),
+        '[' | ']' => ('[', ']'),
+        '{' | '}' | 'B' => ('{', '}'),
+        '<' | '>' => ('<', '>'),
+        _ => return None,
+    })
+}
+
+/// What a motion or an object covers, before it's made an edit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Span {
+    Chars(usize, usize),
+    /// The first and the last line.
+    Lines(usize, usize),
+}
+
+fn apply(
+    operator: Operator,
+    target: &Target,
+    count: Option<usize>,
+    text: &Rope,
+    offset: usize,
+) -> Option<VimEdit> {
+    let n = count.unwrap_or(1).max(1);
+    let line = text.line_of_offset(offset);
+    let span = match *target {
+        Target::Lines => Span::Lines(line, (line + n - 1).min(last_line(text))),
+        Target::Motion(motion) => {
+            motion_span(operator, motion, count, text, offset)?
+        }
+        Target::Object { around, object } => {
+            object_span(object, around, text, offset)?
+        }
+    };
+    let (start, end, linewise) = match span {
+        // the lines are kept, with their indentation, for what's typed
+        Span::Lines(first, last) if operator == Operator::Change => (
+            first_non_blank(text, first),
+            line_end(text, last).max(first_non_blank(text, first)),
+            false,
+        ),
+        Span::Lines(first, last) => {
+            (text.offset_of_line(first), line_after(text, last), true)
+        }
+        Span::Chars(start, end) => (start, end, false),
+    };
+    if start >= end && operator != Operator::Change {
+        return None;
+    }
+    Some(VimEdit {
+        operator,
+        start,
+        end,
+        linewise,
+        mode: if operator == Operator::Change {
+            Mode::Insert
+        } else {
+            Mode::Normal
+        },
+    })
+}
+
+fn motion_span(
+    operator: Operator,
+    motion: Motion,
+    count: Option<usize>,
+    text: &Rope,
+    offset: usize,
+) -> Option<Span> {
+    let n = count.unwrap_or(1).max(1);
+    let line = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line);
+    let span = match motion {
+        Motion::Left => {
+            let mut start = offset;
+            for _ in 0..n {
+                match prev_offset(text, start) {
+                    Some(prev) if prev >= line_start => start = prev,
+                    _ => break,
+                }
+            }
+            Span::Chars(start, offset)
+        }
+        Motion::Right => {
+            let line_end = line_end(text, line);
+            let mut end = offset;
+            for _ in 0..n {
+                match next_offset(text, end) {
+                    Some(next) if next <= line_end => end = next,
+                    _ => break,
+                }
+            }
+            Span::Chars(offset, end)
+        }
+        Motion::Up => Span::Lines(line.checked_sub(n)?, line),
+        Motion::Down => {
+            if line + n > last_line(text) {
+                return None;
+            }
+            Span::Lines(line, line + n)
+        }
+        Motion::LineStart => Span::Chars(line_start, offset),
+        Motion::FirstNonBlank => {
+            let non_blank = first_non_blank(text, line);
+            Span::Chars(offset.min(non_blank), offset.max(non_blank))
+        }
+        Motion::LineEnd => {
+            let last = (line + n - 1).min(last_line(text));
+            Span::Chars(offset, line_end(text, last))
+        }
+        Motion::FirstLine | Motion::LastLine => {
+            let target = match count {
+                Some(count) => count.saturating_sub(1).min(last_line(text)),
+                None if motion == Motion::FirstLine => 0,
+                None => last_line(text),
+            };
+            Span::Lines(line.min(target), line.max(target))
+        }
+        Motion::Find { c, forward, till } => {
+            let mut found = None;
+            let mut cursor = Cursor::new(text, offset);
+            if forward {
+                let line_end = line_end(text, line);
+                cursor.next_codepoint();
+                let mut remaining = n;
+                while cursor.pos() < line_end {
+                    let pos = cursor.pos();
+                    if cursor.next_codepoint()? == c {
+                        remaining -= 1;
+                        if remaining == 0 {
+                            found = Some(pos);
+                            break;
+                        }
+                    }
+                }
+                let pos = found?;
+                if till {
+                    Span::Chars(offset, pos)
+                } else {
+                    Span::Chars(offset, pos + c.len_utf8())
+                }
+            } else {
+                let mut remaining = n;
+                while cursor.pos() > line_start {
+                    if cursor.prev_codepoint()? == c {
+                        remaining -= 1;
+                        if remaining == 0 {
+                            found = Some(cursor.pos());
+                            break;
+                        }
+                    }
+                }
+                let pos = found?;
+                if till {
+                    Span::Chars(pos + c.len_utf8(), offset)
+                } else {
+                    Span::Chars(pos, offset)
+                }
+            }
+        }
+        Motion::WordForward(big) => {
+            let on_word = char_at(text, offset)
+                .map(|c| matches!(class(c, big), Class::Word | Class::Punct))
+                .unwrap_or(false);
+            if operator == Operator::Change && on_word {
+                // `cw` on a word changes to its end, as `ce` would
+                let mut end = word_run(text, offset, big).1;
+                for _ in 1..n {
+                    match word_end(text, prev_offset(text, end)?, big) {
+                        Some(last) => end = next_offset(text, last)?,
+                        None => break,
+                    }
+                }
+                return Some(Span::Chars(offset, end));
+            }
+            let mut pos = offset;
+            let mut last_word = offset;
+            for _ in 0..n {
+                last_word = pos;
+                pos = next_word_start(text, pos, big);
+            }
+            // the last word moved over ends its line, and so does the
+            // motion, rather than at the next line
+            let word_line = text.line_of_offset(last_word);
+            if text.line_of_offset(pos) > word_line {
+                pos = line_end(text, word_line).max(last_word);
+            }
+            Span::Chars(offset, pos)
+        }
+        Motion::WordEnd(big) => {
+            let mut end = None;
+            let mut pos = offset;
+            for _ in 0..n {
+                match word_end(text, pos, big) {
+                    Some(last) => {
+                        pos = last;
+                        end = Some(last);
+                    }
+                    None => break,
+                }
+            }
+            Span::Chars(offset, next_offset(text, end?)?)
+        }
+        Motion::WordBackward(big) => {
+            let mut pos = offset;
+            for _ in 0..n {
+                match prev_word_start(text, pos, big) {
+                    Some(start) => pos = start,
+                    None => break,
+                }
+            }
+            Span::Chars(pos, offset)
+        }
+    };
+    Some(span)
+}
+
+fn object_span(
+    object: char,
+    around: bool,
+    text: &Rope,
+    offset: usize,
+) -> Option<Span> {
+    match object {
+        'w' | 'W' => word_object(text, offset, around, object == 'W'),
+        'p' => Some(paragraph_object(text, offset, around)),
+        '"' | '\'' | '`' => quote_object(text, offset, object, around),
+        c => {
+            let (open, close) = brackets(c)?;
+            bracket_object(text, offset, open, close, around)
+        }
+    }
+}
+
+fn word_object(text: &Rope, offset: usize, around: bool, big: bool) -> Option<Span> {
+    let c = char_at(text, offset)?;
+    let kind = class(c, big);
+    if kind == Class::Lf {
+        return None;
+    }
+    let (mut start, mut end) = word_run(text, offset, big);
+    if !around {
+        return Some(Span::Chars(start, end));
+    }
+    if kind == Class::Space {
+        // the blanks and the word after them
+        if let Some(c) = char_at(text, end) {
+            if matches!(class(c, big), Class::Word | Class::Punct) {
+                end = word_run(text, end, big).1;
+            }
+        }
+        return Some(Span::Chars(start, end));
+    }
+    match char_at(text, end) {
+        Some(c) if class(c, big) == Class::Space => {
+            end = word_run(text, end, big).1;
+        }
+        // a word at the end of its line takes the blanks before it, unless
+        // they're the indentation
+        _ => {
+            let line_start = text.offset_of_line(text.line_of_offset(start));
+            if let Some(prev) = prev_offset(text, start) {
+                if char_before(text, start)
+                    .map(|c| class(c, big) == Class::Space)
+                    .unwrap_or(false)
+                {
+                    let blank_start = word_run(text, prev, big).0;
+                    if blank_start > line_start {
+                        start = blank_start;
+                    }
+                }
+            }
+        }
+    }
+    Some(Span::Chars(start, end))
+}
+
+/// The strings of a line are its quotes taken two by two from its start.
+fn quote_object(
+    text: &Rope,
+    offset: usize,
+    quote: char,
+    around: bool,
+) -> Option<Span> {
+    let line = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line);
+    let line_text = text.slice_to_cow(line_start..line_end(text, line));
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (i, c) in line_text.char_indices() {
+        if c == quote && !escaped {
+            quotes.push(line_start + i);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    let pairs: Vec<(usize, usize)> = quotes
+        .chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    // the string the cursor is in, or the next one on the line
+    let (open, close) = pairs
+        .iter()
+        .find(|(open, close)| *open <= offset && offset <= *close)
+        .or_else(|| pairs.iter().find(|(open, _)| *open > offset))
+        .copied()?;
+    if !around {
+        return Some(Span::Chars(open + quote.len_utf8(), close));
+    }
+    let mut start = open;
+    let mut end = close + quote.len_utf8();
+    let blank = |c: Option<char>| c == Some(' ') || c == Some('\t');
+    if blank(char_at(text, end)) {
+        while blank(char_at(text, end)) {
+            end += 1;
+        }
+    } else {
+        let mut blank_start = start;
+        while blank_start > line_start && blank(char_before(text, blank_start)) {
+            blank_start -= 1;
+        }
+        if blank_start > line_start {
+            start = blank_start;
+        }
+    }
+    Some(Span::Chars(start, end))
+}
+
+/// The innermost pair of brackets around the cursor, or the next pair on
+/// its line.
+fn bracket_object(
+    text: &Rope,
+    offset: usize,
+    open: char,
+    close: char,
+    around: bool,
+) -> Option<Span> {
+    let start = match find_open(text, offset, open, close) {
+        Some(start) => start,
+        None => {
+            let line_end = line_end(text, text.line_of_offset(offset));
+            let line_text = text.slice_to_cow(offset..line_end);
+            offset + line_text.find(open)?
+        }
+    };
+    let end = find_close(text, start, open, close)?;
+    if around {
+        return Some(Span::Chars(start, end + close.len_utf8()));
+    }
+    let mut inner_start = start + open.len_utf8();
+    let mut inner_end = end;
+    // the lines between brackets on lines of their own
+    let close_line_start = text.offset_of_line(text.line_of_offset(end));
+    if char_at(text, inner_start) == Some('\n')
+        && close_line_start > inner_start
+        && text.slice_to_cow(close_line_start..end).trim().is_empty()
+    {
+        inner_start += 1;
+        inner_end = close_line_start;
+    }
+    Some(Span::Chars(inner_start, inner_end.max(inner_start)))
+}
+
+fn find_open(text: &Rope, offset: usize, open: char, close: char) -> Option<usize> {
+    if char_at(text, offset) == Some(open) {
+        return Some(offset);
+    }
+    let mut depth = 0;
+    let mut cursor = Cursor::new(text, offset);
+    while let Some(c) = cursor.prev_codepoint() {
+        if c == close {
+            depth += 1;
+        } else if c == open {
+            if depth == 0 {
+                return Some(cursor.pos());
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+fn find_close(
+    text: &Rope,
+    open_offset: usize,
+    open: char,
+    close: char,
+) -> Option<usize> {
+    let mut depth = 0;
+    let mut cursor = Cursor::new(text, open_offset);
+    cursor.next_codepoint();
+    loop {
+        let pos = cursor.pos();
+        let c = cursor.next_codepoint()?;
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                return Some(pos);
+            }
+            depth -= 1;
+        }
+    }
+}
+
+/// The lines around the cursor that are all blank or all not, and for
+/// `ap` the lines after them, or before them at the end of the text.
+fn paragraph_object(text: &Rope, offset: usize, around: bool) -> Span {
+    let last = last_line(text);
+    let line = text.line_of_offset(offset);
+    let blank = is_blank(text, line);
+    let mut first = line;
+    while first > 0 && is_blank(text, first - 1) == blank {
+        first -= 1;
+    }
+    let mut end = line;
+    while end < last && is_blank(text, end + 1) == blank {
+        end += 1;
+    }
+    if around {
+        if end < last {
+            let next = is_blank(text, end + 1);
+            while end < last && is_blank(text, end + 1) == next {
+                end += 1;
+            }
+        } else if first > 0 {
+            let prev = is_blank(text, first - 1);
+            while first > 0 && is_blank(text, first - 1) == prev {
+                first -= 1;
+            }
+        }
+    }
+    Span::Lines(first, end)
+}
+
+/// The regions of the lines `>` puts a unit of indentation at, those that
+/// aren't blank.
+pub fn indent_regions(
+    text: &Rope,
+    first: usize,
+    last: usize,
+) -> Vec<(usize, usize)> {
+    (first..=last)
+        .filter(|line| !is_blank(text, *line))
+        .map(|line| {
+            let start = text.offset_of_line(line);
+            (start, start)
+        })
+        .collect()
+}
+
+/// The indentation of the lines `<` deletes, a tab or up to `width`
+/// spaces at the start of each.
+pub fn outdent_regions(
+    text: &Rope,
+    first: usize,
+    last: usize,
+    width: usize,
+) -> Vec<(usize, usize)> {
+    (first..=last)
+        .filter_map(|line| {
+            let start = text.offset_of_line(line);
+            let mut end = start;
+            for c in text.slice_to_cow(start..line_end(text, line)).chars() {
+                match c {
+                    '\t' if end == start => {
+                        end += 1;
+                        break;
+                    }
+                    ' ' if end - start < width => end += 1,
+                    _ => break,
+                }
+            }
+            (end > start).then(|| (start, end))
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Space,
+    Lf,
+    Punct,
+    Word,
+}
+
+/// A big word, as in `W`, is any run of chars that aren't blank.
+fn class(c: char, big: bool) -> Class {
+    match get_word_property(c) {
+        WordProperty::Lf => Class::Lf,
+        WordProperty::Space => Class::Space,
+        _ if big => Class::Word,
+        WordProperty::Punctuation => Class::Punct,
+        WordProperty::Other => Class::Word,
+    }
+}
+
+/// The run of chars of the class of that at `offset`, a newline being a
+/// run of its own.
+fn word_run(text: &Rope, offset: usize, big: bool) -> (usize, usize) {
+    let kind = match char_at(text, offset) {
+        Some(c) => class(c, big),
+        None => return (offset, offset),
+    };
+    let mut cursor = Cursor::new(text, offset);
+    let mut start = offset;
+    while kind != Class::Lf {
+        match cursor.prev_codepoint() {
+            Some(c) if class(c, big) == kind => start = cursor.pos(),
+            _ => break,
+        }
+    }
+    let mut cursor = Cursor::new(text, offset);
+    cursor.next_codepoint();
+    let mut end = cursor.pos();
+    while kind != Class::Lf {
+        match cursor.next_codepoint() {
+            Some(c) if class(c, big) == kind => end = cursor.pos(),
+            _ => break,
+        }
+    }
+    (start, end)
+}
+
+/// Where `w` goes, an empty line being a word.
+fn next_word_start(text: &Rope, offset: usize, big: bool) -> usize {
+    let mut pos = offset;
+    if let Some(c) = char_at(text, pos) {
+        if matches!(class(c, big), Class::Word | Class::Punct) {
+            pos = word_run(text, pos, big).1;
+        }
+    }
+    loop {
+        match char_at(text, pos).map(|c| class(c, big)) {
+            Some(Class::Space) => pos += 1,
+            Some(Class::Lf) => {
+                pos += 1;
+                if char_at(text, pos) == Some('\n') {
+                    return pos;
+                }
+            }
+            _ => return pos,
+        }
+    }
+}
+
+/// The last char of the word `e` goes to.
+fn word_end(text: &Rope, offset: usize, big: bool) -> Option<usize> {
+    let mut pos = next_offset(text, offset)?;
+    loop {
+        match class(char_at(text, pos)?, big) {
+            Class::Space | Class::Lf => pos = next_offset(text, pos)?,
+            _ => break,
+        }
+    }
+    prev_offset(text, word_run(text, pos, big).1)
+}
+
+/// Where `b` goes.
+fn prev_word_start(text: &Rope, offset: usize, big: bool) -> Option<usize> {
+    let mut pos = prev_offset(text, offset)?;
+    while matches!(class(char_at(text, pos)?, big), Class::Space | Class::Lf) {
+        pos = prev_offset(text, pos)?;
+    }
+    Some(word_run(text, pos, big).0)
+}
+
+fn char_at(text: &Rope, offset: usize) -> Option<char> {
+    Cursor::<RopeInfo>::new(text, offset).next_codepoint()
+}
+
+fn char_before(text: &Rope, offset: usize) -> Option<char> {
+    Cursor::<RopeInfo>::new(text, offset).prev_codepoint()
+}
+
+fn next_offset(text: &Rope, offset: usize) -> Option<usize> {
+    let mut cursor = Cursor::new(text, offset);
+    cursor.next_codepoint()?;
+    Some(cursor.pos())
+}
+
+fn prev_offset(text: &Rope, offset: usize) -> Option<usize> {
+    let mut cursor = Cursor::new(text, offset);
+    cursor.prev_codepoint()?;
+    Some(cursor.pos())
+}
+
+/// The last line, not counting the empty one after a final newline.
+fn last_line(text: &Rope) -> usize {
+    let last = text.line_of_offset(text.len());
+    if last > 0 && char_before(text, text.len()) == Some('\n') {
+        last - 1
+    } else {
+        last
+    }
+}
+
+/// The end of a line, before its newline.
+fn line_end(text: &Rope, line: usize) -> usize {
+    let end = if line < text.line_of_offset(text.len()) {
+        text.offset_of_line(line + 1) - 1
+    } else {
+        text.len()
+    };
+    let start = text.offset_of_line(line);
+    if end > start && char_before(text, end) == Some('\r') {
+        end - 1
+    } else {
+        end
+    }
+}
+
+/// The start of the line after `line`, or the end of the text.
+fn line_after(text: &Rope, line: usize) -> usize {
+    if line < text.line_of_offset(text.len()) {
+        text.offset_of_line(line + 1)
+    } else {
+        text.len()
+    }
+}
+
+fn first_non_blank(text: &Rope, line: usize) -> usize {
+    let start = text.offset_of_line(line);
+    let line_text = text.slice_to_cow(start..line_end(text, line));
+    start
+        + line_text
+            .find(|c: char| c != ' ' && c != '\t')
+            .unwrap_or(line_text.len())
+}
+
+fn is_blank(text: &Rope, line: usize) -> bool {
+    let start = text.offset_of_line(line);
+    text.slice_to_cow(start..line_end(text, line))
+        .trim()
+        .is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The edit of the keys typed after the operator, the cursor being at
+    /// the `|` of the text.
+    fn run(
+        text: &str,
+        operator: Operator,
+        count: Option<usize>,
+        keys: &str,
+    ) -> (Rope, Option<VimEdit>) {
+        let offset = text.find('|').unwrap();
+        let rope = Rope::from(text.replacen('|', "", 1));
+        let mut vim = VimState::default();
+        vim.start_operator(operator, count);
+        let mut result = VimResult::Pending;
+        for c in keys.chars() {
+            assert_eq!(VimResult::Pending, result, "{}", keys);
+            result = vim.feed(&c.to_string(), &rope, offset);
+        }
+        match result {
+            VimResult::Apply(edit) => (rope, Some(edit)),
+            _ => (rope, None),
+        }
+    }
+
+    /// What's left after the delete, or the text if there's nothing to
+    /// delete.
+    fn delete(text: &str, keys: &str) -> String {
+        let count = keys
+            .split_once('d')
+            .and_then(|(count, _)| count.parse().ok());
+        let keys = keys.trim_start_matches(|c: char| c.is_ascii_digit());
+        let keys = keys.strip_prefix('d').unwrap();
+        let (rope, edit) = run(text, Operator::Delete, count, keys);
+        let text = String::from(&rope);
+        match edit {
+            Some(edit) => {
+                let (start, end) = edit.delete_range(&rope);
+                format!("{}{}", &text[..start], &text[end..])
+            }
+            None => text,
+        }
+    }
+
+    /// What `c` changes.
+    fn change(text: &str, keys: &str) -> Option<String> {
+        let (rope, edit) = run(text, Operator::Change, None, keys);
+        let edit = edit?;
+        assert_eq!(Mode::Insert, edit.mode);
+        Some(rope.slice_to_cow(edit.start..edit.end).to_string())
+    }
+
+    #[test]
+    fn test_motions() {
+        assert_eq!("bar baz", delete("|foo bar baz", "dw"));
+        assert_eq!("baz", delete("|foo bar baz", "d2w"));
+        assert_eq!("baz", delete("|foo bar baz", "2dw"));
+        assert_eq!("baz", delete("|foo.bar baz", "d3w"));
+        assert_eq!("foo \nbaz", delete("foo |bar\nbaz", "dw"));
+        assert_eq!("foo \nqux", delete("foo |bar\nbaz\nqux", "d2w"));
+        assert_eq!("foo baz", delete("foo |bar. baz", "dW"));
+        assert_eq!("foo  baz", delete("foo |bar baz", "de"));
+        assert_eq!("bar baz", delete("foo |bar baz", "db"));
+        assert_eq!("foo ", delete("foo |bar baz", "d$"));
+        assert_eq!("bar baz", delete("foo |bar baz", "d0"));
+        assert_eq!("  bar", delete("  foo |bar", "d^"));
+        assert_eq!("fo baz", delete("fo|o bar baz", "dfr"));
+        assert_eq!("for baz", delete("fo|o bar baz", "dtr"));
+        assert_eq!("foo bar", delete("fo|o bar", "dfx"));
+        assert_eq!("for baz", delete("foo ba|r baz", "dFo"));
+        assert_eq!("foo r baz", delete("foo ba|r baz", "dT "));
+        assert_eq!("obar", delete("fo|obar", "d2h"));
+        assert_eq!("fbar", delete("f|oobar", "d2l"));
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!("a\nc\n", delete("a\n|b\nc\n", "dd"));
+        assert_eq!("a\nd\n", delete("a\n|b\nc\nd\n", "2dd"));
+        assert_eq!("a\n", delete("a\n|b\nc\nd\n", "3dd"));
+        assert_eq!("a\n", delete("a\n|b\nc\nd\n", "9dd"));
+        // the last line takes the newline before it
+        assert_eq!("a\nb", delete("a\nb\n|c", "dd"));
+        assert_eq!("", delete("|a", "dd"));
+        assert_eq!("a\nd", delete("a\n|b\nc\nd", "dj"));
+        assert_eq!("c\nd", delete("a\n|b\nc\nd", "dk"));
+        assert_eq!("a\nb\nc", delete("a\nb\n|c", "dj"));
+        assert_eq!("a", delete("a\n|b\nc\nd", "dG"));
+        assert_eq!("c\nd", delete("a\n|b\nc\nd", "dgg"));
+        assert_eq!("a\nd", delete("a\n|b\nc\nd", "d3G"));
+
+        let (rope, edit) = run("a\n|b\nc", Operator::Yank, Some(2), "y");
+        let edit = edit.unwrap();
+        assert!(edit.linewise);
+        assert_eq!("b\nc\n", edit.register_text(&rope));
+        assert_eq!((1, 2), edit.lines(&rope));
+
+        // `cc` keeps the line and its indentation
+        assert_eq!(Some("foo bar".to_string()), change("  foo |bar\nbaz", "c"));
+    }
+
+    #[test]
+    fn test_change_word() {
+        // `cw` is `ce` on a word
+        assert_eq!(Some("bar".to_string()), change("foo |bar baz", "w"));
+        assert_eq!(Some("r".to_string()), change("foo ba|r baz", "w"));
+        assert_eq!(Some("bar baz".to_string()), change("foo |bar baz", "2w"));
+        assert_eq!(Some("bar".to_string()), change("foo |bar baz", "e"));
+        assert_eq!(Some("".to_string()), change("f(|)", "i("));
+    }
+
+    #[test]
+    fn test_text_objects() {
+        assert_eq!("foo  baz", delete("foo b|ar baz", "diw"));
+        assert_eq!("foo baz", delete("foo b|ar baz", "daw"));
+        assert_eq!("foobar", delete("foo| bar", "diw"));
+        assert_eq!("foo", delete("foo| bar", "daw"));
+        // at the end of the line the blanks before it go instead
+        assert_eq!("foo\nbaz", delete("foo b|ar\nbaz", "daw"));
+        assert_eq!("    \nbaz", delete("    b|ar\nbaz", "daw"));
+        assert_eq!("foo.baz", delete("foo b|ar.baz", "daw"));
+        assert_eq!("foo", delete("foo b|ar.baz", "daW"));
+
+        assert_eq!("s = \"\";", delete("s = \"a |b\";", "di\""));
+        assert_eq!("s =;", delete("s = \"a |b\";", "da\""));
+        assert_eq!("f(\"a\", \"\");", delete("f(\"a\", \"|b\");", "di\""));
+        assert_eq!("f('', 'b');", delete("f(|'a', 'b');", "di'"));
+        assert_eq!("x = \"\"", delete("x = \"a\\\"|b\"", "di\""));
+
+        assert_eq!("f(a, g())", delete("f(a, g(|b))", "di("));
+        assert_eq!("f()", delete("f(a, |g(b))", "di("));
+        assert_eq!("f(a, g)", delete("f(a, g(|b))", "da("));
+        assert_eq!("f(a, g)", delete("f(a, g|(b))", "da)"));
+        assert_eq!("f(a, g)", delete("f(a, g(b|))", "dab"));
+        // the next pair on the line when the cursor isn't in one
+        assert_eq!(
+            "|let x = f();",
+            format!("|{}", delete("|let x = f(a, b);", "di("))
+        );
+        assert_eq!(Some("a, b".to_string()), change("|let x = f(a, b);", "i("));
+        assert_eq!("x", delete("x|", "di("));
+        assert_eq!("a[]", delete("a[1, |2]", "di]"));
+        assert_eq!("fn f() {\n}", delete("fn f() {\n    |a;\n    b;\n}", "di{"));
+        assert_eq!("fn f() ", delete("fn f() {\n    |a;\n}", "daB"));
+        assert_eq!("<>", delete("<a|b>", "di<"));
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let text = "a\nb\n\n\nc\nd\n";
+        assert_eq!("\n\nc\nd\n", delete(&text.replacen("b", "|b", 1), "dip"));
+        assert_eq!("c\nd\n", delete(&text.replacen("b", "|b", 1), "dap"));
+        assert_eq!("a\nb\n", delete(&text.replacen("c", "|c", 1), "dap"));
+        assert_eq!("a\nb\nc\nd\n", delete("a\nb\n|\n\nc\nd\n", "dip"));
+        assert_eq!("a\nb\n", delete("a\nb\n|\n\nc\nd\n", "dap"));
+    }
+
+    #[test]
+    fn test_keys() {
+        let rope = Rope::from("foo bar");
+        let mut vim = VimState::default();
+        assert!(!vim.is_pending());
+        vim.start_operator(Operator::Delete, None);
+        assert!(vim.is_pending());
+        assert_eq!(VimResult::Pending, vim.feed("1", &rope, 0));
+        assert_eq!(VimResult::Pending, vim.feed("0", &rope, 0));
+        assert_eq!(VimResult::Pending, vim.feed("i", &rope, 0));
+        assert_eq!(VimResult::Cancelled, vim.feed("x", &rope, 0));
+        assert!(!vim.is_pending());
+
+        vim.start_operator(Operator::Delete, None);
+        assert_eq!(VimResult::Cancelled, vim.feed("z", &rope, 0));
+        vim.start_operator(Operator::Delete, None);
+        assert_eq!(VimResult::Pending, vim.feed("g", &rope, 0));
+        assert_eq!(VimResult::Cancelled, vim.feed("x", &rope, 0));
+        // nothing to delete is nothing done
+        vim.start_operator(Operator::Delete, None);
+        assert_eq!(VimResult::Cancelled, vim.feed("h", &rope, 0));
+        assert!(vim.repeat(None, &rope, 0).is_none());
+    }
+
+    #[test]
+    fn test_repeat() {
+        let rope = Rope::from("a b c d e f g");
+        let mut vim = VimState::default();
+        vim.start_operator(Operator::Delete, Some(2));
+        let apply = vim.feed("w", &rope, 0);
+        assert!(matches!(apply, VimResult::Apply(VimEdit { end: 4, .. })));
+        let (edit, inserted) = vim.repeat(None, &rope, 4).unwrap();
+        assert_eq!((4, 8), (edit.start, edit.end));
+        assert_eq!("", inserted);
+        // a count replaces that of the change
+        let (edit, _) = vim.repeat(Some(3), &rope, 0).unwrap();
+        assert_eq!((0, 6), (edit.start, edit.end));
+        let (edit, _) = vim.repeat(None, &rope, 0).unwrap();
+        assert_eq!((0, 6), (edit.start, edit.end));
+
+        // yanks aren't changes
+        vim.start_operator(Operator::Yank, None);
+        vim.feed("w", &rope, 0);
+        assert_eq!(
+            Operator::Delete,
+            vim.repeat(None, &rope, 0).unwrap().0.operator
+        );
+
+        vim.start_operator(Operator::Change, None);
+        vim.feed("i", &rope, 2);
+        vim.feed("w", &rope, 2);
+        vim.record_insert("x");
+        vim.record_insert("y");
+        // the change is the last once insert mode is left
+        assert_eq!(
+            Operator::Delete,
+            vim.repeat(None, &rope, 0).unwrap().0.operator
+        );
+        vim.finish_insert();
+        vim.record_insert("z");
+        let (edit, inserted) = vim.repeat(None, &rope, 6).unwrap();
+        assert_eq!(Operator::Change, edit.operator);
+        assert_eq!((6, 7), (edit.start, edit.end));
+        assert_eq!("xy", inserted);
+    }
+
+    #[test]
+    fn test_shift() {
+        let rope = Rope::from("a\n\n  b\n\tc\n      d");
+        assert_eq!(
+            vec![(0, 0), (3, 3), (7, 7), (10, 10)],
+            indent_regions(&rope, 0, 4)
+        );
+        assert_eq!(
+            vec![(3, 5), (7, 8), (10, 14)],
+            outdent_regions(&rope, 0, 4, 4)
+        );
+    }
+}
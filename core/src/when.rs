@@ -0,0 +1,335 @@
+//! The `when` of a keymap, a condition on the context the key is pressed
+//! in, as in `editor_focus && mode == "normal" && !terminal_focus`. A name
+//! alone is true when the context has it set, `==` and `!=` compare what
+//! it's set to with a string, and `!`, `&&`, `||` and parentheses combine
+//! them, `&&` binding tighter than `||`.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+/// What a name of the context is set to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContextValue {
+    Bool(bool),
+    String(String),
+}
+
+impl ContextValue {
+    fn is_true(&self) -> bool {
+        match self {
+            ContextValue::Bool(b) => *b,
+            ContextValue::String(s) => !s.is_empty(),
+        }
+    }
+
+    fn as_string(&self) -> String {
+        match self {
+            ContextValue::Bool(b) => b.to_string(),
+            ContextValue::String(s) => s.clone(),
+        }
+    }
+}
+
+/// The names a `when` is evaluated against, set by the focused widget.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeymapContext {
+    values: HashMap<String, ContextValue>,
+}
+
+impl KeymapContext {
+    pub fn set_bool(&mut self, name: &str, value: bool) {
+        self.values
+            .insert(name.to_string(), ContextValue::Bool(value));
+    }
+
+    pub fn set_string(&mut self, name: &str, value: &str) {
+        self.values
+            .insert(name.to_string(), ContextValue::String(value.to_string()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ContextValue> {
+        self.values.get(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum When {
+    Name(String),
+    Equals(String, String),
+    NotEquals(String, String),
+    Not(Box<When>),
+    And(Box<When>, Box<When>),
+    Or(Box<When>, Box<When>),
+}
+
+impl When {
+    pub fn parse(s: &str) -> Result<When> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let when = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(anyhow!("unexpected {:?} in \"{}\"", token, s));
+        }
+        Ok(when)
+    }
+
+    pub fn eval(&self, context: &KeymapContext) -> bool {
+        match self {
+            When::Name(name) => context
+                .get(name)
+                .map(|value| value.is_true())
+                .unwrap_or(false),
+            When::Equals(name, value) => {
+                context.get(name).map(|v| v.as_string()).as_deref()
+                    == Some(value.as_str())
+            }
+            When::NotEquals(name, value) => {
+                context.get(name).map(|v| v.as_string()).as_deref()
+                    != Some(value.as_str())
+            }
+            When::Not(when) => !when.eval(context),
+            When::And(a, b) => a.eval(context) && b.eval(context),
+            When::Or(a, b) => a.eval(context) || b.eval(context),
+        }
+    }
+
+    /// How many names it tests, the more the more specific.
+    pub fn specificity(&self) -> usize {
+        match self {
+            When::Name(_) | When::Equals(_, _) | When::NotEquals(_, _) => 1,
+            When::Not(when) => when.specificity(),
+            When::And(a, b) | When::Or(a, b) => a.specificity() + b.specificity(),
+        }
+    }
+
+    /// The names of the context it tests.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            When::Name(name) | When::Equals(name, _) | When::NotEquals(name, _) => {
+                names.push(name)
+            }
+            When::Not(when) => when.collect_names(names),
+            When::And(a, b) | When::Or(a, b) => {
+                a.collect_names(names);
+                b.collect_names(names);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    String(String),
+    Not,
+    And,
+    Or,
+    Equals,
+    NotEquals,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' if chars.peek() == Some(&'=') => {
+                chars.next();
+                Token::NotEquals
+            }
+            '!' => Token::Not,
+            '=' | '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(anyhow!("expected {0}{0} in \"{1}\"", c, s));
+                }
+                match c {
+                    '=' => Token::Equals,
+                    '&' => Token::And,
+                    _ => Token::Or,
+                }
+            }
+            '"' | '\'' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(c) => string.push(c),
+                        None => return Err(anyhow!("unclosed string in \"{}\"", s)),
+                    }
+                }
+                Token::String(string)
+            }
+            c if is_name_char(c) => {
+                let mut name = c.to_string();
+                while let Some(c) =
+                    chars.peek().copied().filter(|c| is_name_char(*c))
+                {
+                    name.push(c);
+                    chars.next();
+                }
+                Token::Name(name)
+            }
+            c => return Err(anyhow!("unexpected '{}' in \"{}\"", c, s)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '-'
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<When> {
+        let mut when = self.and()?;
+        while self.eat(&Token::Or) {
+            when = When::Or(Box::new(when), Box::new(self.and()?));
+        }
+        Ok(when)
+    }
+
+    fn and(&mut self) -> Result<When> {
+        let mut when = self.unary()?;
+        while self.eat(&Token::And) {
+            when = When::And(Box::new(when), Box::new(self.unary()?));
+        }
+        Ok(when)
+    }
+
+    fn unary(&mut self) -> Result<When> {
+        if self.eat(&Token::Not) {
+            return Ok(When::Not(Box::new(self.unary()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let when = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err(anyhow!("missing )"));
+                }
+                Ok(when)
+            }
+            Some(Token::Name(name)) => {
+                if self.eat(&Token::Equals) {
+                    Ok(When::Equals(name, self.value()?))
+                } else if self.eat(&Token::NotEquals) {
+                    Ok(When::NotEquals(name, self.value()?))
+                } else {
+                    Ok(When::Name(name))
+                }
+            }
+            Some(token) => Err(anyhow!("unexpected {:?}", token)),
+            None => Err(anyhow!("unexpected end")),
+        }
+    }
+
+    /// What a name is compared with, quoted or not.
+    fn value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::String(value)) | Some(Token::Name(value)) => Ok(value),
+            Some(token) => Err(anyhow!("expected a value, found {:?}", token)),
+            None => Err(anyhow!("expected a value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> KeymapContext {
+        let mut context = KeymapContext::default();
+        context.set_bool("editor_focus", true);
+        context.set_bool("terminal_focus", false);
+        context.set_string("mode", "normal");
+        context
+    }
+
+    #[test]
+    fn test_eval() {
+        let context = context();
+        let eval = |s: &str| When::parse(s).unwrap().eval(&context);
+        assert!(eval("editor_focus"));
+        assert!(!eval("terminal_focus"));
+        assert!(!eval("unknown_focus"));
+        assert!(eval("!terminal_focus"));
+        assert!(eval("!!editor_focus"));
+        assert!(eval("mode == \"normal\""));
+        assert!(eval("mode == 'normal'"));
+        assert!(eval("mode == normal"));
+        assert!(eval("mode != \"insert\""));
+        assert!(eval("unknown != \"insert\""));
+        assert!(eval("editor_focus == true"));
+        assert!(eval(
+            "editor_focus && mode == \"normal\" && !terminal_focus"
+        ));
+        // && binds tighter
+        assert!(eval("terminal_focus && unknown || editor_focus"));
+        assert!(eval("editor_focus || terminal_focus && unknown"));
+        assert!(!eval("(editor_focus || terminal_focus) && unknown"));
+        assert!(!eval("!(editor_focus || terminal_focus)"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for s in [
+            "",
+            "editor_focus &&",
+            "&& editor_focus",
+            "(editor_focus",
+            "editor_focus)",
+            "mode ==",
+            "mode == \"normal",
+            "editor_focus & terminal_focus",
+            "editor_focus terminal_focus",
+            "mode = normal",
+        ] {
+            assert!(When::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_specificity() {
+        let specificity = |s: &str| When::parse(s).unwrap().specificity();
+        assert_eq!(1, specificity("editor_focus"));
+        assert_eq!(1, specificity("!editor_focus"));
+        assert_eq!(2, specificity("editor_focus && mode == normal"));
+        assert_eq!(3, specificity("a && (b || !c)"));
+        assert_eq!(
+            vec!["a", "b", "c"],
+            When::parse("a && (b || c == x)").unwrap().names()
+        );
+    }
+}
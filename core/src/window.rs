@@ -6,6 +6,7 @@ use crate::{
     editor::EditorUIState,
     explorer::{FileExplorer, FileExplorerState},
     panel::{LapcePanel, PanelPosition, PanelProperty},
+    recent,
     state::{LapceWorkspace, LapceWorkspaceType},
     tab::{LapceTabHeader, LapceTabNew},
     theme::OldLapceTheme,
@@ -86,6 +87,7 @@ impl LapceWindowNew {
             }
             data.active_id = tab_id;
         } else {
+            active_tab_lost_focus(data);
             self.tabs
                 .insert(data.active + 1, WidgetPod::new(tab.boxed()));
             self.tab_headers
@@ -164,6 +166,13 @@ impl LapceWindowNew {
     }
 }
 
+/// Lets the proxy of the tab going to the background auto-save its files.
+fn active_tab_lost_focus(data: &LapceWindowData) {
+    if let Some(tab) = data.tabs.get(&data.active_id) {
+        tab.proxy.focus_lost();
+    }
+}
+
 impl Widget<LapceWindowData> for LapceWindowNew {
     fn event(
         &mut self,
@@ -184,16 +193,10 @@ impl Widget<LapceWindowData> for LapceWindowNew {
                 let command = cmd.get_unchecked(LAPCE_UI_COMMAND);
                 match command {
                     LapceUICommand::ReloadConfig => {
+                        // the tabs get theirs from their proxies, with the
+                        // workspace settings
                         data.config =
                             Arc::new(Config::load(None).unwrap_or_default());
-                        for (_, tab) in data.tabs.iter_mut() {
-                            tab.config = Arc::new(
-                                Config::load(
-                                    tab.workspace.clone().map(|w| (*w).clone()),
-                                )
-                                .unwrap_or_default(),
-                            );
-                        }
                         Arc::make_mut(&mut data.keypress).update_keymaps();
                         ctx.set_handled();
                     }
@@ -208,20 +211,14 @@ impl Widget<LapceWindowData> for LapceWindowNew {
                         let mut workspaces =
                             Config::recent_workspaces().unwrap_or(Vec::new());
 
-                        let mut exits = false;
-                        for w in workspaces.iter_mut() {
-                            if w.path == workspace.path && w.kind == workspace.kind {
-                                w.last_open = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs();
-                                exits = true;
-                            }
-                        }
-                        if !exits {
-                            workspaces.push(workspace.clone());
-                        }
-                        workspaces.sort_by_key(|w| -(w.last_open as i64));
+                        recent::touch_workspace(
+                            &mut workspaces,
+                            workspace,
+                            std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs(),
+                        );
                         Config::update_recent_workspaces(workspaces);
 
                         self.new_tab(ctx, data, Some(workspace.clone()), true);
@@ -254,6 +251,7 @@ impl Widget<LapceWindowData> for LapceWindowNew {
                         for (i, tab) in self.tabs.iter().enumerate() {
                             if tab_id == &tab.id() {
                                 if i != data.active {
+                                    active_tab_lost_focus(data);
                                     data.active = i;
                                     data.active_id = tab.id();
                                     data.db.save_tabs(data);
@@ -278,6 +276,7 @@ impl Widget<LapceWindowData> for LapceWindowNew {
                         return;
                     }
                     LapceUICommand::NextTab => {
+                        active_tab_lost_focus(data);
                         let new_index = if data.active >= self.tabs.len() - 1 {
                             0
                         } else {
@@ -295,6 +294,7 @@ impl Widget<LapceWindowData> for LapceWindowNew {
                         ctx.set_handled();
                     }
                     LapceUICommand::PreviousTab => {
+                        active_tab_lost_focus(data);
                         let new_index = if data.active == 0 {
                             self.tabs.len() - 1
                         } else {
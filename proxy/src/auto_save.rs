@@ -0,0 +1,145 @@
+//! Saving buffers without a ctrl+s, from `auto-save` in the `[editor]`
+//! settings: some time after their last edit, or when the tab they're open
+//! in loses focus.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::BufferId;
+use crate::dispatch::Dispatcher;
+use crate::settings::settings;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoSaveMode {
+    Off,
+    /// `auto-save-delay` milliseconds after the last edit of the buffer.
+    AfterDelay,
+    /// When the core says its window or tab lost focus.
+    OnFocusChange,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoSaveSettings {
+    pub mode: AutoSaveMode,
+    pub delay: Duration,
+    /// Whether the buffer is formatted first, like by `format-on-save`.
+    pub format: bool,
+    /// How long a save waits for the formatting.
+    pub format_timeout: Duration,
+}
+
+impl Default for AutoSaveSettings {
+    fn default() -> Self {
+        Self {
+            mode: AutoSaveMode::Off,
+            delay: Duration::from_millis(1000),
+            format: false,
+            format_timeout: Duration::from_millis(1000),
+        }
+    }
+}
+
+impl AutoSaveSettings {
+    pub fn load() -> Self {
+        settings()
+            .map(|settings| Self::from_settings(&settings))
+            .unwrap_or_default()
+    }
+
+    fn from_settings(settings: &toml::Value) -> Self {
+        let mut auto_save = Self::default();
+        let editor = match settings.get("editor") {
+            Some(editor) => editor,
+            None => return auto_save,
+        };
+        if let Some(mode) = editor
+            .get("auto-save")
+            .and_then(|mode| mode.clone().try_into().ok())
+        {
+            auto_save.mode = mode;
+        }
+        let millis = |key| {
+            let value = editor.get(key)?.as_integer()?;
+            Some(Duration::from_millis(value.max(0) as u64))
+        };
+        if let Some(delay) = millis("auto-save-delay") {
+            auto_save.delay = delay;
+        }
+        if let Some(timeout) = millis("format-on-save-timeout") {
+            auto_save.format_timeout = timeout;
+        }
+        if let Some(format) = editor
+            .get("auto-save-format")
+            .and_then(|format| format.as_bool())
+        {
+            auto_save.format = format;
+        }
+        auto_save
+    }
+}
+
+/// Waits for the edits of buffers on `receiver` and saves each buffer once
+/// it went `auto-save-delay` without one.
+pub fn debounce_edits(dispatcher: Dispatcher, receiver: Receiver<BufferId>) {
+    let mut due: HashMap<BufferId, Instant> = HashMap::new();
+    loop {
+        let received = match due.values().min() {
+            Some(deadline) => receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(buffer_id) => {
+                let settings = *dispatcher.auto_save.lock();
+                if settings.mode == AutoSaveMode::AfterDelay {
+                    due.insert(buffer_id, Instant::now() + settings.delay);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        // the mode can have changed since the edit
+        if dispatcher.auto_save.lock().mode != AutoSaveMode::AfterDelay {
+            due.clear();
+            continue;
+        }
+        let now = Instant::now();
+        let buffer_ids: Vec<BufferId> = due
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(buffer_id, _)| *buffer_id)
+            .collect();
+        for buffer_id in buffer_ids {
+            due.remove(&buffer_id);
+            dispatcher.auto_save_buffer(buffer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let settings: toml::Value = toml::from_str(
+            "[editor]\nauto-save = \"after-delay\"\nauto-save-delay = 250\n",
+        )
+        .unwrap();
+        let auto_save = AutoSaveSettings::from_settings(&settings);
+        assert_eq!(AutoSaveMode::AfterDelay, auto_save.mode);
+        assert_eq!(Duration::from_millis(250), auto_save.delay);
+        assert!(!auto_save.format);
+
+        let settings: toml::Value =
+            toml::from_str("[editor]\nauto-save = \"sometimes\"\n").unwrap();
+        assert_eq!(
+            AutoSaveSettings::default(),
+            AutoSaveSettings::from_settings(&settings)
+        );
+    }
+}
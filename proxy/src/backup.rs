@@ -0,0 +1,269 @@
+//! Copies of the unsaved edits of buffers under `~/.lapce/backups`, so that
+//! a crash of the proxy or the editor loses none of them. A backup is the
+//! text of a buffer, next to a manifest of what it's the text of. The
+//! proxy that wrote it keeps a heartbeat file, and once that's gone or
+//! stale its backups are left over, to be restored.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use home::home_dir;
+use serde::{Deserialize, Serialize};
+use xi_rope::Rope;
+
+use crate::buffer::BufferId;
+use crate::save::write_file;
+
+/// How often the backups are written, at most once per buffer.
+pub const BACKUP_INTERVAL: Duration = Duration::from_secs(5);
+/// A session whose heartbeat is older than this has ended.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// A hash of the path, or a made-up id for a buffer without a file.
+    pub id: String,
+    pub path: Option<PathBuf>,
+    pub rev: u64,
+    /// When it was written, in seconds since the epoch.
+    pub timestamp: u64,
+    /// The proxy that wrote it.
+    pub session: String,
+}
+
+/// A buffer with edits, as it was when the backups were written.
+pub struct DirtyBuffer {
+    pub id: BufferId,
+    pub path: Option<PathBuf>,
+    pub rev: u64,
+    pub rope: Rope,
+}
+
+pub struct Backups {
+    dir: PathBuf,
+    session: String,
+    /// The backup of each buffer written so far, and the rev it has.
+    written: HashMap<BufferId, (String, u64)>,
+}
+
+impl Backups {
+    pub fn new() -> Option<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos();
+        Some(Self {
+            dir: home_dir()?.join(".lapce").join("backups"),
+            session: format!("{}-{:x}", std::process::id(), nanos),
+            written: HashMap::new(),
+        })
+    }
+
+    /// Writes the backups of the buffers edited since, removes the ones of
+    /// buffers saved or closed since, and keeps the session alive.
+    pub fn sync(&mut self, dirty: &[DirtyBuffer]) {
+        if let Err(e) = self.heartbeat() {
+            eprintln!("can't write the backups: {}", e);
+            return;
+        }
+        for buffer in dirty {
+            if self.written.get(&buffer.id).map(|(_, rev)| *rev) == Some(buffer.rev)
+            {
+                continue;
+            }
+            let id = match &buffer.path {
+                Some(path) => path_id(path),
+                None => format!("untitled-{}-{}", self.session, buffer.id.0),
+            };
+            match self.write(&id, buffer) {
+                Ok(()) => {
+                    self.written.insert(buffer.id, (id, buffer.rev));
+                }
+                Err(e) => eprintln!("can't back up {:?}: {}", buffer.path, e),
+            }
+        }
+        let clean: Vec<BufferId> = self
+            .written
+            .keys()
+            .filter(|id| !dirty.iter().any(|buffer| buffer.id == **id))
+            .copied()
+            .collect();
+        for buffer_id in clean {
+            if let Some((id, _)) = self.written.remove(&buffer_id) {
+                self.remove(&id);
+            }
+        }
+    }
+
+    /// Ends the session with the backups of `dirty` written, for them to be
+    /// restored next time.
+    pub fn shutdown(&mut self, dirty: &[DirtyBuffer]) {
+        self.sync(dirty);
+        let _ = fs::remove_file(self.session_path(&self.session));
+    }
+
+    /// The backups left by the sessions that ended, of files in `folders`
+    /// or of buffers without a file, newest first.
+    pub fn leftovers(&self, folders: &[PathBuf]) -> Vec<BackupManifest> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut backups: Vec<BackupManifest> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "json" {
+                    return None;
+                }
+                serde_json::from_slice(&fs::read(path).ok()?).ok()
+            })
+            .filter(|backup: &BackupManifest| {
+                self.session_ended(&backup.session)
+                    && match &backup.path {
+                        Some(path) => {
+                            folders.iter().any(|folder| path.starts_with(folder))
+                        }
+                        None => true,
+                    }
+            })
+            .collect();
+        backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        backups
+    }
+
+    /// A backup left over, with its text.
+    pub fn restore(&self, id: &str) -> Result<(BackupManifest, String)> {
+        let manifest = self.leftover(id)?;
+        let text = fs::read_to_string(self.dir.join(id))?;
+        Ok((manifest, text))
+    }
+
+    /// Removes a backup left over, once restored or not wanted.
+    pub fn discard(&self, id: &str) -> Result<()> {
+        self.leftover(id)?;
+        self.remove(id);
+        Ok(())
+    }
+
+    fn leftover(&self, id: &str) -> Result<BackupManifest> {
+        // the id comes from the editor, and names a file
+        if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(anyhow!("{} isn't a backup", id));
+        }
+        let manifest: BackupManifest =
+            serde_json::from_slice(&fs::read(self.manifest_path(id))?)?;
+        if !self.session_ended(&manifest.session) {
+            return Err(anyhow!("the backup {} is still being written", id));
+        }
+        Ok(manifest)
+    }
+
+    /// The text goes first, so that there's no manifest without it.
+    fn write(&self, id: &str, buffer: &DirtyBuffer) -> Result<()> {
+        write_file(&self.dir.join(id), buffer.rope.to_string().as_bytes())?;
+        let manifest = BackupManifest {
+            id: id.to_string(),
+            path: buffer.path.clone(),
+            rev: buffer.rev,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            session: self.session.clone(),
+        };
+        write_file(&self.manifest_path(id), &serde_json::to_vec(&manifest)?)
+    }
+
+    fn remove(&self, id: &str) {
+        let _ = fs::remove_file(self.manifest_path(id));
+        let _ = fs::remove_file(self.dir.join(id));
+    }
+
+    fn heartbeat(&self) -> Result<()> {
+        let path = self.session_path(&self.session);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, b"")?;
+        Ok(())
+    }
+
+    fn session_ended(&self, session: &str) -> bool {
+        if session == self.session {
+            return false;
+        }
+        let beat = fs::metadata(self.session_path(session))
+            .and_then(|metadata| metadata.modified());
+        match beat {
+            Ok(beat) => beat.elapsed().map_or(false, |age| age > SESSION_TIMEOUT),
+            Err(_) => true,
+        }
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn session_path(&self, session: &str) -> PathBuf {
+        self.dir.join("sessions").join(session)
+    }
+}
+
+/// FNV-1a of the path, which unlike the hasher of std stays the same
+/// across releases.
+fn path_id(path: &Path) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in path.to_string_lossy().as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_backups() {
+        let dir = TestDir::new("backups");
+        let backups = |session: &str| Backups {
+            dir: dir.to_path_buf(),
+            session: session.to_string(),
+            written: HashMap::new(),
+        };
+        let file = |rev| DirtyBuffer {
+            id: BufferId(1),
+            path: Some(PathBuf::from("/workspace/src/main.rs")),
+            rev,
+            rope: Rope::from("fn main() {}\n"),
+        };
+        let untitled = DirtyBuffer {
+            id: BufferId(2),
+            path: None,
+            rev: 1,
+            rope: Rope::from("notes"),
+        };
+
+        let mut old = backups("old");
+        old.sync(&[file(3), untitled]);
+        let new = backups("new");
+        // the old session is still running
+        assert!(new.leftovers(&[PathBuf::from("/workspace")]).is_empty());
+        // the untitled buffer was closed since
+        old.shutdown(&[file(4)]);
+
+        let leftovers = new.leftovers(&[PathBuf::from("/workspace")]);
+        assert_eq!(1, leftovers.len());
+        assert_eq!(4, leftovers[0].rev);
+        assert!(new.leftovers(&[PathBuf::from("/elsewhere")]).is_empty());
+        let (manifest, text) = new.restore(&leftovers[0].id).unwrap();
+        assert_eq!(leftovers[0], manifest);
+        assert_eq!("fn main() {}\n", text);
+        new.discard(&manifest.id).unwrap();
+        assert!(new.leftovers(&[PathBuf::from("/workspace")]).is_empty());
+        assert!(new.restore("../settings").is_err());
+    }
+}
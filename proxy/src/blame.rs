@@ -0,0 +1,301 @@
+//! Who last changed each line of a file, for the blame gutter and the
+//! annotation at the cursor. The blame of a buffer is of its text rather
+//! than the file at HEAD, so the lines edited since are "not committed"
+//! instead of put down to the commit that last had a line there. A blame is
+//! kept until HEAD moves or the file is saved, the edits in between
+//! shifting its lines rather than blaming the file again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use lsp_types::TextDocumentContentChangeEvent;
+use serde::{Deserialize, Serialize};
+
+use crate::git::{open_repo, CommitInfo};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileBlame {
+    /// The commits of `lines`, by id.
+    pub commits: HashMap<String, CommitInfo>,
+    /// The commit of each line, None for one that isn't committed.
+    pub lines: Vec<Option<String>>,
+}
+
+/// The blame of a line, for the annotation at the cursor.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineBlame {
+    pub line: usize,
+    /// None when the line isn't committed.
+    pub commit: Option<CommitInfo>,
+}
+
+impl FileBlame {
+    pub fn line(&self, line: usize) -> LineBlame {
+        LineBlame {
+            line,
+            commit: self
+                .lines
+                .get(line)
+                .and_then(|id| self.commits.get(id.as_ref()?))
+                .cloned(),
+        }
+    }
+
+    /// Shifts the lines after an edit, the ones it touched being no longer
+    /// committed. False when the edit replaced the whole text, for the file
+    /// to be blamed again.
+    fn edited(&mut self, change: &TextDocumentContentChangeEvent) -> bool {
+        let range = match change.range {
+            Some(range) if !self.lines.is_empty() => range,
+            _ => return false,
+        };
+        let start = range.start.line as usize;
+        let end = (range.end.line as usize).min(self.lines.len().saturating_sub(1));
+        if start > end {
+            return false;
+        }
+        let new_lines = change.text.matches('\n').count();
+        if range.start == range.end
+            && range.start.character == 0
+            && change.text.ends_with('\n')
+        {
+            // whole lines put in before a line leave it as it was
+            self.lines
+                .splice(start..start, std::iter::repeat(None).take(new_lines));
+        } else {
+            self.lines
+                .splice(start..end + 1, std::iter::repeat(None).take(new_lines + 1));
+        }
+        true
+    }
+}
+
+struct CachedBlame {
+    blame: FileBlame,
+    /// The rev of the buffer it's of, None for a file that isn't open.
+    rev: Option<u64>,
+}
+
+/// The blames worked out, by path.
+#[derive(Default)]
+pub struct Blames {
+    files: HashMap<PathBuf, CachedBlame>,
+}
+
+impl Blames {
+    /// The blame of `path` if it's of that rev of its buffer.
+    pub fn get(&self, path: &Path, rev: Option<u64>) -> Option<&FileBlame> {
+        self.files
+            .get(path)
+            .filter(|cached| cached.rev == rev)
+            .map(|cached| &cached.blame)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, rev: Option<u64>, blame: FileBlame) {
+        self.files.insert(path, CachedBlame { blame, rev });
+    }
+
+    /// Follows an edit of the buffer of `path` from `rev - 1` to `rev`.
+    pub fn edited(
+        &mut self,
+        path: &Path,
+        rev: u64,
+        change: &TextDocumentContentChangeEvent,
+    ) {
+        let shifted = match self.files.get_mut(path) {
+            Some(cached) if cached.rev == Some(rev - 1) => {
+                cached.rev = Some(rev);
+                cached.blame.edited(change)
+            }
+            Some(_) => false,
+            None => return,
+        };
+        if !shifted {
+            self.files.remove(path);
+        }
+    }
+
+    pub fn invalidate(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// For when HEAD moved.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// Blames `content`, the text of the file at `path`, against HEAD: the
+/// file at HEAD is blamed and its lines are followed to `content` by a
+/// diff, the lines that aren't in it being not committed.
+pub fn blame_file(path: &Path, content: &str) -> Result<FileBlame> {
+    let repo = open_repo(path.parent().unwrap_or(path))
+        .ok_or_else(|| anyhow!("not in a git repository"))?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("the repository has no working directory"))?;
+    let relative = path
+        .strip_prefix(workdir)
+        .map_err(|_| anyhow!("{:?} isn't in the repository", path))?;
+    let line_count = content.lines().count();
+    let mut blame = FileBlame {
+        commits: HashMap::new(),
+        lines: vec![None; line_count],
+    };
+    // a file that isn't committed has no line that is
+    let head_blob = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .and_then(|tree| tree.get_path(relative))
+        .and_then(|entry| repo.find_blob(entry.id()));
+    let (head_blob, file_blame) = match (head_blob, repo.blame_file(relative, None))
+    {
+        (Ok(head_blob), Ok(file_blame)) => (head_blob, file_blame),
+        _ => return Ok(blame),
+    };
+    let mut head_lines: Vec<Option<String>> = Vec::new();
+    for hunk in file_blame.iter() {
+        let id = hunk.final_commit_id();
+        let id_string = match id.is_zero() {
+            true => None,
+            false => Some(id.to_string()),
+        };
+        if let Some(id_string) = id_string.as_ref() {
+            if !blame.commits.contains_key(id_string) {
+                let commit = repo.find_commit(id)?;
+                blame
+                    .commits
+                    .insert(id_string.clone(), CommitInfo::new(&commit));
+            }
+        }
+        let start = hunk.final_start_line().saturating_sub(1);
+        if head_lines.len() < start + hunk.lines_in_hunk() {
+            head_lines.resize(start + hunk.lines_in_hunk(), None);
+        }
+        for line in head_lines[start..start + hunk.lines_in_hunk()].iter_mut() {
+            *line = id_string.clone();
+        }
+    }
+    let head_of_line = lines_at_head(&head_blob, content, line_count)?;
+    for (line, head_line) in blame.lines.iter_mut().zip(head_of_line) {
+        *line = head_line.and_then(|head_line| head_lines.get(head_line)?.clone());
+    }
+    Ok(blame)
+}
+
+/// For each of the `line_count` lines of `content`, the line of `blob` it
+/// is unchanged from, if any.
+fn lines_at_head(
+    blob: &git2::Blob,
+    content: &str,
+    line_count: usize,
+) -> Result<Vec<Option<usize>>> {
+    let mut options = git2::DiffOptions::new();
+    options.context_lines(0);
+    let patch = git2::Patch::from_blob_and_buffer(
+        blob,
+        None,
+        content.as_bytes(),
+        None,
+        Some(&mut options),
+    )?;
+    let mut head_of_line = vec![None; line_count];
+    // the lines between the hunks are the same, shifted by the hunks before
+    let (mut old, mut new) = (0, 0);
+    let mut same_until = |old: &mut usize, new: &mut usize, end: usize| {
+        while *new < end.min(line_count) {
+            head_of_line[*new] = Some(*old);
+            *old += 1;
+            *new += 1;
+        }
+    };
+    for i in 0..patch.num_hunks() {
+        let (hunk, _) = patch.hunk(i)?;
+        // a side without lines starts after the line it gives
+        let start = |start: u32, lines: u32| match lines {
+            0 => start as usize,
+            _ => start as usize - 1,
+        };
+        let new_start = start(hunk.new_start(), hunk.new_lines());
+        same_until(&mut old, &mut new, new_start);
+        old = start(hunk.old_start(), hunk.old_lines()) + hunk.old_lines() as usize;
+        new = new_start + hunk.new_lines() as usize;
+    }
+    same_until(&mut old, &mut new, line_count);
+    Ok(head_of_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use git2::Repository;
+    use lsp_types::{Position, Range};
+
+    use super::*;
+    use crate::git::{git_commit, git_stage};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_blame_edited() {
+        let mut blame = FileBlame {
+            commits: HashMap::new(),
+            lines: ["a", "b", "c"]
+                .iter()
+                .map(|id| Some(id.to_string()))
+                .collect(),
+        };
+        let change = |start: (u32, u32), end: (u32, u32), text: &str| {
+            TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position::new(start.0, start.1),
+                    end: Position::new(end.0, end.1),
+                }),
+                range_length: None,
+                text: text.to_string(),
+            }
+        };
+        let ids = |blame: &FileBlame| {
+            blame
+                .lines
+                .iter()
+                .map(|id| id.as_deref().unwrap_or("-").to_string())
+                .collect::<Vec<_>>()
+                .join("")
+        };
+        assert!(blame.edited(&change((1, 0), (1, 0), "new\n")));
+        assert_eq!("a-bc", ids(&blame));
+        assert!(blame.edited(&change((2, 1), (3, 0), "")));
+        assert_eq!("a--", ids(&blame));
+        assert!(blame.edited(&change((0, 1), (0, 1), "x\ny")));
+        assert_eq!("----", ids(&blame));
+    }
+
+    #[test]
+    fn test_blame_file() {
+        let root = TestDir::new("blame");
+        let repo = Repository::init(&root).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Lapce").unwrap();
+        config.set_str("user.email", "lapce@example.com").unwrap();
+        let path = root.join("a.txt");
+        fs::write(&path, "one\ntwo\nthree\nfour\n").unwrap();
+        git_stage(&root, &[path.clone()]).unwrap();
+        let first = git_commit(&root, "add a", false).unwrap();
+        fs::write(&path, "one\n2\nthree\nfour\n").unwrap();
+        git_stage(&root, &[path.clone()]).unwrap();
+        let second = git_commit(&root, "change a", false).unwrap();
+
+        // a line added, one changed and one removed since HEAD
+        let blame = blame_file(&path, "zero\none\n2\n3\n").unwrap();
+        let first = repo.revparse_single(&first).unwrap().id().to_string();
+        let second = repo.revparse_single(&second).unwrap().id().to_string();
+        assert_eq!(
+            vec![None, Some(first.clone()), Some(second.clone()), None],
+            blame.lines
+        );
+        assert_eq!("add a", blame.commits[&first].summary);
+        assert_eq!("change a", blame.commits[&second].summary);
+    }
+}
@@ -0,0 +1,211 @@
+//! The bracket matching another and how deep brackets are nested, from
+//! the syntax tree when there's one, so that the brackets in strings and
+//! comments don't count, and from the text otherwise.
+
+use tree_sitter::{Node, Tree};
+use xi_rope::Rope;
+
+/// How far the text is scanned for a matching bracket, or for the depth a
+/// range starts at, so that an unbalanced file doesn't get scanned to its
+/// end for every bracket.
+pub const MAX_BRACKET_DISTANCE: usize = 64 * 1024;
+
+/// The other bracket of a pair, and whether `c` opens it.
+fn bracket_pair(c: u8) -> Option<(u8, bool)> {
+    Some(match c {
+        b'(' => (b')', true),
+        b')' => (b'(', false),
+        b'[' => (b']', true),
+        b']' => (b'[', false),
+        b'{' => (b'}', true),
+        b'}' => (b'{', false),
+        _ => return None,
+    })
+}
+
+/// A bracket token of the tree, rather than one in a string or a comment,
+/// which are in bigger nodes.
+fn is_bracket(node: &Node) -> bool {
+    !node.is_named()
+        && node.end_byte() == node.start_byte() + 1
+        && node.kind().len() == 1
+        && bracket_pair(node.kind().as_bytes()[0]).is_some()
+}
+
+/// The offset of the bracket matching the one at `offset`. None when
+/// there's no bracket there, it's in a string or a comment, or its match
+/// isn't within `MAX_BRACKET_DISTANCE`.
+pub fn matching_pair(
+    rope: &Rope,
+    tree: Option<&Tree>,
+    offset: usize,
+) -> Option<usize> {
+    if offset >= rope.len() {
+        return None;
+    }
+    let c = rope.byte_at(offset);
+    let (other, open) = bracket_pair(c)?;
+    match tree {
+        Some(tree) => {
+            let node = tree
+                .root_node()
+                .descendant_for_byte_range(offset, offset + 1)?;
+            if !is_bracket(&node) || node.start_byte() != offset {
+                return None;
+            }
+            // the brackets of a pair are children of the same node, with
+            // the ones nested in between in nodes of their own
+            let mut sibling = node;
+            loop {
+                sibling = match open {
+                    true => sibling.next_sibling()?,
+                    false => sibling.prev_sibling()?,
+                };
+                if is_bracket(&sibling) && sibling.kind().as_bytes()[0] == other {
+                    return Some(sibling.start_byte());
+                }
+            }
+        }
+        None => {
+            let mut depth = 0;
+            let mut scan = |i: usize| {
+                let current = rope.byte_at(i);
+                if current == c {
+                    depth += 1;
+                } else if current == other {
+                    depth -= 1;
+                    if depth == 0 {
+                        return true;
+                    }
+                }
+                false
+            };
+            match open {
+                true => (offset..rope.len().min(offset + MAX_BRACKET_DISTANCE))
+                    .find(|i| scan(*i)),
+                false => (offset.saturating_sub(MAX_BRACKET_DISTANCE)..offset + 1)
+                    .rev()
+                    .find(|i| scan(*i)),
+            }
+        }
+    }
+}
+
+/// The brackets in `start..end` and how many pairs of brackets each is
+/// in, for the core to color them by. Without a tree the depths count
+/// from `MAX_BRACKET_DISTANCE` before `start`.
+pub fn bracket_depths(
+    rope: &Rope,
+    tree: Option<&Tree>,
+    start: usize,
+    end: usize,
+) -> Vec<(usize, usize)> {
+    let end = end.min(rope.len());
+    let mut depths = Vec::new();
+    match tree {
+        Some(tree) => {
+            node_bracket_depths(tree.root_node(), 0, start, end, &mut depths)
+        }
+        None => {
+            let mut depth: usize = 0;
+            for i in start.saturating_sub(MAX_BRACKET_DISTANCE)..end {
+                if let Some((_, open)) = bracket_pair(rope.byte_at(i)) {
+                    if !open {
+                        depth = depth.saturating_sub(1);
+                    }
+                    if i >= start {
+                        depths.push((i, depth));
+                    }
+                    if open {
+                        depth += 1;
+                    }
+                }
+            }
+        }
+    }
+    depths
+}
+
+/// The brackets of `node` are at `depth`, and what's between them one in.
+fn node_bracket_depths(
+    node: Node,
+    depth: usize,
+    start: usize,
+    end: usize,
+    depths: &mut Vec<(usize, usize)>,
+) {
+    let children: Vec<Node> = (0..node.child_count())
+        .filter_map(|i| node.child(i))
+        .collect();
+    let inner = match children.iter().any(is_bracket) {
+        true => depth + 1,
+        false => depth,
+    };
+    for child in children {
+        if child.end_byte() <= start || child.start_byte() >= end {
+            continue;
+        }
+        if is_bracket(&child) {
+            depths.push((child.start_byte(), depth));
+        } else {
+            node_bracket_depths(child, inner, start, end, depths);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn f(a: [u8; 2]) { g(\"(\"); }";
+
+    fn tree() -> Tree {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_rust::language()).unwrap();
+        parser.parse(TEXT, None).unwrap()
+    }
+
+    #[test]
+    fn test_matching_pair() {
+        let rope = Rope::from(TEXT);
+        let tree = tree();
+        for tree in [Some(&tree), None] {
+            assert_eq!(Some(15), matching_pair(&rope, tree, 4));
+            assert_eq!(Some(4), matching_pair(&rope, tree, 15));
+            assert_eq!(Some(14), matching_pair(&rope, tree, 8));
+            assert_eq!(Some(17), matching_pair(&rope, tree, 27));
+            assert_eq!(None, matching_pair(&rope, tree, 0));
+        }
+        // the one in the string is text, unless there's no tree to say so
+        assert_eq!(None, matching_pair(&rope, Some(&tree), 22));
+        assert_eq!(Some(24), matching_pair(&rope, Some(&tree), 20));
+        assert_eq!(Some(24), matching_pair(&rope, None, 22));
+        assert_eq!(None, matching_pair(&rope, None, 20));
+
+        let unbalanced =
+            Rope::from(format!("({}", " ".repeat(MAX_BRACKET_DISTANCE)));
+        assert_eq!(None, matching_pair(&unbalanced, None, 0));
+    }
+
+    #[test]
+    fn test_bracket_depths() {
+        let rope = Rope::from(TEXT);
+        assert_eq!(
+            vec![
+                (4, 0),
+                (8, 1),
+                (14, 1),
+                (15, 0),
+                (17, 0),
+                (20, 1),
+                (24, 1),
+                (27, 0)
+            ],
+            bracket_depths(&rope, Some(&tree()), 0, rope.len())
+        );
+        assert_eq!(
+            vec![(20, 1), (22, 2), (24, 2)],
+            bracket_depths(&rope, None, 19, 25)
+        );
+    }
+}
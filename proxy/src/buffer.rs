@@ -1,20 +1,49 @@
 use anyhow::{anyhow, Result};
 use crossbeam_channel::Sender;
-use std::ffi::OsString;
-use std::fs::File;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::io::Read;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::{borrow::Cow, path::Path, time::SystemTime};
 use std::{fs, str::FromStr};
 
 use lsp_types::*;
 use serde::{Deserialize, Deserializer, Serialize};
+use tree_sitter::Tree;
 use xi_rope::{
     interval::IntervalBounds, rope::Rope, Cursor, Delta, DeltaBuilder, Interval,
     LinesMetric, RopeDelta, RopeInfo, Transformer,
 };
 
+use crate::encoding::{is_binary, read_file, FileEncoding, BINARY_SNIFF_LEN};
+use crate::indent::{indent_style, IndentStyle};
+use crate::language::language_id;
+use crate::line_ending::{LineEnding, LineEndings};
+use crate::lsp::tree_sitter_language;
+use crate::position::{lsp_position_to_offset, offset_to_lsp_position};
+use crate::save::{write_file, READ_ONLY_FILE_SYSTEM};
+use crate::settings::setting;
+use crate::symbols::SymbolStore;
+
+/// Files bigger than this many megabytes open read-only and are sent to the
+/// editor by chunks of lines, unless `large-file-size` in the `[editor]`
+/// settings says otherwise.
+const LARGE_FILE_SIZE: u64 = 20;
+/// How many lines apart the offsets of a large file are kept.
+const LINE_CHECKPOINT: usize = 1000;
+/// How many lines of a large file the editor gets at a time.
+pub const LARGE_FILE_CHUNK_LINES: usize = 5000;
+/// The code of the error an edit or a save of a read-only buffer gets, with
+/// a `BufferReadOnly` as its data.
+pub const READ_ONLY_ERROR_CODE: i64 = 3;
+/// The most bytes of a binary file `open_binary` reads at a time.
+pub const MAX_BINARY_CHUNK: usize = 1024 * 1024;
+
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct BufferId(pub usize);
 
@@ -27,22 +56,214 @@ pub struct Buffer {
     pub dirty: bool,
     sender: Sender<(BufferId, u64)>,
     pub mod_time: Option<SystemTime>,
+    /// How the file is indented, as told by its `.editorconfig` or its
+    /// lines.
+    pub indent: IndentStyle,
+    /// The workspace folder the file is in, the innermost one if folders
+    /// are nested.
+    pub root: Option<PathBuf>,
+    /// Set for a file too big to be loaded, whose rope stays empty.
+    pub large_file: Option<Arc<LargeFile>>,
+    /// Set for a file that isn't text, which the editor shows as hex read
+    /// with `open_binary`. Its rope stays empty.
+    pub binary: bool,
+    /// What the file is written in, and written back in on save.
+    pub encoding: FileEncoding,
+    /// The line endings of the file, which the rope doesn't have.
+    pub line_endings: LineEndings,
+    /// The hash of the file as it was last loaded or saved, to tell a real
+    /// change on disk from a touch.
+    content_hash: u64,
+    /// How the file changed on disk since, until it's reloaded or saved
+    /// over.
+    pub external_change: Option<ExternalChange>,
+    /// Why the buffer can't be edited, if it can't.
+    pub read_only: Option<ReadOnlyReason>,
+    /// The tree of the rope at a rev, parsed when it's asked for.
+    syntax_tree: Option<(u64, Tree)>,
+    /// The document symbols of the latest rev a server gave them for.
+    pub symbols: SymbolStore,
+}
+
+/// A change to the file of a buffer made by another program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalChange {
+    /// The buffer had no edits, and is reloaded unless `auto-reload` is
+    /// off.
+    Changed,
+    /// The buffer has edits, and either the file or the edits have to go.
+    Conflict,
+    Deleted,
+}
+
+/// Why a buffer is read-only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadOnlyReason {
+    /// The user can't write the file, but could with more rights.
+    NoPermission,
+    ReadOnlyFileSystem,
+    /// A file in `.git/objects`, which git never changes in place.
+    GitObject,
+    LargeFile,
+    Binary,
+    /// Asked for with `set_buffer_readonly`, like for the files of a diff.
+    Requested,
+}
+
+/// The error of an edit or a save refused because the buffer is read-only.
+/// It goes to the editor as the data of the error, so that a save the user
+/// has no permission for can be offered again with sudo.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BufferReadOnly {
+    pub path: PathBuf,
+    pub reason: ReadOnlyReason,
+    /// Whether writing the file with more rights would work.
+    pub elevated_save: bool,
+}
+
+impl BufferReadOnly {
+    pub fn new(path: &Path, reason: ReadOnlyReason) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            reason,
+            elevated_save: reason == ReadOnlyReason::NoPermission,
+        }
+    }
+}
+
+impl std::fmt::Display for BufferReadOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            ReadOnlyReason::NoPermission => "no permission to write it",
+            ReadOnlyReason::ReadOnlyFileSystem => "the file system is read-only",
+            ReadOnlyReason::GitObject => "it's a git object",
+            ReadOnlyReason::LargeFile => "it's a large file",
+            ReadOnlyReason::Binary => "it's a binary file",
+            ReadOnlyReason::Requested => "it was opened read-only",
+        };
+        write!(f, "{:?} is read-only: {}", self.path, reason)
+    }
+}
+
+impl std::error::Error for BufferReadOnly {}
+
+/// Where the lines of a file too big to keep in a rope start, found by a
+/// scan for newlines.
+pub struct LargeFile {
+    pub num_lines: usize,
+    /// The byte offset of every `LINE_CHECKPOINT`th line.
+    checkpoints: Vec<u64>,
+}
+
+impl LargeFile {
+    fn scan(path: &Path) -> Result<LargeFile> {
+        let mut f = File::open(path)?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut checkpoints = vec![0];
+        let mut offset = 0;
+        let mut newlines = 0;
+        let mut ends_with_newline = true;
+        loop {
+            let n = f.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            for (i, b) in buf[..n].iter().enumerate() {
+                if *b == b'\n' {
+                    newlines += 1;
+                    if newlines % LINE_CHECKPOINT == 0 {
+                        checkpoints.push(offset + i as u64 + 1);
+                    }
+                }
+            }
+            ends_with_newline = buf[n - 1] == b'\n';
+            offset += n as u64;
+        }
+        let num_lines = if ends_with_newline {
+            newlines
+        } else {
+            newlines + 1
+        };
+        Ok(LargeFile {
+            num_lines,
+            checkpoints,
+        })
+    }
+
+    /// The text of the lines from `start_line` up to `end_line`, with their
+    /// line endings.
+    pub fn read_lines(
+        &self,
+        path: &Path,
+        start_line: usize,
+        end_line: usize,
+    ) -> Result<String> {
+        let checkpoint =
+            (start_line / LINE_CHECKPOINT).min(self.checkpoints.len() - 1);
+        let mut reader = BufReader::new(File::open(path)?);
+        reader.seek(SeekFrom::Start(self.checkpoints[checkpoint]))?;
+        let mut line = checkpoint * LINE_CHECKPOINT;
+        let mut skipped = Vec::new();
+        while line < start_line {
+            skipped.clear();
+            if reader.read_until(b'\n', &mut skipped)? == 0 {
+                break;
+            }
+            line += 1;
+        }
+        let mut bytes = Vec::new();
+        while line < end_line {
+            if reader.read_until(b'\n', &mut bytes)? == 0 {
+                break;
+            }
+            line += 1;
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
 
 impl Buffer {
+    /// Loads the file, a large one being scanned for its lines instead,
+    /// which errs if the scan fails rather than reading it whole.
     pub fn new(
         id: BufferId,
         path: PathBuf,
         sender: Sender<(BufferId, u64)>,
-    ) -> Buffer {
-        let rope = if let Ok(rope) = load_file(&path) {
-            rope
-        } else {
-            Rope::from("")
+    ) -> Result<Buffer> {
+        let binary = is_binary(&read_head(&path));
+        let large_file = match fs::metadata(&path) {
+            Ok(metadata) if !binary && metadata.len() > large_file_size() => {
+                let large_file = LargeFile::scan(&path).map_err(|e| {
+                    anyhow!("can't scan the large file {:?}: {}", path, e)
+                })?;
+                Some(Arc::new(large_file))
+            }
+            _ => None,
+        };
+        let bytes = match large_file {
+            Some(_) => Vec::new(),
+            None if binary => Vec::new(),
+            None => fs::read(&path).unwrap_or_default(),
+        };
+        let encoding = FileEncoding::detect(&bytes);
+        let (text, line_endings) = LineEndings::normalize(&encoding.decode(&bytes));
+        let rope = Rope::from(text);
+        // large and binary files get no highlighting or language server
+        let language_id = match large_file {
+            Some(_) => String::new(),
+            None if binary => String::new(),
+            None => language_id(&path, &rope).unwrap_or_default(),
         };
-        let language_id = language_id_from_path(&path).unwrap_or("").to_string();
+        let indent = indent_style(&path, &rope, &language_id);
         let mod_time = get_mod_time(&path);
-        Buffer {
+        let read_only = match large_file {
+            Some(_) => Some(ReadOnlyReason::LargeFile),
+            None if binary => Some(ReadOnlyReason::Binary),
+            None => read_only_reason(&path),
+        };
+        Ok(Buffer {
             id,
             rope,
             path,
@@ -51,43 +272,236 @@ impl Buffer {
             sender,
             dirty: false,
             mod_time,
+            indent,
+            root: None,
+            large_file,
+            binary,
+            encoding,
+            line_endings,
+            content_hash: hash_bytes(&bytes),
+            external_change: None,
+            read_only,
+            syntax_tree: None,
+            symbols: SymbolStore::default(),
+        })
+    }
+
+    /// The syntax tree of the rope, if there's a grammar for its language.
+    pub fn syntax_tree(&mut self) -> Option<&Tree> {
+        if self.syntax_tree.as_ref().map(|(rev, _)| *rev) != Some(self.rev) {
+            let language = tree_sitter_language(&self.language_id)?;
+            let mut parser = tree_sitter::Parser::new();
+            parser.set_language(language).ok()?;
+            let tree = parser.parse(self.rope.to_string(), None)?;
+            self.syntax_tree = Some((self.rev, tree));
+        }
+        self.syntax_tree.as_ref().map(|(_, tree)| tree)
+    }
+
+    pub fn editable(&self) -> bool {
+        self.read_only.is_none()
+    }
+
+    /// Errs with a `BufferReadOnly` if the buffer can't be edited.
+    pub fn check_editable(&self) -> Result<()> {
+        match self.read_only {
+            Some(reason) => Err(BufferReadOnly::new(&self.path, reason).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes the buffer read-only, or editable again. A large file stays
+    /// read-only, but a file without write permission can be edited, to be
+    /// saved with more rights.
+    pub fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        if read_only {
+            self.read_only.get_or_insert(ReadOnlyReason::Requested);
+        } else if let Some(ReadOnlyReason::LargeFile | ReadOnlyReason::Binary) =
+            self.read_only
+        {
+            return self.check_editable();
+        } else {
+            self.read_only = None;
+        }
+        Ok(())
+    }
+
+    /// What formatting requests are made with, from the buffer's
+    /// indentation.
+    pub fn formatting_options(&self) -> FormattingOptions {
+        FormattingOptions {
+            tab_size: self.indent.width(),
+            insert_spaces: self.indent.insert_spaces(),
+            ..Default::default()
         }
     }
 
-    pub fn save(&mut self, rev: u64) -> Result<()> {
+    /// Writes the buffer to its file, unless another program changed the
+    /// file since and `overwrite` isn't set.
+    pub fn save(&mut self, rev: u64, overwrite: bool) -> Result<()> {
         if self.rev != rev {
             return Err(anyhow!("not the right rev"));
         }
-        self.dirty = false;
-        let tmp_extension = self.path.extension().map_or_else(
-            || OsString::from("swp"),
-            |ext| {
-                let mut ext = ext.to_os_string();
-                ext.push(".swp");
-                ext
-            },
-        );
-        let tmp_path = &self.path.with_extension(tmp_extension);
-
-        let mut f = File::create(tmp_path)?;
-        for chunk in self.rope.iter_chunks(..self.rope.len()) {
-            f.write_all(chunk.as_bytes())?;
+        self.check_editable()?;
+        if !overwrite {
+            if let Some(ExternalChange::Changed | ExternalChange::Conflict) =
+                self.check_disk()
+            {
+                return Err(anyhow!(
+                    "{:?} changed on disk, reload it or overwrite it",
+                    self.path
+                ));
+            }
+        }
+        let bytes = self.encoding.encode(&self.file_text())?;
+        if let Err(e) = write_file(&self.path, &bytes) {
+            // the file can have lost its permissions since it was loaded
+            return Err(match read_only_reason(&self.path) {
+                Some(reason) => BufferReadOnly::new(&self.path, reason).into(),
+                None => e,
+            });
         }
-        fs::rename(tmp_path, &self.path)?;
+        self.dirty = false;
         self.mod_time = get_mod_time(&self.path);
+        self.content_hash = hash_bytes(&bytes);
+        self.external_change = None;
         Ok(())
     }
 
+    /// Writes the buffer to another file, staying the buffer of its own. A
+    /// binary file has no text and can't be edited, so it's copied as it is
+    /// on disk.
+    pub fn save_copy_as(&self, path: &Path) -> Result<()> {
+        if self.large_file.is_some() {
+            return Err(anyhow!("large files are read-only"));
+        }
+        if self.binary {
+            return write_file(path, &fs::read(&self.path)?);
+        }
+        let bytes = self.encoding.encode(&self.file_text())?;
+        write_file(path, &bytes)
+    }
+
+    /// Reads the file again, dropping any edits.
     pub fn reload(&mut self) {
-        let rope = if let Ok(rope) = load_file(&self.path) {
-            rope
-        } else {
-            Rope::from("")
+        if self.large_file.is_some() || self.binary {
+            return;
+        }
+        let bytes = fs::read(&self.path).unwrap_or_default();
+        self.load_bytes(&bytes);
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) {
+        let (text, line_endings) =
+            LineEndings::normalize(&self.encoding.decode(bytes));
+        self.rope = Rope::from(text);
+        self.line_endings = line_endings;
+        self.content_hash = hash_bytes(bytes);
+        self.mod_time = get_mod_time(&self.path);
+        self.external_change = None;
+        self.dirty = false;
+        self.rev += 1;
+        self.sender.send((self.id, self.rev));
+    }
+
+    /// Takes the text and rev the core has, which it kept through a restart
+    /// of the proxy with the edits not saved yet. A file whose lines end in
+    /// more than one way gets the dominant ending on every line if the text
+    /// isn't that of the file.
+    pub fn resync(&mut self, content: &str, rev: u64) {
+        self.rev = rev;
+        if self.large_file.is_some() || self.binary {
+            return;
+        }
+        if self.rope.to_string() != content {
+            self.rope = Rope::from(content);
+            self.line_endings = LineEndings::uniform(self.line_endings.dominant);
+            self.dirty = true;
+        }
+        self.syntax_tree = None;
+        self.sender.send((self.id, self.rev));
+    }
+
+    /// Looks at the file for a change made by another program. A file
+    /// touched but with the same content isn't changed.
+    pub fn check_disk(&mut self) -> Option<ExternalChange> {
+        if self.large_file.is_some() || self.binary {
+            return None;
+        }
+        self.external_change = match fs::read(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Some(ExternalChange::Deleted)
+            }
+            Err(_) => return None,
+            Ok(bytes) if hash_bytes(&bytes) == self.content_hash => None,
+            Ok(_) if self.dirty => Some(ExternalChange::Conflict),
+            Ok(_) => Some(ExternalChange::Changed),
         };
+        self.mod_time = get_mod_time(&self.path);
+        self.external_change
+    }
+
+    /// Reads the file again as `encoding`, for when the detected one was
+    /// wrong. Unsaved changes would be lost, so a dirty buffer is refused.
+    pub fn reopen_with_encoding(&mut self, encoding: FileEncoding) -> Result<()> {
+        if self.dirty {
+            return Err(anyhow!("save or revert the changes before reopening"));
+        }
+        if self.large_file.is_some() {
+            return Err(anyhow!("large files can only be read as UTF-8"));
+        }
+        if self.binary {
+            return Err(anyhow!("binary files are shown as hex"));
+        }
+        let bytes = fs::read(&self.path)?;
+        let bom = matches!(
+            encoding_rs::Encoding::for_bom(&bytes),
+            Some((bom_encoding, _)) if bom_encoding == encoding.encoding
+        );
+        self.encoding = FileEncoding { bom, ..encoding };
+        self.load_bytes(&bytes);
+        Ok(())
+    }
 
-        self.rope = rope;
+    /// Saves the buffer in another encoding, which it keeps from then on.
+    /// The encoding stays as it was if the text can't be written in it.
+    pub fn save_with_encoding(
+        &mut self,
+        rev: u64,
+        encoding: FileEncoding,
+    ) -> Result<()> {
+        let old_encoding = self.encoding;
+        self.encoding = encoding;
+        let result = self.save(rev, false);
+        if result.is_err() {
+            self.encoding = old_encoding;
+        }
+        result
+    }
+
+    /// Makes every line end with `ending` from the next save on.
+    pub fn set_line_ending(&mut self, ending: LineEnding) {
+        if self.line_endings != LineEndings::uniform(ending) {
+            self.line_endings = LineEndings::uniform(ending);
+            self.dirty = true;
+        }
+    }
+
+    /// Makes the edits a server formatted the buffer with, as a change of
+    /// its own.
+    pub fn apply_formatting(&mut self, edits: &[TextEdit]) -> Result<()> {
+        let delta = text_edits_delta(&self.rope, edits)?;
         self.rev += 1;
+        self.dirty = true;
+        self.line_endings.update(&delta, &self.rope);
+        self.rope = delta.apply(&self.rope);
         self.sender.send((self.id, self.rev));
+        Ok(())
+    }
+
+    /// The text as it's written to the file, with its line endings.
+    pub fn file_text(&self) -> String {
+        self.line_endings.restore(&self.rope.to_string())
     }
 
     pub fn update(
@@ -101,6 +515,7 @@ impl Buffer {
         self.rev += 1;
         self.dirty = true;
         let content_change = get_document_content_changes(delta, self);
+        self.line_endings.update(delta, &self.rope);
         self.rope = delta.apply(&self.rope);
         let content_change = match content_change {
             Some(content_change) => content_change,
@@ -118,6 +533,10 @@ impl Buffer {
         self.rope.to_string()
     }
 
+    pub fn num_lines(&self) -> usize {
+        self.rope.measure::<LinesMetric>() + 1
+    }
+
     pub fn offset_of_line(&self, offset: usize) -> usize {
         self.rope.offset_of_line(offset)
     }
@@ -132,11 +551,11 @@ impl Buffer {
     }
 
     pub fn offset_to_position(&self, offset: usize) -> Position {
-        let (line, col) = self.offset_to_line_col(offset);
-        Position {
-            line: line as u32,
-            character: col as u32,
-        }
+        offset_to_lsp_position(&self.rope, offset)
+    }
+
+    pub fn offset_of_position(&self, position: &Position) -> Result<usize> {
+        lsp_position_to_offset(&self.rope, position)
     }
 
     pub fn slice_to_cow<T: IntervalBounds>(&self, range: T) -> Cow<str> {
@@ -148,19 +567,63 @@ impl Buffer {
     }
 }
 
-fn load_file(path: &PathBuf) -> Result<Rope> {
-    let mut f = File::open(path)?;
+/// The first bytes of a file, to tell whether it's text.
+fn read_head(path: &Path) -> Vec<u8> {
+    let mut head = Vec::new();
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(BINARY_SNIFF_LEN as u64).read_to_end(&mut head);
+    }
+    head
+}
+
+/// Up to `len` bytes of a binary file from `offset`, at most
+/// `MAX_BINARY_CHUNK`, with the size of the file. Past the end, there are
+/// none.
+pub fn read_bytes(path: &Path, offset: u64, len: usize) -> Result<(Vec<u8>, u64)> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
     let mut bytes = Vec::new();
-    f.read_to_end(&mut bytes)?;
-    Ok(Rope::from(std::str::from_utf8(&bytes)?))
+    if offset < file_size {
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(len.min(MAX_BINARY_CHUNK) as u64)
+            .read_to_end(&mut bytes)?;
+    }
+    Ok((bytes, file_size))
 }
 
-fn language_id_from_path(path: &PathBuf) -> Option<&str> {
-    Some(match path.extension()?.to_str()? {
-        "rs" => "rust",
-        "go" => "go",
-        _ => return None,
-    })
+/// Why the file at `path` can't be written, if it can't. A file that
+/// doesn't exist yet can be.
+fn read_only_reason(path: &Path) -> Option<ReadOnlyReason> {
+    let components: Vec<_> = path.components().map(|c| c.as_os_str()).collect();
+    if components
+        .windows(2)
+        .any(|pair| pair[0] == ".git" && pair[1] == "objects")
+    {
+        return Some(ReadOnlyReason::GitObject);
+    }
+    // opening doesn't truncate or touch the content
+    match OpenOptions::new().write(true).open(path) {
+        Err(e) if e.raw_os_error() == Some(READ_ONLY_FILE_SYSTEM) => {
+            Some(ReadOnlyReason::ReadOnlyFileSystem)
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Some(ReadOnlyReason::NoPermission)
+        }
+        _ => None,
+    }
+}
+
+/// The size in bytes from which files open as large files.
+fn large_file_size() -> u64 {
+    let size = setting(&["editor", "large-file-size"])
+        .and_then(|size| size.as_integer())
+        .map_or(LARGE_FILE_SIZE, |size| size as u64);
+    size * 1024 * 1024
+}
+
+pub(crate) fn load_file(path: &PathBuf) -> Result<Rope> {
+    let (text, _) = read_file(path)?;
+    Ok(Rope::from(text))
 }
 
 fn get_document_content_changes(
@@ -205,6 +668,20 @@ fn get_document_content_changes(
     None
 }
 
+/// Whether a buffer without edits follows changes to its file on disk, from
+/// `auto-reload` in the `[editor]` settings.
+pub fn auto_reload() -> bool {
+    setting(&["editor", "auto-reload"])
+        .and_then(|auto_reload| auto_reload.as_bool())
+        .unwrap_or(true)
+}
+
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
 /// Returns the modification timestamp for the file at a given path,
 /// if present.
 pub fn get_mod_time<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
@@ -213,3 +690,244 @@ pub fn get_mod_time<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
         .and_then(|meta| meta.modified())
         .ok()
 }
+
+/// Answers `apply_file_edits`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ApplyFileEditsResponse {
+    /// The edits putting each file back as it was, for the edit to be
+    /// undone when the buffers open can't have theirs.
+    pub undo: HashMap<PathBuf, Vec<TextEdit>>,
+}
+
+/// Applies text edits to files that aren't open in any buffer. Every file
+/// is edited in memory first, so nothing is written if any edit fails, and
+/// the files written are put back if one of them can't be.
+pub fn apply_file_edits(
+    edits: &HashMap<PathBuf, Vec<TextEdit>>,
+) -> Result<ApplyFileEditsResponse> {
+    let mut contents = Vec::new();
+    for (path, edits) in edits.iter() {
+        let original = fs::read(path)?;
+        let (text, encoding) = read_file(path)?;
+        let rope = apply_text_edits(&Rope::from(&text), edits)?;
+        let undo = TextEdit {
+            range: Range {
+                start: Position::new(0, 0),
+                end: offset_to_lsp_position(&rope, rope.len()),
+            },
+            new_text: text,
+        };
+        contents.push((path, encoding.encode(&rope.to_string())?, original, undo));
+    }
+    let mut written = Vec::new();
+    for (path, content, original, _) in contents.iter() {
+        if let Err(e) = write_file(path, content) {
+            for (path, original) in written {
+                let _ = write_file(path, original);
+            }
+            return Err(e);
+        }
+        written.push((path, original));
+    }
+    Ok(ApplyFileEditsResponse {
+        undo: contents
+            .into_iter()
+            .map(|(path, _, _, undo)| (path.clone(), vec![undo]))
+            .collect(),
+    })
+}
+
+pub(crate) fn apply_text_edits(rope: &Rope, edits: &[TextEdit]) -> Result<Rope> {
+    Ok(text_edits_delta(rope, edits)?.apply(rope))
+}
+
+fn text_edits_delta(rope: &Rope, edits: &[TextEdit]) -> Result<RopeDelta> {
+    let mut edits = edits
+        .iter()
+        .map(|edit| {
+            Ok((
+                lsp_position_to_offset(rope, &edit.range.start)?,
+                lsp_position_to_offset(rope, &edit.range.end)?,
+                edit.new_text.as_str(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+
+    let mut builder = DeltaBuilder::new(rope.len());
+    let mut last_end = 0;
+    for (start, end, text) in edits {
+        if start < last_end || end < start {
+            return Err(anyhow!("overlapping text edits"));
+        }
+        builder.replace(start..end, Rope::from(text));
+        last_end = end;
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn text_edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position::new(start.0, start.1),
+                end: Position::new(end.0, end.1),
+            },
+            new_text: new_text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_text_edits() {
+        let rope = Rope::from("let ä = 1;\nlet b = 2;\n");
+        let edits = vec![
+            text_edit((1, 4), (1, 5), "c"),
+            text_edit((0, 4), (0, 5), "a"),
+            text_edit((2, 0), (2, 0), "let d = 3;\n"),
+        ];
+        let rope = apply_text_edits(&rope, &edits).unwrap();
+        assert_eq!(rope.to_string(), "let a = 1;\nlet c = 2;\nlet d = 3;\n");
+
+        let edits =
+            vec![text_edit((0, 0), (0, 5), ""), text_edit((0, 4), (0, 6), "")];
+        assert!(apply_text_edits(&rope, &edits).is_err());
+    }
+
+    #[test]
+    fn test_resync() {
+        let dir = TestDir::new("resync");
+        let path = dir.join("file");
+        std::fs::write(&path, "a\r\nb\r\n").unwrap();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut buffer = Buffer::new(BufferId(1), path.clone(), sender).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        buffer.resync("a\nb\n", 7);
+        assert_eq!(7, buffer.rev);
+        assert!(!buffer.dirty);
+        buffer.resync("a\nc\n", 8);
+        assert!(buffer.dirty);
+        assert_eq!("a\r\nc\r\n", buffer.file_text());
+    }
+
+    #[test]
+    fn test_large_file_lines() {
+        let dir = TestDir::new("large-file");
+        let path = dir.join("file");
+        let text: String = (0..2500).map(|i| format!("line {}\n", i)).collect();
+        std::fs::write(&path, text + "last").unwrap();
+        let large_file = LargeFile::scan(&path).unwrap();
+        let lines = large_file.read_lines(&path, 1999, 2001).unwrap();
+        let end = large_file.read_lines(&path, 2500, 2600).unwrap();
+
+        assert_eq!(2501, large_file.num_lines);
+        assert_eq!("line 1999\nline 2000\n", lines);
+        assert_eq!("last", end);
+    }
+
+    #[test]
+    fn test_external_change() {
+        let dir = TestDir::new("external-change");
+        let path = dir.join("file");
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut buffer = Buffer::new(BufferId(0), path.clone(), sender).unwrap();
+        assert_eq!("one\ntwo\n", buffer.get_document());
+
+        // touched with the same content
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+        assert_eq!(None, buffer.check_disk());
+
+        std::fs::write(&path, "one\r\n2\r\n").unwrap();
+        assert_eq!(Some(ExternalChange::Changed), buffer.check_disk());
+        buffer.dirty = true;
+        assert_eq!(Some(ExternalChange::Conflict), buffer.check_disk());
+        assert!(buffer.save(0, false).is_err());
+        buffer.save(0, true).unwrap();
+        assert_eq!(None, buffer.external_change);
+        assert_eq!(b"one\r\ntwo\r\n", std::fs::read(&path).unwrap().as_slice());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(ExternalChange::Deleted), buffer.check_disk());
+    }
+
+    #[test]
+    fn test_apply_file_edits() {
+        let dir = TestDir::new("apply-file-edits");
+        let path = dir.join("file");
+        std::fs::write(&path, "let a = 1;\nlet b = a;\n").unwrap();
+        let mut edits = HashMap::new();
+        edits.insert(
+            path.clone(),
+            vec![
+                text_edit((0, 4), (0, 5), "ä"),
+                text_edit((1, 8), (1, 9), "ä"),
+            ],
+        );
+        let resp = apply_file_edits(&edits).unwrap();
+        assert_eq!(
+            "let ä = 1;\nlet b = ä;\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        apply_file_edits(&resp.undo).unwrap();
+        assert_eq!(
+            "let a = 1;\nlet b = a;\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        // a file that can't be read leaves the others alone
+        edits.insert(path.with_extension("missing"), Vec::new());
+        assert!(apply_file_edits(&edits).is_err());
+        assert_eq!(
+            "let a = 1;\nlet b = a;\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let object = Path::new("/repo/.git/objects/ab/cdef");
+        assert_eq!(Some(ReadOnlyReason::GitObject), read_only_reason(object));
+        let dir = TestDir::new("read-only");
+        assert_eq!(None, read_only_reason(&dir.join("missing/file")));
+
+        let path = dir.join("file");
+        std::fs::write(&path, "text").unwrap();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let mut buffer = Buffer::new(BufferId(0), path.clone(), sender).unwrap();
+        assert!(buffer.editable());
+        buffer.set_read_only(true).unwrap();
+        let e = buffer.save(0, true).unwrap_err();
+        let e = e.downcast_ref::<BufferReadOnly>().unwrap();
+        assert_eq!(ReadOnlyReason::Requested, e.reason);
+        assert!(!e.elevated_save);
+        buffer.set_read_only(false).unwrap();
+        buffer.save(0, true).unwrap();
+    }
+
+    #[test]
+    fn test_binary() {
+        let dir = TestDir::new("binary");
+        let path = dir.join("image.png");
+        let bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+        std::fs::write(&path, bytes).unwrap();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let buffer = Buffer::new(BufferId(0), path.clone(), sender).unwrap();
+        assert!(buffer.binary);
+        assert!(!buffer.editable());
+        assert_eq!("", buffer.get_document());
+
+        let (chunk, file_size) = read_bytes(&path, 4, 4).unwrap();
+        assert_eq!(&bytes[4..8], chunk.as_slice());
+        assert_eq!(bytes.len() as u64, file_size);
+        assert!(read_bytes(&path, 100, 4).unwrap().0.is_empty());
+
+        let copy = path.with_extension("copy.png");
+        buffer.save_copy_as(&copy).unwrap();
+        assert_eq!(bytes.to_vec(), std::fs::read(&copy).unwrap());
+    }
+}
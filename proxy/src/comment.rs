@@ -0,0 +1,402 @@
+//! Comments lines out or back in, and parts of lines in block comments,
+//! with the tokens of the language the text is in, which for the scripts
+//! and styles of an HTML file isn't the language of the file.
+//!
+//! The tokens come from `COMMENT_TOKENS`, over which the user's
+//! `[comment-tokens.<language>]` go, as in:
+//!
+//! ```toml
+//! [comment-tokens.nix]
+//! line = "#"
+//! block = ["/*", "*/"]
+//! ```
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::Tree;
+use xi_rope::Rope;
+
+use crate::settings::setting;
+
+/// The line comment token and the block comment pair by language id.
+const COMMENT_TOKENS: &[(&str, Option<&str>, Option<(&str, &str)>)] = &[
+    ("rust", Some("//"), Some(("/*", "*/"))),
+    ("go", Some("//"), Some(("/*", "*/"))),
+    ("c", Some("//"), Some(("/*", "*/"))),
+    ("cpp", Some("//"), Some(("/*", "*/"))),
+    ("java", Some("//"), Some(("/*", "*/"))),
+    ("kotlin", Some("//"), Some(("/*", "*/"))),
+    ("csharp", Some("//"), Some(("/*", "*/"))),
+    ("swift", Some("//"), Some(("/*", "*/"))),
+    ("javascript", Some("//"), Some(("/*", "*/"))),
+    ("javascriptreact", Some("//"), Some(("/*", "*/"))),
+    ("typescript", Some("//"), Some(("/*", "*/"))),
+    ("typescriptreact", Some("//"), Some(("/*", "*/"))),
+    ("php", Some("//"), Some(("/*", "*/"))),
+    ("zig", Some("//"), None),
+    ("css", None, Some(("/*", "*/"))),
+    ("python", Some("#"), None),
+    ("ruby", Some("#"), None),
+    ("elixir", Some("#"), None),
+    ("shellscript", Some("#"), None),
+    ("makefile", Some("#"), None),
+    ("dockerfile", Some("#"), None),
+    ("cmake", Some("#"), None),
+    ("toml", Some("#"), None),
+    ("yaml", Some("#"), None),
+    ("pip-requirements", Some("#"), None),
+    ("nix", Some("#"), Some(("/*", "*/"))),
+    ("lua", Some("--"), Some(("--[[", "]]"))),
+    ("haskell", Some("--"), Some(("{-", "-}"))),
+    ("html", None, Some(("<!--", "-->"))),
+    ("markdown", None, Some(("<!--", "-->"))),
+];
+
+/// The languages embedded in others, by the element they're in.
+const EMBEDDED_LANGUAGES: &[(&str, &str, &str)] =
+    &[("html", "script", "javascript"), ("html", "style", "css")];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct CommentTokens {
+    pub line: Option<String>,
+    pub block: Option<(String, String)>,
+}
+
+/// The result of `toggle_comment`, for the text of `rev`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToggleCommentResponse {
+    pub rev: u64,
+    /// The ranges replaced and what with, in order and not overlapping.
+    pub edits: Vec<(usize, usize, String)>,
+    /// The selections in the text after the edits, as `(start, end)`.
+    pub selections: Vec<(usize, usize)>,
+}
+
+/// The `[comment-tokens]` of the settings, by language id.
+pub fn user_comment_tokens() -> HashMap<String, CommentTokens> {
+    setting(&["comment-tokens"])
+        .and_then(|tokens| tokens.try_into().ok())
+        .unwrap_or_default()
+}
+
+/// The tokens of a language, the user's over the defaults.
+pub fn comment_tokens(
+    language_id: &str,
+    user: &HashMap<String, CommentTokens>,
+) -> Option<CommentTokens> {
+    user.get(language_id).cloned().or_else(|| {
+        COMMENT_TOKENS
+            .iter()
+            .find(|(id, _, _)| *id == language_id)
+            .map(|(_, line, block)| CommentTokens {
+                line: line.map(String::from),
+                block: block.map(|(open, close)| (open.into(), close.into())),
+            })
+    })
+}
+
+/// The language of the text at `offset`: the one of an element like
+/// `<script>` it's in, or else the language of the file. The tree says
+/// which element that is when there's a grammar for the file's language,
+/// and otherwise the tags before the offset do.
+pub fn language_at(
+    language_id: &str,
+    rope: &Rope,
+    tree: Option<&Tree>,
+    offset: usize,
+) -> String {
+    let embedded = EMBEDDED_LANGUAGES
+        .iter()
+        .filter(|(host, _, _)| *host == language_id);
+    let element = match tree {
+        Some(tree) => {
+            let mut node =
+                tree.root_node().descendant_for_byte_range(offset, offset);
+            let mut element = None;
+            while let Some(current) = node {
+                // the tags of the element are in the host language
+                if current.kind() == "raw_text" {
+                    element = current.parent().map(|parent| parent.kind());
+                    break;
+                }
+                node = current.parent();
+            }
+            element.map(|kind| kind.trim_end_matches("_element").to_string())
+        }
+        None => {
+            let before = rope.slice_to_cow(..offset).to_lowercase();
+            embedded
+                .clone()
+                .map(|(_, element, _)| *element)
+                .find(|element| {
+                    let open = before.rfind(&format!("<{}", element));
+                    let close = before.rfind(&format!("</{}", element));
+                    match open {
+                        Some(open) => {
+                            close.map(|close| close < open).unwrap_or(true)
+                                && before[open..].contains('>')
+                        }
+                        None => false,
+                    }
+                })
+                .map(String::from)
+        }
+    };
+    element
+        .and_then(|element| {
+            embedded
+                .clone()
+                .find(|(_, name, _)| *name == element)
+                .map(|(_, _, language)| language.to_string())
+        })
+        .unwrap_or_else(|| language_id.to_string())
+}
+
+/// The edits that comment out the lines of each selection, or the part of
+/// a line it selects, or that take the comments back out, with the
+/// selections after them. Lines are commented out unless all of them are
+/// commented already, at the smallest indent of the lines.
+pub fn toggle_comment(
+    rope: &Rope,
+    selections: &[(usize, usize)],
+    tokens_at: impl Fn(usize) -> Option<CommentTokens>,
+) -> (Vec<(usize, usize, String)>, Vec<(usize, usize)>) {
+    let mut edits = Vec::new();
+    let mut lines_done: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in selections {
+        let (start, end) = (*start.min(end), *start.max(end));
+        let start_line = rope.line_of_offset(start);
+        let mut end_line = rope.line_of_offset(end);
+        if end > start
+            && end_line > start_line
+            && rope.offset_of_line(end_line) == end
+        {
+            end_line -= 1;
+        }
+
+        let (content_start, content_end) = content_range(rope, start_line);
+        let mid_line = end > start
+            && start_line == end_line
+            && (start > content_start || end < content_end);
+        if mid_line {
+            if let Some((open, close)) =
+                tokens_at(start).and_then(|tokens| tokens.block)
+            {
+                edits.extend(toggle_block(rope, start, end, &open, &close));
+                continue;
+            }
+        }
+
+        if lines_done
+            .iter()
+            .any(|(first, last)| *first <= end_line && start_line <= *last)
+        {
+            continue;
+        }
+        lines_done.push((start_line, end_line));
+        let lines: Vec<(usize, usize)> = (start_line..=end_line)
+            .map(|line| content_range(rope, line))
+            .filter(|(start, end)| start < end)
+            .collect();
+        let (first, last) = match (lines.first(), lines.last()) {
+            (Some(first), Some(last)) => (first.0, last.1),
+            _ => continue,
+        };
+        let tokens = match tokens_at(first) {
+            Some(tokens) => tokens,
+            None => continue,
+        };
+        match (tokens.line, tokens.block) {
+            (Some(token), _) => edits.extend(toggle_lines(rope, &lines, &token)),
+            (None, Some((open, close))) => {
+                edits.extend(toggle_block(rope, first, last, &open, &close))
+            }
+            (None, None) => {}
+        }
+    }
+
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    let mut kept: Vec<(usize, usize, String)> = Vec::with_capacity(edits.len());
+    for edit in edits {
+        if kept.last().map(|last| last.1 <= edit.0).unwrap_or(true) {
+            kept.push(edit);
+        }
+    }
+
+    let selections = selections
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                let offset = shift_offset(&kept, *start, true);
+                (offset, offset)
+            } else {
+                let forward = start < end;
+                (
+                    shift_offset(&kept, *start, !forward),
+                    shift_offset(&kept, *end, forward),
+                )
+            }
+        })
+        .collect();
+    (kept, selections)
+}
+
+/// From the first non-blank character of the line to the end of its last
+/// one.
+fn content_range(rope: &Rope, line: usize) -> (usize, usize) {
+    let line_start = rope.offset_of_line(line);
+    let text = rope.slice_to_cow(line_start..rope.offset_of_line(line + 1));
+    let indent = text.len() - text.trim_start().len();
+    let content = text.trim_end();
+    (
+        line_start + indent.min(content.len()),
+        line_start + content.len(),
+    )
+}
+
+/// Lines commented out with `token` at their smallest indent, or the
+/// tokens and the space after them taken out when all have one.
+fn toggle_lines(
+    rope: &Rope,
+    lines: &[(usize, usize)],
+    token: &str,
+) -> Vec<(usize, usize, String)> {
+    let commented = lines
+        .iter()
+        .all(|(start, end)| rope.slice_to_cow(*start..*end).starts_with(token));
+    if commented {
+        return lines
+            .iter()
+            .map(|(start, end)| {
+                let text = rope.slice_to_cow(*start..*end);
+                let space = text[token.len()..].starts_with(' ') as usize;
+                (*start, start + token.len() + space, String::new())
+            })
+            .collect();
+    }
+    let indent = lines
+        .iter()
+        .map(|(start, _)| start - rope.offset_of_line(rope.line_of_offset(*start)))
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|(start, _)| {
+            let offset = rope.offset_of_line(rope.line_of_offset(*start)) + indent;
+            (offset, offset, format!("{} ", token))
+        })
+        .collect()
+}
+
+/// `start..end` in a block comment, or out of the one it is, leaving the
+/// blanks around it.
+fn toggle_block(
+    rope: &Rope,
+    start: usize,
+    end: usize,
+    open: &str,
+    close: &str,
+) -> Vec<(usize, usize, String)> {
+    let text = rope.slice_to_cow(start..end);
+    let start = start + text.len() - text.trim_start().len();
+    let inner = text.trim();
+    let end = start + inner.len();
+    if inner.len() >= open.len() + close.len()
+        && inner.starts_with(open)
+        && inner.ends_with(close)
+    {
+        let body = &inner[open.len()..inner.len() - close.len()];
+        let after_open = body.starts_with(' ') as usize;
+        let before_close = (body.len() > after_open && body.ends_with(' ')) as usize;
+        return vec![
+            (start, start + open.len() + after_open, String::new()),
+            (end - close.len() - before_close, end, String::new()),
+        ];
+    }
+    vec![
+        (start, start, format!("{} ", open)),
+        (end, end, format!(" {}", close)),
+    ]
+}
+
+/// Where `offset` is after the edits. An offset where text is inserted is
+/// put after it when `after`, and before it otherwise.
+fn shift_offset(
+    edits: &[(usize, usize, String)],
+    offset: usize,
+    after: bool,
+) -> usize {
+    let mut shifted = offset;
+    for (start, end, text) in edits {
+        if *end < offset || (*end == offset && (start < end || after)) {
+            shifted = shifted + text.len() - (end - start);
+        } else if *start < offset {
+            // in the text replaced
+            shifted =
+                shifted - (offset - start) + if after { text.len() } else { 0 };
+        }
+    }
+    shifted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toggle(
+        text: &str,
+        selections: &[(usize, usize)],
+    ) -> (String, Vec<(usize, usize)>) {
+        let rope = Rope::from(text);
+        let (edits, selections) = toggle_comment(&rope, selections, |_| {
+            comment_tokens("rust", &HashMap::new())
+        });
+        let mut text = text.to_string();
+        for (start, end, new_text) in edits.iter().rev() {
+            text.replace_range(start..end, new_text);
+        }
+        (text, selections)
+    }
+
+    #[test]
+    fn test_toggle_comment() {
+        // mixed lines get commented, at the smallest indent
+        let text = "fn f() {\n    // a();\n        b();\n\n    c();\n}\n";
+        assert_eq!(
+            (
+                "fn f() {\n    // // a();\n    //     b();\n\n    // c();\n}\n"
+                    .to_string(),
+                vec![(9, 53)]
+            ),
+            toggle(text, &[(9, 44)])
+        );
+        // and uncommented when all of them are
+        let text = "    // a();\n    //b();\n";
+        assert_eq!(
+            ("    a();\n    b();\n".to_string(), vec![(7, 15)]),
+            toggle(text, &[(10, 20)])
+        );
+        // a part of a line goes in a block comment, and back out
+        let text = "f(a, b);\n";
+        assert_eq!(
+            ("f(/* a, b */);\n".to_string(), vec![(2, 12)]),
+            toggle(text, &[(2, 6)])
+        );
+        assert_eq!(
+            ("f(a, b);\n".to_string(), vec![(2, 6)]),
+            toggle("f(/* a, b */);\n", &[(2, 12)])
+        );
+    }
+
+    #[test]
+    fn test_language_at() {
+        let rope =
+            Rope::from("<p>a</p>\n<script type=\"module\">\nf();\n</script>\n");
+        assert_eq!("html", language_at("html", &rope, None, 3));
+        assert_eq!("html", language_at("html", &rope, None, 16));
+        assert_eq!("javascript", language_at("html", &rope, None, 34));
+        assert_eq!("html", language_at("html", &rope, None, rope.len()));
+        assert_eq!("rust", language_at("rust", &rope, None, 34));
+    }
+}
@@ -0,0 +1,280 @@
+//! The merge conflicts in the text of a buffer, between the `<<<<<<<`,
+//! `=======` and `>>>>>>>` markers git leaves, with the `|||||||` base
+//! section of the diff3 style too. A marker is exactly seven chars, the
+//! longer ones of a conflict nested in another by a recursive merge being
+//! part of its sides. Markers out of place, like a second `<<<<<<<` before
+//! the end of a conflict, drop the conflict they'd have been part of rather
+//! than make one up.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictChoice {
+    Ours,
+    Theirs,
+    /// Ours then theirs.
+    Both,
+}
+
+/// A conflict, by lines. The sides are the lines between the markers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// The line of the `<<<<<<<` marker.
+    pub start: usize,
+    /// The line of the `>>>>>>>` marker.
+    pub end: usize,
+    pub ours: Range<usize>,
+    /// Only in the diff3 style.
+    pub base: Option<Range<usize>>,
+    pub theirs: Range<usize>,
+    /// What follows the markers, as in `HEAD` or a branch name.
+    pub ours_label: String,
+    pub base_label: Option<String>,
+    pub theirs_label: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictResolution {
+    pub rev: u64,
+    /// The range replaced and what with, as for a comment toggle.
+    pub edits: Vec<(usize, usize, String)>,
+}
+
+enum Marker {
+    Start,
+    Base,
+    Separator,
+    End,
+}
+
+/// The marker a line is, with its label.
+fn marker(line: &str) -> Option<(Marker, &str)> {
+    let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+    let (marker, c) = match line.get(..7)? {
+        "<<<<<<<" => (Marker::Start, b'<'),
+        "|||||||" => (Marker::Base, b'|'),
+        "=======" => (Marker::Separator, b'='),
+        ">>>>>>>" => (Marker::End, b'>'),
+        _ => return None,
+    };
+    let rest = &line[7..];
+    if rest.as_bytes().first() == Some(&c) {
+        return None;
+    }
+    match marker {
+        // the separator has no label
+        Marker::Separator if !rest.trim().is_empty() => None,
+        _ if !rest.is_empty() && !rest.starts_with(' ') => None,
+        _ => Some((marker, rest.trim())),
+    }
+}
+
+enum State {
+    Outside,
+    Ours {
+        start: usize,
+        label: String,
+    },
+    Base {
+        start: usize,
+        label: String,
+        base_start: usize,
+        base_label: String,
+    },
+    Theirs {
+        start: usize,
+        label: String,
+        base: Option<(Range<usize>, String)>,
+        separator: usize,
+    },
+}
+
+pub fn parse_conflicts(text: &str) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+    let mut state = State::Outside;
+    for (line, line_text) in text.split_inclusive('\n').enumerate() {
+        let (marker, marker_label) = match marker(line_text) {
+            Some(marker) => marker,
+            None => continue,
+        };
+        let marker_label = marker_label.to_string();
+        state = match (state, marker) {
+            // a start out of place drops the conflict it's in
+            (_, Marker::Start) => State::Ours {
+                start: line,
+                label: marker_label,
+            },
+            (State::Ours { start, label }, Marker::Base) => State::Base {
+                start,
+                label,
+                base_start: line,
+                base_label: marker_label,
+            },
+            (State::Ours { start, label }, Marker::Separator) => State::Theirs {
+                start,
+                label,
+                base: None,
+                separator: line,
+            },
+            (
+                State::Base {
+                    start,
+                    label,
+                    base_start,
+                    base_label,
+                },
+                Marker::Separator,
+            ) => State::Theirs {
+                start,
+                label,
+                base: Some((base_start + 1..line, base_label)),
+                separator: line,
+            },
+            (
+                State::Theirs {
+                    start,
+                    label,
+                    base,
+                    separator,
+                },
+                Marker::End,
+            ) => {
+                let ours_end = base
+                    .as_ref()
+                    .map(|(base, _)| base.start - 1)
+                    .unwrap_or(separator);
+                let (base, base_label) = match base {
+                    Some((base, base_label)) => (Some(base), Some(base_label)),
+                    None => (None, None),
+                };
+                conflicts.push(Conflict {
+                    start,
+                    end: line,
+                    ours: start + 1..ours_end,
+                    base,
+                    theirs: separator + 1..line,
+                    ours_label: label,
+                    base_label,
+                    theirs_label: marker_label,
+                });
+                State::Outside
+            }
+            // in the theirs side only an end is a marker
+            (state @ State::Theirs { .. }, _) => state,
+            // an end or a base out of place drops the conflict
+            (_, Marker::End) | (_, Marker::Base) => State::Outside,
+            (state @ State::Outside, Marker::Separator) => state,
+        };
+    }
+    conflicts
+}
+
+/// The byte offset of the start of each line, and of the end of the text.
+fn line_offsets(text: &str) -> Vec<usize> {
+    let mut offsets = vec![0];
+    offsets.extend(
+        text.match_indices('\n')
+            .map(|(i, _)| i + 1)
+            .filter(|i| *i < text.len()),
+    );
+    offsets.push(text.len());
+    offsets
+}
+
+/// The edit that resolves a conflict of `text`, with the lines of the side
+/// chosen in place of the conflict.
+pub fn resolve_conflict(
+    text: &str,
+    conflict: &Conflict,
+    choice: ConflictChoice,
+) -> (usize, usize, String) {
+    let offsets = line_offsets(text);
+    let offset = |line: usize| offsets[line.min(offsets.len() - 1)];
+    let lines = |range: &Range<usize>| &text[offset(range.start)..offset(range.end)];
+    let mut replacement = match choice {
+        ConflictChoice::Ours => lines(&conflict.ours).to_string(),
+        ConflictChoice::Theirs => lines(&conflict.theirs).to_string(),
+        ConflictChoice::Both => {
+            let mut both = lines(&conflict.ours).to_string();
+            if !both.is_empty() && !both.ends_with('\n') {
+                both.push('\n');
+            }
+            both.push_str(lines(&conflict.theirs));
+            both
+        }
+    };
+    // the last line of a text without a line break stays without one
+    let end = offset(conflict.end + 1);
+    if !text[..end].ends_with('\n') && replacement.ends_with('\n') {
+        replacement.pop();
+        if replacement.ends_with('\r') {
+            replacement.pop();
+        }
+    }
+    (offset(conflict.start), end, replacement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(text: &str, index: usize, choice: ConflictChoice) -> String {
+        let conflicts = parse_conflicts(text);
+        let (start, end, replacement) =
+            resolve_conflict(text, &conflicts[index], choice);
+        format!("{}{}{}", &text[..start], replacement, &text[end..])
+    }
+
+    #[test]
+    fn test_parse_conflicts() {
+        let simple = include_str!("../tests/fixtures/conflicts/simple.txt");
+        let conflicts = parse_conflicts(simple);
+        assert_eq!(2, conflicts.len());
+        assert_eq!(
+            Conflict {
+                start: 1,
+                end: 5,
+                ours: 2..3,
+                base: None,
+                theirs: 4..5,
+                ours_label: "HEAD".to_string(),
+                base_label: None,
+                theirs_label: "feature".to_string(),
+            },
+            conflicts[0]
+        );
+        assert!(resolved(simple, 0, ConflictChoice::Ours)
+            .starts_with("fn main() {\n    let a = 1;\n    println!"));
+        assert!(resolved(simple, 1, ConflictChoice::Both)
+            .ends_with("    ours();\n    theirs();\n}\n"));
+
+        let diff3 = include_str!("../tests/fixtures/conflicts/diff3.txt");
+        let conflicts = parse_conflicts(diff3);
+        assert_eq!(1, conflicts.len());
+        assert_eq!(2..3, conflicts[0].ours);
+        assert_eq!(Some(4..6), conflicts[0].base);
+        assert_eq!(7..8, conflicts[0].theirs);
+        assert_eq!(
+            Some("merged common ancestors"),
+            conflicts[0].base_label.as_deref()
+        );
+        assert_eq!(
+            "start\ntheirs\nend",
+            resolved(diff3, 0, ConflictChoice::Theirs)
+        );
+
+        // a longer marker is content, and a start inside a conflict drops it
+        let nested = include_str!("../tests/fixtures/conflicts/nested.txt");
+        let conflicts = parse_conflicts(nested);
+        assert_eq!(2, conflicts.len());
+        assert_eq!((0, 8), (conflicts[0].start, conflicts[0].end));
+        assert_eq!(1..4, conflicts[0].ours);
+        assert_eq!((10, 14), (conflicts[1].start, conflicts[1].end));
+
+        let degenerate = include_str!("../tests/fixtures/conflicts/degenerate.txt");
+        assert!(parse_conflicts(degenerate).is_empty());
+    }
+}
@@ -1,106 +1,360 @@
-use crate::buffer::{get_mod_time, Buffer, BufferId};
+use crate::auto_save::{debounce_edits, AutoSaveMode, AutoSaveSettings};
+use crate::backup::{Backups, DirtyBuffer, BACKUP_INTERVAL};
+use crate::blame::{blame_file, Blames, FileBlame};
+use crate::brackets::matching_pair;
+use crate::buffer::{
+    apply_file_edits, auto_reload, get_mod_time, load_file, read_bytes, Buffer,
+    BufferId, BufferReadOnly, ExternalChange, LARGE_FILE_CHUNK_LINES,
+    READ_ONLY_ERROR_CODE,
+};
+use crate::comment::{
+    comment_tokens, language_at, toggle_comment, user_comment_tokens,
+    ToggleCommentResponse,
+};
+use crate::conflicts::{
+    parse_conflicts, resolve_conflict, ConflictChoice, ConflictResolution,
+};
 use crate::core_proxy::CoreProxy;
-use crate::lsp::LspCatalog;
-use crate::plugin::PluginCatalog;
-use crate::terminal::{TermId, Terminal};
-use alacritty_terminal::event_loop::Msg;
-use alacritty_terminal::term::SizeInfo;
+use crate::encoding::FileEncoding;
+use crate::file_index::{walk_folder, FileIndex};
+use crate::file_ops::{
+    create_directory, create_file, duplicate_path, natural_cmp, read_dir,
+    rename_path, reveal_path, trash_path, DirListing,
+};
+use crate::git::{
+    buffer_git_diff, git_branch_info, git_branches, git_checkout, git_commit,
+    git_create_branch, git_diff, git_diff_paths, git_discard, git_file_history,
+    git_show_file_at, git_stage, git_stage_hunk, git_stash_list, git_stash_pop,
+    git_stash_save, git_status, git_unstage, git_unstage_hunk, open_repo,
+    CheckoutConflict, CommitError, FileStatus, FileStatusKind, HunkRange,
+    BUFFER_DIFF_DEBOUNCE, CHECKOUT_ERROR_CODE, COMMIT_ERROR_CODE,
+};
+use crate::indent::IndentStyle;
+use crate::line_ending::LineEnding;
+use crate::lsp::{
+    DocumentLinkItem, LspCapabilities, LspCatalog, LspServerId, LspTimeout,
+    LspUnsupported, DOCUMENT_HIGHLIGHT_DELAY, FOLDING_RANGE_DELAY,
+    TIMEOUT_ERROR_CODE, UNSUPPORTED_ERROR_CODE, WILL_RENAME_TIMEOUT,
+    WILL_SAVE_TIMEOUT,
+};
+use crate::plugin::{
+    install_plugin, plugins_dir, user_settings_path, PluginCatalog,
+};
+use crate::registry;
+use crate::replace::{apply_replace, global_replace, Replaces};
+use crate::search::{global_search, search_exclude, SearchOptions, Searches};
+use crate::settings::{
+    set_workspace_trust, workspace_settings_path, LayeredSettings,
+};
+use crate::shell_env::{self, resolved_env};
+use crate::task::{load_tasks, run_task, RunningTask, TaskId};
+use crate::terminal::{TermId, Terminal, TerminalInfo, TerminalSettings};
+use crate::watcher::{FileWatcher, WatchEvent, WatchToken, WatcherSettings};
 use anyhow::{anyhow, Result};
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
 use git2::{DiffOptions, Oid, Repository};
 use jsonrpc_lite::{self, JsonRpc};
+use lapce_rpc::transport::{Transport, TransportOptions};
 use lapce_rpc::{self, Call, RequestId, RpcObject};
-use lsp_types::{CompletionItem, Position, TextDocumentContentChangeEvent};
-use notify::DebouncedEvent;
+use lsp_types::{
+    CodeAction, Command, CompletionItem, Diagnostic, FileChangeType, Location,
+    Position, Range, TextDocumentContentChangeEvent, TextEdit, WorkspaceEdit,
+};
 use parking_lot::Mutex;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use serde_json::Value;
+use std::path::PathBuf;
+use std::path::{Component, Path};
+use std::thread;
 use std::{cmp, fs};
 use std::{collections::HashMap, io};
 use std::{collections::HashSet, io::BufRead};
-use std::{path::PathBuf, sync::atomic::AtomicBool};
-use std::{sync::atomic, thread};
-use std::{sync::Arc, time::Duration};
-use xi_core_lib::watcher::{EventQueue, FileWatcher, Notify, WatchToken};
-use xi_rope::{RopeDelta, RopeInfo};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use xi_rope::{Rope, RopeDelta, RopeInfo};
 
 pub const OPEN_FILE_EVENT_TOKEN: WatchToken = WatchToken(1);
 pub const GIT_EVENT_TOKEN: WatchToken = WatchToken(2);
+pub const PLUGIN_EVENT_TOKEN: WatchToken = WatchToken(3);
+pub const SETTINGS_EVENT_TOKEN: WatchToken = WatchToken(4);
+pub const EXPLORER_EVENT_TOKEN: WatchToken = WatchToken(5);
+/// The git dir of a repository the workspace is in a subdirectory of, which
+/// the workspace's own watch doesn't see.
+pub const REPO_EVENT_TOKEN: WatchToken = WatchToken(6);
 
 #[derive(Clone)]
 pub struct Dispatcher {
-    pub sender: Arc<Sender<Value>>,
+    /// To the core attached, a new one when the proxy outlives the last.
+    pub sender: Arc<Mutex<Sender<Value>>>,
+    /// The options and counters of the connection of `sender`.
+    transport: Arc<Mutex<Transport>>,
     pub git_sender: Sender<(BufferId, u64)>,
     pub workspace: Arc<Mutex<PathBuf>>,
+    /// The roots shown side by side, starting with `workspace`.
+    pub workspace_folders: Arc<Mutex<Vec<PathBuf>>>,
     pub buffers: Arc<Mutex<HashMap<BufferId, Buffer>>>,
-    pub terminals: Arc<Mutex<HashMap<TermId, mio::channel::Sender<Msg>>>>,
+    pub buffer_subscriptions: Arc<Mutex<HashMap<BufferId, HashSet<String>>>>,
+    pub terminals: Arc<Mutex<HashMap<TermId, Terminal>>>,
+    pub tasks: Arc<Mutex<HashMap<TaskId, RunningTask>>>,
     open_files: Arc<Mutex<HashMap<String, BufferId>>>,
     plugins: Arc<Mutex<PluginCatalog>>,
     pub lsp: Arc<Mutex<LspCatalog>>,
     pub watcher: Arc<Mutex<Option<FileWatcher>>>,
-    pub workspace_updated: Arc<AtomicBool>,
+    /// The `auto-save` settings, read again when the settings change.
+    pub auto_save: Arc<Mutex<AutoSaveSettings>>,
+    /// The buffers edited, for `auto-save` after a delay.
+    auto_save_sender: Sender<BufferId>,
+    /// None without a home dir to write them to.
+    backups: Arc<Mutex<Option<Backups>>>,
+    /// The workspace search running, stopped by a newer one.
+    searches: Arc<Mutex<Searches>>,
+    /// The replacements of the last `global_replace`, until they're made.
+    replaces: Arc<Mutex<Replaces>>,
+    /// The files of the workspace folders, for the file palette.
+    file_index: Arc<Mutex<FileIndex>>,
+    /// The blames worked out, until HEAD moves or the file is saved.
+    blames: Arc<Mutex<Blames>>,
+    terminal_settings: Arc<Mutex<TerminalSettings>>,
+    /// The directories expanded in the explorer, whose entries are sent
+    /// again when they change.
+    explorer_dirs: Arc<Mutex<HashSet<PathBuf>>>,
+    /// The user and workspace settings last sent to the core.
+    settings: Arc<Mutex<LayeredSettings>>,
+    /// The requests sent to the core, waiting for its answer.
+    core_requests: Arc<Mutex<CoreRequests>>,
 }
 
-impl Notify for Dispatcher {
-    fn notify(&self) {
-        let dispatcher = self.clone();
-        thread::spawn(move || {
-            for (token, event) in
-                { dispatcher.watcher.lock().as_mut().unwrap().take_events() }
-                    .drain(..)
+#[derive(Default)]
+struct CoreRequests {
+    next_id: RequestId,
+    pending: HashMap<RequestId, Sender<Result<Value>>>,
+}
+
+/// The parts of the proxy that hear of the watcher's events, each on a
+/// thread of its own so a slow one doesn't hold back the others.
+impl Dispatcher {
+    fn subscribe_to_watcher(&self) {
+        let subscribers: [fn(&Dispatcher, &[(WatchToken, WatchEvent)]); 5] = [
+            Dispatcher::open_files_changed,
+            Dispatcher::workspace_changed,
+            Dispatcher::git_changed,
+            Dispatcher::explorer_changed,
+            Dispatcher::config_changed,
+        ];
+        for subscriber in subscribers {
+            let events = match self.watcher.lock().as_ref() {
+                Some(watcher) => watcher.subscribe(),
+                None => return,
+            };
+            let dispatcher = self.clone();
+            thread::spawn(move || {
+                for events in events {
+                    subscriber(&dispatcher, &events);
+                }
+            });
+        }
+    }
+
+    /// Reloads or marks the buffers whose files changed on disk. A touch
+    /// that only changes the mtime leaves them alone.
+    fn open_files_changed(&self, events: &[(WatchToken, WatchEvent)]) {
+        for (token, event) in events {
+            if *token != OPEN_FILE_EVENT_TOKEN
+                || matches!(event, WatchEvent::Metadata(_))
             {
-                match token {
-                    OPEN_FILE_EVENT_TOKEN => match event {
-                        DebouncedEvent::Write(path)
-                        | DebouncedEvent::Create(path) => {
-                            if let Some(buffer_id) = {
-                                dispatcher
-                                    .open_files
-                                    .lock()
-                                    .get(&path.to_str().unwrap().to_string())
-                            } {
-                                if let Some(buffer) =
-                                    dispatcher.buffers.lock().get_mut(buffer_id)
-                                {
-                                    if get_mod_time(&buffer.path) == buffer.mod_time
-                                    {
-                                        return;
-                                    }
-                                    if !buffer.dirty {
-                                        buffer.reload();
-                                        dispatcher.lsp.lock().update(
-                                            buffer,
-                                            &TextDocumentContentChangeEvent {
-                                                range: None,
-                                                range_length: None,
-                                                text: buffer.get_document(),
-                                            },
-                                            buffer.rev,
-                                        );
-                                        dispatcher.sender.send(json!({
-                                            "method": "reload_buffer",
-                                            "params": {
-                                                "buffer_id": buffer_id,
-                                                "rev": buffer.rev,
-                                                "new_content": buffer.get_document(),
-                                            },
-                                        }));
-                                    }
-                                }
-                            }
-                        }
-                        _ => (),
-                    },
-                    GIT_EVENT_TOKEN => {
-                        dispatcher
-                            .workspace_updated
-                            .store(true, atomic::Ordering::Relaxed);
+                continue;
+            }
+            for path in event.paths() {
+                let buffer_id = match path.to_str() {
+                    Some(path) => self.open_files.lock().get(path).cloned(),
+                    None => None,
+                };
+                let buffer_id = match buffer_id {
+                    Some(buffer_id) => buffer_id,
+                    None => continue,
+                };
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => continue,
+                };
+                if get_mod_time(&buffer.path) == buffer.mod_time {
+                    continue;
+                }
+                let before = buffer.external_change;
+                match buffer.check_disk() {
+                    Some(ExternalChange::Changed) if auto_reload() => {
+                        buffer.reload();
+                        self.buffer_reloaded(buffer);
                     }
-                    WatchToken(_) => {}
+                    change if change != before => {
+                        self.send_external_change(buffer);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Keeps the file index up to date and tells the servers about the
+    /// files of the workspace that changed.
+    fn workspace_changed(&self, events: &[(WatchToken, WatchEvent)]) {
+        let mut removed_paths = Vec::new();
+        let mut file_events = Vec::new();
+        for (token, event) in events {
+            if *token != GIT_EVENT_TOKEN {
+                continue;
+            }
+            match event.clone() {
+                WatchEvent::Created(path) => {
+                    self.file_index.lock().created(&path);
+                    file_events.push((path, FileChangeType::Created));
+                }
+                WatchEvent::Changed(path) => {
+                    file_events.push((path, FileChangeType::Changed));
+                }
+                WatchEvent::Metadata(_) => continue,
+                WatchEvent::Removed(path) => {
+                    self.file_index.lock().removed(&path);
+                    removed_paths.push(path.clone());
+                    file_events.push((path, FileChangeType::Deleted));
                 }
+                WatchEvent::Renamed(old, new) => {
+                    self.file_index.lock().renamed(&old, &new);
+                    removed_paths.push(old.clone());
+                    file_events.push((old, FileChangeType::Deleted));
+                    file_events.push((new, FileChangeType::Created));
+                }
+            }
+        }
+        if !removed_paths.is_empty() {
+            self.lsp.lock().evict_diagnostics(&removed_paths);
+        }
+        if !file_events.is_empty() {
+            self.lsp.lock().did_change_watched_files(&file_events);
+        }
+    }
+
+    /// Sends the git status again when anything in the workspace or its
+    /// repository changed, a commit or a checkout as well as an edit. A
+    /// change in the git dir can move HEAD, which the buffers are diffed
+    /// against.
+    fn git_changed(&self, events: &[(WatchToken, WatchEvent)]) {
+        let events: Vec<&(WatchToken, WatchEvent)> = events
+            .iter()
+            .filter(|(token, event)| {
+                (*token == GIT_EVENT_TOKEN || *token == REPO_EVENT_TOKEN)
+                    && !matches!(event, WatchEvent::Metadata(_))
+            })
+            .collect();
+        if events.is_empty() {
+            return;
+        }
+        self.refresh_git();
+        for (_, event) in &events {
+            for path in event.paths() {
+                self.blames.lock().invalidate(path);
             }
+        }
+        let in_git_dir = events.iter().any(|(token, event)| {
+            *token == REPO_EVENT_TOKEN
+                || event
+                    .paths()
+                    .iter()
+                    .any(|path| path.components().any(|c| c.as_os_str() == ".git"))
         });
+        if in_git_dir {
+            self.blames.lock().clear();
+            let revs: Vec<(BufferId, u64)> = self
+                .buffers
+                .lock()
+                .values()
+                .map(|buffer| (buffer.id, buffer.rev))
+                .collect();
+            for (buffer_id, rev) in revs {
+                self.send_buffer_diff(buffer_id, rev);
+            }
+        }
+    }
+
+    /// Sends the entries of the expanded directories that changed. A write
+    /// changes no entry, and a build writes a lot.
+    fn explorer_changed(&self, events: &[(WatchToken, WatchEvent)]) {
+        let paths: Vec<&Path> = events
+            .iter()
+            .filter(|(token, _)| *token == EXPLORER_EVENT_TOKEN)
+            .filter(|(_, event)| {
+                !matches!(event, WatchEvent::Changed(_) | WatchEvent::Metadata(_))
+            })
+            .flat_map(|(_, event)| event.paths())
+            .collect();
+        if !paths.is_empty() {
+            self.refresh_dirs(&paths);
+        }
+    }
+
+    /// Sends the settings to the core, unless none changed and `always`
+    /// isn't set, and has what keeps a copy of them take them again.
+    fn send_config_changed(&self, always: bool) {
+        let workspace = self.workspace.lock().clone();
+        let mut settings = self.settings.lock();
+        let last_warnings = settings.warnings().to_vec();
+        let change = settings.reload(&workspace);
+        drop(settings);
+        if always || !change.changed.is_empty() || change.warnings != last_warnings {
+            self.send_notification("config_changed", json!(change));
+        }
+        if !change.changed.is_empty() {
+            self.settings_changed();
+        }
+    }
+
+    /// Loads the settings again into what read them before the workspace's
+    /// were known or before they changed, and sends them to the servers.
+    fn settings_changed(&self) {
+        *self.auto_save.lock() = AutoSaveSettings::load();
+        *self.terminal_settings.lock() = TerminalSettings::load();
+        if let Some(watcher) = self.watcher.lock().as_ref() {
+            watcher.set_settings(WatcherSettings::load());
+        }
+        shell_env::settings_changed();
+        self.plugins.lock().configuration_changed();
+        self.lsp.lock().configuration_changed();
+    }
+
+    /// Reloads the plugins whose files changed, and the settings.
+    fn config_changed(&self, events: &[(WatchToken, WatchEvent)]) {
+        let mut changed_plugins = HashSet::new();
+        let mut settings_changed = false;
+        for (token, event) in events {
+            if matches!(event, WatchEvent::Metadata(_)) {
+                continue;
+            }
+            match *token {
+                PLUGIN_EVENT_TOKEN => {
+                    for path in event.paths() {
+                        if let Some(name) =
+                            self.plugins.lock().plugin_name_for_path(path)
+                        {
+                            changed_plugins.insert(name);
+                        }
+                    }
+                }
+                SETTINGS_EVENT_TOKEN => settings_changed = true,
+                _ => (),
+            }
+        }
+        if settings_changed {
+            self.send_config_changed(false);
+        }
+        for name in changed_plugins {
+            if let Err(e) = self.plugins.lock().reload_plugin(self.clone(), &name) {
+                eprintln!("reload plugin {} err {}", name, e);
+            }
+        }
     }
 }
 
@@ -110,24 +364,73 @@ impl Notify for Dispatcher {
 pub enum Notification {
     Initialize {
         workspace: PathBuf,
+        /// What the core wants done to the messages sent to it, for a slow
+        /// connection.
+        #[serde(default)]
+        transport: TransportOptions,
+    },
+    AddWorkspaceFolder {
+        path: PathBuf,
+    },
+    RemoveWorkspaceFolder {
+        path: PathBuf,
     },
+    /// Brings the proxy down, for testing that the core starts it again
+    /// with the buffers it had.
+    ProxyPanicForTest {},
     Update {
         buffer_id: BufferId,
         delta: RopeDelta,
         rev: u64,
     },
-    NewTerminal {
-        term_id: TermId,
-        cwd: Option<PathBuf>,
-    },
     TerminalWrite {
         term_id: TermId,
         content: String,
     },
     TerminalResize {
         term_id: TermId,
-        width: usize,
-        height: usize,
+        cols: usize,
+        rows: usize,
+    },
+    /// Kills the shell, which is then told as exited.
+    TerminalClose {
+        term_id: TermId,
+    },
+    DisablePlugin {
+        name: String,
+    },
+    EnablePlugin {
+        name: String,
+    },
+    ReloadPlugin {
+        name: String,
+    },
+    ExecutePluginCommand {
+        id: String,
+        args: Option<Value>,
+    },
+    RestartLspServer {
+        buffer_id: BufferId,
+    },
+    CancelSignature {
+        buffer_id: BufferId,
+    },
+    CursorMoved {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    /// The window or tab of the workspace lost focus.
+    FocusLost {},
+    CancelSearch {
+        search_id: u64,
+    },
+    /// A directory was expanded in the explorer.
+    WatchDir {
+        path: PathBuf,
+    },
+    /// A directory was collapsed in the explorer.
+    UnwatchDir {
+        path: PathBuf,
     },
 }
 
@@ -135,10 +438,67 @@ pub enum Notification {
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "method", content = "params")]
 pub enum Request {
+    /// Starts a shell in a pty, by default the login shell in the
+    /// workspace, answered with its id.
+    TerminalNew {
+        /// The id the core has for it, if any.
+        term_id: Option<TermId>,
+        cwd: Option<PathBuf>,
+        shell: Option<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+        cols: usize,
+        rows: usize,
+    },
+    /// Sends the output of a shell kept from an earlier core on again,
+    /// answered with the output kept.
+    TerminalAttach {
+        term_id: TermId,
+    },
+    TerminalList {},
+    /// The tasks of the workspace's tasks file.
+    ListTasks {},
+    /// Answered with the id of the run, its output and exit following in
+    /// notifications.
+    RunTask {
+        name: String,
+    },
+    CancelTask {
+        task_id: TaskId,
+    },
+    /// The environment of the login shell the processes the proxy starts
+    /// are given.
+    ResolvedEnv {},
+    ProxyVersion {},
+    /// The counters of the connection to the core.
+    ProxyStats {},
+    /// Takes the keys of the workspace settings that could run something
+    /// or not, as the user answered.
+    TrustWorkspace {
+        trusted: bool,
+    },
+    /// A key's value for the workspace, with its default and where it
+    /// comes from.
+    GetSetting {
+        key: String,
+    },
+    /// Sets a key of the user's settings file, or removes it for null.
+    UpdateSetting {
+        key: String,
+        value: Value,
+    },
     NewBuffer {
         buffer_id: BufferId,
         path: PathBuf,
     },
+    /// A buffer of the core's opened again with its text and rev, after
+    /// the proxy was restarted.
+    ResyncBuffer {
+        buffer_id: BufferId,
+        path: PathBuf,
+        content: String,
+        rev: u64,
+    },
     GetCompletion {
         request_id: usize,
         buffer_id: BufferId,
@@ -152,9 +512,18 @@ pub enum Request {
         buffer_id: BufferId,
         position: Position,
     },
+    GetHover {
+        buffer_id: BufferId,
+        position: Position,
+    },
     GetReferences {
         buffer_id: BufferId,
         position: Position,
+        include_declaration: bool,
+    },
+    GetImplementation {
+        buffer_id: BufferId,
+        position: Position,
     },
     GetDefinition {
         request_id: usize,
@@ -162,84 +531,510 @@ pub enum Request {
         position: Position,
     },
     GetCodeActions {
+        buffer_id: BufferId,
+        range: Range,
+        diagnostics: Vec<Diagnostic>,
+    },
+    GetInlayHints {
+        buffer_id: BufferId,
+        range: Range,
+    },
+    GetAllDiagnostics {},
+    GetDocumentLinks {
+        buffer_id: BufferId,
+        range: Range,
+    },
+    ResolveDocumentLink {
+        buffer_id: BufferId,
+        link: DocumentLinkItem,
+    },
+    GetSelectionRanges {
+        buffer_id: BufferId,
+        positions: Vec<Position>,
+    },
+    GetFoldingRanges {
+        buffer_id: BufferId,
+    },
+    GotoTypeDefinition {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    GotoDeclaration {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    PrepareCallHierarchy {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    CallHierarchyIncoming {
+        handle: u64,
+    },
+    CallHierarchyOutgoing {
+        handle: u64,
+    },
+    ResolveCodeAction {
+        buffer_id: BufferId,
+        action: CodeAction,
+    },
+    ExecuteCommand {
+        buffer_id: BufferId,
+        command: Command,
+    },
+    ApplyFileEdits {
+        edits: HashMap<PathBuf, Vec<TextEdit>>,
+    },
+    PrepareRename {
         buffer_id: BufferId,
         position: Position,
     },
+    Rename {
+        buffer_id: BufferId,
+        position: Position,
+        new_name: String,
+    },
     GetDocumentSymbols {
         buffer_id: BufferId,
     },
-    GetDocumentFormatting {
+    EnclosingScopes {
+        buffer_id: BufferId,
+        line: u32,
+    },
+    FormatDocument {
+        buffer_id: BufferId,
+    },
+    FormatRange {
         buffer_id: BufferId,
+        range: Range,
+    },
+    GetWorkspaceSymbols {
+        query: String,
+        limit: usize,
     },
     GetFiles {
         path: String,
     },
+    FuzzyFileSearch {
+        query: String,
+        max_results: usize,
+    },
+    /// Walks the workspace folders again, for when the watcher missed
+    /// something.
+    RebuildFileIndex {},
+    /// The entries of a directory, without going into the ones in it.
     ReadDir {
         path: PathBuf,
     },
+    /// The entries of the directories down to `path`, which are watched
+    /// like the ones expanded.
+    RevealPath {
+        path: PathBuf,
+    },
+    CreateFile {
+        path: PathBuf,
+    },
+    CreateDirectory {
+        path: PathBuf,
+    },
+    /// Renames a file or a directory, with the edits the servers want made
+    /// for it, like the `mod` lines of a Rust file, in the response for the
+    /// core to make. The buffers of the files moved follow them.
+    RenamePath {
+        old: PathBuf,
+        new: PathBuf,
+    },
+    TrashPath {
+        path: PathBuf,
+    },
+    DuplicatePath {
+        path: PathBuf,
+    },
+    /// The files of the workspace changed from HEAD, staged and not.
+    GitStatus {},
+    GitBranchInfo {},
+    /// The hunks of a buffer's text that differ from HEAD, for its gutter.
+    GitDiffBuffer {
+        buffer_id: BufferId,
+    },
+    GitStage {
+        paths: Vec<PathBuf>,
+    },
+    GitUnstage {
+        paths: Vec<PathBuf>,
+    },
+    /// Puts the working files back as they're staged. Unless `confirmed`
+    /// nothing is done, and the response tells what would be lost.
+    GitDiscard {
+        paths: Vec<PathBuf>,
+        #[serde(default)]
+        confirmed: bool,
+    },
+    GitStageHunk {
+        path: PathBuf,
+        hunk_range: HunkRange,
+    },
+    GitUnstageHunk {
+        path: PathBuf,
+        hunk_range: HunkRange,
+    },
+    /// Responds with the id of the commit made, or an error with a
+    /// `CommitError` as its data.
+    GitCommit {
+        message: String,
+        #[serde(default)]
+        amend: bool,
+    },
+    /// Who last changed each line of a file, as its buffer has it if it's
+    /// open, for the blame gutter.
+    GitBlame {
+        path: PathBuf,
+    },
+    /// The blame of one line, for the annotation at the cursor.
+    GitBlameLine {
+        path: PathBuf,
+        line: usize,
+    },
+    /// The merge conflicts in the text of a buffer.
+    GetConflicts {
+        buffer_id: BufferId,
+    },
+    /// The edit that keeps a side of a conflict, for the core to make so
+    /// it can be undone.
+    ResolveConflict {
+        buffer_id: BufferId,
+        conflict_index: usize,
+        choice: ConflictChoice,
+    },
+    /// Stages a file once it has no conflicts left.
+    GitMarkResolved {
+        path: PathBuf,
+    },
+    GitBranches {},
+    /// Checks out a branch or a commit, responding with an error with a
+    /// `CheckoutConflict` as its data when it would overwrite changes,
+    /// unless `force`.
+    GitCheckout {
+        target: String,
+        #[serde(default)]
+        create: bool,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Makes a branch at `from`, or at HEAD.
+    GitCreateBranch {
+        name: String,
+        from: Option<String>,
+    },
+    GitStashSave {
+        message: Option<String>,
+        #[serde(default)]
+        include_untracked: bool,
+    },
+    GitStashPop {
+        #[serde(default)]
+        index: usize,
+    },
+    GitStashList {},
+    /// The commits that changed a file, newest first, a page at a time.
+    GitFileHistory {
+        path: PathBuf,
+        limit: usize,
+        #[serde(default)]
+        skip: usize,
+    },
+    /// A file as it was at a commit, for a read-only buffer.
+    GitShowFileAt {
+        path: PathBuf,
+        commit_id: String,
+    },
+    /// The diff of a file between two revisions, or from one to the working
+    /// file without `rev_b`.
+    GitDiffPaths {
+        path: PathBuf,
+        rev_a: String,
+        rev_b: Option<String>,
+    },
+    GetBufferLines {
+        buffer_id: BufferId,
+        start_line: usize,
+        end_line: usize,
+    },
+    /// The bytes of a binary file, for the hex view, a page at a time.
+    OpenBinary {
+        path: PathBuf,
+        offset: u64,
+        len: usize,
+    },
+    SetBufferLanguage {
+        buffer_id: BufferId,
+        language_id: String,
+    },
+    WillSave {
+        buffer_id: BufferId,
+    },
     Save {
         rev: u64,
         buffer_id: BufferId,
+        /// Writes over a file another program changed since it was loaded.
+        #[serde(default)]
+        overwrite: bool,
+    },
+    ReloadBuffer {
+        buffer_id: BufferId,
+    },
+    SaveCopyAs {
+        buffer_id: BufferId,
+        path: PathBuf,
+    },
+    GetBufferEncoding {
+        buffer_id: BufferId,
+    },
+    SetLineEnding {
+        buffer_id: BufferId,
+        ending: LineEnding,
+    },
+    /// Makes a buffer read-only, like the files of a diff, or lets a user
+    /// edit a file they can't write.
+    SetBufferReadonly {
+        buffer_id: BufferId,
+        readonly: bool,
+    },
+    ReopenWithEncoding {
+        buffer_id: BufferId,
+        encoding: String,
+    },
+    SaveWithEncoding {
+        rev: u64,
+        buffer_id: BufferId,
+        encoding: String,
+    },
+    /// The backups of unsaved edits left by a proxy that crashed or was
+    /// closed with them, for the files of the workspace.
+    ListUnsavedBackups {},
+    RestoreBackup {
+        id: String,
+    },
+    DiscardBackup {
+        id: String,
+    },
+    /// The bracket matching the one at an offset, ignoring the ones in
+    /// strings and comments when the language has a grammar.
+    MatchingPair {
+        buffer_id: BufferId,
+        offset: usize,
+    },
+    ToggleComment {
+        buffer_id: BufferId,
+        selections: Vec<(usize, usize)>,
+    },
+    /// Answered at once, with the matches following in
+    /// `global_search_results` notifications.
+    GlobalSearch {
+        search_id: u64,
+        query: String,
+        options: SearchOptions,
+    },
+    /// Finds the replacements without making them, for `apply_replace`.
+    GlobalReplace {
+        query: String,
+        options: SearchOptions,
+        replacement: String,
+    },
+    ApplyReplace {
+        session_id: u64,
+        ids: Vec<usize>,
+    },
+    PluginList {},
+    PluginCommands {},
+    InstallPlugin {
+        source: String,
+    },
+    UninstallPlugin {
+        name: String,
     },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NewBufferResponse {
     pub content: String,
+    pub language_id: String,
+    /// A large file is read-only, and comes with its first lines only, the
+    /// rest being asked for with `get_buffer_lines`.
+    pub large_file: bool,
+    pub num_lines: usize,
+    /// What most lines of the file end with, shown in the status bar.
+    pub line_ending: LineEnding,
+    pub indent: IndentStyle,
+    /// Read-only buffers refuse edits, see `BufferReadOnly`.
+    pub editable: bool,
+    /// A binary file has no content, and is read with `open_binary`.
+    pub is_binary: bool,
+    pub file_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferLinesResponse {
+    pub text: String,
+    /// The line after the last one in `text`.
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryChunkResponse {
+    pub offset: u64,
+    /// The bytes from `offset`, in base64.
+    pub data: String,
+    pub file_size: u64,
+}
+
+/// A location found by a references or implementation request, with the
+/// line it points at for the preview.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceLocation {
+    pub path: PathBuf,
+    pub line: u32,
+    pub column: u32,
+    pub preview_line: String,
+    /// The file is ignored by git or lives in `target/`.
+    pub ignored: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Ord, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FileNodeItem {
     pub path_buf: PathBuf,
+    #[serde(default)]
+    pub name: String,
     pub is_dir: bool,
+    /// A symlink, to a directory when `is_dir`.
+    #[serde(default)]
+    pub is_symlink: bool,
+    /// Ignored by git, for the explorer to dim.
+    #[serde(default)]
+    pub is_ignored: bool,
+    #[serde(default)]
+    pub size: u64,
+    /// The seconds since the epoch of the last change.
+    #[serde(default)]
+    pub mtime: Option<u64>,
     pub read: bool,
     pub open: bool,
     pub children: Vec<FileNodeItem>,
 }
 
-impl std::cmp::PartialOrd for FileNodeItem {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        let self_dir = self.is_dir;
-        let other_dir = other.is_dir;
-        if self_dir && !other_dir {
-            return Some(cmp::Ordering::Less);
-        }
-        if !self_dir && other_dir {
-            return Some(cmp::Ordering::Greater);
+impl FileNodeItem {
+    /// The item of `path` as it is on disk, following a symlink for whether
+    /// it's a directory.
+    pub fn new(path: PathBuf) -> FileNodeItem {
+        let is_symlink = fs::symlink_metadata(&path)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+        let metadata = fs::metadata(&path).ok();
+        FileNodeItem {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            is_dir: metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false),
+            is_symlink,
+            is_ignored: false,
+            size: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+            mtime: metadata
+                .and_then(|m| m.modified().ok())
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+            path_buf: path,
+            read: false,
+            open: false,
+            children: Vec::new(),
         }
+    }
+}
 
-        let self_file_name = self.path_buf.file_name()?.to_str()?.to_lowercase();
-        let other_file_name = other.path_buf.file_name()?.to_str()?.to_lowercase();
-        if self_file_name.starts_with(".") && !other_file_name.starts_with(".") {
-            return Some(cmp::Ordering::Less);
-        }
-        if !self_file_name.starts_with(".") && other_file_name.starts_with(".") {
-            return Some(cmp::Ordering::Greater);
-        }
-        self_file_name.partial_cmp(&other_file_name)
+/// Directories first, then the hidden entries, each sorted by name with
+/// the numbers in them compared by value, so `file2` comes before `file10`.
+impl Ord for FileNodeItem {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        let name = |item: &FileNodeItem| {
+            item.path_buf
+                .file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .unwrap_or_default()
+        };
+        let (self_name, other_name) = (name(self), name(other));
+        other
+            .is_dir
+            .cmp(&self.is_dir)
+            .then_with(|| {
+                other_name.starts_with('.').cmp(&self_name.starts_with('.'))
+            })
+            .then_with(|| natural_cmp(&self_name, &other_name))
+            .then_with(|| self.path_buf.cmp(&other.path_buf))
+    }
+}
+
+impl PartialOrd for FileNodeItem {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl Dispatcher {
-    pub fn new(sender: Sender<Value>) -> Dispatcher {
+    pub fn new(sender: Sender<Value>, transport: Transport) -> Dispatcher {
         let plugins = PluginCatalog::new();
         let (git_sender, git_receiver) = unbounded();
+        let (auto_save_sender, auto_save_receiver) = unbounded();
         let dispatcher = Dispatcher {
-            sender: Arc::new(sender),
+            sender: Arc::new(Mutex::new(sender)),
+            transport: Arc::new(Mutex::new(transport)),
             git_sender,
             workspace: Arc::new(Mutex::new(PathBuf::new())),
+            workspace_folders: Arc::new(Mutex::new(Vec::new())),
             buffers: Arc::new(Mutex::new(HashMap::new())),
+            buffer_subscriptions: Arc::new(Mutex::new(HashMap::new())),
             open_files: Arc::new(Mutex::new(HashMap::new())),
             terminals: Arc::new(Mutex::new(HashMap::new())),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
             plugins: Arc::new(Mutex::new(plugins)),
             lsp: Arc::new(Mutex::new(LspCatalog::new())),
             watcher: Arc::new(Mutex::new(None)),
-            workspace_updated: Arc::new(AtomicBool::new(false)),
+            auto_save: Arc::new(Mutex::new(AutoSaveSettings::load())),
+            auto_save_sender,
+            backups: Arc::new(Mutex::new(Backups::new())),
+            searches: Arc::new(Mutex::new(Searches::default())),
+            replaces: Arc::new(Mutex::new(Replaces::default())),
+            file_index: Arc::new(Mutex::new(FileIndex::new(search_exclude()))),
+            blames: Arc::new(Mutex::new(Blames::default())),
+            terminal_settings: Arc::new(Mutex::new(TerminalSettings::load())),
+            explorer_dirs: Arc::new(Mutex::new(HashSet::new())),
+            settings: Arc::new(Mutex::new(LayeredSettings::default())),
+            core_requests: Arc::new(Mutex::new(CoreRequests::default())),
         };
-        *dispatcher.watcher.lock() = Some(FileWatcher::new(dispatcher.clone()));
+        *dispatcher.watcher.lock() = Some(FileWatcher::new(WatcherSettings::load()));
+        dispatcher.subscribe_to_watcher();
         dispatcher.lsp.lock().dispatcher = Some(dispatcher.clone());
-        dispatcher.plugins.lock().reload();
-        dispatcher.plugins.lock().start_all(dispatcher.clone());
+        // create it on first run so there's something to watch and
+        // install_plugin has somewhere to put plugins
+        match plugins_dir().and_then(|dir| {
+            fs::create_dir_all(&dir)?;
+            Ok(dir)
+        }) {
+            Ok(dir) => {
+                dispatcher.watcher.lock().as_mut().unwrap().watch(
+                    &dir,
+                    true,
+                    PLUGIN_EVENT_TOKEN,
+                );
+            }
+            Err(e) => eprintln!("plugins dir err {}", e),
+        }
+        dispatcher.plugins.lock().reload(dispatcher.clone());
+        if let Some(path) = user_settings_path() {
+            dispatcher.watcher.lock().as_mut().unwrap().watch(
+                &path,
+                false,
+                SETTINGS_EVENT_TOKEN,
+            );
+        }
         let local_dispatcher = dispatcher.clone();
         thread::spawn(move || {
             local_dispatcher.start_update_process(git_receiver);
@@ -247,7 +1042,16 @@ impl Dispatcher {
 
         let local_dispatcher = dispatcher.clone();
         thread::spawn(move || {
-            local_dispatcher.monitor_workspace_update();
+            debounce_edits(local_dispatcher, auto_save_receiver);
+        });
+
+        let local_dispatcher = dispatcher.clone();
+        thread::spawn(move || loop {
+            thread::sleep(BACKUP_INTERVAL);
+            let dirty = local_dispatcher.dirty_buffers();
+            if let Some(backups) = local_dispatcher.backups.lock().as_mut() {
+                backups.sync(&dirty);
+            }
         });
         dispatcher
     }
@@ -256,6 +1060,7 @@ impl Dispatcher {
         for msg in receiver {
             let rpc: RpcObject = msg.into();
             if rpc.is_response() {
+                self.handle_response(rpc);
             } else {
                 match rpc.into_rpc::<Notification, Request>() {
                     Ok(Call::Request(id, request)) => {
@@ -268,62 +1073,241 @@ impl Dispatcher {
                 }
             }
         }
+        self.shutdown();
         Ok(())
     }
 
-    pub fn monitor_workspace_update(&self) -> Result<()> {
-        loop {
-            thread::sleep(Duration::from_secs(1));
-            if self.workspace_updated.load(atomic::Ordering::Relaxed) {
-                self.workspace_updated
-                    .store(false, atomic::Ordering::Relaxed);
-                if let Some(diff_files) = git_diff(&self.workspace.lock()) {
-                    self.send_notification(
-                        "diff_files",
-                        json!({
-                            "files": diff_files,
-                        }),
-                    );
-                }
-            }
+    pub fn shutdown(&self) {
+        let dirty = self.dirty_buffers();
+        if let Some(backups) = self.backups.lock().as_mut() {
+            backups.shutdown(&dirty);
+        }
+        self.plugins.lock().stop_all();
+        self.lsp.lock().stop_all();
+    }
+
+    /// The buffers with unsaved edits, copied so that the backups are
+    /// written without the buffers locked.
+    fn dirty_buffers(&self) -> Vec<DirtyBuffer> {
+        self.buffers
+            .lock()
+            .values()
+            .filter(|buffer| buffer.dirty && !buffer.binary)
+            .map(|buffer| DirtyBuffer {
+                id: buffer.id,
+                path: buffer.path.file_name().map(|_| buffer.path.clone()),
+                rev: buffer.rev,
+                rope: buffer.rope.clone(),
+            })
+            .collect()
+    }
+
+    /// Sends the files that differ from HEAD, the status of the workspace
+    /// and its branch. Nothing is sent outside of a repository.
+    fn refresh_git(&self) {
+        let workspace = self.workspace.lock().clone();
+        if let Some(diff_files) = git_diff(&workspace) {
+            self.send_notification(
+                "diff_files",
+                json!({
+                    "files": diff_files,
+                }),
+            );
+        }
+        if let (Ok(status), Ok(branch)) =
+            (git_status(&workspace), git_branch_info(&workspace))
+        {
+            self.send_notification(
+                "git_status",
+                json!({
+                    "status": status,
+                    "branch": branch,
+                }),
+            );
+        }
+    }
+
+    /// Watches the git dir of the repository the workspace is in when it's
+    /// above the workspace, for its status to follow commits and checkouts.
+    fn watch_repo(&self, workspace: &Path) {
+        let git_dir = match open_repo(workspace) {
+            Some(repo) => repo.path().to_path_buf(),
+            None => return,
+        };
+        if !git_dir.starts_with(workspace) {
+            let mut watcher = self.watcher.lock();
+            let watcher = watcher.as_mut().unwrap();
+            watcher.unwatch(&git_dir, REPO_EVENT_TOKEN);
+            watcher.watch(&git_dir, true, REPO_EVENT_TOKEN);
         }
     }
 
+    /// Requests the semantic tokens of each buffer edited, and sends its
+    /// diff against HEAD once the edits pause for `BUFFER_DIFF_DEBOUNCE`.
     pub fn start_update_process(
         &self,
         receiver: Receiver<(BufferId, u64)>,
     ) -> Result<()> {
+        let mut pending: HashMap<BufferId, u64> = HashMap::new();
         loop {
-            let workspace = self.workspace.lock().clone();
-            let (buffer_id, rev) = receiver.recv()?;
-            let buffers = self.buffers.lock();
-            let buffer = buffers.get(&buffer_id).unwrap();
-            let (path, content) = if buffer.rev != rev {
-                continue;
-            } else {
-                (
-                    buffer.path.clone(),
-                    buffer.slice_to_cow(..buffer.len()).to_string(),
-                )
+            let received = match pending.is_empty() {
+                true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                false => receiver.recv_timeout(BUFFER_DIFF_DEBOUNCE),
             };
+            match received {
+                Ok((buffer_id, rev)) => {
+                    let buffers = self.buffers.lock();
+                    if let Some(buffer) =
+                        buffers.get(&buffer_id).filter(|b| b.rev == rev)
+                    {
+                        self.lsp.lock().get_semantic_tokens(buffer);
+                        pending.insert(buffer_id, rev);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    for (buffer_id, rev) in pending.drain() {
+                        self.send_buffer_diff(buffer_id, rev);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("buffer updates disconnected"))
+                }
+            }
+        }
+    }
+
+    /// The diff of a buffer against HEAD, worked out without the buffers
+    /// locked. None when the buffer was edited again since `rev`.
+    fn buffer_diff(&self, buffer_id: BufferId, rev: Option<u64>) -> Option<Value> {
+        let (path, content, rev) = {
+            let buffers = self.buffers.lock();
+            let buffer = buffers.get(&buffer_id)?;
+            if rev.map(|rev| rev != buffer.rev).unwrap_or(false) {
+                return None;
+            }
+            (buffer.path.clone(), buffer.file_text(), buffer.rev)
+        };
+        let (hunks, line_changes) =
+            buffer_git_diff(&path, &content).unwrap_or_default();
+        Some(json!({
+            "buffer_id": buffer_id,
+            "hunks": hunks,
+            "line_changes": line_changes,
+            "rev": rev,
+        }))
+    }
+
+    /// The blame of a file, of the text of its buffer if it's open, kept
+    /// for the next time unless the buffer was edited in the meantime.
+    fn blame(&self, path: &Path) -> Result<FileBlame> {
+        let open = self
+            .buffers
+            .lock()
+            .values()
+            .find(|buffer| buffer.path == path)
+            .map(|buffer| (buffer.file_text(), buffer.rev));
+        let rev = open.as_ref().map(|(_, rev)| *rev);
+        if let Some(blame) = self.blames.lock().get(path, rev) {
+            return Ok(blame.clone());
+        }
+        let content = match open {
+            Some((content, _)) => content,
+            None => fs::read_to_string(path)?,
+        };
+        let blame = blame_file(path, &content)?;
+        let current = self
+            .buffers
+            .lock()
+            .values()
+            .find(|buffer| buffer.path == path)
+            .map(|buffer| buffer.rev);
+        if current == rev {
+            self.blames
+                .lock()
+                .insert(path.to_path_buf(), rev, blame.clone());
+        }
+        Ok(blame)
+    }
 
-            self.lsp.lock().get_semantic_tokens(buffer);
+    /// Follows a checkout: the buffers of the files it changed are reloaded,
+    /// the servers told of them, and the git status sent, without waiting
+    /// for the watcher.
+    fn checked_out(&self, changes: &[FileStatus]) {
+        let paths: Vec<PathBuf> =
+            changes.iter().map(|change| change.path.clone()).collect();
+        self.reload_changed_buffers(Some(&paths));
+        let events: Vec<(PathBuf, FileChangeType)> = changes
+            .iter()
+            .map(|change| {
+                let change_type = match change.kind {
+                    FileStatusKind::Added => FileChangeType::Created,
+                    FileStatusKind::Deleted => FileChangeType::Deleted,
+                    _ => FileChangeType::Changed,
+                };
+                (change.path.clone(), change_type)
+            })
+            .collect();
+        if !events.is_empty() {
+            self.lsp.lock().did_change_watched_files(&events);
+        }
+        self.blames.lock().clear();
+        self.refresh_git();
+    }
 
-            if let Some((diff, line_changes)) =
-                file_git_diff(&workspace, &PathBuf::from(path), &content)
+    /// Reloads the buffers whose files changed on disk, of `paths` or all
+    /// of them, or marks them when they have unsaved edits.
+    fn reload_changed_buffers(&self, paths: Option<&[PathBuf]>) {
+        let mut buffers = self.buffers.lock();
+        for buffer in buffers.values_mut() {
+            if paths
+                .map(|paths| !paths.contains(&buffer.path))
+                .unwrap_or(false)
             {
-                self.sender.send(json!({
-                    "method": "update_git",
-                    "params": {
-                        "buffer_id": buffer_id,
-                        "line_changes": line_changes,
-                        "rev": rev,
-                    },
-                }));
+                continue;
+            }
+            let before = buffer.external_change;
+            match buffer.check_disk() {
+                Some(ExternalChange::Changed) => {
+                    buffer.reload();
+                    self.buffer_reloaded(buffer);
+                }
+                change if change != before => {
+                    self.send_external_change(buffer);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reloads the buffers of the files a discard put back, and tells of
+    /// the ones of the untracked files it deleted.
+    fn discarded(&self, paths: &[&Path]) {
+        let mut buffers = self.buffers.lock();
+        for path in paths {
+            let buffer_id = match path.to_str() {
+                Some(path) => self.open_files.lock().get(path).cloned(),
+                None => None,
+            };
+            let buffer = match buffer_id.and_then(|id| buffers.get_mut(&id)) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            if path.exists() {
+                buffer.reload();
+                self.buffer_reloaded(buffer);
+            } else {
+                buffer.check_disk();
+                self.send_external_change(buffer);
             }
         }
     }
 
+    fn send_buffer_diff(&self, buffer_id: BufferId, rev: u64) {
+        if let Some(diff) = self.buffer_diff(buffer_id, Some(rev)) {
+            self.send_notification("update_git", diff);
+        }
+    }
+
     pub fn next<R: BufRead>(
         &self,
         reader: &mut R,
@@ -347,140 +1331,930 @@ impl Dispatcher {
         }
     }
 
+    /// Turns lsp locations into what the core shows in its references list.
+    /// The preview comes from the open buffer if there is one, and from the
+    /// file on disk otherwise.
+    pub fn reference_locations(&self, locations: Vec<Location>) -> Result<Value> {
+        let workspace = self.workspace.lock().clone();
+        let repo = Repository::open(&workspace).ok();
+        let buffers = self.buffers.lock();
+        let mut files: HashMap<PathBuf, Option<Rope>> = HashMap::new();
+        let references: Vec<ReferenceLocation> = locations
+            .into_iter()
+            .filter_map(|location| {
+                let path = location.uri.to_file_path().ok()?;
+                let rope = files.entry(path.clone()).or_insert_with(|| {
+                    buffers
+                        .values()
+                        .find(|buffer| buffer.path == path)
+                        .map(|buffer| buffer.rope.clone())
+                        .or_else(|| load_file(&path).ok())
+                });
+                let line = location.range.start.line as usize;
+                let preview_line = rope
+                    .as_ref()
+                    .map(|rope| {
+                        rope.slice_to_cow(
+                            rope.offset_of_line(line)..rope.offset_of_line(line + 1),
+                        )
+                        .trim_end()
+                        .to_string()
+                    })
+                    .unwrap_or_default();
+                let ignored = is_ignored(repo.as_ref(), &workspace, &path);
+                Some(ReferenceLocation {
+                    path,
+                    line: location.range.start.line,
+                    column: location.range.start.character,
+                    preview_line,
+                    ignored,
+                })
+            })
+            .collect();
+        Ok(serde_json::to_value(references)?)
+    }
+
+    /// The error for a request to a feature none of the buffer's servers
+    /// supports, so it fails right away instead of with whatever a server
+    /// answers to methods it doesn't know.
+    fn unsupported_request(&self, rpc: &Request) -> Option<LspUnsupported> {
+        let (buffer_id, feature, supported): (
+            &BufferId,
+            &'static str,
+            fn(&LspCapabilities) -> bool,
+        ) = match rpc {
+            Request::GetCompletion { buffer_id, .. } => {
+                (buffer_id, "completion", |caps| caps.completion)
+            }
+            Request::GetSignature { buffer_id, .. } => {
+                (buffer_id, "signature help", |caps| caps.signature_help)
+            }
+            Request::GetHover { buffer_id, .. } => {
+                (buffer_id, "hover", |caps| caps.hover)
+            }
+            Request::GetReferences { buffer_id, .. } => {
+                (buffer_id, "references", |caps| caps.references)
+            }
+            Request::GetImplementation { buffer_id, .. } => {
+                (buffer_id, "implementation", |caps| caps.implementation)
+            }
+            Request::GetDefinition { buffer_id, .. } => {
+                (buffer_id, "definition", |caps| caps.definition)
+            }
+            Request::GetCodeActions { buffer_id, .. } => {
+                (buffer_id, "code actions", |caps| caps.code_action)
+            }
+            Request::GotoTypeDefinition { buffer_id, .. } => {
+                (buffer_id, "type definition", |caps| caps.type_definition)
+            }
+            Request::GotoDeclaration { buffer_id, .. } => {
+                (buffer_id, "declaration", |caps| caps.declaration)
+            }
+            Request::PrepareCallHierarchy { buffer_id, .. } => {
+                (buffer_id, "call hierarchy", |caps| caps.call_hierarchy)
+            }
+            Request::PrepareRename { buffer_id, .. }
+            | Request::Rename { buffer_id, .. } => {
+                (buffer_id, "rename", |caps| caps.rename)
+            }
+            Request::GetDocumentSymbols { buffer_id } => {
+                (buffer_id, "document symbols", |caps| caps.document_symbol)
+            }
+            Request::FormatDocument { buffer_id } => {
+                (buffer_id, "formatting", |caps| caps.formatting)
+            }
+            Request::FormatRange { buffer_id, .. } => {
+                (buffer_id, "formatting", |caps| caps.range_formatting)
+            }
+            _ => return None,
+        };
+        let language_id = self.buffers.lock().get(buffer_id)?.language_id.clone();
+        match self.lsp.lock().language_supports(&language_id, supported) {
+            Some(false) => Some(LspUnsupported {
+                feature,
+                language_id,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Answers a request about a buffer the proxy doesn't have, as one
+    /// started again doesn't until the core gives it the buffers.
+    fn unknown_buffer(&self, id: RequestId, buffer_id: BufferId) {
+        self.respond(id, Err(anyhow!("no buffer {:?}", buffer_id)));
+    }
+
     pub fn respond(&self, id: RequestId, result: Result<Value>) {
         let mut resp = json!({ "id": id });
         match result {
             Ok(v) => resp["result"] = v,
             Err(e) => {
+                let code = if e.is::<LspUnsupported>() {
+                    UNSUPPORTED_ERROR_CODE
+                } else if e.is::<LspTimeout>() {
+                    TIMEOUT_ERROR_CODE
+                } else if e.is::<BufferReadOnly>() {
+                    READ_ONLY_ERROR_CODE
+                } else if e.is::<CommitError>() {
+                    COMMIT_ERROR_CODE
+                } else if e.is::<CheckoutConflict>() {
+                    CHECKOUT_ERROR_CODE
+                } else {
+                    0
+                };
                 resp["error"] = json!({
-                    "code": 0,
+                    "code": code,
                     "message": format!("{}",e),
-                })
+                });
+                if let Some(read_only) = e.downcast_ref::<BufferReadOnly>() {
+                    resp["error"]["data"] = json!(read_only);
+                }
+                if let Some(commit) = e.downcast_ref::<CommitError>() {
+                    resp["error"]["data"] = json!(commit);
+                }
+                if let Some(conflict) = e.downcast_ref::<CheckoutConflict>() {
+                    resp["error"]["data"] = json!(conflict);
+                }
+            }
+        }
+        self.sender.lock().send(resp);
+    }
+
+    /// Starts a language server registered with `RegisterLspServer` for a
+    /// newly opened file and confirms its id to the registering plugins.
+    pub fn start_registered_lsp_server(&self, language_id: &str, path: &Path) {
+        let started = self.lsp.lock().start_registered_server(language_id, path);
+        if let Some((language_id, id, plugins)) = started {
+            for plugin in plugins {
+                self.notify_plugin(
+                    &plugin,
+                    "lsp_server_registered",
+                    json!({
+                        "language_id": language_id,
+                        "id": id,
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Restarts the language server for `language_id` and replays `didOpen`
+    /// for its buffers.
+    pub fn restart_lsp_server(&self, language_id: &str) -> Result<()> {
+        self.lsp.lock().restart_server(language_id)?;
+        self.open_lsp_buffers(language_id);
+        self.lsp_server_status(
+            language_id,
+            "running",
+            format!("{} language server restarted", language_id),
+        );
+        Ok(())
+    }
+
+    /// Called after the backoff delay once a language server crashed.
+    pub fn respawn_lsp_server(&self, language_id: &str, id: LspServerId) {
+        let respawned = self.lsp.lock().respawn_server(language_id, id);
+        match respawned {
+            Ok(Some(_)) => {
+                self.open_lsp_buffers(language_id);
+                self.lsp_server_status(
+                    language_id,
+                    "running",
+                    format!("{} language server restarted", language_id),
+                );
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.lsp_server_status(language_id, "failed", format!("{}", e));
+            }
+        }
+    }
+
+    fn open_lsp_buffers(&self, language_id: &str) {
+        let buffers = self.buffers.lock();
+        self.lsp.lock().open_buffers(language_id, buffers.values());
+    }
+
+    /// Tells the UI what happened to a language server, as in
+    /// "rust-analyzer crashed (restarting 2/5)".
+    pub fn lsp_server_status(
+        &self,
+        language_id: &str,
+        status: &str,
+        message: String,
+    ) {
+        self.send_notification(
+            "lsp_server_status",
+            json!({
+                "language_id": language_id,
+                "status": status,
+                "message": message,
+            }),
+        );
+    }
+
+    pub fn notify_plugin(&self, name: &str, method: &str, params: Value) {
+        if let Err(e) = self.plugins.lock().notify_plugin(name, method, params) {
+            eprintln!("notify plugin {} err {}", name, e);
+        }
+    }
+
+    /// Sends the explorer's top level: the entries of the workspace, or a
+    /// directory for each workspace folder when there's more than one.
+    fn send_workspace_items(&self) {
+        let folders = self.workspace_folders.lock().clone();
+        let items = match folders.as_slice() {
+            [workspace] => {
+                self.watch_dir(workspace);
+                read_dir(workspace).unwrap_or_default()
+            }
+            _ => folders.into_iter().map(FileNodeItem::new).collect(),
+        };
+        self.send_notification(
+            "list_dir",
+            json!({
+                "items": items,
+            }),
+        );
+    }
+
+    /// Sends the explorer the entries of the directories `paths` are in,
+    /// once for each directory.
+    fn refresh_dirs(&self, paths: &[&Path]) {
+        let mut dirs: Vec<&Path> = paths.iter().filter_map(|p| p.parent()).collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            if let Ok(items) = read_dir(dir) {
+                let listing = DirListing {
+                    path: dir.to_path_buf(),
+                    items,
+                };
+                self.send_notification("refresh_dir", json!(listing));
+            }
+        }
+    }
+
+    fn watch_dir(&self, path: &Path) {
+        if self.explorer_dirs.lock().insert(path.to_path_buf()) {
+            if let Some(watcher) = self.watcher.lock().as_mut() {
+                watcher.watch(path, false, EXPLORER_EVENT_TOKEN);
+            }
+        }
+    }
+
+    /// Points the buffers of `old`, or of the files in it when it's a
+    /// directory, at where they were moved. The servers are told they were
+    /// closed and opened again under the new path.
+    fn retarget_buffers(&self, old: &Path, new: &Path) {
+        let folders = self.workspace_folders.lock().clone();
+        let mut languages = HashSet::new();
+        {
+            let mut buffers = self.buffers.lock();
+            let mut open_files = self.open_files.lock();
+            let mut watcher = self.watcher.lock();
+            for buffer in buffers.values_mut() {
+                let path = match buffer.path.strip_prefix(old) {
+                    Ok(rest) if rest.as_os_str().is_empty() => new.to_path_buf(),
+                    Ok(rest) => new.join(rest),
+                    Err(_) => continue,
+                };
+                self.lsp.lock().close_buffer(buffer);
+                if let Some(watcher) = watcher.as_mut() {
+                    watcher.unwatch(&buffer.path, OPEN_FILE_EVENT_TOKEN);
+                    watcher.watch(&path, true, OPEN_FILE_EVENT_TOKEN);
+                }
+                open_files.remove(buffer.path.to_str().unwrap());
+                open_files.insert(path.to_str().unwrap().to_string(), buffer.id);
+                buffer.root = workspace_root(&folders, &path);
+                buffer.path = path;
+                languages.insert(buffer.language_id.clone());
+            }
+        }
+        for language_id in languages {
+            self.open_lsp_buffers(&language_id);
+        }
+    }
+
+    fn update_buffer_roots(&self) {
+        let folders = self.workspace_folders.lock().clone();
+        for buffer in self.buffers.lock().values_mut() {
+            buffer.root = workspace_root(&folders, &buffer.path);
+        }
+    }
+
+    /// Tells the language servers and the editor about the new content of
+    /// a buffer that was read again.
+    fn buffer_reloaded(&self, buffer: &Buffer) {
+        self.lsp.lock().update(
+            buffer,
+            &TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: buffer.get_document(),
+            },
+            buffer.rev,
+        );
+        self.send_notification(
+            "reload_buffer",
+            json!({
+                "buffer_id": buffer.id,
+                "rev": buffer.rev,
+                "new_content": buffer.get_document(),
+            }),
+        );
+    }
+
+    /// Tells the editor how the file of a buffer changed on disk, `null`
+    /// once that's settled.
+    fn send_external_change(&self, buffer: &Buffer) {
+        self.send_notification(
+            "buffer_external_change",
+            json!({
+                "buffer_id": buffer.id,
+                "state": buffer.external_change,
+            }),
+        );
+    }
+
+    /// The text of each buffer and its revision, for the searches to read
+    /// rather than the file. Large files are left out, as their buffer only
+    /// has some of their lines.
+    fn open_texts(&self) -> HashMap<PathBuf, (u64, Rope)> {
+        self.buffers
+            .lock()
+            .values()
+            .filter(|buffer| buffer.large_file.is_none())
+            .map(|buffer| (buffer.path.clone(), (buffer.rev, buffer.rope.clone())))
+            .collect()
+    }
+
+    /// Starts a shell in a pty, in `cwd` or else the workspace or the home
+    /// directory, giving its id.
+    fn new_terminal(
+        &self,
+        term_id: TermId,
+        cwd: Option<PathBuf>,
+        shell: Option<&str>,
+        env: &HashMap<String, String>,
+        cols: usize,
+        rows: usize,
+    ) -> Result<TermId> {
+        let workspace = self.workspace.lock().clone();
+        let cwd = cwd
+            .or_else(|| Some(workspace).filter(|w| w.is_dir()))
+            .or_else(home::home_dir)
+            .unwrap_or_else(|| PathBuf::from("."));
+        let (terminal, reader) = Terminal::new(
+            cwd,
+            shell,
+            env,
+            cols,
+            rows,
+            self.terminal_settings.lock().scrollback,
+        )?;
+        // held while it starts, for a shell that exits at once to be gone
+        let mut terminals = self.terminals.lock();
+        terminal.run(self.clone(), term_id, reader);
+        terminals.insert(term_id, terminal);
+        Ok(term_id)
+    }
+
+    /// Sends to a core attaching to the proxy kept after the last.
+    pub fn attach_core(&self, sender: Sender<Value>, transport: Transport) {
+        *self.sender.lock() = sender;
+        *self.transport.lock() = transport;
+    }
+
+    /// Loads the file of a buffer, watching it and starting what its
+    /// language needs. `resync` is the text and rev the core kept for it
+    /// through a restart of the proxy, which replace what's on disk. Errs,
+    /// with nothing opened, when a large file can't be scanned.
+    fn open_buffer(
+        &self,
+        buffer_id: BufferId,
+        path: PathBuf,
+        resync: Option<(String, u64)>,
+    ) -> Result<NewBufferResponse> {
+        let mut buffer =
+            Buffer::new(buffer_id, path.clone(), self.git_sender.clone())?;
+        self.watcher.lock().as_mut().unwrap().watch(
+            &path,
+            true,
+            OPEN_FILE_EVENT_TOKEN,
+        );
+        self.open_files
+            .lock()
+            .insert(path.to_str().unwrap().to_string(), buffer_id);
+        buffer.root = workspace_root(&self.workspace_folders.lock(), &path);
+        if let Some((content, rev)) = resync {
+            buffer.resync(&content, rev);
+        }
+        let language_id = buffer.language_id.clone();
+        let file_size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let resp = match buffer.large_file.clone() {
+            Some(large_file) => NewBufferResponse {
+                content: large_file
+                    .read_lines(&path, 0, LARGE_FILE_CHUNK_LINES)
+                    .unwrap_or_default(),
+                language_id,
+                large_file: true,
+                num_lines: large_file.num_lines,
+                line_ending: buffer.line_endings.dominant,
+                indent: buffer.indent,
+                editable: false,
+                is_binary: false,
+                file_size,
+            },
+            None => NewBufferResponse {
+                content: buffer.rope.to_string(),
+                language_id,
+                large_file: false,
+                num_lines: buffer.num_lines(),
+                line_ending: buffer.line_endings.dominant,
+                indent: buffer.indent,
+                editable: buffer.editable(),
+                is_binary: buffer.binary,
+                file_size,
+            },
+        };
+        let text = !resp.large_file && !resp.is_binary;
+        let rev = buffer.rev;
+        self.buffers.lock().insert(buffer_id, buffer);
+        if text {
+            self.plugins
+                .lock()
+                .activate_for_language(self, &resp.language_id);
+            self.start_registered_lsp_server(&resp.language_id, &path);
+            self.git_sender.send((buffer_id, rev));
+        }
+        Ok(resp)
+    }
+
+    /// Keeps the output of the shells for when a core attaches again.
+    pub fn detach_terminals(&self) {
+        for terminal in self.terminals.lock().values() {
+            terminal.detach();
+        }
+    }
+
+    /// Kills the shells no core was attached to for the idle timeout.
+    pub fn reap_idle_terminals(&self) {
+        let timeout = match self.terminal_settings.lock().idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        for terminal in self.terminals.lock().values() {
+            if terminal
+                .idle_for()
+                .map(|idle| idle > timeout)
+                .unwrap_or(false)
+            {
+                terminal.kill();
+            }
+        }
+    }
+
+    /// Walks a workspace folder on another thread, for the file index to
+    /// keep its files.
+    fn index_folder(&self, folder: PathBuf) {
+        let file_index = self.file_index.clone();
+        thread::spawn(move || {
+            let (exclude, roots) = {
+                let file_index = file_index.lock();
+                (file_index.exclude().to_vec(), file_index.roots())
+            };
+            let files = walk_folder(&folder, &exclude, &roots);
+            file_index.lock().set_folder(&folder, files);
+        });
+    }
+
+    /// Saves every buffer with edits, when `auto-save` is `on-focus-change`.
+    fn focus_lost(&self) {
+        if self.auto_save.lock().mode != AutoSaveMode::OnFocusChange {
+            return;
+        }
+        let buffer_ids: Vec<BufferId> = self
+            .buffers
+            .lock()
+            .values()
+            .filter(|buffer| buffer.dirty)
+            .map(|buffer| buffer.id)
+            .collect();
+        for buffer_id in buffer_ids {
+            self.auto_save_buffer(buffer_id);
+        }
+    }
+
+    /// Saves a buffer for `auto-save`, formatted first with
+    /// `auto-save-format`. A buffer that's read-only, has no file, or whose
+    /// file another program changed is left to be saved by hand.
+    pub fn auto_save_buffer(&self, buffer_id: BufferId) {
+        let format = self.auto_save.lock().format;
+        let mut buffers = self.buffers.lock();
+        let buffer = match buffers.get_mut(&buffer_id) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        if !buffer.dirty || !buffer.editable() || buffer.path.file_name().is_none() {
+            return;
+        }
+        let before = buffer.external_change;
+        buffer.check_disk();
+        if buffer.external_change != before {
+            self.send_external_change(buffer);
+        }
+        if buffer.external_change.is_some() {
+            return;
+        }
+        let receiver = match format {
+            true => self.lsp.lock().format_for_save(buffer),
+            false => None,
+        };
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => {
+                self.write_auto_save(buffer);
+                return;
+            }
+        };
+
+        let rev = buffer.rev;
+        let timeout = self.auto_save.lock().format_timeout;
+        let dispatcher = self.clone();
+        thread::spawn(move || {
+            let edits = match receiver.recv_timeout(timeout) {
+                Ok(Ok(value)) => {
+                    serde_json::from_value::<Option<Vec<TextEdit>>>(value)
+                        .unwrap_or(None)
+                        .unwrap_or_default()
+                }
+                Ok(Err(e)) => {
+                    eprintln!("formatting for auto-save failed: {}", e);
+                    Vec::new()
+                }
+                Err(_) => {
+                    eprintln!("formatting timed out, auto-saving without it");
+                    Vec::new()
+                }
+            };
+            let mut buffers = dispatcher.buffers.lock();
+            let buffer = match buffers.get_mut(&buffer_id) {
+                Some(buffer) if buffer.rev == rev => buffer,
+                // edited while formatting, the edit brings another auto-save
+                _ => return,
+            };
+            if !edits.is_empty() {
+                if let Err(e) = buffer.apply_formatting(&edits) {
+                    eprintln!("formatting for auto-save failed: {}", e);
+                    return;
+                }
+                dispatcher.buffer_reloaded(buffer);
             }
+            dispatcher.write_auto_save(buffer);
+        });
+    }
+
+    /// Writes a buffer for `auto-save` and tells the editor, which takes its
+    /// dirty indicator off.
+    fn write_auto_save(&self, buffer: &mut Buffer) {
+        let rev = buffer.rev;
+        if let Err(e) = buffer.save(rev, false) {
+            eprintln!("auto-save of {:?} failed: {}", buffer.path, e);
+            return;
         }
-        self.sender.send(resp);
+        self.blames.lock().invalidate(&buffer.path);
+        self.lsp.lock().save_buffer(buffer);
+        self.send_notification(
+            "buffer_saved",
+            json!({
+                "buffer_id": buffer.id,
+                "rev": rev,
+                "auto": true,
+            }),
+        );
     }
 
     pub fn send_notification(&self, method: &str, params: Value) {
-        self.sender.send(json!({
+        self.sender.lock().send(json!({
+            "method": method,
+            "params": params,
+        }));
+    }
+
+    /// Sends the core a request and waits for its answer, for at most
+    /// `timeout`.
+    pub fn request_core(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+    ) -> Result<Value> {
+        let (sender, receiver) = bounded(1);
+        let id = {
+            let mut requests = self.core_requests.lock();
+            let id = requests.next_id;
+            requests.next_id += 1;
+            requests.pending.insert(id, sender);
+            id
+        };
+        self.sender.lock().send(json!({
+            "id": id,
             "method": method,
             "params": params,
         }));
+        let result = receiver.recv_timeout(timeout);
+        self.core_requests.lock().pending.remove(&id);
+        result.unwrap_or_else(|_| Err(anyhow!("no answer to {}", method)))
+    }
+
+    fn handle_response(&self, rpc: RpcObject) {
+        let sender = match rpc.get_id() {
+            Some(id) => self.core_requests.lock().pending.remove(&id),
+            None => None,
+        };
+        if let Some(sender) = sender {
+            sender.send(rpc.into_response());
+        }
     }
 
     fn handle_notification(&self, rpc: Notification) {
         match rpc {
-            Notification::Initialize { workspace } => {
+            Notification::ProxyPanicForTest {} => {
+                panic!("proxy_panic_for_test");
+            }
+            Notification::Initialize {
+                workspace,
+                transport,
+            } => {
+                self.transport.lock().configure(transport);
                 *self.workspace.lock() = workspace.clone();
-                let mut items = Vec::new();
-                if let Ok(entries) = fs::read_dir(&workspace) {
-                    for entry in entries {
-                        if let Ok(entry) = entry {
-                            let item = FileNodeItem {
-                                path_buf: entry.path(),
-                                is_dir: entry.path().is_dir(),
-                                open: false,
-                                read: false,
-                                children: Vec::new(),
-                            };
-                            items.push(item);
-                        }
+                *self.workspace_folders.lock() = vec![workspace.clone()];
+                {
+                    // a core attaching to a kept proxy initializes it again
+                    let mut watcher = self.watcher.lock();
+                    let watcher = watcher.as_mut().unwrap();
+                    watcher.unwatch(&workspace, GIT_EVENT_TOKEN);
+                    watcher.watch(&workspace, true, GIT_EVENT_TOKEN);
+                    let settings_path = workspace_settings_path(&workspace);
+                    watcher.unwatch(&settings_path, SETTINGS_EVENT_TOKEN);
+                    watcher.watch(&settings_path, false, SETTINGS_EVENT_TOKEN);
+                }
+                self.send_config_changed(true);
+                // with the workspace's `[search] exclude` now
+                *self.file_index.lock() = FileIndex::new(search_exclude());
+                self.index_folder(workspace.clone());
+                self.send_workspace_items();
+                self.watch_repo(&workspace);
+                self.refresh_git();
+                self.plugins.lock().activate_for_workspace(self, &workspace);
+            }
+            Notification::AddWorkspaceFolder { path } => {
+                {
+                    let mut folders = self.workspace_folders.lock();
+                    if folders.contains(&path) {
+                        return;
                     }
+                    folders.push(path.clone());
                 }
-                self.send_notification(
-                    "list_dir",
-                    json!({
-                        "items": items,
-                    }),
-                );
+                self.index_folder(path.clone());
                 self.watcher.lock().as_mut().unwrap().watch(
-                    &workspace,
+                    &path,
                     true,
                     GIT_EVENT_TOKEN,
                 );
-                if let Some(diff_files) = git_diff(&workspace) {
-                    self.send_notification(
-                        "diff_files",
-                        json!({
-                            "files": diff_files,
-                        }),
-                    );
+                self.update_buffer_roots();
+                self.send_workspace_items();
+                self.lsp.lock().workspace_folders_changed(&[path], &[]);
+            }
+            Notification::RemoveWorkspaceFolder { path } => {
+                {
+                    let mut folders = self.workspace_folders.lock();
+                    // the first folder is the workspace plugins and git
+                    // work with, so it stays
+                    if folders.first() == Some(&path) || !folders.contains(&path) {
+                        return;
+                    }
+                    folders.retain(|folder| folder != &path);
+                }
+                self.file_index.lock().remove_folder(&path);
+                // its files were only kept with it
+                let outer = self
+                    .workspace_folders
+                    .lock()
+                    .iter()
+                    .find(|folder| path.starts_with(folder))
+                    .cloned();
+                if let Some(outer) = outer {
+                    self.index_folder(outer);
                 }
+                self.watcher
+                    .lock()
+                    .as_mut()
+                    .unwrap()
+                    .unwatch(&path, GIT_EVENT_TOKEN);
+                self.update_buffer_roots();
+                self.send_workspace_items();
+                self.lsp.lock().workspace_folders_changed(&[], &[path]);
             }
             Notification::Update {
                 buffer_id,
                 delta,
                 rev,
             } => {
-                let mut buffers = self.buffers.lock();
-                let buffer = buffers.get_mut(&buffer_id).unwrap();
-                if let Some(content_change) = buffer.update(&delta, rev) {
-                    self.lsp.lock().update(buffer, &content_change, buffer.rev);
+                let updated = {
+                    let mut buffers = self.buffers.lock();
+                    // not one of a restarted proxy before the core gives
+                    // it the buffers, whose text will have the edit
+                    let buffer = match buffers.get_mut(&buffer_id) {
+                        Some(buffer) => buffer,
+                        None => return,
+                    };
+                    if !buffer.editable() {
+                        // the editor shouldn't have let the edit through,
+                        // and gets the text back
+                        if buffer.rev + 1 == rev {
+                            buffer.rev = rev + 1;
+                            self.buffer_reloaded(buffer);
+                        }
+                        false
+                    } else if let Some(content_change) = buffer.update(&delta, rev) {
+                        self.blames.lock().edited(
+                            &buffer.path,
+                            buffer.rev,
+                            &content_change,
+                        );
+                        let mut lsp = self.lsp.lock();
+                        lsp.update(buffer, &content_change, buffer.rev);
+                        if let Some((typed, position)) =
+                            typed_char(buffer, &content_change)
+                        {
+                            lsp.signature_on_type(buffer, typed, position);
+                            lsp.format_on_type(buffer, typed, position);
+                        }
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if updated {
+                    self.auto_save_sender.send(buffer_id);
+                    let subscribers: Vec<String> = self
+                        .buffer_subscriptions
+                        .lock()
+                        .get(&buffer_id)
+                        .map(|plugins| plugins.iter().cloned().collect())
+                        .unwrap_or_default();
+                    for plugin in subscribers {
+                        self.notify_plugin(
+                            &plugin,
+                            "buffer_changed",
+                            json!({
+                                "buffer_id": buffer_id,
+                                "rev": rev,
+                                "delta": delta,
+                            }),
+                        );
+                    }
                 }
             }
-            Notification::NewTerminal { term_id, cwd } => {
-                let mut terminal = Terminal::new(term_id, cwd, 50, 10);
-                let tx = terminal.tx.clone();
-                self.terminals.lock().insert(term_id, tx);
-                let dispatcher = self.clone();
-                std::thread::spawn(move || {
-                    terminal.run(dispatcher);
-                });
-            }
             Notification::TerminalWrite { term_id, content } => {
-                let terminals = self.terminals.lock();
-                let tx = terminals.get(&term_id).unwrap();
-                tx.send(Msg::Input(content.into_bytes().into()));
+                if let Some(terminal) = self.terminals.lock().get_mut(&term_id) {
+                    if let Err(e) = terminal.write(content.as_bytes()) {
+                        eprintln!("terminal write failed: {}", e);
+                    }
+                }
             }
             Notification::TerminalResize {
                 term_id,
-                width,
-                height,
+                cols,
+                rows,
             } => {
-                let terminals = self.terminals.lock();
-                let tx = terminals.get(&term_id).unwrap();
-                let size = SizeInfo::new(
-                    width as f32,
-                    height as f32,
-                    1.0,
-                    1.0,
-                    0.0,
-                    0.0,
-                    true,
-                );
-                tx.send(Msg::Resize(size));
+                if let Some(terminal) = self.terminals.lock().get(&term_id) {
+                    if let Err(e) = terminal.resize(cols, rows) {
+                        eprintln!("terminal resize failed: {}", e);
+                    }
+                }
             }
-        }
-    }
-
-    fn handle_request(&self, id: RequestId, rpc: Request) {
-        match rpc {
-            Request::NewBuffer { buffer_id, path } => {
-                self.watcher.lock().as_mut().unwrap().watch(
-                    &path,
-                    true,
-                    OPEN_FILE_EVENT_TOKEN,
-                );
-                self.open_files
-                    .lock()
-                    .insert(path.to_str().unwrap().to_string(), buffer_id);
-                let buffer = Buffer::new(buffer_id, path, self.git_sender.clone());
-                let content = buffer.rope.to_string();
-                self.buffers.lock().insert(buffer_id, buffer);
-                self.git_sender.send((buffer_id, 0));
-                let resp = NewBufferResponse { content };
-                self.sender.send(json!({
-                    "id": id,
-                    "result": resp,
-                }));
+            Notification::TerminalClose { term_id } => {
+                if let Some(terminal) = self.terminals.lock().get(&term_id) {
+                    terminal.kill();
+                }
             }
-            Request::GetCompletion {
-                buffer_id,
-                position,
+            Notification::DisablePlugin { name } => {
+                if let Err(e) = self.plugins.lock().disable_plugin(&name) {
+                    eprintln!("disable plugin {} err {}", name, e);
+                }
+            }
+            Notification::EnablePlugin { name } => {
+                if let Err(e) =
+                    self.plugins.lock().enable_plugin(self.clone(), &name)
+                {
+                    eprintln!("enable plugin {} err {}", name, e);
+                }
+            }
+            Notification::ReloadPlugin { name } => {
+                if let Err(e) =
+                    self.plugins.lock().reload_plugin(self.clone(), &name)
+                {
+                    eprintln!("reload plugin {} err {}", name, e);
+                }
+            }
+            Notification::ExecutePluginCommand { id, args } => {
+                if let Err(e) = self.plugins.lock().execute_command(self, &id, args)
+                {
+                    eprintln!("execute plugin command {} err {}", id, e);
+                }
+            }
+            Notification::RestartLspServer { buffer_id } => {
+                let language_id = self
+                    .buffers
+                    .lock()
+                    .get(&buffer_id)
+                    .map(|buffer| buffer.language_id.clone());
+                if let Some(language_id) = language_id {
+                    if let Err(e) = self.restart_lsp_server(&language_id) {
+                        eprintln!("restart lsp {} err {}", language_id, e);
+                    }
+                }
+            }
+            Notification::CancelSignature { buffer_id } => {
+                self.lsp.lock().cancel_signature(buffer_id);
+            }
+            Notification::FocusLost {} => self.focus_lost(),
+            Notification::CancelSearch { search_id } => {
+                self.searches.lock().cancel(search_id);
+            }
+            Notification::WatchDir { path } => self.watch_dir(&path),
+            Notification::UnwatchDir { path } => {
+                if self.explorer_dirs.lock().remove(&path) {
+                    if let Some(watcher) = self.watcher.lock().as_mut() {
+                        watcher.unwatch(&path, EXPLORER_EVENT_TOKEN);
+                    }
+                }
+            }
+            Notification::CursorMoved {
+                buffer_id,
+                position,
+            } => {
+                let generation = {
+                    let buffers = self.buffers.lock();
+                    let mut lsp = self.lsp.lock();
+                    if let Some(buffer) = buffers.get(&buffer_id) {
+                        lsp.cursor_moved(buffer, position);
+                    }
+                    lsp.document_highlight_moved()
+                };
+                let dispatcher = self.clone();
+                thread::spawn(move || {
+                    thread::sleep(DOCUMENT_HIGHLIGHT_DELAY);
+                    let buffers = dispatcher.buffers.lock();
+                    if let Some(buffer) = buffers.get(&buffer_id) {
+                        dispatcher
+                            .lsp
+                            .lock()
+                            .document_highlight(generation, buffer, position);
+                    }
+                });
+            }
+        }
+    }
+
+    fn handle_request(&self, id: RequestId, rpc: Request) {
+        if let Some(unsupported) = self.unsupported_request(&rpc) {
+            self.respond(id, Err(unsupported.into()));
+            return;
+        }
+        match rpc {
+            Request::NewBuffer { buffer_id, path } => {
+                // scanning a large file takes a while
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = local_dispatcher
+                        .open_buffer(buffer_id, path, None)
+                        .map(|resp| json!(resp));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::ResyncBuffer {
+                buffer_id,
+                path,
+                content,
+                rev,
+            } => {
+                let result = self.open_buffer(buffer_id, path, Some((content, rev)));
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GetCompletion {
+                buffer_id,
+                position,
                 request_id,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
                 self.lsp
                     .lock()
                     .get_completion(id, request_id, buffer, position);
@@ -490,7 +2264,10 @@ impl Dispatcher {
                 completion_item,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
                 self.lsp
                     .lock()
                     .completion_resolve(id, buffer, &completion_item);
@@ -500,16 +2277,50 @@ impl Dispatcher {
                 position,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
                 self.lsp.lock().get_signature(id, buffer, position);
             }
+            Request::GetHover {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_hover(id, buffer, position);
+            }
             Request::GetReferences {
                 buffer_id,
                 position,
+                include_declaration,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_references(id, buffer, position);
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_references(
+                    id,
+                    buffer,
+                    position,
+                    include_declaration,
+                );
+            }
+            Request::GetImplementation {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_implementation(id, buffer, position);
             }
             Request::GetDefinition {
                 buffer_id,
@@ -517,217 +2328,1100 @@ impl Dispatcher {
                 request_id,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
                 self.lsp
                     .lock()
                     .get_definition(id, request_id, buffer, position);
             }
             Request::GetCodeActions {
+                buffer_id,
+                range,
+                diagnostics,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp
+                    .lock()
+                    .get_code_actions(id, buffer, range, diagnostics);
+            }
+            Request::GetInlayHints { buffer_id, range } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_inlay_hints(id, buffer, range);
+            }
+            Request::GotoTypeDefinition {
                 buffer_id,
                 position,
             } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_code_actions(id, buffer, position);
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_type_definition(id, buffer, position);
             }
-            Request::GetDocumentSymbols { buffer_id } => {
+            Request::GotoDeclaration {
+                buffer_id,
+                position,
+            } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_declaration(id, buffer, position);
+            }
+            Request::PrepareCallHierarchy {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().prepare_call_hierarchy(id, buffer, position);
+            }
+            Request::CallHierarchyIncoming { handle } => {
+                self.lsp.lock().call_hierarchy_incoming(id, handle);
+            }
+            Request::CallHierarchyOutgoing { handle } => {
+                self.lsp.lock().call_hierarchy_outgoing(id, handle);
+            }
+            Request::GetDocumentLinks { buffer_id, range } => {
+                let workspace = self.workspace.lock().clone();
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp
+                    .lock()
+                    .get_document_links(id, buffer, range, &workspace);
+            }
+            Request::ResolveDocumentLink { buffer_id, link } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().resolve_document_link(id, buffer, link);
+            }
+            Request::GetSelectionRanges {
+                buffer_id,
+                positions,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_selection_ranges(id, buffer, positions);
+            }
+            Request::GetFoldingRanges { buffer_id } => {
+                let rev = self.buffers.lock().get(&buffer_id).map(|b| b.rev);
+                let dispatcher = self.clone();
+                thread::spawn(move || {
+                    thread::sleep(FOLDING_RANGE_DELAY);
+                    let buffers = dispatcher.buffers.lock();
+                    match buffers.get(&buffer_id) {
+                        Some(buffer) if Some(buffer.rev) == rev => {
+                            dispatcher.lsp.lock().get_folding_ranges(id, buffer);
+                        }
+                        _ => dispatcher.respond(
+                            id,
+                            Err(anyhow!(
+                                "the buffer changed, superseded by a newer request"
+                            )),
+                        ),
+                    }
+                });
+            }
+            Request::GetAllDiagnostics {} => {
+                self.lsp.lock().get_all_diagnostics(id);
+            }
+            Request::ResolveCodeAction { buffer_id, action } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().resolve_code_action(id, buffer, action);
+            }
+            Request::ExecuteCommand { buffer_id, command } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().execute_command(id, buffer, command);
+            }
+            Request::ApplyFileEdits { edits } => {
+                let result = apply_file_edits(&edits).map(|resp| json!(resp));
+                if result.is_ok() {
+                    let events: Vec<(PathBuf, FileChangeType)> = edits
+                        .keys()
+                        .map(|path| (path.clone(), FileChangeType::Changed))
+                        .collect();
+                    self.lsp.lock().did_change_watched_files(&events);
+                }
+                self.respond(id, result);
+            }
+            Request::PrepareRename {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().prepare_rename(id, buffer, position);
+            }
+            Request::Rename {
+                buffer_id,
+                position,
+                new_name,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().rename(id, buffer, position, new_name);
+            }
+            Request::GetDocumentSymbols { buffer_id } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
                 self.lsp.lock().get_document_symbols(id, buffer);
             }
-            Request::GetDocumentFormatting { buffer_id } => {
+            Request::EnclosingScopes { buffer_id, line } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().get_enclosing_scopes(id, buffer, line);
+            }
+            Request::FormatDocument { buffer_id } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().format_document(id, buffer);
+            }
+            Request::FormatRange { buffer_id, range } => {
                 let buffers = self.buffers.lock();
-                let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_document_formatting(id, buffer);
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.lsp.lock().format_range(id, buffer, range);
+            }
+            Request::GetWorkspaceSymbols { query, limit } => {
+                self.lsp.lock().get_workspace_symbols(id, &query, limit);
             }
             Request::ReadDir { path } => {
                 let local_dispatcher = self.clone();
                 thread::spawn(move || {
-                    let result = fs::read_dir(path)
-                        .map(|entries| {
-                            let mut items = entries
-                                .into_iter()
-                                .filter_map(|entry| {
-                                    entry
-                                        .map(|e| FileNodeItem {
-                                            path_buf: e.path(),
-                                            is_dir: e.path().is_dir(),
-                                            open: false,
-                                            read: false,
-                                            children: Vec::new(),
-                                        })
-                                        .ok()
-                                })
-                                .collect::<Vec<FileNodeItem>>();
-                            items.sort();
-                            serde_json::to_value(items).unwrap()
-                        })
-                        .map_err(|e| anyhow!(e));
+                    let result = read_dir(&path).map(|items| json!(items));
                     local_dispatcher.respond(id, result);
                 });
             }
-            Request::GetFiles { path } => {
-                eprintln!("get files");
+            Request::RevealPath { path } => {
+                let folders = self.workspace_folders.lock().clone();
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = reveal_path(&folders, &path);
+                    if let Ok(listings) = result.as_ref() {
+                        for listing in listings {
+                            local_dispatcher.watch_dir(&listing.path);
+                        }
+                    }
+                    local_dispatcher.respond(id, result.map(|r| json!(r)));
+                });
+            }
+            Request::CreateFile { path } => {
+                let result = create_file(&path);
+                self.refresh_dirs(&[&path]);
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::CreateDirectory { path } => {
+                let result = create_directory(&path);
+                self.refresh_dirs(&[&path]);
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::RenamePath { old, new } => {
+                let receivers = self.lsp.lock().will_rename_files(&old, &new);
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let edits = will_rename_edits(receivers);
+                    let result = rename_path(&old, &new);
+                    if result.is_ok() {
+                        local_dispatcher.retarget_buffers(&old, &new);
+                        local_dispatcher.lsp.lock().did_rename_files(&old, &new);
+                    }
+                    local_dispatcher.refresh_dirs(&[&old, &new]);
+                    let result = result.map(|_| json!({ "edits": edits }));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::TrashPath { path } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = trash_path(&path);
+                    local_dispatcher.refresh_dirs(&[&path]);
+                    local_dispatcher.respond(id, result.map(|r| json!(r)));
+                });
+            }
+            Request::DuplicatePath { path } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = duplicate_path(&path);
+                    local_dispatcher.refresh_dirs(&[&path]);
+                    let result = result.map(|copy| json!({ "path": copy }));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::TerminalNew {
+                term_id,
+                cwd,
+                shell,
+                env,
+                cols,
+                rows,
+            } => {
+                let result = self
+                    .new_terminal(
+                        term_id.unwrap_or_else(TermId::next),
+                        cwd,
+                        shell.as_deref(),
+                        &env,
+                        cols,
+                        rows,
+                    )
+                    .map(|term_id| json!({ "term_id": term_id }));
+                self.respond(id, result);
+            }
+            Request::TerminalAttach { term_id } => {
+                let terminals = self.terminals.lock();
+                match terminals.get(&term_id) {
+                    // answered before any more output is sent
+                    Some(terminal) => terminal.attach(|scrollback| {
+                        self.respond(
+                            id,
+                            Ok(json!({ "scrollback": base64::encode(scrollback) })),
+                        )
+                    }),
+                    None => {
+                        self.respond(id, Err(anyhow!("no terminal {:?}", term_id)))
+                    }
+                }
+            }
+            Request::TerminalList {} => {
+                let mut list: Vec<TerminalInfo> = self
+                    .terminals
+                    .lock()
+                    .iter()
+                    .map(|(term_id, terminal)| terminal.info(*term_id))
+                    .collect();
+                list.sort_by_key(|info| info.term_id.0);
+                self.respond(id, Ok(json!(list)));
+            }
+            Request::ListTasks {} => {
                 let workspace = self.workspace.lock().clone();
+                let result = load_tasks(&workspace).map(|tasks| json!(tasks));
+                self.respond(id, result);
+            }
+            Request::RunTask { name } => {
+                let workspace = self.workspace.lock().clone();
+                let result = load_tasks(&workspace)
+                    .and_then(|tasks| {
+                        tasks
+                            .into_iter()
+                            .find(|task| task.name == name)
+                            .ok_or_else(|| anyhow!("no task {}", name))
+                    })
+                    .and_then(|task| run_task(self, &workspace, &task))
+                    .map(|task_id| json!({ "task_id": task_id }));
+                self.respond(id, result);
+            }
+            Request::CancelTask { task_id } => {
+                let result = match self.tasks.lock().get(&task_id) {
+                    Some(task) => task.cancel().map(|_| json!({})),
+                    None => Err(anyhow!("task {:?} isn't running", task_id)),
+                };
+                self.respond(id, result);
+            }
+            Request::TrustWorkspace { trusted } => {
+                let workspace = self.workspace.lock().clone();
+                let result = set_workspace_trust(&workspace, trusted);
+                if result.is_ok() {
+                    self.send_config_changed(true);
+                }
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GetSetting { key } => {
+                let result = self
+                    .settings
+                    .lock()
+                    .get(&key)
+                    .map(|value| json!(value))
+                    .ok_or_else(|| anyhow!("{}: unknown setting", key));
+                self.respond(id, result);
+            }
+            Request::UpdateSetting { key, value } => {
+                // the watcher of the file sends the change
+                let result = user_settings_path()
+                    .ok_or_else(|| anyhow!("no config dir"))
+                    .and_then(|path| registry::update_file(&path, &key, &value));
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::ProxyStats {} => {
+                let stats = self.transport.lock().stats();
+                self.respond(id, Ok(json!(stats)));
+            }
+            Request::ProxyVersion {} => {
+                self.respond(id, Ok(json!({ "version": crate::VERSION })));
+            }
+            Request::ResolvedEnv {} => {
                 let local_dispatcher = self.clone();
                 thread::spawn(move || {
-                    let mut items = Vec::new();
-                    let mut dirs = Vec::new();
-                    dirs.push(workspace.clone());
-                    while let Some(dir) = dirs.pop() {
-                        if let Ok(readdir) = fs::read_dir(dir) {
-                            for entry in readdir {
-                                let entry = entry.unwrap();
-                                let path = entry.path();
-                                if entry
-                                    .file_name()
-                                    .to_str()
-                                    .unwrap()
-                                    .starts_with(".")
-                                {
-                                    continue;
-                                }
-                                if path.is_dir() {
-                                    if !path
-                                        .as_path()
-                                        .to_str()
-                                        .unwrap()
-                                        .to_string()
-                                        .ends_with("target")
-                                    {
-                                        dirs.push(path);
-                                    }
-                                } else {
-                                    items.push(path.to_str().unwrap().to_string());
+                    let resolved = resolved_env();
+                    local_dispatcher.respond(id, Ok(json!(&*resolved)));
+                });
+            }
+            Request::GitStatus {} => {
+                let workspace = self.workspace.lock().clone();
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = git_status(&workspace).map(|s| json!(s));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::GitBranchInfo {} => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_branch_info(&workspace).map(|info| json!(info));
+                self.respond(id, result);
+            }
+            Request::GitDiffBuffer { buffer_id } => {
+                let result = self
+                    .buffer_diff(buffer_id, None)
+                    .ok_or_else(|| anyhow!("no buffer {}", buffer_id.0));
+                self.respond(id, result);
+            }
+            Request::GitStage { paths } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_stage(&workspace, &paths).map(|_| json!({}));
+                self.respond(id, result);
+            }
+            Request::GitUnstage { paths } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_unstage(&workspace, &paths).map(|_| json!({}));
+                self.respond(id, result);
+            }
+            Request::GitDiscard { paths, confirmed } => {
+                let workspace = self.workspace.lock().clone();
+                let unsaved: HashSet<PathBuf> = self
+                    .buffers
+                    .lock()
+                    .values()
+                    .filter(|buffer| buffer.dirty)
+                    .map(|buffer| buffer.path.clone())
+                    .collect();
+                let result = git_discard(&workspace, &paths, &unsaved, confirmed);
+                if let Ok(response) = result.as_ref() {
+                    if response.discarded {
+                        let paths: Vec<&Path> = response
+                            .files
+                            .iter()
+                            .map(|f| f.path.as_path())
+                            .collect();
+                        self.discarded(&paths);
+                    }
+                }
+                self.respond(id, result.map(|r| json!(r)));
+            }
+            Request::GitStageHunk { path, hunk_range } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_stage_hunk(&workspace, &path, hunk_range);
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitUnstageHunk { path, hunk_range } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_unstage_hunk(&workspace, &path, hunk_range);
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitBlame { path } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = local_dispatcher.blame(&path);
+                    local_dispatcher.respond(id, result.map(|b| json!(b)));
+                });
+            }
+            Request::GitBlameLine { path, line } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = local_dispatcher
+                        .blame(&path)
+                        .map(|blame| json!(blame.line(line)));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::GetConflicts { buffer_id } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let conflicts = parse_conflicts(&buffer.rope.to_string());
+                self.respond(
+                    id,
+                    Ok(json!({
+                        "rev": buffer.rev,
+                        "conflicts": conflicts,
+                    })),
+                );
+            }
+            Request::ResolveConflict {
+                buffer_id,
+                conflict_index,
+                choice,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let text = buffer.rope.to_string();
+                let result = parse_conflicts(&text)
+                    .get(conflict_index)
+                    .map(|conflict| ConflictResolution {
+                        rev: buffer.rev,
+                        edits: vec![resolve_conflict(&text, conflict, choice)],
+                    })
+                    .ok_or_else(|| anyhow!("no conflict {}", conflict_index));
+                self.respond(id, result.map(|r| json!(r)));
+            }
+            Request::GitMarkResolved { path } => {
+                let text = self
+                    .buffers
+                    .lock()
+                    .values()
+                    .find(|buffer| buffer.path == path)
+                    .map(|buffer| buffer.rope.to_string());
+                let result = text
+                    .map(Ok)
+                    .unwrap_or_else(|| fs::read_to_string(&path))
+                    .map_err(anyhow::Error::from)
+                    .and_then(|text| match parse_conflicts(&text).len() {
+                        0 => {
+                            let workspace = self.workspace.lock().clone();
+                            git_stage(&workspace, &[path.clone()])
+                        }
+                        left => Err(anyhow!("{} conflicts are left", left)),
+                    });
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitBranches {} => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_branches(&workspace).map(|b| json!(b));
+                self.respond(id, result);
+            }
+            Request::GitCheckout {
+                target,
+                create,
+                force,
+            } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_checkout(&workspace, &target, create, force);
+                if let Ok(changes) = result.as_ref() {
+                    self.checked_out(changes);
+                }
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitCreateBranch { name, from } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_create_branch(&workspace, &name, from.as_deref());
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitStashSave {
+                message,
+                include_untracked,
+            } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_stash_save(
+                    &workspace,
+                    message.as_deref(),
+                    include_untracked,
+                );
+                if result.is_ok() {
+                    self.reload_changed_buffers(None);
+                }
+                self.respond(id, result.map(|stash| json!({ "id": stash })));
+            }
+            Request::GitStashPop { index } => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_stash_pop(&workspace, index);
+                if result.is_ok() {
+                    self.reload_changed_buffers(None);
+                }
+                self.respond(id, result.map(|_| json!({})));
+            }
+            Request::GitStashList {} => {
+                let workspace = self.workspace.lock().clone();
+                let result = git_stash_list(&workspace).map(|s| json!(s));
+                self.respond(id, result);
+            }
+            Request::GitFileHistory { path, limit, skip } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = git_file_history(&path, limit, skip);
+                    local_dispatcher.respond(id, result.map(|h| json!(h)));
+                });
+            }
+            Request::GitShowFileAt { path, commit_id } => {
+                let result = git_show_file_at(&path, &commit_id);
+                self.respond(id, result.map(|file| json!(file)));
+            }
+            Request::GitDiffPaths { path, rev_a, rev_b } => {
+                let result = git_diff_paths(&path, &rev_a, rev_b.as_deref());
+                self.respond(id, result.map(|diff| json!(diff)));
+            }
+            Request::GitCommit { message, amend } => {
+                let workspace = self.workspace.lock().clone();
+                let local_dispatcher = self.clone();
+                // the hooks can take a while
+                thread::spawn(move || {
+                    let result = git_commit(&workspace, &message, amend)
+                        .map(|commit| json!({ "id": commit }));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::GetFiles { path } => {
+                let files = self.file_index.lock().files();
+                self.respond(id, Ok(json!(files)));
+            }
+            Request::FuzzyFileSearch { query, max_results } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let matches = local_dispatcher
+                        .file_index
+                        .lock()
+                        .search(&query, max_results);
+                    local_dispatcher.respond(id, Ok(json!(matches)));
+                });
+            }
+            Request::RebuildFileIndex {} => {
+                let folders = self.workspace_folders.lock().clone();
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let exclude = search_exclude();
+                    let mut file_index = FileIndex::new(exclude.clone());
+                    for folder in folders.iter() {
+                        let files = walk_folder(folder, &exclude, &folders);
+                        file_index.set_folder(folder, files);
+                    }
+                    let files = file_index.len();
+                    *local_dispatcher.file_index.lock() = file_index;
+                    local_dispatcher.respond(id, Ok(json!({ "files": files })));
+                });
+            }
+            Request::PluginList {} => {
+                let statuses = self.plugins.lock().plugin_statuses();
+                self.respond(id, Ok(serde_json::to_value(statuses).unwrap()));
+            }
+            Request::PluginCommands {} => {
+                let commands = self.plugins.lock().plugin_commands();
+                self.respond(id, Ok(serde_json::to_value(commands).unwrap()));
+            }
+            Request::InstallPlugin { source } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = install_plugin(&source)
+                        .and_then(|manifest_path| {
+                            local_dispatcher.plugins.lock().load_plugin(
+                                local_dispatcher.clone(),
+                                &manifest_path,
+                            )
+                        })
+                        .map(|name| json!({ "name": name }));
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::UninstallPlugin { name } => {
+                let resp = self
+                    .plugins
+                    .lock()
+                    .uninstall_plugin(&name)
+                    .map(|_| json!({}));
+                self.respond(id, resp);
+            }
+            Request::GetBufferLines {
+                buffer_id,
+                start_line,
+                end_line,
+            } => {
+                let (path, large_file) = {
+                    let buffers = self.buffers.lock();
+                    let buffer = match buffers.get(&buffer_id) {
+                        Some(buffer) => buffer,
+                        None => return self.unknown_buffer(id, buffer_id),
+                    };
+                    (buffer.path.clone(), buffer.large_file.clone())
+                };
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = match large_file {
+                        Some(large_file) => large_file
+                            .read_lines(&path, start_line, end_line)
+                            .map(|text| {
+                                let end_line = end_line.min(large_file.num_lines);
+                                json!(BufferLinesResponse { text, end_line })
+                            }),
+                        None => Err(anyhow!("{:?} isn't a large file", path)),
+                    };
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::OpenBinary { path, offset, len } => {
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result =
+                        read_bytes(&path, offset, len).map(|(bytes, file_size)| {
+                            json!(BinaryChunkResponse {
+                                offset,
+                                data: base64::encode(&bytes),
+                                file_size,
+                            })
+                        });
+                    local_dispatcher.respond(id, result);
+                });
+            }
+            Request::SetBufferLanguage {
+                buffer_id,
+                language_id,
+            } => {
+                let path = {
+                    let mut buffers = self.buffers.lock();
+                    let buffer = match buffers.get_mut(&buffer_id) {
+                        Some(buffer) => buffer,
+                        None => return self.unknown_buffer(id, buffer_id),
+                    };
+                    if buffer.language_id != language_id {
+                        self.lsp.lock().close_buffer(buffer);
+                        buffer.language_id = language_id.clone();
+                    }
+                    buffer.path.clone()
+                };
+                self.plugins
+                    .lock()
+                    .activate_for_language(self, &language_id);
+                self.start_registered_lsp_server(&language_id, &path);
+                self.open_lsp_buffers(&language_id);
+                self.respond(id, Ok(json!({})));
+            }
+            Request::WillSave { buffer_id } => {
+                let receivers = {
+                    let buffers = self.buffers.lock();
+                    let buffer = match buffers.get(&buffer_id) {
+                        Some(buffer) => buffer,
+                        None => return self.unknown_buffer(id, buffer_id),
+                    };
+                    self.lsp.lock().will_save(buffer)
+                };
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    // the edits of the first server that has some, a save
+                    // never waits past the timeout for them
+                    let deadline = Instant::now() + WILL_SAVE_TIMEOUT;
+                    let mut edits = Vec::new();
+                    for receiver in receivers {
+                        let timeout =
+                            deadline.saturating_duration_since(Instant::now());
+                        match receiver.recv_timeout(timeout) {
+                            Ok(Ok(value)) => {
+                                let server_edits: Option<Vec<TextEdit>> =
+                                    serde_json::from_value(value).unwrap_or(None);
+                                if edits.is_empty() {
+                                    edits = server_edits.unwrap_or_default();
                                 }
                             }
+                            Ok(Err(e)) => {
+                                eprintln!("willSaveWaitUntil failed: {}", e)
+                            }
+                            Err(_) => eprintln!(
+                                "willSaveWaitUntil timed out, saving without its edits"
+                            ),
                         }
                     }
-                    local_dispatcher
-                        .respond(id, Ok(serde_json::to_value(items).unwrap()));
+                    local_dispatcher.respond(id, Ok(json!(edits)));
+                });
+            }
+            Request::Save {
+                rev,
+                buffer_id,
+                overwrite,
+            } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let before = buffer.external_change;
+                let resp = buffer.save(rev, overwrite).map(|r| json!({}));
+                if buffer.external_change != before {
+                    self.send_external_change(buffer);
+                }
+                if resp.is_ok() {
+                    self.blames.lock().invalidate(&buffer.path);
+                    self.lsp.lock().save_buffer(buffer);
+                }
+                self.respond(id, resp);
+            }
+            Request::SaveCopyAs { buffer_id, path } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let resp = buffer.save_copy_as(&path).map(|_| json!({}));
+                self.respond(id, resp);
+            }
+            Request::ReloadBuffer { buffer_id } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let had_change = buffer.external_change.is_some();
+                buffer.reload();
+                self.buffer_reloaded(buffer);
+                if had_change {
+                    self.send_external_change(buffer);
+                }
+                self.respond(id, Ok(json!({})));
+            }
+            Request::GetBufferEncoding { buffer_id } => {
+                let buffers = self.buffers.lock();
+                let buffer = match buffers.get(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                self.respond(
+                    id,
+                    Ok(json!({
+                        "encoding": buffer.encoding.name(),
+                        "bom": buffer.encoding.bom,
+                    })),
+                );
+            }
+            Request::SetLineEnding { buffer_id, ending } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let resp = buffer.check_editable().map(|_| {
+                    buffer.set_line_ending(ending);
+                    json!({})
+                });
+                self.respond(id, resp);
+            }
+            Request::SetBufferReadonly {
+                buffer_id,
+                readonly,
+            } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let resp = buffer
+                    .set_read_only(readonly)
+                    .map(|_| json!({ "editable": buffer.editable() }));
+                self.respond(id, resp);
+            }
+            Request::MatchingPair { buffer_id, offset } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let tree = buffer.syntax_tree().cloned();
+                let offset = matching_pair(&buffer.rope, tree.as_ref(), offset);
+                self.respond(id, Ok(json!({ "offset": offset })));
+            }
+            Request::ToggleComment {
+                buffer_id,
+                selections,
+            } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let tree = buffer.syntax_tree().cloned();
+                let user_tokens = user_comment_tokens();
+                let (edits, selections) =
+                    toggle_comment(&buffer.rope, &selections, |offset| {
+                        let language_id = language_at(
+                            &buffer.language_id,
+                            &buffer.rope,
+                            tree.as_ref(),
+                            offset,
+                        );
+                        comment_tokens(&language_id, &user_tokens)
+                    });
+                let response = ToggleCommentResponse {
+                    rev: buffer.rev,
+                    edits,
+                    selections,
+                };
+                self.respond(id, Ok(json!(response)));
+            }
+            Request::GlobalSearch {
+                search_id,
+                query,
+                options,
+            } => {
+                let regex = match options.regex(&query) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        self.respond(id, Err(e));
+                        return;
+                    }
+                };
+                let cancelled = self.searches.lock().start(search_id);
+                let open = self
+                    .open_texts()
+                    .into_iter()
+                    .map(|(path, (_, rope))| (path, rope))
+                    .collect();
+                let folders = self.workspace_folders.lock().clone();
+                let local_dispatcher = self.clone();
+                global_search(
+                    folders,
+                    &search_exclude(),
+                    open,
+                    regex,
+                    cancelled,
+                    move |matches, done| {
+                        local_dispatcher.send_notification(
+                            "global_search_results",
+                            json!({
+                                "search_id": search_id,
+                                "matches": matches,
+                                "done": done,
+                            }),
+                        );
+                    },
+                );
+                self.respond(id, Ok(json!({})));
+            }
+            Request::GlobalReplace {
+                query,
+                options,
+                replacement,
+            } => {
+                let regex = match options.regex(&query) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        self.respond(id, Err(e));
+                        return;
+                    }
+                };
+                let open = self
+                    .open_texts()
+                    .into_iter()
+                    .map(|(path, (_, rope))| (path, rope))
+                    .collect();
+                let folders = self.workspace_folders.lock().clone();
+                let local_dispatcher = self.clone();
+                thread::spawn(move || {
+                    let files = global_replace(
+                        &folders,
+                        &search_exclude(),
+                        &open,
+                        &regex,
+                        &replacement,
+                        options.regex,
+                    );
+                    let preview = local_dispatcher.replaces.lock().start(files);
+                    local_dispatcher.respond(id, Ok(json!(preview)));
                 });
             }
-            Request::Save { rev, buffer_id } => {
+            Request::ApplyReplace { session_id, ids } => {
+                let files = match self.replaces.lock().take(session_id) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        self.respond(id, Err(e));
+                        return;
+                    }
+                };
+                let ids = ids.into_iter().collect();
+                let response = apply_replace(files, &ids, &self.open_texts());
+                let events: Vec<(PathBuf, FileChangeType)> = response
+                    .written
+                    .iter()
+                    .map(|path| (path.clone(), FileChangeType::Changed))
+                    .collect();
+                self.lsp.lock().did_change_watched_files(&events);
+                self.respond(id, Ok(json!(response)));
+            }
+            Request::ListUnsavedBackups {} => {
+                let folders = self.workspace_folders.lock().clone();
+                let backups = self
+                    .backups
+                    .lock()
+                    .as_ref()
+                    .map(|backups| backups.leftovers(&folders))
+                    .unwrap_or_default();
+                self.respond(id, Ok(json!(backups)));
+            }
+            Request::RestoreBackup { id: backup_id } => {
+                let resp = match self.backups.lock().as_ref() {
+                    Some(backups) => backups.restore(&backup_id),
+                    None => Err(anyhow!("no backups")),
+                };
+                self.respond(
+                    id,
+                    resp.map(|(manifest, content)| {
+                        json!({ "path": manifest.path, "content": content })
+                    }),
+                );
+            }
+            Request::DiscardBackup { id: backup_id } => {
+                let resp = match self.backups.lock().as_ref() {
+                    Some(backups) => backups.discard(&backup_id),
+                    None => Err(anyhow!("no backups")),
+                };
+                self.respond(id, resp.map(|_| json!({})));
+            }
+            Request::ReopenWithEncoding {
+                buffer_id,
+                encoding,
+            } => {
                 let mut buffers = self.buffers.lock();
-                let buffer = buffers.get_mut(&buffer_id).unwrap();
-                let resp = buffer.save(rev).map(|r| json!({}));
-                self.lsp.lock().save_buffer(buffer);
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let resp = FileEncoding::from_label(&encoding)
+                    .and_then(|encoding| buffer.reopen_with_encoding(encoding));
+                if resp.is_ok() {
+                    self.buffer_reloaded(buffer);
+                }
+                self.respond(id, resp.map(|_| json!({})));
+            }
+            Request::SaveWithEncoding {
+                rev,
+                buffer_id,
+                encoding,
+            } => {
+                let mut buffers = self.buffers.lock();
+                let buffer = match buffers.get_mut(&buffer_id) {
+                    Some(buffer) => buffer,
+                    None => return self.unknown_buffer(id, buffer_id),
+                };
+                let resp = FileEncoding::from_label(&encoding)
+                    .and_then(|encoding| buffer.save_with_encoding(rev, encoding))
+                    .map(|_| json!({}));
+                if resp.is_ok() {
+                    self.blames.lock().invalidate(&buffer.path);
+                    self.lsp.lock().save_buffer(buffer);
+                }
                 self.respond(id, resp);
             }
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct DiffHunk {
-    pub old_start: u32,
-    pub old_lines: u32,
-    pub new_start: u32,
-    pub new_lines: u32,
-    pub header: String,
-}
-
-fn git_diff(workspace_path: &PathBuf) -> Option<Vec<String>> {
-    let repo = Repository::open(workspace_path.to_str()?).ok()?;
-    let mut diff_files = HashSet::new();
-    let diff = repo.diff_index_to_workdir(None, None).ok()?;
-    for delta in diff.deltas() {
-        if let Some(path) = delta.new_file().path() {
-            if let Some(s) = path.to_str() {
-                diff_files.insert(workspace_path.join(s).to_str()?.to_string());
+/// The edits the servers answered `willRenameFiles` with, leaving out the
+/// ones that didn't answer in time, as a rename doesn't wait for them.
+fn will_rename_edits(
+    receivers: Vec<std::sync::mpsc::Receiver<Result<Value>>>,
+) -> Vec<WorkspaceEdit> {
+    let deadline = Instant::now() + WILL_RENAME_TIMEOUT;
+    let mut edits = Vec::new();
+    for receiver in receivers {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(value)) => {
+                let edit: Option<WorkspaceEdit> =
+                    serde_json::from_value(value).unwrap_or(None);
+                edits.extend(edit);
             }
+            Ok(Err(e)) => eprintln!("willRenameFiles failed: {}", e),
+            Err(_) => eprintln!("willRenameFiles timed out, renaming without it"),
         }
     }
-    let cached_diff = repo
-        .diff_tree_to_index(
-            repo.find_tree(repo.revparse_single("HEAD^{tree}").ok()?.id())
-                .ok()
-                .as_ref(),
-            None,
-            None,
-        )
-        .ok()?;
-    for delta in cached_diff.deltas() {
-        if let Some(path) = delta.new_file().path() {
-            if let Some(s) = path.to_str() {
-                diff_files.insert(workspace_path.join(s).to_str()?.to_string());
-            }
-        }
+    edits
+}
+
+/// The innermost of the workspace `folders` that `path` is in.
+fn workspace_root(folders: &[PathBuf], path: &Path) -> Option<PathBuf> {
+    folders
+        .iter()
+        .filter(|folder| path.starts_with(folder))
+        .max_by_key(|folder| folder.components().count())
+        .cloned()
+}
+
+/// Joins a path received from a plugin onto the workspace root, refusing
+/// absolute paths and any `..` component that could escape the workspace.
+pub(crate) fn resolve_workspace_path(
+    workspace: &Path,
+    path: &Path,
+) -> Result<PathBuf> {
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow!("{:?} is outside of the workspace", path));
     }
-    let mut diff_files: Vec<String> = diff_files.into_iter().collect();
-    diff_files.sort();
-    Some(diff_files)
+    Ok(workspace.join(path))
 }
 
-fn file_git_diff(
-    workspace_path: &PathBuf,
-    path: &PathBuf,
-    content: &str,
-) -> Option<(Vec<DiffHunk>, HashMap<usize, char>)> {
-    let repo = Repository::open(workspace_path.to_str()?).ok()?;
-    let head = repo.head().ok()?;
-    let tree = head.peel_to_tree().ok()?;
-    let tree_entry = tree
-        .get_path(path.strip_prefix(workspace_path).ok()?)
-        .ok()?;
-    let blob = repo.find_blob(tree_entry.id()).ok()?;
-    let mut patch = git2::Patch::from_blob_and_buffer(
-        &blob,
-        None,
-        content.as_bytes(),
-        None,
-        None,
-    )
-    .ok()?;
-    let mut line_changes = HashMap::new();
-    Some((
-        (0..patch.num_hunks())
-            .into_iter()
-            .filter_map(|i| {
-                let hunk = patch.hunk(i).ok()?;
-                let hunk = DiffHunk {
-                    old_start: hunk.0.old_start(),
-                    old_lines: hunk.0.old_lines(),
-                    new_start: hunk.0.new_start(),
-                    new_lines: hunk.0.new_lines(),
-                    header: String::from_utf8(hunk.0.header().to_vec()).ok()?,
-                };
-                let mut line_diff = 0;
-                for line in 0..hunk.old_lines + hunk.new_lines {
-                    if let Ok(diff_line) = patch.line_in_hunk(i, line as usize) {
-                        match diff_line.origin() {
-                            ' ' => {
-                                let new_line = diff_line.new_lineno().unwrap();
-                                let old_line = diff_line.old_lineno().unwrap();
-                                line_diff = new_line as i32 - old_line as i32;
-                            }
-                            '-' => {
-                                let old_line = diff_line.old_lineno().unwrap() - 1;
-                                let new_line =
-                                    (old_line as i32 + line_diff) as usize;
-                                line_changes.insert(new_line, '-');
-                                line_diff -= 1;
-                            }
-                            '+' => {
-                                let new_line =
-                                    diff_line.new_lineno().unwrap() as usize - 1;
-                                if let Some(c) = line_changes.get(&new_line) {
-                                    if c == &'-' {
-                                        line_changes.insert(new_line, 'm');
-                                    }
-                                } else {
-                                    line_changes.insert(new_line, '+');
-                                }
-                                line_diff += 1;
-                            }
-                            _ => continue,
-                        }
-                        diff_line.origin();
-                    }
-                }
-                Some(hunk)
-            })
-            .collect(),
-        line_changes,
-    ))
+/// Files in `target/` are ignored even without a git repository, as the
+/// file list does.
+fn is_ignored(repo: Option<&Repository>, workspace: &Path, path: &Path) -> bool {
+    let relative = match path.strip_prefix(workspace) {
+        Ok(relative) => relative,
+        Err(_) => return false,
+    };
+    if relative
+        .components()
+        .any(|c| c == Component::Normal("target".as_ref()))
+    {
+        return true;
+    }
+    repo.and_then(|repo| repo.is_path_ignored(relative).ok())
+        .unwrap_or(false)
+}
+
+/// The last character of an insertion and the position right after it, to
+/// check against the servers' trigger characters.
+fn typed_char(
+    buffer: &Buffer,
+    content_change: &TextDocumentContentChangeEvent,
+) -> Option<(char, Position)> {
+    let range = content_change.range?;
+    let typed = content_change.text.chars().last()?;
+    let start = buffer.offset_of_position(&range.start).ok()?;
+    let position = buffer.offset_to_position(start + content_change.text.len());
+    Some((typed, position))
 }
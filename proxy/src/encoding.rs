@@ -0,0 +1,280 @@
+//! Reading files in the encoding they were written in and writing them back
+//! in it. Buffers are UTF-8 in between.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+use crate::settings::setting;
+
+/// How much of a file is looked at to tell UTF-16 without a BOM.
+const UTF_16_SNIFF_LEN: usize = 4096;
+/// How much of a file is looked at to tell it isn't text.
+pub const BINARY_SNIFF_LEN: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileEncoding {
+    pub encoding: &'static Encoding,
+    /// Whether the file starts with a byte order mark, kept on save.
+    pub bom: bool,
+}
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+impl FileEncoding {
+    /// The encoding named `label`, as in `gbk` or `shift_jis`.
+    pub fn from_label(label: &str) -> Result<FileEncoding> {
+        let encoding = Encoding::for_label(label.trim().as_bytes())
+            .ok_or_else(|| anyhow!("unknown encoding {}", label))?;
+        Ok(FileEncoding {
+            encoding,
+            bom: false,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    /// Works out the encoding of `bytes`: a byte order mark first, then the
+    /// `default-encoding` of the settings, then a guess from the bytes.
+    pub fn detect(bytes: &[u8]) -> FileEncoding {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            return FileEncoding {
+                encoding,
+                bom: true,
+            };
+        }
+        // the zeros of UTF-16 are valid in any of the candidates
+        let default = user_default_encoding().unwrap_or(UTF_8);
+        let encoding = sniff_utf_16(bytes)
+            .or_else(|| {
+                [default, UTF_8].iter().copied().find(|encoding| {
+                    encoding
+                        .decode_without_bom_handling_and_without_replacement(bytes)
+                        .is_some()
+                })
+            })
+            .unwrap_or_else(|| {
+                let mut detector = EncodingDetector::new();
+                detector.feed(bytes, true);
+                detector.guess(None, true)
+            });
+        FileEncoding {
+            encoding,
+            bom: false,
+        }
+    }
+
+    /// The text of `bytes` in this encoding, without the byte order mark.
+    /// Bytes that aren't valid in it are replaced.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let bytes = match Encoding::for_bom(bytes) {
+            Some((encoding, len)) if encoding == self.encoding => &bytes[len..],
+            _ => bytes,
+        };
+        let (text, _) = self.encoding.decode_without_bom_handling(bytes);
+        text.into_owned()
+    }
+
+    /// The bytes of `text` in this encoding. Fails on the first character
+    /// the encoding has no way to write.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            let little_endian = self.encoding == UTF_16LE;
+            let unit_bytes = |unit: u16| {
+                if little_endian {
+                    unit.to_le_bytes()
+                } else {
+                    unit.to_be_bytes()
+                }
+            };
+            if self.bom {
+                bytes.extend_from_slice(&unit_bytes(0xFEFF));
+            }
+            for unit in text.encode_utf16() {
+                bytes.extend_from_slice(&unit_bytes(unit));
+            }
+            return Ok(bytes);
+        }
+        if self.encoding == UTF_8 {
+            if self.bom {
+                bytes.extend_from_slice(b"\xEF\xBB\xBF");
+            }
+            bytes.extend_from_slice(text.as_bytes());
+            return Ok(bytes);
+        }
+
+        let (encoded, _, had_errors) = self.encoding.encode(text);
+        if had_errors {
+            return Err(unencodable(text, self.encoding));
+        }
+        bytes.extend_from_slice(&encoded);
+        Ok(bytes)
+    }
+}
+
+/// Whether the start of a file is something other than text, like an
+/// image or an executable: it has a zero byte, and isn't UTF-16, or most of
+/// it is neither UTF-8 nor printable.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let bytes = &bytes[..bytes.len().min(BINARY_SNIFF_LEN)];
+    if Encoding::for_bom(bytes).is_some() || sniff_utf_16(bytes).is_some() {
+        return false;
+    }
+    if bytes.contains(&0) {
+        return true;
+    }
+    // text in a legacy encoding isn't UTF-8 either, but has few controls
+    let controls = bytes
+        .iter()
+        .filter(|b| (**b < 0x20 && !b"\t\n\r\x0c\x1b".contains(b)) || **b == 0x7f)
+        .count();
+    invalid_utf8_len(bytes) * 10 > bytes.len() * 3 && controls * 20 > bytes.len()
+}
+
+/// How many bytes aren't part of a valid UTF-8 char, not counting a char
+/// cut off at the end.
+fn invalid_utf8_len(mut bytes: &[u8]) -> usize {
+    let mut invalid = 0;
+    while let Err(e) = std::str::from_utf8(bytes) {
+        match e.error_len() {
+            Some(len) => {
+                invalid += len;
+                bytes = &bytes[e.valid_up_to() + len..];
+            }
+            None => break,
+        }
+    }
+    invalid
+}
+
+/// Reads a file and turns it into text, with the encoding it was in.
+pub fn read_file(path: &Path) -> Result<(String, FileEncoding)> {
+    let bytes = fs::read(path)?;
+    let encoding = FileEncoding::detect(&bytes);
+    Ok((encoding.decode(&bytes), encoding))
+}
+
+/// The error for the first character of `text` that `encoding` can't
+/// write, with where it is.
+fn unencodable(text: &str, encoding: &'static Encoding) -> anyhow::Error {
+    for (line, line_text) in text.lines().enumerate() {
+        for (column, c) in line_text.chars().enumerate() {
+            let mut buf = [0; 4];
+            let (_, _, had_errors) = encoding.encode(c.encode_utf8(&mut buf));
+            if had_errors {
+                return anyhow!(
+                    "{:?} at line {}, column {} can't be written in {}",
+                    c,
+                    line + 1,
+                    column + 1,
+                    encoding.name()
+                );
+            }
+        }
+    }
+    anyhow!("the text can't be written in {}", encoding.name())
+}
+
+/// Text mostly in ASCII written in UTF-16 has every other byte zero.
+fn sniff_utf_16(bytes: &[u8]) -> Option<&'static Encoding> {
+    let bytes = &bytes[..bytes.len().min(UTF_16_SNIFF_LEN)];
+    if bytes.len() < 2 || bytes.len() % 2 != 0 {
+        return None;
+    }
+    let pairs = bytes.len() / 2;
+    let zeros_at = |parity: usize| {
+        bytes
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|b| **b == 0)
+            .count()
+    };
+    let (even, odd) = (zeros_at(0), zeros_at(1));
+    if odd * 2 > pairs && even * 10 < pairs {
+        Some(UTF_16LE)
+    } else if even * 2 > pairs && odd * 10 < pairs {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// The `default-encoding` of the `[editor]` settings.
+fn user_default_encoding() -> Option<&'static Encoding> {
+    let label = setting(&["editor", "default-encoding"])?;
+    Encoding::for_label(label.as_str()?.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let cases: Vec<(&[u8], &str, &str)> = vec![
+            (b"plain ascii\n", "UTF-8", "plain ascii\n"),
+            (b"\xEF\xBB\xBFbom\n", "UTF-8", "bom\n"),
+            (b"\xFF\xFEh\x00i\x00", "UTF-16LE", "hi"),
+            (b"\xFE\xFF\x00h\x00i", "UTF-16BE", "hi"),
+            (b"h\x00e\x00l\x00l\x00o\x00", "UTF-16LE", "hello"),
+            (
+                "\u{5f53}\u{4f60}\u{597d}\n".as_bytes(),
+                "UTF-8",
+                "\u{5f53}\u{4f60}\u{597d}\n",
+            ),
+        ];
+        for (bytes, name, text) in cases {
+            let encoding = FileEncoding::detect(bytes);
+            assert_eq!(name, encoding.name());
+            assert_eq!(text, encoding.decode(bytes));
+            assert_eq!(bytes, encoding.encode(text).unwrap().as_slice());
+        }
+    }
+
+    #[test]
+    fn test_legacy_encodings() {
+        let text =
+            "\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30c6}\u{30ad}\u{30b9}\u{30c8}\n";
+        let shift_jis = FileEncoding::from_label("shift_jis").unwrap();
+        let bytes = shift_jis.encode(text).unwrap();
+        assert_eq!(text, shift_jis.decode(&bytes));
+
+        let gbk = FileEncoding::from_label("gbk").unwrap();
+        let bytes = gbk.encode("\u{4e2d}\u{6587}").unwrap();
+        assert_eq!(b"\xD6\xD0\xCE\xC4", bytes.as_slice());
+        assert!(gbk.encode("caf\u{e9} \u{1f600}").is_err());
+    }
+
+    #[test]
+    fn test_is_binary() {
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"));
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\x00\x00"));
+        let noise: Vec<u8> =
+            (0..1024u32).map(|i| (i * 97 % 251 + 1) as u8).collect();
+        assert!(is_binary(&noise));
+
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"plain\ttext\r\n"));
+        assert!(!is_binary(b"h\x00e\x00l\x00l\x00o\x00"));
+        let shift_jis = FileEncoding::from_label("shift_jis").unwrap();
+        let bytes = shift_jis.encode("\u{65e5}\u{672c}\u{8a9e}\n").unwrap();
+        assert!(!is_binary(&bytes));
+        // a char cut off by the end of the sniffed bytes
+        let text = format!("a{}", "\u{e9}".repeat(BINARY_SNIFF_LEN));
+        assert!(!is_binary(text.as_bytes()));
+    }
+}
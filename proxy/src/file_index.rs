@@ -0,0 +1,291 @@
+//! The files of the workspace folders, walked once when a folder is added
+//! and then kept up to date from the watcher's events, for the file palette
+//! to match against without walking the tree each time it opens. A file
+//! created later is left out by the folder's top `.gitignore` and the
+//! `[search] exclude` globs, the ones nested deeper only count for walks.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use globset::GlobSet;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkState;
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::search::{glob_set, search_walker};
+
+/// A file matching a `fuzzy_file_search`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMatch {
+    pub path: PathBuf,
+    /// The path in the workspace folder it's in, that the query matched.
+    pub relative: PathBuf,
+    pub score: i64,
+    /// The indices of the chars of `relative` the query matched.
+    pub indices: Vec<usize>,
+}
+
+struct IndexedFolder {
+    root: PathBuf,
+    /// Relative to `root`.
+    files: BTreeSet<PathBuf>,
+    gitignore: Gitignore,
+}
+
+pub struct FileIndex {
+    folders: Vec<IndexedFolder>,
+    exclude: Vec<String>,
+    exclude_set: GlobSet,
+}
+
+impl FileIndex {
+    pub fn new(exclude: Vec<String>) -> FileIndex {
+        FileIndex {
+            folders: Vec::new(),
+            exclude_set: glob_set(&exclude),
+            exclude,
+        }
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    pub fn len(&self) -> usize {
+        self.folders.iter().map(|folder| folder.files.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps the files walked in `root`, in place of the ones it had. Those
+    /// of a folder nested in another are only kept with the nested one.
+    pub fn set_folder(&mut self, root: &Path, files: BTreeSet<PathBuf>) {
+        self.folders.retain(|folder| folder.root != root);
+        for folder in self.folders.iter_mut() {
+            if let Ok(nested) = root.strip_prefix(&folder.root) {
+                folder.files.retain(|file| !file.starts_with(nested));
+            }
+        }
+        let mut gitignore = GitignoreBuilder::new(root);
+        gitignore.add(root.join(".gitignore"));
+        self.folders.push(IndexedFolder {
+            root: root.to_path_buf(),
+            files,
+            gitignore: gitignore.build().unwrap_or_else(|_| Gitignore::empty()),
+        });
+    }
+
+    pub fn remove_folder(&mut self, root: &Path) {
+        self.folders.retain(|folder| folder.root != root);
+    }
+
+    /// The folders indexed, for a folder nested in one of them to be walked.
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.folders
+            .iter()
+            .map(|folder| folder.root.clone())
+            .collect()
+    }
+
+    /// The innermost folder `path` is in.
+    fn folder_of(&self, path: &Path) -> Option<usize> {
+        self.folders
+            .iter()
+            .enumerate()
+            .filter(|(_, folder)| path.starts_with(&folder.root))
+            .max_by_key(|(_, folder)| folder.root.components().count())
+            .map(|(i, _)| i)
+    }
+
+    /// Adds a file, or the files of a directory, unless it's left out.
+    pub fn created(&mut self, path: &Path) {
+        let i = match self.folder_of(path) {
+            Some(i) => i,
+            None => return,
+        };
+        let folder = &self.folders[i];
+        let relative = match path.strip_prefix(&folder.root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return,
+        };
+        let is_dir = path.is_dir();
+        let hidden = relative.components().any(|c| {
+            c.as_os_str()
+                .to_str()
+                .map(|c| c.starts_with('.'))
+                .unwrap_or(false)
+        });
+        if hidden
+            || relative.ancestors().any(|a| self.exclude_set.is_match(a))
+            || folder
+                .gitignore
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+        {
+            return;
+        }
+        let files: Vec<PathBuf> = match is_dir {
+            true => walk_folder(path, &self.exclude, &[])
+                .into_iter()
+                .map(|file| relative.join(file))
+                .collect(),
+            false if path.is_file() => vec![relative.to_path_buf()],
+            false => return,
+        };
+        self.folders[i].files.extend(files);
+    }
+
+    /// Drops a file, or a directory and all the files in it.
+    pub fn removed(&mut self, path: &Path) {
+        let folder = match self.folder_of(path) {
+            Some(i) => &mut self.folders[i],
+            None => return,
+        };
+        let relative = match path.strip_prefix(&folder.root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return,
+        };
+        // the files in a directory come right after it
+        let removed: Vec<PathBuf> = folder
+            .files
+            .range(relative.to_path_buf()..)
+            .take_while(|file| file.starts_with(relative))
+            .cloned()
+            .collect();
+        for file in removed {
+            folder.files.remove(&file);
+        }
+    }
+
+    pub fn renamed(&mut self, from: &Path, to: &Path) {
+        self.removed(from);
+        self.created(to);
+    }
+
+    /// Every file, in no particular order.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.folders
+            .iter()
+            .flat_map(|folder| {
+                folder.files.iter().map(|file| folder.root.join(file))
+            })
+            .collect()
+    }
+
+    /// The best `max_results` files for `query`, scored on all cores.
+    pub fn search(&self, query: &str, max_results: usize) -> Vec<FileMatch> {
+        let matcher = SkimMatcherV2::default().ignore_case();
+        let mut matches: Vec<FileMatch> = self
+            .folders
+            .par_iter()
+            .flat_map(|folder| {
+                let matcher = &matcher;
+                folder.files.par_iter().filter_map(move |file| {
+                    let (score, indices) =
+                        matcher.fuzzy_indices(file.to_str()?, query)?;
+                    Some(FileMatch {
+                        path: folder.root.join(file),
+                        relative: file.clone(),
+                        score,
+                        indices,
+                    })
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| {
+                    let len = |m: &FileMatch| m.relative.as_os_str().len();
+                    len(a).cmp(&len(b))
+                })
+                .then_with(|| a.relative.cmp(&b.relative))
+        });
+        matches.truncate(max_results);
+        matches
+    }
+}
+
+/// The files of `root`, relative to it, that a search goes through, leaving
+/// out the `nested` folders, which are indexed on their own.
+pub fn walk_folder(
+    root: &Path,
+    exclude: &[String],
+    nested: &[PathBuf],
+) -> BTreeSet<PathBuf> {
+    let nested: Vec<&PathBuf> = nested
+        .iter()
+        .filter(|folder| folder.as_path() != root && folder.starts_with(root))
+        .collect();
+    let files = Mutex::new(BTreeSet::new());
+    let walker = match search_walker(&[root.to_path_buf()], exclude) {
+        Some(walker) => walker,
+        None => return BTreeSet::new(),
+    };
+    walker.run(|| {
+        let (files, nested) = (&files, &nested);
+        Box::new(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => return WalkState::Continue,
+            };
+            if nested.iter().any(|folder| entry.path() == folder.as_path()) {
+                return WalkState::Skip;
+            }
+            if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                if let Ok(relative) = entry.path().strip_prefix(root) {
+                    files.lock().insert(relative.to_path_buf());
+                }
+            }
+            WalkState::Continue
+        })
+    });
+    files.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_file_index() {
+        let root = TestDir::new("file-index");
+        fs::create_dir_all(root.join("src/bin")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        for file in ["src/main.rs", "src/bin/cli.rs", "target/out.rs"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let mut index = FileIndex::new(Vec::new());
+        index.set_folder(&root, walk_folder(&root, &[], &[]));
+        let relative = |index: &FileIndex, query: &str| {
+            index
+                .search(query, 10)
+                .into_iter()
+                .map(|m| m.relative.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["src/main.rs", "src/bin/cli.rs"],
+            relative(&index, "src")
+        );
+
+        fs::write(root.join("src/lib.rs"), "").unwrap();
+        fs::write(root.join("target/lib.rs"), "").unwrap();
+        index.created(&root.join("src/lib.rs"));
+        index.created(&root.join("target/lib.rs"));
+        index.removed(&root.join("src/bin"));
+        let results = relative(&index, "rs");
+        assert_eq!(vec!["src/lib.rs", "src/main.rs"], results);
+        assert_eq!(2, index.len());
+    }
+}
@@ -0,0 +1,263 @@
+//! What the file explorer does to the files of the workspace. A directory
+//! is listed a level at a time, as it's expanded, so a huge one nobody
+//! opens is never read. The changes fail rather than overwrite a path
+//! that's there already, and the dispatcher sends the listing of the
+//! directories they changed after, so the explorer refreshes those rather
+//! than the whole tree.
+
+use std::cmp::Ordering;
+use std::fs::{self, OpenOptions};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+use anyhow::{anyhow, Result};
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+
+use crate::dispatch::FileNodeItem;
+
+/// The entries of a directory, as sent in `refresh_dir` and answering
+/// `reveal_path`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirListing {
+    pub path: PathBuf,
+    pub items: Vec<FileNodeItem>,
+}
+
+/// Answers `trash_path`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashResponse {
+    /// False when there was no trash to move it to, so it's gone for good.
+    pub trashed: bool,
+}
+
+/// The entries of a directory, sorted as the explorer shows them. A
+/// symlink to the directory or one it's in is listed as a file, so the
+/// tree can't be expanded into itself forever.
+pub fn read_dir(path: &Path) -> Result<Vec<FileNodeItem>> {
+    let canonical = fs::canonicalize(path)?;
+    let repo = Repository::discover(path).ok();
+    let workdir = repo
+        .as_ref()
+        .and_then(|repo| repo.workdir())
+        .and_then(|workdir| fs::canonicalize(workdir).ok());
+    let mut items: Vec<FileNodeItem> = fs::read_dir(path)?
+        .flatten()
+        .map(|entry| {
+            let mut item = FileNodeItem::new(entry.path());
+            if item.is_symlink && item.is_dir {
+                let target = fs::canonicalize(entry.path()).ok();
+                if target.map(|t| canonical.starts_with(t)).unwrap_or(true) {
+                    item.is_dir = false;
+                }
+            }
+            if let (Some(repo), Some(workdir)) = (repo.as_ref(), workdir.as_ref()) {
+                item.is_ignored = canonical
+                    .join(entry.file_name())
+                    .strip_prefix(workdir)
+                    .ok()
+                    .and_then(|relative| repo.is_path_ignored(relative).ok())
+                    .unwrap_or(false);
+            }
+            item
+        })
+        .collect();
+    items.sort();
+    Ok(items)
+}
+
+/// The listings of the directories from the workspace folder `path` is in
+/// down to the one it's in, for the explorer to expand to it.
+pub fn reveal_path(folders: &[PathBuf], path: &Path) -> Result<Vec<DirListing>> {
+    let folder = folders
+        .iter()
+        .filter(|folder| path.starts_with(folder))
+        .max_by_key(|folder| folder.components().count())
+        .ok_or_else(|| anyhow!("{} isn't in the workspace", path.display()))?;
+    let mut dirs: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(folder))
+        .collect();
+    dirs.reverse();
+    dirs.into_iter()
+        .map(|dir| {
+            Ok(DirListing {
+                path: dir.to_path_buf(),
+                items: read_dir(dir)?,
+            })
+        })
+        .collect()
+}
+
+/// Compares names with the runs of digits in them compared by value.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_digits(&mut a), take_digits(&mut b));
+                let (x_value, y_value) =
+                    (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = x_value
+                    .len()
+                    .cmp(&y_value.len())
+                    .then_with(|| x_value.cmp(y_value))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn ensure_free(path: &Path) -> Result<()> {
+    match path.exists() {
+        true => Err(anyhow!("{} already exists", path.display())),
+        false => Ok(()),
+    }
+}
+
+pub fn create_file(path: &Path) -> Result<()> {
+    OpenOptions::new().write(true).create_new(true).open(path)?;
+    Ok(())
+}
+
+pub fn create_directory(path: &Path) -> Result<()> {
+    ensure_free(path)?;
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+pub fn rename_path(old: &Path, new: &Path) -> Result<()> {
+    // a rename that only changes the case finds the old path there on a
+    // system that ignores case
+    let same = fs::canonicalize(new).ok() == fs::canonicalize(old).ok();
+    if !same {
+        ensure_free(new)?;
+    }
+    if let Some(parent) = new.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(old, new)?;
+    Ok(())
+}
+
+/// Moves `path` to the trash, or deletes it when the trash can't take it,
+/// as on a system without one.
+pub fn trash_path(path: &Path) -> Result<TrashResponse> {
+    if !path.exists() {
+        return Err(anyhow!("{} doesn't exist", path.display()));
+    }
+    if let Err(e) = trash::delete(path) {
+        eprintln!("can't trash {}, deleting it: {}", path.display(), e);
+        match path.is_dir() {
+            true => fs::remove_dir_all(path)?,
+            false => fs::remove_file(path)?,
+        }
+        return Ok(TrashResponse { trashed: false });
+    }
+    Ok(TrashResponse { trashed: true })
+}
+
+/// Copies a file or a directory next to itself, as `name copy.ext`, then
+/// `name copy 2.ext` and so on, giving the path of the copy.
+pub fn duplicate_path(path: &Path) -> Result<PathBuf> {
+    let copy = duplicate_name(path)?;
+    match path.is_dir() {
+        true => copy_dir(path, &copy)?,
+        false => {
+            fs::copy(path, &copy)?;
+        }
+    }
+    Ok(copy)
+}
+
+fn duplicate_name(path: &Path) -> Result<PathBuf> {
+    let parent = path.parent().ok_or_else(|| anyhow!("can't copy a root"))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{} has no name", path.display()))?;
+    // a dot at the start is a hidden name rather than an extension
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && !path.is_dir() => name.split_at(i),
+        _ => (name, ""),
+    };
+    (1..)
+        .map(|n| match n {
+            1 => parent.join(format!("{} copy{}", stem, ext)),
+            n => parent.join(format!("{} copy {}{}", stem, n, ext)),
+        })
+        .find(|copy| !copy.exists())
+        .ok_or_else(|| anyhow!("no name left for a copy"))
+}
+
+pub(crate) fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        digits.push(c);
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["file10", "file2", "file1b", "file02", "a"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(vec!["a", "file1b", "file2", "file02", "file10"], names);
+    }
+
+    #[test]
+    fn test_duplicate_path() {
+        let dir = TestDir::new("file-ops");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join(".env"), "").unwrap();
+
+        let first = duplicate_path(&dir.join("src/main.rs")).unwrap();
+        let second = duplicate_path(&dir.join("src/main.rs")).unwrap();
+        let hidden = duplicate_path(&dir.join(".env")).unwrap();
+        let copied_dir = duplicate_path(&dir.join("src")).unwrap();
+        let copied = fs::read_to_string(copied_dir.join("main.rs")).unwrap();
+        let created = create_file(&dir.join(".env"));
+
+        assert_eq!(dir.join("src/main copy.rs"), first);
+        assert_eq!(dir.join("src/main copy 2.rs"), second);
+        assert_eq!(dir.join(".env copy"), hidden);
+        assert_eq!(dir.join("src copy"), copied_dir);
+        assert_eq!("fn main() {}", copied);
+        assert!(created.is_err());
+    }
+}
@@ -0,0 +1,1433 @@
+//! The git state of the workspace, for source control and the gutters: the
+//! status of the files, staged and not, the branch, and the lines of a
+//! buffer that differ from HEAD. The repository is found from the workspace
+//! up, so a workspace in a subdirectory of a repository works too, with the
+//! status of the files in it only. Files and hunks are staged, unstaged and
+//! discarded here too, and commits made, with the repository's hooks run
+//! as git would. The history of a file follows it through renames, and its
+//! old contents are decoded as a file opened from disk would be. Branches
+//! are listed, created and checked out, and changes stashed, a checkout
+//! refusing to overwrite changes unless forced.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use git2::build::CheckoutBuilder;
+use git2::{
+    ApplyLocation, ApplyOptions, BranchType, CheckoutNotificationType, Delta, Diff,
+    DiffFindOptions, DiffOptions, IndexAddOption, Oid, Patch, Repository, Sort,
+    StashFlags, Status, StatusOptions,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::{is_binary, FileEncoding};
+
+/// How long the edits of a buffer have to pause before its diff against
+/// HEAD is worked out again.
+pub const BUFFER_DIFF_DEBOUNCE: Duration = Duration::from_millis(150);
+/// The code of the error a commit gets, with a `CommitError` as its data.
+pub const COMMIT_ERROR_CODE: i64 = 4;
+/// The code of the error a checkout that would overwrite changes gets,
+/// with a `CheckoutConflict` as its data.
+pub const CHECKOUT_ERROR_CODE: i64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatusKind {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    TypeChange,
+    Untracked,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStatus {
+    pub path: PathBuf,
+    pub kind: FileStatusKind,
+    /// The path it had before, when it was renamed.
+    pub old_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitStatus {
+    pub staged: Vec<FileStatus>,
+    pub unstaged: Vec<FileStatus>,
+    /// The files with conflicts to resolve, which are in neither list.
+    pub conflicted: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchInfo {
+    /// None when HEAD is detached.
+    pub branch: Option<String>,
+    /// The short id of the commit HEAD is at, None before the first one.
+    pub head: Option<String>,
+    pub upstream: Option<String>,
+    /// The commits the branch has that its upstream doesn't.
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// A change of a buffer from HEAD, for a gutter marker. The lines start
+/// from 1, as in git, and a removal has no new lines, being marked after
+/// `new_start`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffHunk {
+    pub kind: LineChangeKind,
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub header: String,
+}
+
+pub fn open_repo(path: &Path) -> Option<Repository> {
+    Repository::discover(path).ok()
+}
+
+fn workdir(repo: &Repository) -> Result<PathBuf> {
+    repo.workdir()
+        .map(|workdir| workdir.to_path_buf())
+        .ok_or_else(|| anyhow!("the repository has no working directory"))
+}
+
+pub fn git_status(workspace: &Path) -> Result<GitStatus> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let workdir = workdir(&repo)?;
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .renames_head_to_index(true)
+        .renames_index_to_workdir(true);
+    if let Ok(relative) = workspace.strip_prefix(&workdir) {
+        if !relative.as_os_str().is_empty() {
+            options.pathspec(relative);
+        }
+    }
+
+    let mut status = GitStatus::default();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        let flags = entry.status();
+        if flags.is_conflicted() {
+            if let Some(path) = entry.path() {
+                status.conflicted.push(workdir.join(path));
+            }
+            continue;
+        }
+        let file_status = |kind, delta: Option<git2::DiffDelta>| {
+            let delta = delta?;
+            let path = delta
+                .new_file()
+                .path()
+                .or_else(|| delta.old_file().path())?;
+            let old_path = delta.old_file().path().filter(|old| *old != path);
+            Some(FileStatus {
+                path: workdir.join(path),
+                kind,
+                old_path: match kind {
+                    FileStatusKind::Renamed => old_path.map(|old| workdir.join(old)),
+                    _ => None,
+                },
+            })
+        };
+        if let Some(kind) = index_kind(flags) {
+            status
+                .staged
+                .extend(file_status(kind, entry.head_to_index()));
+        }
+        if let Some(kind) = worktree_kind(flags) {
+            status
+                .unstaged
+                .extend(file_status(kind, entry.index_to_workdir()));
+        }
+    }
+    Ok(status)
+}
+
+fn index_kind(flags: Status) -> Option<FileStatusKind> {
+    Some(if flags.is_index_new() {
+        FileStatusKind::Added
+    } else if flags.is_index_modified() {
+        FileStatusKind::Modified
+    } else if flags.is_index_deleted() {
+        FileStatusKind::Deleted
+    } else if flags.is_index_renamed() {
+        FileStatusKind::Renamed
+    } else if flags.is_index_typechange() {
+        FileStatusKind::TypeChange
+    } else {
+        return None;
+    })
+}
+
+fn worktree_kind(flags: Status) -> Option<FileStatusKind> {
+    Some(if flags.is_wt_new() {
+        FileStatusKind::Untracked
+    } else if flags.is_wt_modified() {
+        FileStatusKind::Modified
+    } else if flags.is_wt_deleted() {
+        FileStatusKind::Deleted
+    } else if flags.is_wt_renamed() {
+        FileStatusKind::Renamed
+    } else if flags.is_wt_typechange() {
+        FileStatusKind::TypeChange
+    } else {
+        return None;
+    })
+}
+
+pub fn git_branch_info(workspace: &Path) -> Result<BranchInfo> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let head = match repo.head() {
+        Ok(head) => head,
+        // no commit yet
+        Err(_) => return Ok(BranchInfo::default()),
+    };
+    let mut info = BranchInfo {
+        head: head.target().map(|oid| oid.to_string()[..7].to_string()),
+        ..Default::default()
+    };
+    if !head.is_branch() {
+        return Ok(info);
+    }
+    let name = match head.shorthand() {
+        Some(name) => name.to_string(),
+        None => return Ok(info),
+    };
+    let branch = repo.find_branch(&name, BranchType::Local)?;
+    info.branch = Some(name);
+    if let Ok(upstream) = branch.upstream() {
+        info.upstream = upstream.name().ok().flatten().map(|n| n.to_string());
+        if let (Some(local), Some(remote)) =
+            (branch.get().target(), upstream.get().target())
+        {
+            let (ahead, behind) = repo.graph_ahead_behind(local, remote)?;
+            info.ahead = ahead;
+            info.behind = behind;
+        }
+    }
+    Ok(info)
+}
+
+/// The files that differ from HEAD, staged or not, for the source control
+/// list.
+pub fn git_diff(workspace: &Path) -> Option<Vec<String>> {
+    let repo = open_repo(workspace)?;
+    let workdir = workdir(&repo).ok()?;
+    let mut diff_files = HashSet::new();
+    let diff = repo.diff_index_to_workdir(None, None).ok()?;
+    let cached_diff = repo
+        .diff_tree_to_index(
+            repo.find_tree(repo.revparse_single("HEAD^{tree}").ok()?.id())
+                .ok()
+                .as_ref(),
+            None,
+            None,
+        )
+        .ok()?;
+    for delta in diff.deltas().chain(cached_diff.deltas()) {
+        if let Some(path) = delta.new_file().path() {
+            let path = workdir.join(path);
+            if path.starts_with(workspace) {
+                diff_files.insert(path.to_str()?.to_string());
+            }
+        }
+    }
+    let mut diff_files: Vec<String> = diff_files.into_iter().collect();
+    diff_files.sort();
+    Some(diff_files)
+}
+
+/// The hunks of the buffer's content against the file at HEAD, with the
+/// marker of each line changed: `+` added, `m` modified, and `-` for a line
+/// after which lines were removed. None for a file that isn't at HEAD.
+pub fn buffer_git_diff(
+    path: &Path,
+    content: &str,
+) -> Option<(Vec<DiffHunk>, HashMap<usize, char>)> {
+    let repo = open_repo(path.parent()?)?;
+    let workdir = workdir(&repo).ok()?;
+    let head = repo.head().ok()?;
+    let tree = head.peel_to_tree().ok()?;
+    let tree_entry = tree.get_path(path.strip_prefix(&workdir).ok()?).ok()?;
+    let blob = repo.find_blob(tree_entry.id()).ok()?;
+    let patch = git2::Patch::from_blob_and_buffer(
+        &blob,
+        None,
+        content.as_bytes(),
+        None,
+        None,
+    )
+    .ok()?;
+    let mut line_changes = HashMap::new();
+    Some((
+        (0..patch.num_hunks())
+            .into_iter()
+            .filter_map(|i| {
+                let hunk = patch.hunk(i).ok()?;
+                let hunk = DiffHunk {
+                    kind: match (hunk.0.old_lines(), hunk.0.new_lines()) {
+                        (0, _) => LineChangeKind::Added,
+                        (_, 0) => LineChangeKind::Removed,
+                        _ => LineChangeKind::Modified,
+                    },
+                    old_start: hunk.0.old_start(),
+                    old_lines: hunk.0.old_lines(),
+                    new_start: hunk.0.new_start(),
+                    new_lines: hunk.0.new_lines(),
+                    header: String::from_utf8(hunk.0.header().to_vec()).ok()?,
+                };
+                let mut line_diff = 0;
+                for line in 0..hunk.old_lines + hunk.new_lines {
+                    if let Ok(diff_line) = patch.line_in_hunk(i, line as usize) {
+                        match diff_line.origin() {
+                            ' ' => {
+                                let new_line = diff_line.new_lineno().unwrap();
+                                let old_line = diff_line.old_lineno().unwrap();
+                                line_diff = new_line as i32 - old_line as i32;
+                            }
+                            '-' => {
+                                let old_line = diff_line.old_lineno().unwrap() - 1;
+                                let new_line =
+                                    (old_line as i32 + line_diff) as usize;
+                                line_changes.insert(new_line, '-');
+                                line_diff -= 1;
+                            }
+                            '+' => {
+                                let new_line =
+                                    diff_line.new_lineno().unwrap() as usize - 1;
+                                if let Some(c) = line_changes.get(&new_line) {
+                                    if c == &'-' {
+                                        line_changes.insert(new_line, 'm');
+                                    }
+                                } else {
+                                    line_changes.insert(new_line, '+');
+                                }
+                                line_diff += 1;
+                            }
+                            _ => continue,
+                        }
+                    }
+                }
+                Some(hunk)
+            })
+            .collect(),
+        line_changes,
+    ))
+}
+
+/// The lines of a hunk to stage or unstage, as `new_start` and `new_lines`
+/// of a `DiffHunk`: in the working file for staging, in the staged one for
+/// unstaging. A hunk overlapping them is taken whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HunkRange {
+    pub start: u32,
+    pub lines: u32,
+}
+
+impl HunkRange {
+    fn overlaps(&self, start: u32, lines: u32) -> bool {
+        // a removal has no lines, it's where they were
+        start < self.start + self.lines.max(1) && self.start < start + lines.max(1)
+    }
+}
+
+/// What discarding the changes of a file loses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscardedFile {
+    pub path: PathBuf,
+    pub kind: FileStatusKind,
+    /// The lines of the working file that go. An untracked file goes
+    /// whole, being deleted.
+    pub added: usize,
+    /// The lines that come back from the staged file.
+    pub removed: usize,
+    /// Lines can't be counted for a binary file.
+    pub binary: bool,
+    /// The file's buffer has edits that aren't saved, which go too.
+    pub unsaved: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscardResponse {
+    /// False when the discard wasn't confirmed, and nothing was done.
+    pub discarded: bool,
+    pub files: Vec<DiscardedFile>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitErrorKind {
+    EmptyMessage,
+    NothingStaged,
+    /// `user.name` or `user.email` isn't set.
+    NoIdentity,
+    Conflicts,
+    HookFailed,
+}
+
+/// Why a commit wasn't made. It goes to the editor as the data of the
+/// error, for it to show a hook's output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitError {
+    pub kind: CommitErrorKind,
+    pub message: String,
+    /// What a failing hook printed.
+    pub output: Option<String>,
+}
+
+impl CommitError {
+    fn new(kind: CommitErrorKind, message: &str) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            output: None,
+        }
+    }
+}
+
+impl std::fmt::Display for CommitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CommitError {}
+
+fn relative_paths(workdir: &Path, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    paths
+        .iter()
+        .map(|path| {
+            path.strip_prefix(workdir)
+                .map(|relative| relative.to_path_buf())
+                .map_err(|_| anyhow!("{:?} isn't in the repository", path))
+        })
+        .collect()
+}
+
+/// Stages the files, or all the files in the directories, deleted ones
+/// included.
+pub fn git_stage(workspace: &Path, paths: &[PathBuf]) -> Result<()> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, paths)?;
+    let mut index = repo.index()?;
+    index.add_all(&relative, IndexAddOption::DEFAULT, None)?;
+    index.update_all(&relative, None)?;
+    index.write()?;
+    Ok(())
+}
+
+/// Puts the staged files back as they are at HEAD, leaving the working
+/// files as they are.
+pub fn git_unstage(workspace: &Path, paths: &[PathBuf]) -> Result<()> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, paths)?;
+    match repo.head().and_then(|head| head.peel_to_commit()) {
+        Ok(commit) => repo.reset_default(Some(commit.as_object()), &relative)?,
+        // before the first commit everything staged is new
+        Err(_) => {
+            let mut index = repo.index()?;
+            index.remove_all(&relative, None)?;
+            index.write()?;
+        }
+    }
+    Ok(())
+}
+
+/// The changes of the working files from the staged ones, untracked files
+/// included.
+fn worktree_diff<'a>(
+    repo: &'a Repository,
+    relative: &[PathBuf],
+) -> Result<Diff<'a>> {
+    let mut options = DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    for path in relative {
+        options.pathspec(path);
+    }
+    Ok(repo.diff_index_to_workdir(None, Some(&mut options))?)
+}
+
+/// What discarding the unstaged changes of the files would lose, and when
+/// `confirmed` the discard itself: the working files are put back as they
+/// are staged, and untracked ones deleted. `unsaved` tells the files with
+/// unsaved edits.
+pub fn git_discard(
+    workspace: &Path,
+    paths: &[PathBuf],
+    unsaved: &HashSet<PathBuf>,
+    confirmed: bool,
+) -> Result<DiscardResponse> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let workdir = workdir(&repo)?;
+    let relative = relative_paths(&workdir, paths)?;
+    let diff = worktree_diff(&repo, &relative)?;
+    let mut files = Vec::new();
+    let mut tracked = Vec::new();
+    for (i, delta) in diff.deltas().enumerate() {
+        let file = delta.new_file().path().or_else(|| delta.old_file().path());
+        let file = match file {
+            Some(file) => file.to_path_buf(),
+            None => continue,
+        };
+        let kind = match delta.status() {
+            Delta::Untracked => FileStatusKind::Untracked,
+            Delta::Added => FileStatusKind::Added,
+            Delta::Deleted => FileStatusKind::Deleted,
+            Delta::Renamed => FileStatusKind::Renamed,
+            Delta::Typechange => FileStatusKind::TypeChange,
+            _ => FileStatusKind::Modified,
+        };
+        let (added, removed) = Patch::from_diff(&diff, i)?
+            .and_then(|patch| patch.line_stats().ok())
+            .map(|(_, added, removed)| (added, removed))
+            .unwrap_or_default();
+        let path = workdir.join(&file);
+        files.push(DiscardedFile {
+            unsaved: unsaved.contains(&path),
+            path,
+            kind,
+            added,
+            removed,
+            binary: delta.flags().is_binary(),
+        });
+        if kind != FileStatusKind::Untracked {
+            tracked.push(file);
+        }
+    }
+    if !confirmed {
+        return Ok(DiscardResponse {
+            discarded: false,
+            files,
+        });
+    }
+
+    if !tracked.is_empty() {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        for path in &tracked {
+            checkout.path(path);
+        }
+        repo.checkout_index(None, Some(&mut checkout))?;
+    }
+    for file in files
+        .iter()
+        .filter(|file| file.kind == FileStatusKind::Untracked)
+    {
+        fs::remove_file(&file.path)?;
+    }
+    Ok(DiscardResponse {
+        discarded: true,
+        files,
+    })
+}
+
+/// Stages the hunks of the working file overlapping `range`.
+pub fn git_stage_hunk(
+    workspace: &Path,
+    path: &Path,
+    range: HunkRange,
+) -> Result<()> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, &[path.to_path_buf()])?;
+    let diff = worktree_diff(&repo, &relative)?;
+    apply_hunks(&repo, &diff, |hunk| {
+        range.overlaps(hunk.new_start(), hunk.new_lines())
+    })
+}
+
+/// Puts the staged hunks of the file overlapping `range` back as they are at
+/// HEAD.
+pub fn git_unstage_hunk(
+    workspace: &Path,
+    path: &Path,
+    range: HunkRange,
+) -> Result<()> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, &[path.to_path_buf()])?;
+    let tree = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+    let mut options = DiffOptions::new();
+    // from the staged file to HEAD, so the staged lines are the old ones
+    options.reverse(true);
+    for path in &relative {
+        options.pathspec(path);
+    }
+    let diff = repo.diff_tree_to_index(tree.as_ref(), None, Some(&mut options))?;
+    apply_hunks(&repo, &diff, |hunk| {
+        range.overlaps(hunk.old_start(), hunk.old_lines())
+    })
+}
+
+fn apply_hunks(
+    repo: &Repository,
+    diff: &Diff,
+    mut take: impl FnMut(&git2::DiffHunk) -> bool,
+) -> Result<()> {
+    let mut taken = 0;
+    let mut options = ApplyOptions::new();
+    options.hunk_callback(|hunk| {
+        let take = hunk.map(|hunk| take(&hunk)).unwrap_or(false);
+        if take {
+            taken += 1;
+        }
+        take
+    });
+    repo.apply(diff, ApplyLocation::Index, Some(&mut options))?;
+    drop(options);
+    if taken == 0 {
+        return Err(anyhow!("no hunk there"));
+    }
+    Ok(())
+}
+
+/// Commits what's staged, or amends HEAD with it, giving the id of the new
+/// commit. The `pre-commit` and `commit-msg` hooks can stop it, as with
+/// `git commit`; the errors that aren't git's own are `CommitError`s.
+pub fn git_commit(workspace: &Path, message: &str, amend: bool) -> Result<String> {
+    if message.trim().is_empty() {
+        return Err(CommitError::new(
+            CommitErrorKind::EmptyMessage,
+            "the commit message is empty",
+        )
+        .into());
+    }
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let signature = repo.signature().map_err(|_| {
+        CommitError::new(
+            CommitErrorKind::NoIdentity,
+            "set user.name and user.email in the git config to commit",
+        )
+    })?;
+    let head = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    if amend && head.is_none() {
+        return Err(anyhow!("there's no commit to amend"));
+    }
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Err(CommitError::new(
+            CommitErrorKind::Conflicts,
+            "resolve the conflicts before committing",
+        )
+        .into());
+    }
+    let nothing_staged = match head.as_ref() {
+        Some(head) => head.tree_id() == index.write_tree()?,
+        None => index.is_empty(),
+    };
+    if nothing_staged && !amend {
+        return Err(CommitError::new(
+            CommitErrorKind::NothingStaged,
+            "there are no staged changes to commit",
+        )
+        .into());
+    }
+
+    run_hook(&repo, "pre-commit", &[])?;
+    let message_file = repo.path().join("COMMIT_EDITMSG");
+    fs::write(&message_file, message)?;
+    run_hook(&repo, "commit-msg", &[&message_file])?;
+    let message = fs::read_to_string(&message_file)?;
+    if message.trim().is_empty() {
+        return Err(CommitError::new(
+            CommitErrorKind::EmptyMessage,
+            "the commit-msg hook left the message empty",
+        )
+        .into());
+    }
+
+    // the pre-commit hook can stage files, as formatters do
+    index.read(true)?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let id = match head {
+        Some(head) if amend => head.amend(
+            Some("HEAD"),
+            None,
+            Some(&signature),
+            None,
+            Some(&message),
+            Some(&tree),
+        )?,
+        head => {
+            let parents: Vec<&git2::Commit> = head.iter().collect();
+            repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                &message,
+                &tree,
+                &parents,
+            )?
+        }
+    };
+    // it can't stop the commit, which is made
+    let _ = run_hook(&repo, "post-commit", &[]);
+    Ok(id.to_string())
+}
+
+/// Runs a hook of the repository in its working directory, if it has one
+/// that can be run, failing with what it printed when it fails.
+fn run_hook(repo: &Repository, name: &str, args: &[&Path]) -> Result<()> {
+    let hooks_dir = repo
+        .config()
+        .ok()
+        .and_then(|config| config.get_path("core.hooksPath").ok())
+        .map(|dir| match repo.workdir() {
+            Some(workdir) if dir.is_relative() => workdir.join(dir),
+            _ => dir,
+        })
+        .unwrap_or_else(|| repo.path().join("hooks"));
+    let hook = hooks_dir.join(name);
+    if !is_executable(&hook) {
+        return Ok(());
+    }
+    let output = Command::new(&hook)
+        .args(args)
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut printed = String::from_utf8_lossy(&output.stdout).to_string();
+    printed.push_str(&String::from_utf8_lossy(&output.stderr));
+    Err(CommitError {
+        kind: CommitErrorKind::HookFailed,
+        message: format!("the {} hook failed", name),
+        output: Some(printed),
+    }
+    .into())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitInfo {
+    pub id: String,
+    pub short_id: String,
+    pub author: String,
+    pub email: String,
+    /// Seconds since the epoch.
+    pub time: i64,
+    /// The first line of the message.
+    pub summary: String,
+}
+
+impl CommitInfo {
+    pub(crate) fn new(commit: &git2::Commit) -> Self {
+        let id = commit.id().to_string();
+        let author = commit.author();
+        Self {
+            short_id: id[..7].to_string(),
+            id,
+            author: author.name().unwrap_or_default().to_string(),
+            email: author.email().unwrap_or_default().to_string(),
+            time: commit.time().seconds(),
+            summary: commit.summary().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+/// A commit that changed a file, with the path the file had in it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCommit {
+    pub commit: CommitInfo,
+    pub path: PathBuf,
+}
+
+/// A file as it was at a commit. A binary one has no content.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAtRevision {
+    pub path: PathBuf,
+    pub commit_id: String,
+    pub content: Option<String>,
+    pub encoding: Option<String>,
+    pub binary: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffLine {
+    /// `+`, `-` or ` `, as in a unified diff.
+    pub origin: char,
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnifiedHunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub header: String,
+    pub lines: Vec<DiffLine>,
+}
+
+/// The diff of a file between two revisions, with no hunks when either is
+/// binary.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub binary: bool,
+    pub hunks: Vec<UnifiedHunk>,
+}
+
+/// The id of the blob at `path` in a commit, None when it isn't there.
+fn blob_at(commit: &git2::Commit, path: &Path) -> Option<Oid> {
+    commit
+        .tree()
+        .ok()?
+        .get_path(path)
+        .ok()
+        .map(|entry| entry.id())
+}
+
+/// The commits from HEAD that changed `path`, newest first, skipping the
+/// first `skip`. Going back through a commit that renamed the file, the
+/// older ones are of its old path, as `git log --follow` does. A merge is
+/// compared with its first parent.
+pub fn git_file_history(
+    path: &Path,
+    limit: usize,
+    skip: usize,
+) -> Result<Vec<FileCommit>> {
+    let repo = open_repo(path.parent().unwrap_or(path))
+        .ok_or_else(|| anyhow!("not in a git repository"))?;
+    let mut current =
+        relative_paths(&workdir(&repo)?, &[path.to_path_buf()])?.remove(0);
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+
+    let mut history = Vec::new();
+    let mut skipped = 0;
+    for id in revwalk {
+        if history.len() >= limit {
+            break;
+        }
+        let commit = repo.find_commit(id?)?;
+        let blob = match blob_at(&commit, &current) {
+            Some(blob) => blob,
+            None => continue,
+        };
+        let parent = commit.parents().next();
+        let parent_blob = parent.as_ref().and_then(|p| blob_at(p, &current));
+        if parent_blob == Some(blob) {
+            continue;
+        }
+        let path = current.clone();
+        if let (Some(parent), None) = (parent.as_ref(), parent_blob) {
+            // added here, or renamed from another path
+            match renamed_from(&repo, parent, &commit, &current)? {
+                Some(old) => current = old,
+                None => {
+                    if skipped >= skip {
+                        history.push(FileCommit {
+                            commit: CommitInfo::new(&commit),
+                            path,
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+        if skipped < skip {
+            skipped += 1;
+            continue;
+        }
+        history.push(FileCommit {
+            commit: CommitInfo::new(&commit),
+            path,
+        });
+    }
+    Ok(history)
+}
+
+/// The path a file at `path` in `commit` had in `parent`, if the commit
+/// renamed it.
+fn renamed_from(
+    repo: &Repository,
+    parent: &git2::Commit,
+    commit: &git2::Commit,
+    path: &Path,
+) -> Result<Option<PathBuf>> {
+    let mut diff =
+        repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&commit.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+    let old = diff
+        .deltas()
+        .filter(|delta| delta.status() == Delta::Renamed)
+        .find(|delta| delta.new_file().path() == Some(path))
+        .and_then(|delta| delta.old_file().path().map(|old| old.to_path_buf()));
+    Ok(old)
+}
+
+/// The bytes of `path` at a revision, or an empty file when it isn't there.
+fn bytes_at(repo: &Repository, rev: &str, relative: &Path) -> Result<Vec<u8>> {
+    let tree = repo.revparse_single(rev)?.peel_to_tree()?;
+    let entry = match tree.get_path(relative) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(Vec::new()),
+    };
+    Ok(repo.find_blob(entry.id())?.content().to_vec())
+}
+
+/// The file at `path`, the one it had at the commit, as it was then.
+pub fn git_show_file_at(path: &Path, commit_id: &str) -> Result<FileAtRevision> {
+    let repo = open_repo(path.parent().unwrap_or(path))
+        .ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, &[path.to_path_buf()])?;
+    let tree = repo.revparse_single(commit_id)?.peel_to_tree()?;
+    let entry = tree
+        .get_path(&relative[0])
+        .map_err(|_| anyhow!("{:?} isn't in {}", path, commit_id))?;
+    let blob = repo.find_blob(entry.id())?;
+    let bytes = blob.content();
+    let mut file = FileAtRevision {
+        path: path.to_path_buf(),
+        commit_id: commit_id.to_string(),
+        content: None,
+        encoding: None,
+        binary: blob.is_binary() || is_binary(bytes),
+    };
+    if !file.binary {
+        let encoding = FileEncoding::detect(bytes);
+        file.content = Some(encoding.decode(bytes));
+        file.encoding = Some(encoding.name().to_string());
+    }
+    Ok(file)
+}
+
+/// The diff of `path` from `rev_a` to `rev_b`, or to the working file
+/// without `rev_b`. Both sides are decoded first, so the lines of a file in
+/// a legacy encoding read right.
+pub fn git_diff_paths(
+    path: &Path,
+    rev_a: &str,
+    rev_b: Option<&str>,
+) -> Result<FileDiff> {
+    let repo = open_repo(path.parent().unwrap_or(path))
+        .ok_or_else(|| anyhow!("not in a git repository"))?;
+    let relative = relative_paths(&workdir(&repo)?, &[path.to_path_buf()])?;
+    let old = bytes_at(&repo, rev_a, &relative[0])?;
+    let new = match rev_b {
+        Some(rev_b) => bytes_at(&repo, rev_b, &relative[0])?,
+        None => fs::read(path).unwrap_or_default(),
+    };
+    if is_binary(&old) || is_binary(&new) {
+        return Ok(FileDiff {
+            binary: true,
+            hunks: Vec::new(),
+        });
+    }
+    let decode = |bytes: &[u8]| FileEncoding::detect(bytes).decode(bytes);
+    let (old, new) = (decode(&old), decode(&new));
+    let patch = Patch::from_buffers(
+        old.as_bytes(),
+        Some(relative[0].as_path()),
+        new.as_bytes(),
+        Some(relative[0].as_path()),
+        None,
+    )?;
+    let mut hunks = Vec::new();
+    for i in 0..patch.num_hunks() {
+        let (hunk, lines) = patch.hunk(i)?;
+        hunks.push(UnifiedHunk {
+            old_start: hunk.old_start(),
+            old_lines: hunk.old_lines(),
+            new_start: hunk.new_start(),
+            new_lines: hunk.new_lines(),
+            header: String::from_utf8_lossy(hunk.header()).to_string(),
+            lines: (0..lines)
+                .filter_map(|line| patch.line_in_hunk(i, line).ok())
+                .map(|line| DiffLine {
+                    origin: line.origin(),
+                    old_line: line.old_lineno(),
+                    new_line: line.new_lineno(),
+                    text: String::from_utf8_lossy(line.content()).to_string(),
+                })
+                .collect(),
+        });
+    }
+    Ok(FileDiff {
+        binary: false,
+        hunks,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchKind {
+    Local,
+    Remote,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Branch {
+    /// As in `main` or `origin/main`.
+    pub name: String,
+    pub kind: BranchKind,
+    /// Whether it's the branch checked out.
+    pub is_head: bool,
+    pub upstream: Option<String>,
+    pub ahead: usize,
+    pub behind: usize,
+    /// The commit it's at.
+    pub commit: Option<CommitInfo>,
+}
+
+/// Every local and remote branch, local ones first.
+pub fn git_branches(workspace: &Path) -> Result<Vec<Branch>> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let mut branches = Vec::new();
+    for branch in repo.branches(None)? {
+        let (branch, branch_type) = branch?;
+        let name = match branch.name()? {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // origin/HEAD only points at another one
+        if branch.get().symbolic_target().is_some() {
+            continue;
+        }
+        let commit = branch.get().peel_to_commit().ok();
+        let mut info = Branch {
+            name,
+            kind: match branch_type {
+                BranchType::Local => BranchKind::Local,
+                BranchType::Remote => BranchKind::Remote,
+            },
+            is_head: branch.is_head(),
+            upstream: None,
+            ahead: 0,
+            behind: 0,
+            commit: commit.as_ref().map(CommitInfo::new),
+        };
+        if let Ok(upstream) = branch.upstream() {
+            info.upstream = upstream.name().ok().flatten().map(|n| n.to_string());
+            if let (Some(local), Some(remote)) =
+                (branch.get().target(), upstream.get().target())
+            {
+                let (ahead, behind) = repo.graph_ahead_behind(local, remote)?;
+                info.ahead = ahead;
+                info.behind = behind;
+            }
+        }
+        branches.push(info);
+    }
+    branches.sort_by(|a, b| {
+        (a.kind == BranchKind::Remote, &a.name)
+            .cmp(&(b.kind == BranchKind::Remote, &b.name))
+    });
+    Ok(branches)
+}
+
+/// Why a checkout wasn't made: the working files it would overwrite. It
+/// goes to the editor as the data of the error, to offer forcing it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckoutConflict {
+    pub target: String,
+    pub files: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for CheckoutConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checking out {} would overwrite the changes of {} files",
+            self.target,
+            self.files.len()
+        )
+    }
+}
+
+impl std::error::Error for CheckoutConflict {}
+
+/// Checks out a branch, or a commit with HEAD detached, creating the branch
+/// first when `create`. A branch that's only on a remote is checked out as
+/// a local one tracking it, as `git checkout` does. Unless `force`, changes
+/// the checkout would overwrite stop it. Gives the files it changed.
+pub fn git_checkout(
+    workspace: &Path,
+    target: &str,
+    create: bool,
+    force: bool,
+) -> Result<Vec<FileStatus>> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let workdir = workdir(&repo)?;
+    if create {
+        git_create_branch(workspace, target, None)?;
+    }
+    let local = match repo.find_branch(target, BranchType::Local) {
+        Ok(branch) => Some(branch),
+        Err(_) => tracking_branch(&repo, target)?,
+    };
+    let (object, refname) = match local.as_ref() {
+        Some(branch) => (
+            branch.get().peel(git2::ObjectType::Commit)?,
+            branch.get().name().map(|name| name.to_string()),
+        ),
+        None => (
+            repo.revparse_single(target)?
+                .peel(git2::ObjectType::Commit)?,
+            None,
+        ),
+    };
+
+    let head_tree = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+    let changes = repo
+        .diff_tree_to_tree(head_tree.as_ref(), Some(&object.peel_to_tree()?), None)?
+        .deltas()
+        .filter_map(|delta| {
+            let kind = match delta.status() {
+                Delta::Added => FileStatusKind::Added,
+                Delta::Deleted => FileStatusKind::Deleted,
+                _ => FileStatusKind::Modified,
+            };
+            let path = delta
+                .new_file()
+                .path()
+                .or_else(|| delta.old_file().path())?;
+            Some(FileStatus {
+                path: workdir.join(path),
+                kind,
+                old_path: None,
+            })
+        })
+        .collect();
+
+    let mut conflicts = Vec::new();
+    let result = {
+        let mut checkout = CheckoutBuilder::new();
+        if force {
+            checkout.force();
+        } else {
+            checkout.safe();
+        }
+        checkout.notify_on(CheckoutNotificationType::CONFLICT);
+        checkout.notify(|_, path, _, _, _| {
+            if let Some(path) = path {
+                conflicts.push(workdir.join(path));
+            }
+            true
+        });
+        repo.checkout_tree(&object, Some(&mut checkout))
+    };
+    if !conflicts.is_empty() {
+        return Err(CheckoutConflict {
+            target: target.to_string(),
+            files: conflicts,
+        }
+        .into());
+    }
+    result?;
+    match refname {
+        Some(refname) => repo.set_head(&refname)?,
+        None => repo.set_head_detached(object.id())?,
+    }
+    Ok(changes)
+}
+
+/// The local branch made for one of the same name on a single remote.
+fn tracking_branch<'a>(
+    repo: &'a Repository,
+    name: &str,
+) -> Result<Option<git2::Branch<'a>>> {
+    let remotes: Vec<git2::Branch> = repo
+        .branches(Some(BranchType::Remote))?
+        .filter_map(|branch| branch.ok())
+        .map(|(branch, _)| branch)
+        .filter(|branch| {
+            branch
+                .name()
+                .ok()
+                .flatten()
+                .and_then(|remote| remote.split_once('/'))
+                .map(|(_, branch)| branch == name)
+                .unwrap_or(false)
+        })
+        .collect();
+    if remotes.len() != 1 {
+        return Ok(None);
+    }
+    let remote = &remotes[0];
+    let mut local = repo.branch(name, &remote.get().peel_to_commit()?, false)?;
+    local.set_upstream(remote.name()?)?;
+    Ok(Some(local))
+}
+
+/// Makes a branch at `from`, a branch or a commit, or at HEAD.
+pub fn git_create_branch(
+    workspace: &Path,
+    name: &str,
+    from: Option<&str>,
+) -> Result<()> {
+    let repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let commit = match from {
+        Some(from) => repo.revparse_single(from)?.peel_to_commit()?,
+        None => repo.head()?.peel_to_commit()?,
+    };
+    repo.branch(name, &commit, false)?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StashEntry {
+    pub index: usize,
+    pub message: String,
+    pub id: String,
+}
+
+/// Stashes the changes, staged and not, and the untracked files too when
+/// `include_untracked`, giving the id of the stash.
+pub fn git_stash_save(
+    workspace: &Path,
+    message: Option<&str>,
+    include_untracked: bool,
+) -> Result<String> {
+    let mut repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let signature = repo.signature()?;
+    let flags = match include_untracked {
+        true => StashFlags::INCLUDE_UNTRACKED,
+        false => StashFlags::DEFAULT,
+    };
+    let id =
+        repo.stash_save(&signature, message.unwrap_or_default(), Some(flags))?;
+    Ok(id.to_string())
+}
+
+/// Applies a stash and drops it, unless applying it conflicts.
+pub fn git_stash_pop(workspace: &Path, index: usize) -> Result<()> {
+    let mut repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    repo.stash_pop(index, None)?;
+    Ok(())
+}
+
+/// The stashes, newest first.
+pub fn git_stash_list(workspace: &Path) -> Result<Vec<StashEntry>> {
+    let mut repo =
+        open_repo(workspace).ok_or_else(|| anyhow!("not in a git repository"))?;
+    let mut stashes = Vec::new();
+    repo.stash_foreach(|index, message, id| {
+        stashes.push(StashEntry {
+            index,
+            message: message.to_string(),
+            id: id.to_string(),
+        });
+        true
+    })?;
+    Ok(stashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_git_status() {
+        let root = TestDir::new("git-status");
+        fs::create_dir_all(root.join("src")).unwrap();
+        let repo = Repository::init(&root).unwrap();
+        fs::write(root.join("README.md"), "readme\n").unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        index.write().unwrap();
+
+        let status = git_status(&root.join("src")).unwrap();
+        let paths = |files: &[FileStatus]| {
+            files
+                .iter()
+                .map(|file| (file.path.clone(), file.kind))
+                .collect::<Vec<_>>()
+        };
+        // only the files of the subdirectory opened
+        assert!(status.staged.is_empty());
+        assert_eq!(
+            vec![(root.join("src/main.rs"), FileStatusKind::Untracked)],
+            paths(&status.unstaged)
+        );
+        let status = git_status(&root).unwrap();
+        assert_eq!(
+            vec![(root.join("README.md"), FileStatusKind::Added)],
+            paths(&status.staged)
+        );
+        let branch = git_branch_info(&root).unwrap();
+        assert_eq!(BranchInfo::default(), branch);
+    }
+
+    #[test]
+    fn test_git_commit_and_discard() {
+        let root = TestDir::new("git-commit");
+        let repo = Repository::init(&root).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Lapce").unwrap();
+        config.set_str("user.email", "lapce@example.com").unwrap();
+        let file = root.join("a.txt");
+        fs::write(&file, "one\ntwo\n").unwrap();
+        let commit_error = |result: Result<String>| {
+            result.unwrap_err().downcast::<CommitError>().unwrap().kind
+        };
+
+        assert_eq!(
+            CommitErrorKind::NothingStaged,
+            commit_error(git_commit(&root, "first", false))
+        );
+        git_stage(&root, &[file.clone()]).unwrap();
+        assert_eq!(
+            CommitErrorKind::EmptyMessage,
+            commit_error(git_commit(&root, " \n", false))
+        );
+        let id = git_commit(&root, "first", false).unwrap();
+        assert_eq!(id, repo.head().unwrap().target().unwrap().to_string());
+
+        fs::write(&file, "one\n2\nthree\n").unwrap();
+        let response =
+            git_discard(&root, &[file.clone()], &HashSet::new(), false).unwrap();
+        assert!(!response.discarded);
+        assert_eq!((2, 1), (response.files[0].added, response.files[0].removed));
+        assert_eq!("one\n2\nthree\n", fs::read_to_string(&file).unwrap());
+        git_discard(&root, &[file.clone()], &HashSet::new(), true).unwrap();
+        let text = fs::read_to_string(&file).unwrap();
+        assert_eq!("one\ntwo\n", text);
+    }
+
+    #[test]
+    fn test_git_file_history() {
+        let root = TestDir::new("git-history");
+        let repo = Repository::init(&root).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Lapce").unwrap();
+        config.set_str("user.email", "lapce@example.com").unwrap();
+        let (a, b) = (root.join("a.txt"), root.join("b.txt"));
+        let commit = |message: &str| {
+            git_stage(&root, &[a.clone(), b.clone()]).unwrap();
+            git_commit(&root, message, false).unwrap();
+        };
+        fs::write(&a, "one\ntwo\nthree\n").unwrap();
+        commit("add a");
+        fs::write(&a, "one\n2\nthree\n").unwrap();
+        commit("change a");
+        fs::write(root.join("c.txt"), "c\n").unwrap();
+        git_stage(&root, &[root.join("c.txt")]).unwrap();
+        git_commit(&root, "add c", false).unwrap();
+        fs::rename(&a, &b).unwrap();
+        commit("rename a");
+
+        let history = git_file_history(&b, 10, 0).unwrap();
+        let summaries: Vec<(&str, &Path)> = history
+            .iter()
+            .map(|c| (c.commit.summary.as_str(), c.path.as_path()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("rename a", Path::new("b.txt")),
+                ("change a", Path::new("a.txt")),
+                ("add a", Path::new("a.txt")),
+            ],
+            summaries
+        );
+        assert_eq!(2, git_file_history(&b, 10, 1).unwrap().len());
+
+        let diff = git_diff_paths(&a, "HEAD~3", Some("HEAD~2")).unwrap();
+        let file = git_show_file_at(&a, &history[2].commit.id).unwrap();
+        let lines: Vec<(char, &str)> = diff.hunks[0]
+            .lines
+            .iter()
+            .map(|line| (line.origin, line.text.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (' ', "one\n"),
+                ('-', "two\n"),
+                ('+', "2\n"),
+                (' ', "three\n")
+            ],
+            lines
+        );
+        assert_eq!(Some("one\ntwo\nthree\n".to_string()), file.content);
+    }
+
+    #[test]
+    fn test_git_checkout() {
+        let root = TestDir::new("git-checkout");
+        let repo = Repository::init(&root).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Lapce").unwrap();
+        config.set_str("user.email", "lapce@example.com").unwrap();
+        let file = root.join("a.txt");
+        fs::write(&file, "one\n").unwrap();
+        git_stage(&root, &[file.clone()]).unwrap();
+        git_commit(&root, "first", false).unwrap();
+        let first = git_branch_info(&root).unwrap().branch.unwrap();
+
+        git_checkout(&root, "other", true, false).unwrap();
+        fs::write(&file, "two\n").unwrap();
+        git_stage(&root, &[file.clone()]).unwrap();
+        git_commit(&root, "second", false).unwrap();
+        fs::write(&file, "three\n").unwrap();
+        let conflict = git_checkout(&root, &first, false, false)
+            .unwrap_err()
+            .downcast::<CheckoutConflict>()
+            .unwrap();
+        assert_eq!(vec![file.clone()], conflict.files);
+        let changes = git_checkout(&root, &first, false, true).unwrap();
+        let text = fs::read_to_string(&file).unwrap();
+        let branches = git_branches(&root).unwrap();
+        assert_eq!(
+            vec![file],
+            changes.into_iter().map(|c| c.path).collect::<Vec<_>>()
+        );
+        assert_eq!("one\n", text);
+        let head: Vec<(&str, bool)> = branches
+            .iter()
+            .map(|branch| (branch.name.as_str(), branch.is_head))
+            .collect();
+        let mut expected = vec![(first.as_str(), true), ("other", false)];
+        expected.sort();
+        assert_eq!(expected, head);
+    }
+}
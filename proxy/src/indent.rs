@@ -0,0 +1,398 @@
+//! Works out how a file is indented. An `.editorconfig` says it if it has
+//! a section for the file, then the lines of the file tell it, and a file
+//! that doesn't gets the one for its language from the `[indentation]`
+//! settings or the built-in defaults.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use xi_rope::Rope;
+
+use crate::settings::setting;
+
+/// How many lines from the start are looked at.
+const DETECT_LINES: usize = 1000;
+/// Fewer indented lines than this don't tell anything.
+const MIN_INDENTED_LINES: usize = 4;
+/// The space widths told apart.
+const SPACE_WIDTHS: [u32; 3] = [2, 4, 8];
+/// How wide a tab is shown and sent to formatting.
+pub const TAB_WIDTH: u32 = 4;
+
+/// The languages that don't get 4 spaces.
+const LANGUAGE_INDENTS: &[(&str, IndentStyle)] = &[
+    ("go", IndentStyle::Tabs),
+    ("makefile", IndentStyle::Tabs),
+    ("yaml", IndentStyle::Spaces(2)),
+    ("ruby", IndentStyle::Spaces(2)),
+    ("javascript", IndentStyle::Spaces(2)),
+    ("javascriptreact", IndentStyle::Spaces(2)),
+    ("typescript", IndentStyle::Spaces(2)),
+    ("typescriptreact", IndentStyle::Spaces(2)),
+    ("json", IndentStyle::Spaces(2)),
+    ("html", IndentStyle::Spaces(2)),
+    ("css", IndentStyle::Spaces(2)),
+    ("nix", IndentStyle::Spaces(2)),
+    ("elixir", IndentStyle::Spaces(2)),
+    ("haskell", IndentStyle::Spaces(2)),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndentStyle {
+    Tabs,
+    Spaces(u32),
+}
+
+impl Default for IndentStyle {
+    fn default() -> Self {
+        IndentStyle::Spaces(4)
+    }
+}
+
+impl IndentStyle {
+    /// What one level of indentation is.
+    pub fn unit(&self) -> String {
+        match self {
+            IndentStyle::Tabs => "\t".to_string(),
+            IndentStyle::Spaces(n) => " ".repeat(*n as usize),
+        }
+    }
+
+    /// How many columns one level of indentation takes.
+    pub fn width(&self) -> u32 {
+        match self {
+            IndentStyle::Tabs => TAB_WIDTH,
+            IndentStyle::Spaces(n) => *n,
+        }
+    }
+
+    pub fn insert_spaces(&self) -> bool {
+        matches!(self, IndentStyle::Spaces(_))
+    }
+
+    /// An indentation setting, `"tabs"` or a number of spaces.
+    fn from_setting(value: &toml::Value) -> Option<IndentStyle> {
+        match value {
+            toml::Value::String(s) if s == "tabs" => Some(IndentStyle::Tabs),
+            toml::Value::Integer(n) if *n > 0 => {
+                Some(IndentStyle::Spaces(*n as u32))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IndentStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndentStyle::Tabs => write!(f, "Tabs"),
+            IndentStyle::Spaces(n) => write!(f, "Spaces: {}", n),
+        }
+    }
+}
+
+/// The indentation of the file at `path` with `content`.
+pub fn indent_style(path: &Path, content: &Rope, language_id: &str) -> IndentStyle {
+    let detected = detect(content)
+        .unwrap_or_else(|| language_indent(&user_indentation(), language_id));
+    editorconfig_indent(path).apply(detected)
+}
+
+/// The indentation most of the first lines of `content` use, `None` when
+/// they're too few or mix tabs and spaces about as much.
+pub fn detect(content: &Rope) -> Option<IndentStyle> {
+    let mut tabs = 0;
+    let mut spaces = 0;
+    let mut widths = [0; SPACE_WIDTHS.len()];
+    let mut last_spaces = 0;
+    for line in content.lines(..).take(DETECT_LINES) {
+        let rest = line.trim_start_matches(|c: char| c == ' ' || c == '\t');
+        if rest.is_empty() {
+            continue;
+        }
+        let indent = &line[..line.len() - rest.len()];
+        if indent.starts_with('\t') {
+            tabs += 1;
+            continue;
+        }
+        // the ` * ` of a C block comment is off by one
+        if rest.starts_with('*') && indent.len() % 2 == 1 {
+            continue;
+        }
+        let n = indent.len() as u32;
+        if n > 0 {
+            spaces += 1;
+        }
+        let change = if n > last_spaces {
+            n - last_spaces
+        } else {
+            last_spaces - n
+        };
+        if let Some(i) = SPACE_WIDTHS.iter().position(|w| *w == change) {
+            widths[i] += 1;
+        }
+        last_spaces = n;
+    }
+
+    if tabs + spaces < MIN_INDENTED_LINES {
+        return None;
+    }
+    if tabs >= spaces * 2 {
+        return Some(IndentStyle::Tabs);
+    }
+    if spaces < tabs * 2 {
+        return None;
+    }
+    // ties go to the narrower width, a file indented by 4 moving by 8 too
+    let (i, count) = widths
+        .iter()
+        .enumerate()
+        .max_by(|(i, a), (j, b)| a.cmp(b).then(j.cmp(i)))?;
+    (*count > 0).then(|| IndentStyle::Spaces(SPACE_WIDTHS[i]))
+}
+
+/// The indentation of `language_id` in `settings`, the `[indentation]`
+/// of the user, or the built-in one. A `default` in the settings is for
+/// any language.
+fn language_indent(settings: &toml::Value, language_id: &str) -> IndentStyle {
+    let setting = |key: &str| settings.get(key).and_then(IndentStyle::from_setting);
+    setting(language_id)
+        .or_else(|| {
+            LANGUAGE_INDENTS
+                .iter()
+                .find(|(id, _)| *id == language_id)
+                .map(|(_, indent)| *indent)
+        })
+        .or_else(|| setting("default"))
+        .unwrap_or_default()
+}
+
+fn user_indentation() -> toml::Value {
+    setting(&["indentation"])
+        .unwrap_or_else(|| toml::Value::Table(Default::default()))
+}
+
+/// What the `.editorconfig` files say about the indentation of a file.
+#[derive(Debug, Default, PartialEq)]
+struct EditorConfigIndent {
+    tabs: Option<bool>,
+    size: Option<u32>,
+}
+
+impl EditorConfigIndent {
+    /// Overrides what the `.editorconfig` files have a say on.
+    fn apply(&self, indent: IndentStyle) -> IndentStyle {
+        match (self.tabs, self.size) {
+            (Some(true), _) => IndentStyle::Tabs,
+            (Some(false), Some(size)) => IndentStyle::Spaces(size),
+            (Some(false), None) => IndentStyle::Spaces(indent.width()),
+            (None, Some(size)) if indent.insert_spaces() => {
+                IndentStyle::Spaces(size)
+            }
+            (None, _) => indent,
+        }
+    }
+
+    /// Reads the sections of an `.editorconfig` in `dir` that match
+    /// `path`, later ones winning.
+    fn read(&mut self, dir: &Path, contents: &str, path: &Path) {
+        let relative = match path.strip_prefix(dir) {
+            Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+            Err(_) => return,
+        };
+        let mut matches = false;
+        // `indent_size = tab` is the `tab_width`, which may come later
+        let mut size_is_tab = false;
+        let mut tab_width = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(section) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                matches = section_matches(section, &relative);
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => {
+                    (key.trim().to_lowercase(), value.trim().to_lowercase())
+                }
+                None => continue,
+            };
+            if !matches {
+                continue;
+            }
+            match key.as_str() {
+                "indent_style" if value == "tab" => self.tabs = Some(true),
+                "indent_style" if value == "space" => self.tabs = Some(false),
+                "indent_size" if value == "tab" => size_is_tab = true,
+                "indent_size" => {
+                    if let Ok(size) = value.parse::<u32>() {
+                        self.size = Some(size);
+                        size_is_tab = false;
+                    }
+                }
+                "tab_width" => tab_width = value.parse::<u32>().ok(),
+                _ => (),
+            }
+        }
+        if size_is_tab {
+            self.size = tab_width.or(self.size);
+        }
+    }
+}
+
+/// Whether an `.editorconfig` says the ones further up don't count.
+fn is_root(contents: &str) -> bool {
+    contents
+        .lines()
+        .take_while(|line| !line.trim().starts_with('['))
+        .filter_map(|line| line.split_once('='))
+        .any(|(key, value)| {
+            key.trim() == "root" && value.trim().eq_ignore_ascii_case("true")
+        })
+}
+
+/// Reads the `.editorconfig` files from the directory of `path` up to the
+/// root one, the nearest winning.
+fn editorconfig_indent(path: &Path) -> EditorConfigIndent {
+    let mut files = Vec::new();
+    for dir in path.ancestors().skip(1) {
+        if let Ok(contents) = fs::read_to_string(dir.join(".editorconfig")) {
+            let root = is_root(&contents);
+            files.push((dir, contents));
+            if root {
+                break;
+            }
+        }
+    }
+    let mut indent = EditorConfigIndent::default();
+    for (dir, contents) in files.iter().rev() {
+        indent.read(dir, contents, path);
+    }
+    indent
+}
+
+/// A section without a `/` is for the files of that name anywhere below
+/// the `.editorconfig`.
+fn section_matches(section: &str, relative: &str) -> bool {
+    let pattern = if section.contains('/') {
+        section.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{}", section)
+    };
+    Regex::new(&glob_regex(&pattern))
+        .map(|re| re.is_match(relative))
+        .unwrap_or(false)
+}
+
+/// The regex for an editorconfig glob, with `*`, `**`, `?`, `[...]` and
+/// `{a,b}`.
+fn glob_regex(pattern: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    let mut braces = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '^' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            '{' => {
+                braces += 1;
+                re.push_str("(?:");
+            }
+            '}' if braces > 0 => {
+                braces -= 1;
+                re.push(')');
+            }
+            ',' if braces > 0 => re.push('|'),
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    re.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let yaml = "a:\n  b:\n    c: 1\n    d: 2\n  e:\n    f: 3\n";
+        assert_eq!(Some(IndentStyle::Spaces(2)), detect(&Rope::from(yaml)));
+        let rust = "fn a() {\n    if b {\n        c();\n    }\n    d();\n}\n";
+        assert_eq!(Some(IndentStyle::Spaces(4)), detect(&Rope::from(rust)));
+        let go = "func a() {\n\tif b {\n\t\tc()\n\t}\n\td()\n}\n";
+        assert_eq!(Some(IndentStyle::Tabs), detect(&Rope::from(go)));
+        let c = "/*\n * a\n * b\n */\nint a() {\n    b();\n    c();\n}\n";
+        assert_eq!(None, detect(&Rope::from(c)));
+        assert_eq!(None, detect(&Rope::from("a\n  b\n")));
+    }
+
+    #[test]
+    fn test_language_indent() {
+        let settings: toml::Value =
+            toml::from_str("yaml = 4\ndefault = \"tabs\"").unwrap();
+        assert_eq!(IndentStyle::Spaces(4), language_indent(&settings, "yaml"));
+        assert_eq!(IndentStyle::Tabs, language_indent(&settings, "rust"));
+        assert_eq!(IndentStyle::Spaces(2), language_indent(&settings, "ruby"));
+    }
+
+    #[test]
+    fn test_editorconfig() {
+        let contents = "root = true\n\n[*]\nindent_style = space\n\
+            indent_size = 4\n\n[*.{yml,yaml}]\nindent_size = 2\n\n\
+            [Makefile]\nindent_style = tab\n\n[src/**.go]\n\
+            indent_style = tab\n";
+        let read = |path: &str| {
+            let mut indent = EditorConfigIndent::default();
+            indent.read(Path::new("/p"), contents, Path::new(path));
+            indent.apply(IndentStyle::Spaces(8))
+        };
+        assert_eq!(IndentStyle::Spaces(4), read("/p/a.rs"));
+        assert_eq!(IndentStyle::Spaces(2), read("/p/ci/build.yml"));
+        assert_eq!(IndentStyle::Tabs, read("/p/sub/Makefile"));
+        assert_eq!(IndentStyle::Tabs, read("/p/src/a/b.go"));
+        assert_eq!(IndentStyle::Spaces(4), read("/p/b.go"));
+        assert!(is_root(contents));
+    }
+}
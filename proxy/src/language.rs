@@ -0,0 +1,342 @@
+//! Works out the language id of a file, for picking its language servers
+//! and plugins. The user's `[file-associations]` come first, then the file
+//! name, its extension, the filename patterns, a shebang on the first line
+//! and an Emacs or Vim modeline.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use xi_rope::{LinesMetric, Rope};
+
+use crate::settings::setting;
+
+/// How many lines at the start and the end are looked at for a modeline.
+const MODELINE_LINES: usize = 5;
+
+const FILENAMES: &[(&str, &str)] = &[
+    ("Makefile", "makefile"),
+    ("makefile", "makefile"),
+    ("GNUmakefile", "makefile"),
+    ("Dockerfile", "dockerfile"),
+    ("Containerfile", "dockerfile"),
+    (".bashrc", "shellscript"),
+    (".bash_profile", "shellscript"),
+    (".bash_aliases", "shellscript"),
+    (".bash_logout", "shellscript"),
+    (".profile", "shellscript"),
+    (".zshrc", "shellscript"),
+    (".zshenv", "shellscript"),
+    (".zprofile", "shellscript"),
+    ("PKGBUILD", "shellscript"),
+    ("Gemfile", "ruby"),
+    ("Rakefile", "ruby"),
+    ("CMakeLists.txt", "cmake"),
+    ("Cargo.lock", "toml"),
+];
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("go", "go"),
+    ("py", "python"),
+    ("pyi", "python"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("cjs", "javascript"),
+    ("jsx", "javascriptreact"),
+    ("ts", "typescript"),
+    ("tsx", "typescriptreact"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("cxx", "cpp"),
+    ("hh", "cpp"),
+    ("hpp", "cpp"),
+    ("java", "java"),
+    ("kt", "kotlin"),
+    ("cs", "csharp"),
+    ("rb", "ruby"),
+    ("php", "php"),
+    ("lua", "lua"),
+    ("swift", "swift"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("hs", "haskell"),
+    ("zig", "zig"),
+    ("nix", "nix"),
+    ("sh", "shellscript"),
+    ("bash", "shellscript"),
+    ("zsh", "shellscript"),
+    ("mk", "makefile"),
+    ("toml", "toml"),
+    ("json", "json"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("md", "markdown"),
+    ("html", "html"),
+    ("htm", "html"),
+    ("css", "css"),
+    ("cmake", "cmake"),
+];
+
+const FILENAME_PATTERNS: &[(&str, &str)] = &[
+    ("Dockerfile.*", "dockerfile"),
+    ("*.dockerfile", "dockerfile"),
+    ("Makefile.*", "makefile"),
+    ("*.bashrc", "shellscript"),
+    (".env.*", "shellscript"),
+    ("requirements*.txt", "pip-requirements"),
+];
+
+/// The language of a file in the workspace, `None` if it's plain text.
+pub fn language_id(path: &Path, content: &Rope) -> Option<String> {
+    detect_language(path, content, &user_file_associations())
+}
+
+pub fn detect_language(
+    path: &Path,
+    content: &Rope,
+    associations: &HashMap<String, String>,
+) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    if let Some(language_id) = associated_language(path, name, associations) {
+        return Some(language_id.to_string());
+    }
+    language_id_from_name(name)
+        .map(|language_id| language_id.to_string())
+        .or_else(|| shebang_language(content))
+        .or_else(|| modeline_language(content))
+}
+
+/// The language told by the file name alone.
+pub fn language_id_from_name(name: &str) -> Option<&'static str> {
+    let lookup = |table: &[(&str, &'static str)], key: &str| {
+        table
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, language_id)| *language_id)
+    };
+    lookup(FILENAMES, name)
+        .or_else(|| {
+            let (_, extension) = name.rsplit_once('.')?;
+            lookup(EXTENSIONS, extension)
+        })
+        .or_else(|| {
+            FILENAME_PATTERNS
+                .iter()
+                .find(|(pattern, _)| glob_match(pattern, name))
+                .map(|(_, language_id)| *language_id)
+        })
+}
+
+/// A user association matches by file name, or by its whole path when it
+/// has a `/`. Exact names win over patterns.
+fn associated_language<'a>(
+    path: &Path,
+    name: &str,
+    associations: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    if let Some(language_id) = associations.get(name) {
+        return Some(language_id);
+    }
+    let path = path.to_str()?;
+    let mut patterns: Vec<_> = associations
+        .iter()
+        .filter(|(pattern, _)| {
+            if pattern.contains('/') {
+                glob_match(pattern, path)
+                    || !pattern.starts_with('/')
+                        && glob_match(&format!("*/{}", pattern), path)
+            } else {
+                glob_match(pattern, name)
+            }
+        })
+        .collect();
+    // the longest, most specific pattern wins
+    patterns.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+    patterns
+        .first()
+        .map(|(_, language_id)| language_id.as_str())
+}
+
+/// `*` matches any run of characters, `?` a single one.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // where the last `*` was, and where in `s` it started matching
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = star {
+            p = star_p + 1;
+            i = star_i + 1;
+            star = Some((star_p, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The interpreter of a `#!` line, looking past `env` and its flags.
+fn shebang_language(content: &Rope) -> Option<String> {
+    let first_line = content.lines(..).next()?;
+    let command = first_line.strip_prefix("#!")?;
+    let mut words = command.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    // python3.10 is python
+    let interpreter =
+        interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    (!interpreter.is_empty()).then(|| language_alias(interpreter))
+}
+
+/// `-*- mode: python -*-` or `-*- python -*-` in the first two lines, or
+/// `vim: set ft=python:` in the first or last lines.
+fn modeline_language(content: &Rope) -> Option<String> {
+    let head: Vec<_> = content.lines(..).take(MODELINE_LINES).collect();
+    for line in head.iter().take(2) {
+        if let Some(mode) = emacs_mode(line) {
+            return Some(language_alias(&mode));
+        }
+    }
+    let last_line = content.measure::<LinesMetric>();
+    let tail_start =
+        content.offset_of_line(last_line.saturating_sub(MODELINE_LINES));
+    let tail = content.lines(tail_start..).collect::<Vec<_>>();
+    head.iter()
+        .chain(tail.iter())
+        .find_map(|line| vim_filetype(line))
+        .map(|filetype| language_alias(&filetype))
+}
+
+fn emacs_mode(line: &str) -> Option<String> {
+    let start = line.find("-*-")? + 3;
+    let end = start + line[start..].find("-*-")?;
+    let vars = line[start..end].trim();
+    if !vars.contains(':') {
+        return Some(vars.to_lowercase()).filter(|mode| !mode.is_empty());
+    }
+    vars.split(';').find_map(|var| {
+        let (key, value) = var.split_once(':')?;
+        (key.trim() == "mode").then(|| value.trim().to_lowercase())
+    })
+}
+
+fn vim_filetype(line: &str) -> Option<String> {
+    let start = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        line.find(marker)
+            .filter(|i| *i == 0 || line[..*i].ends_with(char::is_whitespace))
+            .map(|i| i + marker.len())
+    })?;
+    line[start..]
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            matches!(key, "ft" | "filetype" | "syntax")
+                .then(|| value.to_string())
+                .filter(|value| !value.is_empty())
+        })
+}
+
+/// The language id for the names editors and interpreters go by.
+fn language_alias(name: &str) -> String {
+    match name {
+        "sh" | "bash" | "zsh" | "dash" | "ksh" | "shell-script" => "shellscript",
+        "make" => "makefile",
+        "c++" => "cpp",
+        "js" | "node" | "nodejs" => "javascript",
+        "ts" | "deno" => "typescript",
+        "py" => "python",
+        "rb" => "ruby",
+        "yml" => "yaml",
+        name => name,
+    }
+    .to_string()
+}
+
+/// The `[file-associations]` of the settings, from a file name or
+/// pattern to a language id.
+fn user_file_associations() -> HashMap<String, String> {
+    setting(&["file-associations"])
+        .and_then(|associations| associations.try_into().ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        let associations: HashMap<String, String> = [
+            ("*.h", "cpp"),
+            ("BUILD", "python"),
+            ("ci/*.yaml", "github-actions"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let cases = [
+            ("src/main.rs", "", Some("rust")),
+            ("Makefile", "all:\n", Some("makefile")),
+            ("Dockerfile.dev", "FROM rust\n", Some("dockerfile")),
+            ("/home/user/.bashrc", "", Some("shellscript")),
+            ("scripts/run", "#!/usr/bin/env python3\n", Some("python")),
+            ("scripts/build", "#!/bin/bash -e\n", Some("shellscript")),
+            (
+                "scripts/x",
+                "#!/usr/bin/env -S node --harmony\n",
+                Some("javascript"),
+            ),
+            (
+                "notes",
+                "# -*- mode: ruby; coding: utf-8 -*-\n",
+                Some("ruby"),
+            ),
+            ("conf", "-*- c++ -*-\n", Some("cpp")),
+            (
+                "script",
+                "echo\n\n# vim: set ft=sh ts=2:\n",
+                Some("shellscript"),
+            ),
+            ("README", "just text\n", None),
+            // the user's associations win
+            ("include/lib.h", "", Some("cpp")),
+            ("pkg/BUILD", "", Some("python")),
+            ("/repo/ci/deploy.yaml", "", Some("github-actions")),
+            ("deploy.yaml", "", Some("yaml")),
+        ];
+        for (path, content, language_id) in cases {
+            assert_eq!(
+                language_id.map(|id| id.to_string()),
+                detect_language(
+                    Path::new(path),
+                    &Rope::from(content),
+                    &associations
+                ),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("Dockerfile.*", "Dockerfile.prod"));
+        assert!(glob_match("requirements*.txt", "requirements-dev.txt"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("*a*b", "xxaxxab"));
+        assert!(!glob_match("*.rs", "main.rsx"));
+        assert!(!glob_match("Makefile.*", "Makefile"));
+    }
+}
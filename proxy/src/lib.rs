@@ -1,14 +1,61 @@
+pub mod auto_save;
+pub mod backup;
+pub mod blame;
+pub mod brackets;
 pub mod buffer;
+pub mod comment;
+pub mod conflicts;
 pub mod core_proxy;
 pub mod dispatch;
+pub mod encoding;
+pub mod file_index;
+pub mod file_ops;
+pub mod git;
+pub mod indent;
+pub mod language;
+pub mod line_ending;
 pub mod lsp;
 pub mod plugin;
+pub mod position;
+pub mod registry;
+pub mod replace;
+pub mod save;
+pub mod search;
+#[cfg(unix)]
+pub mod session;
+pub mod settings;
+pub mod shell_env;
+pub mod snippet;
+pub mod symbols;
+pub mod task;
 pub mod terminal;
+#[cfg(test)]
+mod test_dir;
+pub mod watcher;
 
 use dispatch::Dispatcher;
 
+/// Checked by the core against its own at the handshake, for the proxy of a
+/// remote workspace to be the one it was built with.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub fn mainloop() {
+    if std::env::args().any(|arg| arg == "--version") {
+        println!("{}", VERSION);
+        return;
+    }
+    // run over ssh, for the core to reconnect to after a dropped connection
+    #[cfg(unix)]
+    let remote = std::env::args().any(|arg| arg == "--remote");
     let (sender, receiver, io_threads) = lapce_rpc::stdio();
-    let dispatcher = Dispatcher::new(sender);
+    #[cfg(unix)]
+    let receiver = match session::hand_over(&sender, receiver, &io_threads) {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    shell_env::resolve_in_background();
+    let dispatcher = Dispatcher::new(sender, io_threads.transport());
     dispatcher.mainloop(receiver);
+    #[cfg(unix)]
+    session::keep_terminals(&dispatcher, remote);
 }
@@ -0,0 +1,185 @@
+//! The line endings of a file. Buffers only have `\n` in them, the `\r\n`
+//! of a file being kept aside and put back on save.
+
+use std::fmt;
+use std::iter;
+
+use serde::{Deserialize, Serialize};
+use xi_rope::{DeltaElement, LinesMetric, Rope, RopeDelta};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        LineEnding::Lf
+    }
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineEnding::Lf => write!(f, "LF"),
+            LineEnding::Crlf => write!(f, "CRLF"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LineEndings {
+    /// What most lines end with, and what new lines get.
+    pub dominant: LineEnding,
+    /// The ending of every line, only kept for a file that mixes them.
+    mixed: Option<Vec<LineEnding>>,
+}
+
+impl LineEndings {
+    /// Every line ending with `ending`.
+    pub fn uniform(ending: LineEnding) -> LineEndings {
+        LineEndings {
+            dominant: ending,
+            mixed: None,
+        }
+    }
+
+    /// Takes the endings out of `text`, giving it back with `\n` only. A
+    /// lone `\r` isn't a line ending and stays.
+    pub fn normalize(text: &str) -> (String, LineEndings) {
+        let mut normalized = String::with_capacity(text.len());
+        let mut endings = Vec::new();
+        for line in text.split_inclusive('\n') {
+            if let Some(line) = line.strip_suffix("\r\n") {
+                normalized.push_str(line);
+                normalized.push('\n');
+                endings.push(LineEnding::Crlf);
+            } else {
+                normalized.push_str(line);
+                if line.ends_with('\n') {
+                    endings.push(LineEnding::Lf);
+                }
+            }
+        }
+        let crlf = endings.iter().filter(|e| **e == LineEnding::Crlf).count();
+        let lf = endings.len() - crlf;
+        let dominant = if crlf > lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        };
+        let mixed = (crlf > 0 && lf > 0).then(|| endings);
+        (normalized, LineEndings { dominant, mixed })
+    }
+
+    pub fn is_mixed(&self) -> bool {
+        self.mixed.is_some()
+    }
+
+    /// Puts the endings back into `text`, which has `\n` only.
+    pub fn restore(&self, text: &str) -> String {
+        match &self.mixed {
+            Some(endings) => {
+                let mut restored = String::with_capacity(text.len());
+                for (i, line) in text.split_inclusive('\n').enumerate() {
+                    match line.strip_suffix('\n') {
+                        Some(line) => {
+                            restored.push_str(line);
+                            let ending = endings.get(i).unwrap_or(&self.dominant);
+                            restored.push_str(ending.as_str());
+                        }
+                        None => restored.push_str(line),
+                    }
+                }
+                restored
+            }
+            None if self.dominant == LineEnding::Lf => text.to_string(),
+            None => text.replace('\n', self.dominant.as_str()),
+        }
+    }
+
+    /// Follows an edit of `rope`: the lines copied keep their endings, and
+    /// the lines inserted get the dominant one.
+    pub fn update(&mut self, delta: &RopeDelta, rope: &Rope) {
+        let endings = match self.mixed.as_ref() {
+            Some(endings) => endings,
+            None => return,
+        };
+        let mut new_endings = Vec::with_capacity(endings.len());
+        for element in delta.els.iter() {
+            match element {
+                DeltaElement::Copy(start, end) => {
+                    let first = rope.line_of_offset(*start);
+                    let last = rope.line_of_offset(*end);
+                    new_endings.extend_from_slice(&endings[first..last]);
+                }
+                DeltaElement::Insert(node) => {
+                    let lines = node.measure::<LinesMetric>();
+                    new_endings.extend(iter::repeat(self.dominant).take(lines));
+                }
+            }
+        }
+        self.mixed = Some(new_endings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::{DeltaBuilder, Interval};
+
+    #[test]
+    fn test_normalize_and_restore() {
+        let cases = [
+            ("", "", LineEnding::Lf, false),
+            ("no newline", "no newline", LineEnding::Lf, false),
+            ("a\r\nb", "a\nb", LineEnding::Crlf, false),
+            ("a\r\nb\r\n", "a\nb\n", LineEnding::Crlf, false),
+            ("a\nb\n", "a\nb\n", LineEnding::Lf, false),
+            ("a\r\nb\nc\r\nd", "a\nb\nc\nd", LineEnding::Crlf, true),
+            ("lone\rcr\n", "lone\rcr\n", LineEnding::Lf, false),
+        ];
+        for (text, normalized, dominant, mixed) in cases {
+            let (result, endings) = LineEndings::normalize(text);
+            assert_eq!(normalized, result, "{:?}", text);
+            assert_eq!(dominant, endings.dominant, "{:?}", text);
+            assert_eq!(mixed, endings.is_mixed(), "{:?}", text);
+            assert_eq!(text, endings.restore(&result), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_mixed_endings_edit() {
+        let (text, mut endings) = LineEndings::normalize("a\r\nb\nc\r\nd");
+        let rope = Rope::from(text);
+        // replace "b" with two new lines
+        let mut builder = DeltaBuilder::new(rope.len());
+        builder.replace(Interval::new(2, 3), Rope::from("x\ny\nz"));
+        let delta = builder.build();
+        endings.update(&delta, &rope);
+        let rope = delta.apply(&rope);
+        assert_eq!(
+            "a\r\nx\r\ny\r\nz\nc\r\nd",
+            endings.restore(&rope.to_string())
+        );
+
+        // join the first two lines
+        let mut builder = DeltaBuilder::new(rope.len());
+        builder.delete(Interval::new(1, 2));
+        let delta = builder.build();
+        endings.update(&delta, &rope);
+        let rope = delta.apply(&rope);
+        assert_eq!("ax\r\ny\r\nz\nc\r\nd", endings.restore(&rope.to_string()));
+    }
+}
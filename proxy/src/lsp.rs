@@ -1,28 +1,55 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
     io::BufRead,
-    io::{BufReader, BufWriter, Write},
-    process::{self, Child, Command, Stdio},
-    sync::{mpsc::channel, Arc},
+    io::{BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    process::{self, Child, ChildStderr, Command, Stdio},
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
 use jsonrpc_lite::{Id, JsonRpc, Params};
 use lapce_rpc::RequestId;
+use lsp_types::request::{
+    GotoDeclarationParams, GotoImplementationParams, GotoTypeDefinitionParams,
+};
 use lsp_types::*;
 use parking_lot::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, to_value, Value};
+use xi_rope::{LinesMetric, Rope};
 
 use crate::buffer::Buffer;
 use crate::buffer::BufferId;
 use crate::dispatch::Dispatcher;
 use crate::dispatch::Request;
+use crate::plugin::{merge_configuration, PluginName};
+use crate::position::{
+    lsp_position_to_offset, offset_to_lsp_position, utf16_to_byte,
+};
+use crate::settings::setting;
+use crate::shell_env::resolved_env;
+use crate::symbols::SymbolQuery;
+use crate::terminal::Counter;
 
 pub type Callback = Box<dyn Callable>;
+type ResponseCallback = Box<dyn FnOnce(&LspClient, Result<Value>) + Send>;
 const HEADER_CONTENT_LENGTH: &str = "content-length";
 const HEADER_CONTENT_TYPE: &str = "content-type";
+/// How long connecting to a `tcp://` server may take, unless its URI has a
+/// `timeout` in milliseconds.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many more times connecting is tried, unless the URI has `retries`.
+const CONNECT_RETRIES: usize = 2;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(200);
 
 pub trait Callable: Send {
     fn call(self: Box<Self>, client: &LspClient, result: Result<Value>);
@@ -36,22 +63,795 @@ impl<F: Send + FnOnce(&LspClient, Result<Value>)> Callable for F {
 
 pub struct LspCatalog {
     pub dispatcher: Option<Dispatcher>,
-    clients: HashMap<String, Arc<LspClient>>,
+    clients: HashMap<String, Vec<Arc<LspClient>>>,
+    registrations: HashMap<String, LspRegistration>,
+    respawns: HashMap<LspServerId, LspRespawn>,
+    diagnostics: Arc<Mutex<DiagnosticsCache>>,
+    capabilities: Arc<Mutex<CapabilitiesCache>>,
+    /// The `workspace/symbol` requests still in flight, cancelled when the
+    /// next query comes in.
+    workspace_symbols: Vec<(Arc<LspClient>, u64)>,
+    /// The buffer signature help is showing for, and the last request made
+    /// for it.
+    signature: Option<(BufferId, Arc<LspClient>, u64)>,
+    call_hierarchy: Arc<Mutex<CallHierarchyItems>>,
+    /// Bumped on every cursor move, so only the last move of a burst asks
+    /// for document highlights.
+    highlight_generation: u64,
+    /// The document highlight request in flight.
+    highlight_request: Option<(Arc<LspClient>, u64)>,
+    /// Where the highlights last sent were asked for, to not ask again.
+    last_highlight: Arc<Mutex<Option<(BufferId, u64, Position)>>>,
+    /// The selection range chains last answered for each cursor, by buffer,
+    /// with the rev they were for. Dropped on any edit.
+    selection_ranges: Arc<Mutex<SelectionRangesCache>>,
+}
+
+type SelectionRangesCache = HashMap<BufferId, (u64, Vec<(Position, Vec<Range>)>)>;
+
+/// The call hierarchy items handed out to the core, kept until the buffer
+/// the hierarchy was prepared in is edited, since their ranges go stale.
+/// Items found by expanding a node belong to the buffer of its root.
+#[derive(Default)]
+struct CallHierarchyItems {
+    next_handle: u64,
+    items: HashMap<u64, (BufferId, Arc<LspClient>, CallHierarchyItem)>,
+}
+
+impl CallHierarchyItems {
+    fn insert(
+        &mut self,
+        buffer_id: BufferId,
+        client: &Arc<LspClient>,
+        item: CallHierarchyItem,
+    ) -> CallHierarchyNode {
+        self.next_handle += 1;
+        let node = CallHierarchyNode {
+            handle: self.next_handle,
+            name: item.name.clone(),
+            kind: item.kind,
+            detail: item.detail.clone(),
+            path: item.uri.to_file_path().unwrap_or_default(),
+            range: item.range,
+            selection_range: item.selection_range,
+        };
+        self.items
+            .insert(self.next_handle, (buffer_id, client.clone(), item));
+        node
+    }
+}
+
+/// A call hierarchy item as the core sees it. `handle` stands for the
+/// item in `call_hierarchy_incoming` and `call_hierarchy_outgoing`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CallHierarchyNode {
+    pub handle: u64,
+    pub name: String,
+    pub kind: SymbolKind,
+    pub detail: Option<String>,
+    pub path: PathBuf,
+    pub range: Range,
+    /// The name of the function, to be highlighted.
+    pub selection_range: Range,
+}
+
+/// A caller or callee of a node, with the ranges of the calls. For
+/// incoming calls they're in `item`'s file, for outgoing ones in the file
+/// of the node that was expanded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CallHierarchyCall {
+    pub item: CallHierarchyNode,
+    pub call_sites: Vec<Range>,
+}
+
+/// What published a set of diagnostics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum DiagnosticSource {
+    Server(LspServerId),
+    /// The problem matcher of a task, by name.
+    Task(String),
+}
+
+/// The last diagnostics each server or task published for a document, so
+/// what we send on is the union over all of them.
+type DiagnosticsCache = HashMap<Url, HashMap<DiagnosticSource, Vec<Diagnostic>>>;
+
+/// What each initialized server supports, by language, so what we send on
+/// is the union over all the servers of the language.
+type CapabilitiesCache = HashMap<String, HashMap<LspServerId, LspCapabilities>>;
+
+/// The features a language's servers support, sent with
+/// `server_capabilities` whenever it changes, so the core only offers what
+/// can be done.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LspCapabilities {
+    pub completion: bool,
+    pub hover: bool,
+    pub signature_help: bool,
+    pub definition: bool,
+    pub type_definition: bool,
+    pub declaration: bool,
+    pub implementation: bool,
+    pub references: bool,
+    pub document_highlight: bool,
+    pub document_symbol: bool,
+    pub workspace_symbol: bool,
+    pub code_action: bool,
+    pub rename: bool,
+    pub prepare_rename: bool,
+    pub formatting: bool,
+    pub range_formatting: bool,
+    pub on_type_formatting: bool,
+    pub semantic_tokens: bool,
+    pub inlay_hints: bool,
+    pub folding_range: bool,
+    pub selection_range: bool,
+    pub call_hierarchy: bool,
+    pub document_link: bool,
+}
+
+impl LspCapabilities {
+    /// Reads what `initialize` answered, plus the `registered` methods of
+    /// the dynamic registrations made since.
+    fn new(
+        capabilities: &ServerCapabilities,
+        inlay_hints: bool,
+        registered: &HashSet<String>,
+    ) -> Self {
+        let dynamic = |method: &str| registered.contains(method);
+        Self {
+            completion: capabilities.completion_provider.is_some()
+                || dynamic("textDocument/completion"),
+            hover: !matches!(
+                capabilities.hover_provider,
+                None | Some(HoverProviderCapability::Simple(false))
+            ) || dynamic("textDocument/hover"),
+            signature_help: capabilities.signature_help_provider.is_some()
+                || dynamic("textDocument/signatureHelp"),
+            definition: is_enabled(&capabilities.definition_provider)
+                || dynamic("textDocument/definition"),
+            type_definition: !matches!(
+                capabilities.type_definition_provider,
+                None | Some(TypeDefinitionProviderCapability::Simple(false))
+            ) || dynamic("textDocument/typeDefinition"),
+            declaration: !matches!(
+                capabilities.declaration_provider,
+                None | Some(DeclarationCapability::Simple(false))
+            ) || dynamic("textDocument/declaration"),
+            implementation: !matches!(
+                capabilities.implementation_provider,
+                None | Some(ImplementationProviderCapability::Simple(false))
+            ) || dynamic("textDocument/implementation"),
+            references: is_enabled(&capabilities.references_provider)
+                || dynamic("textDocument/references"),
+            document_highlight: is_enabled(
+                &capabilities.document_highlight_provider,
+            ) || dynamic("textDocument/documentHighlight"),
+            document_symbol: is_enabled(&capabilities.document_symbol_provider)
+                || dynamic("textDocument/documentSymbol"),
+            workspace_symbol: is_enabled(&capabilities.workspace_symbol_provider)
+                || dynamic("workspace/symbol"),
+            code_action: !matches!(
+                capabilities.code_action_provider,
+                None | Some(CodeActionProviderCapability::Simple(false))
+            ) || dynamic("textDocument/codeAction"),
+            rename: is_enabled(&capabilities.rename_provider)
+                || dynamic("textDocument/rename"),
+            prepare_rename: can_prepare_rename(capabilities),
+            formatting: is_enabled(&capabilities.document_formatting_provider)
+                || dynamic("textDocument/formatting"),
+            range_formatting: is_enabled(
+                &capabilities.document_range_formatting_provider,
+            ) || dynamic("textDocument/rangeFormatting"),
+            on_type_formatting: capabilities
+                .document_on_type_formatting_provider
+                .is_some()
+                || dynamic("textDocument/onTypeFormatting"),
+            semantic_tokens: capabilities.semantic_tokens_provider.is_some()
+                || dynamic("textDocument/semanticTokens"),
+            inlay_hints: inlay_hints || dynamic("textDocument/inlayHint"),
+            folding_range: can_fold(capabilities)
+                || dynamic("textDocument/foldingRange"),
+            selection_range: can_select_range(capabilities)
+                || dynamic("textDocument/selectionRange"),
+            call_hierarchy: !matches!(
+                capabilities.call_hierarchy_provider,
+                None | Some(CallHierarchyServerCapability::Simple(false))
+            ) || dynamic("textDocument/prepareCallHierarchy"),
+            document_link: capabilities.document_link_provider.is_some()
+                || dynamic("textDocument/documentLink"),
+        }
+    }
+
+    /// What at least one of `capabilities` supports.
+    fn union<'a>(capabilities: impl Iterator<Item = &'a LspCapabilities>) -> Self {
+        capabilities.fold(Self::default(), |a, b| Self {
+            completion: a.completion || b.completion,
+            hover: a.hover || b.hover,
+            signature_help: a.signature_help || b.signature_help,
+            definition: a.definition || b.definition,
+            type_definition: a.type_definition || b.type_definition,
+            declaration: a.declaration || b.declaration,
+            implementation: a.implementation || b.implementation,
+            references: a.references || b.references,
+            document_highlight: a.document_highlight || b.document_highlight,
+            document_symbol: a.document_symbol || b.document_symbol,
+            workspace_symbol: a.workspace_symbol || b.workspace_symbol,
+            code_action: a.code_action || b.code_action,
+            rename: a.rename || b.rename,
+            prepare_rename: a.prepare_rename || b.prepare_rename,
+            formatting: a.formatting || b.formatting,
+            range_formatting: a.range_formatting || b.range_formatting,
+            on_type_formatting: a.on_type_formatting || b.on_type_formatting,
+            semantic_tokens: a.semantic_tokens || b.semantic_tokens,
+            inlay_hints: a.inlay_hints || b.inlay_hints,
+            folding_range: a.folding_range || b.folding_range,
+            selection_range: a.selection_range || b.selection_range,
+            call_hierarchy: a.call_hierarchy || b.call_hierarchy,
+            document_link: a.document_link || b.document_link,
+        })
+    }
+}
+
+/// Error code of the responses to requests no server of the buffer's
+/// language supports, so the core can tell them apart from failures.
+pub const UNSUPPORTED_ERROR_CODE: i64 = 1;
+
+/// The error for a request none of the language's servers has the
+/// capability for. `Dispatcher::respond` gives it `UNSUPPORTED_ERROR_CODE`.
+#[derive(Debug)]
+pub struct LspUnsupported {
+    pub feature: &'static str,
+    pub language_id: String,
+}
+
+impl LspUnsupported {
+    fn new(feature: &'static str, buffer: &Buffer) -> Self {
+        Self {
+            feature,
+            language_id: buffer.language_id.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for LspUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} not supported for {}", self.feature, self.language_id)
+    }
+}
+
+impl std::error::Error for LspUnsupported {}
+
+/// Error code of the responses to requests the server didn't answer before
+/// their deadline, which the core ignores.
+pub const TIMEOUT_ERROR_CODE: i64 = 2;
+
+/// The error a request gets once it's past its deadline. `Dispatcher::respond`
+/// gives it `TIMEOUT_ERROR_CODE`.
+#[derive(Debug)]
+pub struct LspTimeout {
+    pub method: String,
+}
+
+impl std::fmt::Display for LspTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} timed out", self.method)
+    }
+}
+
+impl std::error::Error for LspTimeout {}
+
+/// How many diagnostics of each severity a file has, sent with
+/// `diagnostics_changed` so counts can be shown without the diagnostics.
+/// Diagnostics without a severity count as errors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticCounts {
+    pub errors: usize,
+    pub warnings: usize,
+    pub information: usize,
+    pub hints: usize,
+}
+
+impl DiagnosticCounts {
+    pub fn of(diagnostics: &[Diagnostic]) -> Self {
+        let mut counts = Self::default();
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Some(DiagnosticSeverity::Warning) => counts.warnings += 1,
+                Some(DiagnosticSeverity::Information) => counts.information += 1,
+                Some(DiagnosticSeverity::Hint) => counts.hints += 1,
+                _ => counts.errors += 1,
+            }
+        }
+        counts
+    }
+}
+
+/// What's needed to bring a crashed server back, kept until the server is
+/// stopped or started again from scratch.
+struct LspRespawn {
+    language_id: String,
+    exec_path: String,
+    root: Option<PathBuf>,
+    options: Option<Value>,
+    plugin: Option<PluginName>,
+    attempts: usize,
+}
+
+/// A server a plugin asked to have started the first time a matching
+/// file is opened. Only the first plugin to register a language decides
+/// how its server is started; the others are just told about it.
+struct LspRegistration {
+    exec_path: String,
+    options: Option<Value>,
+    file_patterns: Vec<String>,
+    plugins: Vec<PluginName>,
+}
+
+impl LspRegistration {
+    /// Patterns are either a file name or `*` followed by a suffix, as in
+    /// `*.rs`.
+    fn matches(&self, path: &Path) -> bool {
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => return false,
+        };
+        self.file_patterns
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some(suffix) => file_name.ends_with(suffix),
+                None => file_name == pattern,
+            })
+    }
+}
+
+/// Collects the responses to a request sent to every server of a
+/// language, kept in server order, until the last one is in.
+struct MergedResponses {
+    pending: usize,
+    results: Vec<Option<ServerResponse>>,
+    merge: Option<Box<dyn FnOnce(Vec<ServerResponse>) -> Result<Value> + Send>>,
+}
+
+struct ServerResponse {
+    server: LspServerId,
+    language_id: String,
+    result: Result<Value>,
+}
+
+impl MergedResponses {
+    /// Returns the merged result once the last response is in.
+    fn add(
+        &mut self,
+        index: usize,
+        client: &LspClient,
+        result: Result<Value>,
+    ) -> Option<Result<Value>> {
+        self.results[index] = Some(ServerResponse {
+            server: client.id,
+            language_id: client.language_id.clone(),
+            result,
+        });
+        self.pending -= 1;
+        if self.pending > 0 {
+            return None;
+        }
+        let merge = self.merge.take()?;
+        Some(merge(self.results.drain(..).flatten().collect()))
+    }
+}
+
+/// A symbol found by `workspace/symbol`, with the language of the server
+/// that found it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkspaceSymbolItem {
+    pub language_id: String,
+    pub symbol: SymbolInformation,
+}
+
+/// The state of one `$/progress` token of a server, sent to the core as
+/// `work_progress` whenever it changes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorkProgress {
+    pub server_id: LspServerId,
+    pub server: String,
+    pub token: String,
+    pub title: String,
+    pub message: Option<String>,
+    pub percentage: Option<u32>,
+    /// The server ended the progress, or went quiet for longer than
+    /// `PROGRESS_TIMEOUT`.
+    pub done: bool,
+}
+
+/// The occurrences of the symbol under the cursor, sent to the core as
+/// `document_highlight`. An empty list clears the highlights.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DocumentHighlightResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub highlights: Vec<DocumentHighlight>,
+}
+
+/// A link in a buffer. `target` is a URL, which for files may end with a
+/// `#L<line>,<col>` fragment; it's missing until the link is resolved
+/// with `resolve_document_link`, which needs `data` back.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DocumentLinkItem {
+    pub range: Range,
+    pub target: Option<String>,
+    pub tooltip: Option<String>,
+    pub data: Option<Value>,
+}
+
+impl From<DocumentLink> for DocumentLinkItem {
+    fn from(link: DocumentLink) -> Self {
+        Self {
+            range: link.range,
+            target: link.target.map(String::from),
+            tooltip: link.tooltip,
+            data: link.data,
+        }
+    }
+}
+
+/// The links of a range of a buffer at a revision.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DocumentLinksResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub links: Vec<DocumentLinkItem>,
+}
+
+/// A hover answer, merged over the servers. `range` is what the docs are
+/// about, from the first server that gave one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HoverResponse {
+    pub sections: Vec<HoverSection>,
+    pub range: Option<Range>,
+}
+
+/// A code block with the language to highlight it in, or a piece of
+/// markdown or plain text.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HoverSection {
+    pub language: Option<String>,
+    pub value: String,
+    pub is_markdown: bool,
+}
+
+/// A signature help answer, sent to the core as `signature_help`. No
+/// signatures means the help should be hidden.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignatureHelpResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub position: Position,
+    pub signatures: Vec<SignatureItem>,
+    pub active_signature: usize,
+    pub active_parameter: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignatureItem {
+    pub label: String,
+    pub documentation: Option<String>,
+    /// The byte range of each parameter in `label`.
+    pub parameters: Vec<(usize, usize)>,
+}
+
+/// The inlay hints of a range of lines, answering `get_inlay_hints`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InlayHintsResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub hints: Vec<InlayHintItem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InlayHintItem {
+    pub position: Position,
+    /// The label parts, shown one after the other.
+    pub label: Vec<String>,
+    pub kind: InlayHintKind,
+    pub padding_left: bool,
+    pub padding_right: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InlayHintKind {
+    Type,
+    Parameter,
+    Other,
+}
+
+/// The foldable line ranges of a buffer, answering `get_folding_ranges`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FoldingRangesResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub ranges: Vec<FoldingRangeItem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FoldingRangeItem {
+    pub start_line: u32,
+    /// The last line folded away, the start line staying visible.
+    pub end_line: u32,
+    pub kind: Option<FoldingRangeKind>,
+}
+
+/// `textDocument/inlayHint` isn't in our version of lsp-types, so its
+/// hints are read with these.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LspInlayHint {
+    position: Position,
+    label: LspInlayHintLabel,
+    kind: Option<u8>,
+    padding_left: Option<bool>,
+    padding_right: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LspInlayHintLabel {
+    String(String),
+    Parts(Vec<LspInlayHintLabelPart>),
+}
+
+#[derive(Deserialize)]
+struct LspInlayHintLabelPart {
+    value: String,
+}
+
+impl From<LspInlayHint> for InlayHintItem {
+    fn from(hint: LspInlayHint) -> Self {
+        Self {
+            position: hint.position,
+            label: match hint.label {
+                LspInlayHintLabel::String(label) => vec![label],
+                LspInlayHintLabel::Parts(parts) => {
+                    parts.into_iter().map(|part| part.value).collect()
+                }
+            },
+            kind: match hint.kind {
+                Some(1) => InlayHintKind::Type,
+                Some(2) => InlayHintKind::Parameter,
+                _ => InlayHintKind::Other,
+            },
+            padding_left: hint.padding_left.unwrap_or(false),
+            padding_right: hint.padding_right.unwrap_or(false),
+        }
+    }
+}
+
+/// The hints a server gave for one revision of a buffer, and the lines
+/// they were asked for. An edit drops the hints of the lines it touched
+/// and moves the ones below it, so only those lines are asked for again.
+#[derive(Default)]
+struct InlayHintsCache {
+    rev: u64,
+    /// Sorted, disjoint and inclusive line ranges.
+    lines: Vec<(u32, u32)>,
+    hints: Vec<InlayHintItem>,
+}
+
+impl InlayHintsCache {
+    fn new(rev: u64) -> Self {
+        Self {
+            rev,
+            ..Default::default()
+        }
+    }
+
+    fn is_covered(&self, line: u32) -> bool {
+        self.lines
+            .iter()
+            .any(|(start, end)| *start <= line && line <= *end)
+    }
+
+    /// The smallest range holding all the lines from `start` to `end` that
+    /// haven't been asked for yet.
+    fn missing(&self, start: u32, end: u32) -> Option<(u32, u32)> {
+        let first = (start..=end).find(|line| !self.is_covered(*line))?;
+        let last = (first..=end).rev().find(|line| !self.is_covered(*line))?;
+        Some((first, last))
+    }
+
+    fn hints(&self, start: u32, end: u32) -> Vec<InlayHintItem> {
+        self.hints
+            .iter()
+            .filter(|hint| start <= hint.position.line && hint.position.line <= end)
+            .cloned()
+            .collect()
+    }
+
+    fn insert(&mut self, start: u32, end: u32, hints: Vec<InlayHintItem>) {
+        self.hints
+            .retain(|hint| hint.position.line < start || end < hint.position.line);
+        self.hints.extend(hints);
+
+        self.lines.push((start, end));
+        self.lines.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(self.lines.len());
+        for (start, end) in self.lines.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.lines = merged;
+    }
+
+    /// Lines `start` to `end` were replaced by lines `start` to `new_end`.
+    fn edited(&mut self, start: u32, end: u32, new_end: u32, rev: u64) {
+        let shift = |line: u32| (line as i64 + new_end as i64 - end as i64) as u32;
+        self.hints
+            .retain(|hint| hint.position.line < start || end < hint.position.line);
+        for hint in self.hints.iter_mut() {
+            if hint.position.line > end {
+                hint.position.line = shift(hint.position.line);
+            }
+        }
+
+        let mut lines = Vec::with_capacity(self.lines.len() + 1);
+        for (a, b) in self.lines.drain(..) {
+            if a < start {
+                lines.push((a, b.min(start - 1)));
+            }
+            if b > end {
+                lines.push((shift(a.max(end + 1)), shift(b)));
+            }
+        }
+        self.lines = lines;
+        self.rev = rev;
+    }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkspaceSymbolResponse {
+    pub symbols: Vec<WorkspaceSymbolItem>,
+    /// Some server failed to answer, most likely because it's still
+    /// indexing, so asking again later can find more.
+    pub incomplete: bool,
+}
+
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct LspServerId(pub u64);
+
+impl LspServerId {
+    pub fn next() -> Self {
+        static LSP_SERVER_ID_COUNTER: Counter = Counter::new();
+        Self(LSP_SERVER_ID_COUNTER.next())
+    }
+}
+
+const STDERR_TAIL_LINES: usize = 20;
+const MAX_RESPAWN_ATTEMPTS: usize = 5;
+const RESPAWN_BACKOFF: Duration = Duration::from_millis(500);
+/// A server that stayed up this long gets a fresh set of respawn attempts.
+const RESPAWN_RESET_AFTER: Duration = Duration::from_secs(60);
+/// Progress that got no report for this long is taken as ended.
+const PROGRESS_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the buffer has to stay unchanged before its folding ranges are
+/// computed again.
+pub const FOLDING_RANGE_DELAY: Duration = Duration::from_millis(300);
+/// How long the cursor has to rest before its occurrences are highlighted.
+pub const DOCUMENT_HIGHLIGHT_DELAY: Duration = Duration::from_millis(200);
+/// How long a save waits for the edits of `willSaveWaitUntil`.
+pub const WILL_SAVE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a rename waits for the edits of `willRenameFiles`.
+pub const WILL_RENAME_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a `workspace/applyEdit` waits for the editor to apply the edit.
+const APPLY_EDIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the requests are checked for having passed their deadline.
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How many milliseconds a request waits for its answer by method, overridden
+/// by `[lsp-timeouts]` in the settings. `default` is for the methods not
+/// listed, and 0 means no deadline.
+const REQUEST_TIMEOUTS: &[(&str, u64)] = &[
+    ("default", 10000),
+    ("initialize", 0),
+    ("shutdown", 0),
+    ("textDocument/completion", 2000),
+    ("textDocument/hover", 3000),
+    ("textDocument/formatting", 5000),
+    ("textDocument/rangeFormatting", 5000),
+    ("textDocument/onTypeFormatting", 5000),
+    ("textDocument/willSaveWaitUntil", 500),
+];
+/// Requests whose answer is of no use once their document is edited.
+const STALE_AFTER_EDIT: &[&str] = &[
+    "textDocument/hover",
+    "textDocument/codeAction",
+    "textDocument/documentHighlight",
+    "textDocument/formatting",
+    "textDocument/rangeFormatting",
+    "textDocument/onTypeFormatting",
+];
+/// Requests about the cursor's position, of no use once it moves away.
+const STALE_AFTER_CURSOR_MOVE: &[&str] = &[
+    "textDocument/hover",
+    "textDocument/codeAction",
+    "textDocument/documentHighlight",
+];
+
 pub struct LspState {
     next_id: u64,
     writer: Box<dyn Write + Send>,
-    process: Child,
-    pending: HashMap<u64, Callback>,
+    transport: Box<dyn LspTransport>,
+    stopped: bool,
+    pending: HashMap<u64, PendingRequest>,
     pub server_capabilities: Option<ServerCapabilities>,
     pub opened_documents: HashMap<BufferId, Url>,
     pub is_initialized: bool,
+    /// Read from the raw `initialize` result, as `ServerCapabilities`
+    /// drops `inlayHintProvider`.
+    pub inlay_hint_provider: bool,
+}
+
+struct PendingRequest {
+    method: String,
+    callback: Callback,
+    /// When it's given up on, if ever.
+    deadline: Option<Instant>,
+    /// Set once its answer is of no use anymore, for the deadline checks to
+    /// cancel it outside of the locks its canceller holds.
+    stale: bool,
+    /// The document and position it's about, from its params.
+    uri: Option<String>,
+    position: Option<Position>,
+}
+
+impl PendingRequest {
+    fn new(method: &str, params: &Params, callback: Callback) -> Self {
+        let params = serde_json::to_value(params).unwrap_or(Value::Null);
+        let uri = params["textDocument"]["uri"].as_str().map(String::from);
+        let position = serde_json::from_value(params["position"].clone())
+            .or_else(|_| serde_json::from_value(params["range"]["start"].clone()))
+            .ok();
+        Self {
+            method: method.to_string(),
+            callback,
+            deadline: None,
+            stale: false,
+            uri,
+            position,
+        }
+    }
 }
 
 pub struct LspClient {
+    pub id: LspServerId,
     language_id: String,
+    exec_path: String,
+    /// The workspace folder the server is limited to, or `None` for a
+    /// server that gets all of them.
+    root: Option<PathBuf>,
     options: Option<Value>,
+    /// What `workspace/configuration` is answered from: `options` with the
+    /// user's `[lsp.<name>]` settings merged over them.
+    settings: Mutex<Value>,
+    /// `REQUEST_TIMEOUTS` with the user's `[lsp-timeouts]` merged over them.
+    timeouts: Mutex<HashMap<String, u64>>,
+    plugin: Option<PluginName>,
+    started: Instant,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    diagnostics: Arc<Mutex<DiagnosticsCache>>,
+    capabilities: Arc<Mutex<CapabilitiesCache>>,
+    /// The methods of the server's dynamic registrations, by registration
+    /// id, other than the file watchers.
+    registered_methods: Mutex<HashMap<String, String>>,
+    progress: Arc<Mutex<HashMap<String, (WorkProgress, Instant)>>>,
+    inlay_hints: Mutex<HashMap<BufferId, InlayHintsCache>>,
+    /// The `resultId` and tokens of the last semantic tokens answer for
+    /// each buffer, which the next `full/delta` answer is applied to.
+    semantic_tokens: Mutex<HashMap<BufferId, (String, Vec<u32>)>>,
+    /// The `workspace/didChangeWatchedFiles` watchers the server registered,
+    /// by registration id.
+    watched_files: Mutex<HashMap<String, Vec<WatchedFiles>>>,
     state: Arc<Mutex<LspState>>,
     dispatcher: Dispatcher,
 }
@@ -61,22 +861,384 @@ impl LspCatalog {
         LspCatalog {
             dispatcher: None,
             clients: HashMap::new(),
+            registrations: HashMap::new(),
+            respawns: HashMap::new(),
+            diagnostics: Arc::new(Mutex::new(HashMap::new())),
+            capabilities: Arc::new(Mutex::new(HashMap::new())),
+            workspace_symbols: Vec::new(),
+            signature: None,
+            call_hierarchy: Arc::new(Mutex::new(CallHierarchyItems::default())),
+            highlight_generation: 0,
+            highlight_request: None,
+            last_highlight: Arc::new(Mutex::new(None)),
+            selection_ranges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether a server of the language supports a feature, or `None` while
+    /// there's none or some haven't answered `initialize` yet.
+    pub fn language_supports(
+        &self,
+        language_id: &str,
+        supported: impl Fn(&LspCapabilities) -> bool,
+    ) -> Option<bool> {
+        let clients = self.clients(language_id);
+        if clients.is_empty()
+            || clients
+                .iter()
+                .any(|client| !client.state.lock().is_initialized)
+        {
+            return None;
+        }
+        let capabilities = self.capabilities.lock();
+        Some(
+            capabilities
+                .get(language_id)?
+                .values()
+                .any(|capabilities| supported(capabilities)),
+        )
+    }
+
+    fn clients(&self, language_id: &str) -> &[Arc<LspClient>] {
+        self.clients
+            .get(language_id)
+            .map(|clients| clients.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The first server for the buffer's language that advertises a
+    /// capability, falling back to the first server when none does, as
+    /// capabilities are unknown until a server answers `initialize`.
+    fn client_with(
+        &self,
+        buffer: &Buffer,
+        capability: impl Fn(&ServerCapabilities) -> bool,
+    ) -> Option<(&Arc<LspClient>, Url)> {
+        let clients: Vec<&Arc<LspClient>> = self.buffer_clients(buffer).collect();
+        let client = *clients
+            .iter()
+            .find(|client| {
+                // opening the document is what initializes the server
+                client.get_uri(buffer);
+                client.has_capability(&capability)
+            })
+            .or_else(|| clients.first())?;
+        let uri = client.get_uri(buffer);
+        Some((client, uri))
+    }
+
+    /// The servers for the buffer's language that serve its workspace
+    /// folder.
+    fn buffer_clients<'a>(
+        &'a self,
+        buffer: &Buffer,
+    ) -> impl Iterator<Item = &'a Arc<LspClient>> {
+        let root = buffer.root.clone();
+        self.clients(&buffer.language_id)
+            .iter()
+            .filter(move |client| client.serves(root.as_ref()))
+    }
+
+    /// Sends a request to each of `clients` and responds with `merge`
+    /// applied to their results. Returns the ids of the requests sent, so
+    /// they can be cancelled.
+    fn request_merged(
+        &self,
+        id: RequestId,
+        clients: &[Arc<LspClient>],
+        merge: impl FnOnce(Vec<ServerResponse>) -> Result<Value> + Send + 'static,
+        request: impl Fn(&LspClient, ResponseCallback) -> u64,
+    ) -> Vec<(Arc<LspClient>, u64)> {
+        if clients.is_empty() {
+            self.dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(anyhow!("no language server")));
+            return Vec::new();
+        }
+        let responses = Arc::new(Mutex::new(MergedResponses {
+            pending: clients.len(),
+            results: clients.iter().map(|_| None).collect(),
+            merge: Some(Box::new(merge)),
+        }));
+        clients
+            .iter()
+            .enumerate()
+            .map(|(index, client)| {
+                let responses = responses.clone();
+                let request_id = request(
+                    client,
+                    Box::new(
+                        move |lsp_client: &LspClient, result: Result<Value>| {
+                            let merged =
+                                responses.lock().add(index, lsp_client, result);
+                            if let Some(merged) = merged {
+                                lsp_client.dispatcher.respond(id, merged);
+                            }
+                        },
+                    ),
+                );
+                (client.clone(), request_id)
+            })
+            .collect()
+    }
+
+    /// Records a server to start on demand by `start_registered_server`.
+    /// Returns the id of the server already running for `language_id`, if
+    /// there is one.
+    pub fn register_server(
+        &mut self,
+        language_id: &str,
+        exec_path: &str,
+        options: Option<Value>,
+        file_patterns: Vec<String>,
+        plugin: PluginName,
+    ) -> Option<LspServerId> {
+        let registration = self
+            .registrations
+            .entry(language_id.to_string())
+            .or_insert_with(|| LspRegistration {
+                exec_path: exec_path.to_string(),
+                options,
+                file_patterns,
+                plugins: Vec::new(),
+            });
+        if !registration.plugins.contains(&plugin) {
+            registration.plugins.push(plugin);
+        }
+        let exec_path = registration.exec_path.clone();
+        self.clients(language_id)
+            .iter()
+            .find(|client| client.exec_path == exec_path)
+            .map(|client| client.id)
+    }
+
+    /// Starts the registered server for a file that was just opened, unless
+    /// it's running already. Returns the new server's id with the plugins
+    /// that registered it.
+    pub fn start_registered_server(
+        &mut self,
+        language_id: &str,
+        path: &Path,
+    ) -> Option<(String, LspServerId, Vec<PluginName>)> {
+        let (server_language_id, registration) =
+            self.registrations.iter().find(|(id, registration)| {
+                id.as_str() == language_id || registration.matches(path)
+            })?;
+        if self
+            .clients(server_language_id)
+            .iter()
+            .any(|client| client.exec_path == registration.exec_path)
+        {
+            return None;
+        }
+        let server_language_id = server_language_id.clone();
+        let exec_path = registration.exec_path.clone();
+        let options = registration.options.clone();
+        let plugins = registration.plugins.clone();
+        match self.start_server(
+            &exec_path,
+            &server_language_id,
+            None,
+            options,
+            plugins.first().cloned(),
+        ) {
+            Ok(id) => Some((server_language_id, id, plugins)),
+            Err(e) => {
+                // don't try again for every file that's opened
+                eprintln!("start registered lsp {} err {}", exec_path, e);
+                self.registrations.remove(&server_language_id);
+                None
+            }
         }
     }
 
+    /// Starts a language server for `language_id` next to the ones already
+    /// running for it, replacing a server started from the same
+    /// `exec_path`. `plugin` is told through a `lsp_server_exited`
+    /// notification if the server dies on its own. An `exec_path` like
+    /// `tcp://127.0.0.1:9257` or `socket:///tmp/server.sock` is connected
+    /// to instead of spawned. A server with a `root` only gets the
+    /// buffers of that workspace folder.
     pub fn start_server(
         &mut self,
         exec_path: &str,
         language_id: &str,
+        root: Option<PathBuf>,
         options: Option<Value>,
-    ) {
+        plugin: Option<PluginName>,
+    ) -> Result<LspServerId> {
+        self.respawns.retain(|_, respawn| {
+            respawn.language_id != language_id
+                || respawn.exec_path != exec_path
+                || respawn.root != root
+        });
+        self.spawn_server(
+            LspServerId::next(),
+            exec_path,
+            language_id,
+            root,
+            options,
+            plugin,
+        )
+    }
+
+    /// Shuts down the servers for `language_id` and starts them again with
+    /// the same ids, so the plugins that started them can keep using those
+    /// ids. The caller has to replay `didOpen` for the open buffers with
+    /// `open_buffers`.
+    pub fn restart_server(&mut self, language_id: &str) -> Result<Vec<LspServerId>> {
+        let mut servers: Vec<_> = self
+            .clients(language_id)
+            .iter()
+            .map(|client| {
+                (
+                    client.id,
+                    client.exec_path.clone(),
+                    client.root.clone(),
+                    client.options.clone(),
+                    client.plugin.clone(),
+                )
+            })
+            .collect();
+        for (id, respawn) in self.respawns.iter() {
+            if respawn.language_id == language_id
+                && !servers.iter().any(|(server, ..)| server == id)
+            {
+                servers.push((
+                    *id,
+                    respawn.exec_path.clone(),
+                    respawn.root.clone(),
+                    respawn.options.clone(),
+                    respawn.plugin.clone(),
+                ));
+            }
+        }
+        if servers.is_empty() {
+            return Err(anyhow!("no language server for {}", language_id));
+        }
+        self.respawns
+            .retain(|_, respawn| respawn.language_id != language_id);
+        servers
+            .into_iter()
+            .map(|(id, exec_path, root, options, plugin)| {
+                self.spawn_server(id, &exec_path, language_id, root, options, plugin)
+            })
+            .collect()
+    }
+
+    /// Brings back a server that crashed, unless it was stopped, replaced
+    /// or restarted in the meantime. Returns `None` if there was nothing to
+    /// do.
+    pub fn respawn_server(
+        &mut self,
+        language_id: &str,
+        id: LspServerId,
+    ) -> Result<Option<LspServerId>> {
+        if self
+            .clients(language_id)
+            .iter()
+            .any(|client| client.id == id)
+        {
+            return Ok(None);
+        }
+        let (exec_path, root, options, plugin) = match self.respawns.get(&id) {
+            Some(respawn) => (
+                respawn.exec_path.clone(),
+                respawn.root.clone(),
+                respawn.options.clone(),
+                respawn.plugin.clone(),
+            ),
+            None => return Ok(None),
+        };
+        self.spawn_server(id, &exec_path, language_id, root, options, plugin)
+            .map(Some)
+    }
+
+    /// Works out whether a server whose process died on its own should be
+    /// respawned. Returns the attempt number if so, counting from 1 up to
+    /// `MAX_RESPAWN_ATTEMPTS`.
+    fn server_crashed(&mut self, client: &LspClient) -> Option<usize> {
+        let respawn = self
+            .respawns
+            .entry(client.id)
+            .or_insert_with(|| LspRespawn {
+                language_id: client.language_id.clone(),
+                exec_path: client.exec_path.clone(),
+                root: client.root.clone(),
+                options: client.options.clone(),
+                plugin: client.plugin.clone(),
+                attempts: 0,
+            });
+        if client.started.elapsed() > RESPAWN_RESET_AFTER {
+            respawn.attempts = 0;
+        }
+        if respawn.attempts == MAX_RESPAWN_ATTEMPTS {
+            return None;
+        }
+        respawn.attempts += 1;
+        Some(respawn.attempts)
+    }
+
+    fn spawn_server(
+        &mut self,
+        id: LspServerId,
+        exec_path: &str,
+        language_id: &str,
+        root: Option<PathBuf>,
+        options: Option<Value>,
+        plugin: Option<PluginName>,
+    ) -> Result<LspServerId> {
         let client = LspClient::new(
+            id,
             language_id.to_string(),
             exec_path,
+            root,
             options,
+            plugin,
+            self.diagnostics.clone(),
+            self.capabilities.clone(),
             self.dispatcher.clone().unwrap(),
-        );
-        self.clients.insert(language_id.to_string(), client);
+        )?;
+        let clients = self.clients.entry(language_id.to_string()).or_default();
+        match clients.iter().position(|old| {
+            old.id == id || (old.exec_path == exec_path && old.root == client.root)
+        }) {
+            Some(index) => std::mem::replace(&mut clients[index], client).stop(),
+            None => clients.push(client),
+        }
+        Ok(id)
+    }
+
+    pub fn stop_server(&mut self, id: LspServerId) {
+        self.respawns.remove(&id);
+        if let Some(client) = self.remove_server(id) {
+            client.stop();
+        }
+    }
+
+    fn remove_server(&mut self, id: LspServerId) -> Option<Arc<LspClient>> {
+        let language_id = self
+            .clients
+            .iter()
+            .find(|(_, clients)| clients.iter().any(|client| client.id == id))
+            .map(|(language_id, _)| language_id.clone())?;
+        let clients = self.clients.get_mut(&language_id)?;
+        let index = clients.iter().position(|client| client.id == id)?;
+        let client = clients.remove(index);
+        if clients.is_empty() {
+            self.clients.remove(&language_id);
+        }
+        Some(client)
+    }
+
+    pub fn stop_all(&mut self) {
+        self.respawns.clear();
+        for (_, clients) in self.clients.drain() {
+            for client in clients {
+                client.stop();
+            }
+        }
     }
 
     pub fn new_buffer(
@@ -85,813 +1247,4586 @@ impl LspCatalog {
         path: &str,
         language_id: &str,
         text: String,
+        rev: u64,
     ) {
         let document_uri = Url::from_file_path(path).unwrap();
-        if let Some(client) = self.clients.get(language_id) {
+        for client in self.clients(language_id) {
             client.send_did_open(
                 buffer_id,
                 document_uri.clone(),
                 language_id,
                 text.clone(),
+                rev,
             );
         }
     }
 
-    pub fn save_buffer(&self, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            client.send_did_save(uri);
+    /// Sends `didClose` to the servers that have the buffer open, as when it
+    /// changes language.
+    pub fn close_buffer(&self, buffer: &Buffer) {
+        for client in self.clients(&buffer.language_id) {
+            client.send_did_close(buffer.id);
         }
     }
 
-    pub fn get_semantic_tokens(&self, buffer: &Buffer) {
-        let buffer_id = buffer.id;
-        let path = buffer.path.clone();
-        let rev = buffer.rev;
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            let local_dispatcher = self.dispatcher.clone().unwrap();
-            client.request_semantic_tokens(uri, move |lsp_client, result| {
-                if let Ok(res) = result {
-                    let buffers = local_dispatcher.buffers.lock();
-                    let buffer = buffers.get(&buffer_id).unwrap();
-                    if buffer.rev != rev {
-                        return;
-                    }
-                    let lsp_state = lsp_client.state.lock();
-                    let semantic_tokens_provider = &lsp_state
-                        .server_capabilities
-                        .as_ref()
-                        .unwrap()
-                        .semantic_tokens_provider;
-                    if let Some(tokens) =
-                        format_semantic_tokens(buffer, semantic_tokens_provider, res)
-                    {
-                        local_dispatcher.send_notification(
-                            "semantic_tokens",
-                            json!({
-                                "rev": rev,
-                                "buffer_id": buffer_id,
-                                "path": path,
-                                "tokens": tokens,
-                            }),
-                        )
-                    }
-                }
-            });
+    /// Sends `didOpen` for every buffer of `language_id`, initializing the
+    /// servers first if needed.
+    pub fn open_buffers<'a>(
+        &self,
+        language_id: &str,
+        buffers: impl Iterator<Item = &'a Buffer>,
+    ) {
+        for buffer in buffers.filter(|b| b.language_id == language_id) {
+            for client in self.buffer_clients(buffer) {
+                client.get_uri(buffer);
+            }
         }
     }
 
-    pub fn get_document_symbols(&self, id: RequestId, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            client.request_document_symbols(uri, move |lsp_client, result| {
-                lsp_client.dispatcher.respond(id, result);
-            });
+    /// Tells the servers that get every workspace folder about the folders
+    /// that were added or removed, and stops the ones limited to a folder
+    /// that was removed.
+    pub fn workspace_folders_changed(
+        &mut self,
+        added: &[PathBuf],
+        removed: &[PathBuf],
+    ) {
+        let stopped: Vec<LspServerId> = self
+            .clients
+            .values()
+            .flatten()
+            .filter(|client| {
+                client
+                    .root
+                    .as_ref()
+                    .map(|root| removed.contains(root))
+                    .unwrap_or(false)
+            })
+            .map(|client| client.id)
+            .collect();
+        for id in stopped {
+            self.stop_server(id);
+        }
+
+        let event = WorkspaceFoldersChangeEvent {
+            added: added
+                .iter()
+                .filter_map(|path| workspace_folder(path))
+                .collect(),
+            removed: removed
+                .iter()
+                .filter_map(|path| workspace_folder(path))
+                .collect(),
+        };
+        for client in self.clients.values().flatten() {
+            if client.root.is_none()
+                && client.has_capability(supports_workspace_folders)
+            {
+                client.send_did_change_workspace_folders(event.clone());
+            }
         }
     }
 
-    pub fn get_document_formatting(&self, id: RequestId, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
+    /// Sends `willSave` to the buffer's servers that want it, and
+    /// `willSaveWaitUntil` to the ones that can answer with edits, whose
+    /// answers come on the returned channels.
+    pub fn will_save(&self, buffer: &Buffer) -> Vec<Receiver<Result<Value>>> {
+        let mut receivers = Vec::new();
+        for client in self.buffer_clients(buffer) {
+            let options = match client.sync_options() {
+                Some(options) => options,
+                None => continue,
+            };
             let uri = client.get_uri(buffer);
-            client.request_document_formatting(uri, move |lsp_client, result| {
-                lsp_client.dispatcher.respond(id, result);
-            });
-        } else {
-            self.dispatcher
-                .as_ref()
-                .unwrap()
-                .respond(id, Err(anyhow!("no document formatting")));
+            if options.will_save == Some(true) {
+                client.send_will_save(uri.clone());
+            }
+            if options.will_save_wait_until == Some(true) {
+                let (sender, receiver) = channel();
+                client.request_will_save_wait_until(uri, move |_, result| {
+                    let _ = sender.send(result);
+                });
+                receivers.push(receiver);
+            }
         }
+        receivers
     }
 
-    pub fn get_completion(
-        &self,
-        id: RequestId,
-        request_id: usize,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
+    pub fn save_buffer(&self, buffer: &Buffer) {
+        for client in self.buffer_clients(buffer) {
             let uri = client.get_uri(buffer);
-            client.request_completion(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
+            let include_text = match client.sync_options().and_then(|o| o.save) {
+                Some(TextDocumentSyncSaveOptions::SaveOptions(options)) => {
+                    options.include_text == Some(true)
                 }
-                lsp_client.dispatcher.sender.send(resp);
-            });
+                _ => false,
+            };
+            let text = include_text.then(|| buffer.rope.to_string());
+            client.send_did_save(uri, text);
         }
     }
 
-    pub fn completion_resolve(
-        &self,
-        id: RequestId,
-        buffer: &Buffer,
-        completion_item: &CompletionItem,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            client.completion_resolve(completion_item, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
+    /// Asks for the buffer's tokens with `full/delta` when the server
+    /// supports it and has given us a result to diff against, `full`
+    /// otherwise, and `range` over the whole buffer for servers with
+    /// neither. The tokens go to the core as per-line styles.
+    pub fn get_semantic_tokens(&self, buffer: &Buffer) {
+        let (client, uri) = match self.client_with(buffer, |capabilities| {
+            capabilities.semantic_tokens_provider.is_some()
+        }) {
+            Some((client, uri)) => (client, uri),
+            None => return,
+        };
+        let options = match client
+            .state
+            .lock()
+            .server_capabilities
+            .as_ref()
+            .and_then(|capabilities| capabilities.semantic_tokens_provider.clone())
+        {
+            Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+                options,
+            )) => options,
+            Some(
+                SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(
+                    options,
+                ),
+            ) => options.semantic_tokens_options,
+            None => return,
+        };
+        let (full, delta) = match options.full {
+            Some(SemanticTokensFullOptions::Bool(full)) => (full, false),
+            Some(SemanticTokensFullOptions::Delta { delta }) => {
+                (true, delta.unwrap_or(false))
+            }
+            None => (false, false),
+        };
+        let previous_result_id = if delta {
+            client
+                .semantic_tokens
+                .lock()
+                .get(&buffer.id)
+                .map(|(result_id, _)| result_id.clone())
+        } else {
+            None
+        };
+
+        let buffer_id = buffer.id;
+        let path = buffer.path.clone();
+        let rev = buffer.rev;
+        let legend = options.legend;
+        let cb = move |lsp_client: &LspClient, result: Result<Value>| {
+            let data = match result.and_then(|value| {
+                lsp_client.update_semantic_tokens(buffer_id, value)
+            }) {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("semantic tokens err {}", e);
+                    return;
                 }
-                lsp_client.dispatcher.sender.send(resp);
-            });
+            };
+            let buffers = lsp_client.dispatcher.buffers.lock();
+            let buffer = match buffers.get(&buffer_id) {
+                Some(buffer) if buffer.rev == rev => buffer,
+                _ => return,
+            };
+            let lines = semantic_line_styles(&buffer.rope, &legend, &data);
+            lsp_client.dispatcher.send_notification(
+                "semantic_tokens",
+                json!({
+                    "rev": rev,
+                    "buffer_id": buffer_id,
+                    "path": path,
+                    "lines": lines,
+                }),
+            );
+        };
+
+        if let Some(previous_result_id) = previous_result_id {
+            client.request_semantic_tokens_delta(uri, previous_result_id, cb);
+        } else if full {
+            client.request_semantic_tokens(uri, cb);
+        } else if options.range.unwrap_or(false) {
+            let range = Range {
+                start: Position::new(0, 0),
+                end: buffer.offset_to_position(buffer.len()),
+            };
+            client.request_semantic_tokens_range(uri, range, cb);
         }
     }
 
-    pub fn get_signature(&self, id: RequestId, buffer: &Buffer, position: Position) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            client.request_signature(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                lsp_client.dispatcher.sender.send(resp);
-            });
-        }
+    pub fn get_document_symbols(&self, id: RequestId, buffer: &mut Buffer) {
+        self.query_symbols(id, buffer, SymbolQuery::DocumentSymbols);
     }
 
-    pub fn get_references(
+    pub fn get_enclosing_scopes(
         &self,
         id: RequestId,
-        buffer: &Buffer,
-        position: Position,
+        buffer: &mut Buffer,
+        line: u32,
     ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            client.request_references(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                lsp_client.dispatcher.sender.send(resp);
+        self.query_symbols(id, buffer, SymbolQuery::EnclosingScopes(line));
+    }
+
+    /// Answers from the symbols stored for the buffer's revision, asking the
+    /// server for them once however many queries wait for them. Without a
+    /// server that has symbols, or when it fails, the scopes come from the
+    /// syntax tree.
+    fn query_symbols(&self, id: RequestId, buffer: &mut Buffer, query: SymbolQuery) {
+        let dispatcher = self.dispatcher.as_ref().unwrap();
+        let supported = |capabilities: &ServerCapabilities| {
+            is_enabled(&capabilities.document_symbol_provider)
+        };
+        let (client, uri) = match self.client_with(buffer, supported) {
+            Some((client, uri)) if client.has_capability(supported) => {
+                (client.clone(), uri)
+            }
+            _ => {
+                dispatcher.respond(id, query.answer(buffer, None));
+                return;
+            }
+        };
+
+        let key = (buffer.rev, client.id);
+        if let Some(symbols) = buffer.symbols.get(key) {
+            dispatcher.respond(id, query.answer(buffer, Some(&*symbols)));
+            return;
+        }
+        if !buffer.symbols.wait(key, id, query) {
+            return;
+        }
+        let buffer_id = buffer.id;
+        client.request_document_symbols(uri, move |lsp_client, result| {
+            let symbols = result.and_then(|value| {
+                let symbols: Option<DocumentSymbolResponse> =
+                    serde_json::from_value(value)?;
+                Ok(symbols.unwrap_or(DocumentSymbolResponse::Flat(Vec::new())))
             });
+            let symbols = match symbols {
+                Ok(symbols) => Some(symbols),
+                Err(e) => {
+                    eprintln!("document symbols err {}", e);
+                    None
+                }
+            };
+            let mut buffers = lsp_client.dispatcher.buffers.lock();
+            let buffer = match buffers.get_mut(&buffer_id) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            for (id, query) in buffer.symbols.set(key, symbols) {
+                let symbols = buffer.symbols.get(key);
+                let result = query.answer(buffer, symbols.as_deref());
+                lsp_client.dispatcher.respond(id, result);
+            }
+        });
+    }
+
+    pub fn format_document(&self, id: RequestId, buffer: &Buffer) {
+        let supported = |capabilities: &ServerCapabilities| {
+            is_enabled(&capabilities.document_formatting_provider)
+        };
+        match self.client_with(buffer, supported) {
+            Some((client, uri)) if client.has_capability(supported) => {
+                client.request_document_formatting(
+                    uri,
+                    buffer.formatting_options(),
+                    formatting_callback(id, buffer),
+                );
+            }
+            _ => self
+                .dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(formatting_not_supported(buffer))),
         }
     }
 
-    pub fn get_code_actions(
+    /// Asks for the formatting of a save the editor doesn't make, like an
+    /// auto-save, whose edits come on the returned channel. `None` when no
+    /// server formats the buffer.
+    pub fn format_for_save(
         &self,
-        id: RequestId,
         buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            let uri = client.get_uri(buffer);
-            let range = Range {
-                start: position,
-                end: position,
-            };
-            client.request_code_actions(uri, range, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                lsp_client.dispatcher.sender.send(resp);
-            });
+    ) -> Option<Receiver<Result<Value>>> {
+        let supported = |capabilities: &ServerCapabilities| {
+            is_enabled(&capabilities.document_formatting_provider)
+        };
+        let (client, uri) = self.client_with(buffer, supported)?;
+        if !client.has_capability(supported) {
+            return None;
         }
+        let (sender, receiver) = channel();
+        client.request_document_formatting(
+            uri,
+            buffer.formatting_options(),
+            move |_, result| {
+                let _ = sender.send(result);
+            },
+        );
+        Some(receiver)
     }
 
-    pub fn get_definition(
+    pub fn format_range(&self, id: RequestId, buffer: &Buffer, range: Range) {
+        let supported = |capabilities: &ServerCapabilities| {
+            is_enabled(&capabilities.document_range_formatting_provider)
+        };
+        match self.client_with(buffer, supported) {
+            Some((client, uri)) if client.has_capability(supported) => {
+                client.request_range_formatting(
+                    uri,
+                    range,
+                    buffer.formatting_options(),
+                    formatting_callback(id, buffer),
+                );
+            }
+            _ => self
+                .dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(formatting_not_supported(buffer))),
+        }
+    }
+
+    /// Asks every server of the buffer's language and concatenates their
+    /// items. Items of a later server whose label an earlier server already
+    /// offered are dropped.
+    pub fn get_completion(
         &self,
         id: RequestId,
         request_id: usize,
         buffer: &Buffer,
         position: Position,
     ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
+        let clients: Vec<Arc<LspClient>> =
+            self.buffer_clients(buffer).cloned().collect();
+        self.request_merged(id, &clients, merge_completions, |client, callback| {
             let uri = client.get_uri(buffer);
-            client.request_definition(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                lsp_client.dispatcher.sender.send(resp);
-            });
-        }
+            client.request_completion(uri, position, callback)
+        });
     }
 
-    pub fn update(
+    /// Resolves the item with the server that produced it. When that
+    /// server has no `resolveProvider` the item is echoed back unchanged,
+    /// so the editor can always wait for an answer.
+    pub fn completion_resolve(
         &self,
+        id: RequestId,
         buffer: &Buffer,
-        content_change: &TextDocumentContentChangeEvent,
-        rev: u64,
+        completion_item: &CompletionItem,
     ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            client.update(buffer, content_change, rev);
+        let (server, resolve_item) = untag_completion_item(completion_item);
+        let clients: Vec<&Arc<LspClient>> = self.buffer_clients(buffer).collect();
+        match clients
+            .iter()
+            .find(|client| Some(client.id) == server)
+            .or_else(|| clients.first())
+        {
+            Some(client) if client.has_capability(can_resolve_completion) => {
+                client.completion_resolve(
+                    &resolve_item,
+                    move |lsp_client, result| {
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            _ => {
+                self.dispatcher.as_ref().unwrap().respond(
+                    id,
+                    serde_json::to_value(completion_item).map_err(|e| anyhow!(e)),
+                );
+            }
         }
     }
-}
-
-impl LspClient {
-    pub fn new(
-        language_id: String,
-        exec_path: &str,
-        options: Option<Value>,
-        dispatcher: Dispatcher,
-    ) -> Arc<LspClient> {
-        let mut process = Command::new(exec_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Error Occurred");
-
-        let writer = Box::new(BufWriter::new(process.stdin.take().unwrap()));
-        let stdout = process.stdout.take().unwrap();
-
-        let lsp_client = Arc::new(LspClient {
-            dispatcher,
-            language_id,
-            options,
-            state: Arc::new(Mutex::new(LspState {
-                next_id: 0,
-                writer,
-                process,
-                pending: HashMap::new(),
-                server_capabilities: None,
-                opened_documents: HashMap::new(),
-                is_initialized: false,
-            })),
-        });
 
-        let local_lsp_client = lsp_client.clone();
-        thread::spawn(move || {
-            let mut reader = Box::new(BufReader::new(stdout));
-            loop {
-                match read_message(&mut reader) {
-                    Ok(message_str) => {
-                        local_lsp_client.handle_message(message_str.as_ref());
-                    }
-                    Err(err) => {
-                        eprintln!("lsp read Error occurred {:?}", err);
-                        return;
-                    }
-                };
-            }
+    /// Asks every server of the buffer's language and stacks the contents
+    /// of their answers.
+    pub fn get_hover(&self, id: RequestId, buffer: &Buffer, position: Position) {
+        let clients: Vec<Arc<LspClient>> =
+            self.buffer_clients(buffer).cloned().collect();
+        self.request_merged(id, &clients, merge_hovers, |client, callback| {
+            let uri = client.get_uri(buffer);
+            client.request_hover(uri, position, callback)
         });
+    }
 
-        lsp_client
+    /// Asks every running server for symbols matching `query` and responds
+    /// with at most `limit` of them. The previous query's requests are
+    /// cancelled, as their answers are of no use anymore.
+    pub fn get_workspace_symbols(
+        &mut self,
+        id: RequestId,
+        query: &str,
+        limit: usize,
+    ) {
+        for (client, request_id) in self.workspace_symbols.drain(..) {
+            client.cancel_request(request_id);
+        }
+        let clients: Vec<Arc<LspClient>> =
+            self.clients.values().flatten().cloned().collect();
+        self.workspace_symbols = self.request_merged(
+            id,
+            &clients,
+            move |responses| merge_workspace_symbols(responses, limit),
+            |client, callback| client.request_workspace_symbols(query, callback),
+        );
     }
 
-    pub fn get_uri(&self, buffer: &Buffer) -> Url {
-        let exits = {
-            let state = self.state.lock();
-            state.opened_documents.contains_key(&buffer.id)
-        };
-        if !exits {
-            let document_uri = Url::from_file_path(&buffer.path).unwrap();
-            self.send_did_open(
-                &buffer.id,
-                document_uri,
-                &buffer.language_id,
-                buffer.get_document(),
+    pub fn get_signature(&self, id: RequestId, buffer: &Buffer, position: Position) {
+        if let Some((client, uri)) = self.client_with(buffer, |capabilities| {
+            capabilities.signature_help_provider.is_some()
+        }) {
+            client.request_signature(
+                uri,
+                position,
+                None,
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
             );
         }
-        self.state
-            .lock()
-            .opened_documents
-            .get(&buffer.id)
-            .unwrap()
-            .clone()
     }
 
-    pub fn handle_message(&self, message: &str) {
-        match JsonRpc::parse(message) {
-            Ok(JsonRpc::Request(obj)) => {
-                // trace!("client received unexpected request: {:?}", obj)
-            }
-            Ok(value @ JsonRpc::Notification(_)) => {
-                self.handle_notification(
-                    value.get_method().unwrap(),
-                    value.get_params().unwrap(),
+    /// Asks for signature help when `typed` is one of the server's trigger
+    /// characters, or one of its retrigger characters while the help is
+    /// showing. The answer goes to the core as a `signature_help`
+    /// notification.
+    pub fn signature_on_type(
+        &mut self,
+        buffer: &Buffer,
+        typed: char,
+        position: Position,
+    ) {
+        let active = self
+            .signature
+            .as_ref()
+            .map(|(buffer_id, _, _)| *buffer_id == buffer.id)
+            .unwrap_or(false);
+        if active && typed == ')' {
+            self.cancel_signature(buffer.id);
+            if let Some(dispatcher) = self.dispatcher.as_ref() {
+                let response =
+                    signature_help_response(buffer.id, buffer.rev, position, None);
+                dispatcher.send_notification(
+                    "signature_help",
+                    json!({ "signature": response }),
                 );
             }
-            Ok(value @ JsonRpc::Success(_)) => {
-                let id = number_from_id(&value.get_id().unwrap());
-                let result = value.get_result().unwrap();
-                self.handle_response(id, Ok(result.clone()));
-            }
-            Ok(value @ JsonRpc::Error(_)) => {
-                let id = number_from_id(&value.get_id().unwrap());
-                let error = value.get_error().unwrap();
-                self.handle_response(id, Err(anyhow!("{}", error)));
-            }
-            Err(err) => {
-                eprintln!("Error in parsing incoming string: {} \n {}", err, message)
-            }
+            return;
+        }
+
+        let (client, uri) = match self.client_with(buffer, |capabilities| {
+            capabilities.signature_help_provider.is_some()
+        }) {
+            Some((client, uri)) => (client.clone(), uri),
+            None => return,
+        };
+        let options =
+            client.state.lock().server_capabilities.as_ref().and_then(
+                |capabilities| capabilities.signature_help_provider.clone(),
+            );
+        let options = match options {
+            Some(options) => options,
+            None => return,
+        };
+        let typed = typed.to_string();
+        let is_trigger = options
+            .trigger_characters
+            .map(|chars| chars.contains(&typed))
+            .unwrap_or(false);
+        let is_retrigger = active
+            && options
+                .retrigger_characters
+                .map(|chars| chars.contains(&typed))
+                .unwrap_or(false);
+        if !is_trigger && !is_retrigger {
+            return;
         }
+
+        if let Some((_, client, request_id)) = self.signature.take() {
+            client.cancel_request(request_id);
+        }
+        let context = SignatureHelpContext {
+            trigger_kind: SignatureHelpTriggerKind::TriggerCharacter,
+            trigger_character: Some(typed),
+            is_retrigger: active,
+            active_signature_help: None,
+        };
+        let buffer_id = buffer.id;
+        let rev = buffer.rev;
+        let request_id = client.request_signature(
+            uri,
+            position,
+            Some(context),
+            move |lsp_client, result| {
+                // a cancelled request is superseded by a newer one
+                if let Ok(value) = result {
+                    let help: Option<SignatureHelp> =
+                        serde_json::from_value(value).unwrap_or(None);
+                    let response =
+                        signature_help_response(buffer_id, rev, position, help);
+                    lsp_client.dispatcher.send_notification(
+                        "signature_help",
+                        json!({ "signature": response }),
+                    );
+                }
+            },
+        );
+        self.signature = Some((buffer_id, client, request_id));
     }
 
-    pub fn handle_notification(&self, method: &str, params: Params) {
-        match method {
-            "textDocument/publishDiagnostics" => {
-                self.dispatcher.send_notification(
-                    "publish_diagnostics",
+    /// Asks the server to format around a typed trigger character. The
+    /// edits are sent to the editor only if the buffer is still at the
+    /// revision the characters were typed in.
+    pub fn format_on_type(&self, buffer: &Buffer, typed: char, position: Position) {
+        let (client, uri) = match self.client_with(buffer, |capabilities| {
+            capabilities.document_on_type_formatting_provider.is_some()
+        }) {
+            Some((client, uri)) => (client.clone(), uri),
+            None => return,
+        };
+        let options = client.state.lock().server_capabilities.as_ref().and_then(
+            |capabilities| capabilities.document_on_type_formatting_provider.clone(),
+        );
+        let options = match options {
+            Some(options) => options,
+            None => return,
+        };
+        let typed = typed.to_string();
+        let is_trigger = options.first_trigger_character == typed
+            || options
+                .more_trigger_character
+                .map(|chars| chars.contains(&typed))
+                .unwrap_or(false);
+        if !is_trigger {
+            return;
+        }
+
+        let buffer_id = buffer.id;
+        let path = buffer.path.clone();
+        let rev = buffer.rev;
+        client.request_on_type_formatting(
+            uri,
+            position,
+            typed,
+            buffer.formatting_options(),
+            move |lsp_client, result| {
+                let edits: Option<Vec<TextEdit>> = match result {
+                    Ok(value) => serde_json::from_value(value).unwrap_or(None),
+                    Err(_) => return,
+                };
+                let edits = match edits {
+                    Some(edits) if !edits.is_empty() => edits,
+                    _ => return,
+                };
+                let current = lsp_client
+                    .dispatcher
+                    .buffers
+                    .lock()
+                    .get(&buffer_id)
+                    .map(|buffer| buffer.rev);
+                if current != Some(rev) {
+                    return;
+                }
+                lsp_client.dispatcher.send_notification(
+                    "on_type_formatting",
                     json!({
-                        "diagnostics": params,
+                        "path": path,
+                        "rev": rev,
+                        "edits": edits,
                     }),
                 );
-            }
-            _ => eprintln!("{} {:?}", method, params),
-        }
+            },
+        );
     }
 
-    pub fn handle_response(&self, id: u64, result: Result<Value>) {
-        let callback =
-            {
-                self.state.lock().pending.remove(&id).unwrap_or_else(|| {
-                    panic!("id {} missing from request table", id)
-                })
-            };
-        callback.call(self, result);
+    /// Called on every cursor move: cancels the highlight request in flight
+    /// and returns the generation to pass to `document_highlight` once the
+    /// cursor has rested for `DOCUMENT_HIGHLIGHT_DELAY`.
+    pub fn document_highlight_moved(&mut self) -> u64 {
+        if let Some((client, request_id)) = self.highlight_request.take() {
+            client.cancel_request(request_id);
+        }
+        self.highlight_generation += 1;
+        self.highlight_generation
     }
 
-    pub fn write(&self, msg: &str) {
-        let mut state = self.state.lock();
-        state
-            .writer
-            .write_all(msg.as_bytes())
-            .expect("error writing to stdin");
-        state.writer.flush().expect("error flushing child stdin");
-    }
-
-    fn send_rpc(&self, value: &Value) {
-        let rpc = match prepare_lsp_json(value) {
-            Ok(r) => r,
-            Err(err) => panic!("Encoding Error {:?}", err),
+    /// Highlights the occurrences of the identifier at the position, unless
+    /// the cursor moved again since `generation`. Without a server that
+    /// supports it, the occurrences are found by matching the word.
+    pub fn document_highlight(
+        &mut self,
+        generation: u64,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        if generation != self.highlight_generation {
+            return;
+        }
+        let dispatcher = match self.dispatcher.as_ref() {
+            Some(dispatcher) => dispatcher.clone(),
+            None => return,
+        };
+        let word = buffer
+            .offset_of_position(&position)
+            .ok()
+            .and_then(|offset| word_at(&buffer.rope, offset));
+        let word = match word {
+            Some(word) => word,
+            None => {
+                if self.last_highlight.lock().take().is_some() {
+                    send_document_highlights(
+                        &dispatcher,
+                        buffer.id,
+                        buffer.rev,
+                        Vec::new(),
+                    );
+                }
+                return;
+            }
         };
+        let key = (buffer.id, buffer.rev, position);
+        if *self.last_highlight.lock() == Some(key) {
+            return;
+        }
 
-        self.write(rpc.as_ref());
+        let found = self
+            .client_with(buffer, |capabilities| {
+                is_enabled(&capabilities.document_highlight_provider)
+            })
+            .map(|(client, uri)| (client.clone(), uri));
+        match found {
+            Some((client, uri)) => {
+                let last_highlight = self.last_highlight.clone();
+                let request_id = client.request_document_highlight(
+                    uri,
+                    position,
+                    move |lsp_client, result| {
+                        // cancelled when the cursor moved on
+                        if let Ok(value) = result {
+                            let highlights: Option<Vec<DocumentHighlight>> =
+                                serde_json::from_value(value).unwrap_or(None);
+                            *last_highlight.lock() = Some(key);
+                            send_document_highlights(
+                                &lsp_client.dispatcher,
+                                key.0,
+                                key.1,
+                                highlights.unwrap_or_default(),
+                            );
+                        }
+                    },
+                );
+                self.highlight_request = Some((client, request_id));
+            }
+            None => {
+                *self.last_highlight.lock() = Some(key);
+                send_document_highlights(
+                    &dispatcher,
+                    buffer.id,
+                    buffer.rev,
+                    word_highlights(&buffer.rope, word),
+                );
+            }
+        }
     }
 
-    pub fn send_notification(&self, method: &str, params: Params) {
-        let notification = JsonRpc::notification_with_params(method, params);
-        let res = to_value(&notification).unwrap();
-        self.send_rpc(&res);
+    /// Answers with the chain of ranges around each of `positions`,
+    /// innermost first, for expanding and shrinking the selection. Chains
+    /// asked for before are taken from the cache until the buffer changes.
+    /// Without a server that provides selection ranges, the chain is made
+    /// of the syntax tree's nodes, or of the word, line and document for
+    /// languages without a grammar.
+    /// Responds with the folding ranges of the buffer, from its server or,
+    /// without one that supports them, from the indentation and region
+    /// markers.
+    pub fn get_folding_ranges(&self, id: RequestId, buffer: &Buffer) {
+        let found = self
+            .client_with(buffer, can_fold)
+            .filter(|(client, _)| client.has_capability(can_fold))
+            .map(|(client, uri)| (client.clone(), uri));
+        let (buffer_id, rev) = (buffer.id, buffer.rev);
+        let (rope, language_id) = (buffer.rope.clone(), buffer.language_id.clone());
+        let num_lines = rope.measure::<LinesMetric>();
+        let respond = move |dispatcher: &Dispatcher,
+                            ranges: Vec<FoldingRangeItem>| {
+            let response = FoldingRangesResponse {
+                buffer_id,
+                rev,
+                ranges: valid_folding_ranges(ranges, num_lines),
+            };
+            dispatcher.respond(id, Ok(serde_json::to_value(response).unwrap()));
+        };
+        match found {
+            Some((client, uri)) => {
+                client.request_folding_range(uri, move |lsp_client, result| {
+                    let ranges: Option<Vec<FoldingRange>> = result
+                        .and_then(|value| Ok(serde_json::from_value(value)?))
+                        .unwrap_or(None);
+                    let ranges = match ranges {
+                        Some(ranges) => ranges
+                            .into_iter()
+                            .map(|range| FoldingRangeItem {
+                                start_line: range.start_line,
+                                end_line: range.end_line,
+                                kind: range.kind,
+                            })
+                            .collect(),
+                        None => fallback_folding_ranges(&rope, &language_id),
+                    };
+                    respond(&lsp_client.dispatcher, ranges);
+                });
+            }
+            None => {
+                let ranges =
+                    fallback_folding_ranges(&buffer.rope, &buffer.language_id);
+                respond(self.dispatcher.as_ref().unwrap(), ranges);
+            }
+        }
     }
 
-    pub fn send_request(&self, method: &str, params: Params, completion: Callback) {
-        let request = {
-            let mut state = self.state.lock();
-            let next_id = state.next_id;
-            state.pending.insert(next_id, completion);
-            state.next_id += 1;
+    pub fn get_selection_ranges(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        positions: Vec<Position>,
+    ) {
+        let cached: Option<Vec<Vec<Range>>> = {
+            let cache = self.selection_ranges.lock();
+            match cache.get(&buffer.id) {
+                Some((rev, chains)) if *rev == buffer.rev => positions
+                    .iter()
+                    .map(|position| {
+                        chains
+                            .iter()
+                            .find(|(cached, _)| cached == position)
+                            .map(|(_, chain)| chain.clone())
+                    })
+                    .collect(),
+                _ => None,
+            }
+        };
+        let dispatcher = self.dispatcher.as_ref().unwrap();
+        if let Some(chains) = cached {
+            dispatcher.respond(id, Ok(serde_json::to_value(chains).unwrap()));
+            return;
+        }
 
-            JsonRpc::request_with_params(Id::Num(next_id as i64), method, params)
+        let found = self
+            .client_with(buffer, can_select_range)
+            .filter(|(client, _)| client.has_capability(can_select_range))
+            .map(|(client, uri)| (client.clone(), uri));
+        let cache = self.selection_ranges.clone();
+        let (buffer_id, rev) = (buffer.id, buffer.rev);
+        let (rope, language_id) = (buffer.rope.clone(), buffer.language_id.clone());
+        let requested = positions.clone();
+        let respond = move |dispatcher: &Dispatcher, chains: Vec<Vec<Range>>| {
+            cache.lock().insert(
+                buffer_id,
+                (
+                    rev,
+                    requested
+                        .iter()
+                        .cloned()
+                        .zip(chains.iter().cloned())
+                        .collect(),
+                ),
+            );
+            dispatcher.respond(id, Ok(serde_json::to_value(chains).unwrap()));
         };
+        match found {
+            Some((client, uri)) => {
+                let requested = positions.clone();
+                client.request_selection_range(
+                    uri,
+                    positions,
+                    move |lsp_client, result| {
+                        let ranges: Option<Vec<SelectionRange>> = result
+                            .and_then(|value| Ok(serde_json::from_value(value)?))
+                            .unwrap_or(None);
+                        let chains = match ranges {
+                            Some(ranges) if ranges.len() == requested.len() => {
+                                ranges
+                                    .into_iter()
+                                    .map(selection_range_chain)
+                                    .collect()
+                            }
+                            _ => syntax_selection_ranges(
+                                &rope,
+                                &language_id,
+                                &requested,
+                            ),
+                        };
+                        respond(&lsp_client.dispatcher, chains);
+                    },
+                );
+            }
+            None => {
+                let chains =
+                    syntax_selection_ranges(&rope, &language_id, &positions);
+                respond(dispatcher, chains);
+            }
+        }
+    }
 
-        self.send_rpc(&to_value(&request).unwrap());
+    /// Answers with the links of the lines in `range`. Buffers without a
+    /// server that provides links get the URLs and the paths of files in
+    /// the workspace found in their text.
+    pub fn get_document_links(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        range: Range,
+        workspace: &Path,
+    ) {
+        let buffer_id = buffer.id;
+        let rev = buffer.rev;
+        match self.client_with(buffer, |capabilities| {
+            capabilities.document_link_provider.is_some()
+        }) {
+            Some((client, uri)) => {
+                client.request_document_links(uri, move |lsp_client, result| {
+                    let result = result.and_then(|value| {
+                        let links: Option<Vec<DocumentLink>> =
+                            serde_json::from_value(value)?;
+                        let links = links
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter(|link| {
+                                link.range.end.line >= range.start.line
+                                    && link.range.start.line <= range.end.line
+                            })
+                            .map(DocumentLinkItem::from)
+                            .collect();
+                        let response = DocumentLinksResponse {
+                            buffer_id,
+                            rev,
+                            links,
+                        };
+                        Ok(serde_json::to_value(response)?)
+                    });
+                    lsp_client.dispatcher.respond(id, result);
+                });
+            }
+            None => {
+                let response = DocumentLinksResponse {
+                    buffer_id,
+                    rev,
+                    links: fallback_document_links(&buffer.rope, range, workspace),
+                };
+                self.dispatcher.as_ref().unwrap().respond(
+                    id,
+                    serde_json::to_value(response).map_err(|e| anyhow!(e)),
+                );
+            }
+        }
     }
 
-    pub fn send_did_open(
+    /// Fills in the target of a link that came without one. Links the
+    /// server can't resolve are echoed back.
+    pub fn resolve_document_link(
         &self,
-        buffer_id: &BufferId,
-        document_uri: Url,
-        language_id: &str,
-        document_text: String,
+        id: RequestId,
+        buffer: &Buffer,
+        link: DocumentLinkItem,
     ) {
-        let is_initialized = {
-            let mut state = self.state.lock();
-            state
-                .opened_documents
-                .insert(buffer_id.clone(), document_uri.clone());
-            state.is_initialized
+        match self.client_with(buffer, |capabilities| {
+            capabilities
+                .document_link_provider
+                .as_ref()
+                .and_then(|provider| provider.resolve_provider)
+                .unwrap_or(false)
+        }) {
+            Some((client, _)) if link.target.is_none() => {
+                let params = DocumentLink {
+                    range: link.range,
+                    target: None,
+                    tooltip: link.tooltip.clone(),
+                    data: link.data.clone(),
+                };
+                client.request_resolve_document_link(
+                    params,
+                    move |lsp_client, result| {
+                        let result = result.and_then(|value| {
+                            let link: DocumentLink = serde_json::from_value(value)?;
+                            Ok(serde_json::to_value(DocumentLinkItem::from(link))?)
+                        });
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            _ => {
+                self.dispatcher
+                    .as_ref()
+                    .unwrap()
+                    .respond(id, serde_json::to_value(link).map_err(|e| anyhow!(e)));
+            }
+        }
+    }
+
+    /// Called when the cursor leaves the call the help was showing for.
+    pub fn cancel_signature(&mut self, buffer_id: BufferId) {
+        if let Some((id, client, request_id)) = self.signature.take() {
+            if id == buffer_id {
+                client.cancel_request(request_id);
+            } else {
+                self.signature = Some((id, client, request_id));
+            }
+        }
+    }
+
+    /// Answers from the hints cached for the buffer's revision, asking the
+    /// server only for the lines of `range` that aren't cached.
+    pub fn get_inlay_hints(&self, id: RequestId, buffer: &Buffer, range: Range) {
+        let dispatcher = self.dispatcher.as_ref().unwrap();
+        let client = match self
+            .clients(&buffer.language_id)
+            .iter()
+            .find(|client| client.state.lock().inlay_hint_provider)
+        {
+            Some(client) => client.clone(),
+            None => {
+                dispatcher.respond(id, Err(anyhow!("no inlay hints")));
+                return;
+            }
         };
 
-        if !is_initialized {
-            let root_url =
-                Url::from_directory_path(self.dispatcher.workspace.lock().clone())
-                    .unwrap();
-            let (sender, receiver) = channel();
-            self.send_initialize(Some(root_url), move |lsp_client, result| {
-                if let Ok(result) = result {
-                    {
-                        let init_result: InitializeResult =
-                            serde_json::from_value(result).unwrap();
-                        let mut state = lsp_client.state.lock();
-                        state.server_capabilities = Some(init_result.capabilities);
-                        state.is_initialized = true;
-                    }
-                    lsp_client.send_initialized();
+        let buffer_id = buffer.id;
+        let rev = buffer.rev;
+        let (start, end) = (range.start.line, range.end.line);
+        let missing = {
+            let mut caches = client.inlay_hints.lock();
+            let cache = caches
+                .entry(buffer_id)
+                .or_insert_with(|| InlayHintsCache::new(rev));
+            if cache.rev != rev {
+                *cache = InlayHintsCache::new(rev);
+            }
+            match cache.missing(start, end) {
+                Some(missing) => missing,
+                None => {
+                    let response = InlayHintsResponse {
+                        buffer_id,
+                        rev,
+                        hints: cache.hints(start, end),
+                    };
+                    dispatcher.respond(
+                        id,
+                        serde_json::to_value(response).map_err(|e| anyhow!(e)),
+                    );
+                    return;
                 }
-                sender.send(true);
-            });
-            receiver.recv_timeout(Duration::from_millis(1000));
-        }
+            }
+        };
 
-        let text_document_did_open_params = DidOpenTextDocumentParams {
-            text_document: TextDocumentItem {
-                language_id: language_id.to_string(),
-                uri: document_uri,
-                version: 0,
-                text: document_text,
+        let (first, last) = missing;
+        let request_range = Range {
+            start: if first == start {
+                range.start
+            } else {
+                Position::new(first, 0)
+            },
+            end: if last == end {
+                range.end
+            } else {
+                Position::new(last + 1, 0)
             },
         };
-        let params = Params::from(
-            serde_json::to_value(text_document_did_open_params).unwrap(),
-        );
-        self.send_notification("textDocument/didOpen", params);
+        let uri = client.get_uri(buffer);
+        client.request_inlay_hints(uri, request_range, move |lsp_client, result| {
+            let result = result.and_then(|value| {
+                let hints: Option<Vec<LspInlayHint>> =
+                    serde_json::from_value(value)?;
+                let hints: Vec<InlayHintItem> = hints
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(InlayHintItem::from)
+                    .collect();
+                let mut caches = lsp_client.inlay_hints.lock();
+                let hints = match caches.get_mut(&buffer_id) {
+                    Some(cache) if cache.rev == rev => {
+                        cache.insert(first, last, hints);
+                        cache.hints(start, end)
+                    }
+                    // edited meanwhile, so these won't be shown for long
+                    _ => hints,
+                };
+                let response = InlayHintsResponse {
+                    buffer_id,
+                    rev,
+                    hints,
+                };
+                Ok(serde_json::to_value(response)?)
+            });
+            lsp_client.dispatcher.respond(id, result);
+        });
     }
 
-    pub fn send_did_save(&self, uri: Url) {
-        let params = DidSaveTextDocumentParams {
-            text_document: TextDocumentIdentifier { uri },
-            text: None,
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_notification("textDocument/didSave", params);
+    pub fn get_references(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        include_declaration: bool,
+    ) {
+        if let Some((client, uri)) = self.client_with(buffer, |capabilities| {
+            is_enabled(&capabilities.references_provider)
+        }) {
+            client.request_references(
+                uri,
+                position,
+                include_declaration,
+                move |lsp_client, result| {
+                    let result = result.and_then(|value| {
+                        let locations: Option<Vec<Location>> =
+                            serde_json::from_value(value)?;
+                        lsp_client
+                            .dispatcher
+                            .reference_locations(locations.unwrap_or_default())
+                    });
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        }
     }
 
-    pub fn send_initialized(&self) {
-        self.send_notification("initialized", Params::from(json!({})));
+    pub fn get_implementation(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        if let Some((client, uri)) = self.client_with(buffer, |capabilities| {
+            !matches!(
+                capabilities.implementation_provider,
+                None | Some(ImplementationProviderCapability::Simple(false))
+            )
+        }) {
+            client.request_implementation(uri, position, goto_callback(id));
+        }
     }
 
-    pub fn send_initialize<CB>(&self, root_uri: Option<Url>, on_init: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let client_capabilities = ClientCapabilities {
-            text_document: Some(TextDocumentClientCapabilities {
-                completion: Some(CompletionClientCapabilities {
-                    completion_item: Some(CompletionItemCapability {
-                        snippet_support: Some(true),
-                        resolve_support: Some(
-                            CompletionItemCapabilityResolveSupport {
-                                properties: vec!["additionalTextEdits".to_string()],
-                            },
-                        ),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }),
-                // signature_help: Some(SignatureHelpCapability {
-                //     signature_information: Some(SignatureInformationSettings {
-                //         parameter_information: Some(ParameterInformationSettings {
-                //             label_offset_support: Some(true),
-                //         }),
-                //         active_parameter_support: Some(true),
-                //         documentation_format: Some(vec![
-                //             MarkupKind::Markdown,
-                //             MarkupKind::PlainText,
-                //         ]),
-                //     }),
-                //     ..Default::default()
-                // }),
-                code_action: Some(CodeActionClientCapabilities {
-                    code_action_literal_support: Some(CodeActionLiteralSupport {
-                        code_action_kind: CodeActionKindLiteralSupport {
-                            value_set: vec![
-                                CodeActionKind::EMPTY.as_str().to_string(),
-                                CodeActionKind::QUICKFIX.as_str().to_string(),
-                                CodeActionKind::REFACTOR.as_str().to_string(),
-                                CodeActionKind::REFACTOR_EXTRACT
-                                    .as_str()
-                                    .to_string(),
-                                CodeActionKind::REFACTOR_INLINE.as_str().to_string(),
-                                CodeActionKind::REFACTOR_REWRITE
-                                    .as_str()
-                                    .to_string(),
-                                CodeActionKind::SOURCE.as_str().to_string(),
-                                CodeActionKind::SOURCE_ORGANIZE_IMPORTS
-                                    .as_str()
-                                    .to_string(),
-                            ],
-                        },
-                    }),
-                    ..Default::default()
-                }),
-                semantic_tokens: Some(SemanticTokensClientCapabilities {
-                    ..Default::default()
-                }),
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
+    /// Responds with every location the server gives, to be picked from
+    /// when there's more than one.
+    pub fn get_type_definition(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        match self.client_with(buffer, |capabilities| {
+            !matches!(
+                capabilities.type_definition_provider,
+                None | Some(TypeDefinitionProviderCapability::Simple(false))
+            )
+        }) {
+            Some((client, uri)) => {
+                client.request_type_definition(uri, position, goto_callback(id));
+            }
+            None => self.dispatcher.as_ref().unwrap().respond(
+                id,
+                Err(LspUnsupported::new("type definition", buffer).into()),
+            ),
+        }
+    }
 
-        let init_params = InitializeParams {
-            process_id: Some(u32::from(process::id())),
-            root_uri,
-            initialization_options: self.options.clone(),
-            capabilities: client_capabilities,
-            trace: Some(TraceOption::Verbose),
-            workspace_folders: None,
-            client_info: None,
-            root_path: None,
-            locale: None,
+    /// The first step of the call hierarchy: the items at the position,
+    /// usually just the function the cursor is in.
+    pub fn prepare_call_hierarchy(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        let (client, uri) = match self.client_with(buffer, |capabilities| {
+            !matches!(
+                capabilities.call_hierarchy_provider,
+                None | Some(CallHierarchyServerCapability::Simple(false))
+            )
+        }) {
+            Some(found) => found,
+            None => {
+                self.dispatcher.as_ref().unwrap().respond(
+                    id,
+                    Err(LspUnsupported::new("call hierarchy", buffer).into()),
+                );
+                return;
+            }
         };
-
-        let params = Params::from(serde_json::to_value(init_params).unwrap());
-        self.send_request("initialize", params, Box::new(on_init));
+        let buffer_id = buffer.id;
+        let items = self.call_hierarchy.clone();
+        let client_ref = client.clone();
+        client.request_prepare_call_hierarchy(
+            uri,
+            position,
+            move |lsp_client, result| {
+                let result = result.and_then(|value| {
+                    let prepared: Option<Vec<CallHierarchyItem>> =
+                        serde_json::from_value(value)?;
+                    let mut items = items.lock();
+                    let nodes: Vec<CallHierarchyNode> = prepared
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|item| items.insert(buffer_id, &client_ref, item))
+                        .collect();
+                    Ok(serde_json::to_value(nodes)?)
+                });
+                lsp_client.dispatcher.respond(id, result);
+            },
+        );
     }
 
-    pub fn request_document_symbols<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = DocumentSymbolParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
+    pub fn call_hierarchy_incoming(&self, id: RequestId, handle: u64) {
+        let (buffer_id, client, item) = match self.call_hierarchy_item(id, handle) {
+            Some(entry) => entry,
+            None => return,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/documentSymbol", params, Box::new(cb));
+        let items = self.call_hierarchy.clone();
+        let client_ref = client.clone();
+        client.request_incoming_calls(item, move |lsp_client, result| {
+            let result = result.and_then(|value| {
+                let calls: Option<Vec<CallHierarchyIncomingCall>> =
+                    serde_json::from_value(value)?;
+                let mut items = items.lock();
+                let calls: Vec<CallHierarchyCall> = calls
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|call| CallHierarchyCall {
+                        item: items.insert(buffer_id, &client_ref, call.from),
+                        call_sites: call.from_ranges,
+                    })
+                    .collect();
+                Ok(serde_json::to_value(calls)?)
+            });
+            lsp_client.dispatcher.respond(id, result);
+        });
     }
 
-    pub fn request_document_formatting<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = DocumentFormattingParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            options: FormattingOptions {
-                tab_size: 4,
-                insert_spaces: true,
-                ..Default::default()
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
+    pub fn call_hierarchy_outgoing(&self, id: RequestId, handle: u64) {
+        let (buffer_id, client, item) = match self.call_hierarchy_item(id, handle) {
+            Some(entry) => entry,
+            None => return,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/formatting", params, Box::new(cb));
+        let items = self.call_hierarchy.clone();
+        let client_ref = client.clone();
+        client.request_outgoing_calls(item, move |lsp_client, result| {
+            let result = result.and_then(|value| {
+                let calls: Option<Vec<CallHierarchyOutgoingCall>> =
+                    serde_json::from_value(value)?;
+                let mut items = items.lock();
+                let calls: Vec<CallHierarchyCall> = calls
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|call| CallHierarchyCall {
+                        item: items.insert(buffer_id, &client_ref, call.to),
+                        call_sites: call.from_ranges,
+                    })
+                    .collect();
+                Ok(serde_json::to_value(calls)?)
+            });
+            lsp_client.dispatcher.respond(id, result);
+        });
     }
 
-    pub fn request_semantic_tokens<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = SemanticTokensParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/semanticTokens/full", params, Box::new(cb));
+    /// Looks up the item behind a handle, responding with an error if it
+    /// was invalidated, so the core knows to prepare the hierarchy again.
+    fn call_hierarchy_item(
+        &self,
+        id: RequestId,
+        handle: u64,
+    ) -> Option<(BufferId, Arc<LspClient>, CallHierarchyItem)> {
+        let entry = self.call_hierarchy.lock().items.get(&handle).cloned();
+        if entry.is_none() {
+            self.dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(anyhow!("call hierarchy item is out of date")));
+        }
+        entry
     }
 
-    pub fn request_code_actions<CB>(&self, document_uri: Url, range: Range, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = CodeActionParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            range,
-            context: CodeActionContext::default(),
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/codeAction", params, Box::new(cb));
+    pub fn get_declaration(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        match self.client_with(buffer, |capabilities| {
+            !matches!(
+                capabilities.declaration_provider,
+                None | Some(DeclarationCapability::Simple(false))
+            )
+        }) {
+            Some((client, uri)) => {
+                client.request_declaration(uri, position, goto_callback(id));
+            }
+            None => self
+                .dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(LspUnsupported::new("declaration", buffer).into())),
+        }
     }
 
-    pub fn request_references<CB>(
+    pub fn get_code_actions(
         &self,
-        document_uri: Url,
+        id: RequestId,
+        buffer: &Buffer,
+        range: Range,
+        diagnostics: Vec<Diagnostic>,
+    ) {
+        if let Some((client, uri)) = self.client_with(buffer, |capabilities| {
+            !matches!(
+                capabilities.code_action_provider,
+                None | Some(CodeActionProviderCapability::Simple(false))
+            )
+        }) {
+            client.request_code_actions(
+                uri,
+                range,
+                diagnostics,
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        }
+    }
+
+    /// Fills in the edit of a code action that the server left out of its
+    /// `textDocument/codeAction` answer. Servers that can't resolve get the
+    /// action back unchanged.
+    pub fn resolve_code_action(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        action: CodeAction,
+    ) {
+        match self.client_with(buffer, can_resolve_code_action) {
+            Some((client, _)) if client.has_capability(can_resolve_code_action) => {
+                client.request_code_action_resolve(
+                    &action,
+                    move |lsp_client, result| {
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            _ => {
+                let action = serde_json::to_value(action).map_err(|e| anyhow!(e));
+                self.dispatcher.as_ref().unwrap().respond(id, action);
+            }
+        }
+    }
+
+    pub fn execute_command(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        command: lsp_types::Command,
+    ) {
+        if let Some((client, _)) = self.client_with(buffer, |capabilities| {
+            capabilities
+                .execute_command_provider
+                .as_ref()
+                .map(|provider| provider.commands.contains(&command.command))
+                .unwrap_or(false)
+        }) {
+            client.request_execute_command(&command, move |lsp_client, result| {
+                lsp_client.dispatcher.respond(id, result);
+            });
+        }
+    }
+
+    pub fn get_definition(
+        &self,
+        id: RequestId,
+        request_id: usize,
+        buffer: &Buffer,
         position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = ReferenceParams {
-            text_document_position: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-            context: ReferenceContext {
-                include_declaration: false,
-            },
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/references", params, Box::new(cb));
+    ) {
+        if let Some((client, uri)) = self.client_with(buffer, |capabilities| {
+            is_enabled(&capabilities.definition_provider)
+        }) {
+            client.request_definition(uri, position, move |lsp_client, result| {
+                lsp_client.dispatcher.respond(id, result);
+            });
+        }
     }
 
-    pub fn request_definition<CB>(
+    /// Responds with the range and placeholder the server suggests for the
+    /// new name. Servers without `prepareRename` get the default behaviour,
+    /// which leaves it to the core to pick the word under the cursor.
+    pub fn prepare_rename(
         &self,
-        document_uri: Url,
+        id: RequestId,
+        buffer: &Buffer,
         position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = GotoDefinitionParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/definition", params, Box::new(cb));
+    ) {
+        match self.client_with(buffer, can_prepare_rename) {
+            Some((client, uri)) if client.has_capability(can_prepare_rename) => {
+                client.request_prepare_rename(
+                    uri,
+                    position,
+                    move |lsp_client, result| {
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            _ => {
+                self.dispatcher
+                    .as_ref()
+                    .unwrap()
+                    .respond(id, Ok(json!({ "defaultBehavior": true })));
+            }
+        }
     }
 
-    pub fn request_completion<CB>(
+    pub fn rename(
         &self,
-        document_uri: Url,
+        id: RequestId,
+        buffer: &Buffer,
         position: Position,
-        on_completion: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let completion_params = CompletionParams {
-            text_document_position: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-            context: None,
+        new_name: String,
+    ) {
+        let can_rename = |capabilities: &ServerCapabilities| {
+            is_enabled(&capabilities.rename_provider)
         };
-        let params = Params::from(serde_json::to_value(completion_params).unwrap());
-        self.send_request(
-            "textDocument/completion",
-            params,
-            Box::new(on_completion),
-        );
+        match self.client_with(buffer, can_rename) {
+            Some((client, uri)) if client.has_capability(can_rename) => {
+                client.request_rename(
+                    uri,
+                    position,
+                    new_name,
+                    move |lsp_client, result| {
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            _ => self
+                .dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(LspUnsupported::new("rename", buffer).into())),
+        }
     }
 
-    pub fn completion_resolve<CB>(
+    /// Sends `willRenameFiles` to the servers whose filters match the path,
+    /// whose answers, edits to make for the rename like the `mod` lines
+    /// pointing at a Rust file, come on the returned channels.
+    pub fn will_rename_files(
         &self,
-        completion_item: &CompletionItem,
-        on_result: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = Params::from(serde_json::to_value(completion_item).unwrap());
-        self.send_request("completionItem/resolve", params, Box::new(on_result));
+        old: &Path,
+        new: &Path,
+    ) -> Vec<Receiver<Result<Value>>> {
+        let rename = match file_rename(old, new) {
+            Some(rename) => rename,
+            None => return Vec::new(),
+        };
+        let is_dir = old.is_dir();
+        let mut receivers = Vec::new();
+        for client in self.clients.values().flatten() {
+            let wants = client.has_capability(|capabilities| {
+                file_operations(capabilities)
+                    .and_then(|operations| operations.will_rename.as_ref())
+                    .map(|options| file_operation_matches(options, old, is_dir))
+                    .unwrap_or(false)
+            });
+            if wants {
+                let (sender, receiver) = channel();
+                client.request_will_rename_files(
+                    vec![rename.clone()],
+                    move |_, result| {
+                        let _ = sender.send(result);
+                    },
+                );
+                receivers.push(receiver);
+            }
+        }
+        receivers
     }
 
-    pub fn request_signature<CB>(
+    /// Sends `didRenameFiles` once the path was renamed on disk.
+    pub fn did_rename_files(&self, old: &Path, new: &Path) {
+        let rename = match file_rename(old, new) {
+            Some(rename) => rename,
+            None => return,
+        };
+        let is_dir = new.is_dir();
+        for client in self.clients.values().flatten() {
+            let wants = client.has_capability(|capabilities| {
+                file_operations(capabilities)
+                    .and_then(|operations| operations.did_rename.as_ref())
+                    .map(|options| file_operation_matches(options, old, is_dir))
+                    .unwrap_or(false)
+            });
+            if wants {
+                client.send_did_rename_files(vec![rename.clone()]);
+            }
+        }
+    }
+
+    /// Tells the servers about files that changed on disk without going
+    /// through a buffer, each one only about the files its registered
+    /// watchers match.
+    pub fn did_change_watched_files(&self, events: &[(PathBuf, FileChangeType)]) {
+        for client in self.clients.values().flatten() {
+            if !client.state.lock().is_initialized {
+                continue;
+            }
+            let changes = client.watched_file_changes(events);
+            if !changes.is_empty() {
+                client.send_did_change_watched_files(changes);
+            }
+        }
+    }
+
+    /// The diagnostics of every file in the workspace, merged over the
+    /// servers that published them.
+    pub fn get_all_diagnostics(&self, id: RequestId) {
+        let diagnostics: HashMap<PathBuf, Vec<Diagnostic>> = self
+            .diagnostics
+            .lock()
+            .iter()
+            .filter_map(|(uri, servers)| {
+                let path = uri.to_file_path().ok()?;
+                Some((path, servers.values().flatten().cloned().collect()))
+            })
+            .collect();
+        self.dispatcher.as_ref().unwrap().respond(
+            id,
+            serde_json::to_value(diagnostics).map_err(|e| anyhow!(e)),
+        );
+    }
+
+    /// Replaces the diagnostics of the last run of a task with `published`,
+    /// by file.
+    pub fn publish_task_diagnostics(
         &self,
-        document_uri: Url,
-        position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = SignatureHelpParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            context: None,
+        task: &str,
+        published: HashMap<PathBuf, Vec<Diagnostic>>,
+    ) {
+        let source = DiagnosticSource::Task(task.to_string());
+        let mut published: HashMap<Url, Vec<Diagnostic>> = published
+            .into_iter()
+            .filter_map(|(path, diagnostics)| {
+                Some((Url::from_file_path(path).ok()?, diagnostics))
+            })
+            .collect();
+        let changed: Vec<(Url, Vec<Diagnostic>)> = {
+            let mut cache = self.diagnostics.lock();
+            // the files the last run had diagnostics in are cleared
+            for (uri, sources) in cache.iter() {
+                if sources.contains_key(&source) && !published.contains_key(uri) {
+                    published.insert(uri.clone(), Vec::new());
+                }
+            }
+            published
+                .into_iter()
+                .map(|(uri, diagnostics)| {
+                    let sources = cache.entry(uri.clone()).or_default();
+                    sources.insert(source.clone(), diagnostics);
+                    let merged = sources.values().flatten().cloned().collect();
+                    if sources.values().all(|diagnostics| diagnostics.is_empty()) {
+                        cache.remove(&uri);
+                    }
+                    (uri, merged)
+                })
+                .collect()
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/signatureHelp", params, Box::new(cb));
+        let dispatcher = self.dispatcher.as_ref().unwrap();
+        for (uri, diagnostics) in changed {
+            send_diagnostics(
+                dispatcher,
+                PublishDiagnosticsParams {
+                    uri,
+                    diagnostics,
+                    version: None,
+                },
+            );
+        }
+    }
+
+    /// Forgets the diagnostics of files that were deleted, or that were in
+    /// a deleted directory, and tells the editor they're gone.
+    pub fn evict_diagnostics(&self, removed: &[PathBuf]) {
+        let evicted: Vec<Url> = {
+            let mut cache = self.diagnostics.lock();
+            let evicted: Vec<Url> = cache
+                .keys()
+                .filter(|uri| {
+                    uri.to_file_path()
+                        .map(|path| removed.iter().any(|r| path.starts_with(r)))
+                        .unwrap_or(false)
+                })
+                .cloned()
+                .collect();
+            for uri in evicted.iter() {
+                cache.remove(uri);
+            }
+            evicted
+        };
+        let dispatcher = self.dispatcher.as_ref().unwrap();
+        for uri in evicted {
+            if let Ok(path) = uri.to_file_path() {
+                dispatcher.send_notification(
+                    "diagnostics_changed",
+                    json!({
+                        "path": path,
+                        "count_by_severity": DiagnosticCounts::default(),
+                    }),
+                );
+            }
+            dispatcher.send_notification(
+                "publish_diagnostics",
+                json!({
+                    "diagnostics": PublishDiagnosticsParams {
+                        uri,
+                        diagnostics: Vec::new(),
+                        version: None,
+                    },
+                }),
+            );
+        }
+    }
+
+    /// Re-reads the settings and sends them to every running server
+    /// with `workspace/didChangeConfiguration`.
+    pub fn configuration_changed(&self) {
+        for client in self.clients.values().flatten() {
+            let settings = lsp_settings(&client.options, client.name());
+            *client.settings.lock() = settings.clone();
+            *client.timeouts.lock() = request_timeouts();
+            if client.state.lock().is_initialized {
+                client.send_did_change_configuration(settings);
+            }
+        }
+    }
+
+    /// Gives up on the requests about where the cursor was.
+    pub fn cursor_moved(&self, buffer: &Buffer, position: Position) {
+        let uri = match Url::from_file_path(&buffer.path) {
+            Ok(uri) => uri,
+            Err(_) => return,
+        };
+        for client in self.buffer_clients(buffer) {
+            client.mark_stale_requests(
+                &uri,
+                STALE_AFTER_CURSOR_MOVE,
+                Some(position),
+            );
+        }
+    }
+
+    pub fn update(
+        &self,
+        buffer: &Buffer,
+        content_change: &TextDocumentContentChangeEvent,
+        rev: u64,
+    ) {
+        for client in self.buffer_clients(buffer) {
+            client.update(buffer, content_change, rev);
+        }
+        self.call_hierarchy
+            .lock()
+            .items
+            .retain(|_, (buffer_id, _, _)| *buffer_id != buffer.id);
+        self.selection_ranges.lock().remove(&buffer.id);
     }
+}
+
+impl LspClient {
+    pub fn new(
+        id: LspServerId,
+        language_id: String,
+        exec_path: &str,
+        root: Option<PathBuf>,
+        options: Option<Value>,
+        plugin: Option<PluginName>,
+        diagnostics: Arc<Mutex<DiagnosticsCache>>,
+        capabilities: Arc<Mutex<CapabilitiesCache>>,
+        dispatcher: Dispatcher,
+    ) -> Result<Arc<LspClient>> {
+        let LspConnection {
+            transport,
+            mut reader,
+            writer,
+            stderr,
+        } = LspConnection::open(exec_path)?;
+
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        if let Some(stderr) = stderr {
+            let local_stderr_tail = stderr_tail.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => return,
+                    };
+                    let mut tail = local_stderr_tail.lock();
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            });
+        }
+
+        let settings = Mutex::new(lsp_settings(&options, server_name(exec_path)));
+        let lsp_client = Arc::new(LspClient {
+            id,
+            dispatcher,
+            language_id,
+            exec_path: exec_path.to_string(),
+            root,
+            options,
+            settings,
+            timeouts: Mutex::new(request_timeouts()),
+            plugin,
+            started: Instant::now(),
+            stderr_tail,
+            diagnostics,
+            capabilities,
+            registered_methods: Mutex::new(HashMap::new()),
+            progress: Arc::new(Mutex::new(HashMap::new())),
+            inlay_hints: Mutex::new(HashMap::new()),
+            semantic_tokens: Mutex::new(HashMap::new()),
+            watched_files: Mutex::new(HashMap::new()),
+            state: Arc::new(Mutex::new(LspState {
+                next_id: 0,
+                writer,
+                transport,
+                stopped: false,
+                pending: HashMap::new(),
+                server_capabilities: None,
+                opened_documents: HashMap::new(),
+                is_initialized: false,
+                inlay_hint_provider: false,
+            })),
+        });
+
+        let local_lsp_client = lsp_client.clone();
+        thread::spawn(move || loop {
+            match read_message(&mut reader) {
+                Ok(message_str) => {
+                    local_lsp_client.handle_message(message_str.as_ref());
+                }
+                Err(err) => {
+                    eprintln!("lsp read Error occurred {:?}", err);
+                    local_lsp_client.handle_exit();
+                    return;
+                }
+            };
+        });
+
+        let local_lsp_client = lsp_client.clone();
+        thread::spawn(move || loop {
+            thread::sleep(DEADLINE_CHECK_INTERVAL);
+            if local_lsp_client.state.lock().stopped {
+                return;
+            }
+            local_lsp_client.cancel_expired_requests();
+        });
+
+        Ok(lsp_client)
+    }
+
+    /// The file name of the server's executable, as in `rust-analyzer`.
+    pub fn name(&self) -> &str {
+        server_name(&self.exec_path)
+    }
+
+    /// Whether the server is for the workspace folder `root` of a buffer.
+    fn serves(&self, root: Option<&PathBuf>) -> bool {
+        match self.root.as_ref() {
+            Some(own) => root == Some(own),
+            None => true,
+        }
+    }
+
+    fn has_capability(
+        &self,
+        capability: impl Fn(&ServerCapabilities) -> bool,
+    ) -> bool {
+        self.state
+            .lock()
+            .server_capabilities
+            .as_ref()
+            .map(capability)
+            .unwrap_or(false)
+    }
+
+    pub fn stop(&self) {
+        self.withdraw_capabilities();
+        let mut state = self.state.lock();
+        state.stopped = true;
+        state.transport.close();
+    }
+
+    /// Called when the server's output closes. Unless the server was
+    /// stopped on purpose, it's removed from the catalog, the plugin that
+    /// started it gets its exit code and the tail of its stderr, and it's
+    /// respawned with exponential backoff up to `MAX_RESPAWN_ATTEMPTS`
+    /// times.
+    fn handle_exit(&self) {
+        let (code, pending) = {
+            let mut state = self.state.lock();
+            let pending: Vec<PendingRequest> =
+                state.pending.drain().map(|(_, request)| request).collect();
+            let code = if state.stopped {
+                None
+            } else {
+                state.stopped = true;
+                Some(state.transport.exit_code())
+            };
+            (code, pending)
+        };
+        // the answers died with the server, and the ids start over with a
+        // respawned one
+        for request in pending {
+            request.callback.call(
+                self,
+                Err(anyhow!("{} exited before answering", self.name())),
+            );
+        }
+        let code = match code {
+            Some(code) => code,
+            None => return,
+        };
+        self.withdraw_capabilities();
+        let progress: Vec<(WorkProgress, Instant)> = self
+            .progress
+            .lock()
+            .drain()
+            .map(|(_, entry)| entry)
+            .collect();
+        for (mut progress, _) in progress {
+            progress.done = true;
+            self.send_progress(&progress);
+        }
+
+        let attempt = {
+            let mut lsp = self.dispatcher.lsp.lock();
+            if lsp.remove_server(self.id).is_none() {
+                // replaced by another server already
+                return;
+            }
+            lsp.server_crashed(self)
+        };
+
+        if let Some(plugin) = self.plugin.as_ref() {
+            let stderr_tail: Vec<String> =
+                self.stderr_tail.lock().iter().cloned().collect();
+            self.dispatcher.notify_plugin(
+                plugin,
+                "lsp_server_exited",
+                json!({
+                    "id": self.id,
+                    "language_id": self.language_id,
+                    "code": code,
+                    "stderr_tail": stderr_tail.join("\n"),
+                    "restarting": attempt.is_some(),
+                }),
+            );
+        }
+
+        let name = self.name();
+        match attempt {
+            Some(attempt) => {
+                self.dispatcher.lsp_server_status(
+                    &self.language_id,
+                    "restarting",
+                    format!(
+                        "{} crashed (restarting {}/{})",
+                        name, attempt, MAX_RESPAWN_ATTEMPTS
+                    ),
+                );
+                let dispatcher = self.dispatcher.clone();
+                let language_id = self.language_id.clone();
+                let id = self.id;
+                thread::spawn(move || {
+                    thread::sleep(RESPAWN_BACKOFF * 2u32.pow(attempt as u32 - 1));
+                    dispatcher.respawn_lsp_server(&language_id, id);
+                });
+            }
+            None => {
+                self.dispatcher.lsp_server_status(
+                    &self.language_id,
+                    "failed",
+                    format!(
+                        "{} crashed (gave up after {} restarts)",
+                        name, MAX_RESPAWN_ATTEMPTS
+                    ),
+                );
+            }
+        }
+    }
+
+    pub fn get_uri(&self, buffer: &Buffer) -> Url {
+        let exits = {
+            let state = self.state.lock();
+            state.opened_documents.contains_key(&buffer.id)
+        };
+        if !exits {
+            let document_uri = Url::from_file_path(&buffer.path).unwrap();
+            self.send_did_open(
+                &buffer.id,
+                document_uri,
+                &buffer.language_id,
+                buffer.get_document(),
+                buffer.rev,
+            );
+        }
+        self.state
+            .lock()
+            .opened_documents
+            .get(&buffer.id)
+            .unwrap()
+            .clone()
+    }
+
+    pub fn handle_message(self: &Arc<Self>, message: &str) {
+        match JsonRpc::parse(message) {
+            Ok(value @ JsonRpc::Request(_))
+                if value.get_method() == Some("workspace/applyEdit") =>
+            {
+                self.apply_workspace_edit(
+                    value.get_id().unwrap(),
+                    value.get_params(),
+                );
+            }
+            Ok(value @ JsonRpc::Request(_)) => {
+                let result = self
+                    .handle_request(value.get_method().unwrap(), value.get_params());
+                self.send_response(value.get_id().unwrap(), result);
+            }
+            Ok(value @ JsonRpc::Notification(_)) => {
+                self.handle_notification(
+                    value.get_method().unwrap(),
+                    value.get_params().unwrap(),
+                );
+            }
+            Ok(value @ JsonRpc::Success(_)) => {
+                let id = number_from_id(&value.get_id().unwrap());
+                let result = value.get_result().unwrap();
+                self.handle_response(id, Ok(result.clone()));
+            }
+            Ok(value @ JsonRpc::Error(_)) => {
+                let id = number_from_id(&value.get_id().unwrap());
+                let error = value.get_error().unwrap();
+                self.handle_response(id, Err(anyhow!("{}", error)));
+            }
+            Err(err) => {
+                eprintln!("Error in parsing incoming string: {} \n {}", err, message)
+            }
+        }
+    }
+
+    /// Answers a request from the server. Returns `None` for methods we
+    /// don't implement.
+    fn handle_request(
+        &self,
+        method: &str,
+        params: Option<Params>,
+    ) -> Option<Result<Value>> {
+        match method {
+            "workspace/configuration" => Some(self.workspace_configuration(params)),
+            // tokens are tracked from their `begin` on
+            "window/workDoneProgress/create" => Some(Ok(Value::Null)),
+            "client/registerCapability" => Some(self.register_capability(params)),
+            "client/unregisterCapability" => {
+                Some(self.unregister_capability(params))
+            }
+            "workspace/inlayHint/refresh" => {
+                self.inlay_hints.lock().clear();
+                self.dispatcher
+                    .send_notification("inlay_hints_refresh", json!({}));
+                Some(Ok(Value::Null))
+            }
+            _ => None,
+        }
+    }
+
+    /// Only file watchers are registered dynamically, other registrations
+    /// are accepted and ignored.
+    fn register_capability(&self, params: Option<Params>) -> Result<Value> {
+        let params: RegistrationParams =
+            serde_json::from_value(serde_json::to_value(params)?)?;
+        for registration in params.registrations {
+            if registration.method != "workspace/didChangeWatchedFiles" {
+                self.registered_methods
+                    .lock()
+                    .insert(registration.id, registration.method);
+                continue;
+            }
+            let options: DidChangeWatchedFilesRegistrationOptions =
+                serde_json::from_value(
+                    registration.register_options.unwrap_or(Value::Null),
+                )?;
+            let watchers = options
+                .watchers
+                .iter()
+                .map(|watcher| {
+                    Ok(WatchedFiles {
+                        glob: glob_to_regex(&watcher.glob_pattern)?,
+                        kind: watcher.kind.unwrap_or(
+                            WatchKind::Create
+                                | WatchKind::Change
+                                | WatchKind::Delete,
+                        ),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.watched_files.lock().insert(registration.id, watchers);
+        }
+        self.publish_capabilities();
+        Ok(Value::Null)
+    }
+
+    fn unregister_capability(&self, params: Option<Params>) -> Result<Value> {
+        let params: UnregistrationParams =
+            serde_json::from_value(serde_json::to_value(params)?)?;
+        {
+            let mut watched_files = self.watched_files.lock();
+            let mut registered_methods = self.registered_methods.lock();
+            for unregistration in params.unregisterations {
+                watched_files.remove(&unregistration.id);
+                registered_methods.remove(&unregistration.id);
+            }
+        }
+        self.publish_capabilities();
+        Ok(Value::Null)
+    }
+
+    /// Sends the core what the servers of the language support now, once
+    /// this one is initialized.
+    fn publish_capabilities(&self) {
+        let capabilities = {
+            let state = self.state.lock();
+            let registered: HashSet<String> =
+                self.registered_methods.lock().values().cloned().collect();
+            match state.server_capabilities.as_ref() {
+                Some(capabilities) if state.is_initialized => LspCapabilities::new(
+                    capabilities,
+                    state.inlay_hint_provider,
+                    &registered,
+                ),
+                _ => return,
+            }
+        };
+        let caps = {
+            let mut cache = self.capabilities.lock();
+            let servers = cache.entry(self.language_id.clone()).or_default();
+            servers.insert(self.id, capabilities);
+            LspCapabilities::union(servers.values())
+        };
+        self.send_capabilities(caps);
+    }
+
+    /// Takes the server's capabilities out of its language's, once it's
+    /// gone.
+    fn withdraw_capabilities(&self) {
+        let caps = {
+            let mut cache = self.capabilities.lock();
+            let servers = match cache.get_mut(&self.language_id) {
+                Some(servers) => servers,
+                None => return,
+            };
+            if servers.remove(&self.id).is_none() {
+                return;
+            }
+            let caps = LspCapabilities::union(servers.values());
+            if servers.is_empty() {
+                cache.remove(&self.language_id);
+            }
+            caps
+        };
+        self.send_capabilities(caps);
+    }
+
+    fn send_capabilities(&self, caps: LspCapabilities) {
+        self.dispatcher.send_notification(
+            "server_capabilities",
+            json!({
+                "language_id": self.language_id,
+                "caps": caps,
+            }),
+        );
+    }
+
+    /// The events on files one of the server's watchers asked for.
+    fn watched_file_changes(
+        &self,
+        events: &[(PathBuf, FileChangeType)],
+    ) -> Vec<FileEvent> {
+        let watched_files = self.watched_files.lock();
+        events
+            .iter()
+            .filter(|(path, change_type)| {
+                let path = path.to_string_lossy().replace('\\', "/");
+                watched_files
+                    .values()
+                    .flatten()
+                    .any(|watcher| watcher.matches(&path, *change_type))
+            })
+            .filter_map(|(path, change_type)| {
+                Url::from_file_path(path)
+                    .ok()
+                    .map(|uri| FileEvent::new(uri, *change_type))
+            })
+            .collect()
+    }
+
+    /// The edit is applied by the core, which owns the open buffers, and the
+    /// server is told whether it was once the core has answered, on a thread
+    /// of its own so the server's other messages aren't held up meanwhile.
+    fn apply_workspace_edit(self: &Arc<Self>, id: Id, params: Option<Params>) {
+        let client = self.clone();
+        thread::spawn(move || {
+            let result = client.request_workspace_edit(params);
+            client.send_response(id, Some(result));
+        });
+    }
+
+    fn request_workspace_edit(&self, params: Option<Params>) -> Result<Value> {
+        let params: ApplyWorkspaceEditParams =
+            serde_json::from_value(serde_json::to_value(params)?)?;
+        let response = match self.dispatcher.request_core(
+            "apply_workspace_edit",
+            json!({
+                "edit": params.edit,
+            }),
+            APPLY_EDIT_TIMEOUT,
+        ) {
+            Ok(value) => serde_json::from_value(value)?,
+            Err(e) => ApplyWorkspaceEditResponse {
+                applied: false,
+                failure_reason: Some(e.to_string()),
+                failed_change: None,
+            },
+        };
+        Ok(serde_json::to_value(response)?)
+    }
+
+    /// Takes a `full`, `range` or `full/delta` answer to the flat token
+    /// data it stands for, keeping it for the next delta.
+    fn update_semantic_tokens(
+        &self,
+        buffer_id: BufferId,
+        value: Value,
+    ) -> Result<Vec<u32>> {
+        let result: Option<SemanticTokensFullDeltaResult> =
+            serde_json::from_value(value)?;
+        let mut semantic_tokens = self.semantic_tokens.lock();
+        let previous = semantic_tokens.remove(&buffer_id);
+        let (result_id, data) = match result {
+            Some(SemanticTokensFullDeltaResult::Tokens(tokens)) => {
+                (tokens.result_id, flatten_semantic_tokens(&tokens.data))
+            }
+            Some(SemanticTokensFullDeltaResult::TokensDelta(tokens_delta)) => {
+                let (_, mut data) = previous.ok_or_else(|| {
+                    anyhow!("semantic tokens delta without a base")
+                })?;
+                apply_semantic_tokens_edits(&mut data, tokens_delta.edits);
+                (tokens_delta.result_id, data)
+            }
+            Some(SemanticTokensFullDeltaResult::PartialTokensDelta { edits }) => {
+                let (result_id, mut data) = previous.ok_or_else(|| {
+                    anyhow!("semantic tokens delta without a base")
+                })?;
+                apply_semantic_tokens_edits(&mut data, edits);
+                (Some(result_id), data)
+            }
+            None => (None, Vec::new()),
+        };
+        if let Some(result_id) = result_id {
+            semantic_tokens.insert(buffer_id, (result_id, data.clone()));
+        }
+        Ok(data)
+    }
+
+    fn workspace_configuration(&self, params: Option<Params>) -> Result<Value> {
+        let params: ConfigurationParams =
+            serde_json::from_value(serde_json::to_value(params)?)?;
+        let settings = self.settings.lock();
+        Ok(Value::Array(
+            params
+                .items
+                .iter()
+                .map(|item| {
+                    configuration_section(
+                        &settings,
+                        item.section.as_deref(),
+                        self.name(),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    fn send_response(&self, id: Id, result: Option<Result<Value>>) {
+        let mut response = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+        });
+        match result {
+            Some(Ok(value)) => response["result"] = value,
+            Some(Err(e)) => {
+                response["error"] = json!({
+                    "code": -32602,
+                    "message": format!("{}", e),
+                })
+            }
+            None => {
+                response["error"] = json!({
+                    "code": -32601,
+                    "message": "method not found",
+                })
+            }
+        }
+        self.send_rpc(&response);
+    }
+
+    pub fn handle_notification(&self, method: &str, params: Params) {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                let params: PublishDiagnosticsParams =
+                    match serde_json::to_value(params)
+                        .and_then(serde_json::from_value)
+                    {
+                        Ok(params) => params,
+                        Err(e) => {
+                            eprintln!("publish diagnostics err {}", e);
+                            return;
+                        }
+                    };
+                let diagnostics = self.merge_diagnostics(params);
+                send_diagnostics(&self.dispatcher, diagnostics);
+            }
+            "$/progress" => {
+                match serde_json::to_value(params).and_then(serde_json::from_value) {
+                    Ok(params) => self.handle_progress(params),
+                    Err(e) => eprintln!("progress err {}", e),
+                }
+            }
+            _ => eprintln!("{} {:?}", method, params),
+        }
+    }
+
+    fn handle_progress(&self, params: ProgressParams) {
+        let token = match params.token {
+            NumberOrString::Number(number) => number.to_string(),
+            NumberOrString::String(string) => string,
+        };
+        let ProgressParamsValue::WorkDone(progress) = params.value;
+        let progress = {
+            let mut entries = self.progress.lock();
+            match progress {
+                WorkDoneProgress::Begin(begin) => {
+                    let progress = WorkProgress {
+                        server_id: self.id,
+                        server: self.name().to_string(),
+                        token: token.clone(),
+                        title: begin.title,
+                        message: begin.message,
+                        percentage: begin.percentage,
+                        done: false,
+                    };
+                    entries
+                        .insert(token.clone(), (progress.clone(), Instant::now()));
+                    self.expire_progress(token);
+                    progress
+                }
+                WorkDoneProgress::Report(report) => {
+                    let (progress, updated) = match entries.get_mut(&token) {
+                        Some(entry) => entry,
+                        None => return,
+                    };
+                    if report.message.is_some() {
+                        progress.message = report.message;
+                    }
+                    if report.percentage.is_some() {
+                        progress.percentage = report.percentage;
+                    }
+                    *updated = Instant::now();
+                    progress.clone()
+                }
+                WorkDoneProgress::End(end) => {
+                    let mut progress = match entries.remove(&token) {
+                        Some((progress, _)) => progress,
+                        None => return,
+                    };
+                    if end.message.is_some() {
+                        progress.message = end.message;
+                    }
+                    progress.done = true;
+                    progress
+                }
+            }
+        };
+        self.send_progress(&progress);
+    }
+
+    /// Ends a progress token once it has been quiet for `PROGRESS_TIMEOUT`,
+    /// for servers that never send `end`.
+    fn expire_progress(&self, token: String) {
+        let entries = self.progress.clone();
+        let dispatcher = self.dispatcher.clone();
+        thread::spawn(move || loop {
+            thread::sleep(PROGRESS_TIMEOUT);
+            let expired = {
+                let mut entries = entries.lock();
+                match entries.get(&token) {
+                    None => return,
+                    Some((_, updated)) if updated.elapsed() < PROGRESS_TIMEOUT => {
+                        continue
+                    }
+                    Some(_) => entries.remove(&token),
+                }
+            };
+            if let Some((mut progress, _)) = expired {
+                progress.done = true;
+                dispatcher.send_notification(
+                    "work_progress",
+                    json!({
+                        "progress": progress,
+                    }),
+                );
+            }
+            return;
+        });
+    }
+
+    fn send_progress(&self, progress: &WorkProgress) {
+        self.dispatcher.send_notification(
+            "work_progress",
+            json!({
+                "progress": progress,
+            }),
+        );
+    }
+
+    /// Replaces this server's diagnostics for the document and returns the
+    /// union with the other servers' ones. Diagnostics without a source
+    /// get the server's name.
+    fn merge_diagnostics(
+        &self,
+        mut params: PublishDiagnosticsParams,
+    ) -> PublishDiagnosticsParams {
+        for diagnostic in params.diagnostics.iter_mut() {
+            if diagnostic.source.is_none() {
+                diagnostic.source = Some(self.name().to_string());
+            }
+        }
+        let mut cache = self.diagnostics.lock();
+        let servers = cache.entry(params.uri.clone()).or_default();
+        servers.insert(DiagnosticSource::Server(self.id), params.diagnostics);
+        let diagnostics = servers.values().flatten().cloned().collect();
+        if servers.values().all(|diagnostics| diagnostics.is_empty()) {
+            cache.remove(&params.uri);
+        }
+        PublishDiagnosticsParams {
+            uri: params.uri,
+            diagnostics,
+            version: params.version,
+        }
+    }
+
+    pub fn handle_response(&self, id: u64, result: Result<Value>) {
+        let request = self.state.lock().pending.remove(&id);
+        // missing if the request was cancelled
+        if let Some(request) = request {
+            request.callback.call(self, result);
+        }
+    }
+
+    /// Sends `$/cancelRequest` for a request that's still waiting for its
+    /// response and calls its callback with an error right away.
+    pub fn cancel_request(&self, id: u64) {
+        let request = self.state.lock().pending.remove(&id);
+        if let Some(request) = request {
+            self.send_notification(
+                "$/cancelRequest",
+                Params::from(json!({ "id": id })),
+            );
+            request
+                .callback
+                .call(self, Err(anyhow!("request cancelled")));
+        }
+    }
+
+    /// Cancels the requests past their deadline with an `LspTimeout`, and
+    /// the ones marked stale.
+    fn cancel_expired_requests(&self) {
+        let now = Instant::now();
+        let expired: Vec<(u64, PendingRequest)> = {
+            let mut state = self.state.lock();
+            let ids: Vec<u64> = state
+                .pending
+                .iter()
+                .filter(|(_, request)| {
+                    request.stale
+                        || request
+                            .deadline
+                            .map(|deadline| deadline <= now)
+                            .unwrap_or(false)
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| Some((id, state.pending.remove(&id)?)))
+                .collect()
+        };
+        for (id, request) in expired {
+            self.send_notification(
+                "$/cancelRequest",
+                Params::from(json!({ "id": id })),
+            );
+            let error = if request.stale {
+                anyhow!("{} cancelled, its answer is out of date", request.method)
+            } else {
+                LspTimeout {
+                    method: request.method,
+                }
+                .into()
+            };
+            request.callback.call(self, Err(error));
+        }
+    }
+
+    /// Marks the requests of `methods` about the document as stale, or only
+    /// those about another position than `position` if given. They are
+    /// cancelled by the next deadline check, as their callbacks may take
+    /// the locks held while this is called.
+    fn mark_stale_requests(
+        &self,
+        uri: &Url,
+        methods: &[&str],
+        position: Option<Position>,
+    ) {
+        let mut state = self.state.lock();
+        for request in state.pending.values_mut() {
+            if request.uri.as_deref() == Some(uri.as_str())
+                && methods.contains(&request.method.as_str())
+                && (position.is_none() || request.position != position)
+            {
+                request.stale = true;
+            }
+        }
+    }
+
+    fn request_deadline(&self, method: &str) -> Option<Instant> {
+        let timeouts = self.timeouts.lock();
+        let timeout = timeouts
+            .get(method)
+            .or_else(|| timeouts.get("default"))
+            .cloned()?;
+        if timeout == 0 {
+            return None;
+        }
+        Some(Instant::now() + Duration::from_millis(timeout))
+    }
+
+    pub fn write(&self, msg: &str) {
+        let mut state = self.state.lock();
+        let result = state.writer.write_all(msg.as_bytes());
+        // a server that went away is handled by the reader thread
+        if let Err(e) = result.and_then(|_| state.writer.flush()) {
+            eprintln!("lsp write error {}", e);
+        }
+    }
+
+    fn send_rpc(&self, value: &Value) {
+        let rpc = match prepare_lsp_json(value) {
+            Ok(r) => r,
+            Err(err) => panic!("Encoding Error {:?}", err),
+        };
+
+        self.write(rpc.as_ref());
+    }
+
+    pub fn send_notification(&self, method: &str, params: Params) {
+        let notification = JsonRpc::notification_with_params(method, params);
+        let res = to_value(&notification).unwrap();
+        self.send_rpc(&res);
+    }
+
+    pub fn send_request(
+        &self,
+        method: &str,
+        params: Params,
+        completion: Callback,
+    ) -> u64 {
+        let mut pending = PendingRequest::new(method, &params, completion);
+        pending.deadline = self.request_deadline(method);
+        let (id, request) = {
+            let mut state = self.state.lock();
+            let next_id = state.next_id;
+            state.pending.insert(next_id, pending);
+            state.next_id += 1;
+
+            (
+                next_id,
+                JsonRpc::request_with_params(
+                    Id::Num(next_id as i64),
+                    method,
+                    params,
+                ),
+            )
+        };
+
+        self.send_rpc(&to_value(&request).unwrap());
+        id
+    }
+
+    /// `version` is the buffer's rev, which its `didChange` versions
+    /// follow and the versions of a workspace edit are checked against.
+    pub fn send_did_open(
+        &self,
+        buffer_id: &BufferId,
+        document_uri: Url,
+        language_id: &str,
+        document_text: String,
+        version: u64,
+    ) {
+        let is_initialized = {
+            let mut state = self.state.lock();
+            state
+                .opened_documents
+                .insert(buffer_id.clone(), document_uri.clone());
+            state.is_initialized
+        };
+
+        if !is_initialized {
+            let root = self
+                .root
+                .clone()
+                .unwrap_or_else(|| self.dispatcher.workspace.lock().clone());
+            let root_url = Url::from_directory_path(&root).unwrap();
+            let folders = match self.root.as_ref() {
+                Some(root) => vec![root.clone()],
+                None => self.dispatcher.workspace_folders.lock().clone(),
+            };
+            let folders = folders
+                .iter()
+                .filter_map(|path| workspace_folder(path))
+                .collect();
+            let (sender, receiver) = channel();
+            self.send_initialize(
+                Some(root_url),
+                folders,
+                move |lsp_client, result| {
+                    if let Ok(result) = result {
+                        {
+                            let inlay_hint_provider = !matches!(
+                                result["capabilities"].get("inlayHintProvider"),
+                                None | Some(Value::Null) | Some(Value::Bool(false))
+                            );
+                            let init_result: InitializeResult =
+                                serde_json::from_value(result).unwrap();
+                            let mut state = lsp_client.state.lock();
+                            state.server_capabilities =
+                                Some(init_result.capabilities);
+                            state.inlay_hint_provider = inlay_hint_provider;
+                            state.is_initialized = true;
+                        }
+                        lsp_client.send_initialized();
+                        lsp_client.publish_capabilities();
+                    }
+                    sender.send(true);
+                },
+            );
+            receiver.recv_timeout(Duration::from_millis(1000));
+        }
+
+        let text_document_did_open_params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                language_id: language_id.to_string(),
+                uri: document_uri,
+                version: version as i32,
+                text: document_text,
+            },
+        };
+        let params = Params::from(
+            serde_json::to_value(text_document_did_open_params).unwrap(),
+        );
+        self.send_notification("textDocument/didOpen", params);
+    }
+
+    pub fn send_did_close(&self, buffer_id: BufferId) {
+        let uri = match self.state.lock().opened_documents.remove(&buffer_id) {
+            Some(uri) => uri,
+            None => return,
+        };
+        let params = DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri },
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("textDocument/didClose", params);
+    }
+
+    pub fn send_will_save(&self, uri: Url) {
+        let params = WillSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri },
+            reason: TextDocumentSaveReason::Manual,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("textDocument/willSave", params);
+    }
+
+    pub fn request_will_save_wait_until<CB>(&self, uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = WillSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri },
+            reason: TextDocumentSaveReason::Manual,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/willSaveWaitUntil", params, Box::new(cb));
+    }
+
+    pub fn send_did_save(&self, uri: Url, text: Option<String>) {
+        let params = DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri },
+            text,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("textDocument/didSave", params);
+    }
+
+    pub fn send_did_change_configuration(&self, settings: Value) {
+        let params = DidChangeConfigurationParams { settings };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("workspace/didChangeConfiguration", params);
+    }
+
+    pub fn request_will_rename_files<CB>(&self, files: Vec<FileRename>, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = RenameFilesParams { files };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("workspace/willRenameFiles", params, Box::new(cb));
+    }
+
+    pub fn send_did_rename_files(&self, files: Vec<FileRename>) {
+        let params = RenameFilesParams { files };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("workspace/didRenameFiles", params);
+    }
+
+    pub fn send_did_change_watched_files(&self, changes: Vec<FileEvent>) {
+        let params = DidChangeWatchedFilesParams { changes };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("workspace/didChangeWatchedFiles", params);
+    }
+
+    pub fn send_did_change_workspace_folders(
+        &self,
+        event: WorkspaceFoldersChangeEvent,
+    ) {
+        let params = DidChangeWorkspaceFoldersParams { event };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("workspace/didChangeWorkspaceFolders", params);
+    }
+
+    pub fn send_initialized(&self) {
+        self.send_notification("initialized", Params::from(json!({})));
+    }
+
+    pub fn send_initialize<CB>(
+        &self,
+        root_uri: Option<Url>,
+        workspace_folders: Vec<WorkspaceFolder>,
+        on_init: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let client_capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                synchronization: Some(TextDocumentSyncClientCapabilities {
+                    dynamic_registration: Some(false),
+                    will_save: Some(true),
+                    will_save_wait_until: Some(true),
+                    did_save: Some(true),
+                }),
+                call_hierarchy: Some(CallHierarchyClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
+                selection_range: Some(SelectionRangeClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
+                folding_range: Some(FoldingRangeClientCapabilities {
+                    dynamic_registration: Some(false),
+                    line_folding_only: Some(true),
+                    ..Default::default()
+                }),
+                on_type_formatting: Some(
+                    DocumentOnTypeFormattingClientCapabilities {
+                        dynamic_registration: Some(false),
+                    },
+                ),
+                type_definition: Some(GotoCapability {
+                    link_support: Some(true),
+                    ..Default::default()
+                }),
+                declaration: Some(GotoCapability {
+                    link_support: Some(true),
+                    ..Default::default()
+                }),
+                completion: Some(CompletionClientCapabilities {
+                    completion_item: Some(CompletionItemCapability {
+                        snippet_support: Some(true),
+                        insert_replace_support: Some(true),
+                        resolve_support: Some(
+                            CompletionItemCapabilityResolveSupport {
+                                properties: vec![
+                                    "additionalTextEdits".to_string(),
+                                    "documentation".to_string(),
+                                    "detail".to_string(),
+                                ],
+                            },
+                        ),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                // signature_help: Some(SignatureHelpCapability {
+                //     signature_information: Some(SignatureInformationSettings {
+                //         parameter_information: Some(ParameterInformationSettings {
+                //             label_offset_support: Some(true),
+                //         }),
+                //         active_parameter_support: Some(true),
+                //         documentation_format: Some(vec![
+                //             MarkupKind::Markdown,
+                //             MarkupKind::PlainText,
+                //         ]),
+                //     }),
+                //     ..Default::default()
+                // }),
+                code_action: Some(CodeActionClientCapabilities {
+                    code_action_literal_support: Some(CodeActionLiteralSupport {
+                        code_action_kind: CodeActionKindLiteralSupport {
+                            value_set: vec![
+                                CodeActionKind::EMPTY.as_str().to_string(),
+                                CodeActionKind::QUICKFIX.as_str().to_string(),
+                                CodeActionKind::REFACTOR.as_str().to_string(),
+                                CodeActionKind::REFACTOR_EXTRACT
+                                    .as_str()
+                                    .to_string(),
+                                CodeActionKind::REFACTOR_INLINE.as_str().to_string(),
+                                CodeActionKind::REFACTOR_REWRITE
+                                    .as_str()
+                                    .to_string(),
+                                CodeActionKind::SOURCE.as_str().to_string(),
+                                CodeActionKind::SOURCE_ORGANIZE_IMPORTS
+                                    .as_str()
+                                    .to_string(),
+                            ],
+                        },
+                    }),
+                    data_support: Some(true),
+                    resolve_support: Some(CodeActionCapabilityResolveSupport {
+                        properties: vec!["edit".to_string()],
+                    }),
+                    ..Default::default()
+                }),
+                rename: Some(RenameClientCapabilities {
+                    prepare_support: Some(true),
+                    ..Default::default()
+                }),
+                semantic_tokens: Some(SemanticTokensClientCapabilities {
+                    requests: SemanticTokensClientCapabilitiesRequests {
+                        range: Some(true),
+                        full: Some(SemanticTokensFullOptions::Delta {
+                            delta: Some(true),
+                        }),
+                    },
+                    token_types: vec![
+                        SemanticTokenType::NAMESPACE,
+                        SemanticTokenType::TYPE,
+                        SemanticTokenType::CLASS,
+                        SemanticTokenType::ENUM,
+                        SemanticTokenType::INTERFACE,
+                        SemanticTokenType::STRUCT,
+                        SemanticTokenType::TYPE_PARAMETER,
+                        SemanticTokenType::PARAMETER,
+                        SemanticTokenType::VARIABLE,
+                        SemanticTokenType::PROPERTY,
+                        SemanticTokenType::ENUM_MEMBER,
+                        SemanticTokenType::EVENT,
+                        SemanticTokenType::FUNCTION,
+                        SemanticTokenType::METHOD,
+                        SemanticTokenType::MACRO,
+                        SemanticTokenType::KEYWORD,
+                        SemanticTokenType::MODIFIER,
+                        SemanticTokenType::COMMENT,
+                        SemanticTokenType::STRING,
+                        SemanticTokenType::NUMBER,
+                        SemanticTokenType::REGEXP,
+                        SemanticTokenType::OPERATOR,
+                    ],
+                    token_modifiers: vec![
+                        SemanticTokenModifier::DECLARATION,
+                        SemanticTokenModifier::DEFINITION,
+                        SemanticTokenModifier::READONLY,
+                        SemanticTokenModifier::STATIC,
+                        SemanticTokenModifier::DEPRECATED,
+                        SemanticTokenModifier::ABSTRACT,
+                        SemanticTokenModifier::ASYNC,
+                        SemanticTokenModifier::MODIFICATION,
+                        SemanticTokenModifier::DOCUMENTATION,
+                        SemanticTokenModifier::DEFAULT_LIBRARY,
+                    ],
+                    formats: vec![TokenFormat::RELATIVE],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            window: Some(WindowClientCapabilities {
+                work_done_progress: Some(true),
+                ..Default::default()
+            }),
+            workspace: Some(WorkspaceClientCapabilities {
+                apply_edit: Some(true),
+                workspace_edit: Some(WorkspaceEditClientCapabilities {
+                    document_changes: Some(true),
+                    // files are only edited, and all of them or none
+                    resource_operations: Some(Vec::new()),
+                    failure_handling: Some(FailureHandlingKind::Transactional),
+                    ..Default::default()
+                }),
+                execute_command: Some(DynamicRegistrationClientCapabilities {
+                    dynamic_registration: Some(false),
+                }),
+                did_change_watched_files: Some(
+                    DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(true),
+                    },
+                ),
+                file_operations: Some(WorkspaceFileOperationsClientCapabilities {
+                    will_rename: Some(true),
+                    did_rename: Some(true),
+                    ..Default::default()
+                }),
+                configuration: Some(true),
+                workspace_folders: Some(true),
+                did_change_configuration: Some(
+                    DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(false),
+                    },
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let init_params = InitializeParams {
+            process_id: Some(u32::from(process::id())),
+            root_uri,
+            initialization_options: self.options.clone(),
+            capabilities: client_capabilities,
+            trace: Some(TraceOption::Verbose),
+            workspace_folders: Some(workspace_folders),
+            client_info: None,
+            root_path: None,
+            locale: None,
+        };
+
+        let mut params = serde_json::to_value(init_params).unwrap();
+        params["capabilities"]["textDocument"]["inlayHint"] = json!({});
+        params["capabilities"]["workspace"]["inlayHint"] =
+            json!({ "refreshSupport": true });
+        let params = Params::from(params);
+        self.send_request("initialize", params, Box::new(on_init));
+    }
+
+    pub fn request_document_symbols<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentSymbolParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/documentSymbol", params, Box::new(cb));
+    }
+
+    pub fn request_document_formatting<CB>(
+        &self,
+        document_uri: Url,
+        options: FormattingOptions,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentFormattingParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            options,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/formatting", params, Box::new(cb));
+    }
+
+    pub fn request_range_formatting<CB>(
+        &self,
+        document_uri: Url,
+        range: Range,
+        options: FormattingOptions,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentRangeFormattingParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            range,
+            options,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/rangeFormatting", params, Box::new(cb));
+    }
+
+    pub fn request_on_type_formatting<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        ch: String,
+        options: FormattingOptions,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentOnTypeFormattingParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            ch,
+            options,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/onTypeFormatting", params, Box::new(cb));
+    }
+
+    pub fn request_semantic_tokens<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SemanticTokensParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/semanticTokens/full", params, Box::new(cb));
+    }
+
+    pub fn request_semantic_tokens_delta<CB>(
+        &self,
+        document_uri: Url,
+        previous_result_id: String,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SemanticTokensDeltaParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            previous_result_id,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request(
+            "textDocument/semanticTokens/full/delta",
+            params,
+            Box::new(cb),
+        );
+    }
+
+    pub fn request_semantic_tokens_range<CB>(
+        &self,
+        document_uri: Url,
+        range: Range,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SemanticTokensRangeParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            range,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/semanticTokens/range", params, Box::new(cb));
+    }
+
+    pub fn request_code_actions<CB>(
+        &self,
+        document_uri: Url,
+        range: Range,
+        diagnostics: Vec<Diagnostic>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CodeActionParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            range,
+            context: CodeActionContext {
+                diagnostics,
+                only: None,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/codeAction", params, Box::new(cb));
+    }
+
+    pub fn request_code_action_resolve<CB>(&self, action: &CodeAction, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(action).unwrap());
+        self.send_request("codeAction/resolve", params, Box::new(cb));
+    }
+
+    pub fn request_execute_command<CB>(&self, command: &lsp_types::Command, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = ExecuteCommandParams {
+            command: command.command.clone(),
+            arguments: command.arguments.clone().unwrap_or_default(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("workspace/executeCommand", params, Box::new(cb));
+    }
+
+    pub fn request_references<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        include_declaration: bool,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = ReferenceParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+            context: ReferenceContext {
+                include_declaration,
+            },
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/references", params, Box::new(cb));
+    }
+
+    pub fn request_definition<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/definition", params, Box::new(cb));
+    }
+
+    pub fn request_type_definition<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoTypeDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/typeDefinition", params, Box::new(cb));
+    }
+
+    pub fn request_declaration<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoDeclarationParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/declaration", params, Box::new(cb));
+    }
+
+    pub fn request_prepare_call_hierarchy<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CallHierarchyPrepareParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/prepareCallHierarchy", params, Box::new(cb));
+    }
+
+    pub fn request_incoming_calls<CB>(&self, item: CallHierarchyItem, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CallHierarchyIncomingCallsParams {
+            item,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("callHierarchy/incomingCalls", params, Box::new(cb));
+    }
+
+    pub fn request_outgoing_calls<CB>(&self, item: CallHierarchyItem, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CallHierarchyOutgoingCallsParams {
+            item,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("callHierarchy/outgoingCalls", params, Box::new(cb));
+    }
+
+    pub fn request_document_highlight<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) -> u64
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentHighlightParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/documentHighlight", params, Box::new(cb))
+    }
+
+    pub fn request_selection_range<CB>(
+        &self,
+        document_uri: Url,
+        positions: Vec<Position>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SelectionRangeParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            positions,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/selectionRange", params, Box::new(cb));
+    }
+
+    pub fn request_folding_range<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = FoldingRangeParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/foldingRange", params, Box::new(cb));
+    }
+
+    pub fn request_document_links<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentLinkParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/documentLink", params, Box::new(cb));
+    }
+
+    pub fn request_resolve_document_link<CB>(&self, link: DocumentLink, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(link).unwrap());
+        self.send_request("documentLink/resolve", params, Box::new(cb));
+    }
+
+    pub fn request_implementation<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoImplementationParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/implementation", params, Box::new(cb));
+    }
+
+    pub fn request_completion<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        on_completion: CB,
+    ) -> u64
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let completion_params = CompletionParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+            context: None,
+        };
+        let params = Params::from(serde_json::to_value(completion_params).unwrap());
+        self.send_request("textDocument/completion", params, Box::new(on_completion))
+    }
+
+    pub fn completion_resolve<CB>(
+        &self,
+        completion_item: &CompletionItem,
+        on_result: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(completion_item).unwrap());
+        self.send_request("completionItem/resolve", params, Box::new(on_result));
+    }
+
+    pub fn request_hover<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) -> u64
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/hover", params, Box::new(cb))
+    }
+
+    pub fn request_workspace_symbols<CB>(&self, query: &str, cb: CB) -> u64
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = WorkspaceSymbolParams {
+            query: query.to_string(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("workspace/symbol", params, Box::new(cb))
+    }
+
+    pub fn request_prepare_rename<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            position,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/prepareRename", params, Box::new(cb));
+    }
+
+    pub fn request_rename<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        new_name: String,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = RenameParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            new_name,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/rename", params, Box::new(cb));
+    }
+
+    pub fn request_signature<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        context: Option<SignatureHelpContext>,
+        cb: CB,
+    ) -> u64
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SignatureHelpParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            context,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/signatureHelp", params, Box::new(cb))
+    }
+
+    pub fn request_inlay_hints<CB>(&self, document_uri: Url, range: Range, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(json!({
+            "textDocument": TextDocumentIdentifier { uri: document_uri },
+            "range": range,
+        }));
+        self.send_request("textDocument/inlayHint", params, Box::new(cb));
+    }
+
+    pub fn send_did_change(
+        &self,
+        buffer: &Buffer,
+        changes: Vec<TextDocumentContentChangeEvent>,
+        version: u64,
+    ) {
+        let uri = self.get_uri(buffer);
+        let text_document_did_change_params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri,
+                version: version as i32,
+            },
+            content_changes: changes,
+        };
+
+        let params = Params::from(
+            serde_json::to_value(text_document_did_change_params).unwrap(),
+        );
+        self.send_notification("textDocument/didChange", params);
+    }
+
+    /// The server's sync options, `None` when it only gave a sync kind.
+    fn sync_options(&self) -> Option<TextDocumentSyncOptions> {
+        let state = self.state.lock();
+        match state
+            .server_capabilities
+            .as_ref()?
+            .text_document_sync
+            .as_ref()?
+        {
+            TextDocumentSyncCapability::Options(options) => Some(options.clone()),
+            TextDocumentSyncCapability::Kind(_) => None,
+        }
+    }
+
+    pub fn get_sync_kind(&self) -> Option<TextDocumentSyncKind> {
+        let state = self.state.lock();
+        let text_document_sync = state
+            .server_capabilities
+            .as_ref()
+            .and_then(|c| c.text_document_sync.as_ref())?;
+        match &text_document_sync {
+            &TextDocumentSyncCapability::Kind(kind) => Some(kind.clone()),
+            &TextDocumentSyncCapability::Options(options) => options.clone().change,
+        }
+    }
+
+    pub fn update(
+        &self,
+        buffer: &Buffer,
+        content_change: &TextDocumentContentChangeEvent,
+        rev: u64,
+    ) {
+        let sync_kind = self.get_sync_kind().unwrap_or(TextDocumentSyncKind::Full);
+        let changes = get_change_for_sync_kind(sync_kind, buffer, content_change);
+        if let Some(changes) = changes {
+            self.send_did_change(buffer, changes, rev);
+        }
+        if let Ok(uri) = Url::from_file_path(&buffer.path) {
+            self.mark_stale_requests(&uri, STALE_AFTER_EDIT, None);
+        }
+
+        let mut inlay_hints = self.inlay_hints.lock();
+        let edited = match (inlay_hints.get_mut(&buffer.id), content_change.range) {
+            (Some(cache), Some(range)) if cache.rev + 1 == rev => {
+                let new_end = range.start.line
+                    + content_change.text.matches('\n').count() as u32;
+                cache.edited(range.start.line, range.end.line, new_end, rev);
+                true
+            }
+            _ => false,
+        };
+        if !edited {
+            inlay_hints.remove(&buffer.id);
+        }
+    }
+}
+
+pub enum LspHeader {
+    ContentType,
+    ContentLength(usize),
+}
+
+/// Sends the merged diagnostics of a document, with their counts.
+fn send_diagnostics(dispatcher: &Dispatcher, diagnostics: PublishDiagnosticsParams) {
+    if let Ok(path) = diagnostics.uri.to_file_path() {
+        dispatcher.send_notification(
+            "diagnostics_changed",
+            json!({
+                "path": path,
+                "count_by_severity": DiagnosticCounts::of(&diagnostics.diagnostics),
+            }),
+        );
+    }
+    dispatcher.send_notification(
+        "publish_diagnostics",
+        json!({
+            "diagnostics": diagnostics,
+        }),
+    );
+}
+
+fn number_from_id(id: &Id) -> u64 {
+    match *id {
+        Id::Num(n) => n as u64,
+        Id::Str(ref s) => {
+            u64::from_str_radix(s, 10).expect("failed to convert string id to u64")
+        }
+        _ => panic!("unexpected value for id: None"),
+    }
+}
+
+fn prepare_lsp_json(msg: &Value) -> Result<String> {
+    let request = serde_json::to_string(&msg)?;
+    Ok(format!(
+        "Content-Length: {}\r\n\r\n{}",
+        request.len(),
+        request
+    ))
+}
+
+fn parse_header(s: &str) -> Result<LspHeader> {
+    let split: Vec<String> =
+        s.splitn(2, ": ").map(|s| s.trim().to_lowercase()).collect();
+    if split.len() != 2 {
+        return Err(anyhow!("Malformed"));
+    };
+    match split[0].as_ref() {
+        HEADER_CONTENT_TYPE => Ok(LspHeader::ContentType),
+        HEADER_CONTENT_LENGTH => Ok(LspHeader::ContentLength(
+            usize::from_str_radix(&split[1], 10)?,
+        )),
+        _ => Err(anyhow!("Unknown parse error occurred")),
+    }
+}
+
+pub fn read_message<T: BufRead>(reader: &mut T) -> Result<String> {
+    let mut buffer = String::new();
+    let mut content_length: Option<usize> = None;
+
+    loop {
+        buffer.clear();
+        let _result = reader.read_line(&mut buffer);
+        // eprin
+        match &buffer {
+            s if s.trim().is_empty() => break,
+            s => {
+                match parse_header(s)? {
+                    LspHeader::ContentLength(len) => content_length = Some(len),
+                    LspHeader::ContentType => (),
+                };
+            }
+        };
+    }
+
+    let content_length = content_length
+        .ok_or_else(|| anyhow!("missing content-length header: {}", buffer))?;
+
+    let mut body_buffer = vec![0; content_length];
+    reader.read_exact(&mut body_buffer)?;
+
+    let body = String::from_utf8(body_buffer)?;
+    Ok(body)
+}
+
+/// What the client holds on to besides the server's message streams: the
+/// child process, or the socket of a server that was listening already.
+/// Both speak through `read_message` and `prepare_lsp_json`.
+trait LspTransport: Send {
+    /// Kills the server or closes the connection, which ends the reader.
+    fn close(&mut self);
+    /// The exit code of a server whose output closed, if there is one.
+    fn exit_code(&mut self) -> Option<i32>;
+}
+
+impl LspTransport for Child {
+    fn close(&mut self) {
+        let _ = self.kill();
+        let _ = self.wait();
+    }
+
+    fn exit_code(&mut self) -> Option<i32> {
+        self.wait().ok().and_then(|status| status.code())
+    }
+}
+
+impl LspTransport for TcpStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn exit_code(&mut self) -> Option<i32> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl LspTransport for std::os::unix::net::UnixStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn exit_code(&mut self) -> Option<i32> {
+        None
+    }
+}
+
+struct LspConnection {
+    transport: Box<dyn LspTransport>,
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+    stderr: Option<ChildStderr>,
+}
+
+impl LspConnection {
+    /// Spawns `exec_path`, or connects to it if it's a `tcp://host:port` or
+    /// `socket:///path` URI. A server that dropped the connection is
+    /// connected to again along with respawning crashed processes.
+    fn open(exec_path: &str) -> Result<LspConnection> {
+        match Url::parse(exec_path) {
+            Ok(url) if url.scheme() == "tcp" || url.scheme() == "socket" => {
+                Self::connect(&url).map_err(|e| {
+                    anyhow!("can't connect to lsp server {}: {}", url, e)
+                })
+            }
+            _ => Self::spawn(exec_path),
+        }
+    }
+
+    fn spawn(exec_path: &str) -> Result<LspConnection> {
+        let mut process = Command::new(exec_path)
+            .envs(&resolved_env().env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("can't start lsp server {}: {}", exec_path, e))?;
+        Ok(LspConnection {
+            writer: Box::new(BufWriter::new(process.stdin.take().unwrap())),
+            reader: Box::new(BufReader::new(process.stdout.take().unwrap())),
+            stderr: process.stderr.take(),
+            transport: Box::new(process),
+        })
+    }
+
+    /// Tries connecting `retries` more times after the first attempt fails,
+    /// each one waiting up to `timeout`, both read from the URI's query.
+    fn connect(url: &Url) -> Result<LspConnection> {
+        let mut timeout = CONNECT_TIMEOUT;
+        let mut retries = CONNECT_RETRIES;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "timeout" => timeout = Duration::from_millis(value.parse()?),
+                "retries" => retries = value.parse()?,
+                _ => {}
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            match Self::connect_once(url, timeout) {
+                Ok(connection) => return Ok(connection),
+                Err(_) if attempt < retries => {
+                    attempt += 1;
+                    thread::sleep(CONNECT_RETRY_DELAY);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn connect_once(url: &Url, timeout: Duration) -> Result<LspConnection> {
+        match url.scheme() {
+            "tcp" => {
+                let addr = url
+                    .socket_addrs(|| None)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("no address"))?;
+                let stream = TcpStream::connect_timeout(&addr, timeout)?;
+                stream.set_nodelay(true)?;
+                Self::from_stream(stream)
+            }
+            #[cfg(unix)]
+            "socket" => Self::from_stream(std::os::unix::net::UnixStream::connect(
+                url.path(),
+            )?),
+            scheme => Err(anyhow!("{} isn't supported here", scheme)),
+        }
+    }
+
+    fn from_stream<S>(stream: S) -> Result<LspConnection>
+    where
+        S: LspTransport + Read + Write + TryClone + 'static,
+    {
+        Ok(LspConnection {
+            reader: Box::new(BufReader::new(stream.try_clone()?)),
+            writer: Box::new(BufWriter::new(stream.try_clone()?)),
+            stderr: None,
+            transport: Box::new(stream),
+        })
+    }
+}
+
+/// `try_clone` of the socket types, which share no trait for it.
+trait TryClone: Sized {
+    fn try_clone(&self) -> std::io::Result<Self>;
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl TryClone for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+}
+
+fn workspace_folder(path: &Path) -> Option<WorkspaceFolder> {
+    Some(WorkspaceFolder {
+        uri: Url::from_directory_path(path).ok()?,
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string_lossy().to_string()),
+    })
+}
+
+fn supports_workspace_folders(capabilities: &ServerCapabilities) -> bool {
+    match capabilities
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.workspace_folders.as_ref())
+        .and_then(|folders| folders.change_notifications.as_ref())
+    {
+        Some(OneOf::Left(enabled)) => *enabled,
+        Some(OneOf::Right(_)) => true,
+        None => false,
+    }
+}
+
+fn file_rename(old: &Path, new: &Path) -> Option<FileRename> {
+    Some(FileRename {
+        old_uri: Url::from_file_path(old).ok()?.to_string(),
+        new_uri: Url::from_file_path(new).ok()?.to_string(),
+    })
+}
+
+fn file_operations(
+    capabilities: &ServerCapabilities,
+) -> Option<&WorkspaceFileOperationsServerCapabilities> {
+    capabilities.workspace.as_ref()?.file_operations.as_ref()
+}
+
+/// Whether one of the filters a server gave for a file operation matches
+/// `path`.
+fn file_operation_matches(
+    options: &FileOperationRegistrationOptions,
+    path: &Path,
+    is_dir: bool,
+) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    options.filters.iter().any(|filter| {
+        if filter.scheme.as_deref().unwrap_or("file") != "file" {
+            return false;
+        }
+        let pattern = &filter.pattern;
+        let kind_matches = match pattern.matches {
+            Some(FileOperationPatternKind::File) => !is_dir,
+            Some(FileOperationPatternKind::Folder) => is_dir,
+            None => true,
+        };
+        let ignore_case = pattern
+            .options
+            .as_ref()
+            .and_then(|options| options.ignore_case)
+            .unwrap_or(false);
+        let glob = match ignore_case {
+            true => glob_to_regex(&pattern.glob.to_lowercase()),
+            false => glob_to_regex(&pattern.glob),
+        };
+        let path = match ignore_case {
+            true => path.to_lowercase(),
+            false => path.clone(),
+        };
+        kind_matches && glob.map(|glob| glob.is_match(&path)).unwrap_or(false)
+    })
+}
+
+struct WatchedFiles {
+    glob: Regex,
+    kind: WatchKind,
+}
+
+impl WatchedFiles {
+    fn matches(&self, path: &str, change_type: FileChangeType) -> bool {
+        let kind = if change_type == FileChangeType::Created {
+            WatchKind::Create
+        } else if change_type == FileChangeType::Deleted {
+            WatchKind::Delete
+        } else {
+            WatchKind::Change
+        };
+        self.kind.contains(kind) && self.glob.is_match(path)
+    }
+}
+
+/// Turns a watcher's glob pattern into a regex over `/` separated paths.
+/// `*` and `?` stay within a path segment while `**` spans any number of
+/// them, `{a,b}` matches either alternative and `[...]` is a character
+/// class. A pattern that isn't absolute matches at any depth.
+fn glob_to_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    if !pattern.starts_with('/')
+        && !pattern.starts_with("**")
+        && pattern.get(1..2) != Some(":")
+    {
+        regex.push_str("(?:.*/)?");
+    }
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut braces = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    i += 1;
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '{' => {
+                braces += 1;
+                regex.push_str("(?:");
+            }
+            '}' if braces > 0 => {
+                braces -= 1;
+                regex.push(')');
+            }
+            ',' if braces > 0 => regex.push('|'),
+            '[' => match chars[i..].iter().position(|c| *c == ']') {
+                Some(len) if len > 1 => {
+                    let mut class = &chars[i + 1..i + len];
+                    regex.push('[');
+                    if class[0] == '!' {
+                        regex.push('^');
+                        class = &class[1..];
+                    }
+                    for c in class {
+                        if *c == '\\' || *c == '[' {
+                            regex.push('\\');
+                        }
+                        regex.push(*c);
+                    }
+                    regex.push(']');
+                    i += len;
+                }
+                _ => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    Ok(Regex::new(&regex)?)
+}
+
+/// The file name of a server's executable, as in `rust-analyzer`.
+fn server_name(exec_path: &str) -> &str {
+    Path::new(exec_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(exec_path)
+}
+
+/// The `[lsp.<name>]` table of the settings.
+fn user_lsp_configuration(name: &str) -> Option<Value> {
+    serde_json::to_value(setting(&["lsp", name])?).ok()
+}
+
+/// `REQUEST_TIMEOUTS` with the user's `[lsp-timeouts]` merged over them.
+fn request_timeouts() -> HashMap<String, u64> {
+    let mut timeouts: HashMap<String, u64> = REQUEST_TIMEOUTS
+        .iter()
+        .map(|(method, timeout)| (method.to_string(), *timeout))
+        .collect();
+    if let Some(user) = user_request_timeouts() {
+        timeouts.extend(user);
+    }
+    timeouts
+}
+
+fn user_request_timeouts() -> Option<HashMap<String, u64>> {
+    setting(&["lsp-timeouts"])?.try_into().ok()
+}
+
+fn lsp_settings(options: &Option<Value>, name: &str) -> Value {
+    let mut settings = options.clone().unwrap_or_else(|| json!({}));
+    if let Some(user) = user_lsp_configuration(name) {
+        merge_configuration(&mut settings, user);
+    }
+    settings
+}
+
+/// Looks up a dotted `section` of a server's settings, as asked for by
+/// `workspace/configuration`. The settings are the server's own, so a
+/// leading key named after the server, as in `rust-analyzer.cargo`, is
+/// skipped unless the settings have it. Missing sections are `null`.
+fn configuration_section(
+    settings: &Value,
+    section: Option<&str>,
+    server: &str,
+) -> Value {
+    let section = match section {
+        Some(section) if !section.is_empty() => section,
+        _ => return settings.clone(),
+    };
+    let mut keys: Vec<&str> = section.split('.').collect();
+    if keys[0] == server && settings.get(server).is_none() {
+        keys.remove(0);
+    }
+    keys.iter()
+        .try_fold(settings, |value, key| value.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+fn is_enabled<T>(provider: &Option<OneOf<bool, T>>) -> bool {
+    matches!(provider, Some(OneOf::Left(true)) | Some(OneOf::Right(_)))
+}
+
+/// Hands the edits back only if the buffer is still at the revision they
+/// were computed for, as applying them to another one would garble it.
+fn formatting_callback(
+    id: RequestId,
+    buffer: &Buffer,
+) -> impl 'static + Send + FnOnce(&LspClient, Result<Value>) {
+    let buffer_id = buffer.id;
+    let rev = buffer.rev;
+    move |lsp_client, result| {
+        let result = result.and_then(|edits| {
+            match lsp_client.dispatcher.buffers.lock().get(&buffer_id) {
+                Some(buffer) if buffer.rev == rev => Ok(edits),
+                _ => Err(anyhow!(
+                    "the buffer changed while formatting, dropped the edits"
+                )),
+            }
+        });
+        lsp_client.dispatcher.respond(id, result);
+    }
+}
+
+fn formatting_not_supported(buffer: &Buffer) -> anyhow::Error {
+    LspUnsupported::new("formatting", buffer).into()
+}
+
+/// Finds `http(s)://` URLs, and paths of files in the workspace with an
+/// optional `:line:col`, in the lines of `range`.
+fn fallback_document_links(
+    rope: &Rope,
+    range: Range,
+    workspace: &Path,
+) -> Vec<DocumentLinkItem> {
+    let url_regex = Regex::new(r#"https?://[^\s<>"'`()\[\]{}]+"#).unwrap();
+    let path_regex =
+        Regex::new(r"(?:[\w.-]+/)*[\w.-]*[\w-]\.[A-Za-z0-9]+(?::(\d+)(?::(\d+))?)?")
+            .unwrap();
+    let last_line = rope.line_of_offset(rope.len());
+    let mut links = Vec::new();
+    for line in range.start.line as usize..=(range.end.line as usize).min(last_line)
+    {
+        let line_start = rope.offset_of_line(line);
+        let text = rope.slice_to_cow(line_start..rope.offset_of_line(line + 1));
+        let link_range = |start: usize, end: usize| Range {
+            start: offset_to_lsp_position(rope, line_start + start),
+            end: offset_to_lsp_position(rope, line_start + end),
+        };
+
+        let mut urls = Vec::new();
+        for m in url_regex.find_iter(&text) {
+            let url = m
+                .as_str()
+                .trim_end_matches(&['.', ',', ';', ':', '!', '?'][..]);
+            urls.push((m.start(), m.start() + url.len()));
+            links.push(DocumentLinkItem {
+                range: link_range(m.start(), m.start() + url.len()),
+                target: Some(url.to_string()),
+                tooltip: None,
+                data: None,
+            });
+        }
+
+        for captures in path_regex.captures_iter(&text) {
+            let m = captures.get(0).unwrap();
+            if urls
+                .iter()
+                .any(|(start, end)| m.start() < *end && *start < m.end())
+            {
+                continue;
+            }
+            let path_end = captures
+                .get(1)
+                .map(|line| line.start() - 1)
+                .unwrap_or(m.end());
+            let path = workspace.join(&text[m.start()..path_end]);
+            if !path.is_file() {
+                continue;
+            }
+            let mut target = match Url::from_file_path(&path) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            if let Some(line) = captures.get(1) {
+                let fragment = match captures.get(2) {
+                    Some(col) => format!("L{},{}", line.as_str(), col.as_str()),
+                    None => format!("L{}", line.as_str()),
+                };
+                target.set_fragment(Some(&fragment));
+            }
+            links.push(DocumentLinkItem {
+                range: link_range(m.start(), m.end()),
+                target: Some(target.to_string()),
+                tooltip: None,
+                data: None,
+            });
+        }
+    }
+    links
+}
+
+fn send_document_highlights(
+    dispatcher: &Dispatcher,
+    buffer_id: BufferId,
+    rev: u64,
+    highlights: Vec<DocumentHighlight>,
+) {
+    let response = DocumentHighlightResponse {
+        buffer_id,
+        rev,
+        highlights,
+    };
+    dispatcher
+        .send_notification("document_highlight", json!({ "highlight": response }));
+}
+
+fn can_fold(capabilities: &ServerCapabilities) -> bool {
+    match &capabilities.folding_range_provider {
+        Some(FoldingRangeProviderCapability::Simple(enabled)) => *enabled,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Drops the ranges that don't fold anything or run past the end of the
+/// buffer, as a response can be for an older version of it, and keeps the
+/// outermost of the ranges starting on the same line.
+fn valid_folding_ranges(
+    mut ranges: Vec<FoldingRangeItem>,
+    num_lines: usize,
+) -> Vec<FoldingRangeItem> {
+    ranges.retain(|range| {
+        range.end_line > range.start_line && (range.end_line as usize) < num_lines
+    });
+    ranges.sort_by(|a, b| {
+        a.start_line
+            .cmp(&b.start_line)
+            .then_with(|| b.end_line.cmp(&a.end_line))
+    });
+    ranges.dedup_by_key(|range| range.start_line);
+    ranges
+}
+
+/// Folds every line followed by more indented ones, up to the last of
+/// them, and the lines between region markers.
+fn fallback_folding_ranges(rope: &Rope, language_id: &str) -> Vec<FoldingRangeItem> {
+    let range = |start_line: u32, end_line: u32, kind| FoldingRangeItem {
+        start_line,
+        end_line,
+        kind,
+    };
+    let mut ranges = Vec::new();
+    let mut regions = Vec::new();
+    let mut indents: Vec<(u32, usize)> = Vec::new();
+    let mut last_line = 0;
+    for (line, text) in rope.lines(..).enumerate() {
+        let line = line as u32;
+        match region_marker(language_id, &text) {
+            Some(true) => regions.push(line),
+            Some(false) => {
+                if let Some(start) = regions.pop() {
+                    ranges.push(range(start, line, Some(FoldingRangeKind::Region)));
+                }
+            }
+            None => (),
+        }
+        if text.trim().is_empty() {
+            continue;
+        }
+        let indent = text
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { 4 } else { 1 })
+            .sum();
+        while let Some((start, start_indent)) = indents.last().cloned() {
+            if start_indent < indent {
+                break;
+            }
+            indents.pop();
+            if last_line > start {
+                ranges.push(range(start, last_line, None));
+            }
+        }
+        indents.push((line, indent));
+        last_line = line;
+    }
+    for (start, _) in indents {
+        if last_line > start {
+            ranges.push(range(start, last_line, None));
+        }
+    }
+    ranges
+}
+
+/// Whether the line starts (`Some(true)`) or ends (`Some(false)`) a region,
+/// for the languages that have a convention for the markers, such as
+/// `// region` in Rust or `#region` in C#.
+fn region_marker(language_id: &str, line: &str) -> Option<bool> {
+    let comment = match language_id {
+        "rust" | "go" | "c" | "cpp" | "java" | "javascript" | "typescript"
+        | "javascriptreact" | "typescriptreact" => "//",
+        "python" | "shellscript" | "ruby" => "#",
+        "csharp" => "",
+        _ => return None,
+    };
+    let marker = line.trim().strip_prefix(comment)?.trim_start();
+    let marker = marker.strip_prefix('#').unwrap_or(marker);
+    let is_word = |rest: &str| {
+        rest.chars()
+            .next()
+            .map(|c| c.is_whitespace() || c == ':')
+            .unwrap_or(true)
+    };
+    if let Some(rest) = marker.strip_prefix("endregion") {
+        return if is_word(rest) { Some(false) } else { None };
+    }
+    match marker.strip_prefix("region") {
+        Some(rest) if is_word(rest) => Some(true),
+        _ => None,
+    }
+}
+
+fn can_select_range(capabilities: &ServerCapabilities) -> bool {
+    match &capabilities.selection_range_provider {
+        Some(SelectionRangeProviderCapability::Simple(enabled)) => *enabled,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// A server's linked selection range, innermost first.
+fn selection_range_chain(range: SelectionRange) -> Vec<Range> {
+    let mut chain = vec![range.range];
+    let mut parent = range.parent;
+    while let Some(range) = parent {
+        chain.push(range.range);
+        parent = range.parent;
+    }
+    chain
+}
+
+pub(crate) fn tree_sitter_language(
+    language_id: &str,
+) -> Option<tree_sitter::Language> {
+    match language_id {
+        "rust" => Some(tree_sitter_rust::language()),
+        _ => None,
+    }
+}
+
+/// The ranges of the syntax nodes around each position, from the smallest
+/// one out to the root, leaving out nodes with the same range as their
+/// child. Without a grammar for the language, the chain is the word, the
+/// line and the whole document.
+fn syntax_selection_ranges(
+    rope: &Rope,
+    language_id: &str,
+    positions: &[Position],
+) -> Vec<Vec<Range>> {
+    let tree = tree_sitter_language(language_id).and_then(|language| {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(language).ok()?;
+        parser.parse(rope.to_string(), None)
+    });
+    positions
+        .iter()
+        .map(|position| {
+            let offset = match lsp_position_to_offset(rope, position) {
+                Ok(offset) => offset,
+                Err(_) => return Vec::new(),
+            };
+            let mut spans = Vec::new();
+            match tree.as_ref() {
+                Some(tree) => {
+                    let mut node =
+                        tree.root_node().descendant_for_byte_range(offset, offset);
+                    while let Some(current) = node {
+                        spans.push((current.start_byte(), current.end_byte()));
+                        node = current.parent();
+                    }
+                }
+                None => {
+                    if let Some(word) = word_at(rope, offset) {
+                        spans.push(word);
+                    }
+                    let line = rope.line_of_offset(offset);
+                    let line_start = rope.offset_of_line(line);
+                    let line_end = rope
+                        .slice_to_cow(line_start..rope.offset_of_line(line + 1))
+                        .trim_end_matches(&['\r', '\n'][..])
+                        .len()
+                        + line_start;
+                    spans.push((line_start, line_end));
+                    spans.push((0, rope.len()));
+                }
+            }
+            spans.dedup();
+            spans
+                .into_iter()
+                .map(|(start, end)| {
+                    Range::new(
+                        offset_to_lsp_position(rope, start),
+                        offset_to_lsp_position(rope, end),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The byte range of the identifier the cursor is in or right after.
+fn word_at(rope: &Rope, offset: usize) -> Option<(usize, usize)> {
+    let line = rope.line_of_offset(offset);
+    let line_start = rope.offset_of_line(line);
+    let text = rope.slice_to_cow(line_start..rope.offset_of_line(line + 1));
+    let col = offset - line_start;
+    let start = text[..col]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(col);
+    let end = text[col..]
+        .char_indices()
+        .find(|(_, c)| !is_word_char(*c))
+        .map(|(i, _)| col + i)
+        .unwrap_or(text.len());
+    if start == end {
+        return None;
+    }
+    Some((line_start + start, line_start + end))
+}
+
+/// The whole-word occurrences of the word at `word`, for buffers without
+/// a server that answers `textDocument/documentHighlight`.
+fn word_highlights(rope: &Rope, word: (usize, usize)) -> Vec<DocumentHighlight> {
+    let word = rope.slice_to_cow(word.0..word.1);
+    let mut highlights = Vec::new();
+    let mut line_start = 0;
+    for line in rope.lines_raw(..) {
+        for (i, _) in line.match_indices(word.as_ref()) {
+            let before = line[..i].chars().next_back();
+            let after = line[i + word.len()..].chars().next();
+            if before.map(is_word_char).unwrap_or(false)
+                || after.map(is_word_char).unwrap_or(false)
+            {
+                continue;
+            }
+            highlights.push(DocumentHighlight {
+                range: Range {
+                    start: offset_to_lsp_position(rope, line_start + i),
+                    end: offset_to_lsp_position(rope, line_start + i + word.len()),
+                },
+                kind: Some(DocumentHighlightKind::Text),
+            });
+        }
+        line_start += line.len();
+    }
+    highlights
+}
+
+/// Turns a `textDocument/definition`-style answer into reference
+/// locations with their line previews. Links jump to their selection
+/// range, like plain locations do to their start.
+fn goto_callback(
+    id: RequestId,
+) -> impl 'static + Send + FnOnce(&LspClient, Result<Value>) {
+    move |lsp_client, result| {
+        let result = result.and_then(|value| {
+            let response: Option<GotoDefinitionResponse> =
+                serde_json::from_value(value)?;
+            let locations = match response {
+                Some(GotoDefinitionResponse::Scalar(location)) => vec![location],
+                Some(GotoDefinitionResponse::Array(locations)) => locations,
+                Some(GotoDefinitionResponse::Link(links)) => links
+                    .into_iter()
+                    .map(|link| Location {
+                        uri: link.target_uri,
+                        range: link.target_selection_range,
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            lsp_client.dispatcher.reference_locations(locations)
+        });
+        lsp_client.dispatcher.respond(id, result);
+    }
+}
 
-    pub fn send_did_change(
-        &self,
-        buffer: &Buffer,
-        changes: Vec<TextDocumentContentChangeEvent>,
-        version: u64,
-    ) {
-        let uri = self.get_uri(buffer);
-        let text_document_did_change_params = DidChangeTextDocumentParams {
-            text_document: VersionedTextDocumentIdentifier {
-                uri,
-                version: version as i32,
-            },
-            content_changes: changes,
-        };
+fn can_prepare_rename(capabilities: &ServerCapabilities) -> bool {
+    matches!(
+        capabilities.rename_provider,
+        Some(OneOf::Right(RenameOptions {
+            prepare_provider: Some(true),
+            ..
+        }))
+    )
+}
 
-        let params = Params::from(
-            serde_json::to_value(text_document_did_change_params).unwrap(),
-        );
-        self.send_notification("textDocument/didChange", params);
+fn can_resolve_completion(capabilities: &ServerCapabilities) -> bool {
+    capabilities
+        .completion_provider
+        .as_ref()
+        .and_then(|provider| provider.resolve_provider)
+        .unwrap_or(false)
+}
+
+fn can_resolve_code_action(capabilities: &ServerCapabilities) -> bool {
+    matches!(
+        capabilities.code_action_provider,
+        Some(CodeActionProviderCapability::Options(CodeActionOptions {
+            resolve_provider: Some(true),
+            ..
+        }))
+    )
+}
+
+/// Completion items remember which server they came from in their `data`,
+/// so that `completionItem/resolve` goes back to the same server.
+fn tag_completion_item(server: LspServerId, item: &mut CompletionItem) {
+    item.data = Some(json!({
+        "lapce_server": server,
+        "data": item.data.take(),
+    }));
+}
+
+fn untag_completion_item(
+    item: &CompletionItem,
+) -> (Option<LspServerId>, CompletionItem) {
+    let mut item = item.clone();
+    let server = item
+        .data
+        .as_ref()
+        .and_then(|data| data.get("lapce_server"))
+        .and_then(|server| serde_json::from_value(server.clone()).ok());
+    if server.is_some() {
+        item.data = item
+            .data
+            .as_mut()
+            .and_then(|data| data.get_mut("data"))
+            .map(Value::take)
+            .filter(|data| !data.is_null());
     }
+    (server, item)
+}
 
-    pub fn get_sync_kind(&self) -> Option<TextDocumentSyncKind> {
-        let state = self.state.lock();
-        let text_document_sync = state
-            .server_capabilities
-            .as_ref()
-            .and_then(|c| c.text_document_sync.as_ref())?;
-        match &text_document_sync {
-            &TextDocumentSyncCapability::Kind(kind) => Some(kind.clone()),
-            &TextDocumentSyncCapability::Options(options) => options.clone().change,
+fn merge_completions(responses: Vec<ServerResponse>) -> Result<Value> {
+    let mut items = Vec::new();
+    let mut is_incomplete = false;
+    let mut labels = HashSet::new();
+    let mut error = None;
+    for ServerResponse { server, result, .. } in responses {
+        let response: Option<CompletionResponse> =
+            match result.and_then(|value| Ok(serde_json::from_value(value)?)) {
+                Ok(response) => response,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    continue;
+                }
+            };
+        let server_items = match response {
+            Some(CompletionResponse::Array(server_items)) => server_items,
+            Some(CompletionResponse::List(list)) => {
+                is_incomplete |= list.is_incomplete;
+                list.items
+            }
+            None => Vec::new(),
+        };
+        let server_labels: HashSet<String> =
+            server_items.iter().map(|item| item.label.clone()).collect();
+        for mut item in server_items {
+            if labels.contains(&item.label) {
+                continue;
+            }
+            tag_completion_item(server, &mut item);
+            items.push(item);
         }
+        labels.extend(server_labels);
     }
-
-    pub fn update(
-        &self,
-        buffer: &Buffer,
-        content_change: &TextDocumentContentChangeEvent,
-        rev: u64,
-    ) {
-        let sync_kind = self.get_sync_kind().unwrap_or(TextDocumentSyncKind::Full);
-        let changes = get_change_for_sync_kind(sync_kind, buffer, content_change);
-        if let Some(changes) = changes {
-            self.send_did_change(buffer, changes, rev);
+    if items.is_empty() {
+        if let Some(error) = error {
+            return Err(error);
         }
     }
+    Ok(serde_json::to_value(CompletionResponse::List(
+        CompletionList {
+            is_incomplete,
+            items,
+        },
+    ))?)
 }
 
-pub enum LspHeader {
-    ContentType,
-    ContentLength(usize),
+/// Splits all three shapes of hover contents into sections, taking the
+/// fenced code blocks out of markdown so they keep their language. Empty
+/// sections are dropped.
+fn hover_sections(contents: HoverContents) -> Vec<HoverSection> {
+    fn marked_string(marked: MarkedString) -> Vec<HoverSection> {
+        match marked {
+            MarkedString::String(text) => markdown_sections(&text),
+            MarkedString::LanguageString(code) => vec![HoverSection {
+                language: Some(code.language),
+                value: code.value.replace('\r', ""),
+                is_markdown: false,
+            }],
+        }
+    }
+    let sections = match contents {
+        HoverContents::Scalar(marked) => marked_string(marked),
+        HoverContents::Array(marked) => {
+            marked.into_iter().flat_map(marked_string).collect()
+        }
+        HoverContents::Markup(markup) => match markup.kind {
+            MarkupKind::Markdown => markdown_sections(&markup.value),
+            MarkupKind::PlainText => vec![HoverSection {
+                language: None,
+                value: markup.value.replace('\r', ""),
+                is_markdown: false,
+            }],
+        },
+    };
+    sections
+        .into_iter()
+        .filter(|section| !section.value.trim().is_empty())
+        .collect()
 }
 
-fn number_from_id(id: &Id) -> u64 {
-    match *id {
-        Id::Num(n) => n as u64,
-        Id::Str(ref s) => {
-            u64::from_str_radix(s, 10).expect("failed to convert string id to u64")
+/// Markdown between fenced code blocks, and the blocks with the language
+/// of their info string.
+fn markdown_sections(text: &str) -> Vec<HoverSection> {
+    let mut sections = Vec::new();
+    let mut current = String::new();
+    // the backticks that opened the current code block, and its language
+    let mut fence: Option<(usize, Option<String>)> = None;
+    for line in text.replace('\r', "").lines() {
+        let trimmed = line.trim_start();
+        let backticks = trimmed.chars().take_while(|c| *c == '`').count();
+        match fence.as_ref() {
+            Some((open, language))
+                if backticks >= *open && trimmed[backticks..].trim().is_empty() =>
+            {
+                sections.push(HoverSection {
+                    language: language.clone(),
+                    value: std::mem::take(&mut current),
+                    is_markdown: false,
+                });
+                fence = None;
+            }
+            None if backticks >= 3 => {
+                sections.push(HoverSection {
+                    language: None,
+                    value: std::mem::take(&mut current),
+                    is_markdown: true,
+                });
+                let language = trimmed[backticks..]
+                    .split_whitespace()
+                    .next()
+                    .map(|language| language.to_string());
+                fence = Some((backticks, language));
+            }
+            _ => {
+                if !current.is_empty() {
+                    current.push('\n');
+                }
+                current.push_str(line);
+            }
         }
-        _ => panic!("unexpected value for id: None"),
     }
+    // an unclosed block runs to the end
+    sections.push(HoverSection {
+        language: fence.as_ref().and_then(|(_, language)| language.clone()),
+        value: current,
+        is_markdown: fence.is_none(),
+    });
+    sections
+        .into_iter()
+        .map(|mut section| {
+            if section.is_markdown {
+                section.value = section.value.trim_matches('\n').to_string();
+            }
+            section
+        })
+        .filter(|section| !section.value.trim().is_empty())
+        .collect()
 }
 
-fn prepare_lsp_json(msg: &Value) -> Result<String> {
-    let request = serde_json::to_string(&msg)?;
-    Ok(format!(
-        "Content-Length: {}\r\n\r\n{}",
-        request.len(),
-        request
-    ))
+/// Plain string documentation is passed on as it is, which reads fine as
+/// markdown in most cases.
+fn documentation_markdown(documentation: Documentation) -> String {
+    match documentation {
+        Documentation::String(text) => text,
+        Documentation::MarkupContent(markup) => markup.value,
+    }
 }
 
-fn parse_header(s: &str) -> Result<LspHeader> {
-    let split: Vec<String> =
-        s.splitn(2, ": ").map(|s| s.trim().to_lowercase()).collect();
-    if split.len() != 2 {
-        return Err(anyhow!("Malformed"));
-    };
-    match split[0].as_ref() {
-        HEADER_CONTENT_TYPE => Ok(LspHeader::ContentType),
-        HEADER_CONTENT_LENGTH => Ok(LspHeader::ContentLength(
-            usize::from_str_radix(&split[1], 10)?,
+/// The byte range of a parameter in its signature's label. Label offsets
+/// from the server are in utf-16 code units.
+fn parameter_range(
+    label: &str,
+    parameter: &ParameterLabel,
+) -> Option<(usize, usize)> {
+    match parameter {
+        ParameterLabel::Simple(name) => {
+            let start = label.find(name.as_str())?;
+            Some((start, start + name.len()))
+        }
+        ParameterLabel::LabelOffsets([start, end]) => Some((
+            utf16_to_byte(label, *start as usize),
+            utf16_to_byte(label, *end as usize),
         )),
-        _ => Err(anyhow!("Unknown parse error occurred")),
     }
 }
 
-pub fn read_message<T: BufRead>(reader: &mut T) -> Result<String> {
-    let mut buffer = String::new();
-    let mut content_length: Option<usize> = None;
-
-    loop {
-        buffer.clear();
-        let _result = reader.read_line(&mut buffer);
-        // eprin
-        match &buffer {
-            s if s.trim().is_empty() => break,
-            s => {
-                match parse_header(s)? {
-                    LspHeader::ContentLength(len) => content_length = Some(len),
-                    LspHeader::ContentType => (),
-                };
+fn signature_help_response(
+    buffer_id: BufferId,
+    rev: u64,
+    position: Position,
+    help: Option<SignatureHelp>,
+) -> SignatureHelpResponse {
+    let help = help.unwrap_or(SignatureHelp {
+        signatures: Vec::new(),
+        active_signature: None,
+        active_parameter: None,
+    });
+    let active_signature = (help.active_signature.unwrap_or(0) as usize)
+        .min(help.signatures.len().saturating_sub(1));
+    let active_parameter = help
+        .signatures
+        .get(active_signature)
+        .and_then(|signature| signature.active_parameter)
+        .or(help.active_parameter)
+        .map(|index| index as usize);
+    let signatures = help
+        .signatures
+        .into_iter()
+        .map(|signature| {
+            let parameters = signature
+                .parameters
+                .unwrap_or_default()
+                .iter()
+                .map(|parameter| {
+                    parameter_range(&signature.label, &parameter.label)
+                        .unwrap_or((0, 0))
+                })
+                .collect();
+            SignatureItem {
+                label: signature.label,
+                documentation: signature.documentation.map(documentation_markdown),
+                parameters,
             }
-        };
+        })
+        .collect();
+    SignatureHelpResponse {
+        buffer_id,
+        rev,
+        position,
+        signatures,
+        active_signature,
+        active_parameter,
     }
+}
 
-    let content_length = content_length
-        .ok_or_else(|| anyhow!("missing content-length header: {}", buffer))?;
-
-    let mut body_buffer = vec![0; content_length];
-    reader.read_exact(&mut body_buffer)?;
+/// Stacks the hovers of all servers into one markdown document, one
+/// section per server. The range is the first server's.
+fn merge_hovers(responses: Vec<ServerResponse>) -> Result<Value> {
+    let mut sections = Vec::new();
+    let mut range = None;
+    for ServerResponse { result, .. } in responses {
+        let hover: Option<Hover> =
+            match result.and_then(|value| Ok(serde_json::from_value(value)?)) {
+                Ok(hover) => hover,
+                Err(_) => continue,
+            };
+        if let Some(hover) = hover {
+            range = range.or(hover.range);
+            sections.extend(hover_sections(hover.contents));
+        }
+    }
+    if sections.is_empty() {
+        return Ok(Value::Null);
+    }
+    Ok(serde_json::to_value(HoverResponse { sections, range })?)
+}
 
-    let body = String::from_utf8(body_buffer)?;
-    Ok(body)
+/// Concatenates the symbols in server order, skipping servers that answered
+/// `null`. Failed servers only mark the response incomplete.
+fn merge_workspace_symbols(
+    responses: Vec<ServerResponse>,
+    limit: usize,
+) -> Result<Value> {
+    let mut symbols = Vec::new();
+    let mut incomplete = false;
+    for ServerResponse {
+        language_id,
+        result,
+        ..
+    } in responses
+    {
+        let server_symbols: Option<Vec<SymbolInformation>> =
+            match result.and_then(|value| Ok(serde_json::from_value(value)?)) {
+                Ok(server_symbols) => server_symbols,
+                Err(_) => {
+                    incomplete = true;
+                    continue;
+                }
+            };
+        symbols.extend(server_symbols.into_iter().flatten().map(|symbol| {
+            WorkspaceSymbolItem {
+                language_id: language_id.clone(),
+                symbol,
+            }
+        }));
+    }
+    if symbols.len() > limit {
+        symbols.truncate(limit);
+        incomplete = true;
+    }
+    Ok(serde_json::to_value(WorkspaceSymbolResponse {
+        symbols,
+        incomplete,
+    })?)
 }
 
 pub fn get_change_for_sync_kind(
@@ -914,43 +5849,677 @@ pub fn get_change_for_sync_kind(
     }
 }
 
-fn format_semantic_tokens(
-    buffer: &Buffer,
-    semantic_tokens_provider: &Option<SemanticTokensServerCapabilities>,
-    value: Value,
-) -> Option<Vec<(usize, usize, String)>> {
-    let semantic_tokens: SemanticTokens = serde_json::from_value(value).ok()?;
-    let semantic_tokens_provider = semantic_tokens_provider.as_ref()?;
-    let semantic_lengends = semantic_tokens_lengend(semantic_tokens_provider);
+/// The semantic token spans of a line, as byte columns, sent to the core
+/// as part of `semantic_tokens`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LineStyles {
+    pub line: usize,
+    /// The start, end and style of each token, the style being the token
+    /// type followed by its modifiers, as in `variable.mutable`.
+    pub styles: Vec<(usize, usize, String)>,
+}
 
-    let mut highlights = Vec::new();
+fn flatten_semantic_tokens(tokens: &[SemanticToken]) -> Vec<u32> {
+    tokens
+        .iter()
+        .flat_map(|token| {
+            [
+                token.delta_line,
+                token.delta_start,
+                token.length,
+                token.token_type,
+                token.token_modifiers_bitset,
+            ]
+        })
+        .collect()
+}
+
+/// The edits' `start` and `delete_count` index the flat data, and all
+/// refer to it as it was before any of them.
+fn apply_semantic_tokens_edits(
+    data: &mut Vec<u32>,
+    mut edits: Vec<SemanticTokensEdit>,
+) {
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.start));
+    for edit in edits {
+        let start = (edit.start as usize).min(data.len());
+        let end = (start + edit.delete_count as usize).min(data.len());
+        let tokens = flatten_semantic_tokens(&edit.data.unwrap_or_default());
+        data.splice(start..end, tokens);
+    }
+}
+
+/// Decodes the relative, UTF-16 based token data into byte columns,
+/// grouped by line.
+fn semantic_line_styles(
+    rope: &Rope,
+    legend: &SemanticTokensLegend,
+    data: &[u32],
+) -> Vec<LineStyles> {
+    let mut lines: Vec<LineStyles> = Vec::new();
     let mut line = 0;
     let mut start = 0;
-    for semantic_token in &semantic_tokens.data {
-        if semantic_token.delta_line > 0 {
-            line += semantic_token.delta_line as usize;
-            start = buffer.offset_of_line(line);
+    for token in data.chunks_exact(5) {
+        if token[0] > 0 {
+            line += token[0];
+            start = 0;
+        }
+        start += token[1];
+        let token_type = match legend.token_types.get(token[3] as usize) {
+            Some(token_type) => token_type,
+            None => continue,
+        };
+        let mut style = token_type.as_str().to_string();
+        for (i, modifier) in legend.token_modifiers.iter().enumerate() {
+            if i < 32 && token[4] & (1 << i) != 0 {
+                style.push('.');
+                style.push_str(modifier.as_str());
+            }
         }
-        start += semantic_token.delta_start as usize;
-        let end = start + semantic_token.length as usize;
-        let kind = semantic_lengends.token_types[semantic_token.token_type as usize]
-            .as_str()
-            .to_string();
-        highlights.push((start, end, kind));
-    }
 
-    Some(highlights)
+        let position = |character| Position::new(line, character);
+        let (line_start, token_start, token_end) = match (
+            lsp_position_to_offset(rope, &position(0)),
+            lsp_position_to_offset(rope, &position(start)),
+            lsp_position_to_offset(rope, &position(start + token[2])),
+        ) {
+            (Ok(line_start), Ok(token_start), Ok(token_end)) => {
+                (line_start, token_start, token_end)
+            }
+            _ => break,
+        };
+        if lines.last().map(|l| l.line) != Some(line as usize) {
+            lines.push(LineStyles {
+                line: line as usize,
+                styles: Vec::new(),
+            });
+        }
+        lines.last_mut().unwrap().styles.push((
+            token_start - line_start,
+            token_end - line_start,
+            style,
+        ));
+    }
+    lines
 }
 
-fn semantic_tokens_lengend(
-    semantic_tokens_provider: &SemanticTokensServerCapabilities,
-) -> SemanticTokensLegend {
-    match semantic_tokens_provider {
-        SemanticTokensServerCapabilities::SemanticTokensOptions(options) => {
-            options.legend.clone()
-        }
-        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(
-            options,
-        ) => options.semantic_tokens_options.legend.clone(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_merge_completions() {
+        let item = |label: &str, data: Option<Value>| CompletionItem {
+            label: label.to_string(),
+            data,
+            ..Default::default()
+        };
+        let first = LspServerId(1);
+        let second = LspServerId(2);
+        let response = |server, items: Vec<CompletionItem>| ServerResponse {
+            server,
+            language_id: "rust".to_string(),
+            result: Ok(serde_json::to_value(items).unwrap()),
+        };
+        let results = vec![
+            response(first, vec![item("len", Some(json!(7))), item("len", None)]),
+            response(second, vec![item("len", None), item("flex", None)]),
+        ];
+        let merged: CompletionResponse =
+            serde_json::from_value(merge_completions(results).unwrap()).unwrap();
+        let items = match merged {
+            CompletionResponse::List(list) => list.items,
+            CompletionResponse::Array(items) => items,
+        };
+        let labels: Vec<&str> =
+            items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, vec!["len", "len", "flex"]);
+
+        let (server, resolved) = untag_completion_item(&items[0]);
+        assert_eq!(server, Some(first));
+        assert_eq!(resolved.data, Some(json!(7)));
+        let (server, resolved) = untag_completion_item(&items[2]);
+        assert_eq!(server, Some(second));
+        assert_eq!(resolved.data, None);
+    }
+
+    #[test]
+    fn test_merge_workspace_symbols() {
+        let symbol = |name: &str| SymbolInformation {
+            name: name.to_string(),
+            kind: SymbolKind::Function,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: Url::parse("file:///main.rs").unwrap(),
+                range: Range::default(),
+            },
+            container_name: None,
+        };
+        let response = |language_id: &str, result| ServerResponse {
+            server: LspServerId(1),
+            language_id: language_id.to_string(),
+            result,
+        };
+        let merged = |responses, limit| -> WorkspaceSymbolResponse {
+            serde_json::from_value(
+                merge_workspace_symbols(responses, limit).unwrap(),
+            )
+            .unwrap()
+        };
+
+        let responses = || {
+            vec![
+                response("rust", Ok(json!([symbol("main"), symbol("run")]))),
+                response("go", Ok(Value::Null)),
+                response("toml", Ok(json!([symbol("package")]))),
+            ]
+        };
+        let all = merged(responses(), 10);
+        assert!(!all.incomplete);
+        let names: Vec<(&str, &str)> = all
+            .symbols
+            .iter()
+            .map(|item| (item.language_id.as_str(), item.symbol.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("rust", "main"), ("rust", "run"), ("toml", "package")]
+        );
+
+        let capped = merged(responses(), 2);
+        assert!(capped.incomplete);
+        assert_eq!(capped.symbols.len(), 2);
+
+        let indexing = merged(
+            vec![
+                response("rust", Err(anyhow!("content modified"))),
+                response("toml", Ok(json!([symbol("package")]))),
+            ],
+            10,
+        );
+        assert!(indexing.incomplete);
+        assert_eq!(indexing.symbols.len(), 1);
+    }
+
+    #[test]
+    fn test_configuration_section() {
+        let settings = json!({
+            "cargo": { "features": ["full"] },
+            "checkOnSave": { "command": "clippy" },
+        });
+        let section =
+            |section| configuration_section(&settings, section, "rust-analyzer");
+        assert_eq!(section(None), settings);
+        assert_eq!(section(Some("rust-analyzer")), settings);
+        assert_eq!(
+            section(Some("rust-analyzer.checkOnSave.command")),
+            json!("clippy")
+        );
+        assert_eq!(section(Some("cargo.features")), json!(["full"]));
+        assert_eq!(section(Some("rust-analyzer.procMacro")), Value::Null);
+        assert_eq!(section(Some("files")), Value::Null);
+    }
+
+    #[test]
+    fn test_inlay_hints_cache() {
+        let hint = |line: u32| InlayHintItem {
+            position: Position::new(line, 4),
+            label: vec![": i32".to_string()],
+            kind: InlayHintKind::Type,
+            padding_left: false,
+            padding_right: false,
+        };
+        let lines = |cache: &InlayHintsCache| {
+            let mut lines: Vec<u32> =
+                cache.hints.iter().map(|hint| hint.position.line).collect();
+            lines.sort_unstable();
+            lines
+        };
+
+        let mut cache = InlayHintsCache::new(1);
+        assert_eq!(cache.missing(0, 9), Some((0, 9)));
+        cache.insert(0, 9, vec![hint(2), hint(5), hint(8)]);
+        cache.insert(20, 29, vec![hint(25)]);
+        assert_eq!(cache.lines, vec![(0, 9), (20, 29)]);
+        assert_eq!(cache.missing(5, 25), Some((10, 19)));
+        assert_eq!(cache.missing(0, 9), None);
+
+        // line 5 split in two
+        cache.edited(5, 5, 6, 2);
+        assert_eq!(cache.rev, 2);
+        assert_eq!(lines(&cache), vec![2, 9, 26]);
+        assert_eq!(cache.lines, vec![(0, 4), (7, 10), (21, 30)]);
+        assert_eq!(cache.missing(0, 10), Some((5, 6)));
+
+        cache.insert(5, 6, vec![hint(6)]);
+        assert_eq!(cache.lines, vec![(0, 10), (21, 30)]);
+        assert_eq!(lines(&cache), vec![2, 6, 9, 26]);
+        assert_eq!(cache.hints(3, 9).len(), 2);
+
+        // lines 8 to 10 joined into one
+        cache.edited(8, 10, 8, 3);
+        assert_eq!(lines(&cache), vec![2, 6, 24]);
+        assert_eq!(cache.lines, vec![(0, 7), (19, 28)]);
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let legend = SemanticTokensLegend {
+            token_types: vec![SemanticTokenType::VARIABLE, SemanticTokenType::TYPE],
+            token_modifiers: vec![
+                SemanticTokenModifier::DECLARATION,
+                SemanticTokenModifier::new("mutable"),
+            ],
+        };
+        let rope = Rope::from("let mut ä = 1;\nlet b: u8 = ä;\n");
+        let mut data = vec![0, 8, 1, 0, 3, 1, 4, 1, 0, 1, 0, 3, 2, 1, 0];
+        assert_eq!(
+            semantic_line_styles(&rope, &legend, &data),
+            vec![
+                LineStyles {
+                    line: 0,
+                    styles: vec![(
+                        8,
+                        10,
+                        "variable.declaration.mutable".to_string()
+                    )],
+                },
+                LineStyles {
+                    line: 1,
+                    styles: vec![
+                        (4, 5, "variable.declaration".to_string()),
+                        (7, 9, "type".to_string()),
+                    ],
+                },
+            ]
+        );
+
+        // `b` is no longer a token, and `ä` of the second line is
+        let token = |delta_line, delta_start, length, token_type| SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset: 0,
+        };
+        apply_semantic_tokens_edits(
+            &mut data,
+            vec![
+                SemanticTokensEdit {
+                    start: 5,
+                    delete_count: 10,
+                    data: Some(vec![token(1, 7, 2, 1)]),
+                },
+                SemanticTokensEdit {
+                    start: 15,
+                    delete_count: 0,
+                    data: Some(vec![token(0, 5, 1, 0)]),
+                },
+            ],
+        );
+        assert_eq!(data, vec![0, 8, 1, 0, 3, 1, 7, 2, 1, 0, 0, 5, 1, 0, 0]);
+        assert_eq!(
+            semantic_line_styles(&rope, &legend, &data)[1].styles,
+            vec![(7, 9, "type".to_string()), (12, 14, "variable".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_signature_help_response() {
+        let help = SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: "fn add(ä: i32, b: i32) -> i32".to_string(),
+                documentation: Some(Documentation::String("Adds".to_string())),
+                parameters: Some(vec![
+                    ParameterInformation {
+                        label: ParameterLabel::LabelOffsets([7, 13]),
+                        documentation: None,
+                    },
+                    ParameterInformation {
+                        label: ParameterLabel::Simple("b: i32".to_string()),
+                        documentation: None,
+                    },
+                ]),
+                active_parameter: None,
+            }],
+            active_signature: Some(3),
+            active_parameter: Some(1),
+        };
+        let response =
+            signature_help_response(BufferId(1), 2, Position::new(0, 0), Some(help));
+        assert_eq!(response.active_signature, 0);
+        assert_eq!(response.active_parameter, Some(1));
+        let signature = &response.signatures[0];
+        assert_eq!(signature.documentation.as_deref(), Some("Adds"));
+        assert_eq!(
+            &signature.label[signature.parameters[0].0..signature.parameters[0].1],
+            "ä: i32"
+        );
+        assert_eq!(
+            &signature.label[signature.parameters[1].0..signature.parameters[1].1],
+            "b: i32"
+        );
+
+        let response =
+            signature_help_response(BufferId(1), 2, Position::new(0, 0), None);
+        assert!(response.signatures.is_empty());
+    }
+
+    #[test]
+    fn test_word_highlights() {
+        let rope = Rope::from("let foo = 1;\nfoo_bar(foo);\n  foo\n");
+        assert_eq!(None, word_at(&rope, 9));
+        assert_eq!(None, word_at(&rope, 27));
+        let word = word_at(&rope, 7).unwrap();
+        assert_eq!((4, 7), word);
+        assert_eq!(Some((4, 7)), word_at(&rope, 5));
+
+        let ranges: Vec<Range> = word_highlights(&rope, word)
+            .into_iter()
+            .map(|highlight| highlight.range)
+            .collect();
+        assert_eq!(
+            vec![
+                Range::new(Position::new(0, 4), Position::new(0, 7)),
+                Range::new(Position::new(1, 8), Position::new(1, 11)),
+                Range::new(Position::new(2, 2), Position::new(2, 5)),
+            ],
+            ranges
+        );
+    }
+
+    #[test]
+    fn test_fallback_document_links() {
+        let workspace = TestDir::new("document-links");
+        std::fs::create_dir_all(workspace.join("src")).unwrap();
+        std::fs::write(workspace.join("src/main.rs"), "").unwrap();
+
+        let rope = Rope::from(
+            "see https://lapce.dev/docs.\nerror at src/main.rs:10:5, not src/lib.rs\n",
+        );
+        let range = Range::new(Position::new(0, 0), Position::new(1, 0));
+        let links = fallback_document_links(&rope, range, &workspace);
+
+        assert_eq!(2, links.len());
+        assert_eq!(Some("https://lapce.dev/docs".to_string()), links[0].target);
+        assert_eq!(
+            Range::new(Position::new(0, 4), Position::new(0, 26)),
+            links[0].range
+        );
+        assert_eq!(
+            Range::new(Position::new(1, 9), Position::new(1, 25)),
+            links[1].range
+        );
+        let target = Url::parse(links[1].target.as_ref().unwrap()).unwrap();
+        assert_eq!(Some("L10,5"), target.fragment());
+        assert!(target.path().ends_with("src/main.rs"));
+    }
+
+    #[test]
+    fn test_syntax_selection_ranges() {
+        let rope = Rope::from("fn main() {\n    let x = foo(1, 2);\n}\n");
+        let chains = syntax_selection_ranges(
+            &rope,
+            "rust",
+            &[Position::new(1, 13), Position::new(1, 4)],
+        );
+        let range = |start: (u32, u32), end: (u32, u32)| {
+            Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+        };
+        let chain = &chains[0];
+        // `foo`, the call, the let, the block, the function, the file
+        assert_eq!(range((1, 12), (1, 15)), chain[0]);
+        assert_eq!(range((1, 12), (1, 21)), chain[1]);
+        assert!(chain.contains(&range((1, 4), (1, 22))));
+        assert!(chain.contains(&range((0, 10), (2, 1))));
+        assert!(chain.windows(2).all(|ranges| ranges[0] != ranges[1]));
+        assert!(chains[1].contains(&range((1, 4), (1, 22))));
+
+        let chains = syntax_selection_ranges(
+            &rope,
+            "plaintext",
+            &[Position::new(1, 13), Position::new(5, 0)],
+        );
+        assert_eq!(
+            vec![
+                range((1, 12), (1, 15)),
+                range((1, 0), (1, 22)),
+                range((0, 0), (3, 0)),
+            ],
+            chains[0]
+        );
+        assert!(chains[1].is_empty());
+    }
+
+    #[test]
+    fn test_lsp_capabilities() {
+        let capabilities = ServerCapabilities {
+            rename_provider: Some(OneOf::Left(true)),
+            hover_provider: Some(HoverProviderCapability::Simple(false)),
+            ..Default::default()
+        };
+        let registered: HashSet<String> =
+            vec!["textDocument/formatting".to_string()]
+                .into_iter()
+                .collect();
+        let caps = LspCapabilities::new(&capabilities, false, &registered);
+        assert!(caps.rename);
+        assert!(!caps.prepare_rename);
+        assert!(!caps.hover);
+        assert!(caps.formatting);
+        assert!(!caps.range_formatting);
+
+        let other = LspCapabilities {
+            hover: true,
+            ..Default::default()
+        };
+        let union = LspCapabilities::union(vec![caps, other].iter());
+        assert!(union.rename && union.hover && union.formatting);
+        assert!(!union.completion);
+    }
+
+    #[test]
+    fn test_pending_request_params() {
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        let params = Params::from(
+            serde_json::to_value(TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                position: Position::new(3, 4),
+            })
+            .unwrap(),
+        );
+        let request = PendingRequest::new(
+            "textDocument/hover",
+            &params,
+            Box::new(|_: &LspClient, _: Result<Value>| {}),
+        );
+        assert_eq!(Some(uri.as_str()), request.uri.as_deref());
+        assert_eq!(Some(Position::new(3, 4)), request.position);
+
+        let params = Params::from(json!({
+            "textDocument": { "uri": uri },
+            "range": Range::new(Position::new(1, 0), Position::new(2, 0)),
+        }));
+        let request = PendingRequest::new(
+            "textDocument/codeAction",
+            &params,
+            Box::new(|_: &LspClient, _: Result<Value>| {}),
+        );
+        assert_eq!(Some(Position::new(1, 0)), request.position);
+
+        let request = PendingRequest::new(
+            "workspace/symbol",
+            &Params::from(json!({ "query": "foo" })),
+            Box::new(|_: &LspClient, _: Result<Value>| {}),
+        );
+        assert_eq!(None, request.uri);
+        assert_eq!(None, request.position);
+    }
+
+    #[test]
+    fn test_fallback_folding_ranges() {
+        let rope = Rope::from(
+            "// region: helpers\nfn main() {\n    let x = 1;\n\n    if x {\n        foo();\n    }\n}\n// endregion\n",
+        );
+        let range = |start_line, end_line, kind| FoldingRangeItem {
+            start_line,
+            end_line,
+            kind,
+        };
+        let ranges = valid_folding_ranges(
+            fallback_folding_ranges(&rope, "rust"),
+            rope.measure::<LinesMetric>(),
+        );
+        assert_eq!(
+            vec![
+                range(0, 8, Some(FoldingRangeKind::Region)),
+                range(1, 6, None),
+                range(4, 5, None),
+            ],
+            ranges
+        );
+        assert_eq!(None, region_marker("rust", "// regional"));
+        assert_eq!(None, region_marker("plaintext", "// region"));
+
+        let ranges = valid_folding_ranges(
+            vec![range(3, 2, None), range(1, 4, None), range(1, 3, None)],
+            3,
+        );
+        assert!(ranges.is_empty());
+        let ranges =
+            valid_folding_ranges(vec![range(0, 1, None), range(0, 2, None)], 3);
+        assert_eq!(vec![range(0, 2, None)], ranges);
+    }
+
+    #[test]
+    fn test_hover_sections() {
+        let section =
+            |language: Option<&str>, value: &str, is_markdown| HoverSection {
+                language: language.map(|language| language.to_string()),
+                value: value.to_string(),
+                is_markdown,
+            };
+
+        let markup = HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: "```rust\r\nfn main()\r\n```\r\n\r\n\
+                    The entry point.\r\n\n```\nplain\n```"
+                .to_string(),
+        });
+        assert_eq!(
+            vec![
+                section(Some("rust"), "fn main()", false),
+                section(None, "The entry point.", true),
+                section(None, "plain", false),
+            ],
+            hover_sections(markup)
+        );
+
+        let marked = HoverContents::Array(vec![
+            MarkedString::LanguageString(LanguageString {
+                language: "python".to_string(),
+                value: "def f(): ...".to_string(),
+            }),
+            MarkedString::String("".to_string()),
+            MarkedString::String("*docs*".to_string()),
+        ]);
+        assert_eq!(
+            vec![
+                section(Some("python"), "def f(): ...", false),
+                section(None, "*docs*", true),
+            ],
+            hover_sections(marked)
+        );
+
+        let plain = HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::PlainText,
+            value: "```not code```".to_string(),
+        });
+        assert_eq!(
+            vec![section(None, "```not code```", false)],
+            hover_sections(plain)
+        );
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |pattern: &str, path: &str| {
+            glob_to_regex(pattern).unwrap().is_match(path)
+        };
+        // what rust-analyzer registers for a workspace
+        let root = "/home/user/project";
+        assert!(matches(
+            &format!("{}/**/*.rs", root),
+            "/home/user/project/main.rs"
+        ));
+        assert!(matches(
+            &format!("{}/**/*.rs", root),
+            "/home/user/project/src/bin/main.rs"
+        ));
+        assert!(!matches(
+            &format!("{}/**/*.rs", root),
+            "/home/user/other/src/main.rs"
+        ));
+        assert!(!matches(
+            &format!("{}/**/*.rs", root),
+            "/home/user/project/a.rsx"
+        ));
+        assert!(matches(
+            &format!("{}/**/Cargo.toml", root),
+            "/home/user/project/crates/core/Cargo.toml"
+        ));
+        assert!(matches(
+            &format!("{}/**/Cargo.lock", root),
+            "/home/user/project/Cargo.lock"
+        ));
+        assert!(matches("**/rust-analyzer.toml", "/x/rust-analyzer.toml"));
+
+        assert!(matches("**/{Cargo.toml,Cargo.lock}", "/a/b/Cargo.lock"));
+        assert!(!matches("**/{Cargo.toml,Cargo.lock}", "/a/b/Cargo.json"));
+        assert!(matches("*.{ts,js}", "/src/app.js"));
+        assert!(!matches("/src/*.ts", "/src/lib/app.ts"));
+        assert!(matches("/src/file?.[!c]s", "/src/file1.ts"));
+        assert!(!matches("/src/file?.[!c]s", "/src/file1.cs"));
+        assert!(matches("/src/**", "/src/lib/app.ts"));
+        assert!(matches("/a+b/(x).rs", "/a+b/(x).rs"));
+    }
+
+    #[test]
+    fn test_tcp_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let message = read_message(&mut reader).unwrap();
+            stream
+                .write_all(
+                    prepare_lsp_json(&json!({ "echo": message }))
+                        .unwrap()
+                        .as_bytes(),
+                )
+                .unwrap();
+        });
+
+        let mut connection =
+            LspConnection::open(&format!("tcp://127.0.0.1:{}?timeout=500", port))
+                .unwrap();
+        assert!(connection.stderr.is_none());
+        connection
+            .writer
+            .write_all(prepare_lsp_json(&json!("ping")).unwrap().as_bytes())
+            .unwrap();
+        connection.writer.flush().unwrap();
+        let reply = read_message(&mut connection.reader).unwrap();
+        assert_eq!(json!({ "echo": "\"ping\"" }).to_string(), reply);
+        server.join().unwrap();
+        assert_eq!(None, connection.transport.exit_code());
+
+        assert!(
+            LspConnection::open(&format!("tcp://127.0.0.1:{}?retries=0", port))
+                .is_err()
+        );
     }
 }
@@ -1,19 +1,34 @@
 use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
 use home::home_dir;
+use parking_lot::{Condvar, Mutex};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Read;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Child;
 use std::process::Command;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 use toml;
 use wasmer::ChainableNamedResolver;
 use wasmer::ImportObject;
@@ -28,10 +43,33 @@ use xi_rpc::RpcPeer;
 
 use crate::buffer::BufferId;
 use crate::core_proxy::CoreProxy;
-use crate::dispatch::Dispatcher;
+use crate::dispatch::{resolve_workspace_path, Dispatcher};
+use crate::file_ops::copy_dir;
+use crate::lsp::LspServerId;
+use crate::settings::setting;
+use crate::shell_env::resolved_env;
+use crate::terminal::Counter as TermCounter;
 
 pub type PluginName = String;
 
+const PLUGIN_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_PLUGIN_INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+const PLUGIN_DIR_MOUNT: &str = "/plugin";
+const PLUGIN_DATA_MOUNT: &str = "/data";
+const PLUGIN_LOG_FILE: &str = "plugin.log";
+const PLUGIN_LOG_MAX_SIZE: u64 = 4 * 1024 * 1024;
+/// A plugin is marked faulted after this many protocol errors in a row.
+const MAX_CONSECUTIVE_PLUGIN_ERRORS: usize = 5;
+
+/// Plugin API versions this proxy speaks. A wasm plugin has to export its
+/// version as `plugin_api_version`; a manifest's `api_version`, checked
+/// before the module is loaded, defaults to the oldest.
+pub const SUPPORTED_API_VERSIONS: RangeInclusive<u32> = 1..=2;
+const DEFAULT_API_VERSION: u32 = 1;
+/// From this API version on, messages the proxy writes to a plugin are
+/// framed with `Content-Length` headers.
+const FRAMED_API_VERSION: u32 = 2;
+
 #[derive(Clone, Debug, Default)]
 pub struct Counter(usize);
 
@@ -46,32 +84,421 @@ impl Counter {
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct PluginId(pub usize);
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct PluginDescription {
     pub name: String,
     pub version: String,
     pub exec_path: PathBuf,
+    pub kind: Option<PluginKind>,
+    pub api_version: Option<u32>,
+    pub dependencies: Option<Vec<PluginName>>,
+    pub activation: Option<PluginActivation>,
+    pub commands: Option<Vec<PluginCommand>>,
+    /// Extra environment variables for a wasm plugin.
+    pub env: Option<BTreeMap<String, String>>,
+    /// Variables a wasm plugin gets from the proxy's own environment;
+    /// it sees none of them otherwise.
+    pub inherit_env: Option<Vec<String>>,
+    #[serde(skip)]
+    pub disabled: bool,
     dir: Option<PathBuf>,
     configuration: Option<Value>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginKind {
+    /// Runs in the proxy on a wasm32-wasi runtime.
+    Wasm,
+    /// A native executable talking JSON-RPC over its stdin/stdout.
+    Process,
+}
+
+impl PluginDescription {
+    pub fn api_version(&self) -> u32 {
+        self.api_version.unwrap_or(DEFAULT_API_VERSION)
+    }
+
+    /// The manifest's `kind`, or one inferred from `exec_path`: a `.wasm`
+    /// file is a wasm plugin, anything else a process.
+    pub fn kind(&self) -> PluginKind {
+        self.kind.unwrap_or_else(|| {
+            match self.exec_path.extension().and_then(|ext| ext.to_str()) {
+                Some("wasm") => PluginKind::Wasm,
+                _ => PluginKind::Process,
+            }
+        })
+    }
+}
+
+/// Events that start a plugin lazily. A plugin without an `[activation]`
+/// table in its manifest is started with the proxy.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct PluginActivation {
+    pub on_language: Option<Vec<String>>,
+    pub on_file_exists: Option<Vec<String>>,
+    pub on_command: Option<Vec<String>>,
+}
+
+/// A command a plugin contributes to the palette, from its manifest's
+/// `[[commands]]` or registered at runtime.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginCommand {
+    pub id: String,
+    pub title: String,
+}
+
+/// A plugin command as listed to the core. `id` is prefixed with
+/// `<plugin>.` when more than one plugin uses the same command id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginCommandItem {
+    pub id: String,
+    pub title: String,
+    pub plugin: PluginName,
+    pub command: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PluginStatus {
+    pub name: PluginName,
+    pub version: String,
+    pub exec_path: PathBuf,
+    pub running: bool,
+    pub disabled: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    InvalidField {
+        path: PathBuf,
+        field: &'static str,
+        reason: String,
+    },
+}
+
+impl ManifestError {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            ManifestError::Io { path, .. }
+            | ManifestError::Parse { path, .. }
+            | ManifestError::InvalidField { path, .. } => path,
+        }
+    }
+
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            ManifestError::InvalidField { field, .. } => Some(*field),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            ManifestError::Parse { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            ManifestError::InvalidField {
+                path,
+                field,
+                reason,
+            } => write!(f, "{}: `{}` {}", path.display(), field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// Failures talking to a wasm plugin over its pipes.
+#[derive(Debug)]
+pub enum PluginError {
+    /// The pipe isn't there or reading/writing it failed.
+    Pipe(std::io::Error),
+    /// What the plugin wrote isn't the JSON we expected.
+    Decode {
+        payload: String,
+        error: serde_json::Error,
+    },
+    Encode(serde_json::Error),
+    Trap(wasmer::RuntimeError),
+    /// The module doesn't export a function every plugin has to.
+    MissingExport(&'static str),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Pipe(error) => write!(f, "pipe error: {}", error),
+            PluginError::Decode { payload, error } => {
+                write!(f, "can't decode {:?}: {}", payload, error)
+            }
+            PluginError::Encode(error) => write!(f, "can't encode: {}", error),
+            PluginError::Trap(error) => write!(f, "trap: {}", error),
+            PluginError::MissingExport(name) => {
+                write!(f, "the module doesn't export `{}`", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<std::io::Error> for PluginError {
+    fn from(error: std::io::Error) -> Self {
+        PluginError::Pipe(error)
+    }
+}
+
+impl From<wasmer_wasi::FsError> for PluginError {
+    fn from(error: wasmer_wasi::FsError) -> Self {
+        PluginError::Pipe(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            format!("{:?}", error),
+        ))
+    }
+}
+
+impl From<wasmer::RuntimeError> for PluginError {
+    fn from(error: wasmer::RuntimeError) -> Self {
+        PluginError::Trap(error)
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct DisabledPlugins {
+    disabled: BTreeSet<PluginName>,
+}
+
 #[derive(WasmerEnv, Clone)]
 pub(crate) struct PluginEnv {
+    name: PluginName,
     wasi_env: WasiEnv,
     dispatcher: Dispatcher,
+    configuration: Arc<Mutex<Value>>,
+    commands: Arc<Mutex<Vec<PluginCommand>>>,
+    log: Arc<Mutex<PluginLog>>,
+    status: PluginRunStatus,
+    consecutive_errors: Arc<AtomicUsize>,
+    api_version: Arc<AtomicU32>,
+}
+
+/// How messages are delimited on a plugin's pipes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// `Content-Length: <n>\r\n\r\n` followed by `n` bytes of JSON.
+    ContentLength,
+    /// One JSON object per line, for plugins older than
+    /// `FRAMED_API_VERSION`.
+    Lines,
+}
+
+impl Framing {
+    fn for_api_version(version: u32) -> Framing {
+        if version >= FRAMED_API_VERSION {
+            Framing::ContentLength
+        } else {
+            Framing::Lines
+        }
+    }
+}
+
+/// State a running plugin shares with the catalog.
+#[derive(Clone, Default)]
+struct PluginShared {
+    configuration: Arc<Mutex<Value>>,
+    /// Commands the plugin registered at runtime.
+    commands: Arc<Mutex<Vec<PluginCommand>>>,
+}
+
+/// Where a plugin's diagnostics go: the proxy's stderr, prefixed with the
+/// plugin name, `<plugin dir>/plugin.log`, and `plugin_log` notifications
+/// to the core. The log file is rotated to `plugin.log.1` once it grows
+/// past `PLUGIN_LOG_MAX_SIZE`.
+pub(crate) struct PluginLog {
+    name: PluginName,
+    dispatcher: Dispatcher,
+    path: Option<PathBuf>,
+    file: Option<fs::File>,
+    size: u64,
+    partial: String,
+}
+
+impl PluginLog {
+    fn new(dispatcher: Dispatcher, plugin_desc: &PluginDescription) -> PluginLog {
+        PluginLog {
+            name: plugin_desc.name.clone(),
+            dispatcher,
+            path: plugin_desc
+                .dir
+                .as_ref()
+                .map(|dir| dir.join(PLUGIN_LOG_FILE)),
+            file: None,
+            size: 0,
+            partial: String::new(),
+        }
+    }
+
+    pub fn log(&mut self, level: &str, message: &str) {
+        for line in message.lines() {
+            eprintln!("[{}] {}", self.name, line);
+            if let Err(e) = self.write_file(level, line) {
+                eprintln!("plugin {} log file err {}", self.name, e);
+            }
+        }
+        self.dispatcher.send_notification(
+            "plugin_log",
+            json!({
+                "name": self.name,
+                "level": level,
+                "message": message,
+            }),
+        );
+    }
+
+    /// Logs the complete lines in `output`, keeping a trailing partial
+    /// line until the rest of it arrives.
+    fn log_output(&mut self, output: &str) {
+        self.partial.push_str(output);
+        if let Some(pos) = self.partial.rfind('\n') {
+            let lines: String = self.partial.drain(..pos + 1).collect();
+            self.log("info", lines.trim_end_matches('\n'));
+        }
+    }
+
+    fn write_file(&mut self, level: &str, line: &str) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if self.size > PLUGIN_LOG_MAX_SIZE {
+            self.file = None;
+            fs::rename(path, path.with_extension("log.1"))?;
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            self.size = 0;
+            self.file = Some(file);
+        }
+        let line = format!("{} {}\n", level, line);
+        self.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
 }
 
+/// Handle to a plugin running on its own thread. The wasm instance never
+/// leaves that thread; everything else talks to it over `sender`.
 pub(crate) struct PluginNew {
-    instance: wasmer::Instance,
-    env: PluginEnv,
+    name: PluginName,
+    sender: crossbeam_channel::Sender<PluginMessage>,
+    status: PluginRunStatus,
+    shared: PluginShared,
+}
+
+enum PluginMessage {
+    /// Writes `input` to the plugin's stdin and calls the exported
+    /// `function`, if the plugin has one.
+    Call {
+        function: &'static str,
+        input: Value,
+    },
+    Shutdown {
+        reply: crossbeam_channel::Sender<()>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PluginRunState {
+    Starting,
+    Running,
+    Stopped,
+    Failed(String),
+}
+
+/// What a plugin's thread owns once the plugin is initialized.
+enum PluginInstance {
+    Wasm {
+        instance: wasmer::Instance,
+        env: PluginEnv,
+    },
+    Process(Plugin),
+}
+
+impl PluginInstance {
+    fn call(&mut self, function: &str, input: &Value) -> Result<()> {
+        match self {
+            PluginInstance::Wasm { instance, env } => {
+                let function = match instance.exports.get_function(function) {
+                    Ok(f) => f,
+                    Err(_) => return Ok(()),
+                };
+                wasi_write_object(&env.wasi_env, env.framing(), input)?;
+                let result = function.call(&[]);
+                env.drain_stderr();
+                result.map_err(PluginError::from)?;
+            }
+            PluginInstance::Process(plugin) => {
+                if let Some(status) = plugin.process.try_wait()? {
+                    return Err(anyhow!("process exited with {}", status));
+                }
+                plugin.peer.send_rpc_notification(function, input);
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(self) -> Result<()> {
+        match self {
+            PluginInstance::Wasm { instance, env } => {
+                if let Ok(shutdown) = instance.exports.get_function("shutdown") {
+                    let result = shutdown.call(&[]);
+                    env.drain_stderr();
+                    result.map_err(PluginError::from)?;
+                }
+            }
+            PluginInstance::Process(mut plugin) => {
+                plugin.peer.send_rpc_notification("shutdown", &json!({}));
+                // give it half the shutdown timeout to exit on its own
+                for _ in 0..(PLUGIN_SHUTDOWN_TIMEOUT.as_millis() / 20) {
+                    if plugin.process.try_wait()?.is_some() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
+type PluginRunStatus = Arc<(Mutex<PluginRunState>, Condvar)>;
+
+/// A process plugin, spoken to over JSON-RPC on its stdin/stdout.
 pub struct Plugin {
-    id: PluginId,
-    dispatcher: Dispatcher,
-    configuration: Option<Value>,
+    name: PluginName,
     peer: RpcPeer,
-    name: String,
     process: Child,
 }
 
@@ -79,7 +506,11 @@ pub struct PluginCatalog {
     id_counter: Counter,
     items: HashMap<PluginName, PluginDescription>,
     plugins: HashMap<PluginId, PluginNew>,
+    disabled: BTreeSet<PluginName>,
+    errors: HashMap<PluginName, String>,
+    manifest_errors: Vec<ManifestError>,
     store: wasmer::Store,
+    initialize_timeout: Duration,
 }
 
 impl PluginCatalog {
@@ -88,181 +519,1696 @@ impl PluginCatalog {
             id_counter: Counter::default(),
             items: HashMap::new(),
             plugins: HashMap::new(),
+            disabled: BTreeSet::new(),
+            errors: HashMap::new(),
+            manifest_errors: Vec::new(),
             store: wasmer::Store::default(),
+            initialize_timeout: DEFAULT_PLUGIN_INITIALIZE_TIMEOUT,
         }
     }
 
-    pub fn reload(&mut self) {
+    pub fn reload(&mut self, dispatcher: Dispatcher) {
         eprintln!("plugin reload from paths");
+        self.stop_all();
         self.items.clear();
-        self.plugins.clear();
+        self.errors.clear();
+        self.initialize_timeout = user_plugin_initialize_timeout();
         self.load();
+        for error in &self.manifest_errors {
+            dispatcher.send_notification(
+                "plugin_manifest_error",
+                json!({
+                    "path": error.path(),
+                    "field": error.field(),
+                    "error": error.to_string(),
+                }),
+            );
+        }
+        self.start_all(dispatcher);
+    }
+
+    pub fn manifest_errors(&self) -> &[ManifestError] {
+        &self.manifest_errors
     }
 
     pub fn load(&mut self) {
-        let all_manifests = find_all_manifests();
+        self.disabled = load_disabled_plugins();
+        self.manifest_errors.clear();
+        let all_manifests = match find_all_manifests() {
+            Ok(manifests) => manifests,
+            Err(e) => {
+                eprintln!("find plugin manifests err {}", e);
+                Vec::new()
+            }
+        };
         for manifest_path in &all_manifests {
             match load_manifest(manifest_path) {
-                Err(e) => eprintln!("load manifest err {}", e),
-                Ok(manifest) => {
+                Err(e) => {
+                    eprintln!("load manifest err {}", e);
+                    self.manifest_errors.push(e);
+                }
+                Ok(mut manifest) => {
+                    manifest.disabled = self.disabled.contains(&manifest.name);
                     self.items.insert(manifest.name.clone(), manifest);
                 }
             }
         }
     }
 
-    pub fn start_all(&mut self, dispatcher: Dispatcher) {
-        for (_, manifest) in self.items.clone().iter() {
-            if let Ok(plugin) =
-                self.start_plugin(dispatcher.clone(), manifest.clone())
-            {
-                let id = self.next_plugin_id();
-                self.plugins.insert(id, plugin);
+    /// Loads the plugin whose manifest is at `manifest_path` and starts it
+    /// unless it waits for an activation event, leaving the other plugins
+    /// untouched.
+    pub fn load_plugin(
+        &mut self,
+        dispatcher: Dispatcher,
+        manifest_path: &PathBuf,
+    ) -> Result<PluginName> {
+        let mut manifest = load_manifest(manifest_path)?;
+        let name = manifest.name.clone();
+        manifest.disabled = self.disabled.contains(&name);
+        let eager = manifest.activation.is_none();
+        self.items.insert(name.clone(), manifest);
+        if eager {
+            self.activate_plugins(&dispatcher, vec![name.clone()]);
+            if let Some(error) = self.errors.get(&name) {
+                return Err(anyhow!(error.clone()));
             }
         }
+        Ok(name)
     }
 
-    fn start_plugin(
+    /// Stops the plugin and removes its directory from disk.
+    pub fn uninstall_plugin(&mut self, name: &str) -> Result<()> {
+        let manifest = self
+            .items
+            .remove(name)
+            .ok_or(anyhow!("plugin {} not found", name))?;
+        let ids: Vec<PluginId> = self
+            .plugins
+            .iter()
+            .filter(|(_, plugin)| plugin.name == name)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            self.stop_plugin(id);
+        }
+        self.errors.remove(name);
+        if let Some(dir) = manifest.dir {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn disable_plugin(&mut self, name: &str) -> Result<()> {
+        self.disabled.insert(name.to_string());
+        save_disabled_plugins(&self.disabled)?;
+        if let Some(manifest) = self.items.get_mut(name) {
+            manifest.disabled = true;
+        }
+        let ids: Vec<PluginId> = self
+            .plugins
+            .iter()
+            .filter(|(_, plugin)| plugin.name == name)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            self.stop_plugin(id);
+        }
+        Ok(())
+    }
+
+    pub fn enable_plugin(
         &mut self,
         dispatcher: Dispatcher,
-        plugin_desc: PluginDescription,
-    ) -> Result<PluginNew> {
-        let module = wasmer::Module::from_file(&self.store, plugin_desc.exec_path)?;
+        name: &str,
+    ) -> Result<()> {
+        self.disabled.remove(name);
+        save_disabled_plugins(&self.disabled)?;
+        // give a plugin that failed before another chance
+        let failed: Vec<PluginId> = self
+            .plugins
+            .iter()
+            .filter(|(_, plugin)| plugin.name == name && plugin.failed())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in failed {
+            self.stop_plugin(id);
+        }
+        let manifest = self
+            .items
+            .get_mut(name)
+            .ok_or(anyhow!("plugin {} not found", name))?;
+        manifest.disabled = false;
+        if manifest.activation.is_none() {
+            self.activate_plugins(&dispatcher, vec![name.to_string()]);
+            if let Some(error) = self.errors.get(name) {
+                return Err(anyhow!(error.clone()));
+            }
+        }
+        Ok(())
+    }
 
-        let output = Pipe::new();
-        let input = Pipe::new();
-        let mut wasi_env = WasiState::new("Lapce")
-            .stdin(Box::new(input))
-            .stdout(Box::new(output))
-            .finalize()?;
-        let wasi = wasi_env.import_object(&module)?;
+    /// Starts every enabled plugin that doesn't wait for an activation
+    /// event. Plugins initialize on their own threads, so this returns
+    /// right away and the outcome reaches the core as `plugin_started` or
+    /// `plugin_error` notifications.
+    pub fn start_all(&mut self, dispatcher: Dispatcher) {
+        let (_, errors) = plugin_start_order(&self.items);
+        for (name, error) in errors {
+            self.plugin_error(&dispatcher, &name, error);
+        }
 
-        let plugin_env = PluginEnv {
-            wasi_env,
-            dispatcher,
-        };
-        let lapce = lapce_exports(&self.store, &plugin_env);
-        let instance = wasmer::Instance::new(&module, &lapce.chain_back(wasi))?;
+        let names = self
+            .items
+            .values()
+            .filter(|manifest| manifest.activation.is_none())
+            .map(|manifest| manifest.name.clone())
+            .collect();
+        self.activate_plugins(&dispatcher, names);
+    }
 
-        let initialize = instance.exports.get_function("initialize")?;
-        wasi_write_object(
-            &plugin_env.wasi_env,
-            &plugin_desc.configuration.unwrap_or(serde_json::json!({})),
-        );
-        initialize.call(&[])?;
+    pub fn activate_for_language(
+        &mut self,
+        dispatcher: &Dispatcher,
+        language_id: &str,
+    ) {
+        let names = self.plugins_activated_by(|activation| {
+            activation
+                .on_language
+                .iter()
+                .flatten()
+                .any(|language| language == language_id)
+        });
+        self.activate_plugins(dispatcher, names);
+    }
 
-        Ok(PluginNew {
-            instance,
-            env: plugin_env,
-        })
+    pub fn activate_for_workspace(
+        &mut self,
+        dispatcher: &Dispatcher,
+        workspace: &Path,
+    ) {
+        let names = self.plugins_activated_by(|activation| {
+            activation
+                .on_file_exists
+                .iter()
+                .flatten()
+                .any(|file| workspace.join(file).exists())
+        });
+        self.activate_plugins(dispatcher, names);
     }
 
-    pub fn next_plugin_id(&mut self) -> PluginId {
-        PluginId(self.id_counter.next())
+    pub fn activate_for_command(&mut self, dispatcher: &Dispatcher, command: &str) {
+        let names = self.plugins_activated_by(|activation| {
+            activation.on_command.iter().flatten().any(|c| c == command)
+        });
+        self.activate_plugins(dispatcher, names);
     }
-}
 
-pub(crate) fn lapce_exports(store: &Store, plugin_env: &PluginEnv) -> ImportObject {
-    macro_rules! lapce_export {
-        ($($host_function:ident),+ $(,)?) => {
-            wasmer::imports! {
-                "lapce" => {
-                    $(stringify!($host_function) =>
-                        wasmer::Function::new_native_with_env(store, plugin_env.clone(), $host_function),)+
+    /// Commands of every enabled plugin, from the manifests and the ones
+    /// registered by running plugins.
+    pub fn plugin_commands(&self) -> Vec<PluginCommandItem> {
+        let mut commands: Vec<(PluginName, PluginCommand)> = Vec::new();
+        for manifest in self.items.values().filter(|m| !m.disabled) {
+            let mut plugin_commands: Vec<PluginCommand> =
+                manifest.commands.clone().unwrap_or_default();
+            for plugin in self.plugins.values().filter(|p| p.name == manifest.name) {
+                for command in plugin.shared.commands.lock().iter() {
+                    if !plugin_commands.iter().any(|c| c.id == command.id) {
+                        plugin_commands.push(command.clone());
+                    }
                 }
             }
+            commands.extend(
+                plugin_commands
+                    .into_iter()
+                    .map(|command| (manifest.name.clone(), command)),
+            );
         }
+
+        let mut items: Vec<PluginCommandItem> = commands
+            .iter()
+            .map(|(plugin, command)| {
+                let duplicate =
+                    commands.iter().filter(|(_, c)| c.id == command.id).count() > 1;
+                PluginCommandItem {
+                    id: if duplicate {
+                        format!("{}.{}", plugin, command.id)
+                    } else {
+                        command.id.clone()
+                    },
+                    title: command.title.clone(),
+                    plugin: plugin.clone(),
+                    command: command.id.clone(),
+                }
+            })
+            .collect();
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        items
     }
 
-    lapce_export! {
-        host_handle_notification,
+    /// Runs a command from `plugin_commands` by sending the owning plugin
+    /// an `execute_command` notification, activating it first if the
+    /// command is one of its activation events.
+    pub fn execute_command(
+        &mut self,
+        dispatcher: &Dispatcher,
+        id: &str,
+        args: Option<Value>,
+    ) -> Result<()> {
+        let item = self
+            .plugin_commands()
+            .into_iter()
+            .find(|item| item.id == id)
+            .ok_or(anyhow!("plugin command {} not found", id))?;
+        self.activate_for_command(dispatcher, &item.command);
+        self.notify_plugin(
+            &item.plugin,
+            "execute_command",
+            json!({
+                "id": item.command,
+                "args": args,
+            }),
+        )
     }
-}
 
-fn host_handle_notification(plugin_env: &PluginEnv) {
-    let notification: Result<PluginNotification> =
-        wasi_read_object(&plugin_env.wasi_env);
-    if let Ok(notification) = notification {
-        match notification {
-            PluginNotification::StartLspServer {
-                exec_path,
-                language_id,
-                options,
-            } => {
-                plugin_env.dispatcher.lsp.lock().start_server(
-                    &exec_path,
-                    &language_id,
-                    options.clone(),
-                );
+    fn plugins_activated_by(
+        &self,
+        f: impl Fn(&PluginActivation) -> bool,
+    ) -> Vec<PluginName> {
+        self.items
+            .values()
+            .filter(|manifest| manifest.activation.as_ref().map(&f).unwrap_or(false))
+            .map(|manifest| manifest.name.clone())
+            .collect()
+    }
+
+    /// Starts the given plugins and, before them, the plugins they depend
+    /// on. Plugins that can't be ordered because of a missing dependency
+    /// or a cycle are skipped.
+    fn activate_plugins(&mut self, dispatcher: &Dispatcher, names: Vec<PluginName>) {
+        let (order, _) = plugin_start_order(&self.items);
+        let mut names: Vec<PluginName> = names
+            .into_iter()
+            .filter(|name| order.contains(name))
+            .collect();
+        names.sort_by_key(|name| order.iter().position(|n| n == name));
+        for name in names {
+            let _ = self.activate_plugin(dispatcher, &name);
+        }
+    }
+
+    /// Must only be called for plugins in the `plugin_start_order`, which
+    /// guarantees the recursion into dependencies terminates.
+    fn activate_plugin(
+        &mut self,
+        dispatcher: &Dispatcher,
+        name: &str,
+    ) -> Result<()> {
+        if self.plugins.values().any(|plugin| plugin.name == name) {
+            return Ok(());
+        }
+        let manifest = self
+            .items
+            .get(name)
+            .ok_or(anyhow!("plugin {} not found", name))?
+            .clone();
+        if manifest.disabled {
+            return Err(anyhow!("plugin {} is disabled", name));
+        }
+        for dep in manifest.dependencies.iter().flatten() {
+            if self.activate_plugin(dispatcher, dep).is_err() {
+                let error = format!("dependency \"{}\" is not running", dep);
+                self.plugin_error(dispatcher, name, error.clone());
+                return Err(anyhow!(error));
+            }
+        }
+        match self.start_plugin(dispatcher.clone(), manifest) {
+            Ok(plugin) => {
+                let id = self.next_plugin_id();
+                self.plugins.insert(id, plugin);
+                self.errors.remove(name);
+                Ok(())
+            }
+            Err(e) => {
+                self.plugin_error(dispatcher, name, e.to_string());
+                Err(e)
             }
         }
     }
-}
 
-pub fn wasi_read_string(wasi_env: &WasiEnv) -> Result<String> {
-    let mut state = wasi_env.state();
-    let wasi_file = state
-        .fs
-        .stdout_mut()?
-        .as_mut()
-        .ok_or(anyhow!("can't get stdout"))?;
-    let mut buf = String::new();
-    wasi_file.read_to_string(&mut buf)?;
-    Ok(buf)
-}
+    fn plugin_error(&mut self, dispatcher: &Dispatcher, name: &str, error: String) {
+        eprintln!("plugin {} not started: {}", name, error);
+        dispatcher.send_notification(
+            "plugin_error",
+            json!({
+                "name": name,
+                "error": error,
+            }),
+        );
+        self.errors.insert(name.to_string(), error);
+    }
 
-pub fn wasi_read_object<T: DeserializeOwned>(wasi_env: &WasiEnv) -> Result<T> {
-    let json = wasi_read_string(wasi_env)?;
-    Ok(serde_json::from_str(&json)?)
-}
+    pub fn plugin_statuses(&self) -> Vec<PluginStatus> {
+        let mut statuses: Vec<PluginStatus> = self
+            .items
+            .values()
+            .map(|manifest| PluginStatus {
+                name: manifest.name.clone(),
+                version: manifest.version.clone(),
+                exec_path: manifest.exec_path.clone(),
+                running: self.plugins.values().any(|plugin| {
+                    plugin.name == manifest.name
+                        && *plugin.status.0.lock() == PluginRunState::Running
+                }),
+                disabled: manifest.disabled,
+                error: self.errors.get(&manifest.name).cloned().or_else(|| {
+                    self.plugins
+                        .values()
+                        .find(|plugin| plugin.name == manifest.name)
+                        .and_then(|plugin| match &*plugin.status.0.lock() {
+                            PluginRunState::Failed(error) => Some(error.clone()),
+                            _ => None,
+                        })
+                }),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
 
-pub fn wasi_write_string(wasi_env: &WasiEnv, buf: &str) {
-    let mut state = wasi_env.state();
-    let wasi_file = state.fs.stdin_mut().unwrap().as_mut().unwrap();
-    writeln!(wasi_file, "{}\r", buf).unwrap();
-}
+    pub fn plugin_name_for_path(&self, path: &Path) -> Option<PluginName> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.items
+            .values()
+            .find(|manifest| {
+                manifest
+                    .dir
+                    .as_ref()
+                    .map(|dir| {
+                        path.starts_with(dir)
+                            && !path.starts_with(dir.join("data"))
+                            && !path
+                                .file_name()
+                                .and_then(|name| name.to_str())
+                                .map(|name| name.starts_with(PLUGIN_LOG_FILE))
+                                .unwrap_or(false)
+                    })
+                    .unwrap_or(false)
+            })
+            .map(|manifest| manifest.name.clone())
+    }
 
-pub fn wasi_write_object(wasi_env: &WasiEnv, object: &(impl Serialize + ?Sized)) {
-    wasi_write_string(wasi_env, &serde_json::to_string(&object).unwrap());
-}
+    /// Restarts a single plugin from its manifest on disk, keeping its
+    /// `PluginId` so the core can keep referring to it.
+    pub fn reload_plugin(
+        &mut self,
+        dispatcher: Dispatcher,
+        name: &str,
+    ) -> Result<()> {
+        let dir = self
+            .items
+            .get(name)
+            .and_then(|manifest| manifest.dir.clone())
+            .ok_or(anyhow!("plugin {} not found", name))?;
+        let id = self
+            .plugins
+            .iter()
+            .find(|(_, plugin)| plugin.name == name)
+            .map(|(id, _)| id.clone());
+        if let Some(id) = id.clone() {
+            self.stop_plugin(id);
+        }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "method", content = "params")]
-pub enum PluginNotification {
-    StartLspServer {
-        exec_path: String,
-        language_id: String,
-        options: Option<Value>,
-    },
-}
+        let mut manifest = match load_manifest(&dir.join("manifest.toml")) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                self.plugin_error(&dispatcher, name, e.to_string());
+                return Err(e.into());
+            }
+        };
+        manifest.disabled = self.disabled.contains(&manifest.name);
+        self.items.insert(manifest.name.clone(), manifest.clone());
+        // a lazy plugin that wasn't activated yet stays that way
+        if manifest.disabled || (id.is_none() && manifest.activation.is_some()) {
+            return Ok(());
+        }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum PluginRequest {}
+        match self.start_plugin(dispatcher.clone(), manifest) {
+            Ok(plugin) => {
+                let id = id.unwrap_or_else(|| self.next_plugin_id());
+                self.plugins.insert(id, plugin);
+                self.errors.remove(name);
+                Ok(())
+            }
+            Err(e) => {
+                self.plugin_error(&dispatcher, name, e.to_string());
+                Err(e)
+            }
+        }
+    }
 
-pub struct PluginHandler {
-    dispatcher: Dispatcher,
-}
+    pub fn stop_all(&mut self) {
+        let ids: Vec<PluginId> = self.plugins.keys().cloned().collect();
+        for id in ids {
+            self.stop_plugin(id);
+        }
+    }
 
-impl Handler for PluginHandler {
-    type Notification = PluginNotification;
-    type Request = PluginRequest;
+    /// Asks the plugin's thread to call the exported `shutdown` function,
+    /// if it has one, and drop the instance. A plugin that doesn't answer
+    /// within `PLUGIN_SHUTDOWN_TIMEOUT` is abandoned on its thread.
+    pub fn stop_plugin(&mut self, id: PluginId) {
+        let plugin = match self.plugins.remove(&id) {
+            Some(plugin) => plugin,
+            None => return,
+        };
+        if plugin.failed() {
+            return;
+        }
+        let (reply, receiver) = crossbeam_channel::bounded(1);
+        if plugin
+            .sender
+            .send(PluginMessage::Shutdown { reply })
+            .is_err()
+        {
+            return;
+        }
+        if receiver.recv_timeout(PLUGIN_SHUTDOWN_TIMEOUT).is_err() {
+            eprintln!("plugin {} shutdown timed out", plugin.name);
+        }
+    }
 
-    fn handle_notification(
+    /// Spawns the plugin's thread and returns its handle without waiting
+    /// for it to initialize. The thread waits for the plugin's
+    /// dependencies to be running first, and a watchdog marks the plugin
+    /// failed if `initialize` doesn't return within `initialize_timeout`.
+    fn start_plugin(
         &mut self,
-        ctx: &xi_rpc::RpcCtx,
-        rpc: Self::Notification,
-    ) {
-        match &rpc {
-            PluginNotification::StartLspServer {
+        dispatcher: Dispatcher,
+        plugin_desc: PluginDescription,
+    ) -> Result<PluginNew> {
+        check_api_version(plugin_desc.api_version())?;
+        let name = plugin_desc.name.clone();
+        let dependencies: Vec<(PluginName, PluginRunStatus)> = plugin_desc
+            .dependencies
+            .iter()
+            .flatten()
+            .filter_map(|dep| {
+                self.plugins
+                    .values()
+                    .find(|plugin| &plugin.name == dep)
+                    .map(|plugin| (dep.clone(), plugin.status.clone()))
+            })
+            .collect();
+        let status: PluginRunStatus =
+            Arc::new((Mutex::new(PluginRunState::Starting), Condvar::new()));
+        let shared = PluginShared {
+            configuration: Arc::new(Mutex::new(plugin_configuration(&plugin_desc))),
+            ..Default::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        {
+            let store = self.store.clone();
+            let dispatcher = dispatcher.clone();
+            let status = status.clone();
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("plugin {}", name))
+                .spawn(move || {
+                    run_plugin(
+                        store,
+                        dispatcher,
+                        plugin_desc,
+                        shared,
+                        status,
+                        dependencies,
+                        receiver,
+                    )
+                })?;
+        }
+
+        {
+            let status = status.clone();
+            let name = name.clone();
+            let timeout = self.initialize_timeout;
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                if *status.0.lock() == PluginRunState::Starting {
+                    plugin_failed(
+                        &dispatcher,
+                        &name,
+                        &status,
+                        format!("initialize timed out after {:?}", timeout),
+                    );
+                }
+            });
+        }
+
+        Ok(PluginNew {
+            name,
+            sender,
+            status,
+            shared,
+        })
+    }
+
+    /// Re-merges the user settings into every running plugin's
+    /// configuration and has its exported `configuration_changed`
+    /// function called with the new value on stdin if anything changed.
+    pub fn configuration_changed(&mut self) {
+        for plugin in self.plugins.values() {
+            let manifest = match self.items.get(&plugin.name) {
+                Some(manifest) => manifest,
+                None => continue,
+            };
+            let configuration = plugin_configuration(manifest);
+            {
+                let mut current = plugin.shared.configuration.lock();
+                if *current == configuration {
+                    continue;
+                }
+                *current = configuration.clone();
+            }
+            let _ = plugin.sender.send(PluginMessage::Call {
+                function: "configuration_changed",
+                input: configuration,
+            });
+        }
+    }
+
+    /// Queues `{"method": ..., "params": ...}` for the plugin's exported
+    /// `handle_notification` function. The call happens on the plugin's
+    /// thread, so this never blocks on the plugin.
+    pub fn notify_plugin(
+        &self,
+        name: &str,
+        method: &str,
+        params: Value,
+    ) -> Result<()> {
+        let plugin = self
+            .plugins
+            .values()
+            .find(|plugin| plugin.name == name && !plugin.failed())
+            .ok_or(anyhow!("plugin {} is not running", name))?;
+        plugin
+            .sender
+            .send(PluginMessage::Call {
+                function: "handle_notification",
+                input: json!({
+                    "method": method,
+                    "params": params,
+                }),
+            })
+            .map_err(|_| anyhow!("plugin {} is not running", name))?;
+        Ok(())
+    }
+
+    pub fn next_plugin_id(&mut self) -> PluginId {
+        PluginId(self.id_counter.next())
+    }
+}
+
+impl PluginNew {
+    fn failed(&self) -> bool {
+        matches!(*self.status.0.lock(), PluginRunState::Failed(_))
+    }
+}
+
+/// Body of a plugin's thread: instantiates and initializes the plugin,
+/// then serves `PluginMessage`s until shutdown. Traps and panics inside
+/// the plugin mark it failed instead of taking the proxy down.
+fn run_plugin(
+    store: Store,
+    dispatcher: Dispatcher,
+    plugin_desc: PluginDescription,
+    shared: PluginShared,
+    status: PluginRunStatus,
+    dependencies: Vec<(PluginName, PluginRunStatus)>,
+    receiver: crossbeam_channel::Receiver<PluginMessage>,
+) {
+    let name = plugin_desc.name.clone();
+    let started = Instant::now();
+    // compile before waiting on dependencies so plugins compile in parallel
+    let module = if plugin_desc.kind() == PluginKind::Wasm {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            load_module(&store, &plugin_desc.exec_path)
+        }));
+        match result {
+            Ok(Ok(module)) => Some(module),
+            Ok(Err(e)) => {
+                plugin_failed(&dispatcher, &name, &status, e.to_string());
+                return;
+            }
+            Err(_) => {
+                let error = "panicked while compiling".to_string();
+                plugin_failed(&dispatcher, &name, &status, error);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    for (dep, dep_status) in &dependencies {
+        let (state, condvar) = &**dep_status;
+        let mut state = state.lock();
+        while *state == PluginRunState::Starting {
+            condvar.wait(&mut state);
+        }
+        if *state != PluginRunState::Running {
+            drop(state);
+            let error = format!("dependency \"{}\" is not running", dep);
+            plugin_failed(&dispatcher, &name, &status, error);
+            return;
+        }
+    }
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        initialize_plugin(
+            &store,
+            dispatcher.clone(),
+            &plugin_desc,
+            module,
+            shared,
+            status.clone(),
+        )
+    }));
+    let mut instance = match result {
+        Ok(Ok(plugin)) => plugin,
+        Ok(Err(e)) => {
+            plugin_failed(&dispatcher, &name, &status, e.to_string());
+            return;
+        }
+        Err(_) => {
+            let error = "panicked in initialize".to_string();
+            plugin_failed(&dispatcher, &name, &status, error);
+            return;
+        }
+    };
+    {
+        let (state, condvar) = &*status;
+        let mut state = state.lock();
+        // the watchdog gave up on us already
+        if *state != PluginRunState::Starting {
+            return;
+        }
+        *state = PluginRunState::Running;
+        condvar.notify_all();
+    }
+    eprintln!("plugin {} started in {:?}", name, started.elapsed());
+    dispatcher.send_notification("plugin_started", json!({ "name": name }));
+
+    for message in receiver {
+        match message {
+            PluginMessage::Call { function, input } => {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    instance.call(function, &input)
+                }));
+                let error = match result {
+                    Ok(Ok(_)) => {
+                        if let PluginRunState::Failed(_) = *status.0.lock() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => "panicked".to_string(),
+                };
+                plugin_failed(&dispatcher, &name, &status, error);
+                return;
+            }
+            PluginMessage::Shutdown { reply } => {
+                *status.0.lock() = PluginRunState::Stopped;
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| instance.shutdown()));
+                if let Ok(Err(e)) = result {
+                    eprintln!("plugin {} shutdown err {}", name, e);
+                }
+                let _ = reply.send(());
+                return;
+            }
+        }
+    }
+}
+
+/// Instantiates the compiled `module`, or spawns the plugin's executable
+/// if there is none, and sends it the `initialize` call with its
+/// configuration.
+fn initialize_plugin(
+    store: &Store,
+    dispatcher: Dispatcher,
+    plugin_desc: &PluginDescription,
+    module: Option<wasmer::Module>,
+    shared: PluginShared,
+    status: PluginRunStatus,
+) -> Result<PluginInstance> {
+    let log = Arc::new(Mutex::new(PluginLog::new(dispatcher.clone(), plugin_desc)));
+    let module = match module {
+        Some(module) => module,
+        None => {
+            let mut initialize_configuration = shared.configuration.lock().clone();
+            if let Some(object) = initialize_configuration.as_object_mut() {
+                object.insert("plugin_dir".to_string(), json!(plugin_desc.dir));
+                if let Some(dir) = plugin_desc.dir.as_ref() {
+                    let data_dir = dir.join("data");
+                    fs::create_dir_all(&data_dir)?;
+                    object.insert("data_dir".to_string(), json!(data_dir));
+                }
+            }
+            let plugin =
+                Plugin::spawn(dispatcher, plugin_desc, shared, status, log)?;
+            plugin.initialize(&initialize_configuration);
+            return Ok(PluginInstance::Process(plugin));
+        }
+    };
+
+    let workspace = dispatcher.workspace.lock().clone();
+    let mut wasi_env = plugin_wasi_env(plugin_desc, &workspace)?;
+    let wasi = wasi_env.import_object(&module)?;
+
+    let mut initialize_configuration = shared.configuration.lock().clone();
+    let plugin_env = PluginEnv {
+        name: plugin_desc.name.clone(),
+        wasi_env,
+        dispatcher,
+        configuration: shared.configuration,
+        commands: shared.commands,
+        log,
+        status,
+        consecutive_errors: Arc::new(AtomicUsize::new(0)),
+        // until the module's export is read, before anything is written
+        api_version: Arc::new(AtomicU32::new(plugin_desc.api_version())),
+    };
+    let lapce = lapce_exports(store, &plugin_env);
+    let instance = wasmer::Instance::new(&module, &lapce.chain_back(wasi))?;
+
+    // the exported version wins over the manifest, which may be stale
+    let version = exported_api_version(&instance)?;
+    check_api_version(version)?;
+    plugin_env.api_version.store(version, Ordering::SeqCst);
+
+    let initialize = instance.exports.get_function("initialize")?;
+    if let Some(object) = initialize_configuration.as_object_mut() {
+        object.insert("plugin_dir".to_string(), json!(PLUGIN_DIR_MOUNT));
+        object.insert("data_dir".to_string(), json!(PLUGIN_DATA_MOUNT));
+    }
+    wasi_write_object(
+        &plugin_env.wasi_env,
+        plugin_env.framing(),
+        &initialize_configuration,
+    )?;
+    let result = initialize.call(&[]);
+    plugin_env.drain_stderr();
+    result.map_err(PluginError::from)?;
+
+    Ok(PluginInstance::Wasm {
+        instance,
+        env: plugin_env,
+    })
+}
+
+fn module_cache_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.cache_dir().join("plugin-modules"))
+}
+
+/// Compiles the wasm file at `path`, or loads it from the module cache.
+/// Cache entries are keyed by a hash of the wasm bytes and the wasmer
+/// version, so a changed plugin or runtime never picks up a stale module.
+fn load_module(store: &Store, path: &Path) -> Result<wasmer::Module> {
+    let started = Instant::now();
+    let wasm = fs::read(path)?;
+    let mut hasher = DefaultHasher::new();
+    wasm.hash(&mut hasher);
+    wasmer::VERSION.hash(&mut hasher);
+    let cache_path = module_cache_dir()
+        .map(|dir| dir.join(format!("{:016x}.module", hasher.finish())));
+
+    if let Some(cache_path) = cache_path.as_ref() {
+        if let Ok(bytes) = fs::read(cache_path) {
+            // Safety: the cache only holds modules this proxy serialized
+            // with the same wasmer version.
+            match unsafe { wasmer::Module::deserialize(store, &bytes) } {
+                Ok(module) => {
+                    eprintln!(
+                        "plugin module {:?} loaded from cache in {:?}",
+                        path,
+                        started.elapsed()
+                    );
+                    return Ok(module);
+                }
+                Err(e) => {
+                    eprintln!("plugin module cache {:?} err {}", cache_path, e);
+                    let _ = fs::remove_file(cache_path);
+                }
+            }
+        }
+    }
+
+    let module = wasmer::Module::new(store, &wasm)?;
+    eprintln!(
+        "plugin module {:?} compiled in {:?}",
+        path,
+        started.elapsed()
+    );
+    if let Some(cache_path) = cache_path.as_ref() {
+        if let Err(e) = write_module_cache(&module, cache_path) {
+            eprintln!("plugin module cache {:?} err {}", cache_path, e);
+        }
+    }
+    Ok(module)
+}
+
+fn write_module_cache(module: &wasmer::Module, cache_path: &Path) -> Result<()> {
+    if let Some(dir) = cache_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // write then rename so a concurrent reader never sees half a module
+    let tmp_path = cache_path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_path, module.serialize()?)?;
+    fs::rename(&tmp_path, cache_path)?;
+    Ok(())
+}
+
+/// What the module's `plugin_api_version` returns, which a module without
+/// it errs for.
+fn exported_api_version(instance: &wasmer::Instance) -> Result<u32, PluginError> {
+    let plugin_api_version = instance
+        .exports
+        .get_native_function::<(), u32>("plugin_api_version")
+        .map_err(|_| PluginError::MissingExport("plugin_api_version"))?;
+    Ok(plugin_api_version.call()?)
+}
+
+fn check_api_version(version: u32) -> Result<()> {
+    if version > *SUPPORTED_API_VERSIONS.end() {
+        return Err(anyhow!(
+            "requires newer Lapce (plugin API version {}, supported {}-{})",
+            version,
+            SUPPORTED_API_VERSIONS.start(),
+            SUPPORTED_API_VERSIONS.end()
+        ));
+    }
+    if version < *SUPPORTED_API_VERSIONS.start() {
+        return Err(anyhow!(
+            "plugin API version {} is no longer supported (supported {}-{})",
+            version,
+            SUPPORTED_API_VERSIONS.start(),
+            SUPPORTED_API_VERSIONS.end()
+        ));
+    }
+    Ok(())
+}
+
+/// Marks a plugin failed, waking anything waiting on it, and tells the
+/// core. Only the first failure is reported.
+fn plugin_failed(
+    dispatcher: &Dispatcher,
+    name: &str,
+    status: &PluginRunStatus,
+    error: String,
+) {
+    {
+        let (state, condvar) = &**status;
+        let mut state = state.lock();
+        if let PluginRunState::Failed(_) | PluginRunState::Stopped = *state {
+            return;
+        }
+        *state = PluginRunState::Failed(error.clone());
+        condvar.notify_all();
+    }
+    eprintln!("plugin {} failed: {}", name, error);
+    dispatcher.send_notification(
+        "plugin_error",
+        json!({
+            "name": name,
+            "error": error,
+        }),
+    );
+}
+
+/// Orders the plugins so that every plugin comes after its dependencies.
+/// Plugins with a missing dependency or that are part of a dependency
+/// cycle are left out of the order and returned with the reason instead.
+fn plugin_start_order(
+    items: &HashMap<PluginName, PluginDescription>,
+) -> (Vec<PluginName>, Vec<(PluginName, String)>) {
+    fn visit(
+        name: &str,
+        items: &HashMap<PluginName, PluginDescription>,
+        visiting: &mut Vec<PluginName>,
+        resolved: &mut HashMap<PluginName, Result<(), String>>,
+        order: &mut Vec<PluginName>,
+    ) -> Result<(), String> {
+        if let Some(result) = resolved.get(name) {
+            return result.clone();
+        }
+        if let Some(pos) = visiting.iter().position(|n| n == name) {
+            let mut cycle = visiting[pos..].to_vec();
+            cycle.push(name.to_string());
+            return Err(format!("dependency cycle {}", cycle.join(" -> ")));
+        }
+
+        visiting.push(name.to_string());
+        let mut result = Ok(());
+        for dep in items[name].dependencies.iter().flatten() {
+            if !items.contains_key(dep) {
+                result = Err(format!("missing dependency \"{}\"", dep));
+                break;
+            }
+            if let Err(e) = visit(dep, items, visiting, resolved, order) {
+                result = Err(e);
+                break;
+            }
+        }
+        visiting.pop();
+
+        if result.is_ok() {
+            order.push(name.to_string());
+        }
+        resolved.insert(name.to_string(), result.clone());
+        result
+    }
+
+    let mut names: Vec<&PluginName> = items.keys().collect();
+    names.sort();
+
+    let mut order = Vec::new();
+    let mut resolved = HashMap::new();
+    for name in names {
+        let _ = visit(name, items, &mut Vec::new(), &mut resolved, &mut order);
+    }
+
+    let mut errors: Vec<(PluginName, String)> = resolved
+        .into_iter()
+        .filter_map(|(name, result)| Some((name, result.err()?)))
+        .collect();
+    errors.sort();
+    (order, errors)
+}
+
+/// Builds the WASI environment for a plugin: piped stdin/stdout for
+/// talking to the proxy, the plugin's own directory mounted read-only at
+/// `PLUGIN_DIR_MOUNT` and a writable `data` directory inside it mounted at
+/// `PLUGIN_DATA_MOUNT`. The plugin directory is also the working
+/// directory, and the environment is only `plugin_env_vars`.
+fn plugin_wasi_env(
+    plugin_desc: &PluginDescription,
+    workspace: &Path,
+) -> Result<WasiEnv> {
+    let mut state = WasiState::new("Lapce");
+    state
+        .stdin(Box::new(Pipe::new()))
+        .stdout(Box::new(Pipe::new()))
+        .stderr(Box::new(Pipe::new()))
+        .envs(plugin_env_vars(plugin_desc, workspace));
+    if let Some(dir) = plugin_desc.dir.as_ref() {
+        let data_dir = dir.join("data");
+        fs::create_dir_all(&data_dir)?;
+        state
+            .preopen(|p| {
+                p.directory(dir)
+                    .alias(PLUGIN_DIR_MOUNT)
+                    .read(true)
+                    .write(false)
+                    .create(false)
+            })?
+            .preopen(|p| {
+                p.directory(&data_dir)
+                    .alias(PLUGIN_DATA_MOUNT)
+                    .read(true)
+                    .write(true)
+                    .create(true)
+            })?
+            // relative paths resolve against the plugin directory
+            .preopen(|p| {
+                p.directory(dir)
+                    .alias(".")
+                    .read(true)
+                    .write(false)
+                    .create(false)
+            })?;
+    }
+    Ok(state.finalize()?)
+}
+
+/// The whitelisted `inherit_env` variables, then the manifest's `env`,
+/// then what Lapce tells every plugin: its name, the API version and the
+/// workspace.
+fn plugin_env_vars(
+    plugin_desc: &PluginDescription,
+    workspace: &Path,
+) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    for name in plugin_desc.inherit_env.iter().flatten() {
+        if let Ok(value) = std::env::var(name) {
+            vars.insert(name.clone(), value);
+        }
+    }
+    if let Some(env) = plugin_desc.env.as_ref() {
+        vars.extend(env.clone());
+    }
+    vars.insert("PWD".to_string(), PLUGIN_DIR_MOUNT.to_string());
+    vars.insert("LAPCE_PLUGIN_NAME".to_string(), plugin_desc.name.clone());
+    vars.insert(
+        "LAPCE_PLUGIN_API_VERSION".to_string(),
+        plugin_desc.api_version().to_string(),
+    );
+    vars.insert(
+        "LAPCE_WORKSPACE".to_string(),
+        workspace.to_string_lossy().to_string(),
+    );
+    vars
+}
+
+pub(crate) fn lapce_exports(store: &Store, plugin_env: &PluginEnv) -> ImportObject {
+    macro_rules! lapce_export {
+        ($($host_function:ident),+ $(,)?) => {
+            wasmer::imports! {
+                "lapce" => {
+                    $(stringify!($host_function) =>
+                        wasmer::Function::new_native_with_env(store, plugin_env.clone(), $host_function),)+
+                }
+            }
+        }
+    }
+
+    lapce_export! {
+        host_handle_notification,
+        host_handle_request,
+        host_read_file,
+        host_get_buffer_text,
+        host_get_buffer_line,
+        host_subscribe_buffer,
+        host_unsubscribe_buffer,
+        host_log,
+    }
+}
+
+const BUFFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the text of the buffer to the plugin's stdin as one JSON object
+/// per line: `{"chunk": "...", "done": false}` for every chunk but the
+/// last, which has `"done": true`. Chunks are at most `BUFFER_CHUNK_SIZE`
+/// bytes and never split a character. If the buffer isn't open, a single
+/// `{"error": "..."}` line is written instead.
+fn host_get_buffer_text(plugin_env: &PluginEnv, buffer_id: u64) {
+    let text = plugin_env
+        .dispatcher
+        .buffers
+        .lock()
+        .get(&BufferId(buffer_id as usize))
+        .map(|buffer| buffer.get_document());
+    let result = match text {
+        Some(text) => {
+            wasi_write_chunks(&plugin_env.wasi_env, plugin_env.framing(), &text)
+        }
+        None => wasi_write_object(
+            &plugin_env.wasi_env,
+            plugin_env.framing(),
+            &json!({ "error": format!("buffer {} is not open", buffer_id) }),
+        ),
+    };
+    plugin_env.report("host_get_buffer_text", result);
+}
+
+/// Same wire format as `host_get_buffer_text`, for a single zero based
+/// line including its line ending.
+fn host_get_buffer_line(plugin_env: &PluginEnv, buffer_id: u64, line: u64) {
+    let result = plugin_env
+        .dispatcher
+        .buffers
+        .lock()
+        .get(&BufferId(buffer_id as usize))
+        .ok_or(anyhow!("buffer {} is not open", buffer_id))
+        .and_then(|buffer| {
+            let line = line as usize;
+            if line > buffer.line_of_offset(buffer.len()) {
+                return Err(anyhow!("line {} is out of range", line));
+            }
+            let start = buffer.offset_of_line(line);
+            let end = buffer.offset_of_line(line + 1);
+            Ok(buffer.slice_to_cow(start..end).to_string())
+        });
+    let result = match result {
+        Ok(text) => {
+            wasi_write_chunks(&plugin_env.wasi_env, plugin_env.framing(), &text)
+        }
+        Err(e) => wasi_write_object(
+            &plugin_env.wasi_env,
+            plugin_env.framing(),
+            &json!({ "error": e.to_string() }),
+        ),
+    };
+    plugin_env.report("host_get_buffer_line", result);
+}
+
+/// After subscribing, every edit to the buffer is sent to the plugin's
+/// `handle_notification` export as
+/// `{"method": "buffer_changed", "params": {"buffer_id": .., "rev": .., "delta": ..}}`
+/// where `delta` is the serialized xi-rope `RopeDelta` of the edit.
+fn host_subscribe_buffer(plugin_env: &PluginEnv, buffer_id: u64) {
+    plugin_env
+        .dispatcher
+        .buffer_subscriptions
+        .lock()
+        .entry(BufferId(buffer_id as usize))
+        .or_default()
+        .insert(plugin_env.name.clone());
+}
+
+fn host_unsubscribe_buffer(plugin_env: &PluginEnv, buffer_id: u64) {
+    if let Some(plugins) = plugin_env
+        .dispatcher
+        .buffer_subscriptions
+        .lock()
+        .get_mut(&BufferId(buffer_id as usize))
+    {
+        plugins.remove(&plugin_env.name);
+    }
+}
+
+fn wasi_write_chunks(
+    wasi_env: &WasiEnv,
+    framing: Framing,
+    text: &str,
+) -> Result<(), PluginError> {
+    let mut start = 0;
+    loop {
+        let mut end = (start + BUFFER_CHUNK_SIZE).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let done = end == text.len();
+        wasi_write_object(
+            wasi_env,
+            framing,
+            &json!({
+                "chunk": &text[start..end],
+                "done": done,
+            }),
+        )?;
+        if done {
+            return Ok(());
+        }
+        start = end;
+    }
+}
+
+fn next_request_id() -> u64 {
+    static PLUGIN_REQUEST_ID_COUNTER: TermCounter = TermCounter::new();
+    PLUGIN_REQUEST_ID_COUNTER.next()
+}
+
+/// Reads a `PluginRequest` the plugin wrote to its stdout and writes the
+/// response, tagged with a fresh request id, back to its stdin before
+/// returning, so the plugin can block on reading it.
+fn host_handle_request(plugin_env: &PluginEnv) {
+    let id = next_request_id();
+    let request = plugin_env.report(
+        "host_handle_request",
+        wasi_read_object::<PluginRequest>(&plugin_env.wasi_env),
+    );
+    let result = request
+        .ok_or(anyhow!("invalid request"))
+        .and_then(|request| {
+            handle_plugin_request(
+                &plugin_env.dispatcher,
+                Some(&plugin_env.name),
+                &plugin_env.configuration.lock(),
+                request,
+            )
+        });
+    let mut resp = json!({ "id": id });
+    match result {
+        Ok(v) => resp["result"] = v,
+        Err(e) => {
+            resp["error"] = json!({
+                "code": 0,
+                "message": format!("{}",e),
+            })
+        }
+    }
+    let result =
+        wasi_write_object(&plugin_env.wasi_env, plugin_env.framing(), &resp);
+    plugin_env.report("host_handle_request", result);
+}
+
+fn handle_plugin_request(
+    dispatcher: &Dispatcher,
+    plugin: Option<&str>,
+    configuration: &Value,
+    request: PluginRequest,
+) -> Result<Value> {
+    match request {
+        PluginRequest::GetWorkspace {} => Ok(json!({
+            "workspace": dispatcher.workspace.lock().clone(),
+            "workspace_folders": dispatcher.workspace_folders.lock().clone(),
+        })),
+        PluginRequest::GetConfiguration { key } => {
+            let pointer = format!("/{}", key.replace('.', "/"));
+            Ok(configuration
+                .pointer(&pointer)
+                .cloned()
+                .unwrap_or(Value::Null))
+        }
+        PluginRequest::ExecuteProcess { program, args } => {
+            let output = Command::new(&program)
+                .args(&args)
+                .envs(&resolved_env().env)
+                .output()?;
+            Ok(json!({
+                "exit_code": output.status.code(),
+                "stdout": String::from_utf8_lossy(&output.stdout),
+            }))
+        }
+        PluginRequest::StartLspServer {
+            exec_path,
+            language_id,
+            root,
+            options,
+        } => {
+            let id = dispatcher.lsp.lock().start_server(
+                &exec_path,
+                &language_id,
+                root,
+                options,
+                plugin.map(|name| name.to_string()),
+            )?;
+            Ok(json!({ "id": id }))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReadFileParams {
+    path: PathBuf,
+}
+
+fn host_read_file(plugin_env: &PluginEnv) {
+    let workspace = plugin_env.dispatcher.workspace.lock().clone();
+    let result =
+        wasi_read_file(&plugin_env.wasi_env, plugin_env.framing(), &workspace);
+    plugin_env.report("host_read_file", result);
+}
+
+/// Reads the workspace relative path the plugin wrote to its stdout and
+/// writes back either `{"content": ...}` or `{"error": ...}` to its stdin.
+/// Only failures of the pipes themselves are returned.
+fn wasi_read_file(
+    wasi_env: &WasiEnv,
+    framing: Framing,
+    workspace: &Path,
+) -> Result<(), PluginError> {
+    let params = wasi_read_object::<ReadFileParams>(wasi_env);
+    let result = match params.as_ref() {
+        Ok(params) => resolve_workspace_path(workspace, &params.path)
+            .and_then(|path| Ok(fs::read_to_string(path)?)),
+        Err(e) => Err(anyhow!(e.to_string())),
+    };
+    let response = match result {
+        Ok(content) => json!({ "content": content }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    wasi_write_object(wasi_env, framing, &response)?;
+    params.map(|_| ())
+}
+
+#[derive(Deserialize)]
+struct LogParams {
+    level: String,
+    message: String,
+}
+
+/// Reads `{"level": ..., "message": ...}` from the plugin's stdout and
+/// logs it. Structured logging that doesn't go through stderr.
+fn host_log(plugin_env: &PluginEnv) {
+    let params = wasi_read_object::<LogParams>(&plugin_env.wasi_env);
+    if let Some(params) = plugin_env.report("host_log", params) {
+        plugin_env.log.lock().log(&params.level, &params.message);
+    }
+}
+
+fn host_handle_notification(plugin_env: &PluginEnv) {
+    let notification = plugin_env.report(
+        "host_handle_notification",
+        wasi_read_object::<PluginNotification>(&plugin_env.wasi_env),
+    );
+    if let Some(notification) = notification {
+        match notification {
+            PluginNotification::StartLspServer {
+                exec_path,
+                language_id,
+                root,
+                options,
+            } => {
+                if let Err(e) = plugin_env.dispatcher.lsp.lock().start_server(
+                    &exec_path,
+                    &language_id,
+                    root,
+                    options,
+                    Some(plugin_env.name.clone()),
+                ) {
+                    eprintln!("plugin {} start lsp err {}", plugin_env.name, e);
+                }
+            }
+            PluginNotification::StopLspServer { id } => {
+                plugin_env.dispatcher.lsp.lock().stop_server(id);
+            }
+            PluginNotification::RegisterLspServer {
+                language_id,
+                exec_path,
+                options,
+                file_patterns,
+            } => {
+                register_lsp_server(
+                    &plugin_env.dispatcher,
+                    &plugin_env.name,
+                    &language_id,
+                    &exec_path,
+                    options,
+                    file_patterns,
+                );
+            }
+            PluginNotification::RegisterCommand { id, title } => {
+                register_command(&plugin_env.commands, PluginCommand { id, title });
+            }
+        }
+    }
+}
+
+/// Records the server and starts it right away if a matching file is
+/// already open. The plugin gets an `lsp_server_registered` notification
+/// once there is a server, whether it started it or another plugin did.
+fn register_lsp_server(
+    dispatcher: &Dispatcher,
+    plugin: &str,
+    language_id: &str,
+    exec_path: &str,
+    options: Option<Value>,
+    file_patterns: Vec<String>,
+) {
+    let running = dispatcher.lsp.lock().register_server(
+        language_id,
+        exec_path,
+        options,
+        file_patterns,
+        plugin.to_string(),
+    );
+    if let Some(id) = running {
+        dispatcher.notify_plugin(
+            plugin,
+            "lsp_server_registered",
+            json!({
+                "language_id": language_id,
+                "id": id,
+            }),
+        );
+        return;
+    }
+
+    let open_files: Vec<(String, PathBuf)> = dispatcher
+        .buffers
+        .lock()
+        .values()
+        .map(|buffer| (buffer.language_id.clone(), buffer.path.clone()))
+        .collect();
+    for (language_id, path) in open_files {
+        dispatcher.start_registered_lsp_server(&language_id, &path);
+    }
+}
+
+/// Registering an id again replaces its title.
+fn register_command(commands: &Mutex<Vec<PluginCommand>>, command: PluginCommand) {
+    let mut commands = commands.lock();
+    commands.retain(|c| c.id != command.id);
+    commands.push(command);
+}
+
+const CONTENT_LENGTH: &str = "Content-Length:";
+
+/// Reads one message the plugin wrote to its stdout. A message starting
+/// with a `Content-Length` header is read as exactly that many bytes;
+/// anything else is taken to be a line of JSON from an older plugin, or
+/// everything written if there's no line break. Whatever follows the
+/// message is put back for the next read.
+pub fn read_frame(wasi_env: &WasiEnv) -> Result<String, PluginError> {
+    let mut state = wasi_env.state();
+    let wasi_file = state.fs.stdout_mut()?.as_mut().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "can't get stdout")
+    })?;
+    let mut buf = Vec::new();
+    wasi_file.read_to_end(&mut buf)?;
+
+    let (payload, rest) = split_frame(&buf)?;
+    let payload = String::from_utf8(payload.to_vec()).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    })?;
+    // the pipe is empty now, so writing the rest back keeps the order
+    if !rest.iter().all(|b| b.is_ascii_whitespace()) {
+        wasi_file.write_all(rest)?;
+    }
+    Ok(payload)
+}
+
+fn split_frame(buf: &[u8]) -> Result<(&[u8], &[u8]), PluginError> {
+    let invalid = |msg: &str| {
+        PluginError::Pipe(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            msg.to_string(),
+        ))
+    };
+    let start = buf
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buf.len());
+    let buf = &buf[start..];
+
+    if !buf.starts_with(CONTENT_LENGTH.as_bytes()) {
+        return Ok(match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                let line = &buf[..pos];
+                (line.strip_suffix(b"\r").unwrap_or(line), &buf[pos + 1..])
+            }
+            None => (buf, &[]),
+        });
+    }
+
+    let header_end = buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("unterminated Content-Length header"))?;
+    let length: usize = std::str::from_utf8(&buf[CONTENT_LENGTH.len()..header_end])
+        .ok()
+        .and_then(|length| length.trim().parse().ok())
+        .ok_or_else(|| invalid("invalid Content-Length header"))?;
+    let body = &buf[header_end + 4..];
+    if body.len() < length {
+        return Err(invalid("message shorter than its Content-Length"));
+    }
+    Ok((&body[..length], &body[length..]))
+}
+
+impl PluginEnv {
+    fn framing(&self) -> Framing {
+        Framing::for_api_version(self.api_version.load(Ordering::SeqCst))
+    }
+
+    /// Logs a failed exchange with the plugin and marks the plugin faulted
+    /// after `MAX_CONSECUTIVE_PLUGIN_ERRORS` of them in a row. A
+    /// successful one resets the count.
+    fn report<T>(&self, context: &str, result: Result<T, PluginError>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.consecutive_errors.store(0, Ordering::SeqCst);
+                Some(value)
+            }
+            Err(e) => {
+                let error = format!("{}: {}", context, e);
+                self.log.lock().log("error", &error);
+                let errors =
+                    self.consecutive_errors.fetch_add(1, Ordering::SeqCst) + 1;
+                if errors >= MAX_CONSECUTIVE_PLUGIN_ERRORS {
+                    plugin_failed(
+                        &self.dispatcher,
+                        &self.name,
+                        &self.status,
+                        format!("{} errors in a row, last {}", errors, error),
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Logs whatever the plugin wrote to its stderr pipe.
+    fn drain_stderr(&self) {
+        let output = {
+            let mut state = self.wasi_env.state();
+            let mut output = String::new();
+            match state.fs.stderr_mut() {
+                Ok(Some(stderr)) => {
+                    let _ = stderr.read_to_string(&mut output);
+                }
+                _ => return,
+            }
+            output
+        };
+        if !output.is_empty() {
+            self.log.lock().log_output(&output);
+        }
+    }
+}
+
+pub fn wasi_read_object<T: DeserializeOwned>(
+    wasi_env: &WasiEnv,
+) -> Result<T, PluginError> {
+    let payload = read_frame(wasi_env)?;
+    serde_json::from_str(&payload)
+        .map_err(|error| PluginError::Decode { payload, error })
+}
+
+pub fn write_frame(
+    wasi_env: &WasiEnv,
+    framing: Framing,
+    payload: &str,
+) -> Result<(), PluginError> {
+    let mut state = wasi_env.state();
+    let wasi_file = state.fs.stdin_mut()?.as_mut().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "can't get stdin")
+    })?;
+    match framing {
+        Framing::ContentLength => write!(
+            wasi_file,
+            "{} {}\r\n\r\n{}",
+            CONTENT_LENGTH,
+            payload.len(),
+            payload
+        )?,
+        Framing::Lines => writeln!(wasi_file, "{}\r", payload)?,
+    }
+    Ok(())
+}
+
+pub fn wasi_write_object(
+    wasi_env: &WasiEnv,
+    framing: Framing,
+    object: &(impl Serialize + ?Sized),
+) -> Result<(), PluginError> {
+    let json = serde_json::to_string(&object).map_err(PluginError::Encode)?;
+    write_frame(wasi_env, framing, &json)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "method", content = "params")]
+pub enum PluginNotification {
+    /// A server with a `root` is only given the buffers of that workspace
+    /// folder, as in one server per cargo workspace.
+    StartLspServer {
+        exec_path: String,
+        language_id: String,
+        #[serde(default)]
+        root: Option<PathBuf>,
+        options: Option<Value>,
+    },
+    StopLspServer {
+        id: LspServerId,
+    },
+    /// Start this server the first time a file of `language_id`, or one
+    /// matching `file_patterns`, is opened.
+    RegisterLspServer {
+        language_id: String,
+        exec_path: String,
+        options: Option<Value>,
+        #[serde(default)]
+        file_patterns: Vec<String>,
+    },
+    RegisterCommand {
+        id: String,
+        title: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "method", content = "params")]
+pub enum PluginRequest {
+    GetWorkspace {},
+    GetConfiguration {
+        key: String,
+    },
+    ExecuteProcess {
+        program: String,
+        args: Vec<String>,
+    },
+    StartLspServer {
+        exec_path: String,
+        language_id: String,
+        #[serde(default)]
+        root: Option<PathBuf>,
+        options: Option<Value>,
+    },
+}
+
+pub struct PluginHandler {
+    name: PluginName,
+    dispatcher: Dispatcher,
+    shared: PluginShared,
+}
+
+impl Handler for PluginHandler {
+    type Notification = PluginNotification;
+    type Request = PluginRequest;
+
+    fn handle_notification(
+        &mut self,
+        ctx: &xi_rpc::RpcCtx,
+        rpc: Self::Notification,
+    ) {
+        match &rpc {
+            PluginNotification::StartLspServer {
                 exec_path,
                 language_id,
+                root,
                 options,
             } => {
-                self.dispatcher.lsp.lock().start_server(
+                if let Err(e) = self.dispatcher.lsp.lock().start_server(
                     exec_path,
                     language_id,
+                    root.clone(),
                     options.clone(),
+                    Some(self.name.clone()),
+                ) {
+                    eprintln!("plugin {} start lsp err {}", self.name, e);
+                }
+            }
+            PluginNotification::StopLspServer { id } => {
+                self.dispatcher.lsp.lock().stop_server(*id);
+            }
+            PluginNotification::RegisterLspServer {
+                language_id,
+                exec_path,
+                options,
+                file_patterns,
+            } => {
+                register_lsp_server(
+                    &self.dispatcher,
+                    &self.name,
+                    language_id,
+                    exec_path,
+                    options.clone(),
+                    file_patterns.clone(),
+                );
+            }
+            PluginNotification::RegisterCommand { id, title } => {
+                register_command(
+                    &self.shared.commands,
+                    PluginCommand {
+                        id: id.clone(),
+                        title: title.clone(),
+                    },
                 );
             }
         }
@@ -273,50 +2219,812 @@ impl Handler for PluginHandler {
         ctx: &xi_rpc::RpcCtx,
         rpc: Self::Request,
     ) -> Result<serde_json::Value, xi_rpc::RemoteError> {
-        Err(xi_rpc::RemoteError::InvalidRequest(None))
+        let configuration = self.shared.configuration.lock().clone();
+        handle_plugin_request(
+            &self.dispatcher,
+            Some(&self.name),
+            &configuration,
+            rpc,
+        )
+        .map_err(|e| xi_rpc::RemoteError::custom(0, e.to_string(), None::<Value>))
     }
 }
 
 impl Plugin {
-    pub fn initialize(&self) {
-        self.peer.send_rpc_notification(
-            "initialize",
-            &json!({
-                "plugin_id": self.id,
-                "configuration": self.configuration,
-            }),
+    /// Spawns the plugin's executable, serves its requests and
+    /// notifications with a `PluginHandler` on a separate thread, and
+    /// marks the plugin failed if the process goes away on its own.
+    fn spawn(
+        dispatcher: Dispatcher,
+        plugin_desc: &PluginDescription,
+        shared: PluginShared,
+        status: PluginRunStatus,
+        log: Arc<Mutex<PluginLog>>,
+    ) -> Result<Plugin> {
+        let mut command = Command::new(&plugin_desc.exec_path);
+        command.envs(&resolved_env().env);
+        if let Some(dir) = plugin_desc.dir.as_ref() {
+            command.current_dir(dir);
+        }
+        let mut process = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = process.stdin.take().ok_or(anyhow!("can't get stdin"))?;
+        let stdout = process.stdout.take().ok_or(anyhow!("can't get stdout"))?;
+        let stderr = process.stderr.take().ok_or(anyhow!("can't get stderr"))?;
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                match line {
+                    Ok(line) => log.lock().log("info", &line),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        let looper = RpcLoop::new(stdin);
+        let peer: RpcPeer = Box::new(looper.get_raw_peer());
+        let name = plugin_desc.name.clone();
+        {
+            let name = name.clone();
+            let mut handler = PluginHandler {
+                name: name.clone(),
+                dispatcher: dispatcher.clone(),
+                shared,
+            };
+            thread::spawn(move || {
+                let mut looper = looper;
+                let result =
+                    looper.mainloop(|| BufReader::new(stdout), &mut handler);
+                let error = match result {
+                    Ok(_) => "process exited".to_string(),
+                    Err(e) => format!("process exited: {:?}", e),
+                };
+                plugin_failed(&dispatcher, &name, &status, error);
+            });
+        }
+
+        Ok(Plugin {
+            name,
+            peer,
+            process,
+        })
+    }
+
+    pub fn initialize(&self, configuration: &Value) {
+        self.peer.send_rpc_notification("initialize", configuration)
+    }
+
+    pub fn stop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub(crate) fn user_settings_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.config_dir().join("settings.toml"))
+}
+
+/// The `[plugins.<name>]` table of the settings.
+fn user_plugin_configuration(name: &str) -> Option<Value> {
+    serde_json::to_value(setting(&["plugins", name])?).ok()
+}
+
+/// `plugin-initialize-timeout` from the `[lapce]` table of the user
+/// settings, in seconds.
+fn user_plugin_initialize_timeout() -> Duration {
+    setting(&["lapce", "plugin-initialize-timeout"])
+        .and_then(|secs| secs.as_integer())
+        .filter(|secs| *secs > 0)
+        .map(|secs| Duration::from_secs(secs as u64))
+        .unwrap_or(DEFAULT_PLUGIN_INITIALIZE_TIMEOUT)
+}
+
+/// The manifest's configuration with the user's settings merged over it.
+fn plugin_configuration(manifest: &PluginDescription) -> Value {
+    let mut configuration = manifest.configuration.clone().unwrap_or(json!({}));
+    if let Some(user) = user_plugin_configuration(&manifest.name) {
+        merge_configuration(&mut configuration, user);
+    }
+    configuration
+}
+
+/// Merges objects recursively; arrays and scalars are replaced by
+/// `overrides`.
+pub(crate) fn merge_configuration(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_configuration(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// `plugins` in the platform's data directory (`$XDG_DATA_HOME/lapce` on
+/// Linux, `%APPDATA%\Lapce\data` on Windows), unless a legacy
+/// `~/.lapce/plugins` from an older install exists.
+pub(crate) fn plugins_dir() -> Result<PathBuf> {
+    let legacy = home_dir().map(|home| home.join(".lapce").join("plugins"));
+    if let Some(legacy) = legacy.as_ref().filter(|dir| dir.is_dir()) {
+        return Ok(legacy.clone());
+    }
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.data_dir().join("plugins"))
+        .or(legacy)
+        .ok_or(anyhow!("can't find a directory for plugins"))
+}
+
+fn load_disabled_plugins() -> BTreeSet<PluginName> {
+    plugins_dir()
+        .ok()
+        .and_then(|dir| fs::read_to_string(dir.join("disabled.toml")).ok())
+        .and_then(|contents| toml::from_str::<DisabledPlugins>(&contents).ok())
+        .map(|state| state.disabled)
+        .unwrap_or_default()
+}
+
+fn save_disabled_plugins(disabled: &BTreeSet<PluginName>) -> Result<()> {
+    let dir = plugins_dir()?;
+    fs::create_dir_all(&dir)?;
+    let contents = toml::to_string(&DisabledPlugins {
+        disabled: disabled.clone(),
+    })?;
+    fs::write(dir.join("disabled.toml"), contents)?;
+    Ok(())
+}
+
+/// `plugin-registry` of the `[lapce]` table of the settings, which
+/// `name@version` installs are downloaded from. There's none unless it's
+/// set.
+fn plugin_registry() -> Option<String> {
+    setting(&["lapce", "plugin-registry"])?
+        .as_str()
+        .filter(|registry| !registry.is_empty())
+        .map(|registry| registry.to_string())
+}
+
+/// Downloads a plugin archive into a staging directory, validates its
+/// manifest and moves it into the plugins directory. `source` is either a
+/// url to a `.tar.gz` or `.zip` archive, or `name@version` which is looked
+/// up in the `plugin-registry` setting. Returns the installed manifest
+/// path.
+///
+/// The download and extraction shell out to `curl`, `tar` and `unzip`.
+pub fn install_plugin(source: &str) -> Result<PathBuf> {
+    let url = if source.contains("://") {
+        source.to_string()
+    } else {
+        let (name, version) = source
+            .split_once('@')
+            .ok_or(anyhow!("expected a url or name@version, got {}", source))?;
+        if !is_registry_part(name) || !is_registry_part(version) {
+            return Err(anyhow!("invalid plugin name@version {}", source));
+        }
+        let registry = plugin_registry().ok_or_else(|| {
+            anyhow!("can't install {}: lapce.plugin-registry isn't set", source)
+        })?;
+        format!(
+            "{}/{}/{}.tar.gz",
+            registry.trim_end_matches('/'),
+            name,
+            version
         )
+    };
+    install_plugin_into(&url, &plugins_dir()?)
+}
+
+/// Whether a registry name or version is safe to put in the url path:
+/// only `[A-Za-z0-9._-]`, and not just dots.
+fn is_registry_part(part: &str) -> bool {
+    part.chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && part.chars().any(|c| c != '.')
+}
+
+/// The staging directory is in the temp dir, out of sight of whatever
+/// lists or watches the plugins directory, and is always removed, so a
+/// failed install leaves nothing behind.
+fn install_plugin_into(url: &str, plugins_dir: &Path) -> Result<PathBuf> {
+    let staging = std::env::temp_dir().join(format!(
+        "lapce-plugin-install-{}-{}",
+        std::process::id(),
+        next_request_id()
+    ));
+    fs::create_dir_all(&staging)?;
+    let result = install_plugin_from_url(url, &staging, plugins_dir);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn install_plugin_from_url(
+    url: &str,
+    staging: &Path,
+    plugins_dir: &Path,
+) -> Result<PathBuf> {
+    let archive = staging.join("archive");
+    let status = Command::new("curl")
+        .arg("-fsSL")
+        .arg("-o")
+        .arg(&archive)
+        .arg("--")
+        .arg(url)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("download {} failed: {}", url, status));
     }
+
+    let extracted = staging.join("extracted");
+    fs::create_dir_all(&extracted)?;
+    let status = if url.ends_with(".zip") {
+        Command::new("unzip")
+            .arg("-q")
+            .arg(&archive)
+            .arg("-d")
+            .arg(&extracted)
+            .status()?
+    } else {
+        Command::new("tar")
+            .arg("-xzf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&extracted)
+            .status()?
+    };
+    if !status.success() {
+        return Err(anyhow!("extract {} failed: {}", url, status));
+    }
+
+    // archives either have the manifest at the top level or inside a
+    // single top level directory
+    let mut plugin_root = extracted.clone();
+    if !plugin_root.join("manifest.toml").exists() {
+        let entries: Vec<PathBuf> = fs::read_dir(&extracted)?
+            .flat_map(|entry| entry.map(|e| e.path()).ok())
+            .collect();
+        match entries.as_slice() {
+            [dir] if dir.join("manifest.toml").exists() => {
+                plugin_root = dir.clone();
+            }
+            _ => return Err(anyhow!("no manifest.toml in {}", url)),
+        }
+    }
+
+    let manifest = load_manifest(&plugin_root.join("manifest.toml"))?;
+    let target = plugins_dir.join(&manifest.name);
+    if target.exists() {
+        return Err(anyhow!("plugin {} is already installed", manifest.name));
+    }
+    fs::create_dir_all(plugins_dir)?;
+    // the temp dir is often another file system, which can't rename there
+    if fs::rename(&plugin_root, &target).is_err() {
+        if let Err(e) = copy_dir(&plugin_root, &target) {
+            let _ = fs::remove_dir_all(&target);
+            return Err(e);
+        }
+    }
+    Ok(target.join("manifest.toml"))
 }
 
-fn find_all_manifests() -> Vec<PathBuf> {
-    let mut manifest_paths = Vec::new();
-    let home = home_dir().unwrap();
-    let path = home.join(".lapce").join("plugins");
-    path.read_dir().map(|dir| {
-        dir.flat_map(|item| item.map(|p| p.path()).ok())
-            .map(|dir| dir.join("manifest.toml"))
-            .filter(|f| f.exists())
-            .for_each(|f| manifest_paths.push(f))
-    });
+fn find_all_manifests() -> Result<Vec<PathBuf>> {
+    let path = plugins_dir()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let manifest_paths: Vec<PathBuf> = path
+        .read_dir()?
+        .flat_map(|item| item.map(|p| p.path()).ok())
+        .map(|dir| dir.join("manifest.toml"))
+        .filter(|f| f.exists())
+        .collect();
     eprintln!("proxy mainfiest paths {:?}", manifest_paths);
-    manifest_paths
+    Ok(manifest_paths)
 }
 
-fn load_manifest(path: &PathBuf) -> Result<PluginDescription> {
-    let mut file = fs::File::open(&path)?;
+fn load_manifest(path: &PathBuf) -> Result<PluginDescription, ManifestError> {
+    let io_error = |error| ManifestError::Io {
+        path: path.clone(),
+        error,
+    };
+    let invalid = |field, reason: &str| ManifestError::InvalidField {
+        path: path.clone(),
+        field,
+        reason: reason.to_string(),
+    };
+
+    let mut file = fs::File::open(&path).map_err(io_error)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let mut manifest: PluginDescription = toml::from_str(&contents)?;
+    file.read_to_string(&mut contents).map_err(io_error)?;
+    let mut manifest: PluginDescription =
+        toml::from_str(&contents).map_err(|error| ManifestError::Parse {
+            path: path.clone(),
+            error,
+        })?;
+
+    if manifest.name.is_empty()
+        || !manifest.name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'
+        })
+    {
+        return Err(invalid(
+            "name",
+            "must be non-empty and only contain a-z, 0-9, '-' and '_'",
+        ));
+    }
+    if !is_semver(&manifest.version) {
+        return Err(invalid("version", "must be a semantic version like 0.1.0"));
+    }
+
     // normalize relative paths
-    //manifest.dir = Some(path.parent().unwrap().canonicalize()?);
-    //    if manifest.exec_path.starts_with("./") {
-    manifest.dir = Some(path.parent().unwrap().canonicalize()?);
-    manifest.exec_path = path
-        .parent()
-        .unwrap()
-        .join(manifest.exec_path)
-        .canonicalize()?;
-    //   }
+    let dir = path.parent().unwrap();
+    manifest.dir = Some(dir.canonicalize().map_err(io_error)?);
+    manifest.exec_path = dir
+        .join(&manifest.exec_path)
+        .canonicalize()
+        .map_err(|_| invalid("exec_path", "file does not exist"))?;
     Ok(manifest)
 }
+
+fn is_semver(version: &str) -> bool {
+    let version = version.split('+').next().unwrap();
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (version, None),
+    };
+    let is_identifier = |s: &str| {
+        !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    let numbers: Vec<&str> = core.split('.').collect();
+    numbers.len() == 3
+        && numbers.iter().all(|n| {
+            !n.is_empty()
+                && n.chars().all(|c| c.is_ascii_digit())
+                && (n.len() == 1 || !n.starts_with('0'))
+        })
+        && pre
+            .map(|pre| pre.split('.').all(is_identifier))
+            .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn write_manifest(dir: &Path, name: &str, dependencies: &[&str]) -> PathBuf {
+        let plugin_dir = dir.join(name);
+        fs::create_dir_all(&plugin_dir).unwrap();
+        fs::write(plugin_dir.join("plugin.wasm"), "").unwrap();
+        let manifest_path = plugin_dir.join("manifest.toml");
+        fs::write(
+            &manifest_path,
+            format!(
+                "name = \"{}\"\nversion = \"0.1.0\"\nexec_path = \"plugin.wasm\"\ndependencies = {:?}\n",
+                name, dependencies
+            ),
+        )
+        .unwrap();
+        manifest_path
+    }
+
+    fn load_items(
+        dir: &Path,
+        manifests: &[(&str, &[&str])],
+    ) -> HashMap<PluginName, PluginDescription> {
+        manifests
+            .iter()
+            .map(|(name, deps)| {
+                let manifest =
+                    load_manifest(&write_manifest(dir, name, deps)).unwrap();
+                (manifest.name.clone(), manifest)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plugin_start_order_chain() {
+        let dir = TestDir::new("plugin-chain");
+        let items = load_items(&dir, &[("a", &["b"]), ("b", &["c"]), ("c", &[])]);
+        let (order, errors) = plugin_start_order(&items);
+        assert_eq!(order, vec!["c", "b", "a"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_plugin_start_order_cycle() {
+        let dir = TestDir::new("plugin-cycle");
+        let items = load_items(&dir, &[("a", &["b"]), ("b", &["a"]), ("c", &["d"])]);
+        let (order, errors) = plugin_start_order(&items);
+        assert!(order.is_empty());
+        let names: Vec<&str> = errors.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert!(errors[0].1.contains("dependency cycle"));
+        assert_eq!(errors[2].1, "missing dependency \"d\"");
+    }
+
+    #[test]
+    fn test_manifest_validation() {
+        let dir = TestDir::new("manifest-validation");
+        fs::write(dir.join("plugin.wasm"), "").unwrap();
+        let manifest_path = dir.join("manifest.toml");
+        let field_error = |manifest: &str| {
+            fs::write(&manifest_path, manifest).unwrap();
+            load_manifest(&manifest_path).unwrap_err().field()
+        };
+
+        assert_eq!(
+            field_error(
+                "name = \"Bad Name\"\nversion = \"0.1.0\"\nexec_path = \"plugin.wasm\""
+            ),
+            Some("name")
+        );
+        assert_eq!(
+            field_error(
+                "name = \"good\"\nversion = \"1.0\"\nexec_path = \"plugin.wasm\""
+            ),
+            Some("version")
+        );
+        assert_eq!(
+            field_error(
+                "name = \"good\"\nversion = \"1.0.0\"\nexec_path = \"missing.wasm\""
+            ),
+            Some("exec_path")
+        );
+        assert_eq!(field_error("name = \"good\""), None);
+
+        assert!(is_semver("1.2.3-beta.1+build.5"));
+        assert!(!is_semver("01.2.3"));
+    }
+
+    #[test]
+    fn test_plugin_kind() {
+        let manifest = |extra: &str| -> PluginDescription {
+            toml::from_str(&format!("name = \"a\"\nversion = \"0.1.0\"\n{}", extra))
+                .unwrap()
+        };
+        assert_eq!(manifest("exec_path = \"a.wasm\"").kind(), PluginKind::Wasm);
+        assert_eq!(manifest("exec_path = \"a\"").kind(), PluginKind::Process);
+        assert_eq!(
+            manifest("exec_path = \"a.wasm\"\nkind = \"process\"").kind(),
+            PluginKind::Process
+        );
+    }
+
+    #[test]
+    fn test_check_api_version() {
+        assert!(check_api_version(*SUPPORTED_API_VERSIONS.end()).is_ok());
+        let error = check_api_version(*SUPPORTED_API_VERSIONS.end() + 1)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("requires newer Lapce"));
+        assert!(check_api_version(0).is_err());
+    }
+
+    #[test]
+    fn test_plugin_commands_namespaced() {
+        let dir = TestDir::new("plugin-commands");
+        let mut catalog = PluginCatalog::new();
+        catalog.items = load_items(&dir, &[("a", &[]), ("b", &[])]);
+        let command = |id: &str| PluginCommand {
+            id: id.to_string(),
+            title: id.to_string(),
+        };
+        catalog.items.get_mut("a").unwrap().commands =
+            Some(vec![command("format"), command("lint")]);
+        catalog.items.get_mut("b").unwrap().commands = Some(vec![command("format")]);
+        let ids: Vec<String> = catalog
+            .plugin_commands()
+            .into_iter()
+            .map(|item| item.id)
+            .collect();
+        assert_eq!(ids, vec!["a.format", "b.format", "lint"]);
+    }
+
+    #[test]
+    fn test_wasi_read_object_decode_error() {
+        let wasi_env = WasiState::new("test")
+            .stdout(Box::new(Pipe::new()))
+            .finalize()
+            .unwrap();
+        {
+            let mut state = wasi_env.state();
+            let stdout = state.fs.stdout_mut().unwrap().as_mut().unwrap();
+            write!(stdout, "{{\"path\": 1}}").unwrap();
+        }
+        match wasi_read_object::<ReadFileParams>(&wasi_env) {
+            Err(PluginError::Decode { payload, .. }) => {
+                assert_eq!(payload, "{\"path\": 1}")
+            }
+            _ => panic!("expected a decode error"),
+        }
+    }
+
+    fn stdout_pipe(output: &str) -> WasiEnv {
+        let wasi_env = WasiState::new("test")
+            .stdout(Box::new(Pipe::new()))
+            .finalize()
+            .unwrap();
+        {
+            let mut state = wasi_env.state();
+            let stdout = state.fs.stdout_mut().unwrap().as_mut().unwrap();
+            write!(stdout, "{}", output).unwrap();
+        }
+        wasi_env
+    }
+
+    fn frame(payload: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", payload.len(), payload)
+    }
+
+    #[test]
+    fn test_read_frame_interleaved() {
+        let first = json!({ "method": "a", "params": { "text": "x\ny" } });
+        let second = json!({ "method": "b", "params": {} });
+        let pretty = serde_json::to_string_pretty(&first).unwrap();
+        let wasi_env = stdout_pipe(&(frame(&pretty) + &frame(&second.to_string())));
+
+        let read: Value =
+            serde_json::from_str(&read_frame(&wasi_env).unwrap()).unwrap();
+        assert_eq!(read, first);
+        let read: Value =
+            serde_json::from_str(&read_frame(&wasi_env).unwrap()).unwrap();
+        assert_eq!(read, second);
+    }
+
+    #[test]
+    fn test_read_frame_large() {
+        let text = "a".repeat(2 * 1024 * 1024);
+        let payload = json!({ "text": text }).to_string();
+        let wasi_env = stdout_pipe(&frame(&payload));
+        assert_eq!(read_frame(&wasi_env).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_read_frame_lines() {
+        let wasi_env = stdout_pipe("{\"a\": \"x\\ny\"}\r\n{\"b\": 1}\r\n");
+        let read: Value =
+            serde_json::from_str(&read_frame(&wasi_env).unwrap()).unwrap();
+        assert_eq!(read, json!({ "a": "x\ny" }));
+        let read: Value =
+            serde_json::from_str(&read_frame(&wasi_env).unwrap()).unwrap();
+        assert_eq!(read, json!({ "b": 1 }));
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let wasi_env = WasiState::new("test")
+            .stdin(Box::new(Pipe::new()))
+            .finalize()
+            .unwrap();
+        write_frame(&wasi_env, Framing::ContentLength, "{\n}").unwrap();
+        let mut state = wasi_env.state();
+        let stdin = state.fs.stdin_mut().unwrap().as_mut().unwrap();
+        let mut written = String::new();
+        stdin.read_to_string(&mut written).unwrap();
+        assert_eq!(written, frame("{\n}"));
+    }
+
+    #[test]
+    fn test_merge_configuration() {
+        let mut configuration = json!({
+            "lsp_server": "rust-analyzer",
+            "args": ["--stdio"],
+            "options": { "cargo": { "features": "all", "target": "x86" } },
+        });
+        merge_configuration(
+            &mut configuration,
+            json!({
+                "lsp_server": "/opt/rust-analyzer",
+                "args": [],
+                "options": { "cargo": { "target": "arm" }, "check": true },
+            }),
+        );
+        assert_eq!(
+            configuration,
+            json!({
+                "lsp_server": "/opt/rust-analyzer",
+                "args": [],
+                "options": {
+                    "cargo": { "features": "all", "target": "arm" },
+                    "check": true,
+                },
+            })
+        );
+    }
+
+    /// Looks up the preopened `/plugin` directory by name, as fd 3 is the
+    /// virtual root, opens `bundled.txt` relative to it and echoes its
+    /// contents to stdout.
+    const READ_BUNDLED_FILE_WAT: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_prestat_get"
+    (func $fd_prestat_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+    (func $fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "bundled.txt")
+  (data (i32.const 80) "plugin")
+  (func (export "initialize")
+    (local $fd i32)
+    (local.set $fd (i32.const 3))
+    (block $found
+      (loop $next
+        (if (i32.eq (local.get $fd) (i32.const 16))
+          (then (return)))
+        ;; the name has no leading slash and its length counts a nul
+        (if (i32.and
+            (i32.eqz (call $fd_prestat_get (local.get $fd) (i32.const 48)))
+            (i32.eq (i32.load (i32.const 52)) (i32.const 7)))
+          (then
+            (drop (call $fd_prestat_dir_name (local.get $fd) (i32.const 96)
+              (i32.const 7)))
+            (br_if $found (i32.and
+              (i32.eq (i32.load (i32.const 96)) (i32.load (i32.const 80)))
+              (i32.eq (i32.load (i32.const 98)) (i32.load (i32.const 82)))))))
+        (local.set $fd (i32.add (local.get $fd) (i32.const 1)))
+        (br $next)))
+    (drop (call $path_open (local.get $fd) (i32.const 0) (i32.const 0)
+      (i32.const 11) (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0)
+      (i32.const 16)))
+    (i32.store (i32.const 32) (i32.const 256))
+    (i32.store (i32.const 36) (i32.const 64))
+    (drop (call $fd_read (i32.load (i32.const 16)) (i32.const 32)
+      (i32.const 1) (i32.const 40)))
+    (i32.store (i32.const 36) (i32.load (i32.const 40)))
+    (drop (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1)
+      (i32.const 44)))))
+"#;
+
+    #[test]
+    fn test_plugin_dir_preopen() {
+        let dir = TestDir::new("plugin-preopen");
+        let manifest_path = write_manifest(&dir, "preopen", &[]);
+        let plugin_dir = manifest_path.parent().unwrap();
+        fs::write(plugin_dir.join("bundled.txt"), "bundled data").unwrap();
+        let manifest = load_manifest(&manifest_path).unwrap();
+
+        let store = Store::default();
+        let module = wasmer::Module::new(&store, READ_BUNDLED_FILE_WAT).unwrap();
+        let mut wasi_env = plugin_wasi_env(&manifest, Path::new("/w")).unwrap();
+        let import_object = wasi_env.import_object(&module).unwrap();
+        let instance = wasmer::Instance::new(&module, &import_object).unwrap();
+        instance
+            .exports
+            .get_function("initialize")
+            .unwrap()
+            .call(&[])
+            .unwrap();
+
+        assert_eq!(read_frame(&wasi_env).unwrap(), "bundled data");
+        assert!(plugin_dir.join("data").is_dir());
+    }
+
+    #[test]
+    fn test_is_registry_part() {
+        assert!(is_registry_part("lapce-rust_1"));
+        assert!(is_registry_part("0.1.2"));
+        assert!(!is_registry_part(""));
+        assert!(!is_registry_part(".."));
+        assert!(!is_registry_part("a/b"));
+        assert!(!is_registry_part("a?b"));
+        assert!(!is_registry_part("é"));
+    }
+
+    #[test]
+    fn test_install_plugin_from_archive() {
+        let dir = TestDir::new("plugin-install");
+        write_manifest(&dir.join("source"), "archived", &[]);
+        let archive = dir.join("archived.tar.gz");
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(dir.join("source"))
+            .arg("archived")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let plugins_dir = dir.join("plugins");
+        let url = format!("file://{}", archive.display());
+
+        let manifest_path = install_plugin_into(&url, &plugins_dir).unwrap();
+        assert_eq!(
+            plugins_dir.join("archived").join("manifest.toml"),
+            manifest_path
+        );
+        assert_eq!(load_manifest(&manifest_path).unwrap().name, "archived");
+        // nothing but the plugin was left in the plugins directory
+        assert_eq!(fs::read_dir(&plugins_dir).unwrap().count(), 1);
+        assert!(install_plugin_into(&url, &plugins_dir).is_err());
+    }
+
+    #[test]
+    fn test_exported_api_version() {
+        let store = Store::default();
+        let instance = |wat: &str| {
+            let module = wasmer::Module::new(&store, wat).unwrap();
+            wasmer::Instance::new(&module, &wasmer::imports! {}).unwrap()
+        };
+        let versioned = instance(
+            r#"(module (func (export "plugin_api_version") (result i32)
+                 (i32.const 2)))"#,
+        );
+        assert_eq!(exported_api_version(&versioned).unwrap(), 2);
+        let unversioned = instance(r#"(module (func (export "initialize")))"#);
+        assert!(matches!(
+            exported_api_version(&unversioned),
+            Err(PluginError::MissingExport("plugin_api_version"))
+        ));
+    }
+
+    #[test]
+    fn test_plugin_env_vars() {
+        std::env::set_var("LAPCE_TEST_PLUGIN_SECRET", "secret");
+        std::env::set_var("LAPCE_TEST_PLUGIN_PATH", "/bin");
+        let mut manifest: PluginDescription = toml::from_str(
+            "name = \"a\"\nversion = \"0.1.0\"\nexec_path = \"a.wasm\"\ninherit_env = [\"LAPCE_TEST_PLUGIN_PATH\"]\n[env]\nFOO = \"bar\"\n",
+        )
+        .unwrap();
+        manifest.api_version = Some(1);
+
+        let vars = plugin_env_vars(&manifest, Path::new("/workspace"));
+        assert!(!vars.contains_key("LAPCE_TEST_PLUGIN_SECRET"));
+        assert_eq!(vars["LAPCE_TEST_PLUGIN_PATH"], "/bin");
+        assert_eq!(vars["FOO"], "bar");
+        assert_eq!(vars["LAPCE_PLUGIN_NAME"], "a");
+        assert_eq!(vars["LAPCE_PLUGIN_API_VERSION"], "1");
+        assert_eq!(vars["LAPCE_WORKSPACE"], "/workspace");
+    }
+
+    fn wasi_read_file_round_trip(workspace: &Path, path: &str) -> Value {
+        let wasi_env = WasiState::new("test")
+            .stdin(Box::new(Pipe::new()))
+            .stdout(Box::new(Pipe::new()))
+            .finalize()
+            .unwrap();
+        {
+            let mut state = wasi_env.state();
+            let stdout = state.fs.stdout_mut().unwrap().as_mut().unwrap();
+            writeln!(stdout, "{}", json!({ "path": path })).unwrap();
+        }
+        wasi_read_file(&wasi_env, Framing::Lines, workspace).unwrap();
+
+        let mut state = wasi_env.state();
+        let stdin = state.fs.stdin_mut().unwrap().as_mut().unwrap();
+        let mut response = String::new();
+        stdin.read_to_string(&mut response).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_host_read_file() {
+        let workspace = TestDir::new("host-read-file");
+        fs::write(workspace.join("package.json"), "{}").unwrap();
+
+        let response = wasi_read_file_round_trip(&workspace, "package.json");
+        assert_eq!(response, json!({ "content": "{}" }));
+
+        let response = wasi_read_file_round_trip(&workspace, "../package.json");
+        assert!(response.get("error").is_some());
+    }
+}
@@ -0,0 +1,193 @@
+//! Conversions between the byte offsets of buffers, the positions of
+//! language servers, whose characters are utf-16 code units, and the
+//! columns text is shown at. Most work on the text of a line, since the
+//! editor keeps its own ropes.
+
+use anyhow::{anyhow, Result};
+use lsp_types::Position;
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthChar;
+use xi_rope::{LinesMetric, Rope};
+
+/// The utf-16 character of `byte` in `line`. A byte inside a char counts
+/// as the start of it.
+pub fn byte_to_utf16(line: &str, byte: usize) -> usize {
+    line.char_indices()
+        .take_while(|(i, c)| i + c.len_utf8() <= byte)
+        .map(|(_, c)| c.len_utf16())
+        .sum()
+}
+
+/// The byte of utf-16 `character` in `line`, which has no newline in it.
+/// A character past the end is the end, and one in the middle of a
+/// surrogate pair is the start of its char.
+pub fn utf16_to_byte(line: &str, character: usize) -> usize {
+    let mut utf16 = 0;
+    for (i, c) in line.char_indices() {
+        utf16 += c.len_utf16();
+        if utf16 > character {
+            return i;
+        }
+    }
+    line.len()
+}
+
+/// The column `byte` in `line` is shown at, with tabs going to the next
+/// multiple of `tab_width`.
+pub fn offset_to_display_col(line: &str, byte: usize, tab_width: usize) -> usize {
+    let mut col = 0;
+    for (i, grapheme) in line.grapheme_indices(true) {
+        if i + grapheme.len() > byte {
+            break;
+        }
+        col = next_col(col, grapheme, tab_width);
+    }
+    col
+}
+
+/// The byte of the grapheme shown at `col` in `line`, the end if the line
+/// is shorter.
+pub fn display_col_to_offset(line: &str, col: usize, tab_width: usize) -> usize {
+    let mut current = 0;
+    for (i, grapheme) in line.grapheme_indices(true) {
+        let next = next_col(current, grapheme, tab_width);
+        if next > col {
+            return i;
+        }
+        current = next;
+    }
+    line.len()
+}
+
+fn next_col(col: usize, grapheme: &str, tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        let tab_width = tab_width.max(1);
+        return (col / tab_width + 1) * tab_width;
+    }
+    col + grapheme_width(grapheme)
+}
+
+/// How many columns a grapheme takes: the widest of its chars, as the
+/// other ones are combining marks or joined into it.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    // a flag is a pair of regional indicators
+    if let Some('\u{1F1E6}'..='\u{1F1FF}') = grapheme.chars().next() {
+        return 2;
+    }
+    grapheme
+        .chars()
+        .map(|c| c.width().unwrap_or(0))
+        .max()
+        .unwrap_or(0)
+}
+
+/// The start of the grapheme before `byte` in `text`, or 0.
+pub fn prev_grapheme_boundary(text: &str, byte: usize) -> usize {
+    let mut cursor = GraphemeCursor::new(byte.min(text.len()), text.len(), true);
+    cursor.prev_boundary(text, 0).ok().flatten().unwrap_or(0)
+}
+
+/// The end of the grapheme at `byte` in `text`, or the end of `text`.
+pub fn next_grapheme_boundary(text: &str, byte: usize) -> usize {
+    let mut cursor = GraphemeCursor::new(byte.min(text.len()), text.len(), true);
+    cursor
+        .next_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(text.len())
+}
+
+/// The lsp position of a byte offset in `rope`.
+pub fn offset_to_lsp_position(rope: &Rope, offset: usize) -> Position {
+    let offset = offset.min(rope.len());
+    let line = rope.line_of_offset(offset);
+    let line_start = rope.offset_of_line(line);
+    let character =
+        byte_to_utf16(&rope.slice_to_cow(line_start..offset), offset - line_start);
+    Position {
+        line: line as u32,
+        character: character as u32,
+    }
+}
+
+/// The byte offset of an lsp position in `rope`. Servers send characters
+/// past the end of the line, which are the end of it, but a line past the
+/// end of the text is an error.
+pub fn lsp_position_to_offset(rope: &Rope, position: &Position) -> Result<usize> {
+    let line = position.line as usize;
+    if line > rope.measure::<LinesMetric>() {
+        return Err(anyhow!("position {:?} is out of range", position));
+    }
+    let line_start = rope.offset_of_line(line);
+    let line_end = rope.offset_of_line(line + 1);
+    let text = rope.slice_to_cow(line_start..line_end);
+    let text = text.trim_end_matches('\n');
+    Ok(line_start + utf16_to_byte(text, position.character as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an emoji, a combining accent, CJK and tabs
+    const LINE: &str = "a\u{1F600}e\u{301}\t\u{4E2D}\u{6587}\tb";
+
+    #[test]
+    fn test_utf16() {
+        let cases = [(0, 0), (1, 1), (5, 3), (6, 4), (8, 5), (9, 6), (12, 7)];
+        for (byte, character) in cases {
+            assert_eq!(character, byte_to_utf16(LINE, byte), "{}", byte);
+            assert_eq!(byte, utf16_to_byte(LINE, character), "{}", character);
+        }
+        // inside the emoji's surrogate pair and its utf-8 bytes
+        assert_eq!(1, utf16_to_byte(LINE, 2));
+        assert_eq!(1, byte_to_utf16(LINE, 3));
+        assert_eq!(LINE.len(), utf16_to_byte(LINE, 100));
+    }
+
+    #[test]
+    fn test_display_col() {
+        // a, the emoji, e with its accent, then the tab to column 8
+        let cases = [(0, 0), (1, 1), (5, 3), (8, 4), (9, 8), (12, 10), (15, 12)];
+        for (byte, col) in cases {
+            assert_eq!(col, offset_to_display_col(LINE, byte, 4), "{}", byte);
+            assert_eq!(byte, display_col_to_offset(LINE, col, 4), "{}", col);
+        }
+        assert_eq!(16, offset_to_display_col(LINE, 16, 4));
+        assert_eq!(17, offset_to_display_col(LINE, LINE.len(), 4));
+        // the middle of a wide char is the char
+        assert_eq!(9, display_col_to_offset(LINE, 9, 4));
+        assert_eq!(LINE.len(), display_col_to_offset(LINE, 100, 4));
+    }
+
+    #[test]
+    fn test_graphemes() {
+        assert_eq!(5, next_grapheme_boundary(LINE, 1));
+        assert_eq!(8, next_grapheme_boundary(LINE, 5));
+        assert_eq!(5, prev_grapheme_boundary(LINE, 8));
+        assert_eq!(1, prev_grapheme_boundary(LINE, 5));
+        assert_eq!(0, prev_grapheme_boundary(LINE, 0));
+        assert_eq!(LINE.len(), next_grapheme_boundary(LINE, LINE.len()));
+    }
+
+    #[test]
+    fn test_lsp_position() {
+        let rope = Rope::from(format!("{}\nx\u{E4}\n", LINE));
+        let end = LINE.len();
+        let position = offset_to_lsp_position(&rope, end);
+        assert_eq!(Position::new(0, 10), position);
+        assert_eq!(end, lsp_position_to_offset(&rope, &position).unwrap());
+        let position = offset_to_lsp_position(&rope, end + 4);
+        assert_eq!(Position::new(1, 2), position);
+        assert_eq!(end + 4, lsp_position_to_offset(&rope, &position).unwrap());
+
+        // past the end of a line is its end, not the next line
+        let past_end = Position::new(0, 42);
+        assert_eq!(end, lsp_position_to_offset(&rope, &past_end).unwrap());
+        assert_eq!(
+            rope.len(),
+            lsp_position_to_offset(&rope, &Position::new(2, 3)).unwrap()
+        );
+        assert!(lsp_position_to_offset(&rope, &Position::new(3, 0)).is_err());
+    }
+}
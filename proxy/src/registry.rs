@@ -0,0 +1,708 @@
+//! Every key of the settings files, with its type, default and what it
+//! does, for the files to be checked against and a settings UI to be built
+//! from. The tables whose keys are up to the user, as `[lsp.<server>]`, are
+//! declared as a whole. The options of the editor a language can set
+//! differently are set in `[language.<id>]`, as `[language.rust]`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const LANGUAGE_TABLE: &str = "language";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingKind {
+    Bool,
+    Integer,
+    String,
+    /// One of the strings.
+    Enum(&'static [&'static str]),
+    Integers,
+    Strings,
+    /// A table whose keys aren't checked.
+    Table,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SettingDef {
+    /// As in `editor.tab-width`.
+    pub key: &'static str,
+    pub kind: SettingKind,
+    /// The default as a TOML value.
+    pub default: &'static str,
+    pub description: &'static str,
+    /// Whether a `[language.<id>]` table can set it.
+    pub per_language: bool,
+}
+
+const fn setting(
+    key: &'static str,
+    kind: SettingKind,
+    default: &'static str,
+    description: &'static str,
+) -> SettingDef {
+    SettingDef {
+        key,
+        kind,
+        default,
+        description,
+        per_language: false,
+    }
+}
+
+const fn language_setting(
+    key: &'static str,
+    kind: SettingKind,
+    default: &'static str,
+    description: &'static str,
+) -> SettingDef {
+    SettingDef {
+        key,
+        kind,
+        default,
+        description,
+        per_language: true,
+    }
+}
+
+pub const SETTINGS: &[SettingDef] = &[
+    setting(
+        "lapce.modal",
+        SettingKind::Bool,
+        "false",
+        "Edit with the keys of vim.",
+    ),
+    setting(
+        "lapce.color-theme",
+        SettingKind::String,
+        "\"Lapce Dark\"",
+        "The name of the color theme.",
+    ),
+    setting(
+        "lapce.icon-theme",
+        SettingKind::String,
+        "\"\"",
+        "The name of the icon theme.",
+    ),
+    setting(
+        "lapce.plugin-initialize-timeout",
+        SettingKind::Integer,
+        "10",
+        "How long a plugin has to start, in seconds.",
+    ),
+    setting(
+        "lapce.workspace-symbol-limit",
+        SettingKind::Integer,
+        "200",
+        "The most workspace symbols listed.",
+    ),
+    setting(
+        "lapce.file-search-limit",
+        SettingKind::Integer,
+        "500",
+        "The most files listed when searching for one.",
+    ),
+    setting(
+        "lapce.unavailable-commands",
+        SettingKind::Enum(&["hide", "disable"]),
+        "\"hide\"",
+        "Whether the palette hides the commands that don't apply or shows \
+         them disabled.",
+    ),
+    setting(
+        "lapce.resolve-shell-env",
+        SettingKind::Bool,
+        "true",
+        "Runs the login shell for the environment of the processes started.",
+    ),
+    setting(
+        "lapce.plugin-registry",
+        SettingKind::String,
+        "\"\"",
+        "The url plugins installed as name@version are downloaded from, \
+         none when empty.",
+    ),
+    setting(
+        "editor.font-family",
+        SettingKind::String,
+        "\"Cascadia Code\"",
+        "The font of the editor.",
+    ),
+    setting(
+        "editor.font-size",
+        SettingKind::Integer,
+        "13",
+        "The size of the font.",
+    ),
+    setting(
+        "editor.line-height",
+        SettingKind::Integer,
+        "25",
+        "The height of a line.",
+    ),
+    language_setting(
+        "editor.tab-width",
+        SettingKind::Integer,
+        "4",
+        "How many columns a tab takes.",
+    ),
+    language_setting(
+        "editor.rulers",
+        SettingKind::Integers,
+        "[]",
+        "The columns a vertical line is drawn at.",
+    ),
+    language_setting(
+        "editor.format-on-save",
+        SettingKind::Bool,
+        "true",
+        "Formats the file on save.",
+    ),
+    setting(
+        "editor.format-on-save-timeout",
+        SettingKind::Integer,
+        "1000",
+        "How long saving waits for the formatting, in milliseconds.",
+    ),
+    setting(
+        "editor.server-indent-languages",
+        SettingKind::Strings,
+        "[]",
+        "The languages indented by their language server.",
+    ),
+    setting(
+        "editor.large-file-size",
+        SettingKind::Integer,
+        "20",
+        "The size in megabytes a file is opened in parts from.",
+    ),
+    setting(
+        "editor.auto-reload",
+        SettingKind::Bool,
+        "true",
+        "Reloads the files changed on disk that weren't edited.",
+    ),
+    setting(
+        "editor.auto-save",
+        SettingKind::Enum(&["off", "after-delay", "on-focus-change"]),
+        "\"off\"",
+        "When the edited files are saved by themselves.",
+    ),
+    setting(
+        "editor.auto-save-delay",
+        SettingKind::Integer,
+        "1000",
+        "How long after an edit an auto-save is, in milliseconds.",
+    ),
+    setting(
+        "editor.auto-save-format",
+        SettingKind::Bool,
+        "false",
+        "Formats the files auto-saved.",
+    ),
+    setting(
+        "editor.default-encoding",
+        SettingKind::String,
+        "\"utf-8\"",
+        "The encoding of a file that doesn't tell.",
+    ),
+    setting(
+        "editor.undo-history-size",
+        SettingKind::Integer,
+        "1024",
+        "How big the undo history kept of a file can get, in kilobytes.",
+    ),
+    language_setting(
+        "editor.trim-trailing-whitespace",
+        SettingKind::Bool,
+        "true",
+        "Trims the trailing whitespace of the lines edited on save.",
+    ),
+    language_setting(
+        "editor.insert-final-newline",
+        SettingKind::Bool,
+        "true",
+        "Ends the file with a newline on save.",
+    ),
+    setting(
+        "terminal.scrollback-kb",
+        SettingKind::Integer,
+        "256",
+        "How much output a terminal keeps, in kilobytes.",
+    ),
+    setting(
+        "terminal.idle-timeout-minutes",
+        SettingKind::Integer,
+        "60",
+        "How long a terminal nothing's attached to is kept, 0 for good.",
+    ),
+    setting(
+        "watcher.debounce-ms",
+        SettingKind::Integer,
+        "100",
+        "How long the events of files are gathered for, in milliseconds.",
+    ),
+    setting(
+        "watcher.exclude",
+        SettingKind::Strings,
+        "[\"**/.git/objects/**\", \"**/.git/logs/**\", \"**/node_modules/**\", \
+         \"**/target/**\"]",
+        "The globs of the files whose changes aren't watched.",
+    ),
+    setting(
+        "search.exclude",
+        SettingKind::Strings,
+        "[\"**/.git\", \"**/node_modules\"]",
+        "The globs of the files left out of searches.",
+    ),
+    setting(
+        "lsp",
+        SettingKind::Table,
+        "{}",
+        "The options of the language servers.",
+    ),
+    setting(
+        "lsp-timeouts",
+        SettingKind::Table,
+        "{}",
+        "How long the requests to the servers can take, by method.",
+    ),
+    setting(
+        "plugins",
+        SettingKind::Table,
+        "{}",
+        "The options of the plugins.",
+    ),
+    setting(
+        "indentation",
+        SettingKind::Table,
+        "{}",
+        "The indentation by language.",
+    ),
+    setting(
+        "comment-tokens",
+        SettingKind::Table,
+        "{}",
+        "The comment tokens by language.",
+    ),
+    setting(
+        "file-associations",
+        SettingKind::Table,
+        "{}",
+        "The languages of the file globs.",
+    ),
+];
+
+/// A problem of a key of a settings file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettingWarning {
+    pub path: PathBuf,
+    /// From 1, 0 when it isn't known.
+    pub line: usize,
+    pub key: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SettingWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: ", self.path.display(), self.line)?;
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The setting of a key, `editor.tab-width` for `language.rust.tab-width`
+/// too, and the table a key in one is for.
+pub fn lookup(key: &str) -> Option<&'static SettingDef> {
+    if let Some(def) = SETTINGS.iter().find(|def| def.key == key) {
+        return Some(def);
+    }
+    if let Some(rest) = key.strip_prefix("language.") {
+        let (_, name) = rest.split_once('.')?;
+        return SETTINGS.iter().find(|def| {
+            def.per_language && def.key.strip_prefix("editor.") == Some(name)
+        });
+    }
+    SETTINGS.iter().find(|def| {
+        def.kind == SettingKind::Table
+            && key.starts_with(def.key)
+            && key[def.key.len()..].starts_with('.')
+    })
+}
+
+impl SettingDef {
+    pub fn default_value(&self) -> toml::Value {
+        toml::from_str::<toml::Value>(&format!("value = {}", self.default))
+            .ok()
+            .and_then(|table| table.get("value").cloned())
+            .unwrap_or_else(|| toml::Value::Table(toml::value::Table::new()))
+    }
+
+    /// Why the value can't be the setting's, if it can't.
+    pub fn check(&self, value: &toml::Value) -> Result<(), String> {
+        let ok = match (self.kind, value) {
+            (SettingKind::Bool, toml::Value::Boolean(_)) => true,
+            (SettingKind::Integer, toml::Value::Integer(_)) => true,
+            (SettingKind::String, toml::Value::String(_)) => true,
+            (SettingKind::Enum(values), toml::Value::String(s)) => {
+                if !values.contains(&s.as_str()) {
+                    return Err(format!(
+                        "expected one of {}, found \"{}\"",
+                        values.join(", "),
+                        s
+                    ));
+                }
+                true
+            }
+            (SettingKind::Integers, toml::Value::Array(values)) => {
+                values.iter().all(|v| v.is_integer())
+            }
+            (SettingKind::Strings, toml::Value::Array(values)) => {
+                values.iter().all(|v| v.is_str())
+            }
+            (SettingKind::Table, toml::Value::Table(_)) => true,
+            _ => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected {}, found {}",
+                kind_name(self.kind),
+                value_name(value)
+            ))
+        }
+    }
+}
+
+fn kind_name(kind: SettingKind) -> &'static str {
+    match kind {
+        SettingKind::Bool => "a boolean",
+        SettingKind::Integer => "an integer",
+        SettingKind::String | SettingKind::Enum(_) => "a string",
+        SettingKind::Integers => "an array of integers",
+        SettingKind::Strings => "an array of strings",
+        SettingKind::Table => "a table",
+    }
+}
+
+fn value_name(value: &toml::Value) -> &'static str {
+    match value {
+        toml::Value::Boolean(_) => "a boolean",
+        toml::Value::Integer(_) => "an integer",
+        toml::Value::Float(_) => "a float",
+        toml::Value::String(_) => "a string",
+        toml::Value::Datetime(_) => "a date",
+        toml::Value::Array(_) => "an array",
+        toml::Value::Table(_) => "a table",
+    }
+}
+
+/// The problems of the keys of a settings file.
+pub fn validate(path: &Path, contents: &str) -> Vec<SettingWarning> {
+    let mut warnings = Vec::new();
+    let settings = match toml::from_str::<toml::Value>(contents) {
+        Ok(settings) => settings,
+        Err(e) => {
+            warnings.push(SettingWarning {
+                path: path.to_path_buf(),
+                line: e.line_col().map(|(line, _)| line + 1).unwrap_or(0),
+                key: "".to_string(),
+                message: e.to_string(),
+            });
+            return warnings;
+        }
+    };
+    let mut problems = Vec::new();
+    check_table(&settings, "", &mut problems);
+    for (key, message) in problems {
+        warnings.push(SettingWarning {
+            path: path.to_path_buf(),
+            line: key_line(contents, &key),
+            key,
+            message,
+        });
+    }
+    warnings
+}
+
+fn check_table(
+    value: &toml::Value,
+    prefix: &str,
+    problems: &mut Vec<(String, String)>,
+) {
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => return,
+    };
+    for (name, value) in table {
+        let key = match prefix {
+            "" => name.clone(),
+            prefix => format!("{}.{}", prefix, name),
+        };
+        if key == LANGUAGE_TABLE {
+            match value.as_table() {
+                Some(languages) => {
+                    for (language, value) in languages {
+                        check_language(value, &key, language, problems);
+                    }
+                }
+                None => problems.push((key, "expected a table".to_string())),
+            }
+            continue;
+        }
+        if let Some(def) = SETTINGS.iter().find(|def| def.key == key) {
+            if let Err(message) = def.check(value) {
+                problems.push((key, message));
+            }
+            continue;
+        }
+        let is_table = SETTINGS
+            .iter()
+            .any(|def| def.key.starts_with(&format!("{}.", key)));
+        match value {
+            toml::Value::Table(_) if is_table => check_table(value, &key, problems),
+            _ if is_table => problems.push((key, "expected a table".to_string())),
+            _ => problems.push((key, "unknown setting".to_string())),
+        }
+    }
+}
+
+fn check_language(
+    value: &toml::Value,
+    prefix: &str,
+    language: &str,
+    problems: &mut Vec<(String, String)>,
+) {
+    let prefix = format!("{}.{}", prefix, language);
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => {
+            problems.push((prefix, "expected a table".to_string()));
+            return;
+        }
+    };
+    for (name, value) in table {
+        let key = format!("{}.{}", prefix, name);
+        match lookup(&key) {
+            Some(def) => {
+                if let Err(message) = def.check(value) {
+                    problems.push((key, message));
+                }
+            }
+            None => problems.push((
+                key,
+                "unknown setting, or one a language can't set".to_string(),
+            )),
+        }
+    }
+}
+
+/// The line of the file a dotted key is set on, from 1, 0 if it can't be
+/// told.
+fn key_line(contents: &str, key: &str) -> usize {
+    let mut table = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            table = line
+                .trim_start_matches('[')
+                .split(']')
+                .next()
+                .unwrap_or("")
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(".");
+            if table == key {
+                return i + 1;
+            }
+            continue;
+        }
+        let name = match line.split_once('=') {
+            Some((name, _)) => name
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join("."),
+            None => continue,
+        };
+        let full = if table.is_empty() {
+            name
+        } else {
+            format!("{}.{}", table, name)
+        };
+        if full == key || key.starts_with(&format!("{}.", full)) {
+            return i + 1;
+        }
+    }
+    0
+}
+
+/// Sets a key of a settings file, or removes it for null, leaving the
+/// comments and the formatting of the rest of the file as they are.
+pub fn update_file(path: &Path, key: &str, value: &Value) -> Result<()> {
+    if let Some(def) = lookup(key) {
+        if !value.is_null() {
+            let value = toml::Value::try_from(value)?;
+            if def.key == key || def.per_language {
+                def.check(&value).map_err(|e| anyhow!("{}: {}", key, e))?;
+            }
+        }
+    } else if !key.starts_with("language.") {
+        return Err(anyhow!("{}: unknown setting", key));
+    } else {
+        return Err(anyhow!("{}: a language can't set it", key));
+    }
+
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let mut doc = contents.parse::<toml_edit::Document>()?;
+    let parts: Vec<&str> = key.split('.').collect();
+    let (name, parents) = parts.split_last().ok_or_else(|| anyhow!("no key"))?;
+    let mut table: &mut dyn toml_edit::TableLike = doc.as_table_mut();
+    for part in parents {
+        if table.get(part).is_none() {
+            if value.is_null() {
+                return Ok(());
+            }
+            let mut new = toml_edit::Table::new();
+            new.set_implicit(true);
+            table.insert(part, toml_edit::Item::Table(new));
+        }
+        table = table
+            .get_mut(part)
+            .and_then(|item| item.as_table_like_mut())
+            .ok_or_else(|| anyhow!("{} isn't a table", part))?;
+    }
+    if value.is_null() {
+        table.remove(name);
+    } else {
+        let new = to_toml_edit(value)?;
+        match table.get_mut(name).and_then(|item| item.as_value_mut()) {
+            Some(existing) => {
+                // the comment after the old value stays
+                let decor = existing.decor().clone();
+                *existing = new;
+                *existing.decor_mut() = decor;
+            }
+            None => {
+                table.insert(name, toml_edit::Item::Value(new));
+            }
+        }
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, doc.to_string())?;
+    Ok(())
+}
+
+fn to_toml_edit(value: &Value) -> Result<toml_edit::Value> {
+    Ok(match value {
+        Value::Null => return Err(anyhow!("TOML has no null")),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or_default().into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(values) => toml_edit::Value::Array(
+            values
+                .iter()
+                .map(to_toml_edit)
+                .collect::<Result<toml_edit::Array>>()?,
+        ),
+        Value::Object(map) => toml_edit::Value::InlineTable(
+            map.iter()
+                .map(|(key, value)| Ok((key.as_str(), to_toml_edit(value)?)))
+                .collect::<Result<toml_edit::InlineTable>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let contents = r#"
+[editor]
+tab-width = "4"
+auto-save = "never"
+fonts-size = 12
+
+[language.rust]
+rulers = [100]
+font-size = 12
+
+[lsp.rust-analyzer]
+anything = true
+"#;
+        let warnings = validate(Path::new("settings.toml"), contents);
+        let found: Vec<(&str, usize)> =
+            warnings.iter().map(|w| (w.key.as_str(), w.line)).collect();
+        assert_eq!(
+            vec![
+                ("editor.auto-save", 4),
+                ("editor.fonts-size", 5),
+                ("editor.tab-width", 3),
+                ("language.rust.font-size", 9),
+            ],
+            found
+        );
+        assert_eq!("expected an integer, found a string", warnings[2].message);
+
+        let warnings = validate(Path::new("settings.toml"), "[editor\n");
+        assert_eq!(1, warnings.len());
+        assert_eq!(1, warnings[0].line);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let path = Path::new("../defaults/settings.toml");
+        let contents = include_str!("../../defaults/settings.toml");
+        assert!(validate(path, contents).is_empty());
+        let defaults: toml::Value = toml::from_str(contents).unwrap();
+        for def in SETTINGS {
+            let mut value = Some(&defaults);
+            for part in def.key.split('.') {
+                value = value.and_then(|value| value.get(part));
+            }
+            if let Some(value) = value {
+                assert_eq!(&def.default_value(), value, "{}", def.key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_file() {
+        let dir = TestDir::new("registry-update");
+        let path = dir.join("settings.toml");
+        fs::write(
+            &path,
+            "# mine\n[editor]\ntab-width = 2 # for now\nfont-size = 13\n",
+        )
+        .unwrap();
+
+        update_file(&path, "editor.tab-width", &json!(8)).unwrap();
+        update_file(&path, "language.rust.rulers", &json!([100])).unwrap();
+        update_file(&path, "editor.font-size", &Value::Null).unwrap();
+        assert!(update_file(&path, "editor.tab-width", &json!("8")).is_err());
+        assert!(update_file(&path, "editor.typo", &json!(1)).is_err());
+        assert!(update_file(&path, "language.rust.font-size", &json!(1)).is_err());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# mine\n[editor]\ntab-width = 8 # for now\n"));
+        assert!(contents.contains("[language.rust]\nrulers = [100]\n"));
+        assert!(!contents.contains("font-size"));
+        assert!(!contents.contains("[language]"));
+    }
+}
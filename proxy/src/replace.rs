@@ -0,0 +1,359 @@
+//! Replacing the matches of a workspace search in two steps: the
+//! replacements are found and kept as a session, with a preview of each
+//! line before and after for the core to show, and then the ones the user
+//! kept are made. A file that changed in between is left alone and
+//! reported, as the matches found in it may have moved.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Result};
+use ignore::WalkState;
+use lsp_types::{Position, Range, TextEdit};
+use parking_lot::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use xi_rope::Rope;
+
+use crate::buffer::{apply_text_edits, hash_bytes};
+use crate::encoding::read_file;
+use crate::save::write_file;
+use crate::search::{line_window, read_text, search_walker, MAX_SEARCH_MATCHES};
+
+/// A match and what it becomes.
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    pub line: usize,
+    /// The line, or the part of it around the match when it's long.
+    pub before: String,
+    /// `before` with the match replaced.
+    pub after: String,
+    pub edit: TextEdit,
+}
+
+/// The replacements in a file, with the hash of the text they were found
+/// in.
+#[derive(Clone, Debug)]
+pub struct FileReplacements {
+    pub path: PathBuf,
+    pub hash: u64,
+    pub replacements: Vec<Replacement>,
+}
+
+/// Answers `global_replace`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplacePreview {
+    pub session_id: u64,
+    pub files: Vec<ReplacePreviewFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplacePreviewFile {
+    pub path: PathBuf,
+    pub matches: Vec<ReplacePreviewMatch>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplacePreviewMatch {
+    /// What `apply_replace` is given to make this replacement.
+    pub id: usize,
+    pub line: usize,
+    /// The UTF-16 column of the match.
+    pub column: u32,
+    pub before: String,
+    pub after: String,
+}
+
+/// Answers `apply_replace`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplyReplaceResponse {
+    /// The edits of the files open in buffers, which the core makes so that
+    /// they can be undone, each with the revision it's for.
+    pub buffer_edits: Vec<(PathBuf, u64, Vec<TextEdit>)>,
+    pub written: Vec<PathBuf>,
+    /// The files left alone, with why.
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+/// The replacements found by the last `global_replace`, a newer one taking
+/// the place of the one before.
+#[derive(Default)]
+pub struct Replaces {
+    last_id: u64,
+    session: Option<(u64, Vec<FileReplacements>)>,
+}
+
+impl Replaces {
+    /// Keeps `files` as a new session, giving its preview. The matches are
+    /// numbered in the order of `files`.
+    pub fn start(&mut self, files: Vec<FileReplacements>) -> ReplacePreview {
+        self.last_id += 1;
+        let mut id = 0;
+        let preview = ReplacePreview {
+            session_id: self.last_id,
+            files: files
+                .iter()
+                .map(|file| ReplacePreviewFile {
+                    path: file.path.clone(),
+                    matches: file
+                        .replacements
+                        .iter()
+                        .map(|replacement| {
+                            id += 1;
+                            ReplacePreviewMatch {
+                                id: id - 1,
+                                line: replacement.line,
+                                column: replacement.edit.range.start.character,
+                                before: replacement.before.clone(),
+                                after: replacement.after.clone(),
+                            }
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        self.session = Some((self.last_id, files));
+        preview
+    }
+
+    /// The replacements of session `session_id`, which can only be made
+    /// once, as the text they were found in is gone after.
+    pub fn take(&mut self, session_id: u64) -> Result<Vec<FileReplacements>> {
+        match self.session.take() {
+            Some((id, files)) if id == session_id => Ok(files),
+            session => {
+                self.session = session;
+                Err(anyhow!("replace session {} is gone", session_id))
+            }
+        }
+    }
+}
+
+/// The replacements of the matches of `regex` in the files of `folders`,
+/// `open` ones in their buffer's text rather than on disk, in the order of
+/// their paths. `expand` replaces `$1` and the like with what the groups
+/// of the regex matched.
+pub fn global_replace(
+    folders: &[PathBuf],
+    exclude: &[String],
+    open: &HashMap<PathBuf, Rope>,
+    regex: &Regex,
+    replacement: &str,
+    expand: bool,
+) -> Vec<FileReplacements> {
+    let walker = match search_walker(folders, exclude) {
+        Some(walker) => walker,
+        None => return Vec::new(),
+    };
+    let files = Mutex::new(Vec::new());
+    let found = AtomicUsize::new(0);
+    walker.run(|| {
+        let (files, found) = (&files, &found);
+        Box::new(move |entry| {
+            if found.load(Ordering::Relaxed) >= MAX_SEARCH_MATCHES {
+                return WalkState::Quit;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => return WalkState::Continue,
+            };
+            if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                return WalkState::Continue;
+            }
+            let path = entry.path();
+            let text = match open.get(path) {
+                Some(rope) => Some(rope.to_string()),
+                None => read_text(path),
+            };
+            let file = text.and_then(|text| {
+                file_replacements(path, &text, regex, replacement, expand)
+            });
+            if let Some(file) = file {
+                found.fetch_add(file.replacements.len(), Ordering::Relaxed);
+                files.lock().push(file);
+            }
+            WalkState::Continue
+        })
+    });
+    let mut files = files.into_inner();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+/// The replacements in `text`, None without matches.
+fn file_replacements(
+    path: &Path,
+    text: &str,
+    regex: &Regex,
+    replacement: &str,
+    expand: bool,
+) -> Option<FileReplacements> {
+    let mut replacements = Vec::new();
+    for (line, line_text) in text.lines().enumerate() {
+        let column = |offset: usize| line_text[..offset].encode_utf16().count();
+        for captures in regex.captures_iter(line_text) {
+            let m = captures.get(0).unwrap();
+            if m.start() == m.end() {
+                continue;
+            }
+            let mut new_text = String::new();
+            match expand {
+                true => captures.expand(replacement, &mut new_text),
+                false => new_text.push_str(replacement),
+            }
+            let (start, end) = line_window(line_text, m.start());
+            let end = end.max(m.end());
+            let position =
+                |offset| Position::new(line as u32, column(offset) as u32);
+            replacements.push(Replacement {
+                line,
+                before: line_text[start..end].to_string(),
+                after: format!(
+                    "{}{}{}",
+                    &line_text[start..m.start()],
+                    new_text,
+                    &line_text[m.end()..end]
+                ),
+                edit: TextEdit {
+                    range: Range {
+                        start: position(m.start()),
+                        end: position(m.end()),
+                    },
+                    new_text,
+                },
+            });
+        }
+    }
+    if replacements.is_empty() {
+        return None;
+    }
+    Some(FileReplacements {
+        path: path.to_path_buf(),
+        hash: hash_bytes(text.as_bytes()),
+        replacements,
+    })
+}
+
+/// Makes the replacements of `ids`, numbered as in the preview of `files`.
+/// The edits of the files `open` in buffers, with their revisions, are given
+/// back for the core to make, and the others are written, all of them edited
+/// in memory first so that a failed edit writes nothing.
+pub fn apply_replace(
+    files: Vec<FileReplacements>,
+    ids: &HashSet<usize>,
+    open: &HashMap<PathBuf, (u64, Rope)>,
+) -> ApplyReplaceResponse {
+    let mut response = ApplyReplaceResponse::default();
+    let mut contents = Vec::new();
+    let mut id = 0;
+    for file in files {
+        let edits: Vec<TextEdit> = file
+            .replacements
+            .into_iter()
+            .filter(|_| {
+                id += 1;
+                ids.contains(&(id - 1))
+            })
+            .map(|replacement| replacement.edit)
+            .collect();
+        if edits.is_empty() {
+            continue;
+        }
+        let changed = || anyhow!("changed since the replacements were found");
+        let result = match open.get(&file.path) {
+            Some((rev, rope)) => {
+                if hash_bytes(rope.to_string().as_bytes()) == file.hash {
+                    response.buffer_edits.push((file.path, *rev, edits));
+                    continue;
+                }
+                Err(changed())
+            }
+            None => read_file(&file.path).and_then(|(text, encoding)| {
+                if hash_bytes(text.as_bytes()) != file.hash {
+                    return Err(changed());
+                }
+                let rope = apply_text_edits(&Rope::from(text), &edits)?;
+                encoding.encode(&rope.to_string())
+            }),
+        };
+        match result {
+            Ok(bytes) => contents.push((file.path, bytes)),
+            Err(e) => response.skipped.push((file.path, e.to_string())),
+        }
+    }
+    for (path, bytes) in contents {
+        match write_file(&path, &bytes) {
+            Ok(()) => response.written.push(path),
+            Err(e) => response.skipped.push((path, e.to_string())),
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::search::SearchOptions;
+    use crate::test_dir::TestDir;
+
+    const TEXT: &str = "let a = b.unwrap();\nlet c = d.unwrap() + e.unwrap();\n";
+
+    #[test]
+    fn test_replace() {
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let regex = options.regex(r"(\w+)\.unwrap\(\)").unwrap();
+        let file = |path: &Path| {
+            file_replacements(path, TEXT, &regex, "$1?", true).unwrap()
+        };
+        let after = |file: &FileReplacements| {
+            file.replacements
+                .iter()
+                .map(|r| (r.line, r.after.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![
+                (0, "let a = b?;".to_string()),
+                (1, "let c = d? + e.unwrap();".to_string()),
+                (1, "let c = d.unwrap() + e?;".to_string()),
+            ],
+            after(&file(Path::new("a.rs")))
+        );
+        // the replacement is text unless the query is a regex
+        let regex = SearchOptions::default().regex("unwrap()").unwrap();
+        let literal =
+            file_replacements(Path::new("a.rs"), TEXT, &regex, "$1", false);
+        assert_eq!("let a = b.$1;", literal.unwrap().replacements[0].after);
+
+        let dir = TestDir::new("replace");
+        let (kept, changed) = (dir.join("kept.rs"), dir.join("changed.rs"));
+        fs::write(&kept, TEXT).unwrap();
+        fs::write(&changed, TEXT).unwrap();
+        let mut replaces = Replaces::default();
+        let preview = replaces.start(vec![file(&kept), file(&changed)]);
+        fs::write(&changed, "let b = 1;\n").unwrap();
+
+        let files = replaces.take(preview.session_id).unwrap();
+        let ids: HashSet<usize> = [0, 2, 3].into_iter().collect();
+        let response = apply_replace(files, &ids, &HashMap::new());
+        let text = fs::read_to_string(&kept).unwrap();
+        assert!(replaces.take(preview.session_id).is_err());
+
+        assert_eq!("let a = b?;\nlet c = d.unwrap() + e?;\n", text);
+        assert_eq!(vec![kept], response.written);
+        assert_eq!(
+            vec![changed],
+            response
+                .skipped
+                .into_iter()
+                .map(|s| s.0)
+                .collect::<Vec<_>>()
+        );
+    }
+}
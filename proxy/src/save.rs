@@ -0,0 +1,223 @@
+//! Writing a file so that a crash halfway leaves the old content there: the
+//! bytes go to a temp file next to it, which is synced and renamed over it.
+//! When a rename would lose something about the file, like its other hard
+//! links or its owner, it's written in place instead.
+
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+/// `EROFS` on Linux and macOS.
+pub(crate) const READ_ONLY_FILE_SYSTEM: i32 = 30;
+/// How many symlinks in a row are followed.
+const MAX_SYMLINKS: usize = 40;
+/// How many temp file names are tried before writing in place.
+const MAX_TMP_NAMES: usize = 16;
+
+pub fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let path = resolve_symlinks(path).map_err(|e| write_error(path, e))?;
+    let metadata = fs::metadata(&path).ok();
+    let result = match metadata.as_ref() {
+        Some(metadata) if has_other_links(metadata) => write_in_place(&path, bytes),
+        _ => match write_atomically(&path, bytes, metadata.as_ref()) {
+            Ok(true) => Ok(()),
+            // the temp file couldn't be made or can't stand in for the file
+            Ok(false) => write_in_place(&path, bytes),
+            Err(e) => Err(e),
+        },
+    };
+    result.map_err(|e| write_error(&path, e))
+}
+
+/// The file a symlink points to, through any number of links, so that
+/// the link itself stays. A path that doesn't exist yet is kept.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "too many levels of symlinks",
+    ))
+}
+
+/// Writes `bytes` to a temp file in the directory of `path` and renames it
+/// over `path`. Gives `false`, having left `path` alone, when it has to be
+/// written in place.
+fn write_atomically(
+    path: &Path,
+    bytes: &[u8],
+    metadata: Option<&Metadata>,
+) -> io::Result<bool> {
+    let (tmp_path, mut file) = match create_tmp_file(path)? {
+        Some(tmp) => tmp,
+        None => return Ok(false),
+    };
+    let result = (|| {
+        if let Some(metadata) = metadata {
+            if !same_owner(&file.metadata()?, metadata) {
+                return Ok(false);
+            }
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(true)
+    })();
+    match result {
+        Ok(true) => sync_dir(path),
+        _ => {
+            let _ = fs::remove_file(&tmp_path);
+        }
+    }
+    result
+}
+
+fn write_in_place(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// A new temp file next to `path`, or `None` when none can be made there.
+/// A name that's taken, by one a crash left behind or another save of the
+/// same file, is skipped for the next.
+fn create_tmp_file(path: &Path) -> io::Result<Option<(PathBuf, File)>> {
+    for attempt in 0..MAX_TMP_NAMES {
+        let tmp_path = tmp_path(path, attempt);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+        {
+            Ok(file) => return Ok(Some((tmp_path, file))),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+fn tmp_path(path: &Path, attempt: usize) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.lapce-save-{}-{}",
+        name,
+        std::process::id(),
+        attempt
+    ))
+}
+
+/// Makes the rename itself durable.
+fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(Ok(dir)) = path.parent().map(File::open) {
+        let _ = dir.sync_all();
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(unix)]
+fn has_other_links(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_other_links(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// A file renamed over another one brings its owner along, so it has to be
+/// the same, which it isn't when editing someone else's file.
+#[cfg(unix)]
+fn same_owner(new: &Metadata, old: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    new.uid() == old.uid() && new.gid() == old.gid()
+}
+
+#[cfg(not(unix))]
+fn same_owner(_new: &Metadata, _old: &Metadata) -> bool {
+    true
+}
+
+fn write_error(path: &Path, e: io::Error) -> anyhow::Error {
+    if e.raw_os_error() == Some(READ_ONLY_FILE_SYSTEM) {
+        anyhow!("can't write {:?}: the file system is read-only", path)
+    } else if e.kind() == io::ErrorKind::PermissionDenied {
+        anyhow!("can't write {:?}: permission denied", path)
+    } else {
+        anyhow!("can't write {:?}: {}", path, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_write_file() {
+        let dir = TestDir::new("save");
+        let path = dir.join("file.txt");
+        fs::write(&path, "old").unwrap();
+        write_file(&path, b"new").unwrap();
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+        // a temp file a crash left behind
+        fs::write(tmp_path(&path, 0), "leftover").unwrap();
+        write_file(&path, b"newer").unwrap();
+        assert_eq!("newer", fs::read_to_string(&path).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_file(tmp_path(&path, 0)).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{symlink, PermissionsExt};
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            let link = dir.join("link.txt");
+            symlink("file.txt", &link).unwrap();
+            write_file(&link, b"through the link").unwrap();
+            assert!(fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!("through the link", fs::read_to_string(&path).unwrap());
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o640, mode & 0o777);
+
+            let hard_link = dir.join("hard-link.txt");
+            fs::hard_link(&path, &hard_link).unwrap();
+            write_file(&path, b"both").unwrap();
+            assert_eq!("both", fs::read_to_string(&hard_link).unwrap());
+        }
+    }
+}
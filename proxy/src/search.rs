@@ -0,0 +1,351 @@
+//! Searches the files of the workspace folders for a query, walking them
+//! on a thread per core and leaving out what git ignores, the user's
+//! `[search] exclude` globs and binary files. Matches are sent in batches
+//! as they're found, each with the id the core gave the search, so the
+//! batches of a search it has moved on from are dropped. A newer search
+//! stops the walk of the one before.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossbeam_channel::{unbounded, RecvTimeoutError};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{WalkBuilder, WalkParallel, WalkState};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use xi_rope::Rope;
+
+use crate::encoding::{is_binary, FileEncoding};
+use crate::settings::setting;
+
+/// How often the matches found are sent, so the first ones show right
+/// away however big the workspace is.
+pub const SEARCH_BATCH_INTERVAL: Duration = Duration::from_millis(50);
+/// How many matches a search finds before it stops looking.
+pub const MAX_SEARCH_MATCHES: usize = 10_000;
+/// How much of a long line, like a minified one, is sent with its match.
+const MAX_LINE_TEXT: usize = 300;
+/// Left out of searches unless the user's `[search] exclude` says
+/// otherwise.
+const DEFAULT_SEARCH_EXCLUDE: &[&str] = &["**/.git", "**/node_modules"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchOptions {
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    /// The query is a regular expression rather than text.
+    pub regex: bool,
+}
+
+impl SearchOptions {
+    pub fn regex(&self, query: &str) -> Result<Regex> {
+        let mut pattern = match self.regex {
+            true => query.to_string(),
+            false => regex::escape(query),
+        };
+        if self.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        Ok(RegexBuilder::new(&pattern)
+            .case_insensitive(!self.case_sensitive)
+            .build()?)
+    }
+}
+
+/// A line with matches of a search.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchMatch {
+    pub path: PathBuf,
+    pub line: usize,
+    /// The UTF-16 column of the first match in the line of the file, as
+    /// the positions of the editor count.
+    pub column: usize,
+    /// The line, or the part of it around the first match when it's long.
+    pub line_text: String,
+    /// The byte ranges of the matches in `line_text`.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// The search running, for a newer one or `cancel_search` to stop.
+#[derive(Default)]
+pub struct Searches {
+    running: Option<(u64, Arc<AtomicBool>)>,
+}
+
+impl Searches {
+    /// Stops the search running, giving the flag that stops search `id`.
+    pub fn start(&mut self, id: u64) -> Arc<AtomicBool> {
+        if let Some((_, cancelled)) = self.running.take() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.running = Some((id, cancelled.clone()));
+        cancelled
+    }
+
+    pub fn cancel(&mut self, id: u64) {
+        if self.running.as_ref().map(|(running, _)| *running) == Some(id) {
+            if let Some((_, cancelled)) = self.running.take() {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The globs of the user's `[search] exclude`, or the defaults.
+pub fn search_exclude() -> Vec<String> {
+    let exclude =
+        setting(&["search", "exclude"]).and_then(|exclude| exclude.try_into().ok());
+    exclude.unwrap_or_else(|| {
+        DEFAULT_SEARCH_EXCLUDE
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect()
+    })
+}
+
+pub(crate) fn glob_set(globs: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        match Glob::new(glob) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => eprintln!("search exclude {} err {}", glob, e),
+        }
+    }
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
+
+/// The walk of the files a search of `folders` goes through. None without
+/// folders.
+pub(crate) fn search_walker(
+    folders: &[PathBuf],
+    exclude: &[String],
+) -> Option<WalkParallel> {
+    let mut builder = WalkBuilder::new(folders.first()?);
+    for folder in &folders[1..] {
+        builder.add(folder);
+    }
+    // a folder that isn't a repository can still have a .gitignore
+    builder.require_git(false);
+    let exclude = glob_set(exclude);
+    let roots = folders.to_vec();
+    builder.filter_entry(move |entry| {
+        // nested folders are walked on their own
+        if entry.depth() > 0 && roots.iter().any(|root| root == entry.path()) {
+            return false;
+        }
+        let relative = roots
+            .iter()
+            .find_map(|root| entry.path().strip_prefix(root).ok())
+            .unwrap_or_else(|| entry.path());
+        !exclude.is_match(relative)
+    });
+    Some(builder.build_parallel())
+}
+
+/// Searches the files of `folders` on other threads, `open` ones in their
+/// buffer's text rather than on disk, calling `send` with each batch of
+/// matches and whether it's the last one. Nothing is sent once
+/// `cancelled`.
+pub fn global_search(
+    folders: Vec<PathBuf>,
+    exclude: &[String],
+    open: HashMap<PathBuf, Rope>,
+    regex: Regex,
+    cancelled: Arc<AtomicBool>,
+    send: impl Fn(Vec<SearchMatch>, bool) + Send + 'static,
+) {
+    let walker = match search_walker(&folders, exclude) {
+        Some(walker) => walker,
+        None => {
+            send(Vec::new(), true);
+            return;
+        }
+    };
+
+    let (sender, receiver) = unbounded();
+    let found = Arc::new(AtomicUsize::new(0));
+    let open = Arc::new(open);
+    let walk_cancelled = cancelled.clone();
+    thread::spawn(move || {
+        walker.run(|| {
+            let sender = sender.clone();
+            let regex = regex.clone();
+            let open = open.clone();
+            let found = found.clone();
+            let cancelled = walk_cancelled.clone();
+            Box::new(move |entry| {
+                if cancelled.load(Ordering::Relaxed)
+                    || found.load(Ordering::Relaxed) >= MAX_SEARCH_MATCHES
+                {
+                    return WalkState::Quit;
+                }
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => return WalkState::Continue,
+                };
+                if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                    return WalkState::Continue;
+                }
+                let path = entry.path();
+                let matches = match open.get(path) {
+                    Some(rope) => search_text(path, &rope.to_string(), &regex),
+                    None => match read_text(path) {
+                        Some(text) => search_text(path, &text, &regex),
+                        None => Vec::new(),
+                    },
+                };
+                if !matches.is_empty() {
+                    found.fetch_add(matches.len(), Ordering::Relaxed);
+                    if sender.send(matches).is_err() {
+                        return WalkState::Quit;
+                    }
+                }
+                WalkState::Continue
+            })
+        });
+    });
+
+    thread::spawn(move || {
+        let mut batch = Vec::new();
+        let mut last_sent = Instant::now();
+        loop {
+            let timeout = SEARCH_BATCH_INTERVAL
+                .checked_sub(last_sent.elapsed())
+                .unwrap_or_default();
+            let done = match receiver.recv_timeout(timeout) {
+                Ok(matches) => {
+                    batch.extend(matches);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            if done {
+                send(batch, true);
+                return;
+            }
+            if last_sent.elapsed() >= SEARCH_BATCH_INTERVAL {
+                if !batch.is_empty() {
+                    send(std::mem::take(&mut batch), false);
+                }
+                last_sent = Instant::now();
+            }
+        }
+    });
+}
+
+/// The text of a file, None if it isn't text.
+pub(crate) fn read_text(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    if is_binary(&bytes) {
+        return None;
+    }
+    match String::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            let bytes = e.into_bytes();
+            Some(FileEncoding::detect(&bytes).decode(&bytes))
+        }
+    }
+}
+
+fn search_text(path: &Path, text: &str, regex: &Regex) -> Vec<SearchMatch> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(line, line_text)| {
+            let ranges: Vec<(usize, usize)> = regex
+                .find_iter(line_text)
+                .filter(|m| m.start() < m.end())
+                .map(|m| (m.start(), m.end()))
+                .collect();
+            let first = ranges.first()?.0;
+            let (start, end) = line_window(line_text, first);
+            Some(SearchMatch {
+                path: path.to_path_buf(),
+                line,
+                column: line_text[..first].encode_utf16().count(),
+                line_text: line_text[start..end].to_string(),
+                ranges: ranges
+                    .iter()
+                    .filter(|(_, match_end)| *match_end <= end)
+                    .map(|(s, e)| (s - start, e - start))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// The part of a line sent with a match at `column`: all of it, or for a
+/// long line `MAX_LINE_TEXT` bytes from a bit before the match.
+pub(crate) fn line_window(line: &str, column: usize) -> (usize, usize) {
+    if line.len() <= MAX_LINE_TEXT {
+        return (0, line.len());
+    }
+    let mut start = column.saturating_sub(MAX_LINE_TEXT / 4);
+    while !line.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (start + MAX_LINE_TEXT).min(line.len());
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_text() {
+        let text = "let a = foo();\nfoobar(Foo);\n";
+        let search = |query: &str, options: SearchOptions| {
+            let regex = options.regex(query).unwrap();
+            search_text(Path::new("a.rs"), text, &regex)
+                .into_iter()
+                .map(|m| (m.line, m.column, m.ranges))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![(0, 8, vec![(8, 11)]), (1, 0, vec![(0, 3), (7, 10)])],
+            search("foo", SearchOptions::default())
+        );
+        let case_sensitive = SearchOptions {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(vec![(1, 7, vec![(7, 10)])], search("Foo", case_sensitive));
+        let whole_word = SearchOptions {
+            whole_word: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![(0, 8, vec![(8, 11)]), (1, 7, vec![(7, 10)])],
+            search("foo", whole_word)
+        );
+        let regex = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        assert_eq!(vec![(1, 0, vec![(0, 7)])], search(r"fo+bar\(", regex));
+        // the query is text unless it's a regex
+        assert!(search("foo(", SearchOptions::default()).len() == 1);
+
+        let exclude = glob_set(&["**/node_modules".to_string()]);
+        assert!(exclude.is_match("node_modules"));
+        assert!(exclude.is_match("web/node_modules"));
+        assert!(!exclude.is_match("src/node_modules.rs"));
+    }
+}
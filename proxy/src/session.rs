@@ -0,0 +1,275 @@
+//! The proxy living on when its core goes, for the shells of its terminals.
+//! It then listens on a socket of its workspace, and the proxy a new core
+//! starts for that workspace hands the core over to it through the socket
+//! instead of starting afresh. It exits once the last shell is gone with no
+//! core attached, or, for the proxy of a remote workspace, once no core has
+//! reconnected for a while either.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::Shutdown;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use lapce_rpc::IoThreads;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+use crate::dispatch::Dispatcher;
+
+/// How often the shells no core is attached to are checked for idling.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the proxy of a remote workspace waits for its core to come
+/// back, with its buffers, when it has no shells to keep it.
+const RECONNECT_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// The socket the proxy kept for the terminals of `workspace` listens on.
+fn socket_path(workspace: &Path) -> PathBuf {
+    let user = std::env::var("USER").unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    workspace.hash(&mut hasher);
+    std::env::temp_dir()
+        .join(format!("lapce-proxy-{}", user))
+        .join(format!("{:x}.sock", hasher.finish()))
+}
+
+/// Hands the core over to the proxy kept for the workspace it initializes,
+/// if there's one, returning once it's gone. Otherwise what it sends is
+/// returned for this proxy to handle.
+pub fn hand_over(
+    sender: &Sender<Value>,
+    receiver: Receiver<Value>,
+    io_threads: &IoThreads,
+) -> Option<Receiver<Value>> {
+    let mut first = receiver.recv().ok()?;
+    let stream = (first["method"] == "initialize")
+        .then(|| first["params"]["workspace"].as_str().map(PathBuf::from))
+        .flatten()
+        .and_then(|workspace| UnixStream::connect(socket_path(&workspace)).ok());
+    let stream = match stream {
+        Some(stream) => stream,
+        None => {
+            let (tx, rx) = unbounded();
+            let _ = tx.send(first);
+            thread::spawn(move || {
+                for msg in receiver {
+                    if tx.send(msg).is_err() {
+                        return;
+                    }
+                }
+            });
+            return Some(rx);
+        }
+    };
+
+    // what's done for a slow connection is done here, the socket being local
+    if let Some(options) = first["params"]
+        .as_object_mut()
+        .and_then(|params| params.remove("transport"))
+        .and_then(|options| serde_json::from_value(options).ok())
+    {
+        io_threads.transport().configure(options);
+    }
+    let connection = stream.try_clone().ok()?;
+    let (kept_sender, kept_receiver, _) =
+        lapce_rpc::stream(stream.try_clone().ok()?, stream);
+    let _ = kept_sender.send(first);
+    thread::spawn(move || {
+        for msg in receiver {
+            if kept_sender.send(msg).is_err() {
+                break;
+            }
+        }
+        // for the kept proxy to see the core go
+        let _ = connection.shutdown(Shutdown::Both);
+    });
+    for msg in kept_receiver {
+        if sender.send(msg).is_err() {
+            break;
+        }
+    }
+    None
+}
+
+/// Keeps the proxy for its shells once the core is gone, for the next core
+/// of the workspace to attach to. A remote one is kept for the grace period
+/// without shells too, for a core whose connection dropped.
+pub fn keep_terminals(dispatcher: &Dispatcher, remote: bool) {
+    dispatcher.detach_terminals();
+    if !remote && dispatcher.terminals.lock().is_empty() {
+        return;
+    }
+    let path = socket_path(&dispatcher.workspace.lock());
+    if let Some(dir) = path.parent() {
+        let _ = fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir);
+    }
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("can't keep the terminals: {}", e);
+            return;
+        }
+    };
+
+    let attached = Arc::new(AtomicBool::new(false));
+    let detached_at = Arc::new(Mutex::new(Instant::now()));
+    let local_dispatcher = dispatcher.clone();
+    let local_attached = attached.clone();
+    let local_detached_at = detached_at.clone();
+    let local_path = path.clone();
+    thread::spawn(move || loop {
+        thread::sleep(REAP_INTERVAL);
+        local_dispatcher.reap_idle_terminals();
+        let in_grace =
+            remote && local_detached_at.lock().elapsed() < RECONNECT_GRACE;
+        if !local_attached.load(Ordering::SeqCst)
+            && !in_grace
+            && local_dispatcher.terminals.lock().is_empty()
+        {
+            let _ = fs::remove_file(&local_path);
+            std::process::exit(0);
+        }
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(_) => continue,
+        };
+        let (sender, receiver, io_threads) = lapce_rpc::stream(reader, stream);
+        attached.store(true, Ordering::SeqCst);
+        dispatcher.attach_core(sender, io_threads.transport());
+        let _ = dispatcher.mainloop(receiver);
+        dispatcher.detach_terminals();
+        *detached_at.lock() = Instant::now();
+        attached.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn initialize(workspace: &Path) -> Value {
+        json!({
+            "method": "initialize",
+            "params": {
+                "workspace": workspace,
+                "transport": { "compression": true },
+            },
+        })
+    }
+
+    /// A connection for the core, which isn't used.
+    fn io_threads() -> IoThreads {
+        let (a, b) = UnixStream::pair().unwrap();
+        lapce_rpc::stream(a, b).2
+    }
+
+    fn kept_socket(workspace: &Path) -> PathBuf {
+        let path = socket_path(workspace);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path.parent().unwrap())
+            .unwrap();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_socket_path() {
+        let a = socket_path(Path::new("/a"));
+        assert_eq!(a, socket_path(Path::new("/a")));
+        assert_ne!(a, socket_path(Path::new("/b")));
+        assert_eq!(Some("sock"), a.extension().and_then(|e| e.to_str()));
+    }
+
+    #[test]
+    fn test_hand_over() {
+        let workspace = TestDir::new("session-hand-over");
+        let path = kept_socket(&workspace);
+        let listener = UnixListener::bind(&path).unwrap();
+        let kept = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (sender, receiver, _) =
+                lapce_rpc::stream(stream.try_clone().unwrap(), stream);
+            let first = receiver.recv().unwrap();
+            sender.send(json!({ "method": "attached" })).unwrap();
+            let next = receiver.recv().unwrap();
+            (first, next)
+        });
+
+        let (core_sender, core_receiver) = unbounded();
+        core_sender.send(initialize(&workspace)).unwrap();
+        let (sender, receiver) = unbounded();
+        let handing_over = thread::spawn(move || {
+            hand_over(&sender, core_receiver, &io_threads()).is_none()
+        });
+        let attached = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(json!({ "method": "attached" }), attached);
+        core_sender.send(json!({ "method": "next" })).unwrap();
+
+        // the kept proxy gets what the core sends, without the transport
+        // options of its own connection
+        let (first, next) = kept.join().unwrap();
+        assert_eq!("initialize", first["method"]);
+        assert!(first["params"].get("transport").is_none());
+        assert_eq!(json!({ "method": "next" }), next);
+
+        // and the core is handed over until it's gone
+        drop(core_sender);
+        assert!(handing_over.join().unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_hand_over_without_kept_proxy() {
+        let workspace = TestDir::new("session-none-kept");
+        let path = kept_socket(&workspace);
+        let (core_sender, core_receiver) = unbounded();
+        core_sender.send(initialize(&workspace)).unwrap();
+        core_sender.send(json!({ "method": "next" })).unwrap();
+        let receiver = hand_over(&unbounded().0, core_receiver, &io_threads());
+
+        // what the core sends is this proxy's to handle, from the first
+        let receiver = receiver.unwrap();
+        assert_eq!(initialize(&workspace), receiver.recv().unwrap());
+        assert_eq!(json!({ "method": "next" }), receiver.recv().unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_hand_over_to_stale_socket() {
+        // left by a kept proxy that didn't exit cleanly
+        let workspace = TestDir::new("session-stale");
+        let path = kept_socket(&workspace);
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (core_sender, core_receiver) = unbounded();
+        core_sender.send(initialize(&workspace)).unwrap();
+        let receiver = hand_over(&unbounded().0, core_receiver, &io_threads());
+        let _ = fs::remove_file(&path);
+        assert_eq!(initialize(&workspace), receiver.unwrap().recv().unwrap());
+    }
+}
@@ -0,0 +1,378 @@
+//! The user's settings file with the workspace's `.lapce/settings.toml`
+//! merged over it, the workspace winning, sent to the core whenever either
+//! changes. The keys of the workspace that could run something, as the path
+//! of a program, are only taken from a workspace the user trusts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::plugin::user_settings_path;
+use crate::registry::{self, validate, SettingWarning, LANGUAGE_TABLE};
+
+/// Where the value of a key comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingSource {
+    Default,
+    User,
+    Workspace,
+}
+
+/// What's sent to the core in `config_changed`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    /// The user settings with the workspace's over them, the defaults the
+    /// core has left out.
+    pub settings: Value,
+    /// The source of every key set, as in `editor.format-on-save`. A key
+    /// that isn't here has its default.
+    pub sources: BTreeMap<String, SettingSource>,
+    /// The keys whose value changed since the last time.
+    pub changed: Vec<String>,
+    /// The keys of the workspace held back until it's trusted.
+    pub untrusted: Vec<String>,
+    /// What the user said when asked to trust the workspace, if asked.
+    pub trusted: Option<bool>,
+    /// The problems of the keys of both files.
+    pub warnings: Vec<SettingWarning>,
+}
+
+/// A key's value for the workspace, answered to `get_setting`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SettingValue {
+    pub key: String,
+    pub value: Value,
+    pub default: Value,
+    pub source: SettingSource,
+    pub description: String,
+}
+
+/// The settings of the last `LayeredSettings::reload`, so the ones read
+/// through `settings` have the workspace's over the user's.
+static CURRENT: Mutex<Option<toml::Value>> = parking_lot::const_mutex(None);
+
+/// The user's settings with the workspace's over them, as of the last
+/// reload; before the first, the user's file alone. None when it's
+/// missing or broken.
+pub fn settings() -> Option<toml::Value> {
+    if let Some(settings) = CURRENT.lock().clone() {
+        return Some(settings);
+    }
+    let contents = fs::read_to_string(user_settings_path()?).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+/// The value at `path` of the settings, as `["editor", "auto-save"]`.
+pub fn setting(path: &[&str]) -> Option<toml::Value> {
+    let mut value = settings()?;
+    for key in path {
+        value = value.as_table_mut()?.remove(*key)?;
+    }
+    Some(value)
+}
+
+pub fn workspace_settings_path(workspace: &Path) -> PathBuf {
+    workspace.join(".lapce").join("settings.toml")
+}
+
+/// The settings last sent, to tell which keys changed.
+#[derive(Default)]
+pub struct LayeredSettings {
+    settings: Option<toml::Value>,
+    sources: BTreeMap<String, SettingSource>,
+    effective: BTreeMap<String, toml::Value>,
+    warnings: Vec<SettingWarning>,
+}
+
+impl LayeredSettings {
+    /// Reads both files again.
+    pub fn reload(&mut self, workspace: &Path) -> ConfigChange {
+        let mut warnings = Vec::new();
+        let user = user_settings_path()
+            .map(|path| load_file(&path, &mut warnings))
+            .unwrap_or_else(empty);
+        let trusted = workspace_trust(workspace);
+        let layered = layer(
+            user,
+            load_file(&workspace_settings_path(workspace), &mut warnings),
+            trusted,
+        );
+        let mut effective = BTreeMap::new();
+        flatten(&layered.settings, "", &mut effective);
+        let mut changed: Vec<String> = effective
+            .iter()
+            .filter(|(key, value)| self.effective.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .chain(
+                self.effective
+                    .keys()
+                    .filter(|key| !effective.contains_key(*key))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort();
+        self.effective = effective;
+        self.warnings = warnings.clone();
+        self.sources = layered.sources.clone();
+        let settings =
+            serde_json::to_value(&layered.settings).unwrap_or(Value::Null);
+        *CURRENT.lock() = Some(layered.settings.clone());
+        self.settings = Some(layered.settings);
+        ConfigChange {
+            settings,
+            sources: layered.sources,
+            changed,
+            untrusted: layered.untrusted,
+            trusted,
+            warnings,
+        }
+    }
+
+    /// The warnings of the last reload.
+    pub fn warnings(&self) -> &[SettingWarning] {
+        &self.warnings
+    }
+
+    /// The value of a key as of the last reload, a key of a language
+    /// falling back to that of the editor when the language has none.
+    pub fn get(&self, key: &str) -> Option<SettingValue> {
+        let def = registry::lookup(key)?;
+        let lookup = |key: &str| {
+            let mut value = self.settings.as_ref();
+            for part in key.split('.') {
+                value = value.and_then(|value| value.get(part));
+            }
+            value.cloned()
+        };
+        let source = |key: &str| {
+            self.sources
+                .get(key)
+                .copied()
+                .unwrap_or(SettingSource::Default)
+        };
+        let default = def.default_value();
+        let (value, source) = match lookup(key) {
+            Some(value) => (value, source(key)),
+            None if key.starts_with(&format!("{}.", LANGUAGE_TABLE)) => {
+                match lookup(def.key) {
+                    Some(value) => (value, source(def.key)),
+                    None => (default.clone(), SettingSource::Default),
+                }
+            }
+            None => (default.clone(), SettingSource::Default),
+        };
+        Some(SettingValue {
+            key: key.to_string(),
+            value: serde_json::to_value(value).ok()?,
+            default: serde_json::to_value(default).ok()?,
+            source,
+            description: def.description.to_string(),
+        })
+    }
+}
+
+struct Layered {
+    settings: toml::Value,
+    sources: BTreeMap<String, SettingSource>,
+    untrusted: Vec<String>,
+}
+
+fn empty() -> toml::Value {
+    toml::Value::Table(toml::value::Table::new())
+}
+
+/// A missing or broken file is no settings, its problems added to
+/// `warnings`.
+fn load_file(path: &Path, warnings: &mut Vec<SettingWarning>) -> toml::Value {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return empty(),
+    };
+    warnings.extend(validate(path, &contents));
+    match toml::from_str(&contents) {
+        Ok(value @ toml::Value::Table(_)) => value,
+        _ => empty(),
+    }
+}
+
+fn layer(
+    user: toml::Value,
+    mut workspace: toml::Value,
+    trusted: Option<bool>,
+) -> Layered {
+    let mut sources = BTreeMap::new();
+    let mut keys = BTreeMap::new();
+    flatten(&user, "", &mut keys);
+    for key in keys.into_keys() {
+        sources.insert(key, SettingSource::User);
+    }
+
+    let mut keys = BTreeMap::new();
+    flatten(&workspace, "", &mut keys);
+    let mut untrusted = Vec::new();
+    for key in keys.into_keys() {
+        if trusted != Some(true) && is_dangerous(&key) {
+            remove_key(&mut workspace, &key);
+            untrusted.push(key);
+        } else {
+            sources.insert(key, SettingSource::Workspace);
+        }
+    }
+
+    let mut settings = user;
+    merge(&mut settings, workspace);
+    Layered {
+        settings,
+        sources,
+        untrusted,
+    }
+}
+
+/// The key could have something run, as the path of a program, the
+/// arguments it's given or the registry plugins are downloaded from.
+pub fn is_dangerous(key: &str) -> bool {
+    let name = key.rsplit('.').next().unwrap_or(key);
+    let suffixes = [
+        "path", "command", "program", "shell", "args", "env", "registry",
+    ];
+    suffixes.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Puts the tables of `overlay` into those of `base`, the values of
+/// `overlay` replacing those of `base`.
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// The values of a table by dotted key, an array being one value.
+fn flatten(
+    value: &toml::Value,
+    prefix: &str,
+    out: &mut BTreeMap<String, toml::Value>,
+) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = match prefix {
+                    "" => key.clone(),
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                flatten(value, &key, out);
+            }
+        }
+        value => {
+            out.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+fn remove_key(value: &mut toml::Value, key: &str) {
+    match key.split_once('.') {
+        Some((first, rest)) => {
+            if let Some(value) = value.get_mut(first) {
+                remove_key(value, rest);
+            }
+        }
+        None => {
+            if let Some(table) = value.as_table_mut() {
+                table.remove(key);
+            }
+        }
+    }
+}
+
+fn trust_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.config_dir().join("trusted-workspaces.toml"))
+}
+
+fn load_trust() -> BTreeMap<String, bool> {
+    trust_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Whether the user trusts the workspace, None if they weren't asked.
+pub fn workspace_trust(workspace: &Path) -> Option<bool> {
+    load_trust().get(workspace.to_str()?).copied()
+}
+
+pub fn set_workspace_trust(workspace: &Path, trusted: bool) -> Result<()> {
+    let path = trust_path().ok_or_else(|| anyhow::anyhow!("no config dir"))?;
+    let mut trust = load_trust();
+    trust.insert(workspace.to_string_lossy().to_string(), trusted);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, toml::to_string(&trust)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer() {
+        let user: toml::Value = toml::from_str(
+            r#"
+[editor]
+format-on-save = false
+tab-width = 4
+"#,
+        )
+        .unwrap();
+        let workspace: toml::Value = toml::from_str(
+            r#"
+[editor]
+format-on-save = true
+
+[lsp.rust]
+exec-path = "/tmp/evil"
+"#,
+        )
+        .unwrap();
+
+        let layered = layer(user.clone(), workspace.clone(), None);
+        let editor = &layered.settings["editor"];
+        assert_eq!(Some(true), editor["format-on-save"].as_bool());
+        assert_eq!(Some(4), editor["tab-width"].as_integer());
+        assert!(layered.settings["lsp"]["rust"].get("exec-path").is_none());
+        assert_eq!(vec!["lsp.rust.exec-path".to_string()], layered.untrusted);
+        assert_eq!(
+            Some(&SettingSource::Workspace),
+            layered.sources.get("editor.format-on-save")
+        );
+        assert_eq!(
+            Some(&SettingSource::User),
+            layered.sources.get("editor.tab-width")
+        );
+
+        let layered = layer(user, workspace, Some(true));
+        assert!(layered.untrusted.is_empty());
+        assert_eq!(
+            Some("/tmp/evil"),
+            layered.settings["lsp"]["rust"]["exec-path"].as_str()
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! The environment of the user's login shell, for the processes the proxy
+//! starts. Launched from a desktop entry or the dock the proxy has a bare
+//! `PATH`, without what the shell's profile adds, so the shell is run once
+//! at startup to print its environment, which is then put on top of the
+//! proxy's for language servers, plugin processes, tasks and terminals.
+//! `resolve-shell-env = false` in the `[lapce]` table turns it off, for a
+//! shell that's slow to start.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc::channel, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use parking_lot::{const_mutex, Condvar, Mutex};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::setting;
+
+/// How long the shell has to print its environment.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MARKER: &str = "__LAPCE_SHELL_ENV__";
+
+/// The error of the environment when the settings turn it off.
+const OFF: &str = "resolve-shell-env is off";
+
+/// Set by the shell and meaningless to another process.
+const SKIPPED: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolvedEnv {
+    /// What the shell set, put on top of the proxy's environment.
+    pub env: HashMap<String, String>,
+    /// Why there's none, when there's none.
+    pub error: Option<String>,
+}
+
+enum State {
+    NotStarted,
+    Resolving,
+    Resolved(Arc<ResolvedEnv>),
+}
+
+static STATE: Mutex<State> = const_mutex(State::NotStarted);
+static RESOLVED: Condvar = Condvar::new();
+
+/// Runs the login shell on a thread of its own, unless the settings turn
+/// it off.
+pub fn resolve_in_background() {
+    if !resolve_enabled() {
+        *STATE.lock() = State::Resolved(Arc::new(ResolvedEnv {
+            env: HashMap::new(),
+            error: Some(OFF.to_string()),
+        }));
+        RESOLVED.notify_all();
+        return;
+    }
+    *STATE.lock() = State::Resolving;
+    thread::spawn(|| {
+        let resolved = match resolve() {
+            Ok(env) => ResolvedEnv { env, error: None },
+            Err(e) => {
+                eprintln!("can't resolve the shell environment: {}", e);
+                ResolvedEnv {
+                    env: HashMap::new(),
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        let mut state = STATE.lock();
+        // unless turned off while the shell ran
+        if matches!(*state, State::Resolving) {
+            *state = State::Resolved(Arc::new(resolved));
+        }
+        RESOLVED.notify_all();
+    });
+}
+
+/// Resolves the environment, or stops using it, when the settings turned
+/// `resolve-shell-env` on or off since it was last resolved.
+pub fn settings_changed() {
+    let off = match &*STATE.lock() {
+        State::Resolved(resolved) => resolved.error.as_deref() == Some(OFF),
+        _ => false,
+    };
+    if off == resolve_enabled() {
+        resolve_in_background();
+    }
+}
+
+/// The environment of the shell, waiting for it while it's resolved.
+pub fn resolved_env() -> Arc<ResolvedEnv> {
+    let mut state = STATE.lock();
+    loop {
+        match &*state {
+            State::NotStarted => return Arc::new(ResolvedEnv::default()),
+            State::Resolving => RESOLVED.wait(&mut state),
+            State::Resolved(resolved) => return resolved.clone(),
+        }
+    }
+}
+
+/// `resolve-shell-env` of the `[lapce]` table of the settings.
+fn resolve_enabled() -> bool {
+    if cfg!(windows) {
+        return false;
+    }
+    setting(&["lapce", "resolve-shell-env"])
+        .and_then(|enabled| enabled.as_bool())
+        .unwrap_or(true)
+}
+
+fn resolve() -> Result<HashMap<String, String>> {
+    let shell = std::env::var("SHELL").map_err(|_| anyhow!("SHELL isn't set"))?;
+    let script = format!("echo {0}; /usr/bin/env; echo {0}", MARKER);
+    run_shell(&shell, &script, RESOLVE_TIMEOUT)
+}
+
+/// Runs `script` in `shell` and parses the environment it prints, giving
+/// up after `timeout`. A daemon the profile starts, an ssh-agent say, keeps
+/// the shell's stdout open after the shell exits, so the output is read on
+/// a thread that stops at the closing marker and is never joined.
+fn run_shell(
+    shell: &str,
+    script: &str,
+    timeout: Duration,
+) -> Result<HashMap<String, String>> {
+    let deadline = Instant::now() + timeout;
+    let mut child = Command::new(shell)
+        .args(["-i", "-l", "-c", script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let _ = sender.send(read_to_marker(stdout));
+    });
+    let output = match receiver.recv_timeout(timeout) {
+        Ok(output) => output,
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("{} took over {:?}", shell, timeout));
+        }
+    };
+    // the shell exits right after the marker, unless its logout hangs
+    while child.try_wait()?.is_none() {
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    parse_env(&output).ok_or_else(|| anyhow!("{} printed no environment", shell))
+}
+
+/// The output up to the second marker line, or to its end when it has
+/// fewer.
+fn read_to_marker(stdout: impl Read) -> String {
+    let mut reader = BufReader::new(stdout);
+    let mut output = Vec::new();
+    let mut markers = 0;
+    loop {
+        let start = output.len();
+        match reader.read_until(b'\n', &mut output) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if output[start..].strip_suffix(b"\n") == Some(MARKER.as_bytes()) {
+            markers += 1;
+            if markers == 2 {
+                break;
+            }
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// The variables between the markers in the output, with what a profile
+/// prints around them left out. A line without `=` goes on the value
+/// before it, which had a line break.
+fn parse_env(output: &str) -> Option<HashMap<String, String>> {
+    let marker = format!("{}\n", MARKER);
+    let mut sections = output.split(&marker);
+    sections.next()?;
+    let section = sections.next()?;
+    let mut env: Vec<(String, String)> = Vec::new();
+    for line in section.lines() {
+        match line.split_once('=') {
+            Some((key, value)) if is_name(key) => {
+                env.push((key.to_string(), value.to_string()))
+            }
+            _ => {
+                if let Some((_, value)) = env.last_mut() {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+    Some(
+        env.into_iter()
+            .filter(|(key, _)| !SKIPPED.contains(&key.as_str()))
+            .collect(),
+    )
+}
+
+fn is_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env() {
+        let output = format!(
+            "Welcome!\n{0}\nPATH=/home/u/.cargo/bin:/usr/bin\nPWD=/home/u\n\
+             MULTI=a\nb\n{0}\nbye\n",
+            MARKER
+        );
+        let env = parse_env(&output).unwrap();
+        assert_eq!(
+            Some("/home/u/.cargo/bin:/usr/bin"),
+            env.get("PATH").map(|s| s.as_str())
+        );
+        assert_eq!(Some("a\nb"), env.get("MULTI").map(|s| s.as_str()));
+        assert!(!env.contains_key("PWD"));
+        assert_eq!(2, env.len());
+        assert!(parse_env("no markers\n").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stdout_held_open() {
+        // the backgrounded sleep keeps stdout open after the shell exits
+        let timeout = Duration::from_secs(2);
+        let start = Instant::now();
+        let script = format!("sleep 10 & echo {0}; echo A=b; echo {0}", MARKER);
+        let env = run_shell("/bin/sh", &script, timeout).unwrap();
+        assert_eq!(Some("b"), env.get("A").map(|s| s.as_str()));
+        assert!(start.elapsed() < timeout);
+
+        let start = Instant::now();
+        let script = format!("sleep 10 & echo {}; echo A=b", MARKER);
+        assert!(run_shell("/bin/sh", &script, timeout).is_err());
+        assert!(start.elapsed() < timeout + Duration::from_secs(1));
+    }
+}
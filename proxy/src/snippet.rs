@@ -0,0 +1,636 @@
+//! The snippet syntax of LSP completion items: tabstops, placeholders that
+//! may nest, choices and variables. Parsing never fails, the parts that
+//! don't follow the grammar are taken as literal text.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt::{Display, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Placeholders nested deeper are taken as text.
+const MAX_NESTING: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snippet {
+    elements: Vec<SnippetElement>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnippetElement {
+    Text(String),
+    Tabstop(usize),
+    PlaceHolder(usize, Vec<SnippetElement>),
+    /// Inserted as the first of the choices.
+    Choice(usize, Vec<String>),
+    /// The transform is kept for printing the snippet back, but isn't
+    /// applied to the value.
+    Variable {
+        name: String,
+        default: Option<Vec<SnippetElement>>,
+        transform: Option<String>,
+    },
+}
+
+/// What the variables of a snippet are replaced with, as far as the editor
+/// knows them.
+#[derive(Clone, Debug, Default)]
+pub struct SnippetVariables {
+    pub selected_text: String,
+    pub current_line: String,
+    pub current_word: String,
+    pub line_index: usize,
+    pub path: Option<PathBuf>,
+    pub workspace: Option<PathBuf>,
+    pub clipboard: String,
+}
+
+impl SnippetVariables {
+    /// The value of a variable, `None` for the ones that aren't known.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let path = self.path.as_ref();
+        let lossy = |s: Option<&std::ffi::OsStr>| {
+            s.map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        Some(match name {
+            "TM_SELECTED_TEXT" => self.selected_text.clone(),
+            "TM_CURRENT_LINE" => self.current_line.clone(),
+            "TM_CURRENT_WORD" => self.current_word.clone(),
+            "TM_LINE_INDEX" => self.line_index.to_string(),
+            "TM_LINE_NUMBER" => (self.line_index + 1).to_string(),
+            "TM_FILENAME" => lossy(path.and_then(|path| path.file_name())),
+            "TM_FILENAME_BASE" => lossy(path.and_then(|path| path.file_stem())),
+            "TM_DIRECTORY" => lossy(
+                path.and_then(|path| path.parent())
+                    .map(|dir| dir.as_os_str()),
+            ),
+            "TM_FILEPATH" => lossy(path.map(|path| path.as_os_str())),
+            "RELATIVE_FILEPATH" => lossy(path.map(|path| {
+                self.workspace
+                    .as_ref()
+                    .and_then(|workspace| path.strip_prefix(workspace).ok())
+                    .unwrap_or(path)
+                    .as_os_str()
+            })),
+            "CLIPBOARD" => self.clipboard.clone(),
+            "WORKSPACE_NAME" => {
+                lossy(self.workspace.as_ref().and_then(|path| path.file_name()))
+            }
+            "WORKSPACE_FOLDER" => {
+                lossy(self.workspace.as_ref().map(|path| path.as_os_str()))
+            }
+            _ => return None,
+        })
+    }
+}
+
+impl Snippet {
+    pub fn elements(&self) -> &[SnippetElement] {
+        &self.elements
+    }
+
+    /// The text inserted, with the placeholders' defaults and the first of
+    /// the choices.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        elements_text(&self.elements, &mut text);
+        text
+    }
+
+    /// The ranges of the tabstops for the text inserted at `start`, in the
+    /// order they are tabbed through: by number, with the final `$0` last.
+    /// The end of the snippet is the final tabstop if it has none.
+    pub fn tabs(&self, start: usize) -> Vec<(usize, (usize, usize))> {
+        let mut tabs = Vec::new();
+        let end = elements_tabs(&self.elements, start, &mut tabs);
+        tabs.sort_by_key(|(tab, _)| if *tab == 0 { usize::MAX } else { *tab });
+        if !tabs.iter().any(|(tab, _)| *tab == 0) {
+            tabs.push((0, (end, end)));
+        }
+        tabs
+    }
+
+    /// Replaces the variables with their values, or their defaults when the
+    /// values are empty. Unknown variables become placeholders of their
+    /// name, tabbed through after the snippet's own.
+    pub fn resolve_variables(&mut self, variables: &SnippetVariables) {
+        let mut next_tab = max_tab(&self.elements) + 1;
+        let elements = std::mem::take(&mut self.elements);
+        self.elements = resolve_variables(elements, variables, &mut next_tab);
+    }
+}
+
+impl FromStr for Snippet {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            s,
+            pos: 0,
+            depth: 0,
+            failed: HashSet::new(),
+        };
+        let elements = parser.elements(false);
+        Ok(Snippet { elements })
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_elements(f, &self.elements)
+    }
+}
+
+fn elements_text(elements: &[SnippetElement], text: &mut String) {
+    for element in elements {
+        match element {
+            SnippetElement::Text(t) => text.push_str(t),
+            SnippetElement::Tabstop(_) => (),
+            SnippetElement::PlaceHolder(_, elements) => {
+                elements_text(elements, text)
+            }
+            SnippetElement::Choice(_, choices) => {
+                text.push_str(choices.first().map(|c| c.as_str()).unwrap_or(""))
+            }
+            SnippetElement::Variable { default, .. } => {
+                if let Some(default) = default {
+                    elements_text(default, text);
+                }
+            }
+        }
+    }
+}
+
+/// Pushes the tabstops of `elements` starting at `pos` and returns where
+/// they end.
+fn elements_tabs(
+    elements: &[SnippetElement],
+    mut pos: usize,
+    tabs: &mut Vec<(usize, (usize, usize))>,
+) -> usize {
+    for element in elements {
+        match element {
+            SnippetElement::Text(t) => pos += t.len(),
+            SnippetElement::Tabstop(tab) => tabs.push((*tab, (pos, pos))),
+            SnippetElement::PlaceHolder(tab, elements) => {
+                let index = tabs.len();
+                tabs.push((*tab, (pos, pos)));
+                let end = elements_tabs(elements, pos, tabs);
+                tabs[index].1 .1 = end;
+                pos = end;
+            }
+            SnippetElement::Choice(tab, choices) => {
+                let len = choices.first().map(|c| c.len()).unwrap_or(0);
+                tabs.push((*tab, (pos, pos + len)));
+                pos += len;
+            }
+            SnippetElement::Variable { default, .. } => {
+                if let Some(default) = default {
+                    pos = elements_tabs(default, pos, tabs);
+                }
+            }
+        }
+    }
+    pos
+}
+
+fn max_tab(elements: &[SnippetElement]) -> usize {
+    elements
+        .iter()
+        .map(|element| match element {
+            SnippetElement::Text(_) => 0,
+            SnippetElement::Tabstop(tab) | SnippetElement::Choice(tab, _) => *tab,
+            SnippetElement::PlaceHolder(tab, elements) => {
+                (*tab).max(max_tab(elements))
+            }
+            SnippetElement::Variable { default, .. } => {
+                default.as_deref().map(max_tab).unwrap_or(0)
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn resolve_variables(
+    elements: Vec<SnippetElement>,
+    variables: &SnippetVariables,
+    next_tab: &mut usize,
+) -> Vec<SnippetElement> {
+    let mut resolved = Vec::new();
+    for element in elements {
+        match element {
+            SnippetElement::PlaceHolder(tab, elements) => {
+                resolved.push(SnippetElement::PlaceHolder(
+                    tab,
+                    resolve_variables(elements, variables, next_tab),
+                ))
+            }
+            SnippetElement::Variable { name, default, .. } => {
+                match (variables.get(&name), default) {
+                    (Some(value), _) if !value.is_empty() => {
+                        resolved.push(SnippetElement::Text(value))
+                    }
+                    (_, Some(default)) => resolved
+                        .extend(resolve_variables(default, variables, next_tab)),
+                    (Some(_), None) => (),
+                    (None, None) => {
+                        resolved.push(SnippetElement::PlaceHolder(
+                            *next_tab,
+                            vec![SnippetElement::Text(name)],
+                        ));
+                        *next_tab += 1;
+                    }
+                }
+            }
+            element => resolved.push(element),
+        }
+    }
+    resolved
+}
+
+fn write_elements(
+    f: &mut std::fmt::Formatter<'_>,
+    elements: &[SnippetElement],
+) -> std::fmt::Result {
+    for (i, element) in elements.iter().enumerate() {
+        // `$1` followed by `2` would read as `$12`
+        let followed_by = |is_part: fn(char) -> bool| {
+            matches!(
+                elements.get(i + 1),
+                Some(SnippetElement::Text(t)) if t.starts_with(is_part)
+            )
+        };
+        match element {
+            SnippetElement::Text(text) => {
+                for c in text.chars() {
+                    if matches!(c, '$' | '}' | '\\') {
+                        f.write_char('\\')?;
+                    }
+                    f.write_char(c)?;
+                }
+            }
+            SnippetElement::Tabstop(tab) => {
+                if followed_by(|c| c.is_ascii_digit()) {
+                    write!(f, "${{{}}}", tab)?;
+                } else {
+                    write!(f, "${}", tab)?;
+                }
+            }
+            SnippetElement::PlaceHolder(tab, elements) => {
+                write!(f, "${{{}:", tab)?;
+                write_elements(f, elements)?;
+                f.write_char('}')?;
+            }
+            SnippetElement::Choice(tab, choices) => {
+                write!(f, "${{{}|", tab)?;
+                for (i, choice) in choices.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    for c in choice.chars() {
+                        if matches!(c, '$' | '}' | '\\' | ',' | '|') {
+                            f.write_char('\\')?;
+                        }
+                        f.write_char(c)?;
+                    }
+                }
+                f.write_str("|}")?;
+            }
+            SnippetElement::Variable {
+                name,
+                default,
+                transform,
+            } => match (default, transform) {
+                (Some(default), _) => {
+                    write!(f, "${{{}:", name)?;
+                    write_elements(f, default)?;
+                    f.write_char('}')?;
+                }
+                (None, Some(transform)) => write!(f, "${{{}{}}}", name, transform)?,
+                (None, None) => {
+                    if followed_by(|c| c == '_' || c.is_ascii_alphanumeric()) {
+                        write!(f, "${{{}}}", name)?;
+                    } else {
+                        write!(f, "${}", name)?;
+                    }
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    /// Where a `$` was found not to start anything, so nested unclosed
+    /// placeholders aren't parsed over and over again.
+    failed: HashSet<usize>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses up to the end, or up to the `}` closing the placeholder the
+    /// elements are in.
+    fn elements(&mut self, in_placeholder: bool) -> Vec<SnippetElement> {
+        let mut elements = Vec::new();
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '}' if in_placeholder => break,
+                '$' => match self.element() {
+                    Some(element) => {
+                        if !text.is_empty() {
+                            elements.push(SnippetElement::Text(std::mem::take(
+                                &mut text,
+                            )));
+                        }
+                        elements.push(element);
+                    }
+                    None => {
+                        text.push('$');
+                        self.pos += 1;
+                    }
+                },
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c @ ('$' | '}' | '\\')) => {
+                            text.push(c);
+                            self.pos += 1;
+                        }
+                        _ => text.push('\\'),
+                    }
+                }
+                c => {
+                    text.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+        if !text.is_empty() {
+            elements.push(SnippetElement::Text(text));
+        }
+        elements
+    }
+
+    /// The tabstop, placeholder, choice or variable at the `$`, leaving the
+    /// position there if it's none of them.
+    fn element(&mut self) -> Option<SnippetElement> {
+        let start = self.pos;
+        if self.failed.contains(&start) {
+            return None;
+        }
+        let element = self.parse_element();
+        if element.is_none() {
+            self.pos = start;
+            self.failed.insert(start);
+        }
+        element
+    }
+
+    fn parse_element(&mut self) -> Option<SnippetElement> {
+        self.eat('$')?;
+        if let Some(tab) = self.int() {
+            return Some(SnippetElement::Tabstop(tab));
+        }
+        if let Some(name) = self.var() {
+            return Some(SnippetElement::Variable {
+                name,
+                default: None,
+                transform: None,
+            });
+        }
+        self.eat('{')?;
+        if let Some(tab) = self.int() {
+            if self.eat('}').is_some() {
+                return Some(SnippetElement::Tabstop(tab));
+            }
+            if self.eat(':').is_some() {
+                let elements = self.nested_elements()?;
+                self.eat('}')?;
+                return Some(SnippetElement::PlaceHolder(tab, elements));
+            }
+            if self.eat('|').is_some() {
+                return Some(SnippetElement::Choice(tab, self.choices()?));
+            }
+            // transforms of tabstops only matter for mirrors, which we
+            // don't update
+            self.transform()?;
+            self.eat('}')?;
+            return Some(SnippetElement::Tabstop(tab));
+        }
+        let name = self.var()?;
+        if self.eat('}').is_some() {
+            return Some(SnippetElement::Variable {
+                name,
+                default: None,
+                transform: None,
+            });
+        }
+        if self.eat(':').is_some() {
+            let default = self.nested_elements()?;
+            self.eat('}')?;
+            return Some(SnippetElement::Variable {
+                name,
+                default: Some(default),
+                transform: None,
+            });
+        }
+        let transform = self.transform()?;
+        self.eat('}')?;
+        Some(SnippetElement::Variable {
+            name,
+            default: None,
+            transform: Some(transform),
+        })
+    }
+
+    fn nested_elements(&mut self) -> Option<Vec<SnippetElement>> {
+        if self.depth >= MAX_NESTING {
+            return None;
+        }
+        self.depth += 1;
+        let elements = self.elements(true);
+        self.depth -= 1;
+        Some(elements)
+    }
+
+    /// The choices after `${1|`, up to and including the closing `|}`.
+    fn choices(&mut self) -> Option<Vec<String>> {
+        let mut choices = Vec::new();
+        let mut choice = String::new();
+        loop {
+            match self.bump()? {
+                '\\' => match self.peek()? {
+                    c @ ('$' | '}' | '\\' | ',' | '|') => {
+                        choice.push(c);
+                        self.pos += 1;
+                    }
+                    _ => choice.push('\\'),
+                },
+                ',' => choices.push(std::mem::take(&mut choice)),
+                '|' => {
+                    self.eat('}')?;
+                    choices.push(choice);
+                    return Some(choices);
+                }
+                c => choice.push(c),
+            }
+        }
+    }
+
+    /// A `/regex/format/options` transform, as written.
+    fn transform(&mut self) -> Option<String> {
+        let start = self.pos;
+        self.eat('/')?;
+        // the regex, then the format whose `${1:/upcase}` can hold slashes
+        for format in [false, true] {
+            loop {
+                match self.bump()? {
+                    '\\' => {
+                        self.bump()?;
+                    }
+                    '/' => break,
+                    '$' if format && self.eat('{').is_some() => {
+                        while self.bump()? != '}' {}
+                    }
+                    _ => (),
+                }
+            }
+        }
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        Some(self.s[start..self.pos].to_string())
+    }
+
+    fn int(&mut self) -> Option<usize> {
+        let len = self.s[self.pos..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.s.len() - self.pos);
+        let n = self.s[self.pos..self.pos + len].parse().ok()?;
+        self.pos += len;
+        Some(n)
+    }
+
+    fn var(&mut self) -> Option<String> {
+        let rest = &self.s[self.pos..];
+        if !rest.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let len = rest
+            .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        self.pos += len;
+        Some(rest[..len].to_string())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> Option<()> {
+        if self.peek()? != c {
+            return None;
+        }
+        self.pos += c.len_utf8();
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Tabs = Vec<(usize, (usize, usize))>;
+
+    #[test]
+    fn test_snippet() {
+        // the snippet, the text inserted and its tabstops
+        let cases: Vec<(&str, &str, Tabs)> = vec![
+            ("plain", "plain", vec![(0, (5, 5))]),
+            ("foo($1)$0", "foo()", vec![(1, (4, 4)), (0, (5, 5))]),
+            ("${1:foo}", "foo", vec![(1, (0, 3)), (0, (3, 3))]),
+            (
+                "$2 then $1",
+                " then ",
+                vec![(1, (6, 6)), (2, (0, 0)), (0, (6, 6))],
+            ),
+            (
+                "${1:another ${2:placeholder}}",
+                "another placeholder",
+                vec![(1, (0, 19)), (2, (8, 19)), (0, (19, 19))],
+            ),
+            ("${1|one,two,three|}", "one", vec![(1, (0, 3)), (0, (3, 3))]),
+            (
+                "println!(\"${1:msg}\")$0",
+                "println!(\"msg\")",
+                vec![(1, (10, 13)), (0, (15, 15))],
+            ),
+            ("\\$1 \\} \\\\", "$1 } \\", vec![(0, (6, 6))]),
+            ("${1:a\\}b}", "a}b", vec![(1, (0, 3)), (0, (3, 3))]),
+            ("${TM_SELECTED_TEXT:default}", "default", vec![(0, (7, 7))]),
+            ("$TM_FILENAME", "", vec![(0, (0, 0))]),
+            ("${TM_FILENAME/(.*)\\..+$/$1/}", "", vec![(0, (0, 0))]),
+            // malformed parts are literal text
+            ("${1:unclosed", "${1:unclosed", vec![(0, (12, 12))]),
+            ("cost: $ 5 ${}", "cost: $ 5 ${}", vec![(0, (13, 13))]),
+            ("${1|a,b", "${1|a,b", vec![(0, (7, 7))]),
+            ("a }", "a }", vec![(0, (3, 3))]),
+        ];
+        for (s, text, tabs) in cases {
+            let snippet = Snippet::from_str(s).unwrap();
+            assert_eq!(text, snippet.text(), "{}", s);
+            assert_eq!(tabs, snippet.tabs(0), "{}", s);
+            let printed = snippet.to_string();
+            assert_eq!(snippet, Snippet::from_str(&printed).unwrap(), "{}", s);
+        }
+
+        let s = "start $1${2:second ${3:third}} $0";
+        assert_eq!(s, Snippet::from_str(s).unwrap().to_string());
+        let s = "\\$1 ${1:a\\}b} ${2|x\\,y,z|}";
+        assert_eq!(s, Snippet::from_str(s).unwrap().to_string());
+        let s = "${TM_FILENAME/(.*)\\..+$/${1:/upcase}/g}";
+        assert_eq!(s, Snippet::from_str(s).unwrap().to_string());
+
+        let s = "${1:".repeat(1000);
+        assert_eq!(s, Snippet::from_str(&s).unwrap().text());
+    }
+
+    #[test]
+    fn test_resolve_variables() {
+        let variables = SnippetVariables {
+            current_word: "word".to_string(),
+            line_index: 4,
+            path: Some(PathBuf::from("/crate/src/main.rs")),
+            workspace: Some(PathBuf::from("/crate")),
+            ..Default::default()
+        };
+        let cases = vec![
+            ("$TM_CURRENT_WORD!", "word!"),
+            ("${TM_LINE_NUMBER}", "5"),
+            ("$TM_FILENAME_BASE", "main"),
+            ("$RELATIVE_FILEPATH", "src/main.rs"),
+            ("${TM_SELECTED_TEXT:nothing}", "nothing"),
+            ("${1:${TM_SELECTED_TEXT}}", ""),
+            ("${UNKNOWN}", "UNKNOWN"),
+        ];
+        for (s, text) in cases {
+            let mut snippet = Snippet::from_str(s).unwrap();
+            snippet.resolve_variables(&variables);
+            assert_eq!(text, snippet.text(), "{}", s);
+        }
+
+        let mut snippet = Snippet::from_str("${1:a} $UNKNOWN").unwrap();
+        snippet.resolve_variables(&variables);
+        assert_eq!(vec![(1, (0, 1)), (2, (2, 9)), (0, (9, 9))], snippet.tabs(0));
+    }
+}
@@ -0,0 +1,261 @@
+//! The document symbols of each buffer, kept for the revision they're of so
+//! that the palette's outline and the scopes shown above the editor ask the
+//! server once per edit rather than once per scroll, and the scopes a line
+//! is in, from the symbols or from the syntax tree when no server has them.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use lapce_rpc::RequestId;
+use lsp_types::{DocumentSymbol, DocumentSymbolResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tree_sitter::{Node, Point, Tree};
+use xi_rope::Rope;
+
+use crate::buffer::{Buffer, BufferId};
+use crate::lsp::LspServerId;
+
+/// The revision of a buffer and the server that gave its symbols. Another
+/// server, as after a restart, can give other symbols for the same text.
+pub type SymbolKey = (u64, LspServerId);
+
+/// What a request wants of the symbols.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolQuery {
+    DocumentSymbols,
+    /// The scopes the line is in.
+    EnclosingScopes(u32),
+}
+
+impl SymbolQuery {
+    /// The answer from the server's symbols, or without them the scopes of
+    /// the syntax tree.
+    pub fn answer(
+        &self,
+        buffer: &mut Buffer,
+        symbols: Option<&DocumentSymbolResponse>,
+    ) -> Result<Value> {
+        let line = match self {
+            SymbolQuery::DocumentSymbols => {
+                let symbols =
+                    symbols.ok_or_else(|| anyhow!("no document symbols"))?;
+                return Ok(serde_json::to_value(symbols)?);
+            }
+            SymbolQuery::EnclosingScopes(line) => *line,
+        };
+        let scopes = match symbols {
+            Some(symbols) => enclosing_scopes(symbols, line),
+            None => match buffer.syntax_tree().cloned() {
+                Some(tree) => tree_scopes(&tree, &buffer.rope, line),
+                None => Vec::new(),
+            },
+        };
+        let response = EnclosingScopesResponse {
+            buffer_id: buffer.id,
+            rev: buffer.rev,
+            line,
+            scopes,
+        };
+        Ok(serde_json::to_value(response)?)
+    }
+}
+
+/// The symbols a server gave for a buffer, and the queries waiting for the
+/// ones of a newer revision.
+#[derive(Default)]
+pub struct SymbolStore {
+    symbols: Option<(SymbolKey, Arc<DocumentSymbolResponse>)>,
+    waiting: Vec<(SymbolKey, RequestId, SymbolQuery)>,
+}
+
+impl SymbolStore {
+    pub fn get(&self, key: SymbolKey) -> Option<Arc<DocumentSymbolResponse>> {
+        match &self.symbols {
+            Some((symbols_key, symbols)) if *symbols_key == key => {
+                Some(symbols.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Queues a query for the symbols of `key`. True when the server has
+    /// to be asked for them, as no query is waiting for them already.
+    pub fn wait(
+        &mut self,
+        key: SymbolKey,
+        id: RequestId,
+        query: SymbolQuery,
+    ) -> bool {
+        let asked = self.waiting.iter().any(|(waiting, _, _)| *waiting == key);
+        self.waiting.push((key, id, query));
+        !asked
+    }
+
+    /// Keeps the server's answer for `key` unless newer symbols are kept
+    /// already, giving the queries that waited for it. None when the
+    /// server failed to answer.
+    pub fn set(
+        &mut self,
+        key: SymbolKey,
+        symbols: Option<DocumentSymbolResponse>,
+    ) -> Vec<(RequestId, SymbolQuery)> {
+        if let Some(symbols) = symbols {
+            let newer = match &self.symbols {
+                Some(((rev, _), _)) => *rev > key.0,
+                None => false,
+            };
+            if !newer {
+                self.symbols = Some((key, Arc::new(symbols)));
+            }
+        }
+        let mut answered = Vec::new();
+        self.waiting.retain(|(waiting, id, query)| {
+            if *waiting == key {
+                answered.push((*id, *query));
+            }
+            *waiting != key
+        });
+        answered
+    }
+}
+
+/// The scopes a line is in, answering `enclosing_scopes`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnclosingScopesResponse {
+    pub buffer_id: BufferId,
+    pub rev: u64,
+    pub line: u32,
+    /// The outermost first.
+    pub scopes: Vec<EnclosingScope>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnclosingScope {
+    pub name: String,
+    /// The line the scope's name is on.
+    pub line: u32,
+}
+
+/// The symbols whose range holds `line`, the outermost first.
+pub fn enclosing_scopes(
+    symbols: &DocumentSymbolResponse,
+    line: u32,
+) -> Vec<EnclosingScope> {
+    let contains = |range: &lsp_types::Range| {
+        range.start.line <= line && line <= range.end.line
+    };
+    match symbols {
+        DocumentSymbolResponse::Nested(symbols) => {
+            let mut scopes = Vec::new();
+            let mut children: &[DocumentSymbol] = symbols;
+            while let Some(symbol) =
+                children.iter().find(|symbol| contains(&symbol.range))
+            {
+                scopes.push(EnclosingScope {
+                    name: symbol.name.clone(),
+                    line: symbol.selection_range.start.line,
+                });
+                children = symbol.children.as_deref().unwrap_or(&[]);
+            }
+            scopes
+        }
+        DocumentSymbolResponse::Flat(symbols) => {
+            let mut symbols: Vec<_> = symbols
+                .iter()
+                .filter(|symbol| contains(&symbol.location.range))
+                .collect();
+            symbols.sort_by(|a, b| {
+                let (a, b) = (&a.location.range, &b.location.range);
+                a.start.cmp(&b.start).then(b.end.cmp(&a.end))
+            });
+            symbols
+                .into_iter()
+                .map(|symbol| EnclosingScope {
+                    name: symbol.name.clone(),
+                    line: symbol.location.range.start.line,
+                })
+                .collect()
+        }
+    }
+}
+
+/// The named items of the tree that hold `line`, the outermost first: the
+/// nodes of a kind like `function_item` or `class_definition` with a name,
+/// and the impls of Rust named by what's before their body.
+pub fn tree_scopes(tree: &Tree, rope: &Rope, line: u32) -> Vec<EnclosingScope> {
+    let point = Point {
+        row: line as usize,
+        column: 0,
+    };
+    let text = |start: usize, end: usize| {
+        rope.slice_to_cow(start..end)
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    };
+    let mut scopes = Vec::new();
+    let mut node: Option<Node> =
+        tree.root_node().descendant_for_point_range(point, point);
+    while let Some(current) = node {
+        let kind = current.kind();
+        let is_item = kind.ends_with("_item")
+            || kind.ends_with("_definition")
+            || kind.ends_with("_declaration");
+        let name = match (current.child_by_field_name("name"), kind) {
+            (Some(name), _) if is_item => {
+                Some(text(name.start_byte(), name.end_byte()))
+            }
+            (None, "impl_item") => current
+                .child_by_field_name("body")
+                .map(|body| text(current.start_byte(), body.start_byte())),
+            _ => None,
+        };
+        if let Some(name) = name {
+            scopes.push(EnclosingScope {
+                name,
+                line: current.start_position().row as u32,
+            });
+        }
+        node = current.parent();
+    }
+    scopes.reverse();
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+mod a {
+    impl<T> Foo for Bar<T> {
+        fn f() {
+            g();
+        }
+    }
+}
+";
+
+    #[test]
+    fn test_tree_scopes() {
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_rust::language()).unwrap();
+        let tree = parser.parse(TEXT, None).unwrap();
+        let rope = Rope::from(TEXT);
+        let scope = |name: &str, line| EnclosingScope {
+            name: name.to_string(),
+            line,
+        };
+        assert_eq!(
+            vec![
+                scope("a", 0),
+                scope("impl<T> Foo for Bar<T>", 1),
+                scope("f", 2)
+            ],
+            tree_scopes(&tree, &rope, 3)
+        );
+        assert_eq!(vec![scope("a", 0)], tree_scopes(&tree, &rope, 6));
+        assert_eq!(Vec::<EnclosingScope>::new(), tree_scopes(&tree, &rope, 7));
+    }
+}
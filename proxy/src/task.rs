@@ -0,0 +1,350 @@
+//! The tasks of a workspace's `.lapce/tasks.toml`, each run as a process of
+//! its own, in a process group of its own so it's cancelled with whatever
+//! it started. The output is sent on by line as it comes, and the lines
+//! the task's problem matcher matches become diagnostics, replacing the
+//! ones of its last run.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Result};
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+use parking_lot::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::dispatch::Dispatcher;
+use crate::shell_env::resolved_env;
+use crate::terminal::Counter;
+
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TaskId(pub u64);
+
+impl TaskId {
+    pub fn next() -> Self {
+        static TASK_ID_COUNTER: Counter = Counter::new();
+        Self(TASK_ID_COUNTER.next())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TaskConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Relative to the workspace, which it is by default.
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// As in `build` or `test`.
+    pub group: Option<String>,
+    /// A regex with `file`, `line`, `col`, `severity` and `message` groups,
+    /// of which `file`, `line` and `message` are needed.
+    pub problem_matcher: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TasksFile {
+    #[serde(default)]
+    task: Vec<TaskConfig>,
+}
+
+pub fn tasks_path(workspace: &Path) -> PathBuf {
+    workspace.join(".lapce").join("tasks.toml")
+}
+
+/// The tasks of the workspace, none when it has no tasks file.
+pub fn load_tasks(workspace: &Path) -> Result<Vec<TaskConfig>> {
+    let path = tasks_path(workspace);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    parse_tasks(&fs::read_to_string(&path)?)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+fn parse_tasks(contents: &str) -> Result<Vec<TaskConfig>> {
+    Ok(toml::from_str::<TasksFile>(contents)?.task)
+}
+
+/// The lines of the output a problem matcher makes diagnostics of.
+struct ProblemMatcher {
+    regex: Regex,
+    /// What the files are relative to.
+    cwd: PathBuf,
+    source: String,
+}
+
+impl ProblemMatcher {
+    fn new(pattern: &str, cwd: &Path, source: &str) -> Result<Self> {
+        let regex = Regex::new(pattern)?;
+        for group in ["file", "line", "message"] {
+            if !regex.capture_names().any(|name| name == Some(group)) {
+                return Err(anyhow!("the problem matcher has no {} group", group));
+            }
+        }
+        Ok(Self {
+            regex,
+            cwd: cwd.to_path_buf(),
+            source: source.to_string(),
+        })
+    }
+
+    fn problem(&self, line: &str) -> Option<(PathBuf, Diagnostic)> {
+        let captures = self.regex.captures(line.trim_end())?;
+        let path = self.cwd.join(captures.name("file")?.as_str().trim());
+        let number = |group: &str| {
+            captures
+                .name(group)
+                .and_then(|m| m.as_str().parse::<u32>().ok())
+                .map(|n| n.saturating_sub(1))
+        };
+        let position = Position::new(number("line")?, number("col").unwrap_or(0));
+        let severity = captures.name("severity").map(|m| m.as_str().to_lowercase());
+        let severity = match severity.as_deref() {
+            Some("warning") | Some("warn") => DiagnosticSeverity::Warning,
+            Some("info") | Some("information") | Some("note") => {
+                DiagnosticSeverity::Information
+            }
+            Some("hint") => DiagnosticSeverity::Hint,
+            _ => DiagnosticSeverity::Error,
+        };
+        let diagnostic = Diagnostic {
+            range: Range::new(position, position),
+            severity: Some(severity),
+            source: Some(self.source.clone()),
+            message: captures.name("message")?.as_str().to_string(),
+            ..Default::default()
+        };
+        Some((path, diagnostic))
+    }
+}
+
+/// A task that's running, for it to be cancelled.
+pub struct RunningTask {
+    pub name: String,
+    pid: u32,
+}
+
+impl RunningTask {
+    /// Kills the task's process group, the processes it started with it.
+    pub fn cancel(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            if unsafe { libc::killpg(self.pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        #[cfg(windows)]
+        {
+            let status = Command::new("taskkill")
+                .args(["/T", "/F", "/PID", &self.pid.to_string()])
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("taskkill failed"));
+            }
+        }
+        Ok(())
+    }
+}
+
+type Problems = Arc<Mutex<HashMap<PathBuf, Vec<Diagnostic>>>>;
+
+/// Sends the output of a task on by line, keeping the problems in it.
+fn read_output(
+    dispatcher: Dispatcher,
+    task_id: TaskId,
+    stream: &'static str,
+    output: impl Read + Send + 'static,
+    matcher: Arc<Option<ProblemMatcher>>,
+    problems: Problems,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(output);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let text = String::from_utf8_lossy(&line).to_string();
+            if let Some((path, diagnostic)) =
+                matcher.as_ref().as_ref().and_then(|m| m.problem(&text))
+            {
+                problems.lock().entry(path).or_default().push(diagnostic);
+            }
+            dispatcher.send_notification(
+                "task_output",
+                json!({
+                    "task_id": task_id,
+                    "stream": stream,
+                    "text": text,
+                }),
+            );
+        }
+    })
+}
+
+/// Starts a task, sending its output in `task_output` notifications and
+/// its exit code in `task_finished`. The diagnostics of its last run are
+/// cleared, and those of this one published as it finishes.
+pub fn run_task(
+    dispatcher: &Dispatcher,
+    workspace: &Path,
+    task: &TaskConfig,
+) -> Result<TaskId> {
+    let cwd = match task.cwd.as_ref() {
+        Some(cwd) => workspace.join(cwd),
+        None => workspace.to_path_buf(),
+    };
+    let matcher = task
+        .problem_matcher
+        .as_ref()
+        .map(|pattern| ProblemMatcher::new(pattern, &cwd, &task.name))
+        .transpose()?;
+
+    let mut command = Command::new(&task.command);
+    command
+        .args(&task.args)
+        .current_dir(&cwd)
+        .envs(&resolved_env().env)
+        .envs(&task.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // a group of its own, for cancelling to kill what it started
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("can't run {}: {}", task.command, e))?;
+
+    let task_id = TaskId::next();
+    dispatcher
+        .lsp
+        .lock()
+        .publish_task_diagnostics(&task.name, HashMap::new());
+    dispatcher.tasks.lock().insert(
+        task_id,
+        RunningTask {
+            name: task.name.clone(),
+            pid: child.id(),
+        },
+    );
+
+    let problems: Problems = Arc::new(Mutex::new(HashMap::new()));
+    let matcher = Arc::new(matcher);
+    let mut readers = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(read_output(
+            dispatcher.clone(),
+            task_id,
+            "stdout",
+            stdout,
+            matcher.clone(),
+            problems.clone(),
+        ));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(read_output(
+            dispatcher.clone(),
+            task_id,
+            "stderr",
+            stderr,
+            matcher,
+            problems.clone(),
+        ));
+    }
+
+    let local_dispatcher = dispatcher.clone();
+    let name = task.name.clone();
+    thread::spawn(move || {
+        let code = child.wait().ok().and_then(|status| status.code());
+        for reader in readers {
+            let _ = reader.join();
+        }
+        local_dispatcher.tasks.lock().remove(&task_id);
+        let problems = std::mem::take(&mut *problems.lock());
+        local_dispatcher
+            .lsp
+            .lock()
+            .publish_task_diagnostics(&name, problems);
+        local_dispatcher.send_notification(
+            "task_finished",
+            json!({
+                "task_id": task_id,
+                "name": name,
+                "code": code,
+            }),
+        );
+    });
+    Ok(task_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem_matcher() {
+        let tasks = parse_tasks(
+            r#"
+[[task]]
+name = "build"
+command = "cc"
+args = ["-c", "main.c"]
+group = "build"
+problem-matcher = '^(?P<file>[^:]+):(?P<line>\d+):(?P<col>\d+): (?P<severity>\w+): (?P<message>.*)$'
+
+[[task]]
+name = "test"
+command = "make"
+cwd = "tests"
+env = { VERBOSE = "1" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(2, tasks.len());
+        assert_eq!(Some("build"), tasks[0].group.as_deref());
+        assert_eq!(Some(PathBuf::from("tests")), tasks[1].cwd);
+        assert_eq!(Some(&"1".to_string()), tasks[1].env.get("VERBOSE"));
+
+        let matcher = ProblemMatcher::new(
+            tasks[0].problem_matcher.as_ref().unwrap(),
+            Path::new("/work"),
+            "build",
+        )
+        .unwrap();
+        let (path, diagnostic) = matcher
+            .problem("src/main.c:12:5: warning: unused variable 'x'\n")
+            .unwrap();
+        assert_eq!(PathBuf::from("/work/src/main.c"), path);
+        assert_eq!(Position::new(11, 4), diagnostic.range.start);
+        assert_eq!(Some(DiagnosticSeverity::Warning), diagnostic.severity);
+        assert_eq!(Some("build"), diagnostic.source.as_deref());
+        assert_eq!("unused variable 'x'", diagnostic.message);
+        assert!(matcher.problem("compiling main.c").is_none());
+
+        // a matcher needs the groups a diagnostic is made of
+        assert!(ProblemMatcher::new(r"(?P<file>\S+)", Path::new("/"), "").is_err());
+    }
+}
@@ -1,34 +1,52 @@
+//! The shells of the terminal panel, each in a pty of its own, a conpty on
+//! Windows. The output of a shell is read on a thread of its own and sent
+//! on as it comes, while another waits for the shell to exit to drop the
+//! pty and tell the core.
+//!
+//! A shell lives on when the core goes, as the proxy does while it has
+//! shells, with the last of its output kept for a core that attaches to
+//! it again to replay. One no core attaches to for long enough is killed.
+
 use std::{
-    borrow::Cow,
     collections::{HashMap, VecDeque},
-    fs::File,
-    io::{self, ErrorKind, Read, Write},
+    fs,
+    io::{Read, Write},
     path::PathBuf,
-    sync::atomic::{self, AtomicU64},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use alacritty_terminal::{
-    ansi,
-    config::Program,
-    event::OnResize,
-    event_loop::Msg,
-    term::SizeInfo,
-    tty::{self, setup_env, EventedPty, EventedReadWrite},
-};
-use directories::BaseDirs;
-#[cfg(not(windows))]
-use mio::unix::UnixReady;
-use mio::{
-    channel::{channel, Receiver, Sender},
-    Events, PollOpt, Ready,
-};
-use serde::{Deserialize, Deserializer, Serialize};
+use anyhow::Result;
+use crossbeam_channel::bounded;
+use parking_lot::Mutex;
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::dispatch::Dispatcher;
+use crate::settings::setting;
+use crate::shell_env::resolved_env;
 
 const READ_BUFFER_SIZE: usize = 0x10_0000;
 
+/// How often a shell is checked for having exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the output of a shell that exited has to end, as a process it
+/// started can keep the pty open.
+const OUTPUT_END_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DEFAULT_SCROLLBACK: usize = 256 * 1024;
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// The longest title an OSC sequence is taken to set.
+const MAX_OSC_LEN: usize = 4096;
+
 pub struct Counter(AtomicU64);
 
 impl Counter {
@@ -45,279 +63,394 @@ impl Counter {
 pub struct TermId(pub u64);
 
 impl TermId {
+    /// An id no other process has, for the ids of a core not to be those
+    /// of the shells it finds a proxy kept from the last one.
     pub fn next() -> Self {
         static TERMINAL_ID_COUNTER: Counter = Counter::new();
-        Self(TERMINAL_ID_COUNTER.next())
+        Self((std::process::id() as u64) << 32 | TERMINAL_ID_COUNTER.next())
     }
 }
 
-pub type TermConfig = alacritty_terminal::config::Config<HashMap<String, String>>;
+#[derive(Clone, Debug)]
+pub struct TerminalSettings {
+    /// The bytes of output kept of each shell.
+    pub scrollback: usize,
+    /// How long a shell no core is attached to lives, None for as long
+    /// as it runs.
+    pub idle_timeout: Option<Duration>,
+}
 
-pub struct Terminal {
-    term_id: TermId,
-    poll: mio::Poll,
-    pty: alacritty_terminal::tty::Pty,
-    rx: Receiver<Msg>,
-    pub tx: Sender<Msg>,
+impl Default for TerminalSettings {
+    fn default() -> Self {
+        Self {
+            scrollback: DEFAULT_SCROLLBACK,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
 }
 
-impl Terminal {
-    pub fn new(
-        term_id: TermId,
-        cwd: Option<PathBuf>,
-        width: usize,
-        height: usize,
-    ) -> Terminal {
-        let poll = mio::Poll::new().unwrap();
-        let mut config = TermConfig::default();
-        config.working_directory =
-            cwd.or_else(|| BaseDirs::new().map(|d| PathBuf::from(d.home_dir())));
-        config.shell = std::env::var("SHELL").ok().map(|shell| Program::WithArgs {
-            program: shell.to_string(),
-            args: vec!["-l".to_string()],
-        });
-        setup_env(&config);
+impl TerminalSettings {
+    pub fn load() -> Self {
+        let mut settings = Self::default();
+        let terminal = match setting(&["terminal"]) {
+            Some(terminal) => terminal,
+            None => return settings,
+        };
+        if let Some(kb) = terminal.get("scrollback-kb").and_then(|v| v.as_integer())
+        {
+            settings.scrollback = kb.max(0) as usize * 1024;
+        }
+        if let Some(minutes) = terminal
+            .get("idle-timeout-minutes")
+            .and_then(|v| v.as_integer())
+        {
+            // 0 keeps them for good
+            settings.idle_timeout = Some(minutes)
+                .filter(|minutes| *minutes > 0)
+                .map(|minutes| Duration::from_secs(minutes as u64 * 60));
+        }
+        settings
+    }
+}
 
-        #[cfg(target_os = "macos")]
-        set_locale_environment();
+/// A live shell, for the core to make tabs again for the ones it finds.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalInfo {
+    pub term_id: TermId,
+    /// The last title the shell set.
+    pub title: Option<String>,
+    pub cwd: PathBuf,
+    pub attached: bool,
+}
 
-        let size =
-            SizeInfo::new(width as f32, height as f32, 1.0, 1.0, 0.0, 0.0, true);
-        let mut pty = alacritty_terminal::tty::new(&config, &size, None);
+#[derive(Clone, Copy)]
+enum TitleState {
+    Text,
+    Escape,
+    Osc,
+    OscEscape,
+}
 
-        let (tx, rx) = channel();
+/// Follows the title the output sets with OSC 0 and 2 sequences.
+struct TitleParser {
+    state: TitleState,
+    osc: Vec<u8>,
+    title: Option<String>,
+}
 
-        Terminal {
-            term_id,
-            poll,
-            pty,
-            tx,
-            rx,
+impl TitleParser {
+    fn new() -> Self {
+        Self {
+            state: TitleState::Text,
+            osc: Vec::new(),
+            title: None,
         }
     }
 
-    pub fn run(&mut self, dispatcher: Dispatcher) {
-        let mut tokens = (0..).map(Into::into);
-        let poll_opts = PollOpt::edge() | PollOpt::oneshot();
-
-        let channel_token = tokens.next().unwrap();
-        self.poll
-            .register(&self.rx, channel_token, Ready::readable(), poll_opts)
-            .unwrap();
-
-        self.pty
-            .register(&self.poll, &mut tokens, Ready::readable(), poll_opts)
-            .unwrap();
-
-        let mut buf = [0u8; READ_BUFFER_SIZE];
-        let mut events = Events::with_capacity(1024);
-        let mut state = State::default();
-
-        'event_loop: loop {
-            self.poll.poll(&mut events, None);
-            for event in events.iter() {
-                match event.token() {
-                    token if token == channel_token => {
-                        if !self.channel_event(channel_token, &mut state) {
-                            break 'event_loop;
-                        }
-                    }
-
-                    token if token == self.pty.child_event_token() => {
-                        if let Some(tty::ChildEvent::Exited) =
-                            self.pty.next_child_event()
-                        {
-                            dispatcher.send_notification(
-                                "close_terminal",
-                                json!({
-                                    "term_id": self.term_id,
-                                }),
-                            );
-                            break 'event_loop;
-                        }
-                    }
-                    token
-                        if token == self.pty.read_token()
-                            || token == self.pty.write_token() =>
-                    {
-                        #[cfg(unix)]
-                        if UnixReady::from(event.readiness()).is_hup() {
-                            // Don't try to do I/O on a dead PTY.
-                            continue;
-                        }
-
-                        if event.readiness().is_readable() {
-                            match self.pty.reader().read(&mut buf) {
-                                Ok(n) => {
-                                    dispatcher.send_notification(
-                                        "update_terminal",
-                                        json!({
-                                            "term_id": self.term_id,
-                                            "content": base64::encode(&buf[..n]),
-                                        }),
-                                    );
-                                }
-                                Err(e) => (),
-                            }
-                        }
-
-                        if event.readiness().is_writable() {
-                            if let Err(err) = self.pty_write(&mut state) {}
-                        }
+    fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state = match (self.state, b) {
+                (TitleState::Escape, b']') => {
+                    self.osc.clear();
+                    TitleState::Osc
+                }
+                (TitleState::Osc, 0x07) | (TitleState::OscEscape, b'\\') => {
+                    self.osc_end();
+                    TitleState::Text
+                }
+                (TitleState::Osc, 0x1b) => TitleState::OscEscape,
+                (TitleState::Osc, b) => {
+                    if self.osc.len() < MAX_OSC_LEN {
+                        self.osc.push(b);
                     }
-
-                    _ => (),
+                    TitleState::Osc
                 }
-            }
-            // Register write interest if necessary.
-            let mut interest = Ready::readable();
-            if state.needs_write() {
-                interest.insert(Ready::writable());
-            }
-            // Reregister with new interest.
-            self.pty
-                .reregister(&self.poll, interest, poll_opts)
-                .unwrap();
+                (_, 0x1b) => TitleState::Escape,
+                _ => TitleState::Text,
+            };
         }
     }
 
-    /// Drain the channel.
-    ///
-    /// Returns `false` when a shutdown message was received.
-    fn drain_recv_channel(&mut self, state: &mut State) -> bool {
-        while let Ok(msg) = self.rx.try_recv() {
-            match msg {
-                Msg::Input(input) => state.write_list.push_back(input),
-                Msg::Shutdown => return false,
-                Msg::Resize(size) => self.pty.on_resize(&size),
-            }
+    fn osc_end(&mut self) {
+        let osc = String::from_utf8_lossy(&self.osc);
+        if let Some(title) =
+            osc.strip_prefix("0;").or_else(|| osc.strip_prefix("2;"))
+        {
+            self.title = Some(title.to_string());
         }
-
-        true
     }
+}
 
-    #[inline]
-    fn channel_event(&mut self, token: mio::Token, state: &mut State) -> bool {
-        if !self.drain_recv_channel(state) {
-            return false;
-        }
-
-        self.poll
-            .reregister(
-                &self.rx,
-                token,
-                Ready::readable(),
-                PollOpt::edge() | PollOpt::oneshot(),
-            )
-            .unwrap();
+/// What's kept of a shell for a core attaching to it again.
+struct Session {
+    scrollback: VecDeque<u8>,
+    limit: usize,
+    title: TitleParser,
+    /// Whether the output is sent on to the core.
+    attached: bool,
+    /// When the core it was attached to went.
+    detached_at: Option<Instant>,
+}
 
-        true
+impl Session {
+    fn new(limit: usize) -> Self {
+        Self {
+            scrollback: VecDeque::new(),
+            limit,
+            title: TitleParser::new(),
+            attached: true,
+            detached_at: None,
+        }
     }
 
-    #[inline]
-    fn pty_write(&mut self, state: &mut State) -> io::Result<()> {
-        state.ensure_next();
-
-        'write_many: while let Some(mut current) = state.take_current() {
-            'write_one: loop {
-                match self.pty.writer().write(current.remaining_bytes()) {
-                    Ok(0) => {
-                        state.set_current(Some(current));
-                        break 'write_many;
-                    }
-                    Ok(n) => {
-                        current.advance(n);
-                        if current.finished() {
-                            state.goto_next();
-                            break 'write_one;
-                        }
-                    }
-                    Err(err) => {
-                        state.set_current(Some(current));
-                        match err.kind() {
-                            ErrorKind::Interrupted | ErrorKind::WouldBlock => {
-                                break 'write_many
-                            }
-                            _ => return Err(err),
-                        }
-                    }
-                }
+    fn output(&mut self, bytes: &[u8]) {
+        self.title.feed(bytes);
+        self.scrollback.extend(bytes);
+        let excess = self.scrollback.len().saturating_sub(self.limit);
+        if excess > 0 {
+            self.scrollback.drain(..excess);
+            // the replay starts at a line, not halfway through one
+            match self.scrollback.iter().position(|b| *b == b'\n') {
+                Some(i) => drop(self.scrollback.drain(..i + 1)),
+                None => self.scrollback.clear(),
             }
         }
-
-        Ok(())
     }
 }
 
-struct Writing {
-    source: Cow<'static, [u8]>,
-    written: usize,
+type ShellProcess = Arc<Mutex<Box<dyn Child + Send + Sync>>>;
+
+pub struct Terminal {
+    master: Box<dyn MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    child: ShellProcess,
+    /// The directory the shell started in.
+    cwd: PathBuf,
+    session: Arc<Mutex<Session>>,
 }
 
-impl Writing {
-    #[inline]
-    fn new(c: Cow<'static, [u8]>) -> Writing {
-        Writing {
-            source: c,
-            written: 0,
+impl Terminal {
+    /// Starts `shell`, or the user's login shell, in `cwd` with `env` on top
+    /// of the proxy's environment, keeping `scrollback` bytes of output.
+    /// The output is read from what's returned with it, until the shell
+    /// exits.
+    pub fn new(
+        cwd: PathBuf,
+        shell: Option<&str>,
+        env: &HashMap<String, String>,
+        cols: usize,
+        rows: usize,
+        scrollback: usize,
+    ) -> Result<(Terminal, Box<dyn Read + Send>)> {
+        let pair = native_pty_system().openpty(pty_size(cols, rows))?;
+        let mut cmd = match shell {
+            Some(shell) => CommandBuilder::new(shell),
+            None => login_shell(),
+        };
+        cmd.cwd(&cwd);
+        for (key, value) in resolved_env().env.iter() {
+            cmd.env(key, value);
+        }
+        cmd.env("TERM", "xterm-256color");
+        cmd.env("COLORTERM", "truecolor");
+        #[cfg(target_os = "macos")]
+        cmd.env("LC_ALL", locale());
+        for (key, value) in env {
+            cmd.env(key, value);
         }
+        let child = pair.slave.spawn_command(cmd)?;
+        // with the slave left to the shell the output ends when it exits
+        drop(pair.slave);
+        let reader = pair.master.try_clone_reader()?;
+        let writer = pair.master.try_clone_writer()?;
+        let terminal = Terminal {
+            master: pair.master,
+            writer,
+            child: Arc::new(Mutex::new(child)),
+            cwd,
+            session: Arc::new(Mutex::new(Session::new(scrollback))),
+        };
+        Ok((terminal, reader))
     }
 
-    #[inline]
-    fn advance(&mut self, n: usize) {
-        self.written += n;
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()?;
+        Ok(())
     }
 
-    #[inline]
-    fn remaining_bytes(&self) -> &[u8] {
-        &self.source[self.written..]
+    pub fn resize(&self, cols: usize, rows: usize) -> Result<()> {
+        self.master.resize(pty_size(cols, rows))
     }
 
-    #[inline]
-    fn finished(&self) -> bool {
-        self.written >= self.source.len()
+    /// Kills the shell, which is then told as exited like any other.
+    pub fn kill(&self) {
+        if let Err(e) = self.child.lock().kill() {
+            eprintln!("killing the shell failed: {}", e);
+        }
     }
-}
 
-#[derive(Default)]
-pub struct State {
-    write_list: VecDeque<Cow<'static, [u8]>>,
-    writing: Option<Writing>,
-    parser: ansi::Processor,
-}
+    /// Sends the output kept to `f`, then the output as it comes to the
+    /// core again.
+    pub fn attach(&self, f: impl FnOnce(&[u8])) {
+        let mut session = self.session.lock();
+        session.attached = true;
+        session.detached_at = None;
+        f(&session.scrollback.iter().copied().collect::<Vec<_>>());
+    }
 
-impl State {
-    #[inline]
-    fn ensure_next(&mut self) {
-        if self.writing.is_none() {
-            self.goto_next();
+    /// For when the core is gone.
+    pub fn detach(&self) {
+        let mut session = self.session.lock();
+        if session.attached {
+            session.attached = false;
+            session.detached_at = Some(Instant::now());
         }
     }
 
-    #[inline]
-    fn goto_next(&mut self) {
-        self.writing = self.write_list.pop_front().map(Writing::new);
+    /// How long it's been since a core was attached to it.
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.session.lock().detached_at.map(|at| at.elapsed())
     }
 
-    #[inline]
-    fn take_current(&mut self) -> Option<Writing> {
-        self.writing.take()
+    pub fn info(&self, term_id: TermId) -> TerminalInfo {
+        let session = self.session.lock();
+        TerminalInfo {
+            term_id,
+            title: session.title.title.clone(),
+            cwd: self.current_dir(),
+            attached: session.attached,
+        }
+    }
+
+    /// Where the shell is now, where that can be told.
+    fn current_dir(&self) -> PathBuf {
+        #[cfg(target_os = "linux")]
+        if let Some(pid) = self.child.lock().process_id() {
+            if let Ok(cwd) = fs::read_link(format!("/proc/{}/cwd", pid)) {
+                return cwd;
+            }
+        }
+        self.cwd.clone()
+    }
+
+    /// Sends the output of the shell on as `terminal_output` notifications
+    /// while a core is attached to it, keeping the last of it, until the
+    /// shell exits. It then drops the terminal and sends `terminal_exited`
+    /// with whether the shell succeeded, as a pty tells no exit code.
+    pub fn run(
+        &self,
+        dispatcher: Dispatcher,
+        term_id: TermId,
+        mut reader: Box<dyn Read + Send>,
+    ) {
+        let child = self.child.clone();
+        let session = self.session.clone();
+        thread::spawn(move || {
+            let (done_tx, done_rx) = bounded(1);
+            let output_dispatcher = dispatcher.clone();
+            thread::spawn(move || {
+                let mut buf = vec![0u8; READ_BUFFER_SIZE];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            let mut session = session.lock();
+                            session.output(&buf[..n]);
+                            if session.attached {
+                                output_dispatcher.send_notification(
+                                    "terminal_output",
+                                    json!({
+                                        "term_id": term_id,
+                                        "data": base64::encode(&buf[..n]),
+                                    }),
+                                );
+                            }
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+                let _ = done_tx.send(());
+            });
+
+            let success = loop {
+                match child.lock().try_wait() {
+                    Ok(Some(status)) => break Some(status.success()),
+                    Ok(None) => {}
+                    Err(_) => break None,
+                }
+                thread::sleep(EXIT_POLL_INTERVAL);
+            };
+            // a conpty's output only ends once it's dropped
+            dispatcher.terminals.lock().remove(&term_id);
+            let _ = done_rx.recv_timeout(OUTPUT_END_TIMEOUT);
+            dispatcher.send_notification(
+                "terminal_exited",
+                json!({
+                    "term_id": term_id,
+                    "success": success,
+                }),
+            );
+        });
     }
+}
 
-    #[inline]
-    fn needs_write(&self) -> bool {
-        self.writing.is_some() || !self.write_list.is_empty()
+fn pty_size(cols: usize, rows: usize) -> PtySize {
+    PtySize {
+        rows: rows.max(1) as u16,
+        cols: cols.max(1) as u16,
+        pixel_width: 0,
+        pixel_height: 0,
     }
+}
 
-    #[inline]
-    fn set_current(&mut self, new: Option<Writing>) {
-        self.writing = new;
+/// The user's shell, `$SHELL` or the one of their account run as a login
+/// shell, and on Windows the default shell in the registry or `COMSPEC`.
+fn login_shell() -> CommandBuilder {
+    #[cfg(windows)]
+    if let Some(shell) = registry_shell() {
+        return CommandBuilder::new(shell);
     }
+    CommandBuilder::new_default_prog()
 }
 
-fn set_locale_environment() {
+/// The shell set for the OpenSSH sessions of the machine, the one default
+/// shell Windows keeps.
+#[cfg(windows)]
+fn registry_shell() -> Option<String> {
+    use winreg::{enums::HKEY_LOCAL_MACHINE, RegKey};
+
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey("SOFTWARE\\OpenSSH")
+        .ok()?
+        .get_value("DefaultShell")
+        .ok()
+}
+
+#[cfg(target_os = "macos")]
+fn locale() -> String {
     let locale = locale_config::Locale::global_default()
         .to_string()
         .replace("-", "_");
-    std::env::set_var("LC_ALL", locale + ".UTF-8");
+    locale + ".UTF-8"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_output() {
+        let mut session = Session::new(16);
+        session.output(b"\x1b]0;first\x07$ ls\r\n");
+        assert_eq!(Some("first"), session.title.title.as_deref());
+        // a title split across reads, ended with ST
+        session.output(b"a\r\nb\r\n\x1b]2;sec");
+        session.output(b"ond\x1b\\$ ");
+        assert_eq!(Some("second"), session.title.title.as_deref());
+        assert!(session.scrollback.len() <= 16);
+        let scrollback: Vec<u8> = session.scrollback.iter().copied().collect();
+        assert_eq!(b"\x1b]2;second\x1b\\$ ", &scrollback[..]);
+    }
 }
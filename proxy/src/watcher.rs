@@ -0,0 +1,477 @@
+//! The one watcher of the proxy. The raw events of the system are gathered
+//! until a burst of them is over, as a build writes thousands of files, and
+//! each path changed in the burst is sent once, with a rename paired into
+//! one event and a change of only the mtime or the permissions told apart
+//! from one of the contents. The paths matched by the `[watcher] exclude`
+//! globs are dropped before anything hears of them.
+//!
+//! Each part of the proxy watches the paths it cares about with its own
+//! token, and all of them get the events through `subscribe`, each event
+//! with the tokens of the watches it falls in.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Sender};
+use globset::GlobSet;
+use notify::{
+    raw_watcher, Op, RawEvent, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use parking_lot::Mutex;
+
+use crate::search::glob_set;
+use crate::settings::setting;
+
+/// How long the watcher waits after an event for more of its burst,
+/// unless the user's `[watcher] debounce-ms` says otherwise.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+/// A burst that goes on for longer than this many windows is sent anyway,
+/// so a long build doesn't hold back every event until it's over.
+const MAX_BURST_WINDOWS: u32 = 10;
+/// Left out of the events unless the user's `[watcher] exclude` says
+/// otherwise.
+const DEFAULT_WATCHER_EXCLUDE: &[&str] = &[
+    "**/.git/objects/**",
+    "**/.git/logs/**",
+    "**/node_modules/**",
+    "**/target/**",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchToken(pub usize);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    Created(PathBuf),
+    /// The contents changed.
+    Changed(PathBuf),
+    /// Only the metadata changed, like the mtime on a touch.
+    Metadata(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+impl WatchEvent {
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            WatchEvent::Created(path)
+            | WatchEvent::Changed(path)
+            | WatchEvent::Metadata(path)
+            | WatchEvent::Removed(path) => vec![path.as_path()],
+            WatchEvent::Renamed(old, new) => vec![old.as_path(), new.as_path()],
+        }
+    }
+}
+
+/// The events of a burst, each with the token of a watch it falls in, an
+/// event falling in several watches coming once for each.
+pub type WatchEvents = Arc<Vec<(WatchToken, WatchEvent)>>;
+
+#[derive(Clone, Debug)]
+pub struct WatcherSettings {
+    pub debounce: Duration,
+    pub exclude: Vec<String>,
+}
+
+impl Default for WatcherSettings {
+    fn default() -> Self {
+        Self {
+            debounce: DEFAULT_DEBOUNCE,
+            exclude: DEFAULT_WATCHER_EXCLUDE
+                .iter()
+                .map(|glob| glob.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl WatcherSettings {
+    pub fn load() -> Self {
+        let mut settings = Self::default();
+        let watcher = match setting(&["watcher"]) {
+            Some(watcher) => watcher,
+            None => return settings,
+        };
+        if let Some(millis) = watcher.get("debounce-ms").and_then(|v| v.as_integer())
+        {
+            settings.debounce = Duration::from_millis(millis.max(0) as u64);
+        }
+        if let Some(exclude) = watcher
+            .get("exclude")
+            .and_then(|exclude| exclude.clone().try_into().ok())
+        {
+            settings.exclude = exclude;
+        }
+        settings
+    }
+}
+
+struct Watch {
+    path: PathBuf,
+    recursive: bool,
+    token: WatchToken,
+}
+
+impl Watch {
+    fn contains(&self, path: &Path) -> bool {
+        match self.recursive {
+            true => path.starts_with(&self.path),
+            false => path == self.path || path.parent() == Some(self.path.as_path()),
+        }
+    }
+}
+
+pub struct FileWatcher {
+    /// None when the system's watcher couldn't be started, in which case
+    /// nothing is ever heard of.
+    watcher: Option<RecommendedWatcher>,
+    watches: Arc<Mutex<Vec<Watch>>>,
+    /// The paths given to the system's watcher, and whether recursively.
+    watching: HashMap<PathBuf, bool>,
+    subscribers: Arc<Mutex<Vec<Sender<WatchEvents>>>>,
+    /// Settings given since, taken by the debouncer at its next event.
+    pending: Arc<Mutex<Option<WatcherSettings>>>,
+}
+
+impl FileWatcher {
+    pub fn new(settings: WatcherSettings) -> FileWatcher {
+        let (sender, receiver) = channel();
+        let watcher = match raw_watcher(sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("can't start the file watcher: {}", e);
+                None
+            }
+        };
+        let watches = Arc::new(Mutex::new(Vec::new()));
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let local_watches = watches.clone();
+        let local_subscribers = subscribers.clone();
+        let pending = Arc::new(Mutex::new(None));
+        let local_pending = pending.clone();
+        thread::spawn(move || {
+            debounce(
+                receiver,
+                settings,
+                local_pending,
+                local_watches,
+                local_subscribers,
+            );
+        });
+        FileWatcher {
+            watcher,
+            watches,
+            watching: HashMap::new(),
+            subscribers,
+            pending,
+        }
+    }
+
+    /// Debounces and excludes by `settings` from the next event on.
+    pub fn set_settings(&self, settings: WatcherSettings) {
+        *self.pending.lock() = Some(settings);
+    }
+
+    /// The events of every watch from now on.
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<WatchEvents> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    pub fn watch(&mut self, path: &Path, recursive: bool, token: WatchToken) {
+        self.watches.lock().push(Watch {
+            path: path.to_path_buf(),
+            recursive,
+            token,
+        });
+        self.update(path);
+    }
+
+    pub fn unwatch(&mut self, path: &Path, token: WatchToken) {
+        self.watches
+            .lock()
+            .retain(|watch| watch.path != path || watch.token != token);
+        self.update(path);
+    }
+
+    /// Watches `path` with the system's watcher as the watches of it need,
+    /// a path watched by several tokens being given to it once.
+    fn update(&mut self, path: &Path) {
+        let watcher = match self.watcher.as_mut() {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        let wanted = {
+            let watches = self.watches.lock();
+            let mut of_path = watches.iter().filter(|watch| watch.path == path);
+            match of_path.next() {
+                Some(first) => {
+                    Some(first.recursive || of_path.any(|watch| watch.recursive))
+                }
+                None => None,
+            }
+        };
+        if self.watching.get(path).copied() == wanted {
+            return;
+        }
+        if self.watching.remove(path).is_some() {
+            let _ = watcher.unwatch(path);
+        }
+        if let Some(recursive) = wanted {
+            let mode = match recursive {
+                true => RecursiveMode::Recursive,
+                false => RecursiveMode::NonRecursive,
+            };
+            match watcher.watch(path, mode) {
+                Ok(()) => {
+                    self.watching.insert(path.to_path_buf(), recursive);
+                }
+                Err(e) => eprintln!("can't watch {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+fn debounce(
+    receiver: Receiver<RawEvent>,
+    settings: WatcherSettings,
+    pending: Arc<Mutex<Option<WatcherSettings>>>,
+    watches: Arc<Mutex<Vec<Watch>>>,
+    subscribers: Arc<Mutex<Vec<Sender<WatchEvents>>>>,
+) {
+    let mut exclude = glob_set(&settings.exclude);
+    let mut window = settings.debounce;
+    let mut debouncer = Debouncer::default();
+    let push = |debouncer: &mut Debouncer, exclude: &GlobSet, event: RawEvent| {
+        let path = match event.path {
+            Some(path) if !exclude.is_match(&path) => path,
+            _ => return,
+        };
+        if let Ok(op) = event.op {
+            debouncer.push(path, op, event.cookie);
+        }
+    };
+    loop {
+        let event = match receiver.recv() {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        if let Some(settings) = pending.lock().take() {
+            exclude = glob_set(&settings.exclude);
+            window = settings.debounce;
+        }
+        push(&mut debouncer, &exclude, event);
+        let started = Instant::now();
+        let mut disconnected = false;
+        while started.elapsed() < window * MAX_BURST_WINDOWS {
+            match receiver.recv_timeout(window) {
+                Ok(event) => push(&mut debouncer, &exclude, event),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        let events = debouncer.flush();
+        let batch: Vec<(WatchToken, WatchEvent)> = {
+            let watches = watches.lock();
+            events
+                .into_iter()
+                .flat_map(|event| {
+                    let mut tokens: Vec<WatchToken> = watches
+                        .iter()
+                        .filter(|watch| {
+                            event.paths().iter().any(|path| watch.contains(path))
+                        })
+                        .map(|watch| watch.token)
+                        .collect();
+                    tokens.sort();
+                    tokens.dedup();
+                    tokens.into_iter().map(move |token| (token, event.clone()))
+                })
+                .collect()
+        };
+        if !batch.is_empty() {
+            let batch = Arc::new(batch);
+            subscribers
+                .lock()
+                .retain(|subscriber| subscriber.send(batch.clone()).is_ok());
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Change {
+    Created,
+    Changed,
+    Metadata,
+    Removed,
+}
+
+/// What a path went through in a burst, from what it went through before
+/// and what happened to it next. None when it's as if nothing happened,
+/// as for a file created and removed again.
+fn coalesce(before: Change, next: Change) -> Option<Change> {
+    match (before, next) {
+        (Change::Created, Change::Removed) => None,
+        (Change::Created, _) => Some(Change::Created),
+        // replaced, as by an editor that saves to a new file
+        (Change::Removed, Change::Created) => Some(Change::Changed),
+        (Change::Changed, Change::Metadata) => Some(Change::Changed),
+        (_, next) => Some(next),
+    }
+}
+
+/// Gathers the raw events of a burst into one event for each path.
+#[derive(Default)]
+struct Debouncer {
+    /// The paths in the order they first changed.
+    order: Vec<PathBuf>,
+    changes: HashMap<PathBuf, Change>,
+    renames: Vec<(PathBuf, PathBuf)>,
+    /// The paths renamed away from, waiting for the path they were renamed
+    /// to, with the cookie that pairs them when the system gives one.
+    renamed_from: Vec<(Option<u32>, PathBuf)>,
+}
+
+impl Debouncer {
+    fn push(&mut self, path: PathBuf, op: Op, cookie: Option<u32>) {
+        if op.contains(Op::RESCAN) {
+            eprintln!("the file watcher missed events, rescan {:?}", path);
+            return;
+        }
+        if op.contains(Op::RENAME) {
+            self.rename(path, cookie);
+            return;
+        }
+        // a system like macOS gives every flag the path had lately, so
+        // where it is now tells which came last
+        let exists = path.exists();
+        let change = if op.contains(Op::REMOVE) && !exists {
+            Change::Removed
+        } else if op.contains(Op::CREATE) {
+            Change::Created
+        } else if op.intersects(Op::WRITE | Op::CLOSE_WRITE) {
+            Change::Changed
+        } else if op.contains(Op::CHMOD) {
+            Change::Metadata
+        } else {
+            return;
+        };
+        self.change(path, change);
+    }
+
+    fn change(&mut self, path: PathBuf, change: Change) {
+        let coalesced = match self.changes.get(&path) {
+            Some(before) => coalesce(*before, change),
+            None => {
+                self.order.push(path.clone());
+                Some(change)
+            }
+        };
+        match coalesced {
+            Some(change) => {
+                self.changes.insert(path, change);
+            }
+            None => {
+                self.changes.remove(&path);
+            }
+        }
+    }
+
+    /// Pairs the two halves of a rename, by their cookie or, without one,
+    /// by whether the path is still there.
+    fn rename(&mut self, path: PathBuf, cookie: Option<u32>) {
+        let from = match cookie {
+            Some(_) => self.renamed_from.iter().position(|(c, _)| *c == cookie),
+            None if path.exists() => {
+                self.renamed_from.iter().position(|(c, _)| c.is_none())
+            }
+            None => None,
+        };
+        let from = match from {
+            Some(i) => self.renamed_from.remove(i).1,
+            None => {
+                match cookie.is_some() || !path.exists() {
+                    true => self.renamed_from.push((cookie, path)),
+                    false => self.change(path, Change::Created),
+                }
+                return;
+            }
+        };
+        // a file created in the burst is only created where it ended up
+        match self.changes.remove(&from) {
+            Some(Change::Created) => self.change(path, Change::Created),
+            Some(Change::Changed) => {
+                self.renames.push((from, path.clone()));
+                self.change(path, Change::Changed);
+            }
+            _ => self.renames.push((from, path)),
+        }
+    }
+
+    /// The events of the burst, the renames first, leaving the debouncer
+    /// empty for the next one.
+    fn flush(&mut self) -> Vec<WatchEvent> {
+        for (_, path) in std::mem::take(&mut self.renamed_from) {
+            self.change(path, Change::Removed);
+        }
+        let mut events: Vec<WatchEvent> = self
+            .renames
+            .drain(..)
+            .map(|(old, new)| WatchEvent::Renamed(old, new))
+            .collect();
+        let mut changes = std::mem::take(&mut self.changes);
+        for path in self.order.drain(..) {
+            let event = match changes.remove(&path) {
+                Some(Change::Created) => WatchEvent::Created(path),
+                Some(Change::Changed) => WatchEvent::Changed(path),
+                Some(Change::Metadata) => WatchEvent::Metadata(path),
+                Some(Change::Removed) => WatchEvent::Removed(path),
+                None => continue,
+            };
+            events.push(event);
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debouncer() {
+        let path = |name: &str| PathBuf::from("/lapce-test-watcher").join(name);
+        let mut debouncer = Debouncer::default();
+        debouncer.push(path("a.rs"), Op::WRITE, None);
+        debouncer.push(path("a.rs"), Op::CHMOD, None);
+        debouncer.push(path("a.rs"), Op::WRITE, None);
+        debouncer.push(path("touched.rs"), Op::CHMOD, None);
+        debouncer.push(path("tmp.rs"), Op::CREATE, None);
+        debouncer.push(path("tmp.rs"), Op::REMOVE, None);
+        debouncer.push(path("old.rs"), Op::RENAME, Some(7));
+        debouncer.push(path("gone.rs"), Op::RENAME, Some(8));
+        debouncer.push(path("new.rs"), Op::RENAME, Some(7));
+        assert_eq!(
+            vec![
+                WatchEvent::Renamed(path("old.rs"), path("new.rs")),
+                WatchEvent::Changed(path("a.rs")),
+                WatchEvent::Metadata(path("touched.rs")),
+                WatchEvent::Removed(path("gone.rs")),
+            ],
+            debouncer.flush()
+        );
+        assert!(debouncer.flush().is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 mod parse;
 mod stdio;
+pub mod transport;
 
 use crossbeam_channel::{Receiver, Sender};
 use jsonrpc_lite::JsonRpc;
@@ -7,8 +8,19 @@ pub use parse::Call;
 pub use parse::RequestId;
 pub use parse::RpcObject;
 use serde_json::Value;
-use stdio::IoThreads;
+pub use stdio::IoThreads;
 
 pub fn stdio() -> (Sender<Value>, Receiver<Value>, IoThreads) {
     stdio::stdio_transport()
 }
+
+pub fn stream<R, W>(
+    reader: R,
+    writer: W,
+) -> (Sender<Value>, Receiver<Value>, IoThreads)
+where
+    R: std::io::Read + Send + 'static,
+    W: std::io::Write + Send + 'static,
+{
+    stdio::stream_transport(reader, writer)
+}
@@ -25,6 +25,20 @@ impl RpcObject {
         self.0.get("id").is_some() && self.0.get("method").is_none()
     }
 
+    /// The result of a response, or its error.
+    pub fn into_response(self) -> Result<Value> {
+        match self.0.get("error") {
+            Some(error) => Err(anyhow!(
+                "{}",
+                error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("error")
+            )),
+            None => Ok(self.0.get("result").cloned().unwrap_or(Value::Null)),
+        }
+    }
+
     pub fn into_rpc<N, R>(self) -> Result<Call<N, R>>
     where
         N: DeserializeOwned,
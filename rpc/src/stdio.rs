@@ -2,63 +2,100 @@ use crossbeam_channel::{bounded, Receiver, Sender};
 use jsonrpc_lite::JsonRpc;
 use serde_json::Value;
 use std::{
-    io::{self, stdin, stdout, BufRead, Read, Write},
+    io::{self, stdin, stdout, Read, Write},
     thread,
 };
 
 use crate::parse::RpcObject;
+use crate::transport::{read_frame, write_loop, Transport};
 
 pub(crate) fn stdio_transport() -> (Sender<Value>, Receiver<Value>, IoThreads) {
+    let transport = Transport::new();
     let (writer_sender, writer_receiver) = bounded(0);
+    let local_transport = transport.clone();
     let writer = thread::spawn(move || {
         let stdout = stdout();
         let mut stdout = stdout.lock();
-        writer_receiver
-            .into_iter()
-            .try_for_each(|it| write_msg(&mut stdout, &it))?;
-        Ok(())
+        write_loop(&mut stdout, writer_receiver, &local_transport)
     });
     let (reader_sender, reader_receiver) = bounded(0);
+    let local_transport = transport.clone();
     let reader = thread::spawn(move || {
         let stdin = stdin();
         let mut stdin = stdin.lock();
         loop {
-            let msg = read_msg(&mut stdin)?;
-            reader_sender.send(msg).unwrap();
+            for msg in read_frame(&mut stdin, &local_transport)? {
+                reader_sender.send(msg).unwrap();
+            }
         }
         Ok(())
     });
-    let threads = IoThreads { reader, writer };
+    let threads = IoThreads {
+        reader,
+        writer,
+        transport,
+    };
     (writer_sender, reader_receiver, threads)
 }
 
-fn write_msg(out: &mut dyn Write, msg: &Value) -> io::Result<()> {
-    let msg = format!("{}\n", serde_json::to_string(msg)?);
-    out.write_all(msg.as_bytes())?;
-    out.flush()?;
-    Ok(())
-}
-
-fn read_msg(inp: &mut dyn BufRead) -> io::Result<Value> {
-    let mut buf = String::new();
-    let s = inp.read_line(&mut buf)?;
-    let value: Value = serde_json::from_str(&buf)?;
-    Ok(value)
+/// The same over a connection other than stdio, as a socket.
+pub(crate) fn stream_transport<R, W>(
+    reader: R,
+    mut writer: W,
+) -> (Sender<Value>, Receiver<Value>, IoThreads)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let transport = Transport::new();
+    let (writer_sender, writer_receiver) = bounded(0);
+    let local_transport = transport.clone();
+    let writer = thread::spawn(move || {
+        write_loop(&mut writer, writer_receiver, &local_transport)
+    });
+    let (reader_sender, reader_receiver) = bounded(0);
+    let local_transport = transport.clone();
+    let reader = thread::spawn(move || {
+        let mut reader = io::BufReader::new(reader);
+        loop {
+            for msg in read_frame(&mut reader, &local_transport)? {
+                if reader_sender.send(msg).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    });
+    let threads = IoThreads {
+        reader,
+        writer,
+        transport,
+    };
+    (writer_sender, reader_receiver, threads)
 }
 
 pub(crate) fn make_io_threads(
     reader: thread::JoinHandle<io::Result<()>>,
     writer: thread::JoinHandle<io::Result<()>>,
 ) -> IoThreads {
-    IoThreads { reader, writer }
+    IoThreads {
+        reader,
+        writer,
+        transport: Transport::new(),
+    }
 }
 
 pub struct IoThreads {
     reader: thread::JoinHandle<io::Result<()>>,
     writer: thread::JoinHandle<io::Result<()>>,
+    transport: Transport,
 }
 
 impl IoThreads {
+    /// The options and counters of the connection.
+    pub fn transport(&self) -> Transport {
+        self.transport.clone()
+    }
+
     pub fn join(self) -> io::Result<()> {
         match self.reader.join() {
             Ok(r) => r?,
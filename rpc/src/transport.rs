@@ -0,0 +1,385 @@
+//! What's done to the messages on the way to a slow connection, as one to
+//! a remote proxy, agreed on as the connection starts. Large frames are
+//! compressed, and the notifications sent in quick succession are batched
+//! into one frame, so each frame is one of
+//!
+//! * a message as a JSON line, starting with `{`,
+//! * `+` and a JSON array of the messages of a batch,
+//! * `~` and the base64 of the zstd of either of the other two.
+//!
+//! A connection the options are off for sends plain lines only.
+
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Frames smaller than this aren't worth compressing.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How long a notification waits for others to be batched with.
+pub const BATCH_WINDOW: Duration = Duration::from_millis(10);
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+const BATCH_PREFIX: u8 = b'+';
+const COMPRESSED_PREFIX: u8 = b'~';
+
+/// The notifications sent often enough to be worth batching.
+const BATCHED: &[&str] = &[
+    "semantic_tokens",
+    "publish_diagnostics",
+    "diagnostics_changed",
+    "work_progress",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportOptions {
+    #[serde(default)]
+    pub compression: bool,
+    #[serde(default)]
+    pub batching: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransportStats {
+    pub options: TransportOptions,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub messages_per_sec: f64,
+    /// The bytes of the messages sent over those of their frames.
+    pub compression_ratio: f64,
+}
+
+#[derive(Default)]
+struct Counters {
+    compression: AtomicBool,
+    batching: AtomicBool,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// What `bytes_out` would have been without compression.
+    raw_bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+}
+
+/// The options and counters of a connection, shared with its threads.
+#[derive(Clone)]
+pub struct Transport {
+    counters: Arc<Counters>,
+    started: Instant,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::new(Counters::default()),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn configure(&self, options: TransportOptions) {
+        let counters = &self.counters;
+        counters
+            .compression
+            .store(options.compression, Ordering::SeqCst);
+        counters.batching.store(options.batching, Ordering::SeqCst);
+    }
+
+    pub fn options(&self) -> TransportOptions {
+        TransportOptions {
+            compression: self.counters.compression.load(Ordering::SeqCst),
+            batching: self.counters.batching.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn stats(&self) -> TransportStats {
+        let counters = &self.counters;
+        let bytes_out = counters.bytes_out.load(Ordering::SeqCst);
+        let raw_bytes_out = counters.raw_bytes_out.load(Ordering::SeqCst);
+        let messages_in = counters.messages_in.load(Ordering::SeqCst);
+        let messages_out = counters.messages_out.load(Ordering::SeqCst);
+        let secs = self.started.elapsed().as_secs_f64().max(1.0);
+        TransportStats {
+            options: self.options(),
+            bytes_in: counters.bytes_in.load(Ordering::SeqCst),
+            bytes_out,
+            messages_in,
+            messages_out,
+            messages_per_sec: (messages_in + messages_out) as f64 / secs,
+            compression_ratio: if bytes_out == 0 {
+                1.0
+            } else {
+                raw_bytes_out as f64 / bytes_out as f64
+            },
+        }
+    }
+
+    fn record_in(&self, bytes: usize, messages: usize) {
+        let counters = &self.counters;
+        counters.bytes_in.fetch_add(bytes as u64, Ordering::SeqCst);
+        counters
+            .messages_in
+            .fetch_add(messages as u64, Ordering::SeqCst);
+    }
+
+    /// The frame of a message, or of the messages of a batch.
+    fn encode(&self, messages: &[Value]) -> io::Result<Vec<u8>> {
+        let mut frame = match messages {
+            [msg] => serde_json::to_vec(msg)?,
+            _ => {
+                let mut frame = vec![BATCH_PREFIX];
+                serde_json::to_writer(&mut frame, messages)?;
+                frame
+            }
+        };
+        let raw_len = frame.len() + 1;
+        if self.counters.compression.load(Ordering::SeqCst)
+            && frame.len() > COMPRESSION_THRESHOLD
+        {
+            let compressed = zstd::encode_all(&frame[..], COMPRESSION_LEVEL)?;
+            frame = Vec::with_capacity(compressed.len() * 4 / 3 + 4);
+            frame.push(COMPRESSED_PREFIX);
+            frame.extend_from_slice(base64::encode(&compressed).as_bytes());
+        }
+        frame.push(b'\n');
+
+        let counters = &self.counters;
+        counters
+            .raw_bytes_out
+            .fetch_add(raw_len as u64, Ordering::SeqCst);
+        counters
+            .bytes_out
+            .fetch_add(frame.len() as u64, Ordering::SeqCst);
+        counters
+            .messages_out
+            .fetch_add(messages.len() as u64, Ordering::SeqCst);
+        Ok(frame)
+    }
+}
+
+fn is_batched(msg: &Value) -> bool {
+    // requests have an id, and a response is waited on
+    msg.get("id").is_none()
+        && msg["method"]
+            .as_str()
+            .map(|method| BATCHED.contains(&method))
+            .unwrap_or(false)
+}
+
+fn write_frame(
+    out: &mut dyn Write,
+    messages: &[Value],
+    transport: &Transport,
+) -> io::Result<()> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    out.write_all(&transport.encode(messages)?)?;
+    out.flush()
+}
+
+/// Writes what's received until the senders are gone, holding the batched
+/// notifications back for the batch window.
+pub(crate) fn write_loop(
+    out: &mut dyn Write,
+    receiver: Receiver<Value>,
+    transport: &Transport,
+) -> io::Result<()> {
+    let mut batch = Vec::new();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let msg = match deadline {
+            Some(deadline) => match receiver.recv_deadline(deadline) {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return write_frame(out, &batch, transport);
+                }
+            },
+            None => match receiver.recv() {
+                Ok(msg) => Some(msg),
+                Err(_) => return Ok(()),
+            },
+        };
+        match msg {
+            Some(msg) if transport.options().batching && is_batched(&msg) => {
+                batch.push(msg);
+                deadline.get_or_insert_with(|| Instant::now() + BATCH_WINDOW);
+            }
+            Some(msg) => {
+                // what came before goes first
+                write_frame(out, &batch, transport)?;
+                batch.clear();
+                deadline = None;
+                write_frame(out, &[msg], transport)?;
+            }
+            None => {
+                write_frame(out, &batch, transport)?;
+                batch.clear();
+                deadline = None;
+            }
+        }
+    }
+}
+
+/// The JSON lines of the messages of a frame, appended to `out`.
+fn decode_frame(frame: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let frame = trim_newline(frame);
+    match frame.first() {
+        Some(&COMPRESSED_PREFIX) => {
+            let compressed = base64::decode(&frame[1..])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let decompressed = zstd::decode_all(&compressed[..])?;
+            decode_frame(&decompressed, out)
+        }
+        Some(&BATCH_PREFIX) => {
+            let messages: Vec<Value> = serde_json::from_slice(&frame[1..])?;
+            for msg in messages {
+                serde_json::to_writer(&mut *out, &msg)?;
+                out.push(b'\n');
+            }
+            Ok(())
+        }
+        _ => {
+            out.extend_from_slice(frame);
+            out.push(b'\n');
+            Ok(())
+        }
+    }
+}
+
+fn trim_newline(frame: &[u8]) -> &[u8] {
+    let mut frame = frame;
+    while let Some((&b'\n', rest)) | Some((&b'\r', rest)) = frame.split_last() {
+        frame = rest;
+    }
+    frame
+}
+
+/// Reads the messages of the next frame, none at the end of the input.
+pub(crate) fn read_frame(
+    inp: &mut dyn BufRead,
+    transport: &Transport,
+) -> io::Result<Vec<Value>> {
+    let mut frame = Vec::new();
+    let read = inp.read_until(b'\n', &mut frame)?;
+    if read == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut lines = Vec::new();
+    decode_frame(&frame, &mut lines)?;
+    let messages = lines
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<Vec<Value>, _>>()?;
+    transport.record_in(read, messages.len());
+    Ok(messages)
+}
+
+/// The frames of a connection read as the plain JSON lines of their
+/// messages, for a reader that knows of nothing else.
+pub struct DecodingReader<R> {
+    inner: R,
+    pending: Vec<u8>,
+    /// How much of `pending` was read.
+    pos: usize,
+}
+
+impl<R: BufRead> DecodingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            let mut frame = Vec::new();
+            if self.inner.read_until(b'\n', &mut frame)? == 0 {
+                return Ok(0);
+            }
+            self.pending.clear();
+            self.pos = 0;
+            decode_frame(&frame, &mut self.pending)?;
+        }
+        let len = buf.len().min(self.pending.len() - self.pos);
+        buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_frames() {
+        let transport = Transport::new();
+        transport.configure(TransportOptions {
+            compression: true,
+            batching: true,
+        });
+        let large = json!({
+            "method": "semantic_tokens",
+            "params": { "tokens": vec!["keyword"; 500] },
+        });
+        let small = json!({ "id": 1, "result": {} });
+
+        let mut wire = Vec::new();
+        wire.extend(transport.encode(&[small.clone()]).unwrap());
+        wire.extend(transport.encode(&[large.clone(), small.clone()]).unwrap());
+        wire.extend(transport.encode(&[large.clone()]).unwrap());
+        // only the large frames are compressed
+        assert_eq!(b'{', wire[0]);
+        assert_eq!(3, wire.iter().filter(|b| **b == b'\n').count());
+        assert_eq!(
+            2,
+            wire.split(|b| *b == b'\n')
+                .filter(|frame| frame.first() == Some(&COMPRESSED_PREFIX))
+                .count()
+        );
+
+        let mut output = String::new();
+        DecodingReader::new(&wire[..])
+            .read_to_string(&mut output)
+            .unwrap();
+        let messages: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(vec![small.clone(), large.clone(), small, large], messages);
+
+        let stats = transport.stats();
+        assert_eq!(4, stats.messages_out);
+        assert!(stats.compression_ratio > 2.0);
+    }
+
+    #[test]
+    fn test_is_batched() {
+        assert!(is_batched(&json!({ "method": "publish_diagnostics" })));
+        assert!(!is_batched(&json!({ "method": "terminal_output" })));
+        assert!(!is_batched(
+            &json!({ "id": 2, "method": "semantic_tokens" })
+        ));
+    }
+}